@@ -410,6 +410,21 @@ impl PageServerNode {
                     .map(|x| x.parse::<usize>())
                     .transpose()?,
                 pitr_interval: settings.get("pitr_interval").map(|x| x.to_string()),
+                logical_size_soft_limit: settings
+                    .get("logical_size_soft_limit")
+                    .map(|x| x.parse::<u64>())
+                    .transpose()?,
+                image_layer_excluded_forks: settings.get("image_layer_excluded_forks").map(|x| {
+                    x.split(',')
+                        .map(str::trim)
+                        .filter(|fork| !fork.is_empty())
+                        .map(str::to_string)
+                        .collect()
+                }),
+                generation: settings
+                    .get("generation")
+                    .map(|x| x.parse::<u32>())
+                    .transpose()?,
             })
             .send()?
             .error_from_body()?
@@ -449,6 +464,16 @@ impl PageServerNode {
                     .get("image_creation_threshold")
                     .map(|x| x.parse::<usize>().unwrap()),
                 pitr_interval: settings.get("pitr_interval").map(|x| x.to_string()),
+                logical_size_soft_limit: settings
+                    .get("logical_size_soft_limit")
+                    .map(|x| x.parse::<u64>().unwrap()),
+                image_layer_excluded_forks: settings.get("image_layer_excluded_forks").map(|x| {
+                    x.split(',')
+                        .map(str::trim)
+                        .filter(|fork| !fork.is_empty())
+                        .map(str::to_string)
+                        .collect()
+                }),
             })
             .send()?
             .error_from_body()?;
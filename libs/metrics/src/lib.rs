@@ -4,6 +4,7 @@
 //! a default registry.
 use lazy_static::lazy_static;
 pub use prometheus::{exponential_buckets, linear_buckets};
+pub use prometheus::{register_counter_vec, CounterVec};
 pub use prometheus::{register_gauge, Gauge};
 pub use prometheus::{register_gauge_vec, GaugeVec};
 pub use prometheus::{register_histogram, Histogram};
@@ -38,6 +39,12 @@ lazy_static! {
         "Memory usage (Maximum Resident Set Size)"
     )
     .expect("Failed to register maxrss_kb int gauge");
+    static ref PAGE_FAULTS: IntGaugeVec = register_int_gauge_vec!(
+        "libmetrics_page_faults",
+        "Page faults of the process, grouped by the kind (minor|major). Major faults needed disk i/o",
+        &["kind"]
+    )
+    .expect("Failed to register page faults int gauge vec");
 }
 
 pub const DISK_WRITE_SECONDS_BUCKETS: &[f64] = &[
@@ -64,6 +71,12 @@ fn update_rusage_metrics() {
         .with_label_values(&["write"])
         .set(rusage_stats.ru_oublock * BYTES_IN_BLOCK);
     MAXRSS_KB.set(rusage_stats.ru_maxrss);
+    PAGE_FAULTS
+        .with_label_values(&["minor"])
+        .set(rusage_stats.ru_minflt as i64);
+    PAGE_FAULTS
+        .with_label_values(&["major"])
+        .set(rusage_stats.ru_majflt as i64);
 }
 
 fn get_rusage_stats() -> libc::rusage {
@@ -5,7 +5,13 @@
 //!   * [`local_fs`] allows to use local file system as an external storage
 //!   * [`s3_bucket`] uses AWS S3 bucket as an external storage
 //!
+//! Large files can be uploaded in parts, resuming after an interruption, see [`upload_file_in_parts`].
+//!
+//! The changes made in a storage can be copied to a secondary one in the background, see [`MirroredStorage`].
+//!
 mod local_fs;
+mod mirror;
+mod multipart;
 mod s3_bucket;
 
 use std::{
@@ -16,7 +22,7 @@ use std::{
     path::{Path, PathBuf},
 };
 
-use anyhow::{bail, Context};
+use anyhow::{bail, ensure, Context};
 
 use tokio::io;
 use toml_edit::Item;
@@ -24,6 +30,8 @@ use tracing::info;
 
 pub use self::{
     local_fs::LocalFs,
+    mirror::{MirrorLag, MirroredStorage},
+    multipart::{upload_file_in_parts, StreamingUpload, UploadedPart},
     s3_bucket::{S3Bucket, S3ObjectKey},
 };
 
@@ -33,12 +41,23 @@ pub use self::{
 /// Both cases may trigger timeline download, that might download a lot of layers. This concurrency is limited by the clients internally, if needed.
 pub const DEFAULT_REMOTE_STORAGE_MAX_CONCURRENT_SYNCS: usize = 50;
 pub const DEFAULT_REMOTE_STORAGE_MAX_SYNC_ERRORS: u32 = 10;
+/// How many timelines can be downloaded simultaneously, out of the concurrent syncs.
+/// A timeline download fetches all of its layers at once, so a tenant attach can otherwise saturate the bandwidth.
+pub const DEFAULT_REMOTE_STORAGE_MAX_CONCURRENT_DOWNLOADS: usize = 8;
+/// How many timelines of the same tenant can be downloaded simultaneously, to let other tenants' downloads through.
+pub const DEFAULT_REMOTE_STORAGE_MAX_CONCURRENT_TENANT_DOWNLOADS: usize = 2;
 /// Currently, sync happens with AWS S3, that has two limits on requests per second:
 /// ~200 RPS for IAM services
 /// https://docs.aws.amazon.com/AmazonRDS/latest/AuroraUserGuide/UsingWithRDS.IAMDBAuth.html
 /// ~3500 PUT/COPY/POST/DELETE or 5500 GET/HEAD S3 requests
 /// https://aws.amazon.com/premiumsupport/knowledge-center/s3-request-limit-avoid-throttling/
 pub const DEFAULT_REMOTE_STORAGE_S3_CONCURRENCY_LIMIT: usize = 100;
+/// Files bigger than this are uploaded in parts of this size, so that an interrupted upload
+/// can be resumed instead of starting over.
+pub const DEFAULT_REMOTE_STORAGE_MULTIPART_UPLOAD_PART_SIZE: usize = 64 * 1024 * 1024;
+/// AWS S3 rejects parts smaller than 5 MiB, except the last one.
+/// https://docs.aws.amazon.com/AmazonS3/latest/userguide/qfacts.html
+pub const MIN_REMOTE_STORAGE_MULTIPART_UPLOAD_PART_SIZE: usize = 5 * 1024 * 1024;
 
 /// Storage (potentially remote) API to manage its state.
 /// This storage tries to be unaware of any layered repository context,
@@ -57,6 +76,9 @@ pub trait RemoteStorage: Send + Sync {
     /// Lists all items the storage has right now.
     async fn list(&self) -> anyhow::Result<Vec<Self::RemoteObjectId>>;
 
+    /// Returns the size of the storage entry in bytes, or `None` if there's no such entry.
+    async fn object_size(&self, id: &Self::RemoteObjectId) -> anyhow::Result<Option<u64>>;
+
     /// Streams the local file contents into remote into the remote storage entry.
     async fn upload(
         &self,
@@ -87,6 +109,45 @@ pub trait RemoteStorage: Send + Sync {
     ) -> anyhow::Result<Option<StorageMetadata>>;
 
     async fn delete(&self, path: &Self::RemoteObjectId) -> anyhow::Result<()>;
+
+    /// Deletes all the given storage entries, with as few requests as the storage allows.
+    /// Entries that don't exist are skipped.
+    async fn delete_objects(&self, paths: &[Self::RemoteObjectId]) -> anyhow::Result<()>;
+
+    /// Starts a new upload of the storage entry in parts, returns the id of the upload.
+    /// The entry does not appear in the storage until the upload is completed.
+    async fn start_multipart_upload(
+        &self,
+        to: &Self::RemoteObjectId,
+        metadata: Option<StorageMetadata>,
+    ) -> anyhow::Result<String>;
+
+    /// Streams the part of the entry contents into the upload given, returns the part's tag
+    /// that has to be passed to [`RemoteStorage::complete_multipart_upload`].
+    /// Parts are numbered from 1, uploading the same part number again replaces the part.
+    async fn upload_part(
+        &self,
+        from: impl io::AsyncRead + Unpin + Send + Sync + 'static,
+        from_size_bytes: usize,
+        to: &Self::RemoteObjectId,
+        upload_id: &str,
+        part_number: u32,
+    ) -> anyhow::Result<String>;
+
+    /// Assembles the storage entry out of the given parts, in the order of their numbers.
+    async fn complete_multipart_upload(
+        &self,
+        to: &Self::RemoteObjectId,
+        upload_id: &str,
+        parts: &[UploadedPart],
+    ) -> anyhow::Result<()>;
+
+    /// Discards the upload along with all its parts uploaded so far.
+    async fn abort_multipart_upload(
+        &self,
+        to: &Self::RemoteObjectId,
+        upload_id: &str,
+    ) -> anyhow::Result<()>;
 }
 
 /// Every storage, currently supported.
@@ -113,6 +174,112 @@ impl GenericRemoteStorage {
             }
         }
     }
+
+    /// Streams the storage entry of the given local path into the writer, see [`RemoteStorage::download`].
+    pub async fn download_local_path(
+        &self,
+        local_path: &Path,
+        to: &mut (impl io::AsyncWrite + Unpin + Send + Sync),
+    ) -> anyhow::Result<Option<StorageMetadata>> {
+        match self {
+            GenericRemoteStorage::Local(storage) => {
+                let remote_object_id = storage.remote_object_id(local_path)?;
+                storage.download(&remote_object_id, to).await
+            }
+            GenericRemoteStorage::S3(storage) => {
+                let remote_object_id = storage.remote_object_id(local_path)?;
+                storage.download(&remote_object_id, to).await
+            }
+        }
+    }
+
+    /// Streams a byte range of the storage entry of the given local path into the writer,
+    /// see [`RemoteStorage::download_byte_range`].
+    pub async fn download_byte_range_local_path(
+        &self,
+        local_path: &Path,
+        start_inclusive: u64,
+        end_exclusive: Option<u64>,
+        to: &mut (impl io::AsyncWrite + Unpin + Send + Sync),
+    ) -> anyhow::Result<Option<StorageMetadata>> {
+        match self {
+            GenericRemoteStorage::Local(storage) => {
+                let remote_object_id = storage.remote_object_id(local_path)?;
+                storage
+                    .download_byte_range(&remote_object_id, start_inclusive, end_exclusive, to)
+                    .await
+            }
+            GenericRemoteStorage::S3(storage) => {
+                let remote_object_id = storage.remote_object_id(local_path)?;
+                storage
+                    .download_byte_range(&remote_object_id, start_inclusive, end_exclusive, to)
+                    .await
+            }
+        }
+    }
+
+    /// Streams the contents into the storage entry of the given local path, see [`RemoteStorage::upload`].
+    pub async fn upload_local_path(
+        &self,
+        from: impl io::AsyncRead + Unpin + Send + Sync + 'static,
+        from_size_bytes: usize,
+        local_path: &Path,
+        metadata: Option<StorageMetadata>,
+    ) -> anyhow::Result<()> {
+        match self {
+            GenericRemoteStorage::Local(storage) => {
+                let remote_object_id = storage.remote_object_id(local_path)?;
+                storage
+                    .upload(from, from_size_bytes, &remote_object_id, metadata)
+                    .await
+            }
+            GenericRemoteStorage::S3(storage) => {
+                let remote_object_id = storage.remote_object_id(local_path)?;
+                storage
+                    .upload(from, from_size_bytes, &remote_object_id, metadata)
+                    .await
+            }
+        }
+    }
+
+    /// Deletes the storage entries of the given local paths, skipping the missing ones,
+    /// see [`RemoteStorage::delete_objects`].
+    pub async fn delete_local_paths(&self, local_paths: &[PathBuf]) -> anyhow::Result<()> {
+        match self {
+            GenericRemoteStorage::Local(storage) => {
+                let remote_object_ids = local_paths
+                    .iter()
+                    .map(|local_path| storage.remote_object_id(local_path))
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                storage.delete_objects(&remote_object_ids).await
+            }
+            GenericRemoteStorage::S3(storage) => {
+                let remote_object_ids = local_paths
+                    .iter()
+                    .map(|local_path| storage.remote_object_id(local_path))
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                storage.delete_objects(&remote_object_ids).await
+            }
+        }
+    }
+
+    /// Lists the local paths of all the entries the storage has right now, see [`RemoteStorage::list`].
+    pub async fn list_local_paths(&self) -> anyhow::Result<Vec<PathBuf>> {
+        match self {
+            GenericRemoteStorage::Local(storage) => storage
+                .list()
+                .await?
+                .iter()
+                .map(|remote_object_id| storage.local_path(remote_object_id))
+                .collect(),
+            GenericRemoteStorage::S3(storage) => storage
+                .list()
+                .await?
+                .iter()
+                .map(|remote_object_id| storage.local_path(remote_object_id))
+                .collect(),
+        }
+    }
 }
 
 /// Extra set of key-value pairs that contain arbitrary metadata about the storage entry.
@@ -144,6 +311,12 @@ pub struct RemoteStorageConfig {
     pub max_concurrent_syncs: NonZeroUsize,
     /// Max allowed errors before the sync task is considered failed and evicted.
     pub max_sync_errors: NonZeroU32,
+    /// Max number of timelines downloaded at the same time.
+    pub max_concurrent_downloads: NonZeroUsize,
+    /// Max number of timelines of a single tenant downloaded at the same time.
+    pub max_concurrent_tenant_downloads: NonZeroUsize,
+    /// Files bigger than this are uploaded in parts of this size.
+    pub multipart_upload_part_size: NonZeroUsize,
     /// The storage connection configuration.
     pub storage: RemoteStorageKind,
 }
@@ -223,6 +396,28 @@ impl RemoteStorageConfig {
         )
         .context("Failed to parse 'max_sync_errors' as a positive integer")?;
 
+        let max_concurrent_downloads = NonZeroUsize::new(
+            parse_optional_integer("max_concurrent_downloads", toml)?
+                .unwrap_or(DEFAULT_REMOTE_STORAGE_MAX_CONCURRENT_DOWNLOADS),
+        )
+        .context("Failed to parse 'max_concurrent_downloads' as a positive integer")?;
+
+        let max_concurrent_tenant_downloads = NonZeroUsize::new(
+            parse_optional_integer("max_concurrent_tenant_downloads", toml)?
+                .unwrap_or(DEFAULT_REMOTE_STORAGE_MAX_CONCURRENT_TENANT_DOWNLOADS),
+        )
+        .context("Failed to parse 'max_concurrent_tenant_downloads' as a positive integer")?;
+
+        let multipart_upload_part_size = NonZeroUsize::new(
+            parse_optional_integer("multipart_upload_part_size", toml)?
+                .unwrap_or(DEFAULT_REMOTE_STORAGE_MULTIPART_UPLOAD_PART_SIZE),
+        )
+        .context("Failed to parse 'multipart_upload_part_size' as a positive integer")?;
+        ensure!(
+            multipart_upload_part_size.get() >= MIN_REMOTE_STORAGE_MULTIPART_UPLOAD_PART_SIZE,
+            "'multipart_upload_part_size' must be at least {MIN_REMOTE_STORAGE_MULTIPART_UPLOAD_PART_SIZE} bytes"
+        );
+
         let concurrency_limit = NonZeroUsize::new(
             parse_optional_integer("concurrency_limit", toml)?
                 .unwrap_or(DEFAULT_REMOTE_STORAGE_S3_CONCURRENCY_LIMIT),
@@ -259,6 +454,9 @@ impl RemoteStorageConfig {
         Ok(RemoteStorageConfig {
             max_concurrent_syncs,
             max_sync_errors,
+            max_concurrent_downloads,
+            max_concurrent_tenant_downloads,
+            multipart_upload_part_size,
             storage,
         })
     }
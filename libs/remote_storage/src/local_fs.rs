@@ -3,11 +3,16 @@
 //!
 //! This storage used in tests, but can also be used in cases when a certain persistent
 //! volume is mounted to the local FS.
+//!
+//! Parts of multipart uploads are stored in a separate directory under the storage root
+//! until the upload is completed, and are not listed as storage files.
 
 use std::{
     future::Future,
     path::{Path, PathBuf},
     pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::{bail, ensure, Context};
@@ -19,7 +24,12 @@ use tracing::*;
 
 use crate::path_with_suffix_extension;
 
-use super::{strip_path_prefix, RemoteStorage, StorageMetadata};
+use super::{strip_path_prefix, RemoteStorage, StorageMetadata, UploadedPart};
+
+/// A directory in the storage root to keep the parts of unfinished multipart uploads in.
+const MULTIPART_UPLOADS_DIR: &str = ".multipart_uploads";
+/// Makes upload ids unique, even if a few uploads are started within the same clock tick.
+static NEXT_UPLOAD_ID_SUFFIX: AtomicU64 = AtomicU64::new(0);
 
 pub struct LocalFs {
     working_directory: PathBuf,
@@ -56,6 +66,20 @@ impl LocalFs {
         }
     }
 
+    fn multipart_upload_dir(&self, upload_id: &str) -> anyhow::Result<PathBuf> {
+        ensure!(
+            !upload_id.is_empty()
+                && upload_id
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-'),
+            "Invalid multipart upload id '{upload_id}'"
+        );
+        Ok(self
+            .storage_root
+            .join(MULTIPART_UPLOADS_DIR)
+            .join(upload_id))
+    }
+
     async fn read_storage_metadata(
         &self,
         file_path: &Path,
@@ -101,7 +125,24 @@ impl RemoteStorage for LocalFs {
     }
 
     async fn list(&self) -> anyhow::Result<Vec<Self::RemoteObjectId>> {
-        get_all_files(&self.storage_root).await
+        let multipart_uploads_dir = self.storage_root.join(MULTIPART_UPLOADS_DIR);
+        Ok(get_all_files(&self.storage_root)
+            .await?
+            .into_iter()
+            .filter(|path| !path.starts_with(&multipart_uploads_dir))
+            .collect())
+    }
+
+    async fn object_size(&self, id: &Self::RemoteObjectId) -> anyhow::Result<Option<u64>> {
+        let file_path = self.resolve_in_storage(id)?;
+        match fs::metadata(&file_path).await {
+            Ok(metadata) if metadata.is_file() => Ok(Some(metadata.len())),
+            Ok(_) => bail!("'{}' is not a file", file_path.display()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| {
+                format!("Failed to get the metadata of '{}'", file_path.display())
+            }),
+        }
     }
 
     async fn upload(
@@ -113,80 +154,10 @@ impl RemoteStorage for LocalFs {
     ) -> anyhow::Result<()> {
         let target_file_path = self.resolve_in_storage(to)?;
         create_target_directory(&target_file_path).await?;
-        // We need this dance with sort of durable rename (without fsyncs)
-        // to prevent partial uploads. This was really hit when pageserver shutdown
-        // cancelled the upload and partial file was left on the fs
-        let temp_file_path = path_with_suffix_extension(&target_file_path, "temp");
-        let mut destination = io::BufWriter::new(
-            fs::OpenOptions::new()
-                .write(true)
-                .create(true)
-                .open(&temp_file_path)
-                .await
-                .with_context(|| {
-                    format!(
-                        "Failed to open target fs destination at '{}'",
-                        target_file_path.display()
-                    )
-                })?,
-        );
-
-        let from_size_bytes = from_size_bytes as u64;
-        // Require to read 1 byte more than the expected to check later, that the stream and its size match.
-        let mut buffer_to_read = from.take(from_size_bytes + 1);
-
-        let bytes_read = io::copy(&mut buffer_to_read, &mut destination)
-            .await
-            .with_context(|| {
-                format!(
-                    "Failed to upload file (write temp) to the local storage at '{}'",
-                    temp_file_path.display()
-                )
-            })?;
-
-        ensure!(
-            bytes_read == from_size_bytes,
-            "Provided stream has actual size {} fthat is smaller than the given stream size {}",
-            bytes_read,
-            from_size_bytes
-        );
-
-        ensure!(
-            buffer_to_read.read(&mut [0]).await? == 0,
-            "Provided stream has bigger size than the given stream size {}",
-            from_size_bytes
-        );
-
-        destination.flush().await.with_context(|| {
-            format!(
-                "Failed to upload (flush temp) file to the local storage at '{}'",
-                temp_file_path.display()
-            )
-        })?;
-
-        fs::rename(temp_file_path, &target_file_path)
-            .await
-            .with_context(|| {
-                format!(
-                    "Failed to upload (rename) file to the local storage at '{}'",
-                    target_file_path.display()
-                )
-            })?;
+        write_stream_to_file(from, from_size_bytes, &target_file_path).await?;
 
         if let Some(storage_metadata) = metadata {
-            let storage_metadata_path = storage_metadata_path(&target_file_path);
-            fs::write(
-                &storage_metadata_path,
-                serde_json::to_string(&storage_metadata.0)
-                    .context("Failed to serialize storage metadata as json")?,
-            )
-            .await
-            .with_context(|| {
-                format!(
-                    "Failed to write metadata to the local storage at '{}'",
-                    storage_metadata_path.display()
-                )
-            })?;
+            write_storage_metadata(&target_file_path, &storage_metadata).await?;
         }
 
         Ok(())
@@ -299,6 +270,167 @@ impl RemoteStorage for LocalFs {
             )
         }
     }
+
+    async fn delete_objects(&self, paths: &[Self::RemoteObjectId]) -> anyhow::Result<()> {
+        for path in paths {
+            let file_path = self.resolve_in_storage(path)?;
+            match fs::remove_file(&file_path).await {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    return Err(e).with_context(|| {
+                        format!("Failed to delete file '{}'", file_path.display())
+                    })
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn start_multipart_upload(
+        &self,
+        to: &Self::RemoteObjectId,
+        metadata: Option<StorageMetadata>,
+    ) -> anyhow::Result<String> {
+        self.resolve_in_storage(to)?;
+        let since_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .context("System time is before the unix epoch")?;
+        let upload_id = format!(
+            "{:x}-{:x}",
+            since_epoch.as_nanos(),
+            NEXT_UPLOAD_ID_SUFFIX.fetch_add(1, Ordering::Relaxed)
+        );
+
+        let upload_dir = self.multipart_upload_dir(&upload_id)?;
+        fs::create_dir_all(&upload_dir).await.with_context(|| {
+            format!(
+                "Failed to create multipart upload directory '{}'",
+                upload_dir.display()
+            )
+        })?;
+        if let Some(storage_metadata) = metadata {
+            write_storage_metadata(&upload_dir.join("upload"), &storage_metadata).await?;
+        }
+        Ok(upload_id)
+    }
+
+    async fn upload_part(
+        &self,
+        from: impl io::AsyncRead + Unpin + Send + Sync + 'static,
+        from_size_bytes: usize,
+        to: &Self::RemoteObjectId,
+        upload_id: &str,
+        part_number: u32,
+    ) -> anyhow::Result<String> {
+        self.resolve_in_storage(to)?;
+        let upload_dir = self.multipart_upload_dir(upload_id)?;
+        ensure!(
+            upload_dir.is_dir(),
+            "Multipart upload {upload_id} does not exist"
+        );
+        write_stream_to_file(
+            from,
+            from_size_bytes,
+            &multipart_part_path(&upload_dir, part_number),
+        )
+        .await?;
+        Ok(format!("{part_number}-{from_size_bytes}"))
+    }
+
+    async fn complete_multipart_upload(
+        &self,
+        to: &Self::RemoteObjectId,
+        upload_id: &str,
+        parts: &[UploadedPart],
+    ) -> anyhow::Result<()> {
+        let target_file_path = self.resolve_in_storage(to)?;
+        let upload_dir = self.multipart_upload_dir(upload_id)?;
+        ensure!(
+            upload_dir.is_dir(),
+            "Multipart upload {upload_id} does not exist"
+        );
+        create_target_directory(&target_file_path).await?;
+
+        let temp_file_path = path_with_suffix_extension(&target_file_path, "temp");
+        let mut destination =
+            io::BufWriter::new(fs::File::create(&temp_file_path).await.with_context(|| {
+                format!(
+                    "Failed to open target fs destination at '{}'",
+                    temp_file_path.display()
+                )
+            })?);
+        let mut sorted_parts = parts.iter().collect::<Vec<_>>();
+        sorted_parts.sort_by_key(|part| part.part_number);
+        for part in sorted_parts {
+            let part_path = multipart_part_path(&upload_dir, part.part_number);
+            let mut part_file = fs::File::open(&part_path).await.with_context(|| {
+                format!(
+                    "Part {} of multipart upload {upload_id} is missing",
+                    part.part_number
+                )
+            })?;
+            io::copy(&mut part_file, &mut destination)
+                .await
+                .with_context(|| {
+                    format!(
+                        "Failed to copy part '{}' into the local storage at '{}'",
+                        part_path.display(),
+                        temp_file_path.display()
+                    )
+                })?;
+        }
+        destination.flush().await.with_context(|| {
+            format!(
+                "Failed to flush the multipart upload into '{}'",
+                temp_file_path.display()
+            )
+        })?;
+
+        fs::rename(&temp_file_path, &target_file_path)
+            .await
+            .with_context(|| {
+                format!(
+                    "Failed to complete (rename) multipart upload to the local storage at '{}'",
+                    target_file_path.display()
+                )
+            })?;
+        if let Some(storage_metadata) = self
+            .read_storage_metadata(&upload_dir.join("upload"))
+            .await?
+        {
+            write_storage_metadata(&target_file_path, &storage_metadata).await?;
+        }
+
+        fs::remove_dir_all(&upload_dir).await.with_context(|| {
+            format!(
+                "Failed to remove multipart upload directory '{}'",
+                upload_dir.display()
+            )
+        })
+    }
+
+    async fn abort_multipart_upload(
+        &self,
+        to: &Self::RemoteObjectId,
+        upload_id: &str,
+    ) -> anyhow::Result<()> {
+        self.resolve_in_storage(to)?;
+        let upload_dir = self.multipart_upload_dir(upload_id)?;
+        if upload_dir.exists() {
+            fs::remove_dir_all(&upload_dir).await.with_context(|| {
+                format!(
+                    "Failed to remove multipart upload directory '{}'",
+                    upload_dir.display()
+                )
+            })?;
+        }
+        Ok(())
+    }
+}
+
+fn multipart_part_path(upload_dir: &Path, part_number: u32) -> PathBuf {
+    upload_dir.join(format!("part-{part_number}"))
 }
 
 fn storage_metadata_path(original_path: &Path) -> PathBuf {
@@ -338,6 +470,95 @@ where
     })
 }
 
+async fn write_stream_to_file(
+    from: impl io::AsyncRead + Unpin + Send + Sync + 'static,
+    from_size_bytes: usize,
+    target_file_path: &Path,
+) -> anyhow::Result<()> {
+    // We need this dance with sort of durable rename (without fsyncs)
+    // to prevent partial uploads. This was really hit when pageserver shutdown
+    // cancelled the upload and partial file was left on the fs
+    let temp_file_path = path_with_suffix_extension(&target_file_path, "temp");
+    let mut destination = io::BufWriter::new(
+        fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&temp_file_path)
+            .await
+            .with_context(|| {
+                format!(
+                    "Failed to open target fs destination at '{}'",
+                    target_file_path.display()
+                )
+            })?,
+    );
+
+    let from_size_bytes = from_size_bytes as u64;
+    // Require to read 1 byte more than the expected to check later, that the stream and its size match.
+    let mut buffer_to_read = from.take(from_size_bytes + 1);
+
+    let bytes_read = io::copy(&mut buffer_to_read, &mut destination)
+        .await
+        .with_context(|| {
+            format!(
+                "Failed to upload file (write temp) to the local storage at '{}'",
+                temp_file_path.display()
+            )
+        })?;
+
+    ensure!(
+        bytes_read == from_size_bytes,
+        "Provided stream has actual size {} fthat is smaller than the given stream size {}",
+        bytes_read,
+        from_size_bytes
+    );
+
+    ensure!(
+        buffer_to_read.read(&mut [0]).await? == 0,
+        "Provided stream has bigger size than the given stream size {}",
+        from_size_bytes
+    );
+
+    destination.flush().await.with_context(|| {
+        format!(
+            "Failed to upload (flush temp) file to the local storage at '{}'",
+            temp_file_path.display()
+        )
+    })?;
+
+    fs::rename(temp_file_path, &target_file_path)
+        .await
+        .with_context(|| {
+            format!(
+                "Failed to upload (rename) file to the local storage at '{}'",
+                target_file_path.display()
+            )
+        })?;
+
+    Ok(())
+}
+
+async fn write_storage_metadata(
+    target_file_path: &Path,
+    storage_metadata: &StorageMetadata,
+) -> anyhow::Result<()> {
+    let storage_metadata_path = storage_metadata_path(target_file_path);
+    fs::write(
+        &storage_metadata_path,
+        serde_json::to_string(&storage_metadata.0)
+            .context("Failed to serialize storage metadata as json")?,
+    )
+    .await
+    .with_context(|| {
+        format!(
+            "Failed to write metadata to the local storage at '{}'",
+            storage_metadata_path.display()
+        )
+    })?;
+    Ok(())
+}
+
 async fn create_target_directory(target_file_path: &Path) -> anyhow::Result<()> {
     let target_dir = match target_file_path.parent() {
         Some(parent_dir) => parent_dir,
@@ -749,6 +970,43 @@ mod fs_tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn delete_files() -> anyhow::Result<()> {
+        let workdir = tempdir()?.path().to_owned();
+
+        let storage = create_storage()?;
+        let upload_1 = upload_dummy_file(&workdir, &storage, "upload_1", None).await?;
+        let upload_2 = upload_dummy_file(&workdir, &storage, "upload_2", None).await?;
+        let upload_3 = upload_dummy_file(&workdir, &storage, "upload_3", None).await?;
+
+        storage
+            .delete_objects(&[upload_1.clone(), upload_3.clone()])
+            .await?;
+        assert_eq!(storage.list().await?, vec![upload_2.clone()]);
+
+        // Missing files are skipped
+        storage.delete_objects(&[upload_1, upload_2]).await?;
+        assert!(storage.list().await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn object_size() -> anyhow::Result<()> {
+        let workdir = tempdir()?.path().to_owned();
+
+        let storage = create_storage()?;
+        let upload_name = "upload_1";
+        let upload_target = upload_dummy_file(&workdir, &storage, upload_name, None).await?;
+        assert_eq!(
+            storage.object_size(&upload_target).await?,
+            Some(dummy_contents(upload_name).len() as u64)
+        );
+
+        storage.delete(&upload_target).await?;
+        assert_eq!(storage.object_size(&upload_target).await?, None);
+        Ok(())
+    }
+
     #[tokio::test]
     async fn file_with_metadata() -> anyhow::Result<()> {
         let workdir = tempdir()?.path().to_owned();
@@ -0,0 +1,796 @@
+//! A remote storage with its changes copied to a secondary storage, e.g. a bucket in another region,
+//! to recover from when the primary one is lost or unavailable.
+//!
+//! [`MirroredStorage`] makes every change in the primary storage first, and returns as soon as it's done
+//! there. The same change is then queued for the secondary storage, and a background task applies the
+//! queued changes one by one, in order, retrying each one until it succeeds. The index files uploaded
+//! after the layers they refer to stay after them in the secondary storage too, so the secondary storage
+//! is always a consistent, if older, copy of the primary one. How far behind it is, is reported by
+//! [`MirroredStorage::lag`] and the `remote_storage_mirror_*` metrics.
+//!
+//! The uploads of local files are mirrored by reading the files again, the other uploads (like the index
+//! files, serialized in memory) are buffered until the secondary storage has them. A local file removed
+//! before its upload got mirrored is copied from the primary storage instead.
+//!
+//! The queue is kept in memory: the changes not mirrored yet when the process stops are lost for the
+//! secondary storage, until the same entries are uploaded again.
+//!
+//! Reads go to the primary storage, and are retried in the secondary one if the primary storage fails
+//! before returning any data: a tenant can be attached while the primary storage is down. An entry that
+//! the primary storage doesn't have is not read from the secondary one: it was deleted, and the secondary
+//! storage may still have it only because the deletion is not mirrored yet.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context as TaskContext, Poll},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use tokio::{
+    fs,
+    io::{self, AsyncReadExt},
+    sync::mpsc,
+};
+use tracing::{debug, info, warn};
+
+use crate::{GenericRemoteStorage, RemoteStorage, StorageMetadata, UploadedPart};
+
+pub(super) mod metrics {
+    use metrics::{register_int_counter, register_int_gauge, IntCounter, IntGauge};
+    use once_cell::sync::Lazy;
+
+    pub static MIRROR_PENDING_OPERATIONS: Lazy<IntGauge> = Lazy::new(|| {
+        register_int_gauge!(
+            "remote_storage_mirror_pending_operations",
+            "Number of changes made in the primary remote storage, not copied to the secondary one yet"
+        )
+        .expect("failed to define a metric")
+    });
+
+    pub static MIRROR_LAG_SECONDS: Lazy<IntGauge> = Lazy::new(|| {
+        register_int_gauge!(
+            "remote_storage_mirror_lag_seconds",
+            "Age of the oldest change not copied to the secondary remote storage yet"
+        )
+        .expect("failed to define a metric")
+    });
+
+    pub static MIRROR_FAILURES: Lazy<IntCounter> = Lazy::new(|| {
+        register_int_counter!(
+            "remote_storage_mirror_failures_total",
+            "Number of failed attempts to copy a change to the secondary remote storage"
+        )
+        .expect("failed to define a metric")
+    });
+
+    pub static MIRROR_FALLBACK_READS: Lazy<IntCounter> = Lazy::new(|| {
+        register_int_counter!(
+            "remote_storage_mirror_fallback_reads_total",
+            "Number of reads served by the secondary remote storage after the primary one failed"
+        )
+        .expect("failed to define a metric")
+    });
+}
+
+/// The longest pause between the attempts to apply a change to the secondary storage.
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(60);
+
+/// How far the secondary storage is behind the primary one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MirrorLag {
+    /// Changes not applied to the secondary storage yet.
+    pub pending_operations: usize,
+    /// Time since the oldest of them was made in the primary storage.
+    pub oldest_pending: Option<Duration>,
+}
+
+/// A change to apply to the secondary storage, with the entries identified by their local paths:
+/// the storages may use different object ids.
+enum MirrorOperation {
+    /// Upload the local file.
+    UploadFile {
+        local_path: PathBuf,
+        metadata: Option<StorageMetadata>,
+    },
+    /// Upload the contents that were uploaded to the primary storage from memory.
+    UploadBytes {
+        local_path: PathBuf,
+        bytes: Vec<u8>,
+        metadata: Option<StorageMetadata>,
+    },
+    Delete {
+        local_paths: Vec<PathBuf>,
+    },
+}
+
+impl std::fmt::Debug for MirrorOperation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MirrorOperation::UploadFile { local_path, .. } => {
+                write!(f, "upload of '{}'", local_path.display())
+            }
+            MirrorOperation::UploadBytes {
+                local_path, bytes, ..
+            } => write!(
+                f,
+                "upload of {} bytes to '{}'",
+                bytes.len(),
+                local_path.display()
+            ),
+            MirrorOperation::Delete { local_paths } => {
+                write!(f, "deletion of {} entries", local_paths.len())
+            }
+        }
+    }
+}
+
+impl MirrorOperation {
+    async fn apply<S>(&self, primary: &S, secondary: &GenericRemoteStorage) -> anyhow::Result<()>
+    where
+        S: RemoteStorage,
+        S::RemoteObjectId: Send + Sync,
+    {
+        match self {
+            MirrorOperation::UploadFile {
+                local_path,
+                metadata,
+            } => {
+                let file = match fs::File::open(local_path).await {
+                    Ok(file) => file,
+                    Err(e) if e.kind() == ErrorKind::NotFound => {
+                        // Removed locally before it got mirrored, e.g. by GC or eviction
+                        return copy_from_primary(primary, secondary, local_path, metadata).await;
+                    }
+                    Err(e) => {
+                        return Err(e).with_context(|| {
+                            format!("Failed to open local file '{}'", local_path.display())
+                        })
+                    }
+                };
+                let size = file.metadata().await?.len() as usize;
+                secondary
+                    .upload_local_path(io::BufReader::new(file), size, local_path, metadata.clone())
+                    .await
+            }
+            MirrorOperation::UploadBytes {
+                local_path,
+                bytes,
+                metadata,
+            } => {
+                secondary
+                    .upload_local_path(
+                        std::io::Cursor::new(bytes.clone()),
+                        bytes.len(),
+                        local_path,
+                        metadata.clone(),
+                    )
+                    .await
+            }
+            MirrorOperation::Delete { local_paths } => {
+                secondary.delete_local_paths(local_paths).await
+            }
+        }
+    }
+}
+
+/// Copies the entry of the local path from the primary storage to the secondary one.
+async fn copy_from_primary<S>(
+    primary: &S,
+    secondary: &GenericRemoteStorage,
+    local_path: &Path,
+    metadata: &Option<StorageMetadata>,
+) -> anyhow::Result<()>
+where
+    S: RemoteStorage,
+    S::RemoteObjectId: Send + Sync,
+{
+    let id = primary.remote_object_id(local_path)?;
+    let size = match primary.object_size(&id).await? {
+        Some(size) => size,
+        None => {
+            // Deleted from the primary storage too, the deletion is queued after this upload
+            warn!(
+                "'{}' is gone locally and in the primary storage, not mirroring it",
+                local_path.display()
+            );
+            return Ok(());
+        }
+    };
+    debug!(
+        "Local file '{}' is gone, copying it from the primary storage",
+        local_path.display()
+    );
+    let mut bytes = Vec::with_capacity(size as usize);
+    let stored_metadata = primary
+        .download(&id, &mut bytes)
+        .await
+        .context("Failed to download the entry from the primary storage")?;
+    secondary
+        .upload_local_path(
+            std::io::Cursor::new(bytes),
+            size as usize,
+            local_path,
+            stored_metadata.or_else(|| metadata.clone()),
+        )
+        .await
+}
+
+/// The changes to apply to the secondary storage, in order, and when each one was queued.
+struct MirrorQueue {
+    sender: mpsc::UnboundedSender<MirrorOperation>,
+    /// Taken by the background task, started with the first change.
+    receiver: Mutex<Option<mpsc::UnboundedReceiver<MirrorOperation>>>,
+    queued_at: Mutex<VecDeque<Instant>>,
+}
+
+impl MirrorQueue {
+    fn lag(&self) -> MirrorLag {
+        let queued_at = self.queued_at.lock().unwrap();
+        MirrorLag {
+            pending_operations: queued_at.len(),
+            oldest_pending: queued_at.front().map(Instant::elapsed),
+        }
+    }
+
+    fn update_metrics(&self) {
+        let lag = self.lag();
+        metrics::MIRROR_PENDING_OPERATIONS.set(lag.pending_operations as i64);
+        metrics::MIRROR_LAG_SECONDS.set(lag.oldest_pending.unwrap_or_default().as_secs() as i64);
+    }
+}
+
+/// A [`RemoteStorage`] that copies its changes to a secondary storage in the background,
+/// and reads from it when the primary storage fails. See the module documentation.
+pub struct MirroredStorage<S> {
+    /// Shared with the background task, that copies the files removed locally from it.
+    primary: Arc<S>,
+    secondary: Arc<GenericRemoteStorage>,
+    /// Decides which entries are mirrored, by their local paths.
+    mirror_filter: Box<dyn Fn(&Path) -> bool + Send + Sync>,
+    queue: Arc<MirrorQueue>,
+    /// Metadata of the multipart uploads in progress, by upload id, to mirror the completed files with.
+    /// Uploads resumed after a restart are mirrored without it.
+    upload_metadata: Mutex<HashMap<String, StorageMetadata>>,
+}
+
+impl<S> MirroredStorage<S>
+where
+    S: RemoteStorage + 'static,
+    S::RemoteObjectId: Send + Sync,
+{
+    /// Mirrors the entries of the local paths that 'mirror_filter' accepts. The background task that
+    /// copies the changes is started with the first one, in the tokio runtime that makes it.
+    pub fn new(
+        primary: S,
+        secondary: GenericRemoteStorage,
+        mirror_filter: impl Fn(&Path) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        Self {
+            primary: Arc::new(primary),
+            secondary: Arc::new(secondary),
+            mirror_filter: Box::new(mirror_filter),
+            queue: Arc::new(MirrorQueue {
+                sender,
+                receiver: Mutex::new(Some(receiver)),
+                queued_at: Mutex::new(VecDeque::new()),
+            }),
+            upload_metadata: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// How far the secondary storage is behind the primary one.
+    pub fn lag(&self) -> MirrorLag {
+        self.queue.lag()
+    }
+
+    fn enqueue(&self, operation: MirrorOperation) {
+        if let Some(receiver) = self.queue.receiver.lock().unwrap().take() {
+            info!("Starting to mirror the remote storage changes");
+            tokio::spawn(mirror_changes(
+                Arc::clone(&self.primary),
+                Arc::clone(&self.secondary),
+                Arc::clone(&self.queue),
+                receiver,
+            ));
+        }
+
+        debug!("Queueing {operation:?} for the secondary storage");
+        self.queue
+            .queued_at
+            .lock()
+            .unwrap()
+            .push_back(Instant::now());
+        if self.queue.sender.send(operation).is_err() {
+            // The task only stops with the runtime
+            warn!("Remote storage mirroring has stopped, not mirroring the change");
+            self.queue.queued_at.lock().unwrap().pop_back();
+        }
+        self.queue.update_metrics();
+    }
+
+    /// The local path of the entry, if it is mirrored.
+    fn mirrored_local_path(&self, remote_object_id: &S::RemoteObjectId) -> Option<PathBuf> {
+        match self.primary.local_path(remote_object_id) {
+            Ok(local_path) if (self.mirror_filter)(&local_path) => Some(local_path),
+            Ok(_) => None,
+            Err(e) => {
+                warn!("Not mirroring an entry without a local path: {e:#}");
+                None
+            }
+        }
+    }
+
+    /// Whether the primary storage failed to read the entry because it doesn't have it,
+    /// rather than failing to respond.
+    async fn primary_lacks(&self, id: &S::RemoteObjectId) -> bool {
+        matches!(self.primary.object_size(id).await, Ok(None))
+    }
+}
+
+/// Applies the queued changes to the secondary storage, in order. A change that fails is retried
+/// until it succeeds, the later changes wait for it.
+async fn mirror_changes<S>(
+    primary: Arc<S>,
+    secondary: Arc<GenericRemoteStorage>,
+    queue: Arc<MirrorQueue>,
+    mut receiver: mpsc::UnboundedReceiver<MirrorOperation>,
+) where
+    S: RemoteStorage,
+    S::RemoteObjectId: Send + Sync,
+{
+    while let Some(operation) = receiver.recv().await {
+        let mut backoff = Duration::from_secs(1);
+        while let Err(e) = operation.apply(primary.as_ref(), &secondary).await {
+            metrics::MIRROR_FAILURES.inc();
+            warn!("Failed to apply {operation:?} to the secondary storage, retrying in {backoff:?}: {e:#}");
+            queue.update_metrics();
+            tokio::time::sleep(backoff).await;
+            backoff = std::cmp::min(backoff * 2, MAX_RETRY_BACKOFF);
+        }
+        queue.queued_at.lock().unwrap().pop_front();
+        queue.update_metrics();
+    }
+}
+
+/// Counts the bytes written, to know if a failed download can be retried into the same writer.
+struct CountingWriter<'a, W> {
+    inner: &'a mut W,
+    written: u64,
+}
+
+impl<W: io::AsyncWrite + Unpin> io::AsyncWrite for CountingWriter<'_, W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let poll = Pin::new(&mut *self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = poll {
+            self.written += written as u64;
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_shutdown(cx)
+    }
+}
+
+#[async_trait::async_trait]
+impl<S> RemoteStorage for MirroredStorage<S>
+where
+    S: RemoteStorage + Send + Sync + 'static,
+    S::RemoteObjectId: std::fmt::Debug + Send + Sync,
+{
+    type RemoteObjectId = S::RemoteObjectId;
+
+    fn remote_object_id(&self, local_path: &Path) -> anyhow::Result<Self::RemoteObjectId> {
+        self.primary.remote_object_id(local_path)
+    }
+
+    fn local_path(&self, remote_object_id: &Self::RemoteObjectId) -> anyhow::Result<PathBuf> {
+        self.primary.local_path(remote_object_id)
+    }
+
+    /// Listing a storage fails only when it doesn't respond: an empty storage lists no entries.
+    async fn list(&self) -> anyhow::Result<Vec<Self::RemoteObjectId>> {
+        match self.primary.list().await {
+            Ok(remote_object_ids) => Ok(remote_object_ids),
+            Err(e) => {
+                warn!("Failed to list the primary storage, listing the secondary one: {e:#}");
+                metrics::MIRROR_FALLBACK_READS.inc();
+                self.secondary
+                    .list_local_paths()
+                    .await?
+                    .iter()
+                    .map(|local_path| self.primary.remote_object_id(local_path))
+                    .collect()
+            }
+        }
+    }
+
+    async fn object_size(&self, id: &Self::RemoteObjectId) -> anyhow::Result<Option<u64>> {
+        // Used to check what the primary storage has, the secondary one may be behind
+        self.primary.object_size(id).await
+    }
+
+    async fn upload(
+        &self,
+        mut from: impl io::AsyncRead + Unpin + Send + Sync + 'static,
+        from_size_bytes: usize,
+        to: &Self::RemoteObjectId,
+        metadata: Option<StorageMetadata>,
+    ) -> anyhow::Result<()> {
+        let local_path = match self.mirrored_local_path(to) {
+            Some(local_path) => local_path,
+            None => {
+                return self
+                    .primary
+                    .upload(from, from_size_bytes, to, metadata)
+                    .await
+            }
+        };
+
+        let is_local_file = matches!(
+            fs::metadata(&local_path).await,
+            Ok(file_metadata) if file_metadata.is_file() && file_metadata.len() == from_size_bytes as u64
+        );
+        if is_local_file {
+            self.primary
+                .upload(from, from_size_bytes, to, metadata.clone())
+                .await?;
+            self.enqueue(MirrorOperation::UploadFile {
+                local_path,
+                metadata,
+            });
+        } else {
+            let mut bytes = Vec::with_capacity(from_size_bytes);
+            from.read_to_end(&mut bytes)
+                .await
+                .context("Failed to read the contents to upload")?;
+            self.primary
+                .upload(
+                    std::io::Cursor::new(bytes.clone()),
+                    from_size_bytes,
+                    to,
+                    metadata.clone(),
+                )
+                .await?;
+            self.enqueue(MirrorOperation::UploadBytes {
+                local_path,
+                bytes,
+                metadata,
+            });
+        }
+        Ok(())
+    }
+
+    async fn download(
+        &self,
+        from: &Self::RemoteObjectId,
+        to: &mut (impl io::AsyncWrite + Unpin + Send + Sync),
+    ) -> anyhow::Result<Option<StorageMetadata>> {
+        let mut counting_writer = CountingWriter {
+            inner: to,
+            written: 0,
+        };
+        match self.primary.download(from, &mut counting_writer).await {
+            Ok(metadata) => Ok(metadata),
+            Err(e) if counting_writer.written == 0 => {
+                if self.primary_lacks(from).await {
+                    return Err(e);
+                }
+                warn!("Failed to download {from:?} from the primary storage, downloading it from the secondary one: {e:#}");
+                metrics::MIRROR_FALLBACK_READS.inc();
+                let local_path = self.primary.local_path(from)?;
+                self.secondary.download_local_path(&local_path, to).await
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn download_byte_range(
+        &self,
+        from: &Self::RemoteObjectId,
+        start_inclusive: u64,
+        end_exclusive: Option<u64>,
+        to: &mut (impl io::AsyncWrite + Unpin + Send + Sync),
+    ) -> anyhow::Result<Option<StorageMetadata>> {
+        let mut counting_writer = CountingWriter {
+            inner: to,
+            written: 0,
+        };
+        match self
+            .primary
+            .download_byte_range(from, start_inclusive, end_exclusive, &mut counting_writer)
+            .await
+        {
+            Ok(metadata) => Ok(metadata),
+            Err(e) if counting_writer.written == 0 => {
+                if self.primary_lacks(from).await {
+                    return Err(e);
+                }
+                warn!("Failed to download {from:?} from the primary storage, downloading it from the secondary one: {e:#}");
+                metrics::MIRROR_FALLBACK_READS.inc();
+                let local_path = self.primary.local_path(from)?;
+                self.secondary
+                    .download_byte_range_local_path(&local_path, start_inclusive, end_exclusive, to)
+                    .await
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn delete(&self, path: &Self::RemoteObjectId) -> anyhow::Result<()> {
+        self.primary.delete(path).await?;
+        if let Some(local_path) = self.mirrored_local_path(path) {
+            self.enqueue(MirrorOperation::Delete {
+                local_paths: vec![local_path],
+            });
+        }
+        Ok(())
+    }
+
+    async fn delete_objects(&self, paths: &[Self::RemoteObjectId]) -> anyhow::Result<()> {
+        self.primary.delete_objects(paths).await?;
+        let local_paths = paths
+            .iter()
+            .filter_map(|path| self.mirrored_local_path(path))
+            .collect::<Vec<_>>();
+        if !local_paths.is_empty() {
+            self.enqueue(MirrorOperation::Delete { local_paths });
+        }
+        Ok(())
+    }
+
+    async fn start_multipart_upload(
+        &self,
+        to: &Self::RemoteObjectId,
+        metadata: Option<StorageMetadata>,
+    ) -> anyhow::Result<String> {
+        let upload_id = self
+            .primary
+            .start_multipart_upload(to, metadata.clone())
+            .await?;
+        if let Some(metadata) = metadata {
+            self.upload_metadata
+                .lock()
+                .unwrap()
+                .insert(upload_id.clone(), metadata);
+        }
+        Ok(upload_id)
+    }
+
+    async fn upload_part(
+        &self,
+        from: impl io::AsyncRead + Unpin + Send + Sync + 'static,
+        from_size_bytes: usize,
+        to: &Self::RemoteObjectId,
+        upload_id: &str,
+        part_number: u32,
+    ) -> anyhow::Result<String> {
+        self.primary
+            .upload_part(from, from_size_bytes, to, upload_id, part_number)
+            .await
+    }
+
+    /// The whole file is mirrored once the upload is completed, in one piece: the secondary storage gets
+    /// the files long after the primary one, there's no interrupted upload to resume there.
+    async fn complete_multipart_upload(
+        &self,
+        to: &Self::RemoteObjectId,
+        upload_id: &str,
+        parts: &[UploadedPart],
+    ) -> anyhow::Result<()> {
+        self.primary
+            .complete_multipart_upload(to, upload_id, parts)
+            .await?;
+        let metadata = self.upload_metadata.lock().unwrap().remove(upload_id);
+        if let Some(local_path) = self.mirrored_local_path(to) {
+            self.enqueue(MirrorOperation::UploadFile {
+                local_path,
+                metadata,
+            });
+        }
+        Ok(())
+    }
+
+    async fn abort_multipart_upload(
+        &self,
+        to: &Self::RemoteObjectId,
+        upload_id: &str,
+    ) -> anyhow::Result<()> {
+        self.primary.abort_multipart_upload(to, upload_id).await?;
+        self.upload_metadata.lock().unwrap().remove(upload_id);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::LocalFs;
+
+    async fn wait_for_mirror<S: RemoteStorage>(storage: &MirroredStorage<S>) {
+        while storage.lag().pending_operations > 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    async fn read_storage_file(storage: &GenericRemoteStorage, local_path: &Path) -> Vec<u8> {
+        let mut contents = Vec::new();
+        storage
+            .download_local_path(local_path, &mut contents)
+            .await
+            .unwrap();
+        contents
+    }
+
+    #[tokio::test]
+    async fn mirror_changes_to_secondary() -> anyhow::Result<()> {
+        let tempdir = tempdir()?;
+        let workdir = tempdir.path().join("workdir");
+        let primary_root = tempdir.path().join("primary");
+        let secondary_root = tempdir.path().join("secondary");
+        std::fs::create_dir_all(&workdir)?;
+
+        let storage = MirroredStorage::new(
+            LocalFs::new(primary_root.clone(), workdir.clone())?,
+            GenericRemoteStorage::Local(LocalFs::new(secondary_root.clone(), workdir.clone())?),
+            |local_path| !local_path.ends_with("not_mirrored"),
+        );
+        let secondary = GenericRemoteStorage::Local(LocalFs::new(secondary_root, workdir.clone())?);
+
+        // A local file, and contents uploaded from memory
+        let layer_path = workdir.join("layer");
+        std::fs::write(&layer_path, b"layer contents")?;
+        storage
+            .upload(
+                fs::File::open(&layer_path).await?,
+                14,
+                &storage.remote_object_id(&layer_path)?,
+                None,
+            )
+            .await?;
+        let index_path = workdir.join("index");
+        storage
+            .upload(
+                std::io::Cursor::new(b"index".to_vec()),
+                5,
+                &storage.remote_object_id(&index_path)?,
+                None,
+            )
+            .await?;
+        let not_mirrored_path = workdir.join("not_mirrored");
+        storage
+            .upload(
+                std::io::Cursor::new(b"data".to_vec()),
+                4,
+                &storage.remote_object_id(&not_mirrored_path)?,
+                None,
+            )
+            .await?;
+
+        wait_for_mirror(&storage).await;
+        assert_eq!(
+            read_storage_file(&secondary, &layer_path).await,
+            b"layer contents"
+        );
+        assert_eq!(read_storage_file(&secondary, &index_path).await, b"index");
+        assert_eq!(
+            secondary.list_local_paths().await?.len(),
+            2,
+            "Only the accepted entries should be mirrored"
+        );
+
+        storage
+            .delete_objects(&[storage.remote_object_id(&layer_path)?])
+            .await?;
+        wait_for_mirror(&storage).await;
+        assert_eq!(secondary.list_local_paths().await?, vec![index_path]);
+        assert_eq!(storage.lag().oldest_pending, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn read_from_secondary() -> anyhow::Result<()> {
+        let tempdir = tempdir()?;
+        let workdir = tempdir.path().join("workdir");
+        let primary_root = tempdir.path().join("primary");
+        let secondary_root = tempdir.path().join("secondary");
+        std::fs::create_dir_all(&workdir)?;
+
+        let storage = MirroredStorage::new(
+            LocalFs::new(primary_root.clone(), workdir.clone())?,
+            GenericRemoteStorage::Local(LocalFs::new(secondary_root, workdir.clone())?),
+            |_| true,
+        );
+
+        let index_path = workdir.join("index");
+        let index_id = storage.remote_object_id(&index_path)?;
+        storage
+            .upload(std::io::Cursor::new(b"index".to_vec()), 5, &index_id, None)
+            .await?;
+        wait_for_mirror(&storage).await;
+
+        // An entry that the primary storage doesn't have is deleted, not read from the secondary one
+        std::fs::remove_file(&index_id)?;
+        let mut contents = Vec::new();
+        assert!(storage.download(&index_id, &mut contents).await.is_err());
+        assert!(storage
+            .download_byte_range(&index_id, 1, Some(3), &mut contents)
+            .await
+            .is_err());
+
+        // The primary storage fails to read anything
+        std::fs::remove_dir_all(&primary_root)?;
+        std::fs::write(&primary_root, b"not a directory")?;
+        let mut contents = Vec::new();
+        storage.download(&index_id, &mut contents).await?;
+        assert_eq!(contents, b"index");
+
+        let mut contents = Vec::new();
+        storage
+            .download_byte_range(&index_id, 1, Some(3), &mut contents)
+            .await?;
+        assert_eq!(contents, b"nd");
+
+        assert_eq!(storage.list().await?, vec![index_id]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn mirror_file_removed_locally() -> anyhow::Result<()> {
+        let tempdir = tempdir()?;
+        let workdir = tempdir.path().join("workdir");
+        let primary_root = tempdir.path().join("primary");
+        let secondary_root = tempdir.path().join("secondary");
+        std::fs::create_dir_all(&workdir)?;
+
+        let storage = MirroredStorage::new(
+            LocalFs::new(primary_root, workdir.clone())?,
+            GenericRemoteStorage::Local(LocalFs::new(secondary_root.clone(), workdir.clone())?),
+            |_| true,
+        );
+        let secondary = GenericRemoteStorage::Local(LocalFs::new(secondary_root, workdir.clone())?);
+
+        let layer_path = workdir.join("layer");
+        std::fs::write(&layer_path, b"layer contents")?;
+        storage
+            .upload(
+                fs::File::open(&layer_path).await?,
+                14,
+                &storage.remote_object_id(&layer_path)?,
+                None,
+            )
+            .await?;
+        // Evicted before the background task got to it
+        std::fs::remove_file(&layer_path)?;
+
+        wait_for_mirror(&storage).await;
+        assert_eq!(
+            read_storage_file(&secondary, &layer_path).await,
+            b"layer contents",
+            "The file should be copied from the primary storage"
+        );
+
+        Ok(())
+    }
+}
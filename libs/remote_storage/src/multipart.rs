@@ -0,0 +1,595 @@
+//! Resumable uploads of big files, in parts.
+//!
+//! A file is split into parts of a configured size, and every part is uploaded separately.
+//! After each part, the upload progress is written into a small state file next to the source file,
+//! so if the upload is interrupted (by a network error or a process restart), the next attempt
+//! only uploads the parts that are missing, instead of starting over.
+//!
+//! A file can also be uploaded while it is still being written, see [`StreamingUpload`].
+
+use std::{cmp, io::ErrorKind, num::NonZeroUsize, ops::Range, path::Path};
+
+use anyhow::{ensure, Context};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs,
+    io::{self, AsyncReadExt, AsyncSeekExt},
+};
+use tracing::{debug, info, warn};
+
+use crate::{path_with_suffix_extension, RemoteStorage, StorageMetadata};
+
+/// AWS S3 allows at most that many parts in a single upload.
+const MAX_PARTS_PER_UPLOAD: u64 = 10_000;
+
+/// If the resumed upload keeps failing without a single part uploaded, it might not exist in the storage anymore
+/// (e.g. aborted by a bucket lifecycle rule), start it over then.
+const MAX_ATTEMPTS_WITHOUT_PROGRESS: u32 = 3;
+
+/// A part of a multipart upload that's stored in the remote storage already.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UploadedPart {
+    pub part_number: u32,
+    /// A tag the storage returned for the part, needed to complete the upload.
+    pub etag: String,
+}
+
+/// Upload progress, persisted after every part.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct MultipartUploadState {
+    upload_id: String,
+    file_size: u64,
+    part_size: u64,
+    uploaded_parts: Vec<UploadedPart>,
+    attempts_without_progress: u32,
+}
+
+impl MultipartUploadState {
+    async fn load(state_path: &Path) -> anyhow::Result<Option<Self>> {
+        let state_bytes = match fs::read(state_path).await {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(e).with_context(|| {
+                    format!(
+                        "Failed to read upload state file '{}'",
+                        state_path.display()
+                    )
+                })
+            }
+        };
+
+        match serde_json::from_slice(&state_bytes) {
+            Ok(state) => Ok(Some(state)),
+            Err(e) => {
+                // Not worth failing over: the worst thing that can happen is the whole file uploaded again.
+                warn!(
+                    "Ignoring malformed upload state file '{}': {e}",
+                    state_path.display()
+                );
+                Ok(None)
+            }
+        }
+    }
+
+    /// Writes the state into a temporary file and renames it in place, so that a crash never leaves a torn state behind.
+    async fn persist(&self, state_path: &Path) -> anyhow::Result<()> {
+        let temp_path = path_with_suffix_extension(state_path, "temp");
+        fs::write(
+            &temp_path,
+            serde_json::to_vec(self).context("Failed to serialize upload state")?,
+        )
+        .await
+        .with_context(|| {
+            format!(
+                "Failed to write upload state file '{}'",
+                temp_path.display()
+            )
+        })?;
+        fs::rename(&temp_path, state_path).await.with_context(|| {
+            format!(
+                "Failed to rename upload state file into '{}'",
+                state_path.display()
+            )
+        })
+    }
+
+    fn has_part(&self, part_number: u32) -> bool {
+        self.uploaded_parts
+            .iter()
+            .any(|part| part.part_number == part_number)
+    }
+}
+
+/// Uploads the local file into the remote storage in parts of `part_size` bytes.
+///
+/// The upload progress is recorded in the file at `state_path` after every part. If that file is left from
+/// a previous, interrupted attempt to upload the same file, the upload is resumed with the parts not uploaded yet.
+/// The state file is removed after the upload completes.
+///
+/// The local file must not change while the upload is in progress, including the time between the attempts.
+/// The `metadata` is stored with the remote file, as with [`RemoteStorage::upload`]; a resumed upload keeps the
+/// metadata it was started with.
+pub async fn upload_file_in_parts<S>(
+    storage: &S,
+    from_path: &Path,
+    to: &S::RemoteObjectId,
+    part_size: NonZeroUsize,
+    state_path: &Path,
+    metadata: Option<StorageMetadata>,
+) -> anyhow::Result<()>
+where
+    S: RemoteStorage,
+    S::RemoteObjectId: Sync,
+{
+    let file_size = fs::metadata(from_path)
+        .await
+        .with_context(|| {
+            format!(
+                "Failed to get the size of file '{}' to upload",
+                from_path.display()
+            )
+        })?
+        .len();
+    let part_size = part_size.get() as u64;
+    let part_count = cmp::max(1, (file_size + part_size - 1) / part_size);
+    ensure!(
+        part_count <= MAX_PARTS_PER_UPLOAD,
+        "File '{}' of {file_size} bytes needs {part_count} parts of {part_size} bytes, more than the {MAX_PARTS_PER_UPLOAD} allowed",
+        from_path.display(),
+    );
+
+    let mut state = match MultipartUploadState::load(state_path).await? {
+        Some(state)
+            if state.file_size == file_size
+                && state.part_size == part_size
+                && state.attempts_without_progress < MAX_ATTEMPTS_WITHOUT_PROGRESS =>
+        {
+            info!(
+                "Resuming upload of file '{}', {} out of {part_count} parts are uploaded already",
+                from_path.display(),
+                state.uploaded_parts.len(),
+            );
+            state
+        }
+        Some(stale_state) => {
+            info!(
+                "Starting the upload of file '{}' over, previous upload {} cannot be resumed",
+                from_path.display(),
+                stale_state.upload_id
+            );
+            if let Err(e) = storage
+                .abort_multipart_upload(to, &stale_state.upload_id)
+                .await
+            {
+                warn!(
+                    "Failed to abort stale upload {}: {e:?}",
+                    stale_state.upload_id
+                );
+            }
+            start_upload(storage, to, file_size, part_size, state_path, metadata).await?
+        }
+        None => start_upload(storage, to, file_size, part_size, state_path, metadata).await?,
+    };
+
+    let upload_result = async {
+        for part_number in 1..=part_count as u32 {
+            if state.has_part(part_number) {
+                continue;
+            }
+            let part_start = (part_number as u64 - 1) * part_size;
+            let part_len = cmp::min(part_size, file_size - part_start);
+
+            let etag = upload_file_part(
+                storage,
+                from_path,
+                to,
+                &state.upload_id,
+                part_number,
+                part_start..part_start + part_len,
+            )
+            .await?;
+            debug!(
+                "Uploaded part {part_number} out of {part_count} of file '{}'",
+                from_path.display()
+            );
+
+            state
+                .uploaded_parts
+                .push(UploadedPart { part_number, etag });
+            state.attempts_without_progress = 0;
+            state.persist(state_path).await?;
+        }
+
+        state.uploaded_parts.sort_by_key(|part| part.part_number);
+        storage
+            .complete_multipart_upload(to, &state.upload_id, &state.uploaded_parts)
+            .await
+            .with_context(|| {
+                format!(
+                    "Failed to complete the upload of file '{}'",
+                    from_path.display()
+                )
+            })
+    }
+    .await;
+
+    match upload_result {
+        Ok(()) => match fs::remove_file(state_path).await {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e).with_context(|| {
+                format!(
+                    "Failed to remove upload state file '{}'",
+                    state_path.display()
+                )
+            }),
+        },
+        Err(e) => {
+            state.attempts_without_progress += 1;
+            if let Err(persist_error) = state.persist(state_path).await {
+                warn!("Failed to record the failed upload attempt: {persist_error:?}");
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Uploads the given byte range of the local file as a part of the upload, returns the part's tag.
+async fn upload_file_part<S>(
+    storage: &S,
+    from_path: &Path,
+    to: &S::RemoteObjectId,
+    upload_id: &str,
+    part_number: u32,
+    part_range: Range<u64>,
+) -> anyhow::Result<String>
+where
+    S: RemoteStorage,
+    S::RemoteObjectId: Sync,
+{
+    let mut source_file = fs::File::open(from_path)
+        .await
+        .with_context(|| format!("Failed to open file '{}' to upload", from_path.display()))?;
+    source_file
+        .seek(io::SeekFrom::Start(part_range.start))
+        .await
+        .context("Failed to seek to the part start in the file to upload")?;
+
+    let part_len = part_range.end - part_range.start;
+    storage
+        .upload_part(
+            source_file.take(part_len),
+            part_len as usize,
+            to,
+            upload_id,
+            part_number,
+        )
+        .await
+        .with_context(|| {
+            format!(
+                "Failed to upload part {part_number} of file '{}'",
+                from_path.display()
+            )
+        })
+}
+
+/// An upload in parts of a file that is still being written.
+///
+/// Every part the writer is done with can be uploaded right away with [`StreamingUpload::upload_part`],
+/// in any order. When the file is complete, [`StreamingUpload::finish`] records the uploaded parts in the
+/// state file of [`upload_file_in_parts`], which then only uploads the rest of the file.
+/// A part the upload of which fails is left for [`upload_file_in_parts`] too.
+#[derive(Debug)]
+pub struct StreamingUpload {
+    upload_id: String,
+    part_size: u64,
+    uploaded_parts: Vec<UploadedPart>,
+}
+
+impl StreamingUpload {
+    /// Starts the upload, the `metadata` is stored with the remote file once it's complete.
+    pub async fn start<S>(
+        storage: &S,
+        to: &S::RemoteObjectId,
+        part_size: NonZeroUsize,
+        metadata: Option<StorageMetadata>,
+    ) -> anyhow::Result<Self>
+    where
+        S: RemoteStorage,
+        S::RemoteObjectId: Sync,
+    {
+        let upload_id = storage
+            .start_multipart_upload(to, metadata)
+            .await
+            .context("Failed to start a multipart upload")?;
+        Ok(Self {
+            upload_id,
+            part_size: part_size.get() as u64,
+            uploaded_parts: Vec::new(),
+        })
+    }
+
+    /// Uploads the part with the given number, which has to be fully written into the file at `from_path` already
+    /// and must not change any more.
+    pub async fn upload_part<S>(
+        &mut self,
+        storage: &S,
+        from_path: &Path,
+        to: &S::RemoteObjectId,
+        part_number: u32,
+    ) -> anyhow::Result<()>
+    where
+        S: RemoteStorage,
+        S::RemoteObjectId: Sync,
+    {
+        ensure!(part_number >= 1, "Part numbers start from 1");
+        ensure!(
+            part_number as u64 <= MAX_PARTS_PER_UPLOAD,
+            "Part {part_number} is over the {MAX_PARTS_PER_UPLOAD} parts allowed"
+        );
+        let part_start = (part_number as u64 - 1) * self.part_size;
+        let etag = upload_file_part(
+            storage,
+            from_path,
+            to,
+            &self.upload_id,
+            part_number,
+            part_start..part_start + self.part_size,
+        )
+        .await?;
+        self.uploaded_parts
+            .retain(|part| part.part_number != part_number);
+        self.uploaded_parts.push(UploadedPart { part_number, etag });
+        Ok(())
+    }
+
+    /// Number of the parts uploaded so far.
+    pub fn uploaded_parts(&self) -> usize {
+        self.uploaded_parts.len()
+    }
+
+    /// Records the uploaded parts in the state file, for [`upload_file_in_parts`] to upload the rest of the
+    /// complete file of `file_size` bytes with the same part size.
+    pub async fn finish(self, file_size: u64, state_path: &Path) -> anyhow::Result<()> {
+        ensure!(
+            self.uploaded_parts
+                .iter()
+                .all(|part| part.part_number as u64 * self.part_size <= file_size),
+            "File of {file_size} bytes is shorter than its uploaded parts"
+        );
+        MultipartUploadState {
+            upload_id: self.upload_id,
+            file_size,
+            part_size: self.part_size,
+            uploaded_parts: self.uploaded_parts,
+            attempts_without_progress: 0,
+        }
+        .persist(state_path)
+        .await
+    }
+
+    /// Discards the upload, when the file is not going to be complete.
+    pub async fn abort<S>(self, storage: &S, to: &S::RemoteObjectId) -> anyhow::Result<()>
+    where
+        S: RemoteStorage,
+        S::RemoteObjectId: Sync,
+    {
+        storage.abort_multipart_upload(to, &self.upload_id).await
+    }
+}
+
+async fn start_upload<S>(
+    storage: &S,
+    to: &S::RemoteObjectId,
+    file_size: u64,
+    part_size: u64,
+    state_path: &Path,
+    metadata: Option<StorageMetadata>,
+) -> anyhow::Result<MultipartUploadState>
+where
+    S: RemoteStorage,
+    S::RemoteObjectId: Sync,
+{
+    let upload_id = storage
+        .start_multipart_upload(to, metadata)
+        .await
+        .context("Failed to start a multipart upload")?;
+    let state = MultipartUploadState {
+        upload_id,
+        file_size,
+        part_size,
+        uploaded_parts: Vec::new(),
+        attempts_without_progress: 0,
+    };
+    state.persist(state_path).await?;
+    Ok(state)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, path::PathBuf};
+
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::LocalFs;
+
+    fn test_contents() -> Vec<u8> {
+        (0..1000u32).flat_map(|i| i.to_le_bytes()).collect()
+    }
+
+    async fn download_contents(storage: &LocalFs, path: &PathBuf) -> anyhow::Result<Vec<u8>> {
+        let mut contents = Vec::new();
+        storage.download(path, &mut contents).await?;
+        Ok(contents)
+    }
+
+    #[tokio::test]
+    async fn upload_resumes_after_interruption() -> anyhow::Result<()> {
+        let workdir = tempdir()?;
+        let storage = LocalFs::new(tempdir()?.path().to_owned(), workdir.path().to_owned())?;
+
+        let contents = test_contents();
+        let from_path = workdir.path().join("big_file");
+        std::fs::write(&from_path, &contents)?;
+        let state_path = workdir.path().join("big_file.upload_state");
+        let to = storage.remote_object_id(&from_path)?;
+        let part_size = NonZeroUsize::new(1024).unwrap();
+
+        // Pretend the first two parts were uploaded before the process got restarted.
+        let upload_id = storage.start_multipart_upload(&to, None).await?;
+        let mut uploaded_parts = Vec::new();
+        for part_number in 1..=2 {
+            let part =
+                contents[(part_number as usize - 1) * 1024..part_number as usize * 1024].to_vec();
+            let etag = storage
+                .upload_part(
+                    std::io::Cursor::new(part),
+                    1024,
+                    &to,
+                    &upload_id,
+                    part_number,
+                )
+                .await?;
+            uploaded_parts.push(UploadedPart { part_number, etag });
+        }
+        MultipartUploadState {
+            upload_id,
+            file_size: contents.len() as u64,
+            part_size: 1024,
+            uploaded_parts,
+            attempts_without_progress: 0,
+        }
+        .persist(&state_path)
+        .await?;
+
+        upload_file_in_parts(&storage, &from_path, &to, part_size, &state_path, None).await?;
+
+        assert_eq!(download_contents(&storage, &to).await?, contents);
+        assert!(
+            !state_path.exists(),
+            "Upload state should be removed after the upload completes"
+        );
+        assert_eq!(
+            storage.list().await?,
+            vec![to],
+            "Only the uploaded file should be listed, without any parts"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn streaming_upload_of_growing_file() -> anyhow::Result<()> {
+        let workdir = tempdir()?;
+        let storage = LocalFs::new(tempdir()?.path().to_owned(), workdir.path().to_owned())?;
+
+        let contents = test_contents();
+        let from_path = workdir.path().join("big_file");
+        let state_path = workdir.path().join("big_file.upload_state");
+        let to = storage.remote_object_id(&from_path)?;
+        let part_size = NonZeroUsize::new(1024).unwrap();
+
+        // The file is written with a placeholder for its header, that is filled in at the end,
+        // so only the parts after the first one are streamed.
+        let mut upload = StreamingUpload::start(&storage, &to, part_size, None).await?;
+        let mut written = vec![0; 1024];
+        for part_number in 2..=3 {
+            written.extend_from_slice(&contents[(part_number - 1) * 1024..part_number * 1024]);
+            std::fs::write(&from_path, &written)?;
+            upload
+                .upload_part(&storage, &from_path, &to, part_number as u32)
+                .await?;
+        }
+        assert!(
+            upload
+                .upload_part(&storage, &from_path, &to, 4)
+                .await
+                .is_err(),
+            "Parts not written yet should not be uploaded"
+        );
+        assert_eq!(upload.uploaded_parts(), 2);
+
+        std::fs::write(&from_path, &contents)?;
+        upload.finish(contents.len() as u64, &state_path).await?;
+        upload_file_in_parts(&storage, &from_path, &to, part_size, &state_path, None).await?;
+
+        assert_eq!(download_contents(&storage, &to).await?, contents);
+        assert!(!state_path.exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn upload_starts_over_on_mismatching_state() -> anyhow::Result<()> {
+        let workdir = tempdir()?;
+        let storage = LocalFs::new(tempdir()?.path().to_owned(), workdir.path().to_owned())?;
+
+        let contents = test_contents();
+        let from_path = workdir.path().join("big_file");
+        std::fs::write(&from_path, &contents)?;
+        let state_path = workdir.path().join("big_file.upload_state");
+        let to = storage.remote_object_id(&from_path)?;
+
+        // A state left from an upload with another part size cannot be resumed.
+        let stale_upload_id = storage.start_multipart_upload(&to, None).await?;
+        MultipartUploadState {
+            upload_id: stale_upload_id,
+            file_size: contents.len() as u64,
+            part_size: 4096,
+            uploaded_parts: vec![UploadedPart {
+                part_number: 1,
+                etag: "missing".to_string(),
+            }],
+            attempts_without_progress: 0,
+        }
+        .persist(&state_path)
+        .await?;
+
+        upload_file_in_parts(
+            &storage,
+            &from_path,
+            &to,
+            NonZeroUsize::new(1000).unwrap(),
+            &state_path,
+            None,
+        )
+        .await?;
+
+        assert_eq!(download_contents(&storage, &to).await?, contents);
+        assert!(!state_path.exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn upload_in_parts_keeps_metadata() -> anyhow::Result<()> {
+        let workdir = tempdir()?;
+        let storage = LocalFs::new(tempdir()?.path().to_owned(), workdir.path().to_owned())?;
+
+        let contents = test_contents();
+        let from_path = workdir.path().join("big_file");
+        std::fs::write(&from_path, &contents)?;
+        let state_path = workdir.path().join("big_file.upload_state");
+        let to = storage.remote_object_id(&from_path)?;
+        let metadata = StorageMetadata(HashMap::from([("one".to_string(), "1".to_string())]));
+
+        upload_file_in_parts(
+            &storage,
+            &from_path,
+            &to,
+            NonZeroUsize::new(1024).unwrap(),
+            &state_path,
+            Some(metadata.clone()),
+        )
+        .await?;
+
+        let mut downloaded = Vec::new();
+        let downloaded_metadata = storage.download(&to, &mut downloaded).await?;
+        assert_eq!(downloaded, contents);
+        assert_eq!(
+            downloaded_metadata,
+            Some(metadata),
+            "The metadata should be stored with a file uploaded in parts"
+        );
+        Ok(())
+    }
+}
@@ -6,20 +6,22 @@
 
 use std::path::{Path, PathBuf};
 
-use anyhow::Context;
+use anyhow::{bail, Context};
 use rusoto_core::{
     credential::{InstanceMetadataProvider, StaticProvider},
-    HttpClient, Region,
+    HttpClient, Region, RusotoError,
 };
 use rusoto_s3::{
-    DeleteObjectRequest, GetObjectRequest, ListObjectsV2Request, PutObjectRequest, S3Client,
-    StreamingBody, S3,
+    AbortMultipartUploadRequest, CompleteMultipartUploadRequest, CompletedMultipartUpload,
+    CompletedPart, CreateMultipartUploadRequest, Delete, DeleteObjectRequest, DeleteObjectsRequest,
+    GetObjectRequest, HeadObjectError, HeadObjectRequest, ListObjectsV2Request, ObjectIdentifier,
+    PutObjectRequest, S3Client, StreamingBody, UploadPartRequest, S3,
 };
 use tokio::{io, sync::Semaphore};
 use tokio_util::io::ReaderStream;
 use tracing::debug;
 
-use crate::{strip_path_prefix, RemoteStorage, S3Config};
+use crate::{strip_path_prefix, RemoteStorage, S3Config, UploadedPart};
 
 use super::StorageMetadata;
 
@@ -65,6 +67,16 @@ pub(super) mod metrics {
             .inc();
     }
 
+    pub fn inc_head_object() {
+        S3_REQUESTS_COUNT.with_label_values(&["head_object"]).inc();
+    }
+
+    pub fn inc_head_object_fail() {
+        S3_REQUESTS_FAIL_COUNT
+            .with_label_values(&["head_object"])
+            .inc();
+    }
+
     pub fn inc_delete_object() {
         S3_REQUESTS_COUNT
             .with_label_values(&["delete_object"])
@@ -77,6 +89,18 @@ pub(super) mod metrics {
             .inc();
     }
 
+    pub fn inc_delete_objects() {
+        S3_REQUESTS_COUNT
+            .with_label_values(&["delete_objects"])
+            .inc();
+    }
+
+    pub fn inc_delete_objects_fail() {
+        S3_REQUESTS_FAIL_COUNT
+            .with_label_values(&["delete_objects"])
+            .inc();
+    }
+
     pub fn inc_list_objects() {
         S3_REQUESTS_COUNT.with_label_values(&["list_objects"]).inc();
     }
@@ -86,10 +110,24 @@ pub(super) mod metrics {
             .with_label_values(&["list_objects"])
             .inc();
     }
+
+    /// `request_type` is one of the multipart upload requests: create, upload part, complete or abort.
+    pub fn inc_multipart_upload(request_type: &str) {
+        S3_REQUESTS_COUNT.with_label_values(&[request_type]).inc();
+    }
+
+    pub fn inc_multipart_upload_fail(request_type: &str) {
+        S3_REQUESTS_FAIL_COUNT
+            .with_label_values(&[request_type])
+            .inc();
+    }
 }
 
 const S3_PREFIX_SEPARATOR: char = '/';
 
+/// Max number of keys a single DeleteObjects request can take.
+const MAX_KEYS_PER_DELETE: usize = 1000;
+
 #[derive(Debug, Eq, PartialEq, PartialOrd, Ord, Hash)]
 pub struct S3ObjectKey(String);
 
@@ -250,6 +288,38 @@ impl RemoteStorage for S3Bucket {
         Ok(document_keys)
     }
 
+    async fn object_size(&self, id: &Self::RemoteObjectId) -> anyhow::Result<Option<u64>> {
+        let _guard = self
+            .concurrency_limiter
+            .acquire()
+            .await
+            .context("Concurrency limiter semaphore got closed during S3 head")?;
+
+        metrics::inc_head_object();
+        match self
+            .client
+            .head_object(HeadObjectRequest {
+                bucket: self.bucket_name.clone(),
+                key: id.key().to_owned(),
+                ..HeadObjectRequest::default()
+            })
+            .await
+        {
+            Ok(output) => Ok(Some(
+                output
+                    .content_length
+                    .context("S3 returned no content length")? as u64,
+            )),
+            // A HEAD response has no body to tell the error with, only the status code
+            Err(RusotoError::Service(HeadObjectError::NoSuchKey(_))) => Ok(None),
+            Err(RusotoError::Unknown(response)) if response.status.as_u16() == 404 => Ok(None),
+            Err(e) => {
+                metrics::inc_head_object_fail();
+                Err(e.into())
+            }
+        }
+    }
+
     async fn upload(
         &self,
         from: impl io::AsyncRead + Unpin + Send + Sync + 'static,
@@ -383,6 +453,184 @@ impl RemoteStorage for S3Bucket {
             })?;
         Ok(())
     }
+
+    async fn delete_objects(&self, paths: &[Self::RemoteObjectId]) -> anyhow::Result<()> {
+        // No version ids are given, so in a versioned bucket, the objects get delete markers
+        // and the bucket's lifecycle rules decide when their data is removed.
+        for chunk in paths.chunks(MAX_KEYS_PER_DELETE) {
+            let _guard = self
+                .concurrency_limiter
+                .acquire()
+                .await
+                .context("Concurrency limiter semaphore got closed during S3 delete objects")?;
+
+            metrics::inc_delete_objects();
+
+            let response = self
+                .client
+                .delete_objects(DeleteObjectsRequest {
+                    bucket: self.bucket_name.clone(),
+                    delete: Delete {
+                        objects: chunk
+                            .iter()
+                            .map(|path| ObjectIdentifier {
+                                key: path.key().to_owned(),
+                                version_id: None,
+                            })
+                            .collect(),
+                        quiet: Some(true),
+                    },
+                    ..DeleteObjectsRequest::default()
+                })
+                .await
+                .map_err(|e| {
+                    metrics::inc_delete_objects_fail();
+                    e
+                })?;
+
+            // Deleting a missing key is not an error, only the failures are reported
+            if let Some(errors) = response.errors.filter(|errors| !errors.is_empty()) {
+                metrics::inc_delete_objects_fail();
+                bail!(
+                    "Failed to delete {} out of {} objects, first error: {:?}",
+                    errors.len(),
+                    chunk.len(),
+                    errors[0]
+                );
+            }
+        }
+        Ok(())
+    }
+
+    async fn start_multipart_upload(
+        &self,
+        to: &Self::RemoteObjectId,
+        metadata: Option<StorageMetadata>,
+    ) -> anyhow::Result<String> {
+        let _guard =
+            self.concurrency_limiter.acquire().await.context(
+                "Concurrency limiter semaphore got closed during S3 multipart upload start",
+            )?;
+
+        metrics::inc_multipart_upload("create_multipart_upload");
+        self.client
+            .create_multipart_upload(CreateMultipartUploadRequest {
+                bucket: self.bucket_name.clone(),
+                key: to.key().to_owned(),
+                metadata: metadata.map(|m| m.0),
+                ..CreateMultipartUploadRequest::default()
+            })
+            .await
+            .map_err(|e| {
+                metrics::inc_multipart_upload_fail("create_multipart_upload");
+                e
+            })?
+            .upload_id
+            .context("S3 returned no upload id for a new multipart upload")
+    }
+
+    async fn upload_part(
+        &self,
+        from: impl io::AsyncRead + Unpin + Send + Sync + 'static,
+        from_size_bytes: usize,
+        to: &Self::RemoteObjectId,
+        upload_id: &str,
+        part_number: u32,
+    ) -> anyhow::Result<String> {
+        let _guard = self
+            .concurrency_limiter
+            .acquire()
+            .await
+            .context("Concurrency limiter semaphore got closed during S3 part upload")?;
+
+        metrics::inc_multipart_upload("upload_part");
+        self.client
+            .upload_part(UploadPartRequest {
+                body: Some(StreamingBody::new_with_size(
+                    ReaderStream::new(from),
+                    from_size_bytes,
+                )),
+                bucket: self.bucket_name.clone(),
+                key: to.key().to_owned(),
+                upload_id: upload_id.to_owned(),
+                part_number: i64::from(part_number),
+                content_length: Some(from_size_bytes as i64),
+                ..UploadPartRequest::default()
+            })
+            .await
+            .map_err(|e| {
+                metrics::inc_multipart_upload_fail("upload_part");
+                e
+            })?
+            .e_tag
+            .with_context(|| {
+                format!("S3 returned no ETag for part {part_number} of upload {upload_id}")
+            })
+    }
+
+    async fn complete_multipart_upload(
+        &self,
+        to: &Self::RemoteObjectId,
+        upload_id: &str,
+        parts: &[UploadedPart],
+    ) -> anyhow::Result<()> {
+        let _guard = self.concurrency_limiter.acquire().await.context(
+            "Concurrency limiter semaphore got closed during S3 multipart upload completion",
+        )?;
+
+        metrics::inc_multipart_upload("complete_multipart_upload");
+        self.client
+            .complete_multipart_upload(CompleteMultipartUploadRequest {
+                bucket: self.bucket_name.clone(),
+                key: to.key().to_owned(),
+                upload_id: upload_id.to_owned(),
+                multipart_upload: Some(CompletedMultipartUpload {
+                    parts: Some(
+                        parts
+                            .iter()
+                            .map(|part| CompletedPart {
+                                e_tag: Some(part.etag.clone()),
+                                part_number: Some(i64::from(part.part_number)),
+                                ..CompletedPart::default()
+                            })
+                            .collect(),
+                    ),
+                }),
+                ..CompleteMultipartUploadRequest::default()
+            })
+            .await
+            .map_err(|e| {
+                metrics::inc_multipart_upload_fail("complete_multipart_upload");
+                e
+            })?;
+        Ok(())
+    }
+
+    async fn abort_multipart_upload(
+        &self,
+        to: &Self::RemoteObjectId,
+        upload_id: &str,
+    ) -> anyhow::Result<()> {
+        let _guard =
+            self.concurrency_limiter.acquire().await.context(
+                "Concurrency limiter semaphore got closed during S3 multipart upload abort",
+            )?;
+
+        metrics::inc_multipart_upload("abort_multipart_upload");
+        self.client
+            .abort_multipart_upload(AbortMultipartUploadRequest {
+                bucket: self.bucket_name.clone(),
+                key: to.key().to_owned(),
+                upload_id: upload_id.to_owned(),
+                ..AbortMultipartUploadRequest::default()
+            })
+            .await
+            .map_err(|e| {
+                metrics::inc_multipart_upload_fail("abort_multipart_upload");
+                e
+            })?;
+        Ok(())
+    }
 }
 
 #[cfg(test)]
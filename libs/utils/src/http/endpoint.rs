@@ -137,6 +137,47 @@ pub fn auth_middleware<B: hyper::body::HttpBody + Send + Sync + 'static>(
     })
 }
 
+///
+/// Require HTTP basic auth for requests to `path`. `provide_credentials`
+/// returns the expected `user:password` pair, or None to let the requests
+/// through.
+///
+pub fn basic_auth_middleware<B: hyper::body::HttpBody + Send + Sync + 'static>(
+    path: &'static str,
+    provide_credentials: fn(&Request<Body>) -> Option<&str>,
+) -> Middleware<B, ApiError> {
+    Middleware::pre(move |req| async move {
+        if req.uri().path() != path {
+            return Ok(req);
+        }
+        if let Some(credentials) = provide_credentials(&req) {
+            let header_value = req
+                .headers()
+                .get(AUTHORIZATION)
+                .ok_or_else(|| ApiError::Unauthorized("missing authorization header".to_string()))?
+                .to_str()
+                .map_err(|_| {
+                    ApiError::Unauthorized("malformed authorization header".to_string())
+                })?;
+            if parse_basic_credentials(header_value)? != credentials {
+                return Err(ApiError::Unauthorized("invalid credentials".to_string()));
+            }
+        }
+        Ok(req)
+    })
+}
+
+fn parse_basic_credentials(header_value: &str) -> Result<String, ApiError> {
+    // header must be in form Basic <base64 of user:password>
+    let malformed = || ApiError::Unauthorized("malformed authorization header".to_string());
+    let (prefix, encoded) = header_value.split_once(' ').ok_or_else(malformed)?;
+    if prefix != "Basic" {
+        return Err(malformed());
+    }
+    let decoded = base64::decode(encoded.trim()).map_err(|_| malformed())?;
+    String::from_utf8(decoded).map_err(|_| malformed())
+}
+
 pub fn check_permission(req: &Request<Body>, tenantid: Option<ZTenantId>) -> Result<(), ApiError> {
     match req.context::<Claims>() {
         Some(claims) => Ok(auth::check_permission(&claims, tenantid)
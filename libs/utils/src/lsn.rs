@@ -240,6 +240,17 @@ impl MonotonicCounter<Lsn> for RecordLsn {
     }
 }
 
+/// A plain LSN as counter in SeqWait
+impl MonotonicCounter<Lsn> for Lsn {
+    fn cnt_advance(&mut self, lsn: Lsn) {
+        assert!(*self <= lsn);
+        *self = lsn;
+    }
+    fn cnt_value(&self) -> Lsn {
+        *self
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
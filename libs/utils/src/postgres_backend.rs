@@ -6,11 +6,11 @@
 use crate::pq_proto::{BeMessage, BeParameterStatusMessage, FeMessage, FeStartupPacket};
 use crate::sock_split::{BidiStream, ReadStream, WriteStream};
 use anyhow::{bail, ensure, Context, Result};
-use bytes::{Bytes, BytesMut};
+use bytes::{BufMut, Bytes, BytesMut};
 use rand::Rng;
 use serde::{Deserialize, Serialize};
 use std::fmt;
-use std::io::{self, Write};
+use std::io::{self, IoSlice, Write};
 use std::net::{Shutdown, SocketAddr, TcpStream};
 use std::str::FromStr;
 use std::sync::atomic::{AtomicBool, Ordering};
@@ -118,6 +118,13 @@ impl io::Write for Stream {
         }
     }
 
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        match self {
+            Self::Bidirectional(bidi_stream) => bidi_stream.write_vectored(bufs),
+            Self::WriteOnly(write_stream) => write_stream.write_vectored(bufs),
+        }
+    }
+
     fn flush(&mut self) -> io::Result<()> {
         match self {
             Self::Bidirectional(bidi_stream) => bidi_stream.flush(),
@@ -150,6 +157,34 @@ pub fn query_from_cstring(query_string: Bytes) -> Vec<u8> {
     query_string
 }
 
+/// Write 'first' and then 'second', like the unstable `Write::write_all_vectored`.
+fn write_all_vectored(
+    stream: &mut impl Write,
+    mut first: &[u8],
+    mut second: &[u8],
+) -> io::Result<()> {
+    while !first.is_empty() || !second.is_empty() {
+        let written = match stream.write_vectored(&[IoSlice::new(first), IoSlice::new(second)]) {
+            Ok(0) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    "failed to write whole buffer",
+                ))
+            }
+            Ok(written) => written,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        if written < first.len() {
+            first = &first[written..];
+        } else {
+            second = &second[written - first.len()..];
+            first = &[];
+        }
+    }
+    Ok(())
+}
+
 // Helper function for socket read loops
 pub fn is_socket_read_timed_out(error: &anyhow::Error) -> bool {
     for cause in error.chain() {
@@ -260,6 +295,29 @@ impl PostgresBackend {
         self.flush()
     }
 
+    ///
+    /// Write a CopyData message with 'prefix' followed by 'data' as its
+    /// contents, and flush. Unlike with [`BeMessage::CopyData`], 'data' isn't
+    /// copied into the output buffer: it's sent from where it is with a
+    /// vectored write, together with the buffered messages.
+    ///
+    pub fn write_copy_data_vectored(
+        &mut self,
+        prefix: &[u8],
+        data: &[u8],
+    ) -> io::Result<&mut Self> {
+        let len = i32::try_from(4 + prefix.len() + data.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "message too large"))?;
+        self.buf_out.put_u8(b'd');
+        self.buf_out.put_i32(len);
+        self.buf_out.put_slice(prefix);
+
+        let stream = self.stream.as_mut().unwrap();
+        write_all_vectored(stream, &self.buf_out, data)?;
+        self.buf_out.clear();
+        Ok(self)
+    }
+
     // Wrapper for run_message_loop() that shuts down socket when we are done
     pub fn run(mut self, handler: &mut impl Handler) -> Result<()> {
         let ret = self.run_message_loop(handler);
@@ -503,3 +561,67 @@ impl PostgresBackend {
 pub fn set_pgbackend_shutdown_requested() {
     PGBACKEND_SHUTDOWN_REQUESTED.swap(true, Ordering::Relaxed);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A stream that accepts at most 'limit' bytes per call, across the
+    /// buffers, and fails with `Interrupted` once before the first write.
+    struct ShortWriter {
+        written: Vec<u8>,
+        limit: usize,
+        interrupted: bool,
+    }
+
+    impl Write for ShortWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.write_vectored(&[IoSlice::new(buf)])
+        }
+
+        fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+            if !self.interrupted {
+                self.interrupted = true;
+                return Err(io::Error::from(io::ErrorKind::Interrupted));
+            }
+            let mut n = 0;
+            for buf in bufs {
+                let len = buf.len().min(self.limit - n);
+                self.written.extend_from_slice(&buf[..len]);
+                n += len;
+            }
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn write_all_vectored_short_writes() {
+        let first: Vec<u8> = (0..10).collect();
+        let second: Vec<u8> = (10..23).collect();
+
+        for limit in 1..8 {
+            let mut stream = ShortWriter {
+                written: Vec::new(),
+                limit,
+                interrupted: false,
+            };
+            write_all_vectored(&mut stream, &first, &second).unwrap();
+            assert!(stream.interrupted);
+            assert_eq!(stream.written, [&first[..], &second[..]].concat());
+        }
+
+        // Empty buffers on either side
+        let mut stream = ShortWriter {
+            written: Vec::new(),
+            limit: 3,
+            interrupted: true,
+        };
+        write_all_vectored(&mut stream, &[], &second).unwrap();
+        write_all_vectored(&mut stream, &first, &[]).unwrap();
+        assert_eq!(stream.written, [&second[..], &first[..]].concat());
+    }
+}
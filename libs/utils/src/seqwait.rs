@@ -1,12 +1,16 @@
 #![warn(missing_docs)]
 
-use std::cmp::{Eq, Ordering, PartialOrd};
+use std::cmp::{self, Eq, Ordering, PartialOrd};
 use std::collections::BinaryHeap;
-use std::fmt::Debug;
+use std::fmt::{self, Debug};
 use std::mem;
-use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::atomic::{self, AtomicBool};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
 use std::sync::Mutex;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+/// How often a cancellable wait checks its [`CancellationToken`]
+const CANCELLATION_CHECK_INTERVAL: Duration = Duration::from_millis(50);
 
 /// An error happened while waiting for a number
 #[derive(Debug, PartialEq, thiserror::Error)]
@@ -16,6 +20,62 @@ pub enum SeqWaitError {
     Timeout,
     /// [`SeqWait::shutdown`] was called
     Shutdown,
+    /// The [`CancellationToken`] of the wait was cancelled
+    Cancelled,
+}
+
+/// A token to abort waits in a [`SeqWait`] early
+///
+/// The token is cancelled either explicitly, with [`CancellationToken::cancel`], or
+/// when its probe says so. The probe is checked periodically while waiting, e.g. to
+/// find out that the client that the wait is done for has disconnected.
+#[derive(Default)]
+pub struct CancellationToken {
+    cancelled: AtomicBool,
+    probe: Option<Box<dyn Fn() -> bool + Send + Sync>>,
+}
+
+impl CancellationToken {
+    /// Create a token that is only cancelled explicitly
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a token that also gets cancelled once `probe` returns true
+    pub fn with_probe(probe: impl Fn() -> bool + Send + Sync + 'static) -> Self {
+        CancellationToken {
+            cancelled: AtomicBool::new(false),
+            probe: Some(Box::new(probe)),
+        }
+    }
+
+    /// Cancel all present and future waits with this token
+    pub fn cancel(&self) {
+        self.cancelled.store(true, atomic::Ordering::Relaxed);
+    }
+
+    /// Check if the token is cancelled, running its probe if it's not yet
+    pub fn is_cancelled(&self) -> bool {
+        if self.cancelled.load(atomic::Ordering::Relaxed) {
+            return true;
+        }
+        match &self.probe {
+            Some(probe) if probe() => {
+                self.cancel();
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Debug for CancellationToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CancellationToken")
+            .field("cancelled", &self.cancelled)
+            .field("has_probe", &self.probe.is_some())
+            .finish()
+    }
 }
 
 /// Monotonically increasing value
@@ -121,6 +181,7 @@ where
             // Prevent new waiters; wake all those that exist.
             // Wake everyone with an error.
             let mut internal = self.internal.lock().unwrap();
+            internal.shutdown = true;
 
             // This will steal the entire waiters map.
             // When we drop it all waiters will be woken.
@@ -160,13 +221,46 @@ where
         match self.queue_for_wait(num) {
             Ok(None) => Ok(()),
             Ok(Some(rx)) => rx.recv_timeout(timeout_duration).map_err(|e| match e {
-                std::sync::mpsc::RecvTimeoutError::Timeout => SeqWaitError::Timeout,
-                std::sync::mpsc::RecvTimeoutError::Disconnected => SeqWaitError::Shutdown,
+                RecvTimeoutError::Timeout => SeqWaitError::Timeout,
+                RecvTimeoutError::Disconnected => SeqWaitError::Shutdown,
             }),
             Err(e) => Err(e),
         }
     }
 
+    /// Wait for a number to arrive
+    ///
+    /// Same as [`SeqWait::wait_for_timeout`], but [`SeqWaitError::Cancelled`] is
+    /// returned as soon as the `cancel` token is cancelled, without waiting for
+    /// the number or the timeout.
+    pub fn wait_for_timeout_cancellable(
+        &self,
+        num: V,
+        timeout_duration: Duration,
+        cancel: &CancellationToken,
+    ) -> Result<(), SeqWaitError> {
+        let rx = match self.queue_for_wait(num)? {
+            None => return Ok(()),
+            Some(rx) => rx,
+        };
+
+        let deadline = Instant::now() + timeout_duration;
+        loop {
+            if cancel.is_cancelled() {
+                return Err(SeqWaitError::Cancelled);
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(SeqWaitError::Timeout);
+            }
+            match rx.recv_timeout(cmp::min(remaining, CANCELLATION_CHECK_INTERVAL)) {
+                Ok(()) => return Ok(()),
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => return Err(SeqWaitError::Shutdown),
+            }
+        }
+    }
+
     /// Register and return a channel that will be notified when a number arrives,
     /// or None, if it has already arrived.
     fn queue_for_wait(&self, num: V) -> Result<Option<Receiver<()>>, SeqWaitError> {
@@ -275,6 +369,21 @@ mod tests {
         seq.shutdown();
     }
 
+    #[test]
+    fn seqwait_shutdown() {
+        let seq = SeqWait::new(0);
+        seq.advance(10);
+        seq.shutdown();
+
+        // Numbers that have arrived can still be waited for, later ones not
+        assert_eq!(seq.wait_for(10), Ok(()));
+        assert_eq!(seq.wait_for(11), Err(SeqWaitError::Shutdown));
+        assert_eq!(
+            seq.wait_for_timeout(11, Duration::from_secs(10)),
+            Err(SeqWaitError::Shutdown)
+        );
+    }
+
     #[test]
     fn seqwait_timeout() {
         let seq = Arc::new(SeqWait::new(0));
@@ -290,4 +399,33 @@ mod tests {
         let old = seq.advance(99);
         assert_eq!(old, 0)
     }
+
+    #[test]
+    fn seqwait_cancel() {
+        let seq = Arc::new(SeqWait::new(0));
+        let cancel = Arc::new(CancellationToken::new());
+        let seq2 = Arc::clone(&seq);
+        let cancel2 = Arc::clone(&cancel);
+        let waiter = spawn(move || {
+            let timeout = Duration::from_secs(600);
+            seq2.wait_for_timeout_cancellable(42, timeout, &cancel2)
+        });
+        sleep(Duration::from_millis(100));
+        cancel.cancel();
+        assert_eq!(waiter.join().unwrap(), Err(SeqWaitError::Cancelled));
+
+        // A number that has arrived already doesn't need waiting
+        seq.advance(42);
+        assert_eq!(
+            seq.wait_for_timeout_cancellable(42, Duration::ZERO, &cancel),
+            Ok(())
+        );
+
+        let probed = CancellationToken::with_probe(|| true);
+        assert_eq!(
+            seq.wait_for_timeout_cancellable(43, Duration::from_secs(600), &probed),
+            Err(SeqWaitError::Cancelled)
+        );
+        assert!(probed.is_cancelled());
+    }
 }
@@ -37,6 +37,10 @@ impl io::Write for BufStream {
         self.get_ref().write(buf)
     }
 
+    fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
+        self.get_ref().write_vectored(bufs)
+    }
+
     fn flush(&mut self) -> io::Result<()> {
         self.get_ref().flush()
     }
@@ -99,6 +103,13 @@ impl io::Write for WriteStream {
         }
     }
 
+    fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
+        match self {
+            Self::Tcp(stream) => stream.as_ref().write_vectored(bufs),
+            Self::Tls(write_half) => write_half.write_vectored(bufs),
+        }
+    }
+
     fn flush(&mut self) -> io::Result<()> {
         match self {
             Self::Tcp(stream) => stream.as_ref().flush(),
@@ -197,6 +208,13 @@ impl io::Write for BidiStream {
         }
     }
 
+    fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
+        match self {
+            Self::Tcp(stream) => stream.write_vectored(bufs),
+            Self::Tls(tls_boxed) => tls_boxed.write_vectored(bufs),
+        }
+    }
+
     fn flush(&mut self) -> io::Result<()> {
         match self {
             Self::Tcp(stream) => stream.flush(),
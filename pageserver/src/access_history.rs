@@ -0,0 +1,455 @@
+//!
+//! Access history of the timelines, for the warm-up after a restart.
+//!
+//! The page service records which timelines were served, and which segments
+//! of the relations were read the most (see [`AccessRecorder`]). The record is
+//! saved to `access_history.json` in the workdir periodically and on shutdown,
+//! bounded to the most recently used timelines and their hottest segments.
+//!
+//! On start, a background thread loads the timelines from the history, most
+//! recently used first, so that their layer maps are ready before the computes
+//! reconnect. Then it reads the pages of the hot segments, hottest first, up to
+//! `warm_up_pages_on_start` pages, to have them materialized in the caches.
+//!
+//! The warm-up is best effort: a timeline that fails to load is skipped, and
+//! the computes can be served while the warm-up is in progress.
+//!
+use std::collections::HashMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{Context, Result};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+use tracing::*;
+use utils::zid::{ZTenantId, ZTenantTimelineId, ZTimelineId};
+
+use crate::config::PageServerConf;
+use crate::reltag::RelTag;
+use crate::repository::Timeline;
+use crate::tenant_mgr;
+use crate::thread_mgr::{self, ThreadKind};
+
+const ACCESS_HISTORY_FILE_NAME: &str = "access_history.json";
+
+/// Size of the relation segments that the access counts are kept for, in blocks (1 MB).
+const SEGMENT_BLOCKS: u32 = 128;
+
+/// Bounds of the saved history.
+const MAX_TIMELINES: usize = 1000;
+const MAX_HOT_SEGMENTS: usize = 64;
+
+/// Segments kept in memory per timeline, before the coldest are trimmed.
+const MAX_TRACKED_SEGMENTS: usize = MAX_HOT_SEGMENTS * 16;
+
+/// How often a connection merges its counts into the history.
+const RECORDER_FLUSH_REQUESTS: u64 = 1024;
+const RECORDER_FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+lazy_static! {
+    static ref ACCESS_HISTORY: Mutex<HashMap<ZTenantTimelineId, TimelineAccesses>> =
+        Mutex::new(HashMap::new());
+}
+
+#[derive(Debug, Clone)]
+struct TimelineAccesses {
+    last_access: SystemTime,
+    requests: u64,
+    segments: HashMap<(RelTag, u32), u64>,
+}
+
+/// Saved access history of a timeline.
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TimelineAccessHistory {
+    #[serde_as(as = "DisplayFromStr")]
+    pub tenant_id: ZTenantId,
+    #[serde_as(as = "DisplayFromStr")]
+    pub timeline_id: ZTimelineId,
+    #[serde(with = "humantime_serde")]
+    pub last_access: SystemTime,
+    pub requests: u64,
+    /// The hottest segments, most requested first.
+    pub hot_segments: Vec<HotSegment>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HotSegment {
+    pub rel: RelTag,
+    /// Number of the segment of `SEGMENT_BLOCKS` blocks in the relation.
+    pub segno: u32,
+    pub requests: u64,
+}
+
+///
+/// Counts the page requests of a page service connection. The counts are
+/// merged into the access history in batches, and when the recorder is dropped.
+///
+pub struct AccessRecorder {
+    id: ZTenantTimelineId,
+    requests: u64,
+    segments: HashMap<(RelTag, u32), u64>,
+    last_flush: Instant,
+}
+
+impl AccessRecorder {
+    pub fn new(tenant_id: ZTenantId, timeline_id: ZTimelineId) -> Self {
+        Self {
+            id: ZTenantTimelineId::new(tenant_id, timeline_id),
+            requests: 0,
+            segments: HashMap::new(),
+            last_flush: Instant::now(),
+        }
+    }
+
+    pub fn record_get_page(&mut self, rel: RelTag, blknum: u32) {
+        self.requests += 1;
+        *self
+            .segments
+            .entry((rel, blknum / SEGMENT_BLOCKS))
+            .or_default() += 1;
+        if self.requests >= RECORDER_FLUSH_REQUESTS
+            || self.last_flush.elapsed() >= RECORDER_FLUSH_INTERVAL
+        {
+            self.flush();
+        }
+    }
+
+    fn flush(&mut self) {
+        self.last_flush = Instant::now();
+        if self.requests == 0 {
+            return;
+        }
+        let mut history = ACCESS_HISTORY.lock().unwrap();
+        let accesses = history.entry(self.id).or_insert_with(|| TimelineAccesses {
+            last_access: SystemTime::UNIX_EPOCH,
+            requests: 0,
+            segments: HashMap::new(),
+        });
+        merge_accesses(
+            accesses,
+            SystemTime::now(),
+            self.requests,
+            self.segments.drain(),
+        );
+        self.requests = 0;
+    }
+}
+
+impl Drop for AccessRecorder {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+fn merge_accesses(
+    accesses: &mut TimelineAccesses,
+    now: SystemTime,
+    requests: u64,
+    segments: impl Iterator<Item = ((RelTag, u32), u64)>,
+) {
+    accesses.last_access = now;
+    accesses.requests += requests;
+    for (segment, count) in segments {
+        *accesses.segments.entry(segment).or_default() += count;
+    }
+    if accesses.segments.len() > MAX_TRACKED_SEGMENTS {
+        let mut counts = accesses.segments.values().copied().collect::<Vec<_>>();
+        counts.sort_unstable_by(|a, b| b.cmp(a));
+        let threshold = counts[MAX_TRACKED_SEGMENTS / 4];
+        accesses.segments.retain(|_, count| *count > threshold);
+    }
+}
+
+fn history_path(conf: &PageServerConf) -> PathBuf {
+    conf.workdir.join(ACCESS_HISTORY_FILE_NAME)
+}
+
+/// The history to save: the most recently used timelines with their hottest segments.
+fn snapshot(history: &HashMap<ZTenantTimelineId, TimelineAccesses>) -> Vec<TimelineAccessHistory> {
+    let mut timelines = history
+        .iter()
+        .map(|(id, accesses)| {
+            let mut hot_segments = accesses
+                .segments
+                .iter()
+                .map(|(&(rel, segno), &requests)| HotSegment {
+                    rel,
+                    segno,
+                    requests,
+                })
+                .collect::<Vec<_>>();
+            hot_segments.sort_by(|a, b| {
+                b.requests
+                    .cmp(&a.requests)
+                    .then_with(|| (a.rel, a.segno).cmp(&(b.rel, b.segno)))
+            });
+            hot_segments.truncate(MAX_HOT_SEGMENTS);
+            TimelineAccessHistory {
+                tenant_id: id.tenant_id,
+                timeline_id: id.timeline_id,
+                last_access: accesses.last_access,
+                requests: accesses.requests,
+                hot_segments,
+            }
+        })
+        .collect::<Vec<_>>();
+    timelines.sort_by(|a, b| {
+        b.last_access
+            .cmp(&a.last_access)
+            .then_with(|| (a.tenant_id, a.timeline_id).cmp(&(b.tenant_id, b.timeline_id)))
+    });
+    timelines.truncate(MAX_TIMELINES);
+    timelines
+}
+
+fn save_history(path: &Path, timelines: &[TimelineAccessHistory]) -> Result<()> {
+    let temp_path = path.with_extension("json.temp");
+    fs::write(&temp_path, serde_json::to_vec(timelines)?)
+        .with_context(|| format!("Failed to write {}", temp_path.display()))?;
+    fs::rename(&temp_path, path).with_context(|| format!("Failed to replace {}", path.display()))
+}
+
+fn load_history(path: &Path) -> Result<Vec<TimelineAccessHistory>> {
+    match fs::read(path) {
+        Ok(bytes) => serde_json::from_slice(&bytes)
+            .with_context(|| format!("Failed to parse {}", path.display())),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    }
+}
+
+/// Seed the in-memory history with the saved one. The saved counts are halved,
+/// so that the recent accesses outweigh the older ones.
+fn restore(timelines: &[TimelineAccessHistory]) {
+    let mut history = ACCESS_HISTORY.lock().unwrap();
+    for timeline in timelines {
+        history.insert(
+            ZTenantTimelineId::new(timeline.tenant_id, timeline.timeline_id),
+            TimelineAccesses {
+                last_access: timeline.last_access,
+                requests: timeline.requests / 2,
+                segments: timeline
+                    .hot_segments
+                    .iter()
+                    .map(|segment| ((segment.rel, segment.segno), segment.requests / 2))
+                    .filter(|(_, requests)| *requests > 0)
+                    .collect(),
+            },
+        );
+    }
+}
+
+/// Forget a timeline that is removed from this pageserver.
+pub fn forget_timeline(tenant_id: ZTenantId, timeline_id: ZTimelineId) {
+    ACCESS_HISTORY
+        .lock()
+        .unwrap()
+        .remove(&ZTenantTimelineId::new(tenant_id, timeline_id));
+}
+
+fn save(conf: &PageServerConf) {
+    let timelines = snapshot(&ACCESS_HISTORY.lock().unwrap());
+    if let Err(e) = save_history(&history_path(conf), &timelines) {
+        error!("Failed to save the access history: {e:?}");
+    }
+}
+
+///
+/// Load the saved access history, and launch the thread that warms up the
+/// timelines from it and keeps saving the history, until shut down. Must be
+/// called after the tenants are registered with the tenant manager.
+///
+pub fn init(conf: &'static PageServerConf) -> Result<()> {
+    let timelines = load_history(&history_path(conf)).unwrap_or_else(|e| {
+        warn!("Ignoring the saved access history: {e:?}");
+        Vec::new()
+    });
+    restore(&timelines);
+
+    thread_mgr::spawn(
+        ThreadKind::AccessHistory,
+        None,
+        None,
+        "access history thread",
+        false,
+        move || {
+            if conf.warm_up_pages_on_start > 0 && !timelines.is_empty() {
+                warm_up(&timelines, conf.warm_up_pages_on_start);
+            }
+            drop(timelines);
+
+            // Saved once more when shut down
+            loop {
+                let mut sleep_time = SAVE_INTERVAL.as_secs();
+                while sleep_time > 0 && !thread_mgr::is_shutdown_requested() {
+                    sleep_time -= 1;
+                    std::thread::sleep(Duration::from_secs(1));
+                }
+                save(conf);
+                if thread_mgr::is_shutdown_requested() {
+                    return Ok(());
+                }
+            }
+        },
+    )
+    .context("Failed to spawn access history thread")?;
+    Ok(())
+}
+
+fn warm_up(timelines: &[TimelineAccessHistory], max_pages: usize) {
+    let started_at = Instant::now();
+
+    // Load the timelines first, serving any request needs the layer map
+    let mut loaded = Vec::with_capacity(timelines.len());
+    for history in timelines {
+        if thread_mgr::is_shutdown_requested() {
+            return;
+        }
+        match tenant_mgr::get_local_timeline_with_load(history.tenant_id, history.timeline_id) {
+            Ok(timeline) => loaded.push((history, timeline)),
+            Err(e) => debug!(
+                "Not warming up timeline {}/{}: {e:#}",
+                history.tenant_id, history.timeline_id
+            ),
+        }
+    }
+    info!(
+        "loaded {} timelines from the access history in {:?}",
+        loaded.len(),
+        started_at.elapsed()
+    );
+
+    // Then read the hot segments, the hottest of all timelines first
+    let mut segments = loaded
+        .iter()
+        .flat_map(|(history, timeline)| {
+            history
+                .hot_segments
+                .iter()
+                .map(move |segment| (segment, timeline))
+        })
+        .collect::<Vec<_>>();
+    segments.sort_by(|(a, _), (b, _)| b.requests.cmp(&a.requests));
+
+    let mut pages = 0;
+    'segments: for (segment, timeline) in segments {
+        let lsn = timeline.tline.get_last_record_lsn();
+        let nblocks = match timeline
+            .get_rel_exists(segment.rel, lsn)
+            .and_then(|exists| {
+                if exists {
+                    timeline.get_rel_size(segment.rel, lsn)
+                } else {
+                    Ok(0)
+                }
+            }) {
+            Ok(nblocks) => nblocks,
+            Err(e) => {
+                debug!("Not warming up relation {}: {e:#}", segment.rel);
+                continue;
+            }
+        };
+        let start = segment.segno.saturating_mul(SEGMENT_BLOCKS);
+        let end = start.saturating_add(SEGMENT_BLOCKS).min(nblocks);
+        for blknum in start..end {
+            if pages >= max_pages || thread_mgr::is_shutdown_requested() {
+                break 'segments;
+            }
+            if let Err(e) = timeline.get_rel_page_at_lsn(segment.rel, blknum, lsn) {
+                debug!("Failed to warm up block {blknum} of {}: {e:#}", segment.rel);
+                continue 'segments;
+            }
+            pages += 1;
+        }
+    }
+    info!(
+        "warmed up {} timelines and {pages} pages in {:?}",
+        loaded.len(),
+        started_at.elapsed()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rel(relnode: u32) -> RelTag {
+        RelTag {
+            spcnode: 1663,
+            dbnode: 13010,
+            relnode,
+            forknum: 0,
+        }
+    }
+
+    #[test]
+    fn save_and_restore_history() -> Result<()> {
+        let tenant_id = ZTenantId::generate();
+        let old_timeline = ZTenantTimelineId::new(tenant_id, ZTimelineId::generate());
+        let new_timeline = ZTenantTimelineId::new(tenant_id, ZTimelineId::generate());
+        let now = SystemTime::now();
+
+        let mut history = HashMap::new();
+        for (id, last_access) in [
+            (old_timeline, now - Duration::from_secs(3600)),
+            (new_timeline, now),
+        ] {
+            let accesses = history.entry(id).or_insert_with(|| TimelineAccesses {
+                last_access: SystemTime::UNIX_EPOCH,
+                requests: 0,
+                segments: HashMap::new(),
+            });
+            // Many single accesses, and a few hot segments
+            let segments = (0..MAX_TRACKED_SEGMENTS as u32 + 10)
+                .map(|segno| ((rel(16384), segno), 1))
+                .chain([((rel(16385), 0), 100), ((rel(16385), 3), 50)]);
+            merge_accesses(accesses, last_access, 150, segments);
+        }
+        assert!(history[&new_timeline].segments.len() <= MAX_TRACKED_SEGMENTS);
+
+        let timelines = snapshot(&history);
+        assert_eq!(timelines.len(), 2);
+        assert_eq!(timelines[0].timeline_id, new_timeline.timeline_id);
+        assert_eq!(timelines[1].timeline_id, old_timeline.timeline_id);
+        assert_eq!(
+            timelines[0].hot_segments[..2],
+            [
+                HotSegment {
+                    rel: rel(16385),
+                    segno: 0,
+                    requests: 100
+                },
+                HotSegment {
+                    rel: rel(16385),
+                    segno: 3,
+                    requests: 50
+                },
+            ]
+        );
+        assert!(timelines[0].hot_segments.len() <= MAX_HOT_SEGMENTS);
+
+        let workdir = tempfile::tempdir()?;
+        let path = workdir.path().join(ACCESS_HISTORY_FILE_NAME);
+        assert!(load_history(&path)?.is_empty());
+        save_history(&path, &timelines)?;
+        let loaded = load_history(&path)?;
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded[0].hot_segments, timelines[0].hot_segments);
+
+        restore(&loaded);
+        let restored = ACCESS_HISTORY.lock().unwrap()[&new_timeline].clone();
+        assert_eq!(restored.requests, 75);
+        assert_eq!(restored.segments[&(rel(16385), 0)], 50);
+        forget_timeline(new_timeline.tenant_id, new_timeline.timeline_id);
+        forget_timeline(old_timeline.tenant_id, old_timeline.timeline_id);
+
+        Ok(())
+    }
+}
@@ -21,6 +21,7 @@ use std::time::SystemTime;
 use tar::{Builder, EntryType, Header};
 use tracing::*;
 
+use crate::pg_file_path;
 use crate::reltag::SlruKind;
 use crate::repository::Timeline;
 use crate::DatadirTimelineImpl;
@@ -28,6 +29,9 @@ use postgres_ffi::xlog_utils::*;
 use postgres_ffi::*;
 use utils::lsn::Lsn;
 
+/// Directory of the extension data in the tarball.
+pub const EXTENSION_DATA_DIR: &str = "neon_ext";
+
 /// This is short-living object only for the time of tarball creation,
 /// created mostly to avoid passing a lot of parameters between various functions
 /// used for constructing tarball.
@@ -144,6 +148,11 @@ where
         for xid in self.timeline.list_twophase_files(self.lsn)? {
             self.add_twophase_file(xid)?;
         }
+        for (path, content) in self.timeline.list_aux_files(self.lsn)? {
+            let header = new_tar_header(&path, content.len() as u64)?;
+            self.ar.append(&header, &content[..])?;
+        }
+        self.add_extension_data()?;
 
         fail_point!("basebackup-before-control-file", |_| {
             bail!("failpoint basebackup-before-control-file")
@@ -157,6 +166,39 @@ where
         Ok(())
     }
 
+    //
+    // Include the extension data, as files named neon_ext/<namespace>/<key>,
+    // for the extensions to load at startup.
+    //
+    fn add_extension_data(&mut self) -> anyhow::Result<()> {
+        let values = self.timeline.list_extension_data(self.lsn)?;
+        if values.is_empty() {
+            return Ok(());
+        }
+        self.ar
+            .append(&new_tar_header_dir(EXTENSION_DATA_DIR)?, &mut io::empty())?;
+
+        let mut last_namespace = None;
+        for (namespace, key) in values {
+            // The values are sorted by namespace
+            if last_namespace.as_ref() != Some(&namespace) {
+                let header = new_tar_header_dir(&format!("{EXTENSION_DATA_DIR}/{namespace}"))?;
+                self.ar.append(&header, &mut io::empty())?;
+                last_namespace = Some(namespace.clone());
+            }
+            let value = self
+                .timeline
+                .get_extension_data(&namespace, key, self.lsn)?
+                .with_context(|| format!("extension data {namespace}/{key} disappeared"))?;
+            let header = new_tar_header(
+                &format!("{EXTENSION_DATA_DIR}/{namespace}/{key}"),
+                value.len() as u64,
+            )?;
+            self.ar.append(&header, &value[..])?;
+        }
+        Ok(())
+    }
+
     //
     // Generate SLRU segment files from repository.
     //
@@ -182,7 +224,7 @@ where
             slru_buf.extend_from_slice(&img[..pg_constants::BLCKSZ as usize]);
         }
 
-        let segname = format!("{}/{:>04X}", slru.to_str(), segno);
+        let segname = pg_file_path::slru_segment_path(slru, segno);
         let header = new_tar_header(&segname, slru_buf.len() as u64)?;
         self.ar.append(&header, slru_buf.as_slice())?;
 
@@ -276,7 +318,7 @@ where
         buf.extend_from_slice(&img[..]);
         let crc = crc32c::crc32c(&img[..]);
         buf.put_u32_le(crc);
-        let path = format!("pg_twophase/{:>08X}", xid);
+        let path = pg_file_path::twophase_file_path(xid);
         let header = new_tar_header(&path, buf.len() as u64)?;
         self.ar.append(&header, &buf[..])?;
 
@@ -362,7 +404,7 @@ where
 //
 // Create new tarball entry header
 //
-fn new_tar_header(path: &str, size: u64) -> anyhow::Result<Header> {
+pub(crate) fn new_tar_header(path: &str, size: u64) -> anyhow::Result<Header> {
     let mut header = Header::new_gnu();
     header.set_size(size);
     header.set_path(path)?;
@@ -10,8 +10,10 @@ use daemonize::Daemonize;
 
 use fail::FailScenario;
 use pageserver::{
+    access_history, compute_notify,
     config::{defaults::*, PageServerConf},
-    http, page_cache, page_service, profiling, tenant_mgr, thread_mgr,
+    format_marker, gc_coordinator, http, metric_labels, page_cache, page_service, profiling,
+    request_priority, tenant_mgr, thread_mgr,
     thread_mgr::ThreadKind,
     timelines, virtual_file, LOG_FILE_NAME,
 };
@@ -190,6 +192,12 @@ fn main() -> anyhow::Result<()> {
     // Basic initialization of things that don't change after startup
     virtual_file::init(conf.max_file_descriptors);
     page_cache::init(conf.page_cache_size);
+    gc_coordinator::init(conf.max_concurrent_gc);
+    request_priority::init(conf.max_concurrent_layer_reads);
+    metric_labels::init(conf.metrics_max_labeled_tenants);
+
+    // Refuse to touch a data directory written by a newer pageserver
+    format_marker::check_and_update(conf, GIT_VERSION)?;
 
     // Create repo and exit if init was requested
     if init {
@@ -274,8 +282,14 @@ fn start_pageserver(conf: &'static PageServerConf, daemonize: bool) -> Result<()
     };
     info!("Using auth: {:#?}", conf.auth_type);
 
+    // Start the notifier before loading tenants, to announce their timelines
+    compute_notify::init(conf)?;
+
     let remote_index = tenant_mgr::init_tenant_mgr(conf)?;
 
+    // Load the recently used timelines first, before the computes ask for them
+    access_history::init(conf)?;
+
     // Spawn a new thread for the http endpoint
     // bind before launching separate thread so the error reported before startup exits
     let auth_cloned = auth.clone();
@@ -0,0 +1,139 @@
+//! Main entry point for the trace_bench executable
+//!
+//! Replays the WAL and GetPage traces recorded for a timeline against a copy of
+//! its tenant, and reports throughput and latencies. See the trace_bench module.
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{ensure, Context, Result};
+use clap::{App, Arg};
+use pageserver::{
+    config::PageServerConf, gc_coordinator, metric_labels, page_cache, trace_bench, virtual_file,
+};
+use utils::{
+    project_git_version,
+    zid::{ZTenantId, ZTimelineId},
+};
+
+project_git_version!(GIT_VERSION);
+
+fn main() -> Result<()> {
+    let arg_matches = App::new("Zenith trace_bench utility")
+        .about("Replay recorded WAL and GetPage traces of a timeline, for benchmarking")
+        .version(GIT_VERSION)
+        .arg(
+            Arg::new("source-workdir")
+                .long("source-workdir")
+                .takes_value(true)
+                .required(true)
+                .help("Workdir of the page server that recorded the traces"),
+        )
+        .arg(
+            Arg::new("scratch-dir")
+                .long("scratch-dir")
+                .takes_value(true)
+                .required(true)
+                .help("Empty directory to run the bench in"),
+        )
+        .arg(
+            Arg::new("tenant-id")
+                .long("tenant-id")
+                .takes_value(true)
+                .required(true),
+        )
+        .arg(
+            Arg::new("timeline-id")
+                .long("timeline-id")
+                .takes_value(true)
+                .required(true),
+        )
+        .arg(
+            Arg::new("wal-trace")
+                .long("wal-trace")
+                .takes_value(true)
+                .required(true)
+                .help("WAL trace to ingest"),
+        )
+        .arg(
+            Arg::new("page-trace")
+                .long("page-trace")
+                .takes_value(true)
+                .help("GetPage trace to serve after the ingest"),
+        )
+        .arg(
+            Arg::new("config-override")
+                .short('c')
+                .takes_value(true)
+                .number_of_values(1)
+                .multiple_occurrences(true)
+                .help("Override the page server configuration of the source, e.g. page_cache_size"),
+        )
+        .get_matches();
+
+    let source_workdir = PathBuf::from(arg_matches.value_of("source-workdir").unwrap());
+    let scratch_dir = PathBuf::from(arg_matches.value_of("scratch-dir").unwrap());
+    let tenant_id = ZTenantId::from_str(arg_matches.value_of("tenant-id").unwrap())?;
+    let timeline_id = ZTimelineId::from_str(arg_matches.value_of("timeline-id").unwrap())?;
+    let wal_trace = PathBuf::from(arg_matches.value_of("wal-trace").unwrap());
+    let page_trace = arg_matches.value_of("page-trace").map(PathBuf::from);
+
+    ensure!(
+        !scratch_dir.exists() || scratch_dir.read_dir()?.next().is_none(),
+        "scratch directory '{}' is not empty",
+        scratch_dir.display()
+    );
+    std::fs::create_dir_all(&scratch_dir)?;
+    let scratch_dir = scratch_dir.canonicalize()?;
+
+    let mut toml = read_source_config(&source_workdir)?;
+    // The bench must not touch the remote storage of the source
+    toml.remove("remote_storage");
+    toml.remove("remote_storage_mirror");
+    if let Some(values) = arg_matches.values_of("config-override") {
+        for option_line in values {
+            let doc = toml_edit::Document::from_str(option_line).with_context(|| {
+                format!(
+                    "Option '{}' could not be parsed as a toml document",
+                    option_line
+                )
+            })?;
+            for (key, item) in doc.iter() {
+                toml.insert(key, item.clone());
+            }
+        }
+    }
+    let conf = PageServerConf::parse_and_validate(&toml, &scratch_dir)
+        .context("Failed to parse pageserver configuration")?;
+    let conf: &'static PageServerConf = Box::leak(Box::new(conf));
+
+    virtual_file::init(conf.max_file_descriptors);
+    page_cache::init(conf.page_cache_size);
+    gc_coordinator::init(conf.max_concurrent_gc);
+    metric_labels::init(conf.metrics_max_labeled_tenants);
+
+    let report = trace_bench::run_trace_bench(
+        conf,
+        &source_workdir,
+        tenant_id,
+        timeline_id,
+        &wal_trace,
+        page_trace.as_deref(),
+    )?;
+    println!("{report}");
+
+    Ok(())
+}
+
+fn read_source_config(source_workdir: &Path) -> Result<toml_edit::Document> {
+    let cfg_file_path = source_workdir.join("pageserver.toml");
+    let cfg_file_contents = std::fs::read_to_string(&cfg_file_path)
+        .with_context(|| format!("No pageserver config at '{}'", cfg_file_path.display()))?;
+    cfg_file_contents
+        .parse::<toml_edit::Document>()
+        .with_context(|| {
+            format!(
+                "Failed to read '{}' as pageserver config",
+                cfg_file_path.display()
+            )
+        })
+}
@@ -1,10 +1,19 @@
 //! Main entry point for the edit_metadata executable
 //!
-//! A handy tool for debugging, that's all.
-use anyhow::Result;
+//! A handy tool for debugging, and for repairing the metadata of a timeline:
+//! the metadata can be dumped as JSON, edited, and written back with
+//! `--restore`. The fields are checked for consistency before anything is
+//! written, and the old metadata file is kept as a backup next to it.
+//!
+//! The page server must not be running, or the tenant must be detached, while
+//! the metadata is written: a running page server overwrites it on the next
+//! checkpoint.
+use anyhow::{Context, Result};
 use clap::{App, Arg};
-use pageserver::layered_repository::metadata::TimelineMetadata;
-use std::path::PathBuf;
+use pageserver::layered_repository::metadata::{TimelineMetadata, TimelineMetadataDump};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use utils::{lsn::Lsn, project_git_version};
 
@@ -33,41 +42,102 @@ fn main() -> Result<()> {
                 .takes_value(true)
                 .help("Previous record LSN"),
         )
+        .arg(
+            Arg::new("json")
+                .long("json")
+                .help("Print the current metadata as JSON, in the format that --restore takes"),
+        )
+        .arg(
+            Arg::new("restore")
+                .long("restore")
+                .takes_value(true)
+                .conflicts_with_all(&["disk_lsn", "prev_lsn"])
+                .help("Replace the metadata with the contents of a JSON file"),
+        )
         .get_matches();
 
     let path = PathBuf::from(arg_matches.value_of("path").unwrap());
     let metadata_bytes = std::fs::read(&path)?;
-    let mut meta = TimelineMetadata::from_bytes(&metadata_bytes)?;
-    println!("Current metadata:\n{:?}", &meta);
+    let meta = TimelineMetadata::from_bytes(&metadata_bytes)?;
+    if arg_matches.is_present("json") {
+        println!("{}", serde_json::to_string_pretty(&meta.dump())?);
+    } else {
+        println!("Current metadata:\n{:?}", &meta);
+    }
 
+    let mut dump = meta.dump();
     let mut update_meta = false;
 
     if let Some(disk_lsn) = arg_matches.value_of("disk_lsn") {
-        meta = TimelineMetadata::new(
-            Lsn::from_str(disk_lsn)?,
-            meta.prev_record_lsn(),
-            meta.ancestor_timeline(),
-            meta.ancestor_lsn(),
-            meta.latest_gc_cutoff_lsn(),
-            meta.initdb_lsn(),
-        );
+        dump.disk_consistent_lsn = Lsn::from_str(disk_lsn)?;
         update_meta = true;
     }
 
     if let Some(prev_lsn) = arg_matches.value_of("prev_lsn") {
-        meta = TimelineMetadata::new(
-            meta.disk_consistent_lsn(),
-            Some(Lsn::from_str(prev_lsn)?),
-            meta.ancestor_timeline(),
-            meta.ancestor_lsn(),
-            meta.latest_gc_cutoff_lsn(),
-            meta.initdb_lsn(),
-        );
+        dump.prev_record_lsn = Some(Lsn::from_str(prev_lsn)?);
+        update_meta = true;
+    }
+
+    if let Some(restore_path) = arg_matches.value_of("restore") {
+        let json = fs::read_to_string(restore_path)
+            .with_context(|| format!("Failed to read '{restore_path}'"))?;
+        dump = serde_json::from_str(&json)
+            .with_context(|| format!("Failed to parse '{restore_path}'"))?;
         update_meta = true;
     }
+
     if update_meta {
-        let metadata_bytes = meta.to_bytes()?;
-        std::fs::write(&path, &metadata_bytes)?;
+        let new_meta = TimelineMetadata::from_dump(&dump).context("Invalid metadata")?;
+        check_ancestor_exists(&path, &dump);
+        let backup_path = write_metadata(&path, &new_meta)?;
+        println!("Old metadata saved to {}", backup_path.display());
+        println!("New metadata:\n{:?}", &new_meta);
     }
     Ok(())
 }
+
+/// The ancestor timeline is expected next to the timeline, in the tenant's directory.
+fn check_ancestor_exists(path: &Path, dump: &TimelineMetadataDump) {
+    if let Some(ancestor_timeline) = dump.ancestor_timeline {
+        if let Some(timelines_dir) = path.parent().and_then(Path::parent) {
+            let ancestor_path = timelines_dir.join(ancestor_timeline.to_string());
+            if !ancestor_path.exists() {
+                println!(
+                    "warning: ancestor timeline directory {} does not exist",
+                    ancestor_path.display()
+                );
+            }
+        }
+    }
+}
+
+///
+/// Replace the metadata file, keeping a copy of the old one. Returns the path
+/// of the copy.
+///
+fn write_metadata(path: &Path, meta: &TimelineMetadata) -> Result<PathBuf> {
+    let file_name = path
+        .file_name()
+        .context("metadata path has no file name")?
+        .to_string_lossy();
+    // The '.old' suffix makes the page server ignore the backup
+    let backup_path = path.with_file_name(format!(
+        "{}.{}.old",
+        file_name,
+        chrono::Utc::now().format("%Y%m%dT%H%M%S")
+    ));
+    fs::copy(path, &backup_path)
+        .with_context(|| format!("Failed to back up '{}'", path.display()))?;
+
+    let temp_path = path.with_extension("tmp");
+    let mut file = File::create(&temp_path)
+        .with_context(|| format!("Failed to create file '{}'", temp_path.display()))?;
+    file.write_all(&meta.to_bytes()?)?;
+    file.sync_all()?;
+    fs::rename(&temp_path, path)
+        .with_context(|| format!("Failed to replace '{}'", path.display()))?;
+    if let Some(dir) = path.parent() {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(backup_path)
+}
@@ -0,0 +1,83 @@
+//!
+//! Source of the current time for the repository.
+//!
+//! GC computes the PITR cutoff from the wall clock time, and some of the
+//! timeline's bookkeeping, like the read replica leases and the time between
+//! layer freezes, depends on the monotonic time. The repository reads both
+//! through a [`Clock`], so that the tests can replace the real time with a
+//! `ManualClock` that only moves when the test advances it, instead of
+//! sleeping and hoping for the timing to work out.
+//!
+#[cfg(test)]
+use std::sync::Mutex;
+#[cfg(test)]
+use std::time::Duration;
+use std::time::{Instant, SystemTime};
+
+pub trait Clock: Send + Sync {
+    /// The current wall clock time
+    fn now(&self) -> SystemTime;
+
+    /// The current monotonic time
+    fn instant(&self) -> Instant;
+}
+
+/// The real time.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn instant(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+///
+/// Virtual time for the tests. It starts at a fixed wall clock time, and
+/// moves only with [`ManualClock::advance`].
+///
+#[cfg(test)]
+pub struct ManualClock {
+    start_time: SystemTime,
+    start_instant: Instant,
+    elapsed: Mutex<Duration>,
+}
+
+#[cfg(test)]
+impl ManualClock {
+    /// 2022-01-01 00:00:00 UTC
+    const START_TIME_SECS: u64 = 1_640_995_200;
+
+    pub fn new() -> Self {
+        ManualClock {
+            start_time: SystemTime::UNIX_EPOCH + Duration::from_secs(Self::START_TIME_SECS),
+            start_instant: Instant::now(),
+            elapsed: Mutex::new(Duration::ZERO),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock().unwrap() += duration;
+    }
+}
+
+#[cfg(test)]
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+impl Clock for ManualClock {
+    fn now(&self) -> SystemTime {
+        self.start_time + *self.elapsed.lock().unwrap()
+    }
+
+    fn instant(&self) -> Instant {
+        self.start_instant + *self.elapsed.lock().unwrap()
+    }
+}
@@ -0,0 +1,274 @@
+//!
+//! Notifications about timeline state changes.
+//!
+//! Computes and the control plane need to know when a timeline becomes usable
+//! on this pageserver, or goes away from it, e.g. to reconnect or to fence a
+//! compute. Instead of polling the HTTP API, they can be notified: every state
+//! change is published to in-process subscribers (see [`subscribe`]) and, if
+//! `compute_notify_url` is configured, POSTed as JSON to that endpoint by a
+//! background thread.
+//!
+//! Delivery is best effort. The webhook queue is bounded, and if the endpoint
+//! can't keep up, new notifications are dropped. A notification is a hint to
+//! re-check: the HTTP API remains the source of truth for a timeline's state.
+//!
+use std::time::Duration;
+
+use anyhow::{anyhow, ensure, Context};
+use hyper::{header, Body, Client, Method, Request, Uri};
+use lazy_static::lazy_static;
+use metrics::{register_int_counter_vec, IntCounterVec};
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+use serde_with::{serde_as, DisplayFromStr};
+use tokio::sync::{broadcast, mpsc};
+use tracing::*;
+use utils::zid::{NodeId, ZTenantId, ZTimelineId};
+
+use crate::config::PageServerConf;
+use crate::thread_mgr::{self, ThreadKind};
+
+static COMPUTE_NOTIFIER: OnceCell<ComputeNotifier> = OnceCell::new();
+
+/// How many notifications can wait for the webhook, or for a slow subscriber.
+const NOTIFY_QUEUE_CAPACITY: usize = 1024;
+const NOTIFY_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const NOTIFY_MAX_ATTEMPTS: u64 = 3;
+
+lazy_static! {
+    static ref COMPUTE_NOTIFICATIONS: IntCounterVec = register_int_counter_vec!(
+        "pageserver_compute_notifications_total",
+        "Number of timeline state change notifications for the compute notify endpoint, by outcome",
+        &["outcome"]
+    )
+    .expect("failed to define a metric");
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TimelineState {
+    /// The timeline is present locally and can serve pages.
+    Ready,
+    /// The timeline's layers are being downloaded from the remote storage.
+    AwaitsDownload,
+    /// The timeline exists in the remote storage only, e.g. after a failed download.
+    CloudOnly,
+    /// The timeline was removed from this pageserver.
+    Detached,
+    /// The timeline was removed from this pageserver and archived in the remote storage.
+    Archived,
+}
+
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TimelineStateNotification {
+    pub pageserver_id: NodeId,
+    #[serde_as(as = "DisplayFromStr")]
+    pub tenant_id: ZTenantId,
+    #[serde_as(as = "DisplayFromStr")]
+    pub timeline_id: ZTimelineId,
+    pub state: TimelineState,
+}
+
+struct ComputeNotifier {
+    node_id: NodeId,
+    subscribers: broadcast::Sender<TimelineStateNotification>,
+    webhook_queue: Option<mpsc::Sender<TimelineStateNotification>>,
+}
+
+impl ComputeNotifier {
+    fn new(
+        node_id: NodeId,
+        webhook_queue: Option<mpsc::Sender<TimelineStateNotification>>,
+    ) -> Self {
+        let (subscribers, _) = broadcast::channel(NOTIFY_QUEUE_CAPACITY);
+        ComputeNotifier {
+            node_id,
+            subscribers,
+            webhook_queue,
+        }
+    }
+}
+
+///
+/// Initialize the notifier, and start the webhook delivery thread if
+/// `compute_notify_url` is configured. Must be called once at page server
+/// startup, after daemonizing.
+///
+pub fn init(conf: &'static PageServerConf) -> anyhow::Result<()> {
+    let webhook_queue = match &conf.compute_notify_url {
+        Some(url) => {
+            // The pageserver's HTTP client has no TLS support.
+            ensure!(
+                url.scheme() == "http",
+                "compute_notify_url '{url}' must be a plain http:// url"
+            );
+            let uri: Uri = url
+                .as_str()
+                .parse()
+                .with_context(|| format!("Invalid compute_notify_url '{url}'"))?;
+
+            let (queue_tx, queue_rx) = mpsc::channel(NOTIFY_QUEUE_CAPACITY);
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .context("Failed to create compute notify runtime")?;
+            thread_mgr::spawn(
+                ThreadKind::ComputeNotify,
+                None,
+                None,
+                "Compute notify thread",
+                false,
+                move || {
+                    runtime.block_on(webhook_loop(uri, queue_rx));
+                    Ok(())
+                },
+            )
+            .context("Failed to spawn compute notify thread")?;
+            Some(queue_tx)
+        }
+        None => None,
+    };
+
+    COMPUTE_NOTIFIER
+        .set(ComputeNotifier::new(conf.id, webhook_queue))
+        .map_err(|_| anyhow!("compute notifier already initialized"))
+}
+
+fn notifier() -> Option<&'static ComputeNotifier> {
+    // In unit tests, page server startup doesn't happen and no one calls
+    // compute_notify::init(). Initialize it here, without a webhook.
+    if cfg!(test) {
+        Some(COMPUTE_NOTIFIER.get_or_init(|| ComputeNotifier::new(NodeId(0), None)))
+    } else {
+        // Not initialized during 'pageserver --init', nobody is listening then.
+        COMPUTE_NOTIFIER.get()
+    }
+}
+
+///
+/// Subscribe to the timeline state changes of all tenants.
+///
+pub fn subscribe() -> Option<broadcast::Receiver<TimelineStateNotification>> {
+    notifier().map(|notifier| notifier.subscribers.subscribe())
+}
+
+///
+/// Announce that a timeline has changed its state. Never blocks.
+///
+pub fn notify(tenant_id: ZTenantId, timeline_id: ZTimelineId, state: TimelineState) {
+    let notifier = match notifier() {
+        Some(notifier) => notifier,
+        None => return,
+    };
+    info!("timeline {timeline_id} of tenant {tenant_id} changed state to {state:?}");
+    let notification = TimelineStateNotification {
+        pageserver_id: notifier.node_id,
+        tenant_id,
+        timeline_id,
+        state,
+    };
+
+    // An error only means that there are no subscribers.
+    let _ = notifier.subscribers.send(notification.clone());
+
+    if let Some(webhook_queue) = &notifier.webhook_queue {
+        if let Err(e) = webhook_queue.try_send(notification) {
+            warn!("Dropping compute notification: {e}");
+            COMPUTE_NOTIFICATIONS.with_label_values(&["dropped"]).inc();
+        }
+    }
+}
+
+async fn webhook_loop(uri: Uri, mut queue: mpsc::Receiver<TimelineStateNotification>) {
+    info!("sending timeline state notifications to {uri}");
+    let client = Client::new();
+    loop {
+        let notification = tokio::select! {
+            _ = thread_mgr::shutdown_watcher() => break,
+            notification = queue.recv() => match notification {
+                Some(notification) => notification,
+                None => break,
+            },
+        };
+
+        for attempt in 1..=NOTIFY_MAX_ATTEMPTS {
+            match post_notification(&client, &uri, &notification).await {
+                Ok(()) => {
+                    COMPUTE_NOTIFICATIONS.with_label_values(&["sent"]).inc();
+                    break;
+                }
+                Err(e) if attempt < NOTIFY_MAX_ATTEMPTS => {
+                    warn!("Failed to send compute notification {notification:?}, attempt {attempt}: {e:#}");
+                    tokio::time::sleep(Duration::from_secs(attempt)).await;
+                }
+                Err(e) => {
+                    error!("Giving up on compute notification {notification:?}: {e:#}");
+                    COMPUTE_NOTIFICATIONS.with_label_values(&["failed"]).inc();
+                }
+            }
+        }
+    }
+    info!("compute notify thread shut down");
+}
+
+async fn post_notification(
+    client: &Client<hyper::client::HttpConnector>,
+    uri: &Uri,
+    notification: &TimelineStateNotification,
+) -> anyhow::Result<()> {
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(uri.clone())
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_vec(notification)?))?;
+
+    let response = tokio::time::timeout(NOTIFY_REQUEST_TIMEOUT, client.request(request))
+        .await
+        .context("Request timed out")??;
+    ensure!(
+        response.status().is_success(),
+        "Endpoint responded with status {}",
+        response.status()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subscribers_receive_notifications() -> anyhow::Result<()> {
+        let mut subscription = subscribe().expect("notifier is always available in tests");
+        let tenant_id = ZTenantId::generate();
+        let timeline_id = ZTimelineId::generate();
+
+        notify(tenant_id, timeline_id, TimelineState::AwaitsDownload);
+        notify(tenant_id, timeline_id, TimelineState::Ready);
+
+        // Other tests may be changing timeline states concurrently.
+        let mut received = Vec::new();
+        while received.len() < 2 {
+            let notification = subscription.try_recv()?;
+            if notification.tenant_id == tenant_id {
+                assert_eq!(notification.timeline_id, timeline_id);
+                received.push(notification.state);
+            }
+        }
+        assert_eq!(
+            received,
+            vec![TimelineState::AwaitsDownload, TimelineState::Ready]
+        );
+
+        let json = serde_json::to_value(TimelineStateNotification {
+            pageserver_id: NodeId(1),
+            tenant_id,
+            timeline_id,
+            state: TimelineState::CloudOnly,
+        })?;
+        assert_eq!(json["state"], "cloud_only");
+        assert_eq!(json["tenant_id"], tenant_id.to_string());
+        Ok(())
+    }
+}
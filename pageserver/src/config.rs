@@ -6,6 +6,7 @@
 
 use anyhow::{anyhow, bail, ensure, Context, Result};
 use remote_storage::RemoteStorageConfig;
+use std::collections::HashSet;
 use std::env;
 
 use std::path::{Path, PathBuf};
@@ -20,7 +21,7 @@ use utils::{
 };
 
 use crate::layered_repository::TIMELINES_SEGMENT_NAME;
-use crate::tenant_config::{TenantConf, TenantConfOpt};
+use crate::tenant_config::{ForkSet, TenantConf, TenantConfOpt};
 
 pub mod defaults {
     use crate::tenant_config::defaults::*;
@@ -37,8 +38,32 @@ pub mod defaults {
     pub const DEFAULT_SUPERUSER: &str = "cloud_admin";
 
     pub const DEFAULT_PAGE_CACHE_SIZE: usize = 8192;
+    pub const DEFAULT_HOT_PAGE_CACHE_SIZE: usize = 32;
+    pub const DEFAULT_PREFETCH_ANCESTOR_LAYERS: bool = false;
+    pub const DEFAULT_EPHEMERAL_FILE_POOL_SIZE: usize = 2;
+    pub const DEFAULT_EPHEMERAL_FILE_PREALLOCATE: u64 = 16 * 1024 * 1024;
+    pub const DEFAULT_LAYER_FLUSH_PACING_PERCENT: u64 = 200;
+    pub const DEFAULT_LAYER_FLUSH_SYNC_INTERVAL: u64 = 8 * 1024 * 1024;
+    pub const DEFAULT_METRICS_MAX_LABELED_TENANTS: usize = 0;
+    pub const DEFAULT_DETACH_GRACE_PERIOD: &str = "1 h";
+    pub const DEFAULT_REMOTE_DELETION_DELAY: &str = "0 s";
+    pub const DEFAULT_STANDBY_HORIZON_LEASE: &str = "30 s";
+    pub const DEFAULT_DOWNLOAD_WAIT_TIMEOUT: &str = "30 s";
+    pub const DEFAULT_USE_SYNCFS: bool = false;
+    pub const DEFAULT_LAYER_READ_MODE: &str = "pread";
+    pub const DEFAULT_MAX_CONCURRENT_LAYER_READS: usize = 64;
+    pub const DEFAULT_REL_SIZE_CACHE_SIZE: usize = 10_000;
+    pub const DEFAULT_MAX_ANCESTOR_HOPS: usize = 8;
+    pub const DEFAULT_STREAM_LAYER_UPLOADS: bool = false;
+    pub const DEFAULT_MAX_OPEN_LAYER_SIZE: u64 = 1024 * 1024 * 1024;
+    pub const DEFAULT_WARM_UP_PAGES_ON_START: usize = 10_000;
+    pub const DEFAULT_WAL_RECEIVER_STATUS_INTERVAL: &str = "10 s";
+    pub const DEFAULT_WAL_RECEIVER_TIMEOUT: &str = "60 s";
+    pub const DEFAULT_GC_MIN_LAYER_AGE: &str = "0 s";
     pub const DEFAULT_MAX_FILE_DESCRIPTORS: usize = 100;
 
+    pub const DEFAULT_MAX_CONCURRENT_GC: usize = 4;
+
     ///
     /// Default built-in configuration file.
     ///
@@ -53,6 +78,36 @@ pub mod defaults {
 #wal_redo_timeout = '{DEFAULT_WAL_REDO_TIMEOUT}'
 
 #max_file_descriptors = {DEFAULT_MAX_FILE_DESCRIPTORS}
+#hot_page_cache_size = {DEFAULT_HOT_PAGE_CACHE_SIZE}
+#prefetch_ancestor_layers = {DEFAULT_PREFETCH_ANCESTOR_LAYERS}
+#ephemeral_file_pool_size = {DEFAULT_EPHEMERAL_FILE_POOL_SIZE}
+#ephemeral_file_preallocate = {DEFAULT_EPHEMERAL_FILE_PREALLOCATE} # in bytes
+#layer_flush_pacing_percent = {DEFAULT_LAYER_FLUSH_PACING_PERCENT}
+#layer_flush_sync_interval = {DEFAULT_LAYER_FLUSH_SYNC_INTERVAL} # in bytes
+#metrics_max_labeled_tenants = {DEFAULT_METRICS_MAX_LABELED_TENANTS}
+#detach_grace_period = '{DEFAULT_DETACH_GRACE_PERIOD}'
+#remote_deletion_delay = '{DEFAULT_REMOTE_DELETION_DELAY}'
+#standby_horizon_lease = '{DEFAULT_STANDBY_HORIZON_LEASE}'
+#download_wait_timeout = '{DEFAULT_DOWNLOAD_WAIT_TIMEOUT}'
+#use_syncfs = {DEFAULT_USE_SYNCFS}
+#layer_read_mode = '{DEFAULT_LAYER_READ_MODE}'
+#max_concurrent_layer_reads = {DEFAULT_MAX_CONCURRENT_LAYER_READS}
+#rel_size_cache_size = {DEFAULT_REL_SIZE_CACHE_SIZE}
+#max_ancestor_hops = {DEFAULT_MAX_ANCESTOR_HOPS}
+#stream_layer_uploads = {DEFAULT_STREAM_LAYER_UPLOADS}
+#max_open_layer_size = {DEFAULT_MAX_OPEN_LAYER_SIZE}
+#warm_up_pages_on_start = {DEFAULT_WARM_UP_PAGES_ON_START}
+#wal_receiver_status_interval = '{DEFAULT_WAL_RECEIVER_STATUS_INTERVAL}'
+#wal_receiver_timeout = '{DEFAULT_WAL_RECEIVER_TIMEOUT}'
+#gc_min_layer_age = '{DEFAULT_GC_MIN_LAYER_AGE}'
+
+#max_concurrent_gc = {DEFAULT_MAX_CONCURRENT_GC}
+
+# File with the 'user:password' credentials required to scrape /metrics
+#metrics_auth_file = 'metrics_auth'
+
+# HTTP endpoint to notify about timeline state changes
+#compute_notify_url = 'http://127.0.0.1:3080/notify'
 
 # initial superuser role name to use when creating a new tenant
 #initial_superuser_name = '{DEFAULT_SUPERUSER}'
@@ -67,6 +122,8 @@ pub mod defaults {
 #gc_horizon = {DEFAULT_GC_HORIZON}
 #image_creation_threshold = {DEFAULT_IMAGE_CREATION_THRESHOLD}
 #pitr_interval = '{DEFAULT_PITR_INTERVAL}'
+#logical_size_soft_limit = 10737418240 # in bytes, no limit if not set
+#image_layer_excluded_forks = ['fsm', 'vm']
 
 # [remote_storage]
 
@@ -74,6 +131,50 @@ pub mod defaults {
     );
 }
 
+/// The `[remote_storage_mirror]` table: a storage with the same parameters as `remote_storage`,
+/// and the tenants to mirror, all of them if not set.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoteStorageMirrorConfig {
+    pub storage: RemoteStorageConfig,
+    pub tenants: Option<HashSet<ZTenantId>>,
+}
+
+impl RemoteStorageMirrorConfig {
+    fn from_toml(item: &Item) -> anyhow::Result<Self> {
+        let tenants = item
+            .get("tenants")
+            .map(|tenants| {
+                parse_toml_array("tenants", tenants)?
+                    .iter()
+                    .map(|tenant_id| {
+                        tenant_id
+                            .parse::<ZTenantId>()
+                            .with_context(|| format!("Invalid tenant id '{tenant_id}'"))
+                    })
+                    .collect::<anyhow::Result<HashSet<_>>>()
+            })
+            .transpose()?;
+        Ok(RemoteStorageMirrorConfig {
+            storage: RemoteStorageConfig::from_toml(item)?,
+            tenants,
+        })
+    }
+
+    /// Whether the remote storage entry of the given local path is mirrored.
+    pub fn is_mirrored(&self, conf: &PageServerConf, local_path: &Path) -> bool {
+        let tenants = match &self.tenants {
+            Some(tenants) => tenants,
+            None => return true,
+        };
+        local_path
+            .strip_prefix(conf.tenants_path())
+            .ok()
+            .and_then(|relative_path| relative_path.iter().next())
+            .and_then(|tenant_dir| tenant_dir.to_str()?.parse::<ZTenantId>().ok())
+            .map_or(false, |tenant_id| tenants.contains(&tenant_id))
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct PageServerConf {
     // Identifier of that particular pageserver so e g safekeepers
@@ -94,6 +195,76 @@ pub struct PageServerConf {
 
     pub page_cache_size: usize,
     pub max_file_descriptors: usize,
+    // Number of hot control pages cached per timeline, see `hot_page_cache`.
+    pub hot_page_cache_size: usize,
+    // Read the ancestor layers needed at the branch point in the background,
+    // when a branch gets loaded.
+    pub prefetch_ancestor_layers: bool,
+    // Number of spare ephemeral files kept per timeline for reuse, and how
+    // much disk space is allocated for each ephemeral file in advance.
+    pub ephemeral_file_pool_size: usize,
+    pub ephemeral_file_preallocate: u64,
+    // Write rate of in-memory layer flushes, in percent of the recent
+    // WAL ingest rate of the timeline. 0 disables pacing.
+    pub layer_flush_pacing_percent: u64,
+    // Sync a new delta layer to disk every this many bytes while it's
+    // written, instead of all at once at the end. 0 disables it.
+    pub layer_flush_sync_interval: u64,
+    // Max number of tenants whose metrics get their own tenant_id and
+    // timeline_id labels, the rest share the "other" labels. 0 for no limit.
+    pub metrics_max_labeled_tenants: usize,
+    // How long the files of a detached timeline are kept, during which the
+    // detach can be undone by re-attaching it.
+    pub detach_grace_period: Duration,
+    // How long the layers that GC removed stay in the remote storage after
+    // the remote index stops referencing them, before they get deleted.
+    pub remote_deletion_delay: Duration,
+    // How long the feedback of a read replica holds back GC, see
+    // crate::standby_horizon.
+    pub standby_horizon_lease: Duration,
+    // How long a compute connection waits for the download of its timeline,
+    // or of an ancestor of it, before it fails.
+    pub download_wait_timeout: Duration,
+    // Make new layer files durable with a syncfs of the whole filesystem instead of
+    // fsyncing each file. Concurrent flushes share one syncfs, which is cheaper
+    // when many timelines flush layers at the same time.
+    pub use_syncfs: bool,
+    // How the blocks of the layer files are read: "pread" reads them into the page
+    // cache, "mmap" maps the files into memory and leaves the caching to the OS.
+    pub layer_read_mode: LayerReadMode,
+    // How many layer file blocks that are not in the page cache can be read at
+    // the same time. The reads of interactive requests go before the background
+    // ones, see `request_priority`.
+    pub max_concurrent_layer_reads: usize,
+    // Number of relation sizes cached per timeline, see `rel_size_cache`.
+    pub rel_size_cache_size: usize,
+    // Number of ancestor timelines a read can go through before the timeline
+    // gets its branch point materialized in image layers of its own. 0 disables it.
+    pub max_ancestor_hops: usize,
+    // Upload the parts of big image layers to the remote storage while the layers
+    // are being written, instead of after they are complete.
+    pub stream_layer_uploads: bool,
+    // Size of the ephemeral file of the open in-memory layer at which the layer
+    // is frozen, even if less than checkpoint_distance of WAL went into it.
+    // 0 disables it.
+    pub max_open_layer_size: u64,
+    //  Number of pages of the recently hot relation segments to read on start,
+    //  from the saved access history. 0 disables the warm-up of the pages, the
+    //  timelines in the history are loaded anyway.
+    pub warm_up_pages_on_start: usize,
+    //  How often the WAL receiver sends its status to the safekeeper, even if the
+    //  safekeeper doesn't ask for it. Zero sends it only when asked for.
+    pub wal_receiver_status_interval: Duration,
+    //  The WAL receiver considers the connection dead and reconnects if it
+    //  receives nothing from the safekeeper for this long. Zero disables the timeout.
+    pub wal_receiver_timeout: Duration,
+    // GC doesn't remove the layer files that were created less than this long ago,
+    // so that new layers aren't removed right after they are created, e.g. when
+    // their upload is still in progress.
+    pub gc_min_layer_age: Duration,
+
+    // How many tenants can run garbage collection at the same time.
+    pub max_concurrent_gc: usize,
 
     // Repository directory, relative to current working directory.
     // Normally, the page server changes the current working directory
@@ -108,7 +279,15 @@ pub struct PageServerConf {
     pub auth_type: AuthType,
 
     pub auth_validation_public_key_path: Option<PathBuf>,
+    /// File with the `user:password` pair that the /metrics endpoint requires
+    /// with HTTP basic auth. If unset, /metrics is served without it.
+    pub metrics_auth_file: Option<PathBuf>,
     pub remote_storage_config: Option<RemoteStorageConfig>,
+    /// Storages to download layers from when the download from `remote_storage_config` fails,
+    /// tried in order. Only downloads use them.
+    pub remote_storage_fallbacks: Vec<RemoteStorageConfig>,
+    /// Secondary storage to copy the changes made in `remote_storage_config` to, in the background.
+    pub remote_storage_mirror: Option<RemoteStorageMirrorConfig>,
 
     pub profiling: ProfilingConfig,
     pub default_tenant_conf: TenantConf,
@@ -119,6 +298,9 @@ pub struct PageServerConf {
 
     /// Etcd broker endpoints to connect to.
     pub broker_endpoints: Vec<Url>,
+
+    /// HTTP endpoint to POST timeline state change notifications to, if any.
+    pub compute_notify_url: Option<Url>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -140,6 +322,25 @@ impl FromStr for ProfilingConfig {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LayerReadMode {
+    Pread,
+    Mmap,
+}
+
+impl FromStr for LayerReadMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<LayerReadMode, Self::Err> {
+        let result = match s {
+            "pread" => LayerReadMode::Pread,
+            "mmap" => LayerReadMode::Mmap,
+            _ => bail!("invalid value \"{s}\" for layer_read_mode option, valid values are \"pread\" and \"mmap\""),
+        };
+        Ok(result)
+    }
+}
+
 // use dedicated enum for builder to better indicate the intention
 // and avoid possible confusion with nested options
 pub enum BuilderValue<T> {
@@ -169,6 +370,29 @@ struct PageServerConfigBuilder {
 
     page_cache_size: BuilderValue<usize>,
     max_file_descriptors: BuilderValue<usize>,
+    hot_page_cache_size: BuilderValue<usize>,
+    prefetch_ancestor_layers: BuilderValue<bool>,
+    ephemeral_file_pool_size: BuilderValue<usize>,
+    ephemeral_file_preallocate: BuilderValue<u64>,
+    layer_flush_pacing_percent: BuilderValue<u64>,
+    layer_flush_sync_interval: BuilderValue<u64>,
+    metrics_max_labeled_tenants: BuilderValue<usize>,
+    detach_grace_period: BuilderValue<Duration>,
+    remote_deletion_delay: BuilderValue<Duration>,
+    standby_horizon_lease: BuilderValue<Duration>,
+    download_wait_timeout: BuilderValue<Duration>,
+    use_syncfs: BuilderValue<bool>,
+    layer_read_mode: BuilderValue<LayerReadMode>,
+    max_concurrent_layer_reads: BuilderValue<usize>,
+    rel_size_cache_size: BuilderValue<usize>,
+    max_ancestor_hops: BuilderValue<usize>,
+    stream_layer_uploads: BuilderValue<bool>,
+    max_open_layer_size: BuilderValue<u64>,
+    warm_up_pages_on_start: BuilderValue<usize>,
+    wal_receiver_status_interval: BuilderValue<Duration>,
+    wal_receiver_timeout: BuilderValue<Duration>,
+    gc_min_layer_age: BuilderValue<Duration>,
+    max_concurrent_gc: BuilderValue<usize>,
 
     workdir: BuilderValue<PathBuf>,
 
@@ -178,13 +402,17 @@ struct PageServerConfigBuilder {
 
     //
     auth_validation_public_key_path: BuilderValue<Option<PathBuf>>,
+    metrics_auth_file: BuilderValue<Option<PathBuf>>,
     remote_storage_config: BuilderValue<Option<RemoteStorageConfig>>,
+    remote_storage_fallbacks: BuilderValue<Vec<RemoteStorageConfig>>,
+    remote_storage_mirror: BuilderValue<Option<RemoteStorageMirrorConfig>>,
 
     id: BuilderValue<NodeId>,
 
     profiling: BuilderValue<ProfilingConfig>,
     broker_etcd_prefix: BuilderValue<String>,
     broker_endpoints: BuilderValue<Vec<Url>>,
+    compute_notify_url: BuilderValue<Option<Url>>,
 }
 
 impl Default for PageServerConfigBuilder {
@@ -201,17 +429,53 @@ impl Default for PageServerConfigBuilder {
             superuser: Set(DEFAULT_SUPERUSER.to_string()),
             page_cache_size: Set(DEFAULT_PAGE_CACHE_SIZE),
             max_file_descriptors: Set(DEFAULT_MAX_FILE_DESCRIPTORS),
+            hot_page_cache_size: Set(DEFAULT_HOT_PAGE_CACHE_SIZE),
+            prefetch_ancestor_layers: Set(DEFAULT_PREFETCH_ANCESTOR_LAYERS),
+            ephemeral_file_pool_size: Set(DEFAULT_EPHEMERAL_FILE_POOL_SIZE),
+            ephemeral_file_preallocate: Set(DEFAULT_EPHEMERAL_FILE_PREALLOCATE),
+            layer_flush_pacing_percent: Set(DEFAULT_LAYER_FLUSH_PACING_PERCENT),
+            layer_flush_sync_interval: Set(DEFAULT_LAYER_FLUSH_SYNC_INTERVAL),
+            metrics_max_labeled_tenants: Set(DEFAULT_METRICS_MAX_LABELED_TENANTS),
+            detach_grace_period: Set(humantime::parse_duration(DEFAULT_DETACH_GRACE_PERIOD)
+                .expect("cannot parse default detach grace period")),
+            remote_deletion_delay: Set(humantime::parse_duration(DEFAULT_REMOTE_DELETION_DELAY)
+                .expect("cannot parse default remote deletion delay")),
+            standby_horizon_lease: Set(humantime::parse_duration(DEFAULT_STANDBY_HORIZON_LEASE)
+                .expect("cannot parse default standby horizon lease")),
+            download_wait_timeout: Set(humantime::parse_duration(DEFAULT_DOWNLOAD_WAIT_TIMEOUT)
+                .expect("cannot parse default download wait timeout")),
+            use_syncfs: Set(DEFAULT_USE_SYNCFS),
+            layer_read_mode: Set(LayerReadMode::Pread),
+            max_concurrent_layer_reads: Set(DEFAULT_MAX_CONCURRENT_LAYER_READS),
+            rel_size_cache_size: Set(DEFAULT_REL_SIZE_CACHE_SIZE),
+            max_ancestor_hops: Set(DEFAULT_MAX_ANCESTOR_HOPS),
+            stream_layer_uploads: Set(DEFAULT_STREAM_LAYER_UPLOADS),
+            max_open_layer_size: Set(DEFAULT_MAX_OPEN_LAYER_SIZE),
+            warm_up_pages_on_start: Set(DEFAULT_WARM_UP_PAGES_ON_START),
+            wal_receiver_status_interval: Set(humantime::parse_duration(
+                DEFAULT_WAL_RECEIVER_STATUS_INTERVAL,
+            )
+            .expect("cannot parse default WAL receiver status interval")),
+            wal_receiver_timeout: Set(humantime::parse_duration(DEFAULT_WAL_RECEIVER_TIMEOUT)
+                .expect("cannot parse default WAL receiver timeout")),
+            gc_min_layer_age: Set(humantime::parse_duration(DEFAULT_GC_MIN_LAYER_AGE)
+                .expect("cannot parse default GC min layer age")),
+            max_concurrent_gc: Set(DEFAULT_MAX_CONCURRENT_GC),
             workdir: Set(PathBuf::new()),
             pg_distrib_dir: Set(env::current_dir()
                 .expect("cannot access current directory")
                 .join("tmp_install")),
             auth_type: Set(AuthType::Trust),
             auth_validation_public_key_path: Set(None),
+            metrics_auth_file: Set(None),
             remote_storage_config: Set(None),
+            remote_storage_fallbacks: Set(Vec::new()),
+            remote_storage_mirror: Set(None),
             id: NotSet,
             profiling: Set(ProfilingConfig::Disabled),
             broker_etcd_prefix: Set(etcd_broker::DEFAULT_NEON_BROKER_ETCD_PREFIX.to_string()),
             broker_endpoints: Set(Vec::new()),
+            compute_notify_url: Set(None),
         }
     }
 }
@@ -245,6 +509,98 @@ impl PageServerConfigBuilder {
         self.max_file_descriptors = BuilderValue::Set(max_file_descriptors)
     }
 
+    pub fn hot_page_cache_size(&mut self, hot_page_cache_size: usize) {
+        self.hot_page_cache_size = BuilderValue::Set(hot_page_cache_size)
+    }
+
+    pub fn prefetch_ancestor_layers(&mut self, prefetch_ancestor_layers: bool) {
+        self.prefetch_ancestor_layers = BuilderValue::Set(prefetch_ancestor_layers)
+    }
+
+    pub fn ephemeral_file_pool_size(&mut self, ephemeral_file_pool_size: usize) {
+        self.ephemeral_file_pool_size = BuilderValue::Set(ephemeral_file_pool_size)
+    }
+
+    pub fn ephemeral_file_preallocate(&mut self, ephemeral_file_preallocate: u64) {
+        self.ephemeral_file_preallocate = BuilderValue::Set(ephemeral_file_preallocate)
+    }
+
+    pub fn layer_flush_pacing_percent(&mut self, layer_flush_pacing_percent: u64) {
+        self.layer_flush_pacing_percent = BuilderValue::Set(layer_flush_pacing_percent)
+    }
+
+    pub fn layer_flush_sync_interval(&mut self, layer_flush_sync_interval: u64) {
+        self.layer_flush_sync_interval = BuilderValue::Set(layer_flush_sync_interval)
+    }
+
+    pub fn metrics_max_labeled_tenants(&mut self, metrics_max_labeled_tenants: usize) {
+        self.metrics_max_labeled_tenants = BuilderValue::Set(metrics_max_labeled_tenants)
+    }
+
+    pub fn detach_grace_period(&mut self, detach_grace_period: Duration) {
+        self.detach_grace_period = BuilderValue::Set(detach_grace_period)
+    }
+
+    pub fn remote_deletion_delay(&mut self, remote_deletion_delay: Duration) {
+        self.remote_deletion_delay = BuilderValue::Set(remote_deletion_delay)
+    }
+
+    pub fn standby_horizon_lease(&mut self, standby_horizon_lease: Duration) {
+        self.standby_horizon_lease = BuilderValue::Set(standby_horizon_lease)
+    }
+
+    pub fn download_wait_timeout(&mut self, download_wait_timeout: Duration) {
+        self.download_wait_timeout = BuilderValue::Set(download_wait_timeout)
+    }
+
+    pub fn use_syncfs(&mut self, use_syncfs: bool) {
+        self.use_syncfs = BuilderValue::Set(use_syncfs)
+    }
+
+    pub fn layer_read_mode(&mut self, layer_read_mode: LayerReadMode) {
+        self.layer_read_mode = BuilderValue::Set(layer_read_mode)
+    }
+
+    pub fn max_concurrent_layer_reads(&mut self, max_concurrent_layer_reads: usize) {
+        self.max_concurrent_layer_reads = BuilderValue::Set(max_concurrent_layer_reads)
+    }
+
+    pub fn rel_size_cache_size(&mut self, rel_size_cache_size: usize) {
+        self.rel_size_cache_size = BuilderValue::Set(rel_size_cache_size)
+    }
+
+    pub fn max_ancestor_hops(&mut self, max_ancestor_hops: usize) {
+        self.max_ancestor_hops = BuilderValue::Set(max_ancestor_hops)
+    }
+
+    pub fn stream_layer_uploads(&mut self, stream_layer_uploads: bool) {
+        self.stream_layer_uploads = BuilderValue::Set(stream_layer_uploads)
+    }
+
+    pub fn max_open_layer_size(&mut self, max_open_layer_size: u64) {
+        self.max_open_layer_size = BuilderValue::Set(max_open_layer_size)
+    }
+
+    pub fn warm_up_pages_on_start(&mut self, warm_up_pages_on_start: usize) {
+        self.warm_up_pages_on_start = BuilderValue::Set(warm_up_pages_on_start)
+    }
+
+    pub fn wal_receiver_status_interval(&mut self, wal_receiver_status_interval: Duration) {
+        self.wal_receiver_status_interval = BuilderValue::Set(wal_receiver_status_interval)
+    }
+
+    pub fn wal_receiver_timeout(&mut self, wal_receiver_timeout: Duration) {
+        self.wal_receiver_timeout = BuilderValue::Set(wal_receiver_timeout)
+    }
+
+    pub fn gc_min_layer_age(&mut self, gc_min_layer_age: Duration) {
+        self.gc_min_layer_age = BuilderValue::Set(gc_min_layer_age)
+    }
+
+    pub fn max_concurrent_gc(&mut self, max_concurrent_gc: usize) {
+        self.max_concurrent_gc = BuilderValue::Set(max_concurrent_gc)
+    }
+
     pub fn workdir(&mut self, workdir: PathBuf) {
         self.workdir = BuilderValue::Set(workdir)
     }
@@ -264,10 +620,25 @@ impl PageServerConfigBuilder {
         self.auth_validation_public_key_path = BuilderValue::Set(auth_validation_public_key_path)
     }
 
+    pub fn metrics_auth_file(&mut self, metrics_auth_file: Option<PathBuf>) {
+        self.metrics_auth_file = BuilderValue::Set(metrics_auth_file)
+    }
+
     pub fn remote_storage_config(&mut self, remote_storage_config: Option<RemoteStorageConfig>) {
         self.remote_storage_config = BuilderValue::Set(remote_storage_config)
     }
 
+    pub fn remote_storage_fallbacks(&mut self, remote_storage_fallbacks: Vec<RemoteStorageConfig>) {
+        self.remote_storage_fallbacks = BuilderValue::Set(remote_storage_fallbacks)
+    }
+
+    pub fn remote_storage_mirror(
+        &mut self,
+        remote_storage_mirror: Option<RemoteStorageMirrorConfig>,
+    ) {
+        self.remote_storage_mirror = BuilderValue::Set(remote_storage_mirror)
+    }
+
     pub fn broker_endpoints(&mut self, broker_endpoints: Vec<Url>) {
         self.broker_endpoints = BuilderValue::Set(broker_endpoints)
     }
@@ -284,6 +655,10 @@ impl PageServerConfigBuilder {
         self.profiling = BuilderValue::Set(profiling)
     }
 
+    pub fn compute_notify_url(&mut self, compute_notify_url: Option<Url>) {
+        self.compute_notify_url = BuilderValue::Set(compute_notify_url)
+    }
+
     pub fn build(self) -> anyhow::Result<PageServerConf> {
         let broker_endpoints = self
             .broker_endpoints
@@ -309,6 +684,73 @@ impl PageServerConfigBuilder {
             max_file_descriptors: self
                 .max_file_descriptors
                 .ok_or(anyhow!("missing max_file_descriptors"))?,
+            hot_page_cache_size: self
+                .hot_page_cache_size
+                .ok_or(anyhow!("missing hot_page_cache_size"))?,
+            prefetch_ancestor_layers: self
+                .prefetch_ancestor_layers
+                .ok_or(anyhow!("missing prefetch_ancestor_layers"))?,
+            ephemeral_file_pool_size: self
+                .ephemeral_file_pool_size
+                .ok_or(anyhow!("missing ephemeral_file_pool_size"))?,
+            ephemeral_file_preallocate: self
+                .ephemeral_file_preallocate
+                .ok_or(anyhow!("missing ephemeral_file_preallocate"))?,
+            layer_flush_pacing_percent: self
+                .layer_flush_pacing_percent
+                .ok_or(anyhow!("missing layer_flush_pacing_percent"))?,
+            layer_flush_sync_interval: self
+                .layer_flush_sync_interval
+                .ok_or(anyhow!("missing layer_flush_sync_interval"))?,
+            metrics_max_labeled_tenants: self
+                .metrics_max_labeled_tenants
+                .ok_or(anyhow!("missing metrics_max_labeled_tenants"))?,
+            detach_grace_period: self
+                .detach_grace_period
+                .ok_or(anyhow!("missing detach_grace_period"))?,
+            remote_deletion_delay: self
+                .remote_deletion_delay
+                .ok_or(anyhow!("missing remote_deletion_delay"))?,
+            standby_horizon_lease: self
+                .standby_horizon_lease
+                .ok_or(anyhow!("missing standby_horizon_lease"))?,
+            download_wait_timeout: self
+                .download_wait_timeout
+                .ok_or(anyhow!("missing download_wait_timeout"))?,
+            use_syncfs: self.use_syncfs.ok_or(anyhow!("missing use_syncfs"))?,
+            layer_read_mode: self
+                .layer_read_mode
+                .ok_or(anyhow!("missing layer_read_mode"))?,
+            max_concurrent_layer_reads: self
+                .max_concurrent_layer_reads
+                .ok_or(anyhow!("missing max_concurrent_layer_reads"))?,
+            rel_size_cache_size: self
+                .rel_size_cache_size
+                .ok_or(anyhow!("missing rel_size_cache_size"))?,
+            max_ancestor_hops: self
+                .max_ancestor_hops
+                .ok_or(anyhow!("missing max_ancestor_hops"))?,
+            stream_layer_uploads: self
+                .stream_layer_uploads
+                .ok_or(anyhow!("missing stream_layer_uploads"))?,
+            max_open_layer_size: self
+                .max_open_layer_size
+                .ok_or(anyhow!("missing max_open_layer_size"))?,
+            warm_up_pages_on_start: self
+                .warm_up_pages_on_start
+                .ok_or(anyhow!("missing warm_up_pages_on_start"))?,
+            wal_receiver_status_interval: self
+                .wal_receiver_status_interval
+                .ok_or(anyhow!("missing wal_receiver_status_interval"))?,
+            wal_receiver_timeout: self
+                .wal_receiver_timeout
+                .ok_or(anyhow!("missing wal_receiver_timeout"))?,
+            gc_min_layer_age: self
+                .gc_min_layer_age
+                .ok_or(anyhow!("missing gc_min_layer_age"))?,
+            max_concurrent_gc: self
+                .max_concurrent_gc
+                .ok_or(anyhow!("missing max_concurrent_gc"))?,
             workdir: self.workdir.ok_or(anyhow!("missing workdir"))?,
             pg_distrib_dir: self
                 .pg_distrib_dir
@@ -317,9 +759,18 @@ impl PageServerConfigBuilder {
             auth_validation_public_key_path: self
                 .auth_validation_public_key_path
                 .ok_or(anyhow!("missing auth_validation_public_key_path"))?,
+            metrics_auth_file: self
+                .metrics_auth_file
+                .ok_or(anyhow!("missing metrics_auth_file"))?,
             remote_storage_config: self
                 .remote_storage_config
                 .ok_or(anyhow!("missing remote_storage_config"))?,
+            remote_storage_fallbacks: self
+                .remote_storage_fallbacks
+                .ok_or(anyhow!("missing remote_storage_fallbacks"))?,
+            remote_storage_mirror: self
+                .remote_storage_mirror
+                .ok_or(anyhow!("missing remote_storage_mirror"))?,
             id: self.id.ok_or(anyhow!("missing id"))?,
             profiling: self.profiling.ok_or(anyhow!("missing profiling"))?,
             // TenantConf is handled separately
@@ -328,6 +779,9 @@ impl PageServerConfigBuilder {
             broker_etcd_prefix: self
                 .broker_etcd_prefix
                 .ok_or(anyhow!("missing broker_etcd_prefix"))?,
+            compute_notify_url: self
+                .compute_notify_url
+                .ok_or(anyhow!("missing compute_notify_url"))?,
         })
     }
 }
@@ -386,16 +840,56 @@ impl PageServerConf {
                 "max_file_descriptors" => {
                     builder.max_file_descriptors(parse_toml_u64(key, item)? as usize)
                 }
+                "hot_page_cache_size" => {
+                    builder.hot_page_cache_size(parse_toml_u64(key, item)? as usize)
+                }
+                "prefetch_ancestor_layers" => {
+                    builder.prefetch_ancestor_layers(parse_toml_bool(key, item)?)
+                }
+                "ephemeral_file_pool_size" => {
+                    builder.ephemeral_file_pool_size(parse_toml_u64(key, item)? as usize)
+                }
+                "ephemeral_file_preallocate" => {
+                    builder.ephemeral_file_preallocate(parse_toml_u64(key, item)?)
+                }
+                "layer_flush_pacing_percent" => builder.layer_flush_pacing_percent(parse_toml_u64(key, item)?),
+                "layer_flush_sync_interval" => builder.layer_flush_sync_interval(parse_toml_u64(key, item)?),
+                "metrics_max_labeled_tenants" => builder.metrics_max_labeled_tenants(parse_toml_u64(key, item)? as usize),
+                "detach_grace_period" => builder.detach_grace_period(parse_toml_duration(key, item)?),
+                "remote_deletion_delay" => builder.remote_deletion_delay(parse_toml_duration(key, item)?),
+                "standby_horizon_lease" => builder.standby_horizon_lease(parse_toml_duration(key, item)?),
+                "download_wait_timeout" => builder.download_wait_timeout(parse_toml_duration(key, item)?),
+                "use_syncfs" => builder.use_syncfs(parse_toml_bool(key, item)?),
+                "layer_read_mode" => builder.layer_read_mode(parse_toml_from_str(key, item)?),
+                "max_concurrent_layer_reads" => builder.max_concurrent_layer_reads(parse_toml_u64(key, item)? as usize),
+                "rel_size_cache_size" => builder.rel_size_cache_size(parse_toml_u64(key, item)? as usize),
+                "max_ancestor_hops" => builder.max_ancestor_hops(parse_toml_u64(key, item)? as usize),
+                "stream_layer_uploads" => builder.stream_layer_uploads(parse_toml_bool(key, item)?),
+                "max_open_layer_size" => builder.max_open_layer_size(parse_toml_u64(key, item)?),
+                "warm_up_pages_on_start" => builder.warm_up_pages_on_start(parse_toml_u64(key, item)? as usize),
+                "wal_receiver_status_interval" => builder.wal_receiver_status_interval(parse_toml_duration(key, item)?),
+                "wal_receiver_timeout" => builder.wal_receiver_timeout(parse_toml_duration(key, item)?),
+                "gc_min_layer_age" => builder.gc_min_layer_age(parse_toml_duration(key, item)?),
+                "max_concurrent_gc" => {
+                    builder.max_concurrent_gc(parse_toml_u64(key, item)? as usize)
+                }
                 "pg_distrib_dir" => {
                     builder.pg_distrib_dir(PathBuf::from(parse_toml_string(key, item)?))
                 }
                 "auth_validation_public_key_path" => builder.auth_validation_public_key_path(Some(
                     PathBuf::from(parse_toml_string(key, item)?),
                 )),
+                "metrics_auth_file" => builder.metrics_auth_file(Some(PathBuf::from(
+                    parse_toml_string(key, item)?,
+                ))),
                 "auth_type" => builder.auth_type(parse_toml_from_str(key, item)?),
                 "remote_storage" => {
                     builder.remote_storage_config(Some(RemoteStorageConfig::from_toml(item)?))
                 }
+                "remote_storage_fallback" => builder
+                    .remote_storage_fallbacks(parse_toml_remote_storage_fallbacks(key, item)?),
+                "remote_storage_mirror" => builder
+                    .remote_storage_mirror(Some(RemoteStorageMirrorConfig::from_toml(item)?)),
                 "tenant_config" => {
                     t_conf = Self::parse_toml_tenant_conf(item)?;
                 }
@@ -412,6 +906,11 @@ impl PageServerConf {
                         })
                         .collect::<anyhow::Result<_>>()?,
                 ),
+                "compute_notify_url" => builder.compute_notify_url(Some(
+                    parse_toml_string(key, item)?
+                        .parse::<Url>()
+                        .with_context(|| format!("Value for key {key} is not a valid url"))?,
+                )),
                 _ => bail!("unrecognized pageserver option '{key}'"),
             }
         }
@@ -431,6 +930,34 @@ impl PageServerConf {
             );
         }
 
+        if let Some(metrics_auth_file) = &conf.metrics_auth_file {
+            ensure!(
+                metrics_auth_file.exists(),
+                format!(
+                    "Can't find metrics_auth_file at '{}'",
+                    metrics_auth_file.display()
+                )
+            );
+        }
+
+        ensure!(
+            conf.remote_storage_fallbacks.is_empty() || conf.remote_storage_config.is_some(),
+            "remote_storage_fallback requires remote_storage to be configured"
+        );
+        ensure!(
+            conf.remote_storage_mirror.is_none() || conf.remote_storage_config.is_some(),
+            "remote_storage_mirror requires remote_storage to be configured"
+        );
+
+        ensure!(
+            conf.max_concurrent_gc > 0,
+            "max_concurrent_gc must be positive"
+        );
+        ensure!(
+            conf.max_concurrent_layer_reads > 0,
+            "max_concurrent_layer_reads must be positive"
+        );
+
         if !conf.pg_distrib_dir.join("bin/postgres").exists() {
             bail!(
                 "Can't find postgres binary at {}",
@@ -439,6 +966,7 @@ impl PageServerConf {
         }
 
         conf.default_tenant_conf = t_conf.merge(TenantConf::default());
+        conf.default_tenant_conf.validate()?;
 
         Ok(conf)
     }
@@ -481,6 +1009,18 @@ impl PageServerConf {
             t_conf.pitr_interval = Some(parse_toml_duration("pitr_interval", pitr_interval)?);
         }
 
+        if let Some(logical_size_soft_limit) = item.get("logical_size_soft_limit") {
+            t_conf.logical_size_soft_limit = Some(parse_toml_u64(
+                "logical_size_soft_limit",
+                logical_size_soft_limit,
+            )?);
+        }
+
+        if let Some(image_layer_excluded_forks) = item.get("image_layer_excluded_forks") {
+            let names = parse_toml_array("image_layer_excluded_forks", image_layer_excluded_forks)?;
+            t_conf.image_layer_excluded_forks = Some(ForkSet::from_names(&names)?);
+        }
+
         Ok(t_conf)
     }
 
@@ -497,6 +1037,29 @@ impl PageServerConf {
             wal_redo_timeout: Duration::from_secs(60),
             page_cache_size: defaults::DEFAULT_PAGE_CACHE_SIZE,
             max_file_descriptors: defaults::DEFAULT_MAX_FILE_DESCRIPTORS,
+            hot_page_cache_size: defaults::DEFAULT_HOT_PAGE_CACHE_SIZE,
+            prefetch_ancestor_layers: defaults::DEFAULT_PREFETCH_ANCESTOR_LAYERS,
+            ephemeral_file_pool_size: defaults::DEFAULT_EPHEMERAL_FILE_POOL_SIZE,
+            ephemeral_file_preallocate: defaults::DEFAULT_EPHEMERAL_FILE_PREALLOCATE,
+            layer_flush_pacing_percent: defaults::DEFAULT_LAYER_FLUSH_PACING_PERCENT,
+            layer_flush_sync_interval: defaults::DEFAULT_LAYER_FLUSH_SYNC_INTERVAL,
+            metrics_max_labeled_tenants: defaults::DEFAULT_METRICS_MAX_LABELED_TENANTS,
+            detach_grace_period: Duration::from_secs(60 * 60),
+            remote_deletion_delay: Duration::from_secs(0),
+            standby_horizon_lease: Duration::from_secs(30),
+            download_wait_timeout: Duration::from_secs(30),
+            use_syncfs: defaults::DEFAULT_USE_SYNCFS,
+            layer_read_mode: LayerReadMode::Pread,
+            max_concurrent_layer_reads: defaults::DEFAULT_MAX_CONCURRENT_LAYER_READS,
+            rel_size_cache_size: defaults::DEFAULT_REL_SIZE_CACHE_SIZE,
+            max_ancestor_hops: defaults::DEFAULT_MAX_ANCESTOR_HOPS,
+            stream_layer_uploads: defaults::DEFAULT_STREAM_LAYER_UPLOADS,
+            max_open_layer_size: defaults::DEFAULT_MAX_OPEN_LAYER_SIZE,
+            warm_up_pages_on_start: defaults::DEFAULT_WARM_UP_PAGES_ON_START,
+            wal_receiver_status_interval: Duration::from_secs(10),
+            wal_receiver_timeout: Duration::from_secs(60),
+            gc_min_layer_age: Duration::ZERO,
+            max_concurrent_gc: defaults::DEFAULT_MAX_CONCURRENT_GC,
             listen_pg_addr: defaults::DEFAULT_PG_LISTEN_ADDR.to_string(),
             listen_http_addr: defaults::DEFAULT_HTTP_LISTEN_ADDR.to_string(),
             superuser: "cloud_admin".to_string(),
@@ -504,11 +1067,15 @@ impl PageServerConf {
             pg_distrib_dir: PathBuf::new(),
             auth_type: AuthType::Trust,
             auth_validation_public_key_path: None,
+            metrics_auth_file: None,
             remote_storage_config: None,
+            remote_storage_fallbacks: Vec::new(),
+            remote_storage_mirror: None,
             profiling: ProfilingConfig::Disabled,
             default_tenant_conf: TenantConf::dummy_conf(),
             broker_endpoints: Vec::new(),
             broker_etcd_prefix: etcd_broker::DEFAULT_NEON_BROKER_ETCD_PREFIX.to_string(),
+            compute_notify_url: None,
         }
     }
 }
@@ -534,6 +1101,11 @@ fn parse_toml_u64(name: &str, item: &Item) -> Result<u64> {
     Ok(i as u64)
 }
 
+fn parse_toml_bool(name: &str, item: &Item) -> Result<bool> {
+    item.as_bool()
+        .with_context(|| format!("configure option {name} is not a bool"))
+}
+
 fn parse_toml_duration(name: &str, item: &Item) -> Result<Duration> {
     let s = item
         .as_str()
@@ -574,6 +1146,25 @@ fn parse_toml_array(name: &str, item: &Item) -> anyhow::Result<Vec<String>> {
         .collect()
 }
 
+/// Parses `[[remote_storage_fallback]]` tables, or an array of inline tables.
+fn parse_toml_remote_storage_fallbacks(
+    name: &str,
+    item: &Item,
+) -> anyhow::Result<Vec<RemoteStorageConfig>> {
+    match item {
+        Item::ArrayOfTables(tables) => tables
+            .iter()
+            .map(|table| RemoteStorageConfig::from_toml(&Item::Table(table.clone())))
+            .collect::<anyhow::Result<Vec<_>>>(),
+        Item::Value(toml_edit::Value::Array(array)) => array
+            .iter()
+            .map(|value| RemoteStorageConfig::from_toml(&Item::Value(value.clone())))
+            .collect::<anyhow::Result<Vec<_>>>(),
+        _ => bail!("configure option {name} is not an array of tables"),
+    }
+    .with_context(|| format!("Failed to parse {name}"))
+}
+
 #[cfg(test)]
 mod tests {
     use std::{
@@ -597,6 +1188,29 @@ wal_redo_timeout = '111 s'
 
 page_cache_size = 444
 max_file_descriptors = 333
+hot_page_cache_size = 22
+prefetch_ancestor_layers = true
+ephemeral_file_pool_size = 5
+ephemeral_file_preallocate = 1048576
+layer_flush_pacing_percent = 150
+layer_flush_sync_interval = 1048576
+metrics_max_labeled_tenants = 100
+detach_grace_period = '10 m'
+remote_deletion_delay = '1 h'
+standby_horizon_lease = '1 m'
+download_wait_timeout = '10 s'
+use_syncfs = true
+layer_read_mode = 'mmap'
+max_concurrent_layer_reads = 20
+rel_size_cache_size = 2000
+max_ancestor_hops = 5
+stream_layer_uploads = true
+max_open_layer_size = 33554432
+warm_up_pages_on_start = 500
+wal_receiver_status_interval = '5 s'
+wal_receiver_timeout = '30 s'
+gc_min_layer_age = '10 m'
+max_concurrent_gc = 7
 
 # initial superuser role name to use when creating a new tenant
 initial_superuser_name = 'zzzz'
@@ -630,17 +1244,52 @@ id = 10
                 superuser: defaults::DEFAULT_SUPERUSER.to_string(),
                 page_cache_size: defaults::DEFAULT_PAGE_CACHE_SIZE,
                 max_file_descriptors: defaults::DEFAULT_MAX_FILE_DESCRIPTORS,
+                hot_page_cache_size: defaults::DEFAULT_HOT_PAGE_CACHE_SIZE,
+                prefetch_ancestor_layers: defaults::DEFAULT_PREFETCH_ANCESTOR_LAYERS,
+                ephemeral_file_pool_size: defaults::DEFAULT_EPHEMERAL_FILE_POOL_SIZE,
+                ephemeral_file_preallocate: defaults::DEFAULT_EPHEMERAL_FILE_PREALLOCATE,
+                layer_flush_pacing_percent: defaults::DEFAULT_LAYER_FLUSH_PACING_PERCENT,
+                layer_flush_sync_interval: defaults::DEFAULT_LAYER_FLUSH_SYNC_INTERVAL,
+                metrics_max_labeled_tenants: defaults::DEFAULT_METRICS_MAX_LABELED_TENANTS,
+                detach_grace_period: humantime::parse_duration(
+                    defaults::DEFAULT_DETACH_GRACE_PERIOD
+                )?,
+                remote_deletion_delay: humantime::parse_duration(
+                    defaults::DEFAULT_REMOTE_DELETION_DELAY
+                )?,
+                standby_horizon_lease: humantime::parse_duration(
+                    defaults::DEFAULT_STANDBY_HORIZON_LEASE
+                )?,
+                download_wait_timeout: humantime::parse_duration(
+                    defaults::DEFAULT_DOWNLOAD_WAIT_TIMEOUT
+                )?,
+                use_syncfs: defaults::DEFAULT_USE_SYNCFS,
+                layer_read_mode: LayerReadMode::Pread,
+                max_concurrent_layer_reads: defaults::DEFAULT_MAX_CONCURRENT_LAYER_READS,
+                rel_size_cache_size: defaults::DEFAULT_REL_SIZE_CACHE_SIZE,
+                max_ancestor_hops: defaults::DEFAULT_MAX_ANCESTOR_HOPS,
+                stream_layer_uploads: defaults::DEFAULT_STREAM_LAYER_UPLOADS,
+                max_open_layer_size: defaults::DEFAULT_MAX_OPEN_LAYER_SIZE,
+                warm_up_pages_on_start: defaults::DEFAULT_WARM_UP_PAGES_ON_START,
+                wal_receiver_status_interval: Duration::from_secs(10),
+                wal_receiver_timeout: Duration::from_secs(60),
+                gc_min_layer_age: Duration::ZERO,
+                max_concurrent_gc: defaults::DEFAULT_MAX_CONCURRENT_GC,
                 workdir,
                 pg_distrib_dir,
                 auth_type: AuthType::Trust,
                 auth_validation_public_key_path: None,
+                metrics_auth_file: None,
                 remote_storage_config: None,
+                remote_storage_fallbacks: Vec::new(),
+                remote_storage_mirror: None,
                 profiling: ProfilingConfig::Disabled,
                 default_tenant_conf: TenantConf::default(),
                 broker_endpoints: vec![broker_endpoint
                     .parse()
                     .expect("Failed to parse a valid broker endpoint URL")],
                 broker_etcd_prefix: etcd_broker::DEFAULT_NEON_BROKER_ETCD_PREFIX.to_string(),
+                compute_notify_url: None,
             },
             "Correct defaults should be used when no config values are provided"
         );
@@ -674,17 +1323,44 @@ id = 10
                 superuser: "zzzz".to_string(),
                 page_cache_size: 444,
                 max_file_descriptors: 333,
+                hot_page_cache_size: 22,
+                prefetch_ancestor_layers: true,
+                ephemeral_file_pool_size: 5,
+                ephemeral_file_preallocate: 1048576,
+                layer_flush_pacing_percent: 150,
+                layer_flush_sync_interval: 1048576,
+                metrics_max_labeled_tenants: 100,
+                detach_grace_period: Duration::from_secs(600),
+                remote_deletion_delay: Duration::from_secs(60 * 60),
+                standby_horizon_lease: Duration::from_secs(60),
+                download_wait_timeout: Duration::from_secs(10),
+                use_syncfs: true,
+                layer_read_mode: LayerReadMode::Mmap,
+                max_concurrent_layer_reads: 20,
+                rel_size_cache_size: 2000,
+                max_ancestor_hops: 5,
+                stream_layer_uploads: true,
+                max_open_layer_size: 33554432,
+                warm_up_pages_on_start: 500,
+                wal_receiver_status_interval: Duration::from_secs(5),
+                wal_receiver_timeout: Duration::from_secs(30),
+                gc_min_layer_age: Duration::from_secs(600),
+                max_concurrent_gc: 7,
                 workdir,
                 pg_distrib_dir,
                 auth_type: AuthType::Trust,
                 auth_validation_public_key_path: None,
+                metrics_auth_file: None,
                 remote_storage_config: None,
+                remote_storage_fallbacks: Vec::new(),
+                remote_storage_mirror: None,
                 profiling: ProfilingConfig::Disabled,
                 default_tenant_conf: TenantConf::default(),
                 broker_endpoints: vec![broker_endpoint
                     .parse()
                     .expect("Failed to parse a valid broker endpoint URL")],
                 broker_etcd_prefix: etcd_broker::DEFAULT_NEON_BROKER_ETCD_PREFIX.to_string(),
+                compute_notify_url: None,
             },
             "Should be able to parse all basic config values correctly"
         );
@@ -740,6 +1416,18 @@ broker_endpoints = ['{broker_endpoint}']
                         .unwrap(),
                     max_sync_errors: NonZeroU32::new(remote_storage::DEFAULT_REMOTE_STORAGE_MAX_SYNC_ERRORS)
                         .unwrap(),
+                    max_concurrent_downloads: NonZeroUsize::new(
+                        remote_storage::DEFAULT_REMOTE_STORAGE_MAX_CONCURRENT_DOWNLOADS
+                    )
+                        .unwrap(),
+                    max_concurrent_tenant_downloads: NonZeroUsize::new(
+                        remote_storage::DEFAULT_REMOTE_STORAGE_MAX_CONCURRENT_TENANT_DOWNLOADS
+                    )
+                        .unwrap(),
+                    multipart_upload_part_size: NonZeroUsize::new(
+                        remote_storage::DEFAULT_REMOTE_STORAGE_MULTIPART_UPLOAD_PART_SIZE
+                    )
+                        .unwrap(),
                     storage: RemoteStorageKind::LocalFs(local_storage_path.clone()),
                 },
                 "Remote storage config should correctly parse the local FS config and fill other storage defaults"
@@ -748,6 +1436,156 @@ broker_endpoints = ['{broker_endpoint}']
         Ok(())
     }
 
+    #[test]
+    fn parse_remote_storage_fallbacks() -> anyhow::Result<()> {
+        let tempdir = tempdir()?;
+        let (workdir, pg_distrib_dir) = prepare_fs(&tempdir)?;
+        let broker_endpoint = "http://127.0.0.1:7777";
+
+        let local_storage_path = tempdir.path().join("local_remote_storage");
+        let fallback_path = tempdir.path().join("fallback_remote_storage");
+
+        let identical_toml_declarations = &[
+            format!(
+                r#"[remote_storage]
+local_path = '{}'
+
+[[remote_storage_fallback]]
+local_path = '{}'
+
+[[remote_storage_fallback]]
+bucket_name = 'replica-bucket'
+bucket_region = 'eu-central-1'"#,
+                local_storage_path.display(),
+                fallback_path.display(),
+            ),
+            format!(
+                "remote_storage={{local_path='{}'}}\n\
+                remote_storage_fallback=[{{local_path='{}'}}, {{bucket_name='replica-bucket', bucket_region='eu-central-1'}}]",
+                local_storage_path.display(),
+                fallback_path.display(),
+            ),
+        ];
+
+        for remote_storage_config_str in identical_toml_declarations {
+            let config_string = format!(
+                r#"{ALL_BASE_VALUES_TOML}
+pg_distrib_dir='{}'
+broker_endpoints = ['{broker_endpoint}']
+
+{remote_storage_config_str}"#,
+                pg_distrib_dir.display(),
+            );
+
+            let toml = config_string.parse()?;
+
+            let fallbacks = PageServerConf::parse_and_validate(&toml, &workdir)
+                .unwrap_or_else(|e| {
+                    panic!("Failed to parse config '{config_string}', reason: {e:?}")
+                })
+                .remote_storage_fallbacks
+                .into_iter()
+                .map(|fallback| fallback.storage)
+                .collect::<Vec<_>>();
+
+            assert_eq!(
+                fallbacks,
+                vec![
+                    RemoteStorageKind::LocalFs(fallback_path.clone()),
+                    RemoteStorageKind::AwsS3(S3Config {
+                        bucket_name: "replica-bucket".to_string(),
+                        bucket_region: "eu-central-1".to_string(),
+                        prefix_in_bucket: None,
+                        endpoint: None,
+                        concurrency_limit: NonZeroUsize::new(
+                            remote_storage::DEFAULT_REMOTE_STORAGE_S3_CONCURRENCY_LIMIT
+                        )
+                        .unwrap(),
+                    }),
+                ],
+                "Fallback storages should be parsed in order"
+            );
+        }
+
+        let config_string = format!(
+            r#"{ALL_BASE_VALUES_TOML}
+pg_distrib_dir='{}'
+broker_endpoints = ['{broker_endpoint}']
+
+[[remote_storage_fallback]]
+local_path = '{}'"#,
+            pg_distrib_dir.display(),
+            fallback_path.display(),
+        );
+        assert!(
+            PageServerConf::parse_and_validate(&config_string.parse()?, &workdir).is_err(),
+            "Fallback storages without the primary remote storage should be rejected"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn parse_remote_storage_mirror() -> anyhow::Result<()> {
+        let tempdir = tempdir()?;
+        let (workdir, pg_distrib_dir) = prepare_fs(&tempdir)?;
+        let broker_endpoint = "http://127.0.0.1:7777";
+
+        let local_storage_path = tempdir.path().join("local_remote_storage");
+        let mirror_path = tempdir.path().join("mirror_remote_storage");
+        let mirrored_tenant = ZTenantId::generate();
+
+        let config_string = format!(
+            r#"{ALL_BASE_VALUES_TOML}
+pg_distrib_dir='{}'
+broker_endpoints = ['{broker_endpoint}']
+
+[remote_storage]
+local_path = '{}'
+
+[remote_storage_mirror]
+local_path = '{}'
+tenants = ['{mirrored_tenant}']"#,
+            pg_distrib_dir.display(),
+            local_storage_path.display(),
+            mirror_path.display(),
+        );
+        let conf = PageServerConf::parse_and_validate(&config_string.parse()?, &workdir)?;
+        let mirror = conf
+            .remote_storage_mirror
+            .as_ref()
+            .expect("Mirror storage should be parsed");
+        assert_eq!(
+            mirror.storage.storage,
+            RemoteStorageKind::LocalFs(mirror_path.clone())
+        );
+        assert!(mirror.is_mirrored(
+            &conf,
+            &conf.timeline_path(&ZTimelineId::generate(), &mirrored_tenant)
+        ));
+        assert!(!mirror.is_mirrored(
+            &conf,
+            &conf.timeline_path(&ZTimelineId::generate(), &ZTenantId::generate())
+        ));
+
+        let config_string = format!(
+            r#"{ALL_BASE_VALUES_TOML}
+pg_distrib_dir='{}'
+broker_endpoints = ['{broker_endpoint}']
+
+[remote_storage_mirror]
+local_path = '{}'"#,
+            pg_distrib_dir.display(),
+            mirror_path.display(),
+        );
+        assert!(
+            PageServerConf::parse_and_validate(&config_string.parse()?, &workdir).is_err(),
+            "Mirror storage without the primary remote storage should be rejected"
+        );
+
+        Ok(())
+    }
+
     #[test]
     fn parse_remote_s3_storage_config() -> anyhow::Result<()> {
         let tempdir = tempdir()?;
@@ -759,7 +1597,10 @@ broker_endpoints = ['{broker_endpoint}']
         let endpoint = "http://localhost:5000".to_string();
         let max_concurrent_syncs = NonZeroUsize::new(111).unwrap();
         let max_sync_errors = NonZeroU32::new(222).unwrap();
+        let max_concurrent_downloads = NonZeroUsize::new(44).unwrap();
+        let max_concurrent_tenant_downloads = NonZeroUsize::new(4).unwrap();
         let s3_concurrency_limit = NonZeroUsize::new(333).unwrap();
+        let multipart_upload_part_size = NonZeroUsize::new(16 * 1024 * 1024).unwrap();
         let broker_endpoint = "http://127.0.0.1:7777";
 
         let identical_toml_declarations = &[
@@ -767,6 +1608,9 @@ broker_endpoints = ['{broker_endpoint}']
                 r#"[remote_storage]
 max_concurrent_syncs = {max_concurrent_syncs}
 max_sync_errors = {max_sync_errors}
+max_concurrent_downloads = {max_concurrent_downloads}
+max_concurrent_tenant_downloads = {max_concurrent_tenant_downloads}
+multipart_upload_part_size = {multipart_upload_part_size}
 bucket_name = '{bucket_name}'
 bucket_region = '{bucket_region}'
 prefix_in_bucket = '{prefix_in_bucket}'
@@ -774,7 +1618,8 @@ endpoint = '{endpoint}'
 concurrency_limit = {s3_concurrency_limit}"#
             ),
             format!(
-                "remote_storage={{max_concurrent_syncs={max_concurrent_syncs}, max_sync_errors={max_sync_errors}, bucket_name='{bucket_name}',\
+                "remote_storage={{max_concurrent_syncs={max_concurrent_syncs}, max_sync_errors={max_sync_errors}, max_concurrent_downloads={max_concurrent_downloads}, \
+                max_concurrent_tenant_downloads={max_concurrent_tenant_downloads}, multipart_upload_part_size={multipart_upload_part_size}, bucket_name='{bucket_name}',\
                 bucket_region='{bucket_region}', prefix_in_bucket='{prefix_in_bucket}', endpoint='{endpoint}', concurrency_limit={s3_concurrency_limit}}}",
             ),
         ];
@@ -803,6 +1648,9 @@ broker_endpoints = ['{broker_endpoint}']
                 RemoteStorageConfig {
                     max_concurrent_syncs,
                     max_sync_errors,
+                    max_concurrent_downloads,
+                    max_concurrent_tenant_downloads,
+                    multipart_upload_part_size,
                     storage: RemoteStorageKind::AwsS3(S3Config {
                         bucket_name: bucket_name.clone(),
                         bucket_region: bucket_region.clone(),
@@ -0,0 +1,171 @@
+//!
+//! Data directory format marker.
+//!
+//! The pageserver writes a small JSON file into its working directory, recording
+//! the storage format version and the layout generation of the data directory,
+//! along with the version of the pageserver that wrote it. On startup, the marker
+//! is checked before any tenants are loaded: if the directory was written by a
+//! newer, incompatible pageserver, we refuse to start with a clear message,
+//! instead of misreading its metadata or renaming its files away.
+//!
+use std::fs::{self, File};
+use std::io::{ErrorKind, Write};
+use std::path::Path;
+
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use tracing::*;
+
+use crate::config::PageServerConf;
+use crate::{DATADIR_LAYOUT_GENERATION, STORAGE_FORMAT_VERSION};
+
+pub const FORMAT_MARKER_FILE_NAME: &str = "format_version.json";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FormatMarker {
+    pub storage_format_version: u16,
+    pub layout_generation: u16,
+    /// Version of the pageserver binary that last wrote the marker, for humans.
+    pub pageserver_version: String,
+}
+
+impl FormatMarker {
+    pub fn current(pageserver_version: &str) -> Self {
+        FormatMarker {
+            storage_format_version: STORAGE_FORMAT_VERSION,
+            layout_generation: DATADIR_LAYOUT_GENERATION,
+            pageserver_version: pageserver_version.to_string(),
+        }
+    }
+
+    /// Can a pageserver with the given marker work with the data directory described by 'self'?
+    fn check_compatible(&self, ours: &FormatMarker) -> anyhow::Result<()> {
+        if self.storage_format_version > ours.storage_format_version
+            || self.layout_generation > ours.layout_generation
+        {
+            bail!(
+                "data directory was written by a newer pageserver (version '{}', storage format version {}, layout generation {}), \
+                this pageserver (version '{}') supports storage format version {} and layout generation {}. \
+                Refusing to start, upgrade the pageserver or use another data directory",
+                self.pageserver_version,
+                self.storage_format_version,
+                self.layout_generation,
+                ours.pageserver_version,
+                ours.storage_format_version,
+                ours.layout_generation,
+            );
+        }
+        Ok(())
+    }
+}
+
+///
+/// Check the format marker in the pageserver's working directory, failing if the
+/// directory was written by a newer pageserver. Then (re)write the marker with the
+/// current versions.
+///
+/// A missing marker means the directory is either new, or was written by a
+/// pageserver that predates the markers; both are fine.
+///
+pub fn check_and_update(conf: &PageServerConf, pageserver_version: &str) -> anyhow::Result<()> {
+    let marker_path = conf.workdir.join(FORMAT_MARKER_FILE_NAME);
+    let ours = FormatMarker::current(pageserver_version);
+
+    match fs::read(&marker_path) {
+        Ok(contents) => {
+            let existing: FormatMarker = serde_json::from_slice(&contents).with_context(|| {
+                format!(
+                    "Failed to parse data directory format marker '{}'",
+                    marker_path.display()
+                )
+            })?;
+            existing.check_compatible(&ours)?;
+            if existing == ours {
+                return Ok(());
+            }
+            info!(
+                "updating data directory format marker, previously written by pageserver version '{}'",
+                existing.pageserver_version
+            );
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            info!("no data directory format marker found, creating one");
+        }
+        Err(e) => {
+            return Err(e).with_context(|| {
+                format!(
+                    "Failed to read data directory format marker '{}'",
+                    marker_path.display()
+                )
+            })
+        }
+    }
+
+    write_marker(&marker_path, &ours).with_context(|| {
+        format!(
+            "Failed to write data directory format marker '{}'",
+            marker_path.display()
+        )
+    })
+}
+
+/// Write the marker into a temporary file and rename it in place, so that a crash
+/// never leaves a torn marker behind.
+fn write_marker(marker_path: &Path, marker: &FormatMarker) -> anyhow::Result<()> {
+    let temp_path = marker_path.with_extension("json.tmp");
+    let mut file = File::create(&temp_path)?;
+    file.write_all(&serde_json::to_vec_pretty(marker)?)?;
+    file.sync_all()?;
+    fs::rename(&temp_path, marker_path)?;
+    if let Some(parent) = marker_path.parent() {
+        File::open(parent)?.sync_all()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn marker_is_written_and_accepted() -> anyhow::Result<()> {
+        let repo_dir = PageServerConf::test_repo_dir("format_marker_is_written");
+        let _ = fs::remove_dir_all(&repo_dir);
+        fs::create_dir_all(&repo_dir)?;
+        let conf = PageServerConf::dummy_conf(repo_dir.clone());
+
+        check_and_update(&conf, "old")?;
+        check_and_update(&conf, "new")?;
+
+        let marker: FormatMarker =
+            serde_json::from_slice(&fs::read(repo_dir.join(FORMAT_MARKER_FILE_NAME))?)?;
+        assert_eq!(marker, FormatMarker::current("new"));
+        Ok(())
+    }
+
+    #[test]
+    fn newer_marker_is_refused() -> anyhow::Result<()> {
+        let repo_dir = PageServerConf::test_repo_dir("format_marker_newer_refused");
+        let _ = fs::remove_dir_all(&repo_dir);
+        fs::create_dir_all(&repo_dir)?;
+        let conf = PageServerConf::dummy_conf(repo_dir.clone());
+
+        let newer = FormatMarker {
+            layout_generation: DATADIR_LAYOUT_GENERATION + 1,
+            ..FormatMarker::current("from the future")
+        };
+        write_marker(&repo_dir.join(FORMAT_MARKER_FILE_NAME), &newer)?;
+
+        let err = check_and_update(&conf, "current").unwrap_err();
+        assert!(
+            err.to_string().contains("newer pageserver"),
+            "unexpected error: {err:?}"
+        );
+
+        // The marker must be left untouched
+        let marker: FormatMarker =
+            serde_json::from_slice(&fs::read(repo_dir.join(FORMAT_MARKER_FILE_NAME))?)?;
+        assert_eq!(marker, newer);
+        Ok(())
+    }
+}
@@ -0,0 +1,265 @@
+//!
+//! Server-wide coordination of garbage collection.
+//!
+//! Every active tenant has its own GC thread, but running GC for hundreds of
+//! tenants at the same time would saturate the disk. Before starting a GC
+//! iteration, the GC thread asks the coordinator for a permit. At most
+//! `max_concurrent_gc` permits are handed out at a time. When more tenants are
+//! waiting, the tenant with the most estimated reclaimable bytes goes first.
+//!
+//! The whole GC subsystem can also be paused and resumed through the management
+//! API. While paused, no new permits are handed out; GC iterations that are
+//! already running are allowed to finish.
+//!
+//! Explicit GC requests through the `do_gc` API bypass the coordinator.
+//!
+use std::collections::HashMap;
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+use tracing::*;
+use utils::zid::ZTenantId;
+
+static GC_COORDINATOR: OnceCell<GcCoordinator> = OnceCell::new();
+const TEST_MAX_CONCURRENT_GC: usize = 4;
+
+/// How often a waiting GC thread re-checks its cancellation condition.
+const WAIT_RECHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+///
+/// Initialize the GC coordinator. This must be called once at page server startup.
+///
+pub fn init(max_concurrent_gc: usize) {
+    if GC_COORDINATOR
+        .set(GcCoordinator::new(max_concurrent_gc))
+        .is_err()
+    {
+        panic!("GC coordinator already initialized");
+    }
+}
+
+///
+/// Get a handle to the GC coordinator.
+///
+pub fn get() -> &'static GcCoordinator {
+    // In unit tests, page server startup doesn't happen and no one calls
+    // gc_coordinator::init(). Initialize it here with a small limit.
+    if cfg!(test) {
+        GC_COORDINATOR.get_or_init(|| GcCoordinator::new(TEST_MAX_CONCURRENT_GC))
+    } else {
+        GC_COORDINATOR
+            .get()
+            .expect("GC coordinator not initialized")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct GcCoordinatorStatus {
+    pub paused: bool,
+    pub max_concurrent_gc: usize,
+    pub running: usize,
+    pub waiting: usize,
+}
+
+pub struct GcCoordinator {
+    max_concurrent_gc: usize,
+    state: Mutex<CoordinatorState>,
+    state_changed: Condvar,
+}
+
+#[derive(Default)]
+struct CoordinatorState {
+    paused: bool,
+    running: usize,
+    /// Tenants waiting for a permit, with their estimated reclaimable bytes.
+    waiting: HashMap<ZTenantId, u64>,
+}
+
+impl CoordinatorState {
+    /// The waiting tenant that should get the next permit: the one with the
+    /// most reclaimable bytes. Ties are broken by tenant id, to be deterministic.
+    fn next_in_line(&self) -> Option<ZTenantId> {
+        self.waiting
+            .iter()
+            .max_by(|(a_id, a_bytes), (b_id, b_bytes)| {
+                a_bytes.cmp(b_bytes).then_with(|| b_id.cmp(a_id))
+            })
+            .map(|(tenant_id, _)| *tenant_id)
+    }
+}
+
+///
+/// A permit to run one GC iteration. The slot is released when it's dropped.
+///
+pub struct GcPermit<'a> {
+    coordinator: &'a GcCoordinator,
+}
+
+impl Drop for GcPermit<'_> {
+    fn drop(&mut self) {
+        let mut state = self.coordinator.state.lock().unwrap();
+        state.running -= 1;
+        self.coordinator.state_changed.notify_all();
+    }
+}
+
+impl GcCoordinator {
+    fn new(max_concurrent_gc: usize) -> Self {
+        assert!(max_concurrent_gc > 0, "max_concurrent_gc must be positive");
+        GcCoordinator {
+            max_concurrent_gc,
+            state: Mutex::new(CoordinatorState::default()),
+            state_changed: Condvar::new(),
+        }
+    }
+
+    ///
+    /// Wait for a permit to run GC for the given tenant.
+    ///
+    /// 'reclaimable_bytes' is the tenant's priority. 'cancelled' is polled
+    /// periodically while waiting; if it returns true, the tenant gives up its
+    /// place in the queue and None is returned.
+    ///
+    pub fn acquire(
+        &self,
+        tenant_id: ZTenantId,
+        reclaimable_bytes: u64,
+        cancelled: impl Fn() -> bool,
+    ) -> Option<GcPermit<'_>> {
+        let mut state = self.state.lock().unwrap();
+        state.waiting.insert(tenant_id, reclaimable_bytes);
+        loop {
+            if !state.paused
+                && state.running < self.max_concurrent_gc
+                && state.next_in_line() == Some(tenant_id)
+            {
+                state.waiting.remove(&tenant_id);
+                state.running += 1;
+                // Others may be able to proceed too, if there are still free slots.
+                self.state_changed.notify_all();
+                return Some(GcPermit { coordinator: self });
+            }
+
+            if cancelled() {
+                state.waiting.remove(&tenant_id);
+                self.state_changed.notify_all();
+                return None;
+            }
+
+            state = self
+                .state_changed
+                .wait_timeout(state, WAIT_RECHECK_INTERVAL)
+                .unwrap()
+                .0;
+        }
+    }
+
+    pub fn pause(&self) {
+        let mut state = self.state.lock().unwrap();
+        if !state.paused {
+            info!("pausing garbage collection");
+            state.paused = true;
+        }
+    }
+
+    pub fn resume(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.paused {
+            info!("resuming garbage collection");
+            state.paused = false;
+            self.state_changed.notify_all();
+        }
+    }
+
+    pub fn status(&self) -> GcCoordinatorStatus {
+        let state = self.state.lock().unwrap();
+        GcCoordinatorStatus {
+            paused: state.paused,
+            max_concurrent_gc: self.max_concurrent_gc,
+            running: state.running,
+            waiting: state.waiting.len(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn limits_concurrency() {
+        let coordinator = GcCoordinator::new(2);
+        let first = coordinator.acquire(ZTenantId::generate(), 0, || false);
+        let second = coordinator.acquire(ZTenantId::generate(), 0, || false);
+        assert!(first.is_some() && second.is_some());
+        assert_eq!(coordinator.status().running, 2);
+
+        // No free slot, so the third one gives up when cancelled.
+        let third = coordinator.acquire(ZTenantId::generate(), 0, || true);
+        assert!(third.is_none());
+        assert_eq!(coordinator.status().waiting, 0);
+
+        drop(first);
+        assert_eq!(coordinator.status().running, 1);
+        assert!(coordinator
+            .acquire(ZTenantId::generate(), 0, || false)
+            .is_some());
+    }
+
+    #[test]
+    fn pause_blocks_new_permits() {
+        let coordinator = GcCoordinator::new(1);
+        coordinator.pause();
+        assert!(coordinator.status().paused);
+        assert!(coordinator
+            .acquire(ZTenantId::generate(), 0, || true)
+            .is_none());
+
+        coordinator.resume();
+        assert!(coordinator
+            .acquire(ZTenantId::generate(), 0, || true)
+            .is_some());
+    }
+
+    #[test]
+    fn prefers_most_reclaimable() {
+        let coordinator = Arc::new(GcCoordinator::new(1));
+        let blocker = coordinator.acquire(ZTenantId::generate(), 0, || false);
+
+        let small = ZTenantId::generate();
+        let big = ZTenantId::generate();
+        let big_done = Arc::new(AtomicBool::new(false));
+
+        let small_waiter = {
+            let coordinator = Arc::clone(&coordinator);
+            let big_done = Arc::clone(&big_done);
+            std::thread::spawn(move || {
+                let _permit = coordinator.acquire(small, 10, || false);
+                big_done.load(Ordering::SeqCst)
+            })
+        };
+        let big_waiter = {
+            let coordinator = Arc::clone(&coordinator);
+            let big_done = Arc::clone(&big_done);
+            std::thread::spawn(move || {
+                let _permit = coordinator.acquire(big, 1000, || false);
+                big_done.store(true, Ordering::SeqCst);
+            })
+        };
+
+        while coordinator.status().waiting < 2 {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        drop(blocker);
+
+        big_waiter.join().unwrap();
+        assert!(
+            small_waiter.join().unwrap(),
+            "tenant with more reclaimable bytes should run GC first"
+        );
+    }
+}
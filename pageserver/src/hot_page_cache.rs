@@ -0,0 +1,187 @@
+//!
+//! Cache of hot control pages of a timeline.
+//!
+//! A few pages are read at very high rates near the last record LSN: the blocks
+//! of the system catalogs, like pg_class, and the head pages of the SLRUs. For
+//! those, looking the page up through the layer map on every read costs more
+//! than the read itself. This cache keeps the latest image of such pages, and
+//! serves reads of them without a layer lookup.
+//!
+//! A cached image stays valid while the last record LSN advances, until WAL
+//! touches the page: the timeline invalidates the page before storing a new
+//! version of it. So an image reconstructed at some LSN is valid for every LSN
+//! from that one up to the last record LSN, and the next read after the page
+//! got modified reconstructs it and refreshes the cache.
+//!
+//! Which keys are hot is decided by [`is_hot_page_key`]. The number of pages
+//! per timeline is limited by the `hot_page_cache_size` setting; when the cache
+//! is full, the least read page is evicted. The validity tracking and the
+//! eviction are in [`crate::lsn_cache`].
+//!
+use std::ops::Range;
+
+use bytes::Bytes;
+use lazy_static::lazy_static;
+use metrics::{register_int_counter, IntCounter};
+use utils::lsn::Lsn;
+
+use crate::lsn_cache::{Eviction, LsnCache};
+use crate::pgdatadir_mapping::is_hot_page_key;
+use crate::repository::Key;
+
+lazy_static! {
+    static ref HOT_PAGE_CACHE_HITS: IntCounter = register_int_counter!(
+        "pageserver_hot_page_cache_hits_total",
+        "Number of page reads served from the hot page cache"
+    )
+    .expect("failed to define a metric");
+    static ref HOT_PAGE_CACHE_MISSES: IntCounter = register_int_counter!(
+        "pageserver_hot_page_cache_misses_total",
+        "Number of hot page reads that had to reconstruct the page"
+    )
+    .expect("failed to define a metric");
+    static ref HOT_PAGE_CACHE_EVICTIONS: IntCounter = register_int_counter!(
+        "pageserver_hot_page_cache_evictions_total",
+        "Number of hot pages evicted from the cache to make room for others"
+    )
+    .expect("failed to define a metric");
+}
+
+pub struct HotPageCache {
+    pages: LsnCache<Bytes>,
+}
+
+impl HotPageCache {
+    pub fn new(capacity: usize) -> Self {
+        HotPageCache {
+            pages: LsnCache::new(capacity, Eviction::LeastRead, &HOT_PAGE_CACHE_EVICTIONS),
+        }
+    }
+
+    ///
+    /// Get the image of the page at 'lsn', if it's cached. The caller must make
+    /// sure that 'lsn' is not past the last record LSN.
+    ///
+    pub fn lookup(&self, key: &Key, lsn: Lsn) -> Option<Bytes> {
+        if !self.pages.is_enabled() || !is_hot_page_key(key) {
+            return None;
+        }
+
+        let cached = self.pages.lookup(key, lsn);
+        if cached.is_some() {
+            HOT_PAGE_CACHE_HITS.inc();
+        } else {
+            HOT_PAGE_CACHE_MISSES.inc();
+        }
+        cached
+    }
+
+    ///
+    /// Remember the image of the page reconstructed at 'lsn'. Does nothing if
+    /// the page is not hot, or WAL touched it after 'lsn'.
+    ///
+    pub fn memorize(&self, key: Key, lsn: Lsn, img: &Bytes) {
+        if is_hot_page_key(&key) {
+            self.pages.memorize(key, lsn, img.clone());
+        }
+    }
+
+    ///
+    /// Called before storing a new version of the page at 'lsn'.
+    ///
+    pub fn invalidate(&self, key: &Key, lsn: Lsn) {
+        if is_hot_page_key(key) {
+            self.pages.invalidate(key, lsn);
+        }
+    }
+
+    ///
+    /// Called before deleting the key range at 'lsn'.
+    ///
+    pub fn invalidate_range(&self, key_range: &Range<Key>, lsn: Lsn) {
+        self.pages.invalidate_range(key_range, lsn);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Block 3 of pg_class
+    const CATALOG_KEY: Key = Key {
+        field1: 0x00,
+        field2: 1663,
+        field3: 13010,
+        field4: 1259,
+        field5: 0,
+        field6: 3,
+    };
+
+    fn catalog_key(blknum: u32) -> Key {
+        Key {
+            field6: blknum,
+            ..CATALOG_KEY
+        }
+    }
+
+    #[test]
+    fn hot_page_invalidation() {
+        let cache = HotPageCache::new(2);
+        let img = Bytes::from_static(b"page at 0/20");
+
+        cache.memorize(CATALOG_KEY, Lsn(0x20), &img);
+        assert_eq!(cache.lookup(&CATALOG_KEY, Lsn(0x20)), Some(img.clone()));
+        // Still valid as the last record LSN advances
+        assert_eq!(cache.lookup(&CATALOG_KEY, Lsn(0x80)), Some(img.clone()));
+        assert_eq!(cache.lookup(&CATALOG_KEY, Lsn(0x10)), None);
+
+        // WAL touches the page: the reads have to reconstruct it, and images
+        // reconstructed before the modification are not accepted
+        cache.invalidate(&CATALOG_KEY, Lsn(0x90));
+        assert_eq!(cache.lookup(&CATALOG_KEY, Lsn(0x90)), None);
+        cache.memorize(CATALOG_KEY, Lsn(0x80), &img);
+        assert_eq!(cache.lookup(&CATALOG_KEY, Lsn(0x90)), None);
+
+        let new_img = Bytes::from_static(b"page at 0/90");
+        cache.memorize(CATALOG_KEY, Lsn(0x90), &new_img);
+        assert_eq!(cache.lookup(&CATALOG_KEY, Lsn(0xA0)), Some(new_img));
+
+        cache.invalidate_range(&(catalog_key(0)..catalog_key(10)), Lsn(0xB0));
+        assert_eq!(cache.lookup(&CATALOG_KEY, Lsn(0xB0)), None);
+    }
+
+    #[test]
+    fn hot_page_admission() {
+        let cache = HotPageCache::new(2);
+        let img = Bytes::from_static(b"page");
+
+        // Not a hot page: a block of a user relation
+        let user_key = Key {
+            field4: 16384,
+            ..CATALOG_KEY
+        };
+        cache.memorize(user_key, Lsn(0x20), &img);
+        assert_eq!(cache.lookup(&user_key, Lsn(0x20)), None);
+
+        // A page modified before it got cached can only be added at a newer LSN
+        cache.invalidate(&catalog_key(1), Lsn(0x30));
+        cache.memorize(catalog_key(1), Lsn(0x20), &img);
+        assert_eq!(cache.lookup(&catalog_key(1), Lsn(0x30)), None);
+        cache.memorize(catalog_key(1), Lsn(0x30), &img);
+        assert_eq!(cache.lookup(&catalog_key(1), Lsn(0x30)), Some(img.clone()));
+
+        // The least read page gets evicted when the cache is full
+        cache.memorize(catalog_key(2), Lsn(0x30), &img);
+        assert!(cache.lookup(&catalog_key(2), Lsn(0x30)).is_some());
+        assert!(cache.lookup(&catalog_key(2), Lsn(0x30)).is_some());
+        cache.memorize(catalog_key(3), Lsn(0x30), &img);
+        assert_eq!(cache.lookup(&catalog_key(1), Lsn(0x30)), None);
+        assert!(cache.lookup(&catalog_key(2), Lsn(0x30)).is_some());
+        assert!(cache.lookup(&catalog_key(3), Lsn(0x30)).is_some());
+
+        // Disabled cache
+        let cache = HotPageCache::new(0);
+        cache.memorize(CATALOG_KEY, Lsn(0x20), &img);
+        assert_eq!(cache.lookup(&CATALOG_KEY, Lsn(0x20)), None);
+    }
+}
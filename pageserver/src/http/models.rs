@@ -5,6 +5,8 @@ use utils::{
     zid::{NodeId, ZTenantId, ZTimelineId},
 };
 
+use crate::reltag::RelTag;
+
 #[serde_as]
 #[derive(Serialize, Deserialize)]
 pub struct TimelineCreateRequest {
@@ -17,6 +19,41 @@ pub struct TimelineCreateRequest {
     #[serde(default)]
     #[serde_as(as = "Option<DisplayFromStr>")]
     pub ancestor_start_lsn: Option<Lsn>,
+    /// Path to a pg_dump output on the page server host, to restore into the new timeline
+    #[serde(default)]
+    pub import_dump_path: Option<String>,
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize)]
+pub struct TimelineBatchCreateRequest {
+    #[serde_as(as = "DisplayFromStr")]
+    pub ancestor_timeline_id: ZTimelineId,
+    #[serde(default)]
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub ancestor_start_lsn: Option<Lsn>,
+    #[serde_as(as = "Vec<DisplayFromStr>")]
+    pub new_timeline_ids: Vec<ZTimelineId>,
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize)]
+pub struct TimelineReadFloorRequest {
+    /// Clears the read floor if not set
+    #[serde(default)]
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub lsn: Option<Lsn>,
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize)]
+pub struct TimelineMaterializeRequest {
+    #[serde(default)]
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub lsn: Option<Lsn>,
+    /// All relations if not set.
+    #[serde(default)]
+    pub relations: Option<Vec<RelTag>>,
 }
 
 #[serde_as]
@@ -33,6 +70,17 @@ pub struct TenantCreateRequest {
     pub gc_period: Option<String>,
     pub image_creation_threshold: Option<usize>,
     pub pitr_interval: Option<String>,
+    pub logical_size_soft_limit: Option<u64>,
+    pub image_layer_excluded_forks: Option<Vec<String>>,
+    pub generation: Option<u32>,
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize)]
+pub struct TenantCloneRequest {
+    #[serde(default)]
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub new_tenant_id: Option<ZTenantId>,
 }
 
 #[serde_as]
@@ -40,6 +88,18 @@ pub struct TenantCreateRequest {
 #[serde(transparent)]
 pub struct TenantCreateResponse(#[serde_as(as = "DisplayFromStr")] pub ZTenantId);
 
+#[derive(Serialize, Deserialize)]
+pub struct TenantGenerationRequest {
+    pub generation: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TenantAppendOnlyRequest {
+    /// Relations as <spcnode>/<dbnode>/<relnode>
+    pub relations: Vec<String>,
+    pub append_only: bool,
+}
+
 #[derive(Serialize)]
 pub struct StatusResponse {
     pub id: NodeId,
@@ -68,6 +128,8 @@ pub struct TenantConfigRequest {
     pub gc_period: Option<String>,
     pub image_creation_threshold: Option<usize>,
     pub pitr_interval: Option<String>,
+    pub logical_size_soft_limit: Option<u64>,
+    pub image_layer_excluded_forks: Option<Vec<String>>,
 }
 
 impl TenantConfigRequest {
@@ -82,6 +144,8 @@ impl TenantConfigRequest {
             gc_period: None,
             image_creation_threshold: None,
             pitr_interval: None,
+            logical_size_soft_limit: None,
+            image_layer_excluded_forks: None,
         }
     }
 }
@@ -1,3 +1,4 @@
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use anyhow::{Context, Result};
@@ -7,24 +8,35 @@ use remote_storage::GenericRemoteStorage;
 use tracing::*;
 
 use super::models::{
-    StatusResponse, TenantConfigRequest, TenantCreateRequest, TenantCreateResponse,
-    TimelineCreateRequest,
+    StatusResponse, TenantAppendOnlyRequest, TenantCloneRequest, TenantConfigRequest,
+    TenantCreateRequest, TenantCreateResponse, TenantGenerationRequest, TimelineBatchCreateRequest,
+    TimelineCreateRequest, TimelineMaterializeRequest,
 };
-use crate::repository::Repository;
+use crate::compute_notify::{self, TimelineState};
+use crate::layered_repository::append_only::RelFileNode;
+use crate::layered_repository::{set_aside, LayeredRepository};
+use crate::repository::{Repository, Timeline};
 use crate::storage_sync;
 use crate::storage_sync::index::{RemoteIndex, RemoteTimeline};
-use crate::tenant_config::TenantConfOpt;
-use crate::timelines::{LocalTimelineInfo, RemoteTimelineInfo, TimelineInfo};
-use crate::{config::PageServerConf, tenant_mgr, timelines};
+use crate::tenant_config::{ForkSet, InvalidTenantConf, TenantConfOpt};
+use crate::thread_mgr::{self, ThreadKind};
+use crate::timelines::{DurabilityScope, LocalTimelineInfo, RemoteTimelineInfo, TimelineInfo};
+use crate::{
+    config::PageServerConf, gc_coordinator, page_service_drain, tenant_mgr, timeline_doctor,
+    timelines, CheckpointConfig,
+};
 use utils::{
     auth::JwtAuth,
     http::{
-        endpoint::{self, attach_openapi_ui, auth_middleware, check_permission},
+        endpoint::{
+            self, attach_openapi_ui, auth_middleware, basic_auth_middleware, check_permission,
+        },
         error::{ApiError, HttpErrorBody},
         json::{json_request, json_response},
         request::parse_request_param,
         RequestExt, RouterBuilder,
     },
+    lsn::Lsn,
     zid::{ZTenantId, ZTenantTimelineId, ZTimelineId},
 };
 
@@ -34,6 +46,8 @@ struct State {
     remote_index: RemoteIndex,
     allowlist_routes: Vec<Uri>,
     remote_storage: Option<GenericRemoteStorage>,
+    /// `user:password` required to scrape /metrics, from `metrics_auth_file`
+    metrics_credentials: Option<String>,
 }
 
 impl State {
@@ -54,6 +68,15 @@ impl State {
             .map(|storage_config| GenericRemoteStorage::new(conf.workdir.clone(), storage_config))
             .transpose()
             .context("Failed to init generic remote storage")?;
+        let metrics_credentials = conf
+            .metrics_auth_file
+            .as_ref()
+            .map(|path| {
+                std::fs::read_to_string(path)
+                    .map(|credentials| credentials.trim().to_string())
+                    .with_context(|| format!("Failed to read {}", path.display()))
+            })
+            .transpose()?;
 
         Ok(Self {
             conf,
@@ -61,6 +84,7 @@ impl State {
             allowlist_routes,
             remote_index,
             remote_storage,
+            metrics_credentials,
         })
     }
 }
@@ -89,6 +113,10 @@ async fn timeline_create_handler(mut request: Request<Body>) -> Result<Response<
     let request_data: TimelineCreateRequest = json_request(&mut request).await?;
 
     check_permission(&request, Some(tenant_id))?;
+    if request_data.import_dump_path.is_some() {
+        // The dump is read from the host, which a tenant must not be able to do
+        check_permission(&request, None)?;
+    }
 
     let new_timeline_info = tokio::task::spawn_blocking(move || {
         let _enter = info_span!("/timeline_create", tenant = %tenant_id, new_timeline = ?request_data.new_timeline_id, lsn=?request_data.ancestor_start_lsn).entered();
@@ -98,6 +126,7 @@ async fn timeline_create_handler(mut request: Request<Body>) -> Result<Response<
             request_data.new_timeline_id.map(ZTimelineId::from),
             request_data.ancestor_timeline_id.map(ZTimelineId::from),
             request_data.ancestor_start_lsn,
+            request_data.import_dump_path.as_deref().map(Path::new),
         )
     })
     .await
@@ -109,6 +138,28 @@ async fn timeline_create_handler(mut request: Request<Body>) -> Result<Response<
     })
 }
 
+async fn timeline_batch_create_handler(
+    mut request: Request<Body>,
+) -> Result<Response<Body>, ApiError> {
+    let tenant_id: ZTenantId = parse_request_param(&request, "tenant_id")?;
+    let request_data: TimelineBatchCreateRequest = json_request(&mut request).await?;
+    check_permission(&request, Some(tenant_id))?;
+
+    let batch_info = tokio::task::spawn_blocking(move || {
+        let _enter = info_span!("/timeline_batch_create", tenant = %tenant_id, ancestor = %request_data.ancestor_timeline_id, count = request_data.new_timeline_ids.len()).entered();
+        timelines::create_branches(
+            tenant_id,
+            request_data.ancestor_timeline_id,
+            request_data.ancestor_start_lsn,
+            &request_data.new_timeline_ids,
+        )
+    })
+    .await
+    .map_err(ApiError::from_err)??;
+
+    json_response(StatusCode::OK, batch_info)
+}
+
 async fn timeline_list_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
     let tenant_id: ZTenantId = parse_request_param(&request, "tenant_id")?;
     check_permission(&request, Some(tenant_id))?;
@@ -135,12 +186,38 @@ async fn timeline_list_handler(request: Request<Body>) -> Result<Response<Body>,
                     timeline_id,
                 })
                 .map(|remote_entry| RemoteTimelineInfo {
-                    remote_consistent_lsn: remote_entry.metadata.disk_consistent_lsn(),
+                    remote_consistent_lsn: remote_entry.remote_consistent_lsn(),
                     awaits_download: remote_entry.awaits_download,
+                    download_progress: storage_sync::download_progress(&ZTenantTimelineId {
+                        tenant_id,
+                        timeline_id,
+                    }),
+                    archived: remote_entry.archived,
                 }),
         })
     }
 
+    // Archived timelines have no local state, but are still listed
+    response_data.extend(
+        get_state(&request)
+            .remote_index
+            .read()
+            .await
+            .tenant_timelines(tenant_id)
+            .filter(|(_, remote_entry)| remote_entry.archived)
+            .map(|(timeline_id, remote_entry)| TimelineInfo {
+                tenant_id,
+                timeline_id,
+                local: None,
+                remote: Some(RemoteTimelineInfo {
+                    remote_consistent_lsn: remote_entry.remote_consistent_lsn(),
+                    awaits_download: remote_entry.awaits_download,
+                    download_progress: None,
+                    archived: true,
+                }),
+            }),
+    );
+
     json_response(StatusCode::OK, response_data)
 }
 
@@ -199,8 +276,13 @@ async fn timeline_detail_handler(request: Request<Body>) -> Result<Response<Body
                     timeline_id,
                 })
                 .map(|remote_entry| RemoteTimelineInfo {
-                    remote_consistent_lsn: remote_entry.metadata.disk_consistent_lsn(),
+                    remote_consistent_lsn: remote_entry.remote_consistent_lsn(),
                     awaits_download: remote_entry.awaits_download,
+                    download_progress: storage_sync::download_progress(&ZTenantTimelineId {
+                        tenant_id,
+                        timeline_id,
+                    }),
+                    archived: remote_entry.archived,
                 })
         };
         (local_timeline_info, remote_timeline_info)
@@ -248,6 +330,428 @@ async fn wal_receiver_get_handler(request: Request<Body>) -> Result<Response<Bod
     json_response(StatusCode::OK, wal_receiver)
 }
 
+async fn timeline_ingest_pause_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let tenant_id: ZTenantId = parse_request_param(&request, "tenant_id")?;
+    check_permission(&request, Some(tenant_id))?;
+
+    let timeline_id: ZTimelineId = parse_request_param(&request, "timeline_id")?;
+
+    let status = tokio::task::spawn_blocking(move || {
+        let _enter =
+            info_span!("timeline_ingest_pause", tenant = %tenant_id, timeline = %timeline_id)
+                .entered();
+        crate::walreceiver::pause_ingest(tenant_id, timeline_id)
+    })
+    .await
+    .map_err(ApiError::from_err)??;
+
+    json_response(StatusCode::OK, status)
+}
+
+async fn timeline_ingest_resume_handler(
+    request: Request<Body>,
+) -> Result<Response<Body>, ApiError> {
+    let tenant_id: ZTenantId = parse_request_param(&request, "tenant_id")?;
+    check_permission(&request, Some(tenant_id))?;
+
+    let timeline_id: ZTimelineId = parse_request_param(&request, "timeline_id")?;
+
+    let status = tokio::task::spawn_blocking(move || {
+        let _enter =
+            info_span!("timeline_ingest_resume", tenant = %tenant_id, timeline = %timeline_id)
+                .entered();
+        crate::walreceiver::resume_ingest(tenant_id, timeline_id)
+    })
+    .await
+    .map_err(ApiError::from_err)??;
+
+    json_response(StatusCode::OK, status)
+}
+
+async fn timeline_wal_record_stats_handler(
+    request: Request<Body>,
+) -> Result<Response<Body>, ApiError> {
+    let tenant_id: ZTenantId = parse_request_param(&request, "tenant_id")?;
+    check_permission(&request, Some(tenant_id))?;
+
+    let timeline_id: ZTimelineId = parse_request_param(&request, "timeline_id")?;
+
+    let stats = tokio::task::spawn_blocking(move || {
+        let _enter =
+            info_span!("wal_record_stats", tenant = %tenant_id, timeline = %timeline_id).entered();
+
+        let timeline = tenant_mgr::get_local_timeline_with_load(tenant_id, timeline_id)?;
+        Ok::<_, anyhow::Error>(timeline.wal_record_stats.snapshot())
+    })
+    .await
+    .map_err(ApiError::from_err)??;
+
+    json_response(StatusCode::OK, stats)
+}
+
+async fn timeline_gc_space_report_handler(
+    request: Request<Body>,
+) -> Result<Response<Body>, ApiError> {
+    let tenant_id: ZTenantId = parse_request_param(&request, "tenant_id")?;
+    check_permission(&request, Some(tenant_id))?;
+
+    let timeline_id: ZTimelineId = parse_request_param(&request, "timeline_id")?;
+    let limit = match request.uri().query().and_then(|query| {
+        url::form_urlencoded::parse(query.as_bytes())
+            .find(|(param, _)| param == "limit")
+            .map(|(_, value)| value.into_owned())
+    }) {
+        Some(limit) => limit
+            .parse::<usize>()
+            .map_err(|e| ApiError::BadRequest(format!("Invalid limit '{limit}': {e}")))?,
+        None => 10,
+    };
+
+    let report = tokio::task::spawn_blocking(move || {
+        let _enter =
+            info_span!("gc_space_report", tenant = %tenant_id, timeline = %timeline_id).entered();
+
+        let timeline = tenant_mgr::get_local_timeline_with_load(tenant_id, timeline_id)?;
+        Ok::<_, anyhow::Error>(timeline.tline.gc_space_report(limit))
+    })
+    .await
+    .map_err(ApiError::from_err)??;
+
+    match report {
+        Some(report) => json_response(StatusCode::OK, report),
+        None => Err(ApiError::NotFound(
+            "GC has not run on this timeline yet".to_string(),
+        )),
+    }
+}
+
+async fn timeline_gc_estimate_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let tenant_id: ZTenantId = parse_request_param(&request, "tenant_id")?;
+    check_permission(&request, Some(tenant_id))?;
+
+    let timeline_id: ZTimelineId = parse_request_param(&request, "timeline_id")?;
+
+    let estimate = tokio::task::spawn_blocking(move || {
+        let _enter =
+            info_span!("gc_estimate", tenant = %tenant_id, timeline = %timeline_id).entered();
+
+        let timeline = tenant_mgr::get_local_timeline_with_load(tenant_id, timeline_id)?;
+        Ok::<_, anyhow::Error>(timeline.tline.gc_estimate())
+    })
+    .await
+    .map_err(ApiError::from_err)??;
+
+    json_response(StatusCode::OK, estimate)
+}
+
+/// Default number of keys reconstructed by the timeline doctor.
+const DEFAULT_DOCTOR_SAMPLE_PAGES: usize = 100;
+
+async fn timeline_doctor_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let tenant_id: ZTenantId = parse_request_param(&request, "tenant_id")?;
+    check_permission(&request, Some(tenant_id))?;
+
+    let timeline_id: ZTimelineId = parse_request_param(&request, "timeline_id")?;
+    let sample_pages = match request.uri().query().and_then(|query| {
+        url::form_urlencoded::parse(query.as_bytes())
+            .find(|(param, _)| param == "sample_pages")
+            .map(|(_, value)| value.into_owned())
+    }) {
+        Some(sample_pages) => sample_pages.parse::<usize>().map_err(|e| {
+            ApiError::BadRequest(format!("Invalid sample_pages '{sample_pages}': {e}"))
+        })?,
+        None => DEFAULT_DOCTOR_SAMPLE_PAGES,
+    };
+
+    let remote_index = get_state(&request).remote_index.clone();
+    let report = tokio::task::spawn_blocking(move || {
+        let _enter =
+            info_span!("timeline_doctor", tenant = %tenant_id, timeline = %timeline_id).entered();
+
+        let repo = tenant_mgr::get_repository_for_tenant(tenant_id)?;
+        let timeline = tenant_mgr::get_local_timeline_with_load(tenant_id, timeline_id)?;
+        let remote_index = remote_index.blocking_read();
+        let remote_timeline = remote_index.timeline_entry(&ZTenantTimelineId {
+            tenant_id,
+            timeline_id,
+        });
+        Ok::<_, anyhow::Error>(timeline_doctor::examine_timeline(
+            &repo,
+            timeline_id,
+            &timeline,
+            remote_timeline,
+            sample_pages,
+        ))
+    })
+    .await
+    .map_err(ApiError::from_err)??;
+
+    json_response(StatusCode::OK, report)
+}
+
+/// The timeline directory, with the layer files that were set aside when the timeline was loaded.
+fn set_aside_timeline_path(request: &Request<Body>) -> Result<PathBuf, ApiError> {
+    let tenant_id: ZTenantId = parse_request_param(request, "tenant_id")?;
+    check_permission(request, Some(tenant_id))?;
+
+    let timeline_id: ZTimelineId = parse_request_param(request, "timeline_id")?;
+    let timeline_path = get_config(request).timeline_path(&timeline_id, &tenant_id);
+    if !timeline_path.exists() {
+        return Err(ApiError::NotFound(format!(
+            "Timeline {tenant_id}/{timeline_id} is not found locally"
+        )));
+    }
+    Ok(timeline_path)
+}
+
+async fn timeline_set_aside_layers_handler(
+    request: Request<Body>,
+) -> Result<Response<Body>, ApiError> {
+    let timeline_path = set_aside_timeline_path(&request)?;
+
+    let files =
+        tokio::task::spawn_blocking(move || set_aside::list_set_aside_layers(&timeline_path))
+            .await
+            .map_err(ApiError::from_err)??;
+
+    json_response(StatusCode::OK, files)
+}
+
+async fn timeline_set_aside_layers_purge_handler(
+    request: Request<Body>,
+) -> Result<Response<Body>, ApiError> {
+    let timeline_path = set_aside_timeline_path(&request)?;
+    let file_name = request.uri().query().and_then(|query| {
+        url::form_urlencoded::parse(query.as_bytes())
+            .find(|(param, _)| param == "file_name")
+            .map(|(_, value)| value.into_owned())
+    });
+
+    let purged = tokio::task::spawn_blocking({
+        let file_name = file_name.clone();
+        move || set_aside::purge_set_aside_layers(&timeline_path, file_name.as_deref())
+    })
+    .await
+    .map_err(ApiError::from_err)??;
+
+    if let Some(file_name) = file_name {
+        if purged.is_empty() {
+            return Err(ApiError::NotFound(format!(
+                "No set-aside layer file '{file_name}'"
+            )));
+        }
+    }
+    json_response(StatusCode::OK, purged)
+}
+
+/// Default number of catalog pages materialized by the warm-up.
+const DEFAULT_WARM_UP_MATERIALIZE_PAGES: usize = 1000;
+
+async fn timeline_warm_up_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let tenant_id: ZTenantId = parse_request_param(&request, "tenant_id")?;
+    check_permission(&request, Some(tenant_id))?;
+
+    let timeline_id: ZTimelineId = parse_request_param(&request, "timeline_id")?;
+    let mut lsn = None;
+    let mut materialize_pages = DEFAULT_WARM_UP_MATERIALIZE_PAGES;
+    if let Some(query) = request.uri().query() {
+        for (param, value) in url::form_urlencoded::parse(query.as_bytes()) {
+            match param.as_ref() {
+                "lsn" => {
+                    lsn =
+                        Some(value.parse::<Lsn>().map_err(|e| {
+                            ApiError::BadRequest(format!("Invalid lsn '{value}': {e}"))
+                        })?)
+                }
+                "materialize_pages" => {
+                    materialize_pages = value.parse::<usize>().map_err(|e| {
+                        ApiError::BadRequest(format!("Invalid materialize_pages '{value}': {e}"))
+                    })?
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let is_local = tokio::task::spawn_blocking(move || {
+        tenant_mgr::get_local_timeline_with_load(tenant_id, timeline_id).is_ok()
+    })
+    .await
+    .map_err(ApiError::from_err)?;
+
+    if is_local {
+        let warm_up_info = tokio::task::spawn_blocking(move || {
+            let _enter =
+                info_span!("timeline_warm_up", tenant = %tenant_id, timeline = %timeline_id)
+                    .entered();
+            timelines::warm_up_timeline(tenant_id, timeline_id, lsn, materialize_pages)
+        })
+        .await
+        .map_err(ApiError::from_err)??;
+        return json_response(StatusCode::OK, warm_up_info);
+    }
+
+    // The timeline is not here yet: start downloading its layers, the compute
+    // has to wait for them anyway. Poll the timeline and warm it up again once
+    // it becomes local, to have the pages materialized.
+    let sync_id = ZTenantTimelineId {
+        tenant_id,
+        timeline_id,
+    };
+    let mut index_accessor = get_state(&request).remote_index.write().await;
+    match index_accessor.timeline_entry_mut(&sync_id) {
+        Some(remote_timeline) if remote_timeline.archived => Err(ApiError::Conflict(
+            "Timeline is archived, unarchive it first".to_string(),
+        )),
+        Some(remote_timeline) => {
+            if !remote_timeline.awaits_download {
+                remote_timeline.awaits_download = true;
+                storage_sync::schedule_layer_download(tenant_id, timeline_id);
+                compute_notify::notify(tenant_id, timeline_id, TimelineState::AwaitsDownload);
+            }
+            json_response(StatusCode::ACCEPTED, ())
+        }
+        None => Err(ApiError::NotFound(format!(
+            "Timeline {timeline_id} is not found neither locally nor remotely"
+        ))),
+    }
+}
+
+async fn timeline_materialize_handler(
+    mut request: Request<Body>,
+) -> Result<Response<Body>, ApiError> {
+    let tenant_id: ZTenantId = parse_request_param(&request, "tenant_id")?;
+    check_permission(&request, Some(tenant_id))?;
+
+    let timeline_id: ZTimelineId = parse_request_param(&request, "timeline_id")?;
+    let request_data: TimelineMaterializeRequest = json_request(&mut request).await?;
+
+    let materialize_info = tokio::task::spawn_blocking(move || {
+        let _enter =
+            info_span!("timeline_materialize", tenant = %tenant_id, timeline = %timeline_id)
+                .entered();
+        timelines::materialize_timeline(
+            tenant_id,
+            timeline_id,
+            request_data.lsn,
+            request_data.relations,
+        )
+    })
+    .await
+    .map_err(ApiError::from_err)??;
+
+    json_response(StatusCode::OK, materialize_info)
+}
+
+/// Default time to wait for the uploads in the remote durability scope.
+const DEFAULT_WAIT_DURABLE_TIMEOUT: &str = "60 s";
+
+async fn timeline_wait_durable_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let tenant_id: ZTenantId = parse_request_param(&request, "tenant_id")?;
+    check_permission(&request, Some(tenant_id))?;
+
+    let timeline_id: ZTimelineId = parse_request_param(&request, "timeline_id")?;
+    let mut lsn = None;
+    let mut scope = DurabilityScope::Local;
+    let mut timeout = humantime::parse_duration(DEFAULT_WAIT_DURABLE_TIMEOUT)
+        .expect("cannot parse default wait durable timeout");
+    if let Some(query) = request.uri().query() {
+        for (param, value) in url::form_urlencoded::parse(query.as_bytes()) {
+            match param.as_ref() {
+                "lsn" => {
+                    lsn =
+                        Some(value.parse::<Lsn>().map_err(|e| {
+                            ApiError::BadRequest(format!("Invalid lsn '{value}': {e}"))
+                        })?)
+                }
+                "scope" => {
+                    scope = value
+                        .parse::<DurabilityScope>()
+                        .map_err(|e| ApiError::BadRequest(e.to_string()))?
+                }
+                "timeout" => {
+                    timeout = humantime::parse_duration(&value).map_err(|e| {
+                        ApiError::BadRequest(format!("Invalid timeout '{value}': {e}"))
+                    })?
+                }
+                _ => {}
+            }
+        }
+    }
+    let lsn = lsn.ok_or_else(|| ApiError::BadRequest("Missing lsn parameter".to_string()))?;
+
+    let state = get_state(&request);
+    let conf = state.conf;
+    let remote_index = state.remote_index.clone();
+    let durability_info = tokio::task::spawn_blocking(move || {
+        let _enter =
+            info_span!("timeline_wait_durable", tenant = %tenant_id, timeline = %timeline_id)
+                .entered();
+        timelines::wait_for_durability(
+            conf,
+            tenant_id,
+            timeline_id,
+            lsn,
+            scope,
+            &remote_index,
+            timeout,
+        )
+    })
+    .await
+    .map_err(ApiError::from_err)??;
+
+    json_response(StatusCode::OK, durability_info)
+}
+
+async fn timeline_fence_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let tenant_id: ZTenantId = parse_request_param(&request, "tenant_id")?;
+    check_permission(&request, Some(tenant_id))?;
+
+    let timeline_id: ZTimelineId = parse_request_param(&request, "timeline_id")?;
+    let mut set_read_floor = false;
+    if let Some(query) = request.uri().query() {
+        for (param, value) in url::form_urlencoded::parse(query.as_bytes()) {
+            if param == "set_read_floor" {
+                set_read_floor = value.parse::<bool>().map_err(|e| {
+                    ApiError::BadRequest(format!("Invalid set_read_floor '{value}': {e}"))
+                })?;
+            }
+        }
+    }
+
+    let fence_info = tokio::task::spawn_blocking(move || {
+        let _enter =
+            info_span!("timeline_fence", tenant = %tenant_id, timeline = %timeline_id).entered();
+        timelines::fence_timeline(tenant_id, timeline_id, set_read_floor)
+    })
+    .await
+    .map_err(ApiError::from_err)??;
+
+    json_response(StatusCode::OK, fence_info)
+}
+
+async fn timeline_read_floor_handler(
+    mut request: Request<Body>,
+) -> Result<Response<Body>, ApiError> {
+    let tenant_id: ZTenantId = parse_request_param(&request, "tenant_id")?;
+    check_permission(&request, Some(tenant_id))?;
+
+    let timeline_id: ZTimelineId = parse_request_param(&request, "timeline_id")?;
+    let request_data: TimelineReadFloorRequest = json_request(&mut request).await?;
+
+    let fence_info = tokio::task::spawn_blocking(move || {
+        let _enter =
+            info_span!("timeline_read_floor", tenant = %tenant_id, timeline = %timeline_id)
+                .entered();
+        timelines::set_read_floor(tenant_id, timeline_id, request_data.lsn)
+    })
+    .await
+    .map_err(ApiError::from_err)?
+    .map_err(|e| ApiError::BadRequest(format!("{e:#}")))?;
+
+    json_response(StatusCode::OK, fence_info)
+}
+
 async fn timeline_attach_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
     let tenant_id: ZTenantId = parse_request_param(&request, "tenant_id")?;
     check_permission(&request, Some(tenant_id))?;
@@ -258,25 +762,37 @@ async fn timeline_attach_handler(request: Request<Body>) -> Result<Response<Body
         timeline_id, tenant_id,
     );
 
-    tokio::task::spawn_blocking(move || {
+    let state = get_state(&request);
+    let remote_index = &state.remote_index;
+
+    let conf = state.conf;
+    let reattach_index = remote_index.clone();
+    let reattached = tokio::task::spawn_blocking(move || {
         if tenant_mgr::get_local_timeline_with_load(tenant_id, timeline_id).is_ok() {
             // TODO: maybe answer with 309 Not Modified here?
             anyhow::bail!("Timeline is already present locally")
         };
-        Ok(())
+        // A timeline detached recently still has its files, undo the detach
+        tenant_mgr::reattach_timeline(conf, &reattach_index, tenant_id, timeline_id)
     })
     .await
     .map_err(ApiError::from_err)??;
+    if reattached {
+        return json_response(StatusCode::OK, ());
+    }
 
     let sync_id = ZTenantTimelineId {
         tenant_id,
         timeline_id,
     };
-    let state = get_state(&request);
-    let remote_index = &state.remote_index;
 
     let mut index_accessor = remote_index.write().await;
     if let Some(remote_timeline) = index_accessor.timeline_entry_mut(&sync_id) {
+        if remote_timeline.archived {
+            return Err(ApiError::Conflict(
+                "Timeline is archived, unarchive it first".to_string(),
+            ));
+        }
         if remote_timeline.awaits_download {
             return Err(ApiError::Conflict(
                 "Timeline download is already in progress".to_string(),
@@ -285,6 +801,7 @@ async fn timeline_attach_handler(request: Request<Body>) -> Result<Response<Body
 
         remote_timeline.awaits_download = true;
         storage_sync::schedule_layer_download(tenant_id, timeline_id);
+        compute_notify::notify(tenant_id, timeline_id, TimelineState::AwaitsDownload);
         return json_response(StatusCode::ACCEPTED, ());
     } else {
         // no timeline in the index, release the lock to make the potentially lengthy download opetation
@@ -292,6 +809,11 @@ async fn timeline_attach_handler(request: Request<Body>) -> Result<Response<Body
     }
 
     let new_timeline = match try_download_index_part_data(state, sync_id).await {
+        Ok(Some(new_timeline)) if new_timeline.archived => {
+            return Err(ApiError::Conflict(
+                "Timeline is archived, unarchive it first".to_string(),
+            ))
+        }
         Ok(Some(mut new_timeline)) => {
             tokio::fs::create_dir_all(state.conf.timeline_path(&timeline_id, &tenant_id))
                 .await
@@ -321,6 +843,7 @@ async fn timeline_attach_handler(request: Request<Body>) -> Result<Response<Body
         None => index_accessor.add_timeline_entry(sync_id, new_timeline),
     }
     storage_sync::schedule_layer_download(tenant_id, timeline_id);
+    compute_notify::notify(tenant_id, timeline_id, TimelineState::AwaitsDownload);
     json_response(StatusCode::ACCEPTED, ())
 }
 
@@ -354,13 +877,41 @@ async fn timeline_detach_handler(request: Request<Body>) -> Result<Response<Body
     check_permission(&request, Some(tenant_id))?;
 
     let timeline_id: ZTimelineId = parse_request_param(&request, "timeline_id")?;
+    let state = get_state(&request);
+
+    // Detaching removes the local files, so with the remote storage configured,
+    // everything that is on the local disk has to be in the remote storage already
+    if state.remote_storage.is_some() {
+        let disk_consistent_lsn = tokio::task::spawn_blocking(move || {
+            let timeline = tenant_mgr::get_local_timeline_with_load(tenant_id, timeline_id)?;
+            Ok::<_, anyhow::Error>(timeline.tline.get_disk_consistent_lsn())
+        })
+        .await
+        .map_err(ApiError::from_err)??;
+
+        let remote_consistent_lsn = state
+            .remote_index
+            .read()
+            .await
+            .timeline_entry(&ZTenantTimelineId {
+                tenant_id,
+                timeline_id,
+            })
+            .map(|remote_entry| remote_entry.remote_consistent_lsn());
+        if remote_consistent_lsn < Some(disk_consistent_lsn) {
+            return Err(ApiError::Conflict(format!(
+                "Timeline is not fully uploaded yet: remote consistent lsn {remote_consistent_lsn:?}, \
+                disk consistent lsn {disk_consistent_lsn}, retry later"
+            )));
+        }
+    }
 
+    let conf = state.conf;
     tokio::task::spawn_blocking(move || {
         let _enter =
             info_span!("timeline_detach_handler", tenant = %tenant_id, timeline = %timeline_id)
                 .entered();
-        let state = get_state(&request);
-        tenant_mgr::detach_timeline(state.conf, tenant_id, timeline_id)
+        tenant_mgr::detach_timeline(conf, tenant_id, timeline_id)
     })
     .await
     .map_err(ApiError::from_err)??;
@@ -368,23 +919,187 @@ async fn timeline_detach_handler(request: Request<Body>) -> Result<Response<Body
     json_response(StatusCode::OK, ())
 }
 
-async fn tenant_list_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
-    // check for management permission
-    check_permission(&request, None)?;
+async fn timeline_archive_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let tenant_id: ZTenantId = parse_request_param(&request, "tenant_id")?;
+    check_permission(&request, Some(tenant_id))?;
 
-    let response_data = tokio::task::spawn_blocking(move || {
-        let _enter = info_span!("tenant_list").entered();
-        crate::tenant_mgr::list_tenants()
+    let timeline_id: ZTimelineId = parse_request_param(&request, "timeline_id")?;
+    let state = get_state(&request);
+    if state.remote_storage.is_none() {
+        return Err(ApiError::BadRequest(
+            "Cannot archive a timeline without remote storage configured".to_string(),
+        ));
+    }
+
+    let disk_consistent_lsn = tokio::task::spawn_blocking(move || {
+        let _enter =
+            info_span!("timeline_archive_flush", tenant = %tenant_id, timeline = %timeline_id)
+                .entered();
+        let timeline = tenant_mgr::get_local_timeline_with_load(tenant_id, timeline_id)?;
+        // Flushing the in-memory data schedules the upload of the new layers
+        timeline.tline.checkpoint(CheckpointConfig::Flush)?;
+        Ok::<_, anyhow::Error>(timeline.tline.get_disk_consistent_lsn())
     })
     .await
-    .map_err(ApiError::from_err)?;
-
-    json_response(StatusCode::OK, response_data)
-}
+    .map_err(ApiError::from_err)??;
 
-async fn tenant_create_handler(mut request: Request<Body>) -> Result<Response<Body>, ApiError> {
-    // check for management permission
-    check_permission(&request, None)?;
+    let sync_id = ZTenantTimelineId {
+        tenant_id,
+        timeline_id,
+    };
+    let remote_consistent_lsn = state
+        .remote_index
+        .read()
+        .await
+        .timeline_entry(&sync_id)
+        .map(|remote_entry| remote_entry.remote_consistent_lsn());
+    if remote_consistent_lsn < Some(disk_consistent_lsn) {
+        return Err(ApiError::Conflict(format!(
+            "Timeline is not fully uploaded yet: remote consistent lsn {remote_consistent_lsn:?}, \
+            disk consistent lsn {disk_consistent_lsn}, retry later"
+        )));
+    }
+
+    set_remote_timeline_archived(state, sync_id, true)
+        .instrument(info_span!("timeline_archive", tenant = %tenant_id, timeline = %timeline_id))
+        .await?;
+
+    let conf = state.conf;
+    let archive_result = tokio::task::spawn_blocking(move || {
+        let _enter =
+            info_span!("timeline_archive_detach", tenant = %tenant_id, timeline = %timeline_id)
+                .entered();
+        tenant_mgr::archive_timeline(conf, tenant_id, timeline_id)
+    })
+    .await
+    .map_err(ApiError::from_err)?;
+
+    if let Err(e) = archive_result {
+        if let Err(revert_error) = set_remote_timeline_archived(state, sync_id, false).await {
+            error!("Failed to revert the archived flag for timeline {sync_id}: {revert_error:?}");
+        }
+        return Err(e.into());
+    }
+
+    json_response(StatusCode::OK, ())
+}
+
+async fn timeline_unarchive_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let tenant_id: ZTenantId = parse_request_param(&request, "tenant_id")?;
+    check_permission(&request, Some(tenant_id))?;
+
+    let timeline_id: ZTimelineId = parse_request_param(&request, "timeline_id")?;
+    let sync_id = ZTenantTimelineId {
+        tenant_id,
+        timeline_id,
+    };
+    let state = get_state(&request);
+
+    let remote_timeline = state
+        .remote_index
+        .read()
+        .await
+        .timeline_entry(&sync_id)
+        .cloned();
+    let remote_timeline = match remote_timeline {
+        Some(remote_timeline) => remote_timeline,
+        None => match try_download_index_part_data(state, sync_id).await {
+            Ok(Some(new_timeline)) => {
+                state
+                    .remote_index
+                    .write()
+                    .await
+                    .add_timeline_entry(sync_id, new_timeline.clone());
+                new_timeline
+            }
+            Ok(None) => return Err(ApiError::NotFound("Unknown remote timeline".to_string())),
+            Err(e) => {
+                error!("Failed to retrieve remote timeline data: {:?}", e);
+                return Err(ApiError::NotFound(
+                    "Failed to retrieve remote timeline".to_string(),
+                ));
+            }
+        },
+    };
+    if !remote_timeline.archived {
+        return Err(ApiError::Conflict("Timeline is not archived".to_string()));
+    }
+
+    set_remote_timeline_archived(state, sync_id, false)
+        .instrument(info_span!("timeline_unarchive", tenant = %tenant_id, timeline = %timeline_id))
+        .await?;
+
+    let timeline_path = state.conf.timeline_path(&timeline_id, &tenant_id);
+    tokio::fs::create_dir_all(&timeline_path)
+        .await
+        .context("Failed to create timeline directory")?;
+    let marker_path = timeline_path.join(storage_sync::ARCHIVED_TIMELINE_MARKER);
+    if marker_path.exists() {
+        tokio::fs::remove_file(&marker_path)
+            .await
+            .context("Failed to remove archived timeline marker")?;
+    }
+
+    if let Some(remote_timeline) = state
+        .remote_index
+        .write()
+        .await
+        .timeline_entry_mut(&sync_id)
+    {
+        remote_timeline.awaits_download = true;
+    }
+    storage_sync::schedule_layer_download(tenant_id, timeline_id);
+    json_response(StatusCode::ACCEPTED, ())
+}
+
+async fn set_remote_timeline_archived(
+    state: &State,
+    sync_id: ZTenantTimelineId,
+    archived: bool,
+) -> anyhow::Result<()> {
+    match state.remote_storage.as_ref() {
+        Some(GenericRemoteStorage::Local(local_storage)) => {
+            storage_sync::set_timeline_archived(
+                state.conf,
+                local_storage,
+                &state.remote_index,
+                sync_id,
+                archived,
+            )
+            .await
+        }
+        Some(GenericRemoteStorage::S3(s3_storage)) => {
+            storage_sync::set_timeline_archived(
+                state.conf,
+                s3_storage,
+                &state.remote_index,
+                sync_id,
+                archived,
+            )
+            .await
+        }
+        None => anyhow::bail!("No remote storage configured"),
+    }
+    .with_context(|| format!("Failed to update archived flag for timeline {sync_id}"))
+}
+
+async fn tenant_list_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    // check for management permission
+    check_permission(&request, None)?;
+
+    let response_data = tokio::task::spawn_blocking(move || {
+        let _enter = info_span!("tenant_list").entered();
+        crate::tenant_mgr::list_tenants()
+    })
+    .await
+    .map_err(ApiError::from_err)?;
+
+    json_response(StatusCode::OK, response_data)
+}
+
+async fn tenant_create_handler(mut request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    // check for management permission
+    check_permission(&request, None)?;
 
     let request_data: TenantCreateRequest = json_request(&mut request).await?;
     let remote_index = get_state(&request).remote_index.clone();
@@ -405,12 +1120,24 @@ async fn tenant_create_handler(mut request: Request<Body>) -> Result<Response<Bo
     tenant_conf.checkpoint_distance = request_data.checkpoint_distance;
     tenant_conf.compaction_target_size = request_data.compaction_target_size;
     tenant_conf.compaction_threshold = request_data.compaction_threshold;
+    tenant_conf.logical_size_soft_limit = request_data.logical_size_soft_limit;
+    if let Some(image_layer_excluded_forks) = request_data.image_layer_excluded_forks {
+        tenant_conf.image_layer_excluded_forks = Some(
+            ForkSet::from_names(&image_layer_excluded_forks)
+                .map_err(|e| ApiError::BadRequest(e.to_string()))?,
+        );
+    }
 
     if let Some(compaction_period) = request_data.compaction_period {
         tenant_conf.compaction_period =
             Some(humantime::parse_duration(&compaction_period).map_err(ApiError::from_err)?);
     }
 
+    tenant_conf
+        .merge(get_config(&request).default_tenant_conf)
+        .validate()
+        .map_err(|e| ApiError::BadRequest(e.to_string()))?;
+
     let target_tenant_id = request_data
         .new_tenant_id
         .map(ZTenantId::from)
@@ -420,7 +1147,13 @@ async fn tenant_create_handler(mut request: Request<Body>) -> Result<Response<Bo
         let _enter = info_span!("tenant_create", tenant = ?target_tenant_id).entered();
         let conf = get_config(&request);
 
-        tenant_mgr::create_tenant_repository(conf, tenant_conf, target_tenant_id, remote_index)
+        tenant_mgr::create_tenant_repository(
+            conf,
+            tenant_conf,
+            target_tenant_id,
+            request_data.generation,
+            remote_index,
+        )
     })
     .await
     .map_err(ApiError::from_err)??;
@@ -431,6 +1164,127 @@ async fn tenant_create_handler(mut request: Request<Body>) -> Result<Response<Bo
     })
 }
 
+async fn tenant_clone_handler(mut request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let source_tenant_id: ZTenantId = parse_request_param(&request, "tenant_id")?;
+    // check for management permission
+    check_permission(&request, None)?;
+
+    let request_data: TenantCloneRequest = json_request(&mut request).await?;
+    let state = get_state(&request);
+    if state.remote_storage.is_none() {
+        return Err(ApiError::BadRequest(
+            "Cannot clone a tenant without remote storage configured".to_string(),
+        ));
+    }
+    let new_tenant_id = request_data
+        .new_tenant_id
+        .unwrap_or_else(ZTenantId::generate);
+
+    // The clone gets the remote state of the source tenant, so the source has to be fully uploaded
+    let disk_consistent_lsns = tokio::task::spawn_blocking(move || {
+        let _enter = info_span!("tenant_clone_flush", tenant = %source_tenant_id).entered();
+        let repo = tenant_mgr::get_repository_for_tenant(source_tenant_id)?;
+        let mut disk_consistent_lsns = Vec::new();
+        for (timeline_id, _) in repo.list_timelines() {
+            let timeline = repo.get_timeline_load(timeline_id)?;
+            // Flushing the in-memory data schedules the upload of the new layers
+            timeline.checkpoint(CheckpointConfig::Flush)?;
+            disk_consistent_lsns.push((timeline_id, timeline.get_disk_consistent_lsn()));
+        }
+        Ok::<_, anyhow::Error>(disk_consistent_lsns)
+    })
+    .await
+    .map_err(ApiError::from_err)??;
+
+    {
+        let index_accessor = state.remote_index.read().await;
+        for (timeline_id, disk_consistent_lsn) in disk_consistent_lsns {
+            let sync_id = ZTenantTimelineId {
+                tenant_id: source_tenant_id,
+                timeline_id,
+            };
+            let remote_consistent_lsn = index_accessor
+                .timeline_entry(&sync_id)
+                .map(|remote_entry| remote_entry.remote_consistent_lsn());
+            if remote_consistent_lsn < Some(disk_consistent_lsn) {
+                return Err(ApiError::Conflict(format!(
+                    "Timeline {timeline_id} is not fully uploaded yet: remote consistent lsn {remote_consistent_lsn:?}, \
+                    disk consistent lsn {disk_consistent_lsn}, retry later"
+                )));
+            }
+        }
+    }
+
+    let conf = state.conf;
+    let remote_index = state.remote_index.clone();
+    let created = tokio::task::spawn_blocking(move || {
+        let _enter = info_span!("tenant_clone_create", tenant = %new_tenant_id).entered();
+        let tenant_conf = LayeredRepository::load_tenant_config(conf, source_tenant_id)?;
+        tenant_mgr::create_tenant_repository(conf, tenant_conf, new_tenant_id, None, remote_index)
+    })
+    .await
+    .map_err(ApiError::from_err)??;
+    if created.is_none() {
+        return Err(ApiError::Conflict(format!(
+            "Tenant {new_tenant_id} already exists"
+        )));
+    }
+
+    let clone_span =
+        info_span!("tenant_clone", source_tenant = %source_tenant_id, tenant = %new_tenant_id);
+    let cloned_timelines = match state.remote_storage.as_ref() {
+        Some(GenericRemoteStorage::Local(local_storage)) => {
+            storage_sync::clone_remote_tenant(
+                conf,
+                local_storage,
+                &state.remote_index,
+                source_tenant_id,
+                new_tenant_id,
+            )
+            .instrument(clone_span)
+            .await
+        }
+        Some(GenericRemoteStorage::S3(s3_storage)) => {
+            storage_sync::clone_remote_tenant(
+                conf,
+                s3_storage,
+                &state.remote_index,
+                source_tenant_id,
+                new_tenant_id,
+            )
+            .instrument(clone_span)
+            .await
+        }
+        None => unreachable!("remote storage presence is checked above"),
+    }
+    .with_context(|| format!("Failed to clone tenant {source_tenant_id} into {new_tenant_id}"))?;
+
+    for (timeline_id, archived) in cloned_timelines {
+        if archived {
+            continue;
+        }
+        tokio::fs::create_dir_all(conf.timeline_path(&timeline_id, &new_tenant_id))
+            .await
+            .context("Failed to create new timeline directory")?;
+        if let Some(remote_timeline) =
+            state
+                .remote_index
+                .write()
+                .await
+                .timeline_entry_mut(&ZTenantTimelineId {
+                    tenant_id: new_tenant_id,
+                    timeline_id,
+                })
+        {
+            remote_timeline.awaits_download = true;
+        }
+        storage_sync::schedule_layer_download(new_tenant_id, timeline_id);
+        compute_notify::notify(new_tenant_id, timeline_id, TimelineState::AwaitsDownload);
+    }
+
+    json_response(StatusCode::ACCEPTED, TenantCreateResponse(new_tenant_id))
+}
+
 async fn tenant_config_handler(mut request: Request<Body>) -> Result<Response<Body>, ApiError> {
     let request_data: TenantConfigRequest = json_request(&mut request).await?;
     let tenant_id = request_data.tenant_id;
@@ -453,6 +1307,13 @@ async fn tenant_config_handler(mut request: Request<Body>) -> Result<Response<Bo
     tenant_conf.checkpoint_distance = request_data.checkpoint_distance;
     tenant_conf.compaction_target_size = request_data.compaction_target_size;
     tenant_conf.compaction_threshold = request_data.compaction_threshold;
+    tenant_conf.logical_size_soft_limit = request_data.logical_size_soft_limit;
+    if let Some(image_layer_excluded_forks) = request_data.image_layer_excluded_forks {
+        tenant_conf.image_layer_excluded_forks = Some(
+            ForkSet::from_names(&image_layer_excluded_forks)
+                .map_err(|e| ApiError::BadRequest(e.to_string()))?,
+        );
+    }
 
     if let Some(compaction_period) = request_data.compaction_period {
         tenant_conf.compaction_period =
@@ -465,11 +1326,271 @@ async fn tenant_config_handler(mut request: Request<Body>) -> Result<Response<Bo
         tenant_mgr::update_tenant_config(tenant_conf, tenant_id)
     })
     .await
+    .map_err(ApiError::from_err)?
+    .map_err(|e| match e.downcast_ref::<InvalidTenantConf>() {
+        Some(invalid) => ApiError::BadRequest(invalid.to_string()),
+        None => ApiError::from_err(e),
+    })?;
+
+    json_response(StatusCode::OK, ())
+}
+
+async fn tenant_config_get_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let tenant_id: ZTenantId = parse_request_param(&request, "tenant_id")?;
+    check_permission(&request, Some(tenant_id))?;
+
+    let effective_conf = tokio::task::spawn_blocking(move || {
+        let _enter = info_span!("tenant_config_get", tenant = ?tenant_id).entered();
+        tenant_mgr::get_repository_for_tenant(tenant_id)?.get_effective_tenant_config()
+    })
+    .await
+    .map_err(ApiError::from_err)??;
+
+    json_response(StatusCode::OK, effective_conf)
+}
+
+async fn tenant_clear_read_only_handler(
+    request: Request<Body>,
+) -> Result<Response<Body>, ApiError> {
+    let tenant_id: ZTenantId = parse_request_param(&request, "tenant_id")?;
+    // check for management permission
+    check_permission(&request, None)?;
+
+    tokio::task::spawn_blocking(move || {
+        let _enter = info_span!("tenant_clear_read_only", tenant = %tenant_id).entered();
+
+        tenant_mgr::clear_tenant_read_only(tenant_id)
+    })
+    .await
     .map_err(ApiError::from_err)??;
 
     json_response(StatusCode::OK, ())
 }
 
+async fn gc_status_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    check_permission(&request, None)?;
+
+    json_response(StatusCode::OK, gc_coordinator::get().status())
+}
+
+async fn gc_pause_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    check_permission(&request, None)?;
+
+    let coordinator = gc_coordinator::get();
+    coordinator.pause();
+    json_response(StatusCode::OK, coordinator.status())
+}
+
+async fn gc_resume_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    check_permission(&request, None)?;
+
+    let coordinator = gc_coordinator::get();
+    coordinator.resume();
+    json_response(StatusCode::OK, coordinator.status())
+}
+
+async fn page_service_drain_status_handler(
+    request: Request<Body>,
+) -> Result<Response<Body>, ApiError> {
+    check_permission(&request, None)?;
+
+    json_response(StatusCode::OK, page_service_drain::get().status())
+}
+
+async fn page_service_drain_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    check_permission(&request, None)?;
+
+    let drain = page_service_drain::get();
+    drain.start();
+    json_response(StatusCode::OK, drain.status())
+}
+
+async fn page_service_resume_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    check_permission(&request, None)?;
+
+    let drain = page_service_drain::get();
+    drain.resume();
+    json_response(StatusCode::OK, drain.status())
+}
+
+async fn tenant_generation_handler(mut request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let tenant_id: ZTenantId = parse_request_param(&request, "tenant_id")?;
+    // check for management permission
+    check_permission(&request, None)?;
+
+    let request_data: TenantGenerationRequest = json_request(&mut request).await?;
+    let conf = get_config(&request);
+
+    tokio::task::spawn_blocking(move || {
+        let _enter = info_span!("tenant_generation", tenant = %tenant_id, generation = request_data.generation).entered();
+
+        tenant_mgr::set_tenant_generation(conf, tenant_id, request_data.generation)
+    })
+    .await
+    .map_err(ApiError::from_err)??;
+
+    json_response(StatusCode::OK, ())
+}
+
+async fn tenant_append_only_get_handler(
+    request: Request<Body>,
+) -> Result<Response<Body>, ApiError> {
+    let tenant_id: ZTenantId = parse_request_param(&request, "tenant_id")?;
+    check_permission(&request, Some(tenant_id))?;
+
+    let repo = tenant_mgr::get_repository_for_tenant(tenant_id)?;
+    let relations = repo
+        .get_append_only_relations()
+        .iter()
+        .map(RelFileNode::to_string)
+        .collect::<Vec<_>>();
+    json_response(StatusCode::OK, relations)
+}
+
+async fn tenant_append_only_handler(
+    mut request: Request<Body>,
+) -> Result<Response<Body>, ApiError> {
+    let tenant_id: ZTenantId = parse_request_param(&request, "tenant_id")?;
+    check_permission(&request, Some(tenant_id))?;
+
+    let request_data: TenantAppendOnlyRequest = json_request(&mut request).await?;
+    let relations = request_data
+        .relations
+        .iter()
+        .map(|rel| rel.parse::<RelFileNode>())
+        .collect::<Result<Vec<_>>>()
+        .map_err(|e| ApiError::BadRequest(format!("{e:#}")))?;
+
+    let relations = tokio::task::spawn_blocking(move || {
+        let _enter = info_span!("tenant_append_only", tenant = %tenant_id).entered();
+        let repo = tenant_mgr::get_repository_for_tenant(tenant_id)?;
+        for rel in relations {
+            repo.set_relation_append_only(rel, request_data.append_only)?;
+        }
+        anyhow::Ok(repo.get_append_only_relations())
+    })
+    .await
+    .map_err(ApiError::from_err)??;
+
+    let relations = relations
+        .iter()
+        .map(RelFileNode::to_string)
+        .collect::<Vec<_>>();
+    json_response(StatusCode::OK, relations)
+}
+
+async fn tenant_quarantine_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let tenant_id: ZTenantId = parse_request_param(&request, "tenant_id")?;
+    check_permission(&request, Some(tenant_id))?;
+
+    json_response(StatusCode::OK, storage_sync::quarantined_layers(tenant_id))
+}
+
+async fn tenant_gc_progress_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let tenant_id: ZTenantId = parse_request_param(&request, "tenant_id")?;
+    check_permission(&request, Some(tenant_id))?;
+
+    let repo = tenant_mgr::get_repository_for_tenant(tenant_id)?;
+    json_response(StatusCode::OK, repo.get_gc_progress())
+}
+
+async fn tenant_gc_cancel_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let tenant_id: ZTenantId = parse_request_param(&request, "tenant_id")?;
+    check_permission(&request, Some(tenant_id))?;
+
+    let repo = tenant_mgr::get_repository_for_tenant(tenant_id)?;
+    if repo.cancel_gc() {
+        info!("requested cancellation of the GC iteration of tenant {tenant_id}");
+    }
+    json_response(StatusCode::OK, repo.get_gc_progress())
+}
+
+/// Parses the `kind` and `timeline_id` query parameters of the thread endpoints.
+fn parse_thread_filter(
+    request: &Request<Body>,
+) -> Result<(Option<ThreadKind>, Option<ZTimelineId>), ApiError> {
+    let mut kind = None;
+    let mut timeline_id = None;
+    if let Some(query) = request.uri().query() {
+        for (param, value) in url::form_urlencoded::parse(query.as_bytes()) {
+            match param.as_ref() {
+                "kind" => {
+                    kind = Some(
+                        serde_json::from_value::<ThreadKind>(serde_json::Value::String(
+                            value.to_string(),
+                        ))
+                        .map_err(|e| {
+                            ApiError::BadRequest(format!("Invalid thread kind '{value}': {e}"))
+                        })?,
+                    )
+                }
+                "timeline_id" => {
+                    timeline_id = Some(value.parse::<ZTimelineId>().map_err(|e| {
+                        ApiError::BadRequest(format!("Invalid timeline_id '{value}': {e}"))
+                    })?)
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok((kind, timeline_id))
+}
+
+async fn thread_list_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    // check for management permission
+    check_permission(&request, None)?;
+
+    let (kind, timeline_id) = parse_thread_filter(&request)?;
+    json_response(
+        StatusCode::OK,
+        thread_mgr::list_threads(kind, None, timeline_id),
+    )
+}
+
+async fn tenant_thread_list_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let tenant_id: ZTenantId = parse_request_param(&request, "tenant_id")?;
+    check_permission(&request, Some(tenant_id))?;
+
+    let (kind, timeline_id) = parse_thread_filter(&request)?;
+    json_response(
+        StatusCode::OK,
+        thread_mgr::list_threads(kind, Some(tenant_id), timeline_id),
+    )
+}
+
+async fn tenant_thread_shutdown_handler(
+    request: Request<Body>,
+) -> Result<Response<Body>, ApiError> {
+    let tenant_id: ZTenantId = parse_request_param(&request, "tenant_id")?;
+    check_permission(&request, Some(tenant_id))?;
+
+    let (kind, timeline_id) = parse_thread_filter(&request)?;
+    // Only the threads that get started again on demand can be stopped:
+    // the WAL receiver by the next callmemaybe or broker update, the page
+    // service connection by the compute reconnecting.
+    let kind = match kind {
+        Some(kind @ (ThreadKind::WalReceiver | ThreadKind::PageRequestHandler)) => kind,
+        Some(kind) => {
+            return Err(ApiError::BadRequest(format!(
+                "Threads of kind {kind:?} cannot be shut down"
+            )))
+        }
+        None => return Err(ApiError::BadRequest("Missing kind parameter".to_string())),
+    };
+
+    let stopped_threads = tokio::task::spawn_blocking(move || {
+        let _enter =
+            info_span!("tenant_thread_shutdown", tenant = %tenant_id, kind = ?kind).entered();
+        let threads = thread_mgr::list_threads(Some(kind), Some(tenant_id), timeline_id);
+        thread_mgr::shutdown_threads(Some(kind), Some(tenant_id), timeline_id);
+        threads
+    })
+    .await
+    .map_err(ApiError::from_err)?;
+
+    json_response(StatusCode::OK, stopped_threads)
+}
+
 async fn handler_404(_: Request<Body>) -> Result<Response<Body>, ApiError> {
     json_response(
         StatusCode::NOT_FOUND,
@@ -487,7 +1608,9 @@ pub fn make_router(
     if auth.is_some() {
         router = router.middleware(auth_middleware(|request| {
             let state = get_state(request);
-            if state.allowlist_routes.contains(request.uri()) {
+            if state.allowlist_routes.contains(request.uri())
+                || (state.metrics_credentials.is_some() && request.uri().path() == "/metrics")
+            {
                 None
             } else {
                 state.auth.as_deref()
@@ -495,16 +1618,60 @@ pub fn make_router(
         }))
     }
 
+    router = router.middleware(basic_auth_middleware("/metrics", |request| {
+        get_state(request).metrics_credentials.as_deref()
+    }));
+
     Ok(router
         .data(Arc::new(
             State::new(conf, auth, remote_index).context("Failed to initialize router state")?,
         ))
         .get("/v1/status", status_handler)
+        .get("/v1/gc", gc_status_handler)
+        .post("/v1/gc/pause", gc_pause_handler)
+        .post("/v1/gc/resume", gc_resume_handler)
+        .get("/v1/page_service/drain", page_service_drain_status_handler)
+        .post("/v1/page_service/drain", page_service_drain_handler)
+        .post("/v1/page_service/resume", page_service_resume_handler)
+        .get("/v1/threads", thread_list_handler)
         .get("/v1/tenant", tenant_list_handler)
         .post("/v1/tenant", tenant_create_handler)
         .put("/v1/tenant/config", tenant_config_handler)
+        .put(
+            "/v1/tenant/:tenant_id/generation",
+            tenant_generation_handler,
+        )
+        .post("/v1/tenant/:tenant_id/clone", tenant_clone_handler)
+        .get("/v1/tenant/:tenant_id/config", tenant_config_get_handler)
+        .post(
+            "/v1/tenant/:tenant_id/clear_read_only",
+            tenant_clear_read_only_handler,
+        )
+        .get(
+            "/v1/tenant/:tenant_id/append_only_relations",
+            tenant_append_only_get_handler,
+        )
+        .put(
+            "/v1/tenant/:tenant_id/append_only_relations",
+            tenant_append_only_handler,
+        )
+        .get("/v1/tenant/:tenant_id/gc", tenant_gc_progress_handler)
+        .post("/v1/tenant/:tenant_id/gc/cancel", tenant_gc_cancel_handler)
+        .get("/v1/tenant/:tenant_id/threads", tenant_thread_list_handler)
+        .get(
+            "/v1/tenant/:tenant_id/quarantine",
+            tenant_quarantine_handler,
+        )
+        .post(
+            "/v1/tenant/:tenant_id/threads/shutdown",
+            tenant_thread_shutdown_handler,
+        )
         .get("/v1/tenant/:tenant_id/timeline", timeline_list_handler)
         .post("/v1/tenant/:tenant_id/timeline", timeline_create_handler)
+        .post(
+            "/v1/tenant/:tenant_id/timeline/batch",
+            timeline_batch_create_handler,
+        )
         .get(
             "/v1/tenant/:tenant_id/timeline/:timeline_id",
             timeline_detail_handler,
@@ -513,13 +1680,73 @@ pub fn make_router(
             "/v1/tenant/:tenant_id/timeline/:timeline_id/wal_receiver",
             wal_receiver_get_handler,
         )
+        .post(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/ingest/pause",
+            timeline_ingest_pause_handler,
+        )
+        .post(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/ingest/resume",
+            timeline_ingest_resume_handler,
+        )
+        .get(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/wal_record_stats",
+            timeline_wal_record_stats_handler,
+        )
+        .get(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/gc_space_report",
+            timeline_gc_space_report_handler,
+        )
+        .get(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/gc_estimate",
+            timeline_gc_estimate_handler,
+        )
+        .get(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/doctor",
+            timeline_doctor_handler,
+        )
+        .get(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/set_aside_layers",
+            timeline_set_aside_layers_handler,
+        )
+        .post(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/set_aside_layers/purge",
+            timeline_set_aside_layers_purge_handler,
+        )
         .post(
             "/v1/tenant/:tenant_id/timeline/:timeline_id/attach",
             timeline_attach_handler,
         )
+        .post(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/warm_up",
+            timeline_warm_up_handler,
+        )
+        .post(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/wait_durable",
+            timeline_wait_durable_handler,
+        )
+        .post(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/fence",
+            timeline_fence_handler,
+        )
+        .put(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/read_floor",
+            timeline_read_floor_handler,
+        )
+        .post(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/materialize",
+            timeline_materialize_handler,
+        )
         .post(
             "/v1/tenant/:tenant_id/timeline/:timeline_id/detach",
             timeline_detach_handler,
         )
+        .post(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/archive",
+            timeline_archive_handler,
+        )
+        .post(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/unarchive",
+            timeline_unarchive_handler,
+        )
         .any(handler_404))
 }
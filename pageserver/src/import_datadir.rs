@@ -180,9 +180,12 @@ fn import_relfile<R: Repository>(
     ensure!(len % pg_constants::BLCKSZ as u64 == 0);
     let nblocks = len / pg_constants::BLCKSZ as u64;
 
-    if segno != 0 {
-        todo!();
-    }
+    // TODO: relations larger than one segment, 1 GB, can only come from a restored dump
+    ensure!(
+        segno == 0,
+        "importing relations of more than one segment is not supported: {}",
+        path.display()
+    );
 
     let rel = RelTag {
         spcnode: spcoid,
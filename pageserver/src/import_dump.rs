@@ -0,0 +1,175 @@
+//!
+//! Restore a logical dump into a temporary PostgreSQL instance, so that its
+//! data directory can be imported into a new timeline.
+//!
+//! The timeline bootstrap runs initdb in a temporary directory and imports the
+//! resulting data directory. To create a timeline from a dump made with pg_dump,
+//! the bootstrap starts a postgres on that directory first, restores the dump
+//! into it with psql or pg_restore, and shuts it down cleanly, so that the data
+//! directory ends at a shutdown checkpoint, like right after initdb.
+//!
+//! The temporary postgres listens on a unix socket only, in a directory of its
+//! own under the system temporary directory: the socket path has a length limit
+//! that the tenant directories can easily exceed.
+//!
+//! The data directory import doesn't support relations larger than one segment
+//! yet, so the tables and indexes of the dump have to stay under 1 GB each.
+//!
+use anyhow::{bail, Context, Result};
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use tracing::*;
+
+use utils::zid::ZTimelineId;
+
+use crate::config::PageServerConf;
+
+/// First bytes of a pg_dump archive in the custom format
+const CUSTOM_ARCHIVE_MAGIC: &[u8] = b"PGDMP";
+/// Offset and value of the magic of a tar archive, used by the tar format of pg_dump
+const TAR_MAGIC_OFFSET: usize = 257;
+const TAR_MAGIC: &[u8] = b"ustar";
+
+///
+/// Restore the dump at 'dump_path' into the freshly initialized data directory
+/// 'pgdata'. A plain SQL script is run with psql, the archive formats of pg_dump
+/// (custom, directory and tar) are restored with pg_restore. Any error in the
+/// dump fails the restore.
+///
+pub fn restore_dump(
+    conf: &'static PageServerConf,
+    pgdata: &Path,
+    dump_path: &Path,
+    timeline_id: ZTimelineId,
+) -> Result<()> {
+    let is_archive = is_archive(dump_path)
+        .with_context(|| format!("Failed to read dump '{}'", dump_path.display()))?;
+
+    let socket_dir = std::env::temp_dir().join(format!("neon-import-{timeline_id}"));
+    if socket_dir.exists() {
+        fs::remove_dir_all(&socket_dir)?;
+    }
+    fs::create_dir_all(&socket_dir)
+        .with_context(|| format!("Failed to create '{}'", socket_dir.display()))?;
+
+    let result = TempPostgres::start(conf, pgdata, &socket_dir).and_then(|postgres| {
+        let restored = postgres.restore(dump_path, is_archive);
+        // Stop it even if the restore failed, and report the restore error first
+        let stopped = postgres.stop();
+        restored.and(stopped)
+    });
+
+    if let Err(e) = fs::remove_dir_all(&socket_dir) {
+        warn!("failed to remove '{}': {e}", socket_dir.display());
+    }
+    result
+}
+
+fn is_archive(dump_path: &Path) -> Result<bool> {
+    if dump_path.is_dir() {
+        return Ok(true);
+    }
+    let mut header = Vec::with_capacity(TAR_MAGIC_OFFSET + TAR_MAGIC.len());
+    File::open(dump_path)?
+        .take((TAR_MAGIC_OFFSET + TAR_MAGIC.len()) as u64)
+        .read_to_end(&mut header)?;
+    Ok(header.starts_with(CUSTOM_ARCHIVE_MAGIC)
+        || header.get(TAR_MAGIC_OFFSET..) == Some(TAR_MAGIC))
+}
+
+struct TempPostgres<'a> {
+    conf: &'static PageServerConf,
+    pgdata: &'a Path,
+    socket_dir: &'a Path,
+}
+
+impl<'a> TempPostgres<'a> {
+    fn start(
+        conf: &'static PageServerConf,
+        pgdata: &'a Path,
+        socket_dir: &'a Path,
+    ) -> Result<TempPostgres<'a>> {
+        info!("starting temporary postgres on {}", pgdata.display());
+        // Nobody connects to it but us, and the data directory is deleted after
+        // the import: skip the fsyncs and the background work.
+        let options = format!(
+            "-c listen_addresses='' -k {} -c fsync=off -c autovacuum=off",
+            socket_dir.display()
+        );
+        let postgres = TempPostgres {
+            conf,
+            pgdata,
+            socket_dir,
+        };
+        postgres.run(
+            "pg_ctl",
+            Command::new(conf.pg_bin_dir().join("pg_ctl"))
+                .args(&["-D", &pgdata.to_string_lossy()])
+                .args(&["-l", &postgres.log_path().to_string_lossy()])
+                .args(&["-o", &options])
+                .args(&["-w", "start"]),
+        )?;
+        Ok(postgres)
+    }
+
+    fn restore(&self, dump_path: &Path, is_archive: bool) -> Result<()> {
+        info!("restoring dump '{}'", dump_path.display());
+        let socket_dir = self.socket_dir.to_string_lossy();
+        if is_archive {
+            self.run(
+                "pg_restore",
+                Command::new(self.conf.pg_bin_dir().join("pg_restore"))
+                    .args(&["-h", &socket_dir, "-U", &self.conf.superuser])
+                    .args(&["-d", "postgres"])
+                    .args(&["--no-owner", "--no-privileges", "--exit-on-error"])
+                    .arg(dump_path),
+            )
+        } else {
+            self.run(
+                "psql",
+                Command::new(self.conf.pg_bin_dir().join("psql"))
+                    .args(&["-h", &socket_dir, "-U", &self.conf.superuser])
+                    .args(&["-d", "postgres"])
+                    .args(&["-X", "-q", "-v", "ON_ERROR_STOP=1"])
+                    .arg("-f")
+                    .arg(dump_path),
+            )
+        }
+    }
+
+    /// Fast shutdown writes a shutdown checkpoint, which the import requires.
+    fn stop(&self) -> Result<()> {
+        self.run(
+            "pg_ctl",
+            Command::new(self.conf.pg_bin_dir().join("pg_ctl"))
+                .args(&["-D", &self.pgdata.to_string_lossy()])
+                .args(&["-m", "fast", "-w", "stop"]),
+        )
+    }
+
+    /// The server log can't be in the data directory: it would get imported.
+    fn log_path(&self) -> PathBuf {
+        self.socket_dir.join("postgres.log")
+    }
+
+    fn run(&self, name: &str, command: &mut Command) -> Result<()> {
+        let Output { status, stderr, .. } = command
+            .env_clear()
+            .env("LD_LIBRARY_PATH", self.conf.pg_lib_dir())
+            .env("DYLD_LIBRARY_PATH", self.conf.pg_lib_dir())
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .output()
+            .with_context(|| format!("failed to execute {name}"))?;
+        if !status.success() {
+            let log = fs::read_to_string(self.log_path()).unwrap_or_default();
+            bail!(
+                "{name} failed: '{}', postgres log: '{log}'",
+                String::from_utf8_lossy(&stderr)
+            );
+        }
+        Ok(())
+    }
+}
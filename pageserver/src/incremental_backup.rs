@@ -0,0 +1,247 @@
+//!
+//! Generate a tarball with the relation pages that changed within a range of
+//! LSNs, for incremental backups.
+//!
+//! A backup tool that has a copy of the relations at 'from_lsn', from a full
+//! backup or a previous incremental one, brings it to 'to_lsn' with this
+//! tarball. It contains the image at 'to_lsn' of every block that was modified
+//! after 'from_lsn', up to and including 'to_lsn'. The modified blocks are found
+//! from the delta layers of the timeline, without reconstructing the others.
+//!
+//! The tarball starts with a 'backup_manifest.json' file, with the LSN range
+//! and the size at 'to_lsn' of every relation that exists at that point.
+//! Relations missing from it were dropped, and the others are truncated or
+//! extended to the given size, with zero pages for the blocks never written.
+//! Then, for every relation with modified blocks, there's a file named after
+//! the relation file in the data directory, with a '.blocks' suffix, like
+//! 'base/13008/16384_fsm.blocks'. It contains the modified blocks in the order
+//! of their block numbers, each one as its block number (4 bytes, big endian)
+//! followed by the page image.
+//!
+use anyhow::{ensure, Result};
+use bytes::{BufMut, BytesMut};
+use serde::Serialize;
+use serde_with::{serde_as, DisplayFromStr};
+use std::cmp::min;
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+use tar::Builder;
+use tracing::*;
+
+use crate::basebackup::new_tar_header;
+use crate::pg_file_path;
+use crate::pgdatadir_mapping::{is_rel_block_key, key_to_rel_block};
+use crate::reltag::RelTag;
+use crate::repository::Timeline;
+use crate::DatadirTimelineImpl;
+use postgres_ffi::pg_constants;
+use utils::lsn::Lsn;
+
+const MANIFEST_FILE_NAME: &str = "backup_manifest.json";
+/// Block number and page image
+const BLOCK_RECORD_SIZE: usize = 4 + pg_constants::BLCKSZ as usize;
+
+#[serde_as]
+#[derive(Serialize)]
+struct BackupManifest {
+    #[serde_as(as = "DisplayFromStr")]
+    from_lsn: Lsn,
+    #[serde_as(as = "DisplayFromStr")]
+    to_lsn: Lsn,
+    relations: Vec<RelationEntry>,
+}
+
+#[derive(Serialize)]
+struct RelationEntry {
+    path: String,
+    nblocks: u32,
+    changed_blocks: usize,
+}
+
+/// The modified blocks and the sizes of all relations, found before anything
+/// is sent, so that an LSN range that can't be served fails with an error.
+pub struct IncrementalBackup<'a> {
+    timeline: &'a Arc<DatadirTimelineImpl>,
+    pub from_lsn: Lsn,
+    pub to_lsn: Lsn,
+    /// Size of every relation at 'to_lsn', with its modified blocks in order
+    relations: BTreeMap<RelTag, (u32, Vec<u32>)>,
+}
+
+impl<'a> IncrementalBackup<'a> {
+    /// The backup covers the changes after 'from_lsn', up to 'to_lsn' or the
+    /// end of the timeline.
+    pub fn new(
+        timeline: &'a Arc<DatadirTimelineImpl>,
+        from_lsn: Lsn,
+        to_lsn: Option<Lsn>,
+    ) -> Result<IncrementalBackup<'a>> {
+        let to_lsn = match to_lsn {
+            Some(to_lsn) => {
+                info!("waiting for {}", to_lsn);
+                timeline.tline.wait_lsn(to_lsn)?;
+                to_lsn
+            }
+            None => timeline.tline.get_last_record_lsn(),
+        };
+        ensure!(
+            from_lsn <= to_lsn,
+            "Invalid LSN range for an incremental backup: {from_lsn} is after {to_lsn}"
+        );
+
+        let changed_keys = timeline
+            .tline
+            .collect_changed_keys(from_lsn + 1..to_lsn + 1)?;
+        let mut changed_blocks: BTreeMap<RelTag, Vec<u32>> = BTreeMap::new();
+        for key in changed_keys.into_iter().filter(is_rel_block_key) {
+            let (rel, blknum) = key_to_rel_block(key)?;
+            changed_blocks.entry(rel).or_default().push(blknum);
+        }
+
+        // Blocks of the relations dropped or truncated since are not sent
+        let mut relations = BTreeMap::new();
+        for (spcnode, dbnode) in timeline.list_dbdirs(to_lsn)?.into_keys() {
+            for rel in timeline.list_rels(spcnode, dbnode, to_lsn)? {
+                let nblocks = timeline.get_rel_size(rel, to_lsn)?;
+                let mut blocks = changed_blocks.remove(&rel).unwrap_or_default();
+                blocks.retain(|blknum| *blknum < nblocks);
+                blocks.sort_unstable();
+                relations.insert(rel, (nblocks, blocks));
+            }
+        }
+
+        Ok(IncrementalBackup {
+            timeline,
+            from_lsn,
+            to_lsn,
+            relations,
+        })
+    }
+
+    pub fn send_tarball<W: Write>(self, write: W) -> Result<()> {
+        let mut ar = Builder::new(write);
+
+        let manifest = BackupManifest {
+            from_lsn: self.from_lsn,
+            to_lsn: self.to_lsn,
+            relations: self
+                .relations
+                .iter()
+                .map(|(rel, (nblocks, blocks))| RelationEntry {
+                    path: relation_path(rel),
+                    nblocks: *nblocks,
+                    changed_blocks: blocks.len(),
+                })
+                .collect(),
+        };
+        let manifest = serde_json::to_vec_pretty(&manifest)?;
+        ar.append(
+            &new_tar_header(MANIFEST_FILE_NAME, manifest.len() as u64)?,
+            &manifest[..],
+        )?;
+
+        let mut total_blocks = 0;
+        for (rel, (_, blocks)) in &self.relations {
+            if blocks.is_empty() {
+                continue;
+            }
+            let path = format!("{}.blocks", relation_path(rel));
+            let size = blocks.len() * BLOCK_RECORD_SIZE;
+            let mut reader = BlocksReader {
+                timeline: self.timeline,
+                rel: *rel,
+                lsn: self.to_lsn,
+                blocks: blocks.iter(),
+                record: BytesMut::with_capacity(BLOCK_RECORD_SIZE),
+            };
+            ar.append(&new_tar_header(&path, size as u64)?, &mut reader)?;
+            total_blocks += blocks.len();
+        }
+        ar.finish()?;
+
+        info!(
+            "sent {} modified blocks between {} and {}",
+            total_blocks, self.from_lsn, self.to_lsn
+        );
+        Ok(())
+    }
+}
+
+/// Reconstructs the blocks of a relation as the tar entry is written, so that
+/// a large relation isn't held in memory at once.
+struct BlocksReader<'a> {
+    timeline: &'a Arc<DatadirTimelineImpl>,
+    rel: RelTag,
+    lsn: Lsn,
+    blocks: std::slice::Iter<'a, u32>,
+    /// The rest of the current block's record
+    record: BytesMut,
+}
+
+impl Read for BlocksReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.record.is_empty() {
+            let blknum = match self.blocks.next() {
+                Some(blknum) => *blknum,
+                None => return Ok(0),
+            };
+            let img = self
+                .timeline
+                .get_rel_page_at_lsn(self.rel, blknum, self.lsn)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{e:#}")))?;
+            if img.len() != pg_constants::BLCKSZ as usize {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!(
+                        "unexpected size {} of block {blknum} of {}",
+                        img.len(),
+                        self.rel
+                    ),
+                ));
+            }
+            self.record.put_u32(blknum);
+            self.record.extend_from_slice(&img);
+        }
+        let n = min(buf.len(), self.record.len());
+        buf[..n].copy_from_slice(&self.record.split_to(n));
+        Ok(n)
+    }
+}
+
+/// Path of the first segment of the relation in the data directory
+fn relation_path(rel: &RelTag) -> String {
+    pg_file_path::rel_segment_path(rel, 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_relation_path() {
+        let rel = RelTag {
+            spcnode: pg_constants::DEFAULTTABLESPACE_OID,
+            dbnode: 13008,
+            relnode: 16384,
+            forknum: pg_constants::MAIN_FORKNUM,
+        };
+        assert_eq!(relation_path(&rel), "base/13008/16384");
+        assert_eq!(
+            relation_path(&RelTag {
+                forknum: pg_constants::FSM_FORKNUM,
+                ..rel
+            }),
+            "base/13008/16384_fsm"
+        );
+        assert_eq!(
+            relation_path(&RelTag {
+                spcnode: pg_constants::GLOBALTABLESPACE_OID,
+                dbnode: 0,
+                relnode: 1262,
+                forknum: pg_constants::MAIN_FORKNUM,
+            }),
+            "global/1262"
+        );
+    }
+}
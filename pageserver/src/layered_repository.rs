@@ -21,7 +21,7 @@ use tracing::*;
 use std::cmp::{max, min, Ordering};
 use std::collections::hash_map::Entry;
 use std::collections::HashMap;
-use std::collections::{BTreeSet, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
 use std::fs;
 use std::fs::{File, OpenOptions};
 use std::io::Write;
@@ -32,13 +32,20 @@ use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, TryLockError};
 use std::time::{Duration, Instant, SystemTime};
 
 use self::metadata::{metadata_path, TimelineMetadata, METADATA_FILE_NAME};
+use crate::clock::{Clock, SystemClock};
 use crate::config::PageServerConf;
+use crate::hot_page_cache::HotPageCache;
 use crate::keyspace::KeySpace;
+use crate::metric_labels;
+use crate::read_error::ReadError;
+use crate::standby_horizon::StandbyHorizon;
 use crate::storage_sync::index::RemoteIndex;
-use crate::tenant_config::{TenantConf, TenantConfOpt};
+use crate::storage_sync::UPLOAD_STATE_EXTENSION;
+use crate::tenant_config::{EffectiveTenantConfValue, ForkSet, TenantConf, TenantConfOpt};
 
 use crate::repository::{
-    GcResult, Repository, RepositoryTimeline, Timeline, TimelineSyncStatusUpdate, TimelineWriter,
+    GcEstimate, GcProgress, GcResult, GcRetainReason, GcSpaceConsumer, GcSpaceReport, Repository,
+    RepositoryTimeline, Timeline, TimelineSyncStatusUpdate, TimelineWriter,
 };
 use crate::repository::{Key, Value};
 use crate::tenant_mgr;
@@ -57,37 +64,55 @@ use toml_edit;
 use utils::{
     crashsafe_dir,
     lsn::{AtomicLsn, Lsn, RecordLsn},
-    seqwait::SeqWait,
+    seqwait::{CancellationToken, SeqWait, SeqWaitError},
     zid::{ZTenantId, ZTimelineId},
 };
 
+pub mod append_only;
 mod blob_io;
 pub mod block_io;
 mod delta_layer;
 mod disk_btree;
 pub(crate) mod ephemeral_file;
 mod filename;
+mod flush_pacing;
 mod image_layer;
 mod inmemory_layer;
+pub mod layer_deletion;
 mod layer_map;
+mod layer_stats;
+pub mod layer_strategy;
 pub mod metadata;
 mod par_fsync;
+pub mod set_aside;
 mod storage_layer;
 
-use crate::pgdatadir_mapping::LsnForTimestamp;
+use crate::pgdatadir_mapping::{is_rel_block_key, key_range_to_rel, LsnForTimestamp, ZERO_PAGE};
+use append_only::{
+    AppendOnlyRelations, RelFileNode, APPEND_ONLY_DELTA_SIZE_FACTOR,
+    APPEND_ONLY_IMAGE_THRESHOLD_FACTOR,
+};
 use delta_layer::{DeltaLayer, DeltaLayerWriter};
 use ephemeral_file::is_ephemeral_file;
 use filename::{DeltaFileName, ImageFileName};
+use flush_pacing::{FlushPacer, FlushPacing};
 use image_layer::{ImageLayer, ImageLayerWriter};
 use inmemory_layer::InMemoryLayer;
 use layer_map::LayerMap;
 use layer_map::SearchResult;
+use layer_strategy::{GcContext, GcLayer, LayerStrategy};
 use postgres_ffi::xlog_utils::to_pg_timestamp;
 use storage_layer::{Layer, ValueReconstructResult, ValueReconstructState};
 
 // re-export this function so that page_cache.rs can use it.
 pub use crate::layered_repository::ephemeral_file::writeback as writeback_ephemeral_file;
 
+// re-export this function so that the downloads can verify the layers with a checksum in their name.
+pub use crate::layered_repository::filename::stamp_of_path;
+
+// re-export this function so that the timeline doctor can tell the layer files in a timeline directory.
+pub use crate::layered_repository::filename::is_layer_file_name;
+
 // Metrics collected on operations on the storage repository.
 lazy_static! {
     static ref STORAGE_TIME: HistogramVec = register_histogram_vec!(
@@ -118,9 +143,37 @@ lazy_static! {
     static ref WAIT_LSN_TIME: HistogramVec = register_histogram_vec!(
         "pageserver_wait_lsn_seconds",
         "Time spent waiting for WAL to arrive",
+        &["tenant_id", "timeline_id"],
+        // Up to the default wait_lsn_timeout of 60 seconds
+        vec![0.0001, 0.001, 0.01, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0]
+    )
+    .expect("failed to define a metric");
+    static ref WAIT_LSN_WAITERS: IntGaugeVec = register_int_gauge_vec!(
+        "pageserver_wait_lsn_waiters",
+        "Number of requests currently waiting for WAL to arrive",
+        &["tenant_id", "timeline_id"]
+    )
+    .expect("failed to define a metric");
+    static ref WAIT_LSN_CANCELLED: IntCounterVec = register_int_counter_vec!(
+        "pageserver_wait_lsn_cancelled_total",
+        "Number of waits for WAL cancelled because the requester went away",
         &["tenant_id", "timeline_id"]
     )
     .expect("failed to define a metric");
+    static ref READ_ANCESTOR_HOPS: HistogramVec = register_histogram_vec!(
+        "pageserver_read_ancestor_hops",
+        "Number of ancestor timelines a page reconstruction went through",
+        &["tenant_id", "timeline_id"],
+        vec![0.0, 1.0, 2.0, 4.0, 8.0, 16.0, 32.0]
+    )
+    .expect("failed to define a metric");
+    static ref READ_ANCESTOR_TIME: HistogramVec = register_histogram_vec!(
+        "pageserver_read_ancestor_seconds",
+        "Time a page reconstruction spent looking through the layers of ancestor timelines, for the reads that went into one",
+        &["tenant_id"],
+        vec![0.00001, 0.0001, 0.001, 0.01, 0.1, 1.0, 10.0]
+    )
+    .expect("failed to define a metric");
 }
 
 lazy_static! {
@@ -130,6 +183,16 @@ lazy_static! {
         &["tenant_id", "timeline_id"]
     )
     .expect("failed to define a metric");
+    static ref COMPACTION_SKIPPED_IDLE: IntCounter = register_int_counter!(
+        "pageserver_compaction_skipped_idle_total",
+        "Number of times compaction skipped a timeline with no writes since its last compaction"
+    )
+    .expect("failed to define a metric");
+    static ref COMPACTION_DONE_BY_GC: IntCounter = register_int_counter!(
+        "pageserver_compaction_done_by_gc_total",
+        "Number of Level 0 compactions done by GC, with the layers found by its scan of the layer map"
+    )
+    .expect("failed to define a metric");
 }
 
 // Metrics for cloud upload. These metrics reflect data uploaded to cloud storage,
@@ -163,6 +226,9 @@ pub struct LayeredRepository {
     // This is necessary to allow global config updates.
     tenant_conf: Arc<RwLock<TenantConfOpt>>,
 
+    /// Relations marked as append-only, for the layer policies of all the timelines.
+    append_only_relations: Arc<RwLock<AppendOnlyRelations>>,
+
     tenant_id: ZTenantId,
     timelines: Mutex<HashMap<ZTimelineId, LayeredTimelineEntry>>,
     // This mutex prevents creation of new timelines during GC.
@@ -180,6 +246,15 @@ pub struct LayeredRepository {
 
     /// Makes every timeline to backup their files to remote storage.
     upload_layers: bool,
+
+    /// Source of the current time, replaced with a virtual clock in the tests
+    clock: Arc<dyn Clock>,
+
+    /// Decides which layers GC keeps, and when to compact, for all the timelines.
+    layer_strategy: Arc<dyn LayerStrategy>,
+
+    /// Progress of the running or the last GC iteration, and the cancel request.
+    gc_progress: Mutex<GcProgress>,
 }
 
 /// Public interface
@@ -233,12 +308,15 @@ impl Repository for LayeredRepository {
         let timeline = LayeredTimeline::new(
             self.conf,
             Arc::clone(&self.tenant_conf),
+            Arc::clone(&self.append_only_relations),
             metadata,
             None,
             timelineid,
             self.tenant_id,
             Arc::clone(&self.walredo_mgr),
             self.upload_layers,
+            Arc::clone(&self.clock),
+            Arc::clone(&self.layer_strategy),
         );
         timeline.layers.write().unwrap().next_open_layer_at = Some(initdb_lsn);
 
@@ -319,12 +397,16 @@ impl Repository for LayeredRepository {
         pitr: Duration,
         checkpoint_before_gc: bool,
     ) -> Result<GcResult> {
-        let timeline_str = target_timelineid
-            .map(|x| x.to_string())
-            .unwrap_or_else(|| "-".to_string());
+        let (tenant_label, timeline_label) = match target_timelineid {
+            Some(timeline_id) => metric_labels::timeline_labels(&self.tenant_id, &timeline_id),
+            None => (
+                metric_labels::tenant_label(&self.tenant_id),
+                "-".to_string(),
+            ),
+        };
 
         STORAGE_TIME
-            .with_label_values(&["gc", &self.tenant_id.to_string(), &timeline_str])
+            .with_label_values(&["gc", &tenant_label, &timeline_label])
             .observe_closure_duration(|| {
                 self.gc_iteration_internal(target_timelineid, horizon, pitr, checkpoint_before_gc)
             })
@@ -347,7 +429,20 @@ impl Repository for LayeredRepository {
                 info_span!("compact", timeline = %timelineid, tenant = %self.tenant_id).entered();
             match timeline {
                 LayeredTimelineEntry::Loaded(timeline) => {
-                    timeline.compact()?;
+                    if !timeline
+                        .compaction_needed
+                        .swap(false, atomic::Ordering::Relaxed)
+                    {
+                        debug!("Skipping compaction of idle timeline {}", timelineid);
+                        COMPACTION_SKIPPED_IDLE.inc();
+                        continue;
+                    }
+                    if let Err(e) = timeline.compact_deltas() {
+                        timeline
+                            .compaction_needed
+                            .store(true, atomic::Ordering::Relaxed);
+                        return Err(e);
+                    }
                 }
                 LayeredTimelineEntry::Unloaded { .. } => {
                     debug!("Cannot compact remote timeline {}", timelineid)
@@ -358,6 +453,49 @@ impl Repository for LayeredRepository {
         Ok(())
     }
 
+    fn image_creation_iteration(&self) -> Result<()> {
+        let timelines = self.timelines.lock().unwrap();
+        let timelines_to_image = timelines
+            .iter()
+            .filter_map(|(timelineid, entry)| match entry {
+                LayeredTimelineEntry::Loaded(timeline) => Some((*timelineid, timeline.clone())),
+                LayeredTimelineEntry::Unloaded { .. } => None,
+            })
+            .collect::<Vec<_>>();
+        drop(timelines);
+
+        for (timelineid, timeline) in &timelines_to_image {
+            let _entered =
+                info_span!("create_images", timeline = %timelineid, tenant = %self.tenant_id)
+                    .entered();
+            if timeline
+                .flattening_needed
+                .swap(false, atomic::Ordering::Relaxed)
+            {
+                if let Err(e) = timeline.flatten_branch_point() {
+                    timeline
+                        .flattening_needed
+                        .store(true, atomic::Ordering::Relaxed);
+                    return Err(e);
+                }
+            }
+            if !timeline
+                .image_creation_needed
+                .swap(false, atomic::Ordering::Relaxed)
+            {
+                continue;
+            }
+            if let Err(e) = timeline.create_image_layers() {
+                timeline
+                    .image_creation_needed
+                    .store(true, atomic::Ordering::Relaxed);
+                return Err(e);
+            }
+        }
+
+        Ok(())
+    }
+
     ///
     /// Flush all in-memory data to disk.
     ///
@@ -408,10 +546,11 @@ impl Repository for LayeredRepository {
             "Cannot detach timeline which has child timelines"
         );
 
-        ensure!(
-            timelines.remove(&timeline_id).is_some(),
-            "Cannot detach timeline {timeline_id} that is not available locally"
-        );
+        match timelines.remove(&timeline_id) {
+            Some(LayeredTimelineEntry::Loaded(timeline)) => timeline.shutdown(),
+            Some(LayeredTimelineEntry::Unloaded { .. }) => {}
+            None => bail!("Cannot detach timeline {timeline_id} that is not available locally"),
+        }
         Ok(())
     }
 
@@ -550,6 +689,20 @@ impl LayeredRepository {
             .unwrap_or(self.conf.default_tenant_conf.image_creation_threshold)
     }
 
+    pub fn get_logical_size_soft_limit(&self) -> Option<u64> {
+        let tenant_conf = self.tenant_conf.read().unwrap();
+        tenant_conf
+            .logical_size_soft_limit
+            .or(self.conf.default_tenant_conf.logical_size_soft_limit)
+    }
+
+    pub fn get_image_layer_excluded_forks(&self) -> ForkSet {
+        let tenant_conf = self.tenant_conf.read().unwrap();
+        tenant_conf
+            .image_layer_excluded_forks
+            .unwrap_or(self.conf.default_tenant_conf.image_layer_excluded_forks)
+    }
+
     pub fn get_pitr_interval(&self) -> Duration {
         let tenant_conf = self.tenant_conf.read().unwrap();
         tenant_conf
@@ -557,15 +710,80 @@ impl LayeredRepository {
             .unwrap_or(self.conf.default_tenant_conf.pitr_interval)
     }
 
+    /// Estimate how many bytes of layer files the next GC iteration could free,
+    /// across all loaded timelines of the tenant.
+    ///
+    /// This only looks at the GC horizon and image layer coverage, ignoring
+    /// branch points and the PITR interval, so it's an upper bound. It's used to
+    /// decide which tenants should be garbage collected first.
+    pub fn estimate_gc_reclaimable_bytes(&self, horizon: u64) -> u64 {
+        let timelines = self.timelines.lock().unwrap();
+        timelines
+            .values()
+            .filter_map(|entry| match entry {
+                LayeredTimelineEntry::Loaded(timeline) => Some(Arc::clone(timeline)),
+                LayeredTimelineEntry::Unloaded { .. } => None,
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .map(|timeline| timeline.estimate_gc_reclaimable_bytes(horizon))
+            .sum()
+    }
+
+    ///
+    /// Apply the settings of 'new_tenant_conf' on top of the current ones. Fails
+    /// with [`crate::tenant_config::InvalidTenantConf`] if the resulting settings are not valid.
+    ///
     pub fn update_tenant_config(&self, new_tenant_conf: TenantConfOpt) -> Result<()> {
         let mut tenant_conf = self.tenant_conf.write().unwrap();
 
-        tenant_conf.update(&new_tenant_conf);
+        let mut updated_conf = *tenant_conf;
+        updated_conf.update(&new_tenant_conf);
+        updated_conf
+            .merge(self.conf.default_tenant_conf)
+            .validate()?;
+
+        LayeredRepository::persist_tenant_config(self.conf, self.tenant_id, updated_conf)?;
+        *tenant_conf = updated_conf;
+        Ok(())
+    }
+
+    /// The settings in effect for the tenant, with the source of each value.
+    pub fn get_effective_tenant_config(
+        &self,
+    ) -> Result<BTreeMap<String, EffectiveTenantConfValue>> {
+        let tenant_conf = self.tenant_conf.read().unwrap();
+        tenant_conf.effective(self.conf.default_tenant_conf)
+    }
 
-        LayeredRepository::persist_tenant_config(self.conf, self.tenant_id, *tenant_conf)?;
+    /// Load the append-only hints saved in the tenant directory.
+    pub fn load_append_only_relations(&self) -> Result<()> {
+        let path = AppendOnlyRelations::path(self.conf, self.tenant_id);
+        *self.append_only_relations.write().unwrap() = AppendOnlyRelations::load(&path)?;
         Ok(())
     }
 
+    ///
+    /// Mark a relation as append-only, or not, for the layer policies of all
+    /// the timelines. Returns whether that changed anything. See the
+    /// [`append_only`] module.
+    ///
+    pub fn set_relation_append_only(&self, rel: RelFileNode, append_only: bool) -> Result<bool> {
+        let mut append_only_relations = self.append_only_relations.write().unwrap();
+        let mut updated = append_only_relations.clone();
+        if !updated.set(rel, append_only) {
+            return Ok(false);
+        }
+        updated.save(&AppendOnlyRelations::path(self.conf, self.tenant_id))?;
+        *append_only_relations = updated;
+        info!("relation {rel} is append-only: {append_only}");
+        Ok(true)
+    }
+
+    pub fn get_append_only_relations(&self) -> Vec<RelFileNode> {
+        self.append_only_relations.read().unwrap().list()
+    }
+
     // Implementation of the public `get_timeline` function.
     // Differences from the public:
     //  * interface in that the caller must already hold the mutex on the 'timelines' hashmap.
@@ -624,6 +842,11 @@ impl LayeredRepository {
             .context("failed to load metadata")?;
         let disk_consistent_lsn = metadata.disk_consistent_lsn();
 
+        if let Some(ancestor_timeline_id) =
+            Self::missing_local_ancestor_internal(timeline_id, timelines)
+        {
+            bail!("ancestor timeline {ancestor_timeline_id} is not present locally yet");
+        }
         let ancestor = metadata
             .ancestor_timeline()
             .map(|ancestor_timeline_id| {
@@ -639,12 +862,15 @@ impl LayeredRepository {
         let timeline = LayeredTimeline::new(
             self.conf,
             Arc::clone(&self.tenant_conf),
+            Arc::clone(&self.append_only_relations),
             metadata,
             ancestor,
             timeline_id,
             self.tenant_id,
             Arc::clone(&self.walredo_mgr),
             self.upload_layers,
+            Arc::clone(&self.clock),
+            Arc::clone(&self.layer_strategy),
         );
         timeline
             .load_layer_map(disk_consistent_lsn)
@@ -665,14 +891,33 @@ impl LayeredRepository {
             tenant_id,
             conf,
             tenant_conf: Arc::new(RwLock::new(tenant_conf)),
+            append_only_relations: Arc::new(RwLock::new(AppendOnlyRelations::default())),
             timelines: Mutex::new(HashMap::new()),
             gc_cs: Mutex::new(()),
             walredo_mgr,
             remote_index,
             upload_layers,
+            clock: Arc::new(SystemClock),
+            layer_strategy: layer_strategy::installed(),
+            gc_progress: Mutex::new(GcProgress::default()),
         }
     }
 
+    /// Use 'clock' as the source of the current time, instead of the real time.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> LayeredRepository {
+        self.clock = clock;
+        self
+    }
+
+    /// Use 'layer_strategy' instead of the installed one.
+    pub fn with_layer_strategy(
+        mut self,
+        layer_strategy: Arc<dyn LayerStrategy>,
+    ) -> LayeredRepository {
+        self.layer_strategy = layer_strategy;
+        self
+    }
+
     /// Locate and load config
     pub fn load_tenant_config(
         conf: &'static PageServerConf,
@@ -774,6 +1019,37 @@ impl LayeredRepository {
         Ok(())
     }
 
+    /// What GC needs from the remote index, read once per iteration: the
+    /// branch points of the archived timelines, and the uploaded layers of the
+    /// timelines.
+    #[allow(clippy::type_complexity)]
+    fn gc_remote_index_snapshot(
+        &self,
+    ) -> (
+        Vec<(ZTimelineId, Lsn)>,
+        HashMap<ZTimelineId, HashSet<PathBuf>>,
+    ) {
+        // GC runs on the GC threads and the page service threads, never on an
+        // async task, so it can block on the lock
+        let index = self.remote_index.blocking_read();
+
+        let mut archived_branchpoints = Vec::new();
+        let mut uploaded_layers = HashMap::new();
+        for (timeline_id, remote_timeline) in index.tenant_timelines(self.tenant_id) {
+            if remote_timeline.archived {
+                if let Some(ancestor_timeline_id) = remote_timeline.metadata.ancestor_timeline() {
+                    archived_branchpoints.push((
+                        ancestor_timeline_id,
+                        remote_timeline.metadata.ancestor_lsn(),
+                    ));
+                }
+            } else {
+                uploaded_layers.insert(timeline_id, remote_timeline.stored_files().clone());
+            }
+        }
+        (archived_branchpoints, uploaded_layers)
+    }
+
     //
     // How garbage collection works:
     //
@@ -814,31 +1090,59 @@ impl LayeredRepository {
         // grab mutex to prevent new timelines from being created here.
         let _gc_cs = self.gc_cs.lock().unwrap();
 
+        *self.gc_progress.lock().unwrap() = GcProgress {
+            running: true,
+            ..GcProgress::default()
+        };
+        scopeguard::defer! {
+            let mut progress = self.gc_progress.lock().unwrap();
+            progress.running = false;
+            progress.cancel_requested = false;
+            progress.current_timeline = None;
+        }
+
         // Scan all timelines. For each timeline, remember the timeline ID and
         // the branch point where it was created.
         let mut all_branchpoints: BTreeSet<(ZTimelineId, Lsn)> = BTreeSet::new();
         let mut timeline_ids = Vec::new();
+
+        // Archived timelines have no local state and are not garbage collected themselves,
+        // but their branch points have to be retained in their local ancestors.
+        let (archived_branchpoints, mut uploaded_layers) = self.gc_remote_index_snapshot();
+
         let mut timelines = self.timelines.lock().unwrap();
 
-        for (timeline_id, timeline_entry) in timelines.iter() {
-            timeline_ids.push(*timeline_id);
-
-            // This is unresolved question for now, how to do gc in presence of remote timelines
-            // especially when this is combined with branching.
-            // Somewhat related: https://github.com/zenithdb/zenith/issues/999
-            if let Some(ancestor_timeline_id) = &timeline_entry.ancestor_timeline_id() {
-                // If target_timeline is specified, we only need to know branchpoints of its children
-                if let Some(timelineid) = target_timelineid {
-                    if ancestor_timeline_id == &timelineid {
-                        all_branchpoints
-                            .insert((*ancestor_timeline_id, timeline_entry.ancestor_lsn()));
-                    }
-                }
-                // Collect branchpoints for all timelines
-                else {
-                    all_branchpoints.insert((*ancestor_timeline_id, timeline_entry.ancestor_lsn()));
+        let local_branchpoints = timelines
+            .values()
+            .filter_map(|timeline_entry| {
+                Some((
+                    timeline_entry.ancestor_timeline_id()?,
+                    timeline_entry.ancestor_lsn(),
+                ))
+            })
+            .collect::<Vec<_>>();
+        timeline_ids.extend(timelines.keys().copied());
+        self.gc_progress.lock().unwrap().timelines_total = match target_timelineid {
+            Some(_) => 1,
+            None => timeline_ids.len(),
+        };
+
+        // This is unresolved question for now, how to do gc in presence of remote timelines
+        // especially when this is combined with branching.
+        // Somewhat related: https://github.com/zenithdb/zenith/issues/999
+        for (ancestor_timeline_id, ancestor_lsn) in
+            local_branchpoints.into_iter().chain(archived_branchpoints)
+        {
+            // If target_timeline is specified, we only need to know branchpoints of its children
+            if let Some(timelineid) = target_timelineid {
+                if ancestor_timeline_id == timelineid {
+                    all_branchpoints.insert((ancestor_timeline_id, ancestor_lsn));
                 }
             }
+            // Collect branchpoints for all timelines
+            else {
+                all_branchpoints.insert((ancestor_timeline_id, ancestor_lsn));
+            }
         }
 
         // Ok, we now know all the branch points.
@@ -849,6 +1153,23 @@ impl LayeredRepository {
                 // made.
                 break;
             }
+            if self.gc_progress.lock().unwrap().cancel_requested {
+                info!("GC iteration cancelled");
+                totals.cancelled = true;
+                break;
+            }
+
+            // A branch of a timeline that is still being downloaded can't be loaded yet,
+            // and has no data of its own to collect.
+            if let Some(ancestor_timelineid) =
+                Self::missing_local_ancestor_internal(timelineid, &timelines)
+            {
+                info!("skipping GC of timeline {timelineid}: its ancestor {ancestor_timelineid} is not present locally yet");
+                if target_timelineid.map_or(true, |target| target == timelineid) {
+                    self.gc_progress.lock().unwrap().timelines_done += 1;
+                }
+                continue;
+            }
 
             // Timeline is known to be local and loaded.
             let timeline = self
@@ -879,12 +1200,24 @@ impl LayeredRepository {
                     timeline.checkpoint(CheckpointConfig::Forced)?;
                     info!("timeline {} checkpoint_before_gc done", timelineid);
                 }
+                self.gc_progress.lock().unwrap().current_timeline = Some(timelineid);
                 timeline.update_gc_info(branchpoints, cutoff, pitr);
+                if timeline.upload_layers.load(atomic::Ordering::Relaxed) {
+                    timeline.gc_info.write().unwrap().uploaded_layers =
+                        Some(uploaded_layers.remove(&timelineid).unwrap_or_default());
+                }
                 let result = timeline.gc()?;
 
+                let mut progress = self.gc_progress.lock().unwrap();
+                progress.layers_examined += result.layers_total;
+                progress.layers_removed += result.layers_removed;
+                progress.bytes_removed += result.bytes_removed;
+                drop(progress);
+
                 totals += result;
                 timelines = self.timelines.lock().unwrap();
             }
+            self.gc_progress.lock().unwrap().timelines_done += 1;
         }
 
         totals.elapsed = now.elapsed();
@@ -894,11 +1227,233 @@ impl LayeredRepository {
     pub fn tenant_id(&self) -> ZTenantId {
         self.tenant_id
     }
+
+    /// Progress of the running GC iteration, or of the last one if none is running.
+    pub fn get_gc_progress(&self) -> GcProgress {
+        self.gc_progress.lock().unwrap().clone()
+    }
+
+    ///
+    /// Ask the running GC iteration to stop before its next timeline. It returns
+    /// the result of the timelines it has collected so far. Returns false if no
+    /// GC iteration is running.
+    ///
+    pub fn cancel_gc(&self) -> bool {
+        let mut progress = self.gc_progress.lock().unwrap();
+        if progress.running {
+            progress.cancel_requested = true;
+        }
+        progress.running
+    }
+
+    ///
+    /// Branch off a timeline that is only present in the remote storage, e.g. while the
+    /// tenant is still being downloaded. The branch point is validated against the
+    /// remote metadata of the source, and the new timeline can only be loaded after
+    /// the source gets downloaded.
+    ///
+    pub fn branch_remote_timeline(
+        &self,
+        src: ZTimelineId,
+        dst: ZTimelineId,
+        start_lsn: Lsn,
+        src_metadata: &TimelineMetadata,
+    ) -> Result<()> {
+        // Same as in branch_timeline: GC must not run while the new timeline is created.
+        let _gc_cs = self.gc_cs.lock().unwrap();
+
+        let mut timelines = self.timelines.lock().unwrap();
+        ensure!(
+            !timelines.contains_key(&src),
+            "timeline {} is present locally, branch it with branch_timeline",
+            src
+        );
+
+        let src_disk_consistent_lsn = src_metadata.disk_consistent_lsn();
+        ensure!(
+            start_lsn <= src_disk_consistent_lsn,
+            "LSN {} is later than the remote disk consistent LSN {} of timeline {}",
+            start_lsn,
+            src_disk_consistent_lsn,
+            src
+        );
+        let latest_gc_cutoff_lsn = src_metadata.latest_gc_cutoff_lsn();
+        ensure!(
+            start_lsn >= latest_gc_cutoff_lsn,
+            "LSN {} is earlier than latest GC horizon {} (we might've already garbage collected needed data)",
+            start_lsn,
+            latest_gc_cutoff_lsn,
+        );
+
+        // Only the metadata is known: the previous record LSN is only there at the last record.
+        let dst_prev = if start_lsn == src_disk_consistent_lsn {
+            src_metadata.prev_record_lsn()
+        } else {
+            None
+        };
+
+        let metadata = TimelineMetadata::new(
+            start_lsn,
+            dst_prev,
+            Some(src),
+            start_lsn,
+            latest_gc_cutoff_lsn,
+            src_metadata.initdb_lsn(),
+        );
+        crashsafe_dir::create_dir_all(self.conf.timeline_path(&dst, &self.tenant_id))?;
+        Self::save_metadata(self.conf, dst, self.tenant_id, &metadata, true)?;
+        timelines.insert(dst, LayeredTimelineEntry::Unloaded { id: dst, metadata });
+
+        info!(
+            "branched timeline {} from remote timeline {} at {}",
+            dst, src, start_lsn
+        );
+
+        Ok(())
+    }
+
+    ///
+    /// Create many branches of a local timeline at the same LSN. Like
+    /// branch_timeline, but the source timeline and the LSN are validated once,
+    /// under one GC lock, and the new timeline directories and metadata files
+    /// are made durable with one batch of parallel fsyncs.
+    ///
+    /// The outer error is for the whole batch. For each new timeline, returns
+    /// whether it was created, or false if a timeline with that id exists.
+    ///
+    pub fn branch_timelines(
+        &self,
+        src: ZTimelineId,
+        dsts: &[ZTimelineId],
+        start_lsn: Lsn,
+    ) -> Result<Vec<Result<bool>>> {
+        // Same as in branch_timeline: GC must not run while the new timelines are created.
+        let _gc_cs = self.gc_cs.lock().unwrap();
+
+        let mut timelines = self.timelines.lock().unwrap();
+        let src_timeline = self
+            .get_timeline_load_internal(src, &mut timelines)
+            .context("failed to load timeline for branching")?
+            .ok_or_else(|| anyhow::anyhow!("unknown timeline id: {}", &src))?;
+        let latest_gc_cutoff_lsn = {
+            let latest_gc_cutoff_lsn = src_timeline.get_latest_gc_cutoff_lsn();
+            src_timeline
+                .check_lsn_is_in_scope(start_lsn, &latest_gc_cutoff_lsn)
+                .context("invalid branch start lsn")?;
+            *latest_gc_cutoff_lsn
+        };
+
+        let RecordLsn {
+            last: src_last,
+            prev: src_prev,
+        } = src_timeline.get_last_record_rlsn();
+        let dst_prev = if src_last == start_lsn {
+            Some(src_prev)
+        } else {
+            None
+        };
+        let metadata = TimelineMetadata::new(
+            start_lsn,
+            dst_prev,
+            Some(src),
+            start_lsn,
+            latest_gc_cutoff_lsn,
+            src_timeline.initdb_lsn,
+        );
+        let metadata_bytes = metadata
+            .to_bytes()
+            .context("Failed to get metadata bytes")?;
+
+        // Create the directories and the metadata files, without syncing them yet
+        let mut results = Vec::with_capacity(dsts.len());
+        let mut created = Vec::new();
+        let mut paths_to_sync = Vec::new();
+        for &dst in dsts {
+            let timelinedir = self.conf.timeline_path(&dst, &self.tenant_id);
+            if timelines.contains_key(&dst) || timelinedir.exists() {
+                results.push(Ok(false));
+                continue;
+            }
+            let metadata_path = metadata_path(self.conf, dst, self.tenant_id);
+            let result = fs::create_dir(&timelinedir)
+                .and_then(|()| {
+                    let mut file = OpenOptions::new()
+                        .write(true)
+                        .create_new(true)
+                        .open(&metadata_path)?;
+                    file.write_all(&metadata_bytes)
+                })
+                .with_context(|| format!("Failed to create timeline {dst}"));
+            match result {
+                Ok(()) => {
+                    paths_to_sync.push(metadata_path);
+                    paths_to_sync.push(timelinedir);
+                    created.push(dst);
+                    results.push(Ok(true));
+                }
+                Err(e) => {
+                    // Don't leave a timeline without metadata behind
+                    let _ = fs::remove_dir_all(&timelinedir);
+                    results.push(Err(e));
+                }
+            }
+        }
+        if created.is_empty() {
+            return Ok(results);
+        }
+
+        paths_to_sync.push(self.conf.timelines_path(&self.tenant_id));
+        if let Err(e) = par_fsync::par_fsync(&paths_to_sync) {
+            for dst in &created {
+                let _ = fs::remove_dir_all(self.conf.timeline_path(dst, &self.tenant_id));
+            }
+            return Err(e).context("Failed to sync the new timelines");
+        }
+
+        for &dst in &created {
+            timelines.insert(
+                dst,
+                LayeredTimelineEntry::Unloaded {
+                    id: dst,
+                    metadata: metadata.clone(),
+                },
+            );
+        }
+        info!(
+            "branched {} timelines from {} at {}",
+            created.len(),
+            src,
+            start_lsn
+        );
+
+        Ok(results)
+    }
+
+    /// Returns the first ancestor of the timeline that is not present locally, if any.
+    pub fn missing_local_ancestor(&self, timelineid: ZTimelineId) -> Option<ZTimelineId> {
+        let timelines = self.timelines.lock().unwrap();
+        Self::missing_local_ancestor_internal(timelineid, &timelines)
+    }
+
+    fn missing_local_ancestor_internal(
+        timelineid: ZTimelineId,
+        timelines: &HashMap<ZTimelineId, LayeredTimelineEntry>,
+    ) -> Option<ZTimelineId> {
+        let mut ancestor_timelineid = timelines.get(&timelineid)?.ancestor_timeline_id();
+        while let Some(ancestor) = ancestor_timelineid {
+            match timelines.get(&ancestor) {
+                Some(entry) => ancestor_timelineid = entry.ancestor_timeline_id(),
+                None => return Some(ancestor),
+            }
+        }
+        None
+    }
 }
 
 pub struct LayeredTimeline {
     conf: &'static PageServerConf,
     tenant_conf: Arc<RwLock<TenantConfOpt>>,
+    append_only_relations: Arc<RwLock<AppendOnlyRelations>>,
 
     tenant_id: ZTenantId,
     timeline_id: ZTimelineId,
@@ -906,6 +1461,9 @@ pub struct LayeredTimeline {
     layers: RwLock<LayerMap>,
 
     last_freeze_at: AtomicLsn,
+    // When the open layer was last frozen, to estimate the WAL ingest rate.
+    last_freeze_time: Mutex<Instant>,
+    flush_pacer: FlushPacer,
 
     // WAL redo manager
     walredo_mgr: Arc<dyn WalRedoManager + Sync + Send>,
@@ -945,12 +1503,41 @@ pub struct LayeredTimeline {
     flush_time_histo: Histogram,
     compact_time_histo: Histogram,
     create_images_time_histo: Histogram,
-    last_record_gauge: IntGauge,
+    last_record_gauge: Option<IntGauge>,
     wait_lsn_time_histo: Histogram,
+    wait_lsn_waiters_gauge: IntGauge,
+    wait_lsn_cancelled_counter: IntCounter,
+    ancestor_hops_histo: Histogram,
+    ancestor_time_histo: Histogram,
 
     /// If `true`, will backup its files that appear after each checkpointing to the remote storage.
     upload_layers: AtomicBool,
 
+    /// Set by every write and by every flush of a frozen layer, cleared when
+    /// compaction starts, or when GC compacts the timeline in the same pass
+    /// (see [`LayeredTimeline::gc`]). Compaction skips timelines that were not
+    /// written to since their last compaction, as it would have nothing new to do.
+    /// Set initially, as the layer files found on load may need compaction.
+    compaction_needed: AtomicBool,
+
+    /// Set by every flush of a frozen layer, cleared when the image builder
+    /// thread starts looking at the timeline. Like `compaction_needed`, set
+    /// initially.
+    image_creation_needed: AtomicBool,
+
+    /// Set by a read that went through more than `max_ancestor_hops` ancestor
+    /// timelines, cleared when the image builder thread materializes the
+    /// branch point, see [`LayeredTimeline::flatten_branch_point`].
+    flattening_needed: AtomicBool,
+    /// Whether the timeline may have image layers of its own at the branch
+    /// point, that reads at the branch point have to look at before going
+    /// into the ancestor.
+    has_branch_point_images: AtomicBool,
+
+    /// Set when the timeline is being detached. Reads, compaction and GC are
+    /// refused from then on, see [`LayeredTimeline::shutdown`].
+    stopping: AtomicBool,
+
     /// Ensures layers aren't frozen by checkpointer between
     /// [`LayeredTimeline::get_layer_for_write`] and layer reads.
     /// Locked automatically by [`LayeredTimelineWriter`] and checkpointer.
@@ -970,10 +1557,29 @@ pub struct LayeredTimeline {
     // Needed to ensure that we can't create a branch at a point that was already garbage collected
     latest_gc_cutoff_lsn: RwLock<Lsn>,
 
+    // Reads below this LSN are rejected, see set_read_floor_lsn(). 0 when not set.
+    read_floor_lsn: AtomicLsn,
+
     // List of child timelines and their branch points. This is needed to avoid
     // garbage collecting data that is still needed by the child timelines.
     gc_info: RwLock<GcInfo>,
 
+    // Where the space retained by the last GC went, see `gc_space_report`
+    last_gc_space_report: Mutex<Option<GcSpaceReport>>,
+
+    // What GC could free now, see `gc_estimate`
+    last_gc_estimate: Mutex<Option<GcEstimate>>,
+
+    // Latest images of the control pages read at high rates
+    hot_page_cache: HotPageCache,
+
+    // Oldest LSN that the read replicas still read at. GC keeps it.
+    standby_horizon: StandbyHorizon,
+
+    clock: Arc<dyn Clock>,
+
+    layer_strategy: Arc<dyn LayerStrategy>,
+
     // It may change across major versions so for simplicity
     // keep it after running initdb for a timeline.
     // It is needed in checks when we want to error on some operations
@@ -1008,6 +1614,29 @@ struct GcInfo {
     /// minus 'pitr_interval'
     ///
     pitr: Duration,
+
+    /// The layer files of the timeline that are in the remote storage, when
+    /// the layers are uploaded. The others are kept, their upload may be in
+    /// progress.
+    uploaded_layers: Option<HashSet<PathBuf>>,
+}
+
+/// The ancestor timelines that a read went through.
+#[derive(Default)]
+struct AncestorTraversal {
+    hops: usize,
+    /// When the read went into the first ancestor.
+    started_at: Option<Instant>,
+}
+
+/// A layer file in the layer map of a timeline.
+#[derive(Debug, Clone)]
+pub struct LayerFileInfo {
+    pub path: PathBuf,
+    pub key_range: Range<Key>,
+    pub lsn_range: Range<Lsn>,
+    /// Delta layers are incremental, image layers are not.
+    pub is_incremental: bool,
 }
 
 /// Public interface functions
@@ -1022,8 +1651,8 @@ impl Timeline for LayeredTimeline {
             .map(LayeredTimelineEntry::timeline_id)
     }
 
-    /// Wait until WAL has been received up to the given LSN.
-    fn wait_lsn(&self, lsn: Lsn) -> anyhow::Result<()> {
+    /// Wait until WAL has been received up to the given LSN, or the wait gets cancelled.
+    fn wait_lsn_cancellable(&self, lsn: Lsn, cancel: &CancellationToken) -> anyhow::Result<()> {
         // This should never be called from the WAL receiver thread, because that could lead
         // to a deadlock.
         ensure!(
@@ -1031,15 +1660,35 @@ impl Timeline for LayeredTimeline {
             "wait_lsn called by WAL receiver thread"
         );
 
-        self.wait_lsn_time_histo.observe_closure_duration(
-            || self.last_record_lsn
-                .wait_for_timeout(lsn, self.conf.wait_lsn_timeout)
-                .with_context(|| {
-                    format!(
-                        "Timed out while waiting for WAL record at LSN {} to arrive, last_record_lsn {} disk consistent LSN={}",
-                        lsn, self.get_last_record_lsn(), self.get_disk_consistent_lsn()
-                    )
-                }))?;
+        self.wait_lsn_waiters_gauge.inc();
+        scopeguard::defer! {
+            self.wait_lsn_waiters_gauge.dec();
+        }
+
+        self.wait_lsn_time_histo.observe_closure_duration(|| {
+            match self.last_record_lsn.wait_for_timeout_cancellable(
+                lsn,
+                self.conf.wait_lsn_timeout,
+                cancel,
+            ) {
+                Ok(()) => Ok(()),
+                Err(SeqWaitError::Cancelled) => {
+                    self.wait_lsn_cancelled_counter.inc();
+                    Err(anyhow!(
+                        "Wait for WAL record at LSN {} to arrive was cancelled, last_record_lsn {}",
+                        lsn,
+                        self.get_last_record_lsn()
+                    ))
+                }
+                Err(e) => Err(e).with_context(|| ReadError::AheadOfLastRecord {
+                    tenant_id: self.tenant_id,
+                    timeline_id: self.timeline_id,
+                    lsn,
+                    last_record_lsn: self.get_last_record_lsn(),
+                    disk_consistent_lsn: self.get_disk_consistent_lsn(),
+                }),
+            }
+        })?;
 
         Ok(())
     }
@@ -1051,14 +1700,27 @@ impl Timeline for LayeredTimeline {
     /// Look up the value with the given a key
     fn get(&self, key: Key, lsn: Lsn) -> Result<Bytes> {
         debug_assert!(lsn <= self.get_last_record_lsn());
+        ensure!(
+            !self.is_stopping(),
+            "timeline {} is being detached",
+            self.timeline_id
+        );
+
+        if let Some(img) = self.hot_page_cache.lookup(&key, lsn) {
+            return Ok(img);
+        }
 
         // Check the page cache. We will get back the most recent page with lsn <= `lsn`.
         // The cached image can be returned directly if there is no WAL between the cached image
-        // and requested LSN. The cached image can also be used to reduce the amount of WAL needed
+        // and requested LSN: it's known for the LSNs up to `valid_until`, otherwise we need to
+        // check the layers. The cached image can also be used to reduce the amount of WAL needed
         // for redo.
-        let cached_page_img = match self.lookup_cached_page(&key, lsn) {
-            Some((cached_lsn, cached_img)) => {
+        let cached_page_img = match debug_span!("page_cache_lookup")
+            .in_scope(|| self.lookup_cached_page(&key, lsn))
+        {
+            Some((cached_lsn, valid_until, cached_img)) => {
                 match cached_lsn.cmp(&lsn) {
+                    Ordering::Less if lsn <= valid_until => return Ok(cached_img), // no WAL in between
                     Ordering::Less => {} // there might be WAL between cached_lsn and lsn, we need to check
                     Ordering::Equal => return Ok(cached_img), // exact LSN match, return the image
                     Ordering::Greater => panic!(), // the returned lsn should never be after the requested lsn
@@ -1067,23 +1729,107 @@ impl Timeline for LayeredTimeline {
             }
             None => None,
         };
+        let cached_lsn = cached_page_img.as_ref().map(|(cached_lsn, _)| *cached_lsn);
 
         let mut reconstruct_state = ValueReconstructState {
             records: Vec::new(),
             img: cached_page_img,
         };
 
-        self.get_reconstruct_data(key, lsn, &mut reconstruct_state)?;
+        debug_span!("layer_lookup")
+            .in_scope(|| self.get_reconstruct_data(key, lsn, &mut reconstruct_state, None))?;
+
+        // No WAL on top of the cached image: remember that it's valid up to `lsn`,
+        // so the next reads in between don't have to check the layers again
+        if let Some(cached_lsn) = cached_lsn {
+            let img_lsn = reconstruct_state.img.as_ref().map(|(img_lsn, _)| *img_lsn);
+            if reconstruct_state.records.is_empty() && img_lsn == Some(cached_lsn) {
+                page_cache::get().extend_materialized_page_validity(
+                    self.tenant_id,
+                    self.timeline_id,
+                    key,
+                    cached_lsn,
+                    lsn,
+                );
+            }
+        }
 
-        self.reconstruct_time_histo
-            .observe_closure_duration(|| self.reconstruct_value(key, lsn, reconstruct_state))
+        let img = self
+            .reconstruct_time_histo
+            .observe_closure_duration(|| self.reconstruct_value(key, lsn, reconstruct_state))?;
+        self.hot_page_cache.memorize(key, lsn, &img);
+        Ok(img)
     }
 
-    /// Public entry point for checkpoint(). All the logic is in the private
-    /// checkpoint_internal function, this public facade just wraps it for
-    /// metrics collection.
-    fn checkpoint(&self, cconf: CheckpointConfig) -> anyhow::Result<()> {
-        match cconf {
+    fn get_range(&self, key_range: Range<Key>, lsn: Lsn) -> Result<Vec<(Key, Bytes)>> {
+        debug_assert!(lsn <= self.get_last_record_lsn());
+        ensure!(
+            !self.is_stopping(),
+            "timeline {} is being detached",
+            self.timeline_id
+        );
+
+        // Collect the data to reconstruct all the values first, with the layer
+        // maps of this timeline and its ancestors locked, so that the layers
+        // can't be replaced by compaction or GC halfway through. The locks are
+        // taken from the child to the ancestors, in the same order as the
+        // lookups of single keys go. WAL redo happens after they're released.
+        let mut reconstruct_states = Vec::new();
+        {
+            let ancestors = self.get_ancestor_timelines()?;
+            let layer_maps = std::iter::once(self)
+                .chain(ancestors.iter().map(Arc::as_ref))
+                .map(|timeline| (timeline.timeline_id, timeline.layers.read().unwrap()))
+                .collect::<Vec<_>>();
+            let range_layers = layer_maps
+                .iter()
+                .map(|(timeline_id, layers)| (*timeline_id, layers.subset(&key_range)))
+                .collect::<HashMap<_, _>>();
+
+            let mut key = key_range.start;
+            while key < key_range.end {
+                let mut reconstruct_state = ValueReconstructState {
+                    records: Vec::new(),
+                    img: None,
+                };
+                match self.lookup_cached_page(&key, lsn) {
+                    Some((cached_lsn, valid_until, cached_img))
+                        if cached_lsn == lsn || lsn <= valid_until =>
+                    {
+                        reconstruct_state.img = Some((cached_lsn, cached_img));
+                    }
+                    cached_page_img => {
+                        reconstruct_state.img =
+                            cached_page_img.map(|(cached_lsn, _, img)| (cached_lsn, img));
+                        self.get_reconstruct_data(
+                            key,
+                            lsn,
+                            &mut reconstruct_state,
+                            Some(&range_layers),
+                        )?;
+                    }
+                }
+                reconstruct_states.push((key, reconstruct_state));
+                key = key.next();
+            }
+        }
+
+        reconstruct_states
+            .into_iter()
+            .map(|(key, reconstruct_state)| {
+                let img = self.reconstruct_time_histo.observe_closure_duration(|| {
+                    self.reconstruct_value(key, lsn, reconstruct_state)
+                })?;
+                Ok((key, img))
+            })
+            .collect()
+    }
+
+    /// Public entry point for checkpoint(). All the logic is in the private
+    /// checkpoint_internal function, this public facade just wraps it for
+    /// metrics collection.
+    fn checkpoint(&self, cconf: CheckpointConfig) -> anyhow::Result<()> {
+        match cconf {
             CheckpointConfig::Flush => {
                 self.freeze_inmem_layer(false);
                 self.flush_frozen_layers(true)
@@ -1104,15 +1850,39 @@ impl Timeline for LayeredTimeline {
         lsn: Lsn,
         latest_gc_cutoff_lsn: &RwLockReadGuard<Lsn>,
     ) -> Result<()> {
-        ensure!(
-            lsn >= **latest_gc_cutoff_lsn,
-            "LSN {} is earlier than latest GC horizon {} (we might've already garbage collected needed data)",
-            lsn,
-            **latest_gc_cutoff_lsn,
-        );
+        if lsn < **latest_gc_cutoff_lsn {
+            return Err(ReadError::GcCutoffViolation {
+                tenant_id: self.tenant_id,
+                timeline_id: self.timeline_id,
+                lsn,
+                gc_cutoff_lsn: **latest_gc_cutoff_lsn,
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    fn check_read_floor(&self, lsn: Lsn) -> Result<()> {
+        let read_floor_lsn = self.read_floor_lsn.load();
+        if lsn < read_floor_lsn {
+            return Err(ReadError::BelowReadFloor {
+                tenant_id: self.tenant_id,
+                timeline_id: self.timeline_id,
+                lsn,
+                read_floor_lsn,
+            }
+            .into());
+        }
         Ok(())
     }
 
+    fn get_logical_size_soft_limit(&self) -> Option<u64> {
+        let tenant_conf = self.tenant_conf.read().unwrap();
+        tenant_conf
+            .logical_size_soft_limit
+            .or(self.conf.default_tenant_conf.logical_size_soft_limit)
+    }
+
     fn get_last_record_lsn(&self) -> Lsn {
         self.last_record_lsn.load().last
     }
@@ -1138,6 +1908,32 @@ impl Timeline for LayeredTimeline {
 }
 
 impl LayeredTimeline {
+    ///
+    /// First step of detaching the timeline: refuse new reads, compaction and
+    /// GC, and wake up everyone waiting for WAL to arrive, with an error.
+    /// The requests that are being served at the moment are not interrupted,
+    /// the caller has to wait for them before calling [`Self::close_layers`].
+    ///
+    pub fn shutdown(&self) {
+        self.stopping.store(true, atomic::Ordering::Relaxed);
+        self.last_record_lsn.shutdown();
+    }
+
+    pub fn is_stopping(&self) -> bool {
+        self.stopping.load(atomic::Ordering::Relaxed)
+    }
+
+    ///
+    /// Last step before the files of a detached timeline are removed: wait for
+    /// a compaction or GC that is already running, and close the layer files.
+    ///
+    pub fn close_layers(&self) {
+        assert!(self.is_stopping(), "closing layers of a running timeline");
+        let _compaction_cs = self.compaction_cs.lock().unwrap();
+        let _layer_flush_lock = self.layer_flush_lock.lock().unwrap();
+        *self.layers.write().unwrap() = LayerMap::default();
+    }
+
     fn get_checkpoint_distance(&self) -> u64 {
         let tenant_conf = self.tenant_conf.read().unwrap();
         tenant_conf
@@ -1159,6 +1955,13 @@ impl LayeredTimeline {
             .unwrap_or(self.conf.default_tenant_conf.compaction_threshold)
     }
 
+    fn get_gc_horizon(&self) -> u64 {
+        let tenant_conf = self.tenant_conf.read().unwrap();
+        tenant_conf
+            .gc_horizon
+            .unwrap_or(self.conf.default_tenant_conf.gc_horizon)
+    }
+
     fn get_image_creation_threshold(&self) -> usize {
         let tenant_conf = self.tenant_conf.read().unwrap();
         tenant_conf
@@ -1166,6 +1969,13 @@ impl LayeredTimeline {
             .unwrap_or(self.conf.default_tenant_conf.image_creation_threshold)
     }
 
+    fn get_image_layer_excluded_forks(&self) -> ForkSet {
+        let tenant_conf = self.tenant_conf.read().unwrap();
+        tenant_conf
+            .image_layer_excluded_forks
+            .unwrap_or(self.conf.default_tenant_conf.image_layer_excluded_forks)
+    }
+
     /// Open a Timeline handle.
     ///
     /// Loads the metadata for the timeline into memory, but not the layer map.
@@ -1173,50 +1983,61 @@ impl LayeredTimeline {
     fn new(
         conf: &'static PageServerConf,
         tenant_conf: Arc<RwLock<TenantConfOpt>>,
+        append_only_relations: Arc<RwLock<AppendOnlyRelations>>,
         metadata: TimelineMetadata,
         ancestor: Option<LayeredTimelineEntry>,
         timeline_id: ZTimelineId,
         tenant_id: ZTenantId,
         walredo_mgr: Arc<dyn WalRedoManager + Send + Sync>,
         upload_layers: bool,
+        clock: Arc<dyn Clock>,
+        layer_strategy: Arc<dyn LayerStrategy>,
     ) -> LayeredTimeline {
+        let (tenant_label, timeline_label) =
+            metric_labels::timeline_labels(&tenant_id, &timeline_id);
+        let labels = [tenant_label.as_str(), timeline_label.as_str()];
         let reconstruct_time_histo = RECONSTRUCT_TIME
-            .get_metric_with_label_values(&[&tenant_id.to_string(), &timeline_id.to_string()])
+            .get_metric_with_label_values(&labels)
             .unwrap();
         let materialized_page_cache_hit_counter = MATERIALIZED_PAGE_CACHE_HIT
-            .get_metric_with_label_values(&[&tenant_id.to_string(), &timeline_id.to_string()])
+            .get_metric_with_label_values(&labels)
             .unwrap();
         let flush_time_histo = STORAGE_TIME
-            .get_metric_with_label_values(&[
-                "layer flush",
-                &tenant_id.to_string(),
-                &timeline_id.to_string(),
-            ])
+            .get_metric_with_label_values(&["layer flush", &tenant_label, &timeline_label])
             .unwrap();
         let compact_time_histo = STORAGE_TIME
-            .get_metric_with_label_values(&[
-                "compact",
-                &tenant_id.to_string(),
-                &timeline_id.to_string(),
-            ])
+            .get_metric_with_label_values(&["compact", &tenant_label, &timeline_label])
             .unwrap();
         let create_images_time_histo = STORAGE_TIME
-            .get_metric_with_label_values(&[
-                "create images",
-                &tenant_id.to_string(),
-                &timeline_id.to_string(),
-            ])
+            .get_metric_with_label_values(&["create images", &tenant_label, &timeline_label])
+            .unwrap();
+        // The LSN of one timeline can't be summed up with the others
+        let last_record_gauge = metric_labels::own_timeline_labels(&tenant_id, &timeline_id).map(
+            |(tenant_id, timeline_id)| {
+                LAST_RECORD_LSN
+                    .get_metric_with_label_values(&[&tenant_id, &timeline_id])
+                    .unwrap()
+            },
+        );
+        let wait_lsn_time_histo = WAIT_LSN_TIME.get_metric_with_label_values(&labels).unwrap();
+        let wait_lsn_waiters_gauge = WAIT_LSN_WAITERS
+            .get_metric_with_label_values(&labels)
             .unwrap();
-        let last_record_gauge = LAST_RECORD_LSN
-            .get_metric_with_label_values(&[&tenant_id.to_string(), &timeline_id.to_string()])
+        let wait_lsn_cancelled_counter = WAIT_LSN_CANCELLED
+            .get_metric_with_label_values(&labels)
             .unwrap();
-        let wait_lsn_time_histo = WAIT_LSN_TIME
-            .get_metric_with_label_values(&[&tenant_id.to_string(), &timeline_id.to_string()])
+        let ancestor_hops_histo = READ_ANCESTOR_HOPS
+            .get_metric_with_label_values(&labels)
+            .unwrap();
+        // Deep branches are a property of the tenant, more than of one of its timelines
+        let ancestor_time_histo = READ_ANCESTOR_TIME
+            .get_metric_with_label_values(&[&tenant_label])
             .unwrap();
 
         LayeredTimeline {
             conf,
             tenant_conf,
+            append_only_relations,
             timeline_id,
             tenant_id,
             layers: RwLock::new(LayerMap::default()),
@@ -1231,6 +2052,11 @@ impl LayeredTimeline {
             disk_consistent_lsn: AtomicLsn::new(metadata.disk_consistent_lsn().0),
 
             last_freeze_at: AtomicLsn::new(metadata.disk_consistent_lsn().0),
+            last_freeze_time: Mutex::new(clock.instant()),
+            flush_pacer: FlushPacer::new(
+                conf.layer_flush_pacing_percent,
+                conf.layer_flush_sync_interval,
+            ),
 
             ancestor_timeline: ancestor,
             ancestor_lsn: metadata.ancestor_lsn(),
@@ -1242,8 +2068,17 @@ impl LayeredTimeline {
             create_images_time_histo,
             last_record_gauge,
             wait_lsn_time_histo,
+            wait_lsn_waiters_gauge,
+            wait_lsn_cancelled_counter,
+            ancestor_hops_histo,
+            ancestor_time_histo,
 
             upload_layers: AtomicBool::new(upload_layers),
+            compaction_needed: AtomicBool::new(true),
+            image_creation_needed: AtomicBool::new(true),
+            flattening_needed: AtomicBool::new(false),
+            has_branch_point_images: AtomicBool::new(false),
+            stopping: AtomicBool::new(false),
 
             write_lock: Mutex::new(()),
             layer_flush_lock: Mutex::new(()),
@@ -1253,9 +2088,17 @@ impl LayeredTimeline {
                 retain_lsns: Vec::new(),
                 cutoff: Lsn(0),
                 pitr: Duration::ZERO,
+                uploaded_layers: None,
             }),
+            last_gc_space_report: Mutex::new(None),
+            last_gc_estimate: Mutex::new(None),
+            hot_page_cache: HotPageCache::new(conf.hot_page_cache_size),
+            standby_horizon: StandbyHorizon::new(conf.standby_horizon_lease),
+            clock,
+            layer_strategy,
 
             latest_gc_cutoff_lsn: RwLock::new(metadata.latest_gc_cutoff_lsn()),
+            read_floor_lsn: AtomicLsn::new(0),
             initdb_lsn: metadata.initdb_lsn(),
         }
     }
@@ -1272,7 +2115,10 @@ impl LayeredTimeline {
         // structs representing all files on disk
         let timeline_path = self.conf.timeline_path(&self.timeline_id, &self.tenant_id);
 
-        for direntry in fs::read_dir(timeline_path)? {
+        // Finish deleting the layers that GC or compaction removed before a restart
+        layer_deletion::complete_interrupted_deletions(&timeline_path)?;
+
+        for direntry in fs::read_dir(&timeline_path)? {
             let direntry = direntry?;
             let fname = direntry.file_name();
             let fname = fname.to_string_lossy();
@@ -1285,10 +2131,20 @@ impl LayeredTimeline {
                         imgfilename, self.timeline_id, disk_consistent_lsn
                     );
 
-                    rename_to_backup(direntry.path())?;
+                    set_aside::set_aside_layer(
+                        &timeline_path,
+                        &direntry.path(),
+                        "image layer beyond disk_consistent_lsn",
+                        disk_consistent_lsn,
+                    )?;
                     continue;
                 }
 
+                if self.ancestor_timeline.is_some() && imgfilename.lsn == self.ancestor_lsn {
+                    self.has_branch_point_images
+                        .store(true, atomic::Ordering::Relaxed);
+                }
+
                 let layer =
                     ImageLayer::new(self.conf, self.timeline_id, self.tenant_id, &imgfilename);
 
@@ -1308,7 +2164,12 @@ impl LayeredTimeline {
                         deltafilename, self.timeline_id, disk_consistent_lsn
                     );
 
-                    rename_to_backup(direntry.path())?;
+                    set_aside::set_aside_layer(
+                        &timeline_path,
+                        &direntry.path(),
+                        "delta layer beyond disk_consistent_lsn",
+                        disk_consistent_lsn,
+                    )?;
                     continue;
                 }
 
@@ -1318,7 +2179,12 @@ impl LayeredTimeline {
                 trace!("found layer {}", layer.filename().display());
                 layers.insert_historic(Arc::new(layer));
                 num_layers += 1;
-            } else if fname == METADATA_FILE_NAME || fname.ends_with(".old") {
+            } else if fname == METADATA_FILE_NAME
+                || fname == set_aside::SET_ASIDE_DIR_NAME
+                || fname.ends_with(".old")
+                || fname.ends_with(&format!(".{UPLOAD_STATE_EXTENSION}"))
+                || fname.ends_with(&format!(".{}", layer_deletion::TOMBSTONE_EXTENSION))
+            {
                 // ignore these
             } else if is_ephemeral_file(&fname) {
                 // Delete any old ephemeral files
@@ -1345,13 +2211,58 @@ impl LayeredTimeline {
     /// The returned Layer might be from an ancestor timeline, if the
     /// segment hasn't been updated on this timeline yet.
     ///
-    /// This function takes the current timeline's locked LayerMap as an argument,
-    /// so callers can avoid potential race conditions.
+    /// The layer maps of the timelines are locked for each layer lookup,
+    /// unless the caller already holds them and passes the layers to search
+    /// in 'range_layers', by timeline.
     fn get_reconstruct_data(
         &self,
         key: Key,
         request_lsn: Lsn,
         reconstruct_state: &mut ValueReconstructState,
+        range_layers: Option<&HashMap<ZTimelineId, LayerMap>>,
+    ) -> anyhow::Result<()> {
+        let mut traversal = AncestorTraversal::default();
+        let result = self.traverse_layers(
+            key,
+            request_lsn,
+            reconstruct_state,
+            range_layers,
+            &mut traversal,
+        );
+        if let Some(started_at) = traversal.started_at {
+            self.ancestor_time_histo
+                .observe(started_at.elapsed().as_secs_f64());
+        }
+        self.record_ancestor_hops(traversal.hops);
+        result
+    }
+
+    /// Report the number of ancestor timelines a read went through, and have
+    /// the branch point materialized if there were too many.
+    fn record_ancestor_hops(&self, ancestor_hops: usize) {
+        self.ancestor_hops_histo.observe(ancestor_hops as f64);
+
+        let max_ancestor_hops = self.conf.max_ancestor_hops;
+        if max_ancestor_hops > 0
+            && ancestor_hops > max_ancestor_hops
+            && !self.flattening_needed.swap(true, atomic::Ordering::Relaxed)
+        {
+            warn!(
+                "read on timeline {} went through {} ancestor timelines, more than max_ancestor_hops {}, scheduling materialization of its branch point",
+                self.timeline_id, ancestor_hops, max_ancestor_hops
+            );
+        }
+    }
+
+    /// The part of [`Self::get_reconstruct_data`] that looks through the
+    /// layers, recording the ancestor timelines it goes into in 'traversal'.
+    fn traverse_layers(
+        &self,
+        key: Key,
+        request_lsn: Lsn,
+        reconstruct_state: &mut ValueReconstructState,
+        range_layers: Option<&HashMap<ZTimelineId, LayerMap>>,
+        traversal: &mut AncestorTraversal,
     ) -> anyhow::Result<()> {
         // Start from the current timeline.
         let mut timeline_owned;
@@ -1389,7 +2300,7 @@ impl LayeredTimeline {
                     if prev_lsn <= cont_lsn {
                         // Didn't make any progress in last iteration. Error out to avoid
                         // getting stuck in the loop.
-                        return layer_traversal_error(format!(
+                        return layer_traversal_error(self.tenant_id, timeline.timeline_id, format!(
                             "could not find layer with more data for key {} at LSN {}, request LSN {}, ancestor {}",
                             key,
                             Lsn(cont_lsn.0 - 1),
@@ -1400,7 +2311,24 @@ impl LayeredTimeline {
                     prev_lsn = cont_lsn;
                 }
                 ValueReconstructResult::Missing => {
+                    // The FSM and VM pages may have been left out of the image layer,
+                    // with the 'image_layer_excluded_forks' tenant setting. PostgreSQL
+                    // rebuilds them from zero pages, so use one as the base image. This
+                    // doesn't depend on the current setting, the layer may have been
+                    // created with a different one.
+                    if let Some((_, _, layer)) = traversal_path.last() {
+                        if !layer.is_incremental()
+                            && ForkSet::REBUILDABLE.contains(key.field5)
+                            && is_rel_block_key(&key)
+                        {
+                            reconstruct_state.img =
+                                Some((layer.get_lsn_range().start, ZERO_PAGE.clone()));
+                            return Ok(());
+                        }
+                    }
                     return layer_traversal_error(
+                        self.tenant_id,
+                        timeline.timeline_id,
                         format!(
                             "could not find data for key {} at LSN {}, for request at LSN {}",
                             key, cont_lsn, request_lsn
@@ -1410,8 +2338,12 @@ impl LayeredTimeline {
                 }
             }
 
-            // Recurse into ancestor if needed
-            if Lsn(cont_lsn.0 - 1) <= timeline.ancestor_lsn {
+            // Recurse into ancestor if needed. At the branch point itself, the
+            // timeline may have an image of its own, see flatten_branch_point.
+            if Lsn(cont_lsn.0 - 1) <= timeline.ancestor_lsn
+                && !(Lsn(cont_lsn.0 - 1) == timeline.ancestor_lsn
+                    && timeline.has_branch_point_image(key, range_layers)?)
+            {
                 trace!(
                     "going into ancestor {}, cont_lsn is {}",
                     timeline.ancestor_lsn,
@@ -1421,10 +2353,19 @@ impl LayeredTimeline {
                 timeline_owned = ancestor;
                 timeline = &*timeline_owned;
                 prev_lsn = Lsn(u64::MAX);
+                traversal.hops += 1;
+                traversal.started_at.get_or_insert_with(Instant::now);
                 continue;
             }
 
-            let layers = timeline.layers.read().unwrap();
+            let layers_guard;
+            let layers = match range_layers.and_then(|maps| maps.get(&timeline.timeline_id)) {
+                Some(layers) => layers,
+                None => {
+                    layers_guard = timeline.layers.read().unwrap();
+                    &*layers_guard
+                }
+            };
 
             // Check the open and frozen in-memory layers first, in order from newest
             // to oldest.
@@ -1483,15 +2424,55 @@ impl LayeredTimeline {
         }
     }
 
-    fn lookup_cached_page(&self, key: &Key, lsn: Lsn) -> Option<(Lsn, Bytes)> {
+    /// Whether the timeline has an image layer of its own with the key at the
+    /// branch point.
+    fn has_branch_point_image(
+        &self,
+        key: Key,
+        range_layers: Option<&HashMap<ZTimelineId, LayerMap>>,
+    ) -> Result<bool> {
+        if !self.has_branch_point_images.load(atomic::Ordering::Relaxed) {
+            return Ok(false);
+        }
+        let layers_guard;
+        let layers = match range_layers.and_then(|maps| maps.get(&self.timeline_id)) {
+            Some(layers) => layers,
+            None => {
+                layers_guard = self.layers.read().unwrap();
+                &*layers_guard
+            }
+        };
+        Ok(matches!(
+            layers.search(key, self.ancestor_lsn + 1)?,
+            Some(SearchResult { layer, .. })
+                if !layer.is_incremental() && layer.get_lsn_range().start == self.ancestor_lsn
+        ))
+    }
+
+    /// Returns the LSN of the cached page version, the LSN up to which it stays the same,
+    /// and the page image.
+    fn lookup_cached_page(&self, key: &Key, lsn: Lsn) -> Option<(Lsn, Lsn, Bytes)> {
         let cache = page_cache::get();
 
         // FIXME: It's pointless to check the cache for things that are not 8kB pages.
         // We should look at the key to determine if it's a cacheable object
-        let (lsn, read_guard) =
+        let (lsn, valid_until, read_guard) =
             cache.lookup_materialized_page(self.tenant_id, self.timeline_id, key, lsn)?;
         let img = Bytes::from(read_guard.to_vec());
-        Some((lsn, img))
+        Some((lsn, valid_until, img))
+    }
+
+    /// All the ancestors of the timeline, from the parent to the root.
+    fn get_ancestor_timelines(&self) -> Result<Vec<Arc<LayeredTimeline>>> {
+        let mut ancestors: Vec<Arc<LayeredTimeline>> = Vec::new();
+        loop {
+            let timeline = ancestors.last().map_or(self, Arc::as_ref);
+            if timeline.ancestor_timeline.is_none() {
+                return Ok(ancestors);
+            }
+            let ancestor = timeline.get_ancestor_timeline()?;
+            ancestors.push(ancestor);
+        }
     }
 
     fn get_ancestor_timeline(&self) -> Result<Arc<LayeredTimeline>> {
@@ -1564,12 +2545,14 @@ impl LayeredTimeline {
 
     fn put_value(&self, key: Key, lsn: Lsn, val: Value) -> Result<()> {
         //info!("PUT: key {} at {}", key, lsn);
+        self.hot_page_cache.invalidate(&key, lsn);
         let layer = self.get_layer_for_write(lsn)?;
         layer.put_value(key, lsn, val)?;
         Ok(())
     }
 
     fn put_tombstone(&self, key_range: Range<Key>, lsn: Lsn) -> Result<()> {
+        self.hot_page_cache.invalidate_range(&key_range, lsn);
         let layer = self.get_layer_for_write(lsn)?;
         layer.put_tombstone(key_range, lsn)?;
 
@@ -1579,8 +2562,12 @@ impl LayeredTimeline {
     fn finish_write(&self, new_lsn: Lsn) {
         assert!(new_lsn.is_aligned());
 
-        self.last_record_gauge.set(new_lsn.0 as i64);
+        if let Some(last_record_gauge) = &self.last_record_gauge {
+            last_record_gauge.set(new_lsn.0 as i64);
+        }
         self.last_record_lsn.advance(new_lsn);
+        self.compaction_needed
+            .store(true, atomic::Ordering::Relaxed);
     }
 
     fn freeze_inmem_layer(&self, write_lock_held: bool) {
@@ -1598,6 +2585,15 @@ impl LayeredTimeline {
             let end_lsn = Lsn(self.get_last_record_lsn().0 + 1);
             open_layer.freeze(end_lsn);
 
+            let now = self.clock.instant();
+            let mut last_freeze_time = self.last_freeze_time.lock().unwrap();
+            self.flush_pacer.observe_ingest(
+                end_lsn.widening_sub(self.last_freeze_at.load()).max(0) as u64,
+                now.duration_since(*last_freeze_time),
+            );
+            *last_freeze_time = now;
+            drop(last_freeze_time);
+
             // The layer is no longer open, update the layer map to reflect this.
             // We will replace it with on-disk historics below.
             layers.frozen_layers.push_back(open_layer_rc);
@@ -1610,14 +2606,24 @@ impl LayeredTimeline {
 
     ///
     /// Check if more than 'checkpoint_distance' of WAL has been accumulated
-    /// in the in-memory layer, and initiate flushing it if so.
+    /// in the in-memory layer, or its ephemeral file has grown beyond
+    /// 'max_open_layer_size', and initiate flushing it if so.
     ///
     pub fn check_checkpoint_distance(self: &Arc<LayeredTimeline>) -> Result<()> {
         let last_lsn = self.get_last_record_lsn();
 
         // Has more than 'checkpoint_distance' of WAL been accumulated?
         let distance = last_lsn.widening_sub(self.last_freeze_at.load());
-        if distance >= self.get_checkpoint_distance().into() {
+        let max_open_layer_size = self.conf.max_open_layer_size;
+        let open_layer_too_big =
+            max_open_layer_size > 0 && self.open_layer_size(last_lsn) >= max_open_layer_size;
+        if distance >= self.get_checkpoint_distance().into() || open_layer_too_big {
+            if open_layer_too_big {
+                debug!(
+                    "open layer reached max_open_layer_size {} at {}, {} bytes of WAL since the last freeze",
+                    max_open_layer_size, last_lsn, distance
+                );
+            }
             // Yes. Freeze the current in-memory layer.
             self.freeze_inmem_layer(true);
             self.last_freeze_at.store(last_lsn);
@@ -1643,6 +2649,15 @@ impl LayeredTimeline {
         Ok(())
     }
 
+    /// Size of the open in-memory layer, if it has any complete records to freeze.
+    fn open_layer_size(&self, last_lsn: Lsn) -> u64 {
+        let layers = self.layers.read().unwrap();
+        match &layers.open_layer {
+            Some(open_layer) if open_layer.get_lsn_range().start <= last_lsn => open_layer.size(),
+            _ => 0,
+        }
+    }
+
     /// Flush all frozen layers to disk.
     ///
     /// Only one thread at a time can be doing layer-flushing for a
@@ -1665,10 +2680,15 @@ impl LayeredTimeline {
 
         loop {
             let layers = self.layers.read().unwrap();
-            if let Some(frozen_layer) = layers.frozen_layers.front() {
-                let frozen_layer = Arc::clone(frozen_layer);
+            if !layers.frozen_layers.is_empty() {
+                // Flush all the layers frozen by now as one batch
+                let frozen_layers = layers.frozen_layers.iter().cloned().collect::<Vec<_>>();
                 drop(layers); // to allow concurrent reads and writes
-                self.flush_frozen_layer(frozen_layer)?;
+                if let Err(e) = self.flush_frozen_layer_batch(&frozen_layers) {
+                    tenant_mgr::report_layer_write_error(self.tenant_id, &e);
+                    return Err(e);
+                }
+                tenant_mgr::report_layer_write_success(self.tenant_id);
             } else {
                 // Drop the 'layer_flush_lock' *before* 'layers'. That
                 // way, if you freeze a layer, and then call
@@ -1688,48 +2708,67 @@ impl LayeredTimeline {
         Ok(())
     }
 
-    /// Flush one frozen in-memory layer to disk, as a new delta layer.
-    fn flush_frozen_layer(&self, frozen_layer: Arc<InMemoryLayer>) -> Result<()> {
-        let new_delta = frozen_layer.write_to_disk()?;
-        let new_delta_path = new_delta.path();
+    ///
+    /// Flush a batch of frozen in-memory layers to disk, as new delta layers.
+    /// 'frozen_layers' are the oldest frozen layers, in order.
+    ///
+    /// The new layer files are synced together, and the metadata is saved once,
+    /// so that the timeline directory and the metadata file get one fsync per
+    /// batch rather than one per layer.
+    ///
+    fn flush_frozen_layer_batch(&self, frozen_layers: &[Arc<InMemoryLayer>]) -> Result<()> {
+        let mut new_deltas = Vec::with_capacity(frozen_layers.len());
+        for (i, frozen_layer) in frozen_layers.iter().enumerate() {
+            // Pace the flush, unless more layers are waiting behind it
+            let mut pacing = self.flush_pacer.start_flush(frozen_layers.len() - 1 - i);
+            let new_delta = frozen_layer.write_to_disk(&mut pacing)?;
+            if !pacing.waited().is_zero() {
+                debug!(
+                    "paced flush of {} waited {:?}",
+                    new_delta.filename().display(),
+                    pacing.waited()
+                );
+            }
+            new_deltas.push(new_delta);
+        }
+        let new_delta_paths = new_deltas.iter().map(|l| l.path()).collect::<Vec<_>>();
 
-        // Sync the new layer to disk.
-        //
-        // We must also fsync the timeline dir to ensure the directory entries for
-        // new layer files are durable
-        //
-        // TODO: If we're running inside 'flush_frozen_layers' and there are multiple
-        // files to flush, it might be better to first write them all, and then fsync
-        // them all in parallel.
-        par_fsync::par_fsync(&[
-            new_delta_path.clone(),
-            self.conf.timeline_path(&self.timeline_id, &self.tenant_id),
-        ])?;
+        // Sync the new layers to disk. Most of them were synced already while
+        // they were written, if layer_flush_sync_interval is set.
+        self.sync_new_layers(&new_delta_paths)?;
         fail_point!("checkpoint-before-sync");
+        fail_point!("layer-flush-write-error", |_| Err(anyhow::Error::new(
+            std::io::Error::from_raw_os_error(nix::errno::Errno::ENOSPC as i32)
+        )
+        .context("failpoint layer-flush-write-error")));
 
         fail_point!("flush-frozen");
 
-        // Finally, replace the frozen in-memory layer with the new on-disk layer
+        // Finally, replace the frozen in-memory layers with the new on-disk layers
         {
             let mut layers = self.layers.write().unwrap();
-            let l = layers.frozen_layers.pop_front();
+            for (frozen_layer, new_delta) in frozen_layers.iter().zip(new_deltas) {
+                let l = layers.frozen_layers.pop_front();
 
-            // Only one thread may call this function at a time (for this
-            // timeline). If two threads tried to flush the same frozen
-            // layer to disk at the same time, that would not work.
-            assert!(Arc::ptr_eq(&l.unwrap(), &frozen_layer));
+                // Only one thread may call this function at a time (for this
+                // timeline). If two threads tried to flush the same frozen
+                // layer to disk at the same time, that would not work.
+                assert!(Arc::ptr_eq(&l.unwrap(), frozen_layer));
 
-            // Add the new delta layer to the LayerMap
-            layers.insert_historic(Arc::new(new_delta));
+                // Add the new delta layer to the LayerMap
+                layers.insert_historic(Arc::new(new_delta));
+            }
 
             // release lock on 'layers'
         }
+        self.compaction_needed
+            .store(true, atomic::Ordering::Relaxed);
+        self.image_creation_needed
+            .store(true, atomic::Ordering::Relaxed);
 
         // Update the metadata file, with new 'disk_consistent_lsn'
-        //
-        // TODO: This perhaps should be done in 'flush_frozen_layers', after flushing
-        // *all* the layers, to avoid fsyncing the file multiple times.
-        let disk_consistent_lsn = Lsn(frozen_layer.get_lsn_range().end.0 - 1);
+        let last_frozen_layer = frozen_layers.last().expect("empty flush batch");
+        let disk_consistent_lsn = Lsn(last_frozen_layer.get_lsn_range().end.0 - 1);
         fail_point!("checkpoint-after-sync");
 
         // If we were able to advance 'disk_consistent_lsn', save it the metadata file.
@@ -1781,28 +2820,109 @@ impl LayeredTimeline {
                 false,
             )?;
 
-            NUM_PERSISTENT_FILES_CREATED.inc_by(1);
-            PERSISTENT_BYTES_WRITTEN.inc_by(new_delta_path.metadata()?.len());
+            NUM_PERSISTENT_FILES_CREATED.inc_by(new_delta_paths.len() as u64);
+            for path in &new_delta_paths {
+                PERSISTENT_BYTES_WRITTEN.inc_by(path.metadata()?.len());
+            }
 
             if self.upload_layers.load(atomic::Ordering::Relaxed) {
                 storage_sync::schedule_layer_upload(
                     self.tenant_id,
                     self.timeline_id,
-                    HashSet::from([new_delta_path]),
+                    new_delta_paths.into_iter().collect(),
                     Some(metadata),
                 );
             }
 
             // Also update the in-memory copy
             self.disk_consistent_lsn.store(disk_consistent_lsn);
+
+            self.update_gc_estimate();
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// Make new layer files durable, along with their directory entries: fsync
+    /// the files and the timeline directory using multiple threads to minimize
+    /// latency, or with `use_syncfs`, sync the whole filesystem in a syncfs
+    /// shared with the other timelines syncing at the same time.
+    ///
+    fn sync_new_layers(&self, paths: &[PathBuf]) -> Result<()> {
+        let timeline_path = self.conf.timeline_path(&self.timeline_id, &self.tenant_id);
+        if self.conf.use_syncfs {
+            par_fsync::syncfs(&timeline_path)
+                .with_context(|| format!("Failed to syncfs '{}'", timeline_path.display()))?;
+        } else {
+            let mut paths = paths.to_vec();
+            paths.push(timeline_path);
+            par_fsync::par_fsync(&paths)?;
+        }
+        Ok(())
+    }
+
+    ///
+    /// Record the feedback of a read replica that still reads at 'lsn'. GC
+    /// keeps the versions needed at 'lsn' for `standby_horizon_lease`.
+    ///
+    pub fn report_standby_feedback(&self, lsn: Lsn) {
+        self.standby_horizon.report(self.clock.instant(), lsn);
+    }
+
+    pub fn get_read_floor_lsn(&self) -> Option<Lsn> {
+        let lsn = self.read_floor_lsn.load();
+        if lsn == Lsn(0) {
+            None
+        } else {
+            Some(lsn)
         }
+    }
 
+    ///
+    /// Reject the reads below 'lsn', or none if it's `None`. The control plane
+    /// sets it to the fence LSN when it restarts a compute, so that no page is
+    /// served at an LSN older than the one the new compute starts from. It's
+    /// not persisted: the control plane sets it again after a pageserver restart.
+    ///
+    pub fn set_read_floor_lsn(&self, lsn: Option<Lsn>) -> Result<()> {
+        let lsn = lsn.unwrap_or(Lsn(0));
+        let last_record_lsn = self.get_last_record_lsn();
+        ensure!(
+            lsn <= last_record_lsn,
+            "Read floor {lsn} is ahead of the last record LSN {last_record_lsn}"
+        );
+        self.read_floor_lsn.store(lsn);
+        info!("read floor set to {lsn}");
         Ok(())
     }
 
+    ///
+    /// Create image layers where needed, and compact the level 0 delta layers.
+    ///
+    /// The background threads do these separately, see [`Self::create_image_layers`]
+    /// and [`Self::compact_deltas`]. This is for the explicit requests, like a
+    /// forced checkpoint.
+    ///
     pub fn compact(&self) -> Result<()> {
+        self.create_image_layers()?;
+        self.compact_deltas()
+    }
+
+    ///
+    /// Create new image layers for the partitions of the key space that have
+    /// been modified "enough".
+    ///
+    /// This runs in its own background thread, the image builder, so that
+    /// the page reconstruction it needs stays off the path of the layer flushes
+    /// and of the delta compaction. The flushes only write delta layers, and
+    /// the image builder follows up when the number of deltas on top of the
+    /// last image of a partition reaches `image_creation_threshold`, which
+    /// bounds the read amplification.
+    ///
+    pub fn create_image_layers(&self) -> Result<()> {
         //
-        // High level strategy for compaction / image creation:
+        // High level strategy for image creation:
         //
         // 1. First, calculate the desired "partitioning" of the
         // currently in-use key space. The goal is to partition the
@@ -1826,60 +2946,81 @@ impl LayeredTimeline {
         // total in the delta file. Or perhaps: if creating an image
         // file would allow to delete some older files.
         //
-        // 3. After that, we compact all level0 delta files if there
-        // are too many of them.  While compacting, we also garbage
-        // collect any page versions that are no longer needed because
-        // of the new image layers we created in step 2.
-        //
         // TODO: This high level strategy hasn't been implemented yet.
-        // Below are functions compact_level0() and create_image_layers()
-        // but they are a bit ad hoc and don't quite work like it's explained
-        // above. Rewrite it.
-        let _compaction_cs = self.compaction_cs.lock().unwrap();
-
-        let target_file_size = self.get_checkpoint_distance();
+        // Only the number of delta files is looked at.
+        if self.is_stopping() {
+            return Ok(());
+        }
 
         // Define partitioning schema if needed
-        if let Ok(pgdir) =
-            tenant_mgr::get_local_timeline_with_load(self.tenant_id, self.timeline_id)
+        let pgdir = match tenant_mgr::get_local_timeline_with_load(self.tenant_id, self.timeline_id)
         {
-            let (partitioning, lsn) = pgdir.repartition(
-                self.get_last_record_lsn(),
-                self.get_compaction_target_size(),
-            )?;
-            let timer = self.create_images_time_histo.start_timer();
-            // 2. Create new image layers for partitions that have been modified
-            // "enough".
-            let mut layer_paths_to_upload = HashSet::with_capacity(partitioning.parts.len());
-            for part in partitioning.parts.iter() {
-                if self.time_for_new_image_layer(part, lsn)? {
-                    let new_path = self.create_image_layer(part, lsn)?;
-                    layer_paths_to_upload.insert(new_path);
-                }
+            Ok(pgdir) => pgdir,
+            Err(_) => {
+                debug!("Could not create image layers because no partitioning specified yet");
+                return Ok(());
             }
-            if self.upload_layers.load(atomic::Ordering::Relaxed) {
-                storage_sync::schedule_layer_upload(
-                    self.tenant_id,
-                    self.timeline_id,
-                    layer_paths_to_upload,
-                    None,
-                );
+        };
+        let (partitioning, lsn) = pgdir.repartition(
+            self.get_last_record_lsn(),
+            self.get_compaction_target_size(),
+        )?;
+
+        let timer = self.create_images_time_histo.start_timer();
+        let mut layer_paths_to_upload = HashSet::with_capacity(partitioning.parts.len());
+        for part in partitioning.parts.iter() {
+            // Hold the compaction lock for one image layer at a time, so that
+            // the delta compaction and GC don't wait for all of them.
+            let _compaction_cs = self.compaction_cs.lock().unwrap();
+            if self.is_stopping() {
+                break;
             }
-            timer.stop_and_record();
+            if self.time_for_new_image_layer(part, lsn)? {
+                let new_path = self.create_image_layer(part, lsn)?;
+                layer_paths_to_upload.insert(new_path);
+            }
+        }
+        if self.upload_layers.load(atomic::Ordering::Relaxed) && !layer_paths_to_upload.is_empty() {
+            storage_sync::schedule_layer_upload(
+                self.tenant_id,
+                self.timeline_id,
+                layer_paths_to_upload,
+                None,
+            );
+        }
+        timer.stop_and_record();
 
-            // 3. Compact
-            let timer = self.compact_time_histo.start_timer();
-            self.compact_level0(target_file_size)?;
-            timer.stop_and_record();
-        } else {
-            debug!("Could not compact because no partitioning specified yet");
+        Ok(())
+    }
+
+    ///
+    /// Compact all level0 delta files if there are too many of them. While
+    /// compacting, we also garbage collect any page versions that are no
+    /// longer needed because of the image layers created by
+    /// [`Self::create_image_layers`].
+    ///
+    pub fn compact_deltas(&self) -> Result<()> {
+        let _compaction_cs = self.compaction_cs.lock().unwrap();
+        if self.is_stopping() {
+            return Ok(());
         }
 
+        let timer = self.compact_time_histo.start_timer();
+        self.compact_level0(self.get_checkpoint_distance())?;
+        timer.stop_and_record();
+
         Ok(())
     }
 
     // Is it time to create a new image layer for the given partition?
     fn time_for_new_image_layer(&self, partition: &KeySpace, lsn: Lsn) -> Result<bool> {
+        // The append-only relations get new images less often, see the
+        // append_only module.
+        let threshold = if self.append_only_relations.read().unwrap().covers(partition) {
+            self.get_image_creation_threshold() * APPEND_ONLY_IMAGE_THRESHOLD_FACTOR
+        } else {
+            self.get_image_creation_threshold()
+        };
         let layers = self.layers.read().unwrap();
 
         for part_range in &partition.ranges {
@@ -1897,7 +3038,7 @@ impl LayeredTimeline {
                     "range {}-{}, has {} deltas on this timeline",
                     img_range.start, img_range.end, num_deltas
                 );
-                if num_deltas >= self.get_image_creation_threshold() {
+                if num_deltas >= threshold {
                     return Ok(true);
                 }
             }
@@ -1911,10 +3052,20 @@ impl LayeredTimeline {
             partition.ranges.first().unwrap().start..partition.ranges.last().unwrap().end;
         let mut image_layer_writer =
             ImageLayerWriter::new(self.conf, self.timeline_id, self.tenant_id, &img_range, lsn)?;
+        if self.upload_layers.load(atomic::Ordering::Relaxed) {
+            image_layer_writer.stream_upload();
+        }
 
+        // The pages of the excluded forks are left out, reads of them find no
+        // image in the layer and fall back to a zero page, see get_reconstruct_data.
+        let excluded_forks = self.get_image_layer_excluded_forks();
         for range in &partition.ranges {
             let mut key = range.start;
             while key < range.end {
+                if excluded_forks.contains(key.field5) && is_rel_block_key(&key) {
+                    key = key.next();
+                    continue;
+                }
                 let img = self.get(key, lsn)?;
                 image_layer_writer.put_image(key, &img)?;
                 key = key.next();
@@ -1926,15 +3077,9 @@ impl LayeredTimeline {
         // we don't garbage collect something based on the new layer, before it has
         // reached the disk.
         //
-        // We must also fsync the timeline dir to ensure the directory entries for
-        // new layer files are durable
-        //
         // Compaction creates multiple image layers. It would be better to create them all
         // and fsync them all in parallel.
-        par_fsync::par_fsync(&[
-            image_layer.path(),
-            self.conf.timeline_path(&self.timeline_id, &self.tenant_id),
-        ])?;
+        self.sync_new_layers(&[image_layer.path()])?;
 
         // FIXME: Do we need to do something to upload it to remote storage here?
 
@@ -1952,11 +3097,28 @@ impl LayeredTimeline {
     ///
     fn compact_level0(&self, target_file_size: u64) -> Result<()> {
         let layers = self.layers.read().unwrap();
-        let mut level0_deltas = layers.get_level0_deltas()?;
+        let level0_deltas = layers.get_level0_deltas()?;
         drop(layers);
 
+        self.compact_level0_deltas(level0_deltas, target_file_size)
+    }
+
+    ///
+    /// Compact the given Level 0 delta files, found by a scan of the layer map.
+    /// The caller must hold `compaction_cs`, so that none of them is removed
+    /// in the meantime.
+    ///
+    fn compact_level0_deltas(
+        &self,
+        mut level0_deltas: Vec<Arc<dyn Layer>>,
+        target_file_size: u64,
+    ) -> Result<()> {
         // Only compact if enough layers have accumulated.
-        if level0_deltas.is_empty() || level0_deltas.len() < self.get_compaction_threshold() {
+        if level0_deltas.is_empty()
+            || !self
+                .layer_strategy
+                .should_compact_level0(level0_deltas.len(), self.get_compaction_threshold())
+        {
             return Ok(());
         }
 
@@ -2036,6 +3198,10 @@ impl LayeredTimeline {
         //
         // TODO: we should also opportunistically materialize and
         // garbage collect what we can.
+        //
+        // The layers of the append-only relations are allowed to grow larger,
+        // see the append_only module.
+        let append_only_relations = self.append_only_relations.read().unwrap().clone();
         let mut new_layers = Vec::new();
         let mut prev_key: Option<Key> = None;
         let mut writer: Option<DeltaLayerWriter> = None;
@@ -2045,7 +3211,14 @@ impl LayeredTimeline {
             if let Some(prev_key) = prev_key {
                 if key != prev_key && writer.is_some() {
                     let size = writer.as_mut().unwrap().size();
-                    if size > target_file_size {
+                    let size_limit = if append_only_relations.contains_key(&prev_key)
+                        && append_only_relations.contains_key(&key)
+                    {
+                        target_file_size.saturating_mul(APPEND_ONLY_DELTA_SIZE_FACTOR)
+                    } else {
+                        target_file_size
+                    };
+                    if size > size_limit {
                         new_layers.push(writer.take().unwrap().finish(prev_key.next())?);
                         writer = None;
                     }
@@ -2071,16 +3244,8 @@ impl LayeredTimeline {
 
         // Sync layers
         if !new_layers.is_empty() {
-            let mut layer_paths: Vec<PathBuf> = new_layers.iter().map(|l| l.path()).collect();
-
-            // also sync the directory
-            layer_paths.push(self.conf.timeline_path(&self.timeline_id, &self.tenant_id));
-
-            // Fsync all the layer files and directory using multiple threads to
-            // minimize latency.
-            par_fsync::par_fsync(&layer_paths)?;
-
-            layer_paths.pop().unwrap();
+            let layer_paths: Vec<PathBuf> = new_layers.iter().map(|l| l.path()).collect();
+            self.sync_new_layers(&layer_paths)?;
         }
 
         let mut layers = self.layers.write().unwrap();
@@ -2098,7 +3263,9 @@ impl LayeredTimeline {
             if let Some(path) = l.local_path() {
                 layer_paths_do_delete.insert(path);
             }
-            layers.remove_historic(l);
+            layers.remove_historic(Arc::clone(&l));
+            // Deleted once concurrent readers are done with the layer
+            layer_deletion::release_removed_layer(l);
         }
         drop(layers);
 
@@ -2150,35 +3317,408 @@ impl LayeredTimeline {
     }
 
     ///
-    /// Garbage collect layer files on a timeline that are no longer needed.
+    /// Sum up the sizes of on-disk layers that are older than the GC horizon and
+    /// covered by newer image layers. See `LayeredRepository::estimate_gc_reclaimable_bytes`.
     ///
-    /// Currently, we don't make any attempt at removing unneeded page versions
-    /// within a layer file. We can only remove the whole file if it's fully
-    /// obsolete.
+    fn estimate_gc_reclaimable_bytes(&self, horizon: u64) -> u64 {
+        self.compute_gc_estimate(horizon, Vec::new())
+            .reclaimable_bytes
+    }
+
     ///
-    fn gc(&self) -> Result<GcResult> {
-        let now = SystemTime::now();
-        let mut result: GcResult = Default::default();
+    /// Find the on-disk layers that GC would remove with the given horizon:
+    /// the layers older than the cutoff, not needed at any of 'retain_lsns',
+    /// and covered by newer image layers. The PITR interval and the read
+    /// replicas are not taken into account, so this is an upper bound when
+    /// they hold GC back.
+    ///
+    fn compute_gc_estimate(&self, horizon: u64, retain_lsns: Vec<Lsn>) -> GcEstimate {
         let disk_consistent_lsn = self.get_disk_consistent_lsn();
+        let mut estimate = GcEstimate {
+            cutoff_lsn: Lsn(0),
+            retain_lsns,
+            reclaimable_layers: 0,
+            reclaimable_bytes: 0,
+            disk_consistent_lsn,
+        };
+        let cutoff = match self.get_last_record_lsn().checked_sub(horizon) {
+            Some(cutoff) => min(cutoff, disk_consistent_lsn),
+            None => return estimate,
+        };
+        estimate.cutoff_lsn = cutoff;
+        if *self.get_latest_gc_cutoff_lsn() >= cutoff {
+            return estimate;
+        }
 
-        let _compaction_cs = self.compaction_cs.lock().unwrap();
-
-        let gc_info = self.gc_info.read().unwrap();
-        let retain_lsns = &gc_info.retain_lsns;
-        let cutoff = min(gc_info.cutoff, disk_consistent_lsn);
-        let pitr = gc_info.pitr;
+        let layers = self.layers.read().unwrap();
+        for l in layers.iter_historic_layers() {
+            if l.is_in_memory() || l.get_lsn_range().end > cutoff {
+                continue;
+            }
+            // The same rules as in gc()
+            if estimate
+                .retain_lsns
+                .iter()
+                .any(|retain_lsn| l.get_lsn_range().start <= *retain_lsn)
+            {
+                continue;
+            }
+            if !matches!(
+                layers.image_layer_exists(&l.get_key_range(), &(l.get_lsn_range().end..cutoff)),
+                Ok(true)
+            ) {
+                continue;
+            }
+            estimate.reclaimable_layers += 1;
+            estimate.reclaimable_bytes += layer_file_size(l.as_ref());
+        }
+        estimate
+    }
 
-        // Calculate pitr cutoff point.
-        // If we cannot determine a cutoff LSN, be conservative and don't GC anything.
-        let mut pitr_cutoff_lsn: Lsn = *self.get_latest_gc_cutoff_lsn();
+    /// Recompute the GC estimate, with the tenant's GC horizon and the branch
+    /// points known to the last GC.
+    fn update_gc_estimate(&self) -> GcEstimate {
+        let retain_lsns = self.gc_info.read().unwrap().retain_lsns.clone();
+        let estimate = self.compute_gc_estimate(self.get_gc_horizon(), retain_lsns);
+        *self.last_gc_estimate.lock().unwrap() = Some(estimate.clone());
+        estimate
+    }
 
-        if let Ok(timeline) =
-            tenant_mgr::get_local_timeline_with_load(self.tenant_id, self.timeline_id)
+    ///
+    /// Estimate how much layer space the GC of this timeline would free now,
+    /// as of the last checkpoint or GC. The branch points are the ones known
+    /// to the last GC iteration, branches created since then are not counted.
+    ///
+    pub fn gc_estimate(&self) -> GcEstimate {
+        let estimate = self.last_gc_estimate.lock().unwrap().clone();
+        match estimate {
+            Some(estimate) => estimate,
+            None => self.update_gc_estimate(),
+        }
+    }
+
+    ///
+    /// Report where the space retained by the last GC of this timeline went,
+    /// or None if GC hasn't run on it since the pageserver started.
+    /// 'limit' caps the number of the top space consumers returned.
+    ///
+    pub fn gc_space_report(&self, limit: usize) -> Option<GcSpaceReport> {
+        let mut report = self.last_gc_space_report.lock().unwrap().clone()?;
+        report.top_consumers.truncate(limit);
+        Some(report)
+    }
+
+    /// The layer files in the layer map of this timeline, without the ancestors' ones.
+    pub fn layer_files(&self) -> Vec<LayerFileInfo> {
+        let layers = self.layers.read().unwrap();
+        layers
+            .iter_historic_layers()
+            .filter(|l| !l.is_in_memory())
+            .filter_map(|l| {
+                Some(LayerFileInfo {
+                    path: l.local_path()?,
+                    key_range: l.get_key_range(),
+                    lsn_range: l.get_lsn_range(),
+                    is_incremental: l.is_incremental(),
+                })
+            })
+            .collect()
+    }
+
+    ///
+    /// Look up the value like [`Timeline::get`], but reconstruct it from the
+    /// layers, bypassing the page caches. Used to check that the layers are
+    /// readable.
+    ///
+    pub fn get_from_layers(&self, key: Key, lsn: Lsn) -> Result<Bytes> {
+        let mut reconstruct_state = ValueReconstructState {
+            records: Vec::new(),
+            img: None,
+        };
+        self.get_reconstruct_data(key, lsn, &mut reconstruct_state, None)?;
+        self.reconstruct_value(key, lsn, reconstruct_state)
+    }
+
+    ///
+    /// Read the layer files that are needed to reconstruct pages at 'lsn'
+    /// through, so that they are in the OS page cache when a compute starts
+    /// reading from this timeline. Includes the layers of the ancestor
+    /// timelines that pages not modified on this timeline are read from.
+    ///
+    /// Returns the number of layers read and their total size in bytes.
+    ///
+    pub fn warm_up_layers(&self, lsn: Lsn) -> Result<(usize, u64)> {
+        let mut layer_paths = Vec::new();
+        {
+            let layers = self.layers.read().unwrap();
+            for l in layers.iter_historic_layers() {
+                if l.is_in_memory() || l.get_lsn_range().start > lsn {
+                    continue;
+                }
+                // Layers behind a newer image layer are only needed for older LSNs.
+                if layers
+                    .image_layer_exists(&l.get_key_range(), &(l.get_lsn_range().end..lsn + 1))?
+                {
+                    continue;
+                }
+                if let Some(path) = l.local_path() {
+                    layer_paths.push(path);
+                }
+            }
+        }
+
+        let mut layers_read = 0;
+        let mut bytes_read = 0;
+        for path in layer_paths {
+            let mut file = match File::open(&path) {
+                Ok(file) => file,
+                // Removed by GC or compaction in the meantime.
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => {
+                    return Err(e)
+                        .with_context(|| format!("Failed to open layer file '{}'", path.display()))
+                }
+            };
+            bytes_read += std::io::copy(&mut file, &mut std::io::sink())
+                .with_context(|| format!("Failed to read layer file '{}'", path.display()))?;
+            layers_read += 1;
+        }
+
+        if self.ancestor_timeline.is_some() {
+            let ancestor = self.get_ancestor_timeline()?;
+            let (ancestor_layers, ancestor_bytes) =
+                ancestor.warm_up_layers(min(lsn, self.ancestor_lsn))?;
+            layers_read += ancestor_layers;
+            bytes_read += ancestor_bytes;
+        }
+
+        Ok((layers_read, bytes_read))
+    }
+
+    ///
+    /// Collect the keys that have a new version within 'lsn_range', from the
+    /// delta and in-memory layers. Includes the changes on the ancestor
+    /// timelines before the branch point. Image layers don't tell what
+    /// changed, but they never replace the delta layers above the GC cutoff,
+    /// so the range must start at or above the cutoff of every timeline that
+    /// it covers.
+    ///
+    pub fn collect_changed_keys(&self, lsn_range: Range<Lsn>) -> Result<HashSet<Key>> {
+        let latest_gc_cutoff_lsn = *self.get_latest_gc_cutoff_lsn();
+        ensure!(
+            lsn_range.start >= latest_gc_cutoff_lsn,
+            "Changes from LSN {} are not available on timeline {}, GC has removed the changes before {latest_gc_cutoff_lsn}",
+            lsn_range.start,
+            self.timeline_id,
+        );
+
+        let changed_layers: Vec<Arc<dyn Layer>> = {
+            let layers = self.layers.read().unwrap();
+            let in_memory_layers = layers
+                .frozen_layers
+                .iter()
+                .chain(layers.open_layer.iter())
+                .map(|l| Arc::clone(l) as Arc<dyn Layer>);
+            layers
+                .iter_historic_layers()
+                .cloned()
+                .chain(in_memory_layers)
+                .filter(|l| {
+                    l.is_incremental()
+                        && storage_layer::range_overlaps(&l.get_lsn_range(), &lsn_range)
+                })
+                .collect()
+        };
+
+        let mut keys = HashSet::new();
+        for layer in changed_layers {
+            // A layer that compaction replaced in the meantime may be gone
+            layer
+                .collect_changed_keys(&lsn_range, &mut keys)
+                .with_context(|| {
+                    format!(
+                        "Failed to read the keys of layer {}",
+                        layer.filename().display()
+                    )
+                })?;
+        }
+
+        // Reads at the branch point and after it see the ancestor's changes up to the branch point
+        if self.ancestor_timeline.is_some() && lsn_range.start <= self.ancestor_lsn {
+            let ancestor = self.get_ancestor_timeline()?;
+            keys.extend(ancestor.collect_changed_keys(
+                lsn_range.start..min(lsn_range.end, self.ancestor_lsn + 1),
+            )?);
+        }
+
+        Ok(keys)
+    }
+
+    ///
+    /// Read the ancestor layers that pages not modified on this branch are
+    /// reconstructed from at the branch point, so that the first reads crossing
+    /// the branch point don't have to wait for them. Does nothing for timelines
+    /// without an ancestor.
+    ///
+    /// Returns the number of layers read and their total size in bytes.
+    ///
+    pub fn prefetch_ancestor_layers(&self) -> Result<(usize, u64)> {
+        if self.ancestor_timeline.is_none() {
+            return Ok((0, 0));
+        }
+        self.get_ancestor_timeline()?
+            .warm_up_layers(self.ancestor_lsn)
+    }
+
+    ///
+    /// Materialize the pages of the branch at its branch point in image layers
+    /// of its own, so that the reads don't have to go through its ancestors
+    /// any more. If GC has already moved past the branch point, the images are
+    /// created at the GC cutoff instead. Does nothing for timelines without an
+    /// ancestor.
+    ///
+    /// Returns the paths of the new image layers.
+    ///
+    pub fn flatten_branch_point(&self) -> Result<Vec<PathBuf>> {
+        if self.ancestor_timeline.is_none() {
+            return Ok(Vec::new());
+        }
+        let pgdir = match tenant_mgr::get_local_timeline_with_load(self.tenant_id, self.timeline_id)
+        {
+            Ok(pgdir) => pgdir,
+            Err(_) => {
+                debug!("Could not flatten the timeline because its key space is unknown");
+                return Ok(Vec::new());
+            }
+        };
+        let lsn = max(self.ancestor_lsn, *self.get_latest_gc_cutoff_lsn());
+        let keyspace = pgdir.collect_keyspace(lsn)?;
+        self.create_branch_point_images(&keyspace, lsn)
+    }
+
+    fn create_branch_point_images(&self, keyspace: &KeySpace, lsn: Lsn) -> Result<Vec<PathBuf>> {
+        let new_paths = self.create_image_layers_at(std::slice::from_ref(keyspace), lsn)?;
+        if lsn == self.ancestor_lsn {
+            self.has_branch_point_images
+                .store(true, atomic::Ordering::Relaxed);
+        }
+        info!(
+            "materialized timeline {} at {} in {} image layers",
+            self.timeline_id,
+            lsn,
+            new_paths.len()
+        );
+        Ok(new_paths)
+    }
+
+    ///
+    /// Create image layers covering the given key spaces at 'lsn', regardless of
+    /// how many deltas have accumulated on top of the existing images. Each key
+    /// space is partitioned separately, so an image layer never spans keys of two
+    /// key spaces. Parts that already have an image layer at 'lsn' are skipped.
+    ///
+    /// Returns the paths of the new image layers.
+    ///
+    pub fn create_image_layers_at(&self, keyspaces: &[KeySpace], lsn: Lsn) -> Result<Vec<PathBuf>> {
+        let last_record_lsn = self.get_last_record_lsn();
+        ensure!(
+            lsn <= last_record_lsn,
+            "Requested LSN {lsn} is beyond the last record LSN {last_record_lsn} of the timeline"
+        );
+
+        // The images are read through the layer map, so make sure the WAL up
+        // to 'lsn' is in delta layers on disk first.
+        if lsn > self.get_disk_consistent_lsn() {
+            self.checkpoint(CheckpointConfig::Flush)?;
+        }
+
+        // Holding the compaction lock also keeps GC from advancing the cutoff.
+        let _compaction_cs = self.compaction_cs.lock().unwrap();
+        self.check_lsn_is_in_scope(lsn, &self.get_latest_gc_cutoff_lsn())?;
+
+        let timer = self.create_images_time_histo.start_timer();
+        let mut new_paths = Vec::new();
+        for keyspace in keyspaces {
+            for part in keyspace.partition(self.get_compaction_target_size()).parts {
+                let (first, last) = match (part.ranges.first(), part.ranges.last()) {
+                    (Some(first), Some(last)) => (first, last),
+                    _ => continue,
+                };
+                if self
+                    .layers
+                    .read()
+                    .unwrap()
+                    .image_layer_exists(&(first.start..last.end), &(lsn..lsn + 1))?
+                {
+                    continue;
+                }
+                new_paths.push(self.create_image_layer(&part, lsn)?);
+            }
+        }
+        timer.stop_and_record();
+
+        if self.upload_layers.load(atomic::Ordering::Relaxed) && !new_paths.is_empty() {
+            storage_sync::schedule_layer_upload(
+                self.tenant_id,
+                self.timeline_id,
+                new_paths.iter().cloned().collect(),
+                None,
+            );
+        }
+
+        Ok(new_paths)
+    }
+
+    ///
+    /// Garbage collect layer files on a timeline that are no longer needed.
+    ///
+    /// Currently, we don't make any attempt at removing unneeded page versions
+    /// within a layer file. We can only remove the whole file if it's fully
+    /// obsolete.
+    ///
+    /// The scan of the layer map also collects the Level 0 delta layers that
+    /// GC keeps. If there are enough of them to compact, they are compacted
+    /// right away, under the same `compaction_cs`, and the timeline is marked
+    /// as compacted so that the compaction thread doesn't scan the layer map
+    /// once more for the same work.
+    ///
+    fn gc(&self) -> Result<GcResult> {
+        let now = SystemTime::now();
+        let mut result: GcResult = Default::default();
+        let disk_consistent_lsn = self.get_disk_consistent_lsn();
+
+        let _compaction_cs = self.compaction_cs.lock().unwrap();
+        if self.is_stopping() {
+            return Ok(result);
+        }
+
+        let gc_info = self.gc_info.read().unwrap();
+        let retain_lsns = &gc_info.retain_lsns;
+        let mut cutoff = min(gc_info.cutoff, disk_consistent_lsn);
+        let pitr = gc_info.pitr;
+
+        // Don't remove the versions that read replicas still read at.
+        if let Some(standby_lsn) = self.standby_horizon.get(self.clock.instant()) {
+            if standby_lsn < cutoff {
+                info!(
+                    "holding back GC cutoff {} at {} for read replicas",
+                    cutoff, standby_lsn
+                );
+                cutoff = standby_lsn;
+            }
+        }
+
+        // Calculate pitr cutoff point.
+        // If we cannot determine a cutoff LSN, be conservative and don't GC anything.
+        let mut pitr_cutoff_lsn: Lsn = *self.get_latest_gc_cutoff_lsn();
+
+        if let Ok(timeline) =
+            tenant_mgr::get_local_timeline_with_load(self.tenant_id, self.timeline_id)
         {
             // First, calculate pitr_cutoff_timestamp and then convert it to LSN.
             // If we don't have enough data to convert to LSN,
             // play safe and don't remove any layers.
-            if let Some(pitr_cutoff_timestamp) = now.checked_sub(pitr) {
+            if let Some(pitr_cutoff_timestamp) = self.clock.now().checked_sub(pitr) {
                 let pitr_timestamp = to_pg_timestamp(pitr_cutoff_timestamp);
 
                 match timeline.find_lsn_for_timestamp(pitr_timestamp)? {
@@ -2222,17 +3762,26 @@ impl LayeredTimeline {
         debug!("retain_lsns: {:?}", retain_lsns);
 
         let mut layers_to_remove = Vec::new();
+        let mut retained_level0_deltas = Vec::new();
+        let mut space_consumers: HashMap<String, GcSpaceConsumer> = HashMap::new();
 
         // Scan all on-disk layers in the timeline.
         //
-        // Garbage collect the layer if all conditions are satisfied:
-        // 1. it is older than cutoff LSN;
-        // 2. it is older than PITR interval;
-        // 3. it doesn't need to be retained for 'retain_lsns';
-        // 4. newer on-disk image layers cover the layer's whole key range
+        // Garbage collect the layer unless the layer strategy has a reason to
+        // keep it, see default_gc_retain_reason for the default rules.
         //
         let mut layers = self.layers.write().unwrap();
-        'outer: for l in layers.iter_historic_layers() {
+        let gc_context = GcContext {
+            tenant_id: self.tenant_id,
+            timeline_id: self.timeline_id,
+            cutoff,
+            pitr_cutoff: pitr_cutoff_lsn,
+            retain_lsns,
+            gc_min_layer_age: self.conf.gc_min_layer_age,
+            uploaded_layers: gc_info.uploaded_layers.as_ref(),
+            layers: &layers,
+        };
+        for l in layers.iter_historic_layers() {
             // This layer is in the process of being flushed to disk.
             // It will be swapped out of the layer map, replaced with
             // on-disk layers containing the same data.
@@ -2244,76 +3793,46 @@ impl LayeredTimeline {
             }
 
             result.layers_total += 1;
-
-            // 1. Is it newer than cutoff point?
-            if l.get_lsn_range().end > cutoff {
-                debug!(
-                    "keeping {} because it's newer than cutoff {}",
-                    l.filename().display(),
-                    cutoff
-                );
-                result.layers_needed_by_cutoff += 1;
-                continue 'outer;
-            }
-
-            // 2. It is newer than PiTR cutoff point?
-            if l.get_lsn_range().end > pitr_cutoff_lsn {
-                debug!(
-                    "keeping {} because it's newer than pitr_cutoff_lsn {}",
-                    l.filename().display(),
-                    pitr_cutoff_lsn
-                );
-                result.layers_needed_by_pitr += 1;
-                continue 'outer;
-            }
-
-            // 3. Is it needed by a child branch?
-            // NOTE With that we would keep data that
-            // might be referenced by child branches forever.
-            // We can track this in child timeline GC and delete parent layers when
-            // they are no longer needed. This might be complicated with long inheritance chains.
-            for retain_lsn in retain_lsns {
-                // start_lsn is inclusive
-                if &l.get_lsn_range().start <= retain_lsn {
-                    debug!(
-                        "keeping {} because it's still might be referenced by child branch forked at {} is_dropped: xx is_incremental: {}",
-                        l.filename().display(),
-                        retain_lsn,
-                        l.is_incremental(),
-                    );
-                    result.layers_needed_by_branches += 1;
-                    continue 'outer;
+            let layer_size = layer_file_size(l.as_ref());
+            let mut retain = |reason: GcRetainReason| {
+                if LayerMap::is_level0_delta(l.as_ref()) {
+                    retained_level0_deltas.push(Arc::clone(l));
                 }
-            }
+                result.bytes_retained.add(reason, layer_size);
+                let owner = layer_space_owner(&l.get_key_range());
+                let consumer = space_consumers
+                    .entry(owner.clone())
+                    .or_insert(GcSpaceConsumer {
+                        owner,
+                        layers: 0,
+                        retained: Default::default(),
+                    });
+                consumer.layers += 1;
+                consumer.retained.add(reason, layer_size);
+            };
 
-            // 4. Is there a later on-disk layer for this relation?
-            //
-            // The end-LSN is exclusive, while disk_consistent_lsn is
-            // inclusive. For example, if disk_consistent_lsn is 100, it is
-            // OK for a delta layer to have end LSN 101, but if the end LSN
-            // is 102, then it might not have been fully flushed to disk
-            // before crash.
-            //
-            // For example, imagine that the following layers exist:
-            //
-            // 1000      - image (A)
-            // 1000-2000 - delta (B)
-            // 2000      - image (C)
-            // 2000-3000 - delta (D)
-            // 3000      - image (E)
-            //
-            // If GC horizon is at 2500, we can remove layers A and B, but
-            // we cannot remove C, even though it's older than 2500, because
-            // the delta layer 2000-3000 depends on it.
-            if !layers
-                .image_layer_exists(&l.get_key_range(), &(l.get_lsn_range().end..new_gc_cutoff))?
+            let gc_layer = GcLayer {
+                filename: l.filename(),
+                path: l.local_path(),
+                key_range: l.get_key_range(),
+                lsn_range: l.get_lsn_range(),
+                is_incremental: l.is_incremental(),
+                size: layer_size,
+            };
+            if let Some(reason) = self
+                .layer_strategy
+                .gc_retain_reason(&gc_layer, &gc_context)?
             {
-                debug!(
-                    "keeping {} because it is the latest layer",
-                    l.filename().display()
-                );
-                result.layers_not_updated += 1;
-                continue 'outer;
+                match reason {
+                    GcRetainReason::Cutoff => result.layers_needed_by_cutoff += 1,
+                    GcRetainReason::Pitr => result.layers_needed_by_pitr += 1,
+                    GcRetainReason::Branches => result.layers_needed_by_branches += 1,
+                    GcRetainReason::Latest => result.layers_not_updated += 1,
+                    GcRetainReason::Recent => result.layers_too_recent += 1,
+                    GcRetainReason::Policy => result.layers_needed_by_policy += 1,
+                }
+                retain(reason);
+                continue;
             }
 
             // We didn't find any reason to keep this file, so remove it.
@@ -2322,9 +3841,26 @@ impl LayeredTimeline {
                 l.filename().display(),
                 l.is_incremental(),
             );
+            result.bytes_removed += layer_size;
             layers_to_remove.push(Arc::clone(l));
         }
 
+        let mut top_consumers = space_consumers.into_values().collect::<Vec<_>>();
+        top_consumers.sort_by(|a, b| {
+            b.retained
+                .total()
+                .cmp(&a.retained.total())
+                .then_with(|| a.owner.cmp(&b.owner))
+        });
+        *self.last_gc_space_report.lock().unwrap() = Some(GcSpaceReport {
+            cutoff_lsn: cutoff,
+            pitr_cutoff_lsn,
+            retain_lsns: retain_lsns.clone(),
+            retained: result.bytes_retained,
+            removed_bytes: result.bytes_removed,
+            top_consumers,
+        });
+
         // Actually delete the layers from disk and remove them from the map.
         // (couldn't do this in the loop above, because you cannot modify a collection
         // while iterating it. BTreeMap::retain() would be another option)
@@ -2334,10 +3870,24 @@ impl LayeredTimeline {
             if let Some(path) = doomed_layer.local_path() {
                 layer_paths_to_delete.insert(path);
             }
-            layers.remove_historic(doomed_layer);
+            layers.remove_historic(Arc::clone(&doomed_layer));
+            // A concurrent read can still hold the layer, the file is deleted
+            // when it's done
+            layer_deletion::release_removed_layer(doomed_layer);
             result.layers_removed += 1;
         }
 
+        // Decide on the compaction while still holding the layer map lock: a
+        // flush that adds a new Level 0 layer after this point sets
+        // 'compaction_needed' again.
+        let compact_level0 = self.layer_strategy.should_compact_level0(
+            retained_level0_deltas.len(),
+            self.get_compaction_threshold(),
+        ) && self
+            .compaction_needed
+            .swap(false, atomic::Ordering::Relaxed);
+        drop(layers);
+
         if self.upload_layers.load(atomic::Ordering::Relaxed) {
             storage_sync::schedule_layer_delete(
                 self.tenant_id,
@@ -2346,6 +3896,23 @@ impl LayeredTimeline {
             );
         }
 
+        if compact_level0 {
+            COMPACTION_DONE_BY_GC.inc();
+            let timer = self.compact_time_histo.start_timer();
+            // GC itself is done, leave a failed compaction to the compaction thread
+            if let Err(e) =
+                self.compact_level0_deltas(retained_level0_deltas, self.get_checkpoint_distance())
+            {
+                error!("Compaction after GC failed: {:?}", e);
+                self.compaction_needed
+                    .store(true, atomic::Ordering::Relaxed);
+            }
+            timer.stop_and_record();
+        }
+
+        drop(gc_info);
+        self.update_gc_estimate();
+
         result.elapsed = now.elapsed()?;
         Ok(result)
     }
@@ -2402,17 +3969,21 @@ impl LayeredTimeline {
 
                 let last_rec_lsn = data.records.last().unwrap().0;
 
-                let img =
+                let img = debug_span!("wal_redo", records = data.records.len()).in_scope(|| {
                     self.walredo_mgr
-                        .request_redo(key, request_lsn, base_img, data.records)?;
+                        .request_redo(key, request_lsn, base_img, data.records)
+                })?;
 
                 if img.len() == page_cache::PAGE_SZ {
                     let cache = page_cache::get();
+                    // All WAL up to the request LSN was collected, so the image
+                    // stays the same from the last record up to it
                     cache.memorize_materialized_page(
                         self.tenant_id,
                         self.timeline_id,
                         key,
                         last_rec_lsn,
+                        request_lsn,
                         &img,
                     );
                 }
@@ -2423,30 +3994,54 @@ impl LayeredTimeline {
     }
 }
 
+/// Name what the data in the key range belongs to, for the GC space report.
+fn layer_space_owner(key_range: &Range<Key>) -> String {
+    match key_range_to_rel(key_range) {
+        Some(rel) => rel.to_string(),
+        None if key_range.start.field1 == 0x00 => "multiple relations".to_string(),
+        None => "non-relational data".to_string(),
+    }
+}
+
+/// Size of the layer's file on local disk, 0 if it has none.
+fn layer_file_size(layer: &dyn Layer) -> u64 {
+    layer
+        .local_path()
+        .and_then(|path| fs::metadata(path).ok())
+        .map_or(0, |metadata| metadata.len())
+}
+
 /// Helper function for get_reconstruct_data() to add the path of layers traversed
 /// to an error, as anyhow context information.
 fn layer_traversal_error(
+    tenant_id: ZTenantId,
+    timeline_id: ZTimelineId,
     msg: String,
     path: Vec<(ValueReconstructResult, Lsn, Arc<dyn Layer>)>,
 ) -> anyhow::Result<()> {
     // We want the original 'msg' to be the outermost context. The outermost context
     // is the most high-level information, which also gets propagated to the client.
-    let mut msg_iter = path
-        .iter()
-        .map(|(r, c, l)| {
-            format!(
-                "layer traversal: result {:?}, cont_lsn {}, layer: {}",
-                r,
-                c,
-                l.filename().display()
-            )
-        })
-        .chain(std::iter::once(msg));
+    let mut msg_iter = path.iter().map(|(r, c, l)| {
+        format!(
+            "layer traversal: result {:?}, cont_lsn {}, layer: {}",
+            r,
+            c,
+            l.filename().display()
+        )
+    });
     // Construct initial message from the first traversed layer
-    let err = anyhow!(msg_iter.next().unwrap());
-
-    // Append all subsequent traversals, and the error message 'msg', as contexts.
-    Err(msg_iter.fold(err, |err, msg| err.context(msg)))
+    let err = match msg_iter.next() {
+        Some(first) => msg_iter.fold(anyhow!(first), |err, msg| err.context(msg)),
+        None => anyhow!("no layers traversed"),
+    };
+
+    // Append the error message 'msg' as the outermost context, as a typed error
+    // that the page service can tell the client about.
+    Err(err.context(ReadError::Corruption {
+        tenant_id,
+        timeline_id,
+        what: msg,
+    }))
 }
 
 struct LayeredTimelineWriter<'a> {
@@ -2498,26 +4093,6 @@ pub fn dump_layerfile_from_path(path: &Path, verbose: bool) -> Result<()> {
     Ok(())
 }
 
-/// Add a suffix to a layer file's name: .{num}.old
-/// Uses the first available num (starts at 0)
-fn rename_to_backup(path: PathBuf) -> anyhow::Result<()> {
-    let filename = path
-        .file_name()
-        .ok_or_else(|| anyhow!("Path {} don't have a file name", path.display()))?
-        .to_string_lossy();
-    let mut new_path = path.clone();
-
-    for i in 0u32.. {
-        new_path.set_file_name(format!("{}.{}.old", filename, i));
-        if !new_path.exists() {
-            std::fs::rename(&path, &new_path)?;
-            return Ok(());
-        }
-    }
-
-    bail!("couldn't find an unused backup number for {:?}", path)
-}
-
 pub fn load_metadata(
     conf: &'static PageServerConf,
     timeline_id: ZTimelineId,
@@ -2548,9 +4123,12 @@ pub fn load_metadata(
 #[cfg(test)]
 pub mod tests {
     use super::*;
+    use crate::config::LayerReadMode;
     use crate::keyspace::KeySpaceAccum;
     use crate::repository::repo_harness::*;
-    use rand::{thread_rng, Rng};
+    use crate::repository::GcRetainedBytes;
+    use postgres_ffi::pg_constants;
+    use rand::{rngs::StdRng, thread_rng, Rng, SeedableRng};
 
     #[test]
     fn corrupt_metadata() -> Result<()> {
@@ -2649,67 +4227,1302 @@ pub mod tests {
     // Insert 1000 key-value pairs with increasing keys, checkpoint,
     // repeat 50 times.
     //
-    #[test]
-    fn test_bulk_insert() -> Result<()> {
-        let repo = RepoHarness::create("test_bulk_insert")?.load();
-        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+    /// A page image that fits a page cache slot
+    fn page_img(s: &str) -> Bytes {
+        let mut img = TEST_IMG(s).to_vec();
+        img.resize(page_cache::PAGE_SZ, 0);
+        Bytes::from(img)
+    }
 
-        let mut lsn = Lsn(0x10);
+    fn cached_page(tline: &LayeredTimeline, key: Key, lsn: Lsn) -> Option<(Lsn, Lsn, Bytes)> {
+        tline.lookup_cached_page(&key, lsn)
+    }
 
-        let mut keyspace = KeySpaceAccum::new();
+    #[test]
+    fn test_cached_page_validity() -> Result<()> {
+        let test_key = Key::from_hex("012222222233333333444444445500000000").unwrap();
 
-        let mut test_key = Key::from_hex("012222222233333333444444445500000000").unwrap();
-        let mut blknum = 0;
-        for _ in 0..50 {
-            for _ in 0..10000 {
-                test_key.field6 = blknum;
-                let writer = tline.writer();
-                writer.put(
-                    test_key,
-                    lsn,
-                    Value::Image(TEST_IMG(&format!("{} at {}", blknum, lsn))),
-                )?;
-                writer.finish_write(lsn);
-                drop(writer);
+        // Exclusive, so that other tests don't evict the cached pages
+        let repo = RepoHarness::create_exclusive("test_cached_page_validity")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+        let writer = tline.writer();
+        writer.put(test_key, Lsn(0x10), Value::Image(page_img("foo at 0x10")))?;
+        writer.finish_write(Lsn(0x40));
+        drop(writer);
 
-                keyspace.add_key(test_key);
+        // The image is memorized with the LSN it stays valid until. It differs
+        // from the one in the layers, to tell where a read got the page from.
+        page_cache::get().memorize_materialized_page(
+            repo.tenant_id,
+            TIMELINE_ID,
+            test_key,
+            Lsn(0x10),
+            Lsn(0x30),
+            &page_img("cached at 0x10"),
+        );
+        assert_eq!(
+            cached_page(&tline, test_key, Lsn(0x20)),
+            Some((Lsn(0x10), Lsn(0x30), page_img("cached at 0x10")))
+        );
 
-                lsn = Lsn(lsn.0 + 0x10);
-                blknum += 1;
-            }
+        // Reads up to the LSN the image is valid until don't look at the layers
+        for lsn in [Lsn(0x10), Lsn(0x20), Lsn(0x30)] {
+            assert_eq!(tline.get(test_key, lsn)?, page_img("cached at 0x10"));
+            assert_eq!(
+                tline.get_range(test_key..test_key.next(), lsn)?,
+                vec![(test_key, page_img("cached at 0x10"))]
+            );
+        }
 
-            let cutoff = tline.get_last_record_lsn();
+        // Past it, the layers are checked. No WAL on top of the cached image, so
+        // the image is used, and its validity is extended to the read LSN.
+        assert_eq!(tline.get(test_key, Lsn(0x40))?, page_img("cached at 0x10"));
+        assert_eq!(
+            cached_page(&tline, test_key, Lsn(0x40)),
+            Some((Lsn(0x10), Lsn(0x40), page_img("cached at 0x10")))
+        );
 
-            tline.update_gc_info(Vec::new(), cutoff, Duration::ZERO);
-            tline.checkpoint(CheckpointConfig::Forced)?;
-            tline.compact()?;
-            tline.gc()?;
-        }
+        // A newer version is found in the layers, and the cached image doesn't
+        // become valid past it
+        let writer = tline.writer();
+        writer.put(test_key, Lsn(0x50), Value::Image(page_img("foo at 0x50")))?;
+        writer.finish_write(Lsn(0x60));
+        drop(writer);
+        assert_eq!(tline.get(test_key, Lsn(0x60))?, page_img("foo at 0x50"));
+        assert_eq!(
+            tline.get_range(test_key..test_key.next(), Lsn(0x50))?,
+            vec![(test_key, page_img("foo at 0x50"))]
+        );
+        assert_eq!(tline.get(test_key, Lsn(0x40))?, page_img("cached at 0x10"));
+        assert_eq!(
+            cached_page(&tline, test_key, Lsn(0x60)),
+            Some((Lsn(0x10), Lsn(0x40), page_img("cached at 0x10")))
+        );
 
         Ok(())
     }
 
     #[test]
-    fn test_random_updates() -> Result<()> {
-        let repo = RepoHarness::create("test_random_updates")?.load();
+    fn test_cached_ancestor_page_validity() -> Result<()> {
+        let test_key = Key::from_hex("012222222233333333444444445500000000").unwrap();
+
+        let repo = RepoHarness::create_exclusive("test_cached_ancestor_page_validity")?.load();
         let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+        let writer = tline.writer();
+        writer.put(test_key, Lsn(0x10), Value::Image(page_img("foo at 0x10")))?;
+        writer.finish_write(Lsn(0x20));
+        drop(writer);
 
-        const NUM_KEYS: usize = 1000;
+        repo.branch_timeline(TIMELINE_ID, NEW_TIMELINE_ID, Lsn(0x20))?;
+        let child = repo.get_timeline_load(NEW_TIMELINE_ID)?;
+        let writer = child.writer();
+        writer.put(test_key, Lsn(0x30), Value::Image(page_img("child at 0x30")))?;
+        writer.finish_write(Lsn(0x40));
+        drop(writer);
 
-        let mut test_key = Key::from_hex("012222222233333333444444445500000000").unwrap();
+        // The ancestor's image, cached for the child
+        page_cache::get().memorize_materialized_page(
+            repo.tenant_id,
+            NEW_TIMELINE_ID,
+            test_key,
+            Lsn(0x10),
+            Lsn(0x10),
+            &page_img("foo at 0x10"),
+        );
 
-        let mut keyspace = KeySpaceAccum::new();
+        // Up to the branch point, the image comes from the ancestor, and it's
+        // valid up to the read LSN
+        assert_eq!(child.get(test_key, Lsn(0x20))?, page_img("foo at 0x10"));
+        assert_eq!(
+            cached_page(&child, test_key, Lsn(0x20)),
+            Some((Lsn(0x10), Lsn(0x20), page_img("foo at 0x10")))
+        );
 
-        // Track when each page was last modified. Used to assert that
-        // a read sees the latest page version.
-        let mut updated = [Lsn(0); NUM_KEYS];
+        // The child's own write is not skipped, and the cached image doesn't
+        // become valid across it
+        assert_eq!(child.get(test_key, Lsn(0x40))?, page_img("child at 0x30"));
+        assert_eq!(
+            cached_page(&child, test_key, Lsn(0x40)),
+            Some((Lsn(0x10), Lsn(0x20), page_img("foo at 0x10")))
+        );
+        assert_eq!(child.get(test_key, Lsn(0x30))?, page_img("child at 0x30"));
 
-        let mut lsn = Lsn(0);
-        #[allow(clippy::needless_range_loop)]
-        for blknum in 0..NUM_KEYS {
-            lsn = Lsn(lsn.0 + 0x10);
-            test_key.field6 = blknum as u32;
-            let writer = tline.writer();
+        Ok(())
+    }
+
+    #[test]
+    fn test_mmap_layer_reads() -> Result<()> {
+        let mut harness = RepoHarness::create("test_mmap_layer_reads")?;
+        let repo = harness.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        let mut keys = Vec::new();
+        let mut keyspace = KeySpaceAccum::new();
+        let mut key = Key::from_hex("012222222233333333444444445500000000").unwrap();
+        for _ in 0..100 {
+            keys.push(key);
+            keyspace.add_key(key);
+            key = key.next();
+        }
+        let keyspace = keyspace.to_keyspace();
+
+        let mut lsn = Lsn(0x10);
+        for _ in 0..3 {
+            let writer = tline.writer();
+            for key in &keys {
+                writer.put(
+                    *key,
+                    lsn,
+                    Value::Image(TEST_IMG(&format!("{key} at {lsn}"))),
+                )?;
+            }
+            writer.finish_write(lsn);
+            drop(writer);
+            lsn += 0x10;
+        }
+        tline.checkpoint(CheckpointConfig::Flush)?;
+        tline.create_image_layers_at(&[keyspace], tline.get_last_record_lsn())?;
+
+        // The latest version of every key in every layer file
+        let read_layers = |tline: &LayeredTimeline| -> Result<Vec<(PathBuf, Key, Option<Bytes>)>> {
+            let layers = tline.layers.read().unwrap();
+            let mut values = Vec::new();
+            for layer in layers.iter_historic_layers() {
+                for key in &keys {
+                    let mut state = ValueReconstructState {
+                        records: Vec::new(),
+                        img: None,
+                    };
+                    layer.get_value_reconstruct_data(
+                        *key,
+                        Lsn(0)..layer.get_lsn_range().end,
+                        &mut state,
+                    )?;
+                    values.push((layer.filename(), *key, state.img.map(|(_, img)| img)));
+                }
+            }
+            values.sort();
+            Ok(values)
+        };
+        let pread_values = read_layers(&tline)?;
+        let layers = tline.layers.read().unwrap();
+        assert!(layers.iter_historic_layers().any(|l| l.is_incremental()));
+        assert!(layers.iter_historic_layers().any(|l| !l.is_incremental()));
+        drop(layers);
+        drop(tline);
+        drop(repo);
+
+        harness.conf = Box::leak(Box::new(PageServerConf {
+            layer_read_mode: LayerReadMode::Mmap,
+            ..harness.conf.clone()
+        }));
+        let repo = harness.load();
+        let tline = repo.get_timeline_load(TIMELINE_ID)?;
+        let mmap_values = read_layers(&tline)?;
+        assert_eq!(mmap_values, pread_values);
+        assert!(mmap_values.iter().all(|(_, _, img)| img.is_some()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bulk_insert() -> Result<()> {
+        let repo = RepoHarness::create("test_bulk_insert")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        let mut lsn = Lsn(0x10);
+
+        let mut keyspace = KeySpaceAccum::new();
+
+        let mut test_key = Key::from_hex("012222222233333333444444445500000000").unwrap();
+        let mut blknum = 0;
+        for _ in 0..50 {
+            for _ in 0..10000 {
+                test_key.field6 = blknum;
+                let writer = tline.writer();
+                writer.put(
+                    test_key,
+                    lsn,
+                    Value::Image(TEST_IMG(&format!("{} at {}", blknum, lsn))),
+                )?;
+                writer.finish_write(lsn);
+                drop(writer);
+
+                keyspace.add_key(test_key);
+
+                lsn = Lsn(lsn.0 + 0x10);
+                blknum += 1;
+            }
+
+            let cutoff = tline.get_last_record_lsn();
+
+            tline.update_gc_info(Vec::new(), cutoff, Duration::ZERO);
+            tline.checkpoint(CheckpointConfig::Forced)?;
+            tline.compact()?;
+            tline.gc()?;
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_gc_space_report() -> Result<()> {
+        let repo = RepoHarness::create("test_gc_space_report")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+        assert!(tline.gc_space_report(10).is_none());
+
+        let mut test_key = Key {
+            field1: 0x00,
+            field2: 1663,
+            field3: 13010,
+            field4: 16384,
+            field5: 0,
+            field6: 0,
+        };
+        let mut lsn = Lsn(0x10);
+        for _ in 0..3 {
+            for blknum in 0..1000 {
+                test_key.field6 = blknum;
+                let writer = tline.writer();
+                writer.put(
+                    test_key,
+                    lsn,
+                    Value::Image(TEST_IMG(&format!("{blknum} at {lsn}"))),
+                )?;
+                writer.finish_write(lsn);
+                drop(writer);
+                lsn = Lsn(lsn.0 + 0x10);
+            }
+            tline.checkpoint(CheckpointConfig::Forced)?;
+            tline.compact()?;
+        }
+
+        // Keep everything for a branch point, so that some layers are retained
+        // by the 'branches' rule.
+        tline.update_gc_info(vec![Lsn(0x20)], tline.get_last_record_lsn(), Duration::ZERO);
+        let result = tline.gc()?;
+
+        let report = tline.gc_space_report(10).expect("GC has run");
+        assert_eq!(report.retained, result.bytes_retained);
+        assert_eq!(report.removed_bytes, result.bytes_removed);
+        assert_eq!(report.retain_lsns, vec![Lsn(0x20)]);
+        assert!(report.retained.total() > 0);
+
+        let mut consumers_total = GcRetainedBytes::default();
+        for consumer in &report.top_consumers {
+            consumers_total += consumer.retained;
+        }
+        assert_eq!(consumers_total, report.retained);
+        assert_eq!(report.top_consumers[0].owner, "1663/13010/16384");
+
+        assert!(tline.gc_space_report(0).unwrap().top_consumers.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_gc_estimate() -> Result<()> {
+        let repo = RepoHarness::create("test_gc_estimate")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        let test_key = Key::from_hex("012222222233333333444444445500000000").unwrap();
+        let mut keyspace = KeySpaceAccum::new();
+        keyspace.add_key(test_key);
+        let keyspace = keyspace.to_keyspace();
+
+        let mut lsn = Lsn(0x10);
+        let mut flush_layer = || -> Result<()> {
+            let writer = tline.writer();
+            writer.put(
+                test_key,
+                lsn,
+                Value::Image(TEST_IMG(&format!("foo at {lsn}"))),
+            )?;
+            writer.finish_write(lsn);
+            drop(writer);
+            lsn = Lsn(lsn.0 + 0x10);
+            tline.checkpoint(CheckpointConfig::Flush)
+        };
+
+        // The first delta layer gets covered by an image layer, the second one
+        // is needed to reconstruct the image LSN.
+        flush_layer()?;
+        flush_layer()?;
+        tline.create_image_layers_at(&[keyspace], tline.get_last_record_lsn())?;
+        flush_layer()?;
+
+        // Refreshed by the checkpoint
+        let estimate = tline.gc_estimate();
+        assert_eq!(
+            estimate.disk_consistent_lsn,
+            tline.get_disk_consistent_lsn()
+        );
+
+        let everything = tline.compute_gc_estimate(0, Vec::new());
+        assert_eq!(everything.cutoff_lsn, tline.get_disk_consistent_lsn());
+        assert_eq!(everything.reclaimable_layers, 1);
+        assert!(everything.reclaimable_bytes > 0);
+        // A branch point pins it
+        let pinned = tline.compute_gc_estimate(0, vec![Lsn(0x10)]);
+        assert_eq!(pinned.reclaimable_layers, 0);
+
+        // Without branches, GC removes what was estimated
+        tline.update_gc_info(Vec::new(), tline.get_last_record_lsn(), Duration::ZERO);
+        let result = tline.gc()?;
+        assert_eq!(result.layers_removed, everything.reclaimable_layers);
+        assert_eq!(result.bytes_removed, everything.reclaimable_bytes);
+
+        // and there's nothing left to collect
+        assert_eq!(
+            tline.compute_gc_estimate(0, Vec::new()).reclaimable_bytes,
+            0
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_gc_keeps_recent_layers() -> Result<()> {
+        let test_key = Key::from_hex("012222222233333333444444445500000000").unwrap();
+        let mut keyspace = KeySpaceAccum::new();
+        keyspace.add_key(test_key);
+        let keyspace = keyspace.to_keyspace();
+
+        let flush_layer = |tline: &LayeredTimeline| -> Result<()> {
+            let lsn = tline.get_last_record_lsn() + 0x10;
+            let writer = tline.writer();
+            writer.put(
+                test_key,
+                lsn,
+                Value::Image(TEST_IMG(&format!("foo at {lsn}"))),
+            )?;
+            writer.finish_write(lsn);
+            drop(writer);
+            tline.checkpoint(CheckpointConfig::Flush)
+        };
+        // Like in test_gc_estimate, the first delta layer gets covered by an
+        // image layer, and GC can remove it.
+        let add_reclaimable_layer = |tline: &LayeredTimeline| -> Result<()> {
+            flush_layer(tline)?;
+            flush_layer(tline)?;
+            tline.create_image_layers_at(&[keyspace.clone()], tline.get_last_record_lsn())?;
+            flush_layer(tline)
+        };
+        let gc = |tline: &LayeredTimeline| {
+            tline.update_gc_info(Vec::new(), tline.get_last_record_lsn(), Duration::ZERO);
+            tline.gc()
+        };
+
+        // Layers younger than gc_min_layer_age are kept
+        let mut harness = RepoHarness::create("test_gc_keeps_recent_layers_by_age")?;
+        harness.conf = Box::leak(Box::new(PageServerConf {
+            gc_min_layer_age: Duration::from_secs(3600),
+            ..harness.conf.clone()
+        }));
+        let repo = harness.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+        add_reclaimable_layer(&tline)?;
+        let result = gc(&tline)?;
+        assert_eq!(result.layers_removed, 0);
+        assert!(result.layers_too_recent > 0);
+        assert!(result.bytes_retained.recent > 0);
+
+        // Layers that are not uploaded yet are kept, when the layers are uploaded
+        let repo = RepoHarness::create("test_gc_keeps_recent_layers_by_upload")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+        add_reclaimable_layer(&tline)?;
+        tline.gc_info.write().unwrap().uploaded_layers = Some(HashSet::new());
+        let result = gc(&tline)?;
+        assert_eq!(result.layers_removed, 0);
+        assert_eq!(result.layers_too_recent, 1);
+
+        add_reclaimable_layer(&tline)?;
+        let uploaded_layers = tline
+            .layer_files()
+            .into_iter()
+            .map(|layer_file| layer_file.path)
+            .collect();
+        tline.gc_info.write().unwrap().uploaded_layers = Some(uploaded_layers);
+        let result = gc(&tline)?;
+        assert!(result.layers_removed > 0);
+        assert_eq!(result.layers_too_recent, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_gc_defers_deletion_of_held_layer() -> Result<()> {
+        let test_key = Key::from_hex("012222222233333333444444445500000000").unwrap();
+        let mut keyspace = KeySpaceAccum::new();
+        keyspace.add_key(test_key);
+        let keyspace = keyspace.to_keyspace();
+
+        let repo = RepoHarness::create("test_gc_defers_deletion_of_held_layer")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+        let flush_layer = |tline: &LayeredTimeline| -> Result<()> {
+            let lsn = tline.get_last_record_lsn() + 0x10;
+            let writer = tline.writer();
+            writer.put(
+                test_key,
+                lsn,
+                Value::Image(TEST_IMG(&format!("foo at {lsn}"))),
+            )?;
+            writer.finish_write(lsn);
+            drop(writer);
+            tline.checkpoint(CheckpointConfig::Flush)
+        };
+        flush_layer(&tline)?;
+        flush_layer(&tline)?;
+        tline.create_image_layers_at(&[keyspace], tline.get_last_record_lsn())?;
+        flush_layer(&tline)?;
+
+        // Hold the layers, like a concurrent reader that looked them up
+        let held_layers: Vec<Arc<dyn Layer>> = tline
+            .layers
+            .read()
+            .unwrap()
+            .iter_historic_layers()
+            .cloned()
+            .collect();
+
+        tline.update_gc_info(Vec::new(), tline.get_last_record_lsn(), Duration::ZERO);
+        let result = tline.gc()?;
+        assert!(result.layers_removed > 0);
+
+        let removed_paths: Vec<PathBuf> = held_layers
+            .iter()
+            .filter_map(|l| l.local_path())
+            .filter(|path| layer_deletion::tombstone_path(path).exists())
+            .collect();
+        assert_eq!(removed_paths.len() as u64, result.layers_removed);
+        for path in &removed_paths {
+            assert!(path.exists(), "the file of a held layer is kept");
+        }
+
+        drop(held_layers);
+        for path in &removed_paths {
+            assert!(
+                !path.exists(),
+                "the file is deleted with the last reference"
+            );
+            assert!(!layer_deletion::tombstone_path(path).exists());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_gc_layer_strategy() -> Result<()> {
+        // Keeps the layers of a timeline under a legal hold
+        struct LegalHold(ZTimelineId);
+        impl LayerStrategy for LegalHold {
+            fn gc_retain_reason(
+                &self,
+                layer: &GcLayer,
+                ctx: &GcContext,
+            ) -> Result<Option<GcRetainReason>> {
+                match layer_strategy::default_gc_retain_reason(layer, ctx)? {
+                    None if ctx.timeline_id == self.0 => Ok(Some(GcRetainReason::Policy)),
+                    reason => Ok(reason),
+                }
+            }
+        }
+
+        let repo = RepoHarness::create("test_gc_layer_strategy")?
+            .load()
+            .with_layer_strategy(Arc::new(LegalHold(TIMELINE_ID)));
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        let test_key = Key::from_hex("012222222233333333444444445500000000").unwrap();
+        let mut keyspace = KeySpaceAccum::new();
+        keyspace.add_key(test_key);
+        let keyspace = keyspace.to_keyspace();
+
+        let flush_layer = || -> Result<()> {
+            let lsn = tline.get_last_record_lsn() + 0x10;
+            let writer = tline.writer();
+            writer.put(
+                test_key,
+                lsn,
+                Value::Image(TEST_IMG(&format!("foo at {lsn}"))),
+            )?;
+            writer.finish_write(lsn);
+            drop(writer);
+            tline.checkpoint(CheckpointConfig::Flush)
+        };
+
+        // Like in test_gc_estimate, the first delta layer could be removed
+        flush_layer()?;
+        flush_layer()?;
+        tline.create_image_layers_at(&[keyspace], tline.get_last_record_lsn())?;
+        flush_layer()?;
+
+        tline.update_gc_info(Vec::new(), tline.get_last_record_lsn(), Duration::ZERO);
+        let result = tline.gc()?;
+        assert_eq!(result.layers_removed, 0);
+        assert_eq!(result.layers_needed_by_policy, 1);
+        assert!(result.bytes_retained.policy > 0);
+        assert_eq!(
+            result.layers_total,
+            result.layers_needed_by_cutoff
+                + result.layers_needed_by_pitr
+                + result.layers_needed_by_branches
+                + result.layers_not_updated
+                + result.layers_too_recent
+                + result.layers_needed_by_policy
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_standby_horizon_lease() -> Result<()> {
+        let harness = RepoHarness::create("test_standby_horizon_lease")?;
+        let repo = harness.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        let test_key = Key::from_hex("012222222233333333444444445500000000").unwrap();
+        let mut lsns = LsnGenerator::new(Lsn(0x10), 0x10);
+        let mut replica_lsn = Lsn(0);
+        for round in 0..3 {
+            for _ in 0..100 {
+                let lsn = lsns.next().unwrap();
+                let writer = tline.writer();
+                writer.put(
+                    test_key,
+                    lsn,
+                    Value::Image(TEST_IMG(&format!("foo at {lsn}"))),
+                )?;
+                writer.finish_write(lsn);
+            }
+            tline.checkpoint(CheckpointConfig::Forced)?;
+            if round == 0 {
+                replica_lsn = tline.get_last_record_lsn();
+            }
+        }
+        let last_lsn = tline.get_last_record_lsn();
+
+        // A read replica still reads at the end of the first round of writes
+        tline.report_standby_feedback(replica_lsn);
+        tline.update_gc_info(Vec::new(), last_lsn, Duration::ZERO);
+        tline.gc()?;
+        assert_eq!(*tline.get_latest_gc_cutoff_lsn(), replica_lsn);
+        assert_eq!(
+            tline.get(test_key, replica_lsn)?,
+            TEST_IMG(&format!("foo at {replica_lsn}"))
+        );
+
+        // The replica stops reporting, and its lease expires
+        harness.advance_time(harness.conf.standby_horizon_lease / 2);
+        tline.gc()?;
+        assert_eq!(*tline.get_latest_gc_cutoff_lsn(), replica_lsn);
+
+        harness.advance_time(harness.conf.standby_horizon_lease);
+        tline.gc()?;
+        assert_eq!(*tline.get_latest_gc_cutoff_lsn(), last_lsn);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_gc_progress() -> Result<()> {
+        let repo = RepoHarness::create("test_gc_progress")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        let test_key = Key::from_hex("012222222233333333444444445500000000").unwrap();
+        for lsn in LsnGenerator::new(Lsn(0x10), 0x10).take(200) {
+            let writer = tline.writer();
+            writer.put(
+                test_key,
+                lsn,
+                Value::Image(TEST_IMG(&format!("foo at {lsn}"))),
+            )?;
+            writer.finish_write(lsn);
+            drop(writer);
+            if lsn.0 % 0x400 == 0 {
+                tline.checkpoint(CheckpointConfig::Forced)?;
+            }
+        }
+        repo.branch_timeline(TIMELINE_ID, NEW_TIMELINE_ID, tline.get_last_record_lsn())?;
+
+        // Nothing to cancel while no GC is running
+        assert!(!repo.cancel_gc());
+        assert!(!repo.get_gc_progress().running);
+
+        let result = repo.gc_iteration(None, 0, Duration::ZERO, true)?;
+        assert!(!result.cancelled);
+        assert!(result.layers_total > 0);
+
+        let progress = repo.get_gc_progress();
+        assert!(!progress.running);
+        assert!(!progress.cancel_requested);
+        assert_eq!(progress.timelines_total, 2);
+        assert_eq!(progress.timelines_done, 2);
+        assert_eq!(progress.current_timeline, None);
+        assert_eq!(progress.layers_examined, result.layers_total);
+        assert_eq!(progress.layers_removed, result.layers_removed);
+        assert_eq!(progress.bytes_removed, result.bytes_removed);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_warm_up_layers() -> Result<()> {
+        let repo = RepoHarness::create("test_warm_up_layers")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        let mut test_key = Key::from_hex("012222222233333333444444445500000000").unwrap();
+        let mut lsn = Lsn(0x10);
+        for _ in 0..2 {
+            for blknum in 0..100 {
+                test_key.field6 = blknum;
+                let writer = tline.writer();
+                writer.put(
+                    test_key,
+                    lsn,
+                    Value::Image(TEST_IMG(&format!("{blknum} at {lsn}"))),
+                )?;
+                writer.finish_write(lsn);
+                drop(writer);
+                lsn = Lsn(lsn.0 + 0x10);
+            }
+            tline.checkpoint(CheckpointConfig::Forced)?;
+        }
+        let last_lsn = tline.get_last_record_lsn();
+
+        let (layers, bytes) = tline.warm_up_layers(last_lsn)?;
+        assert!(layers > 0);
+        assert!(bytes > 0);
+        // Nothing was written before the first record
+        assert_eq!(tline.warm_up_layers(Lsn(0x8))?, (0, 0));
+
+        // A fresh branch has no layers of its own, its pages come from the ancestor
+        let new_tline_id = ZTimelineId::generate();
+        repo.branch_timeline(TIMELINE_ID, new_tline_id, last_lsn)?;
+        let new_tline = repo.get_timeline_load(new_tline_id)?;
+        assert_eq!(new_tline.warm_up_layers(last_lsn)?, (layers, bytes));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_prefetch_ancestor_layers() -> Result<()> {
+        let repo = RepoHarness::create("test_prefetch_ancestor_layers")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+        assert_eq!(tline.prefetch_ancestor_layers()?, (0, 0));
+
+        let mut test_key = Key::from_hex("012222222233333333444444445500000000").unwrap();
+        let mut lsn = Lsn(0x10);
+        for blknum in 0..100 {
+            test_key.field6 = blknum;
+            let writer = tline.writer();
+            writer.put(
+                test_key,
+                lsn,
+                Value::Image(TEST_IMG(&format!("{blknum} at {lsn}"))),
+            )?;
+            writer.finish_write(lsn);
+            drop(writer);
+            lsn = Lsn(lsn.0 + 0x10);
+        }
+        tline.checkpoint(CheckpointConfig::Forced)?;
+        let branch_lsn = tline.get_last_record_lsn();
+
+        let new_tline_id = ZTimelineId::generate();
+        repo.branch_timeline(TIMELINE_ID, new_tline_id, branch_lsn)?;
+        let new_tline = repo.get_timeline_load(new_tline_id)?;
+        let (layers, bytes) = new_tline.prefetch_ancestor_layers()?;
+        assert!(layers > 0);
+        assert_eq!((layers, bytes), tline.warm_up_layers(branch_lsn)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_detach_stops_timeline() -> Result<()> {
+        let repo = RepoHarness::create("test_detach_stops_timeline")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        let test_key = Key::from_hex("012222222233333333444444445500000000").unwrap();
+        let writer = tline.writer();
+        writer.put(test_key, Lsn(0x10), Value::Image(TEST_IMG("foo at 0x10")))?;
+        writer.finish_write(Lsn(0x10));
+        drop(writer);
+        tline.checkpoint(CheckpointConfig::Forced)?;
+        assert_eq!(tline.get(test_key, Lsn(0x10))?, TEST_IMG("foo at 0x10"));
+
+        repo.detach_timeline(TIMELINE_ID)?;
+        assert!(tline.is_stopping());
+        assert!(repo.get_timeline(TIMELINE_ID).is_none());
+
+        // Reads and waits for WAL fail right away, instead of finding no layers
+        // or waiting for WAL that never comes
+        let err = tline.get(test_key, Lsn(0x10)).unwrap_err();
+        assert!(err.to_string().contains("being detached"), "{err}");
+        assert!(tline.wait_lsn(Lsn(0x20)).is_err());
+
+        tline.close_layers();
+        assert_eq!(
+            tline.layers.read().unwrap().iter_historic_layers().count(),
+            0
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_compaction_skips_idle_timelines() -> Result<()> {
+        let repo = RepoHarness::create("test_compaction_skips_idle_timelines")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+        let compaction_needed = || tline.compaction_needed.load(atomic::Ordering::Relaxed);
+        assert!(compaction_needed());
+
+        repo.compaction_iteration()?;
+        assert!(!compaction_needed());
+        repo.compaction_iteration()?;
+        assert!(!compaction_needed());
+
+        let test_key = Key::from_hex("012222222233333333444444445500000000").unwrap();
+        let writer = tline.writer();
+        writer.put(test_key, Lsn(0x10), Value::Image(TEST_IMG("foo at 0x10")))?;
+        writer.finish_write(Lsn(0x10));
+        drop(writer);
+        assert!(compaction_needed());
+        repo.compaction_iteration()?;
+        assert!(!compaction_needed());
+
+        // Flushing the written data creates a layer that compaction has to look at
+        tline.checkpoint(CheckpointConfig::Flush)?;
+        assert!(compaction_needed());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_gc_compacts_level0_deltas() -> Result<()> {
+        let repo = RepoHarness::create("test_gc_compacts_level0_deltas")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+        let level0_deltas = || {
+            tline
+                .layers
+                .read()
+                .unwrap()
+                .get_level0_deltas()
+                .unwrap()
+                .len()
+        };
+
+        let test_key = Key::from_hex("012222222233333333444444445500000000").unwrap();
+        let mut lsn = Lsn(0x10);
+        let mut flush_layer = || -> Result<()> {
+            let writer = tline.writer();
+            writer.put(
+                test_key,
+                lsn,
+                Value::Image(TEST_IMG(&format!("foo at {lsn}"))),
+            )?;
+            writer.finish_write(lsn);
+            drop(writer);
+            lsn = Lsn(lsn.0 + 0x10);
+            tline.checkpoint(CheckpointConfig::Flush)
+        };
+
+        // Too few Level 0 layers, GC leaves them to the compaction thread
+        for _ in 1..repo.get_compaction_threshold() {
+            flush_layer()?;
+        }
+        tline.update_gc_info(Vec::new(), tline.get_last_record_lsn(), Duration::ZERO);
+        tline.gc()?;
+        assert_eq!(level0_deltas(), repo.get_compaction_threshold() - 1);
+        assert!(tline.compaction_needed.load(atomic::Ordering::Relaxed));
+
+        // Enough of them, GC compacts them while it's at it
+        flush_layer()?;
+        tline.update_gc_info(Vec::new(), tline.get_last_record_lsn(), Duration::ZERO);
+        tline.gc()?;
+        assert_eq!(level0_deltas(), 0);
+        assert!(!tline.compaction_needed.load(atomic::Ordering::Relaxed));
+        assert_eq!(
+            tline.get(test_key, lsn)?,
+            TEST_IMG(&format!("foo at {}", Lsn(lsn.0 - 0x10)))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_image_creation_follows_flushes() -> Result<()> {
+        let repo = RepoHarness::create("test_image_creation_follows_flushes")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+        let image_creation_needed = || tline.image_creation_needed.load(atomic::Ordering::Relaxed);
+        assert!(image_creation_needed());
+
+        repo.image_creation_iteration()?;
+        assert!(!image_creation_needed());
+
+        // Writes alone don't change the layer files, and neither does compaction
+        let test_key = Key::from_hex("012222222233333333444444445500000000").unwrap();
+        let writer = tline.writer();
+        writer.put(test_key, Lsn(0x10), Value::Image(TEST_IMG("foo at 0x10")))?;
+        writer.finish_write(Lsn(0x10));
+        drop(writer);
+        repo.compaction_iteration()?;
+        assert!(!image_creation_needed());
+
+        // A flush creates a delta layer that the image builder has to look at
+        tline.checkpoint(CheckpointConfig::Flush)?;
+        assert!(image_creation_needed());
+        repo.image_creation_iteration()?;
+        assert!(!image_creation_needed());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_layer_size_rollover() -> Result<()> {
+        let mut harness = RepoHarness::create("test_open_layer_size_rollover")?;
+        let max_open_layer_size = 8192;
+        harness.conf = Box::leak(Box::new(PageServerConf {
+            max_open_layer_size,
+            ..harness.conf.clone()
+        }));
+        let repo = harness.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+        let first_freeze_at = tline.last_freeze_at.load();
+
+        // Far less WAL than checkpoint_distance, but the values pile up in the open layer
+        let mut test_key = Key::from_hex("012222222233333333444444445500000000").unwrap();
+        let mut lsn = Lsn(0x10);
+        for blknum in 0..1000 {
+            test_key.field6 = blknum;
+            let writer = tline.writer();
+            writer.put(
+                test_key,
+                lsn,
+                Value::Image(TEST_IMG(&format!("{blknum} at {lsn}"))),
+            )?;
+            writer.finish_write(lsn);
+            drop(writer);
+            tline.check_checkpoint_distance()?;
+            assert!(tline.open_layer_size(lsn) < max_open_layer_size);
+            lsn = Lsn(lsn.0 + 0x10);
+        }
+        assert!(tline.last_freeze_at.load() > first_freeze_at);
+
+        tline.checkpoint(CheckpointConfig::Forced)?;
+        let last_lsn = tline.get_last_record_lsn();
+        for blknum in 0..1000 {
+            test_key.field6 = blknum;
+            let lsn = Lsn(0x10 + blknum as u64 * 0x10);
+            assert_eq!(
+                tline.get(test_key, last_lsn)?,
+                TEST_IMG(&format!("{blknum} at {lsn}"))
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_branch_remote_timeline() -> Result<()> {
+        let repo = RepoHarness::create("test_branch_remote_timeline")?.load();
+        // Metadata of TIMELINE_ID, that is only present in the remote storage
+        let src_metadata = TimelineMetadata::new(
+            Lsn(0x40),
+            Some(Lsn(0x38)),
+            None,
+            Lsn(0),
+            Lsn(0x20),
+            Lsn(0x10),
+        );
+
+        assert!(repo
+            .branch_remote_timeline(TIMELINE_ID, NEW_TIMELINE_ID, Lsn(0x50), &src_metadata)
+            .is_err());
+        assert!(repo
+            .branch_remote_timeline(TIMELINE_ID, NEW_TIMELINE_ID, Lsn(0x18), &src_metadata)
+            .is_err());
+
+        repo.branch_remote_timeline(TIMELINE_ID, NEW_TIMELINE_ID, Lsn(0x40), &src_metadata)?;
+        match repo.get_timeline(NEW_TIMELINE_ID) {
+            Some(RepositoryTimeline::Unloaded { metadata }) => {
+                assert_eq!(metadata.ancestor_timeline(), Some(TIMELINE_ID));
+                assert_eq!(metadata.ancestor_lsn(), Lsn(0x40));
+                assert_eq!(metadata.prev_record_lsn(), Some(Lsn(0x38)));
+                assert_eq!(metadata.latest_gc_cutoff_lsn(), Lsn(0x20));
+            }
+            _ => panic!("branch of a remote timeline should be registered unloaded"),
+        }
+
+        // The branch can't be loaded before its ancestor is downloaded, and GC skips it
+        assert_eq!(
+            repo.missing_local_ancestor(NEW_TIMELINE_ID),
+            Some(TIMELINE_ID)
+        );
+        assert!(repo.get_timeline_load(NEW_TIMELINE_ID).is_err());
+        repo.gc_iteration(None, 0, Duration::ZERO, false)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_create_image_layers_at() -> Result<()> {
+        let repo = RepoHarness::create("test_create_image_layers_at")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        let mut test_key = Key::from_hex("012222222233333333444444445500000000").unwrap();
+        let mut keyspace = KeySpaceAccum::new();
+        keyspace.add_range(test_key..test_key.add(50));
+        let keyspace = keyspace.to_keyspace();
+
+        let mut lsn = Lsn(0x10);
+        let mut image_lsn = Lsn(0);
+        for round in 0..2 {
+            for blknum in 0..50 {
+                test_key.field6 = blknum;
+                let writer = tline.writer();
+                writer.put(
+                    test_key,
+                    lsn,
+                    Value::Image(TEST_IMG(&format!("{blknum} round {round}"))),
+                )?;
+                writer.finish_write(lsn);
+                drop(writer);
+                lsn = Lsn(lsn.0 + 0x10);
+            }
+            if round == 0 {
+                image_lsn = tline.get_last_record_lsn();
+            }
+        }
+
+        // The WAL up to the requested LSN is still in memory at this point
+        let new_paths = tline.create_image_layers_at(&[keyspace.clone()], image_lsn)?;
+        assert_eq!(new_paths.len(), 1);
+        assert!(new_paths[0].exists());
+        // The image already exists, nothing to do the second time
+        assert!(tline
+            .create_image_layers_at(&[keyspace], image_lsn)?
+            .is_empty());
+
+        for blknum in 0..50 {
+            test_key.field6 = blknum;
+            assert_eq!(
+                tline.get(test_key, image_lsn)?,
+                TEST_IMG(&format!("{blknum} round 0"))
+            );
+            assert_eq!(
+                tline.get(test_key, tline.get_last_record_lsn())?,
+                TEST_IMG(&format!("{blknum} round 1"))
+            );
+        }
+
+        assert!(tline
+            .create_image_layers_at(&[], Lsn(tline.get_last_record_lsn().0 + 0x10))
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_flatten_branch_point() -> Result<()> {
+        let repo = RepoHarness::create("test_flatten_branch_point")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        let mut test_key = Key::from_hex("012222222233333333444444445500000000").unwrap();
+        let mut keyspace = KeySpaceAccum::new();
+        keyspace.add_range(test_key..test_key.add(20));
+        let keyspace = keyspace.to_keyspace();
+
+        let mut lsn = Lsn(0x10);
+        for blknum in 0..20 {
+            test_key.field6 = blknum;
+            let writer = tline.writer();
+            writer.put(
+                test_key,
+                lsn,
+                Value::Image(TEST_IMG(&format!("{blknum} on main"))),
+            )?;
+            writer.finish_write(lsn);
+            drop(writer);
+            lsn = Lsn(lsn.0 + 0x10);
+        }
+        tline.checkpoint(CheckpointConfig::Forced)?;
+        let branch_lsn = tline.get_last_record_lsn();
+
+        repo.branch_timeline(TIMELINE_ID, NEW_TIMELINE_ID, branch_lsn)?;
+        let new_tline = repo.get_timeline_load(NEW_TIMELINE_ID)?;
+
+        // Overwrite the first block on the branch
+        test_key.field6 = 0;
+        let writer = new_tline.writer();
+        writer.put(test_key, lsn, Value::Image(TEST_IMG("0 on branch")))?;
+        writer.finish_write(lsn);
+        drop(writer);
+
+        let hops_at = |key: Key, lsn: Lsn| -> Result<usize> {
+            let mut reconstruct_state = ValueReconstructState {
+                records: Vec::new(),
+                img: None,
+            };
+            let mut traversal = AncestorTraversal::default();
+            new_tline.traverse_layers(key, lsn, &mut reconstruct_state, None, &mut traversal)?;
+            Ok(traversal.hops)
+        };
+
+        test_key.field6 = 1;
+        assert_eq!(hops_at(test_key, branch_lsn)?, 1);
+
+        let new_paths = new_tline.create_branch_point_images(&keyspace, branch_lsn)?;
+        assert!(!new_paths.is_empty());
+
+        for blknum in 0..20 {
+            test_key.field6 = blknum;
+            assert_eq!(hops_at(test_key, branch_lsn)?, 0);
+            assert_eq!(
+                new_tline.get(test_key, branch_lsn)?,
+                TEST_IMG(&format!("{blknum} on main"))
+            );
+        }
+        test_key.field6 = 0;
+        assert_eq!(new_tline.get(test_key, lsn)?, TEST_IMG("0 on branch"));
+        // Reads below the branch point still go to the ancestor
+        assert_eq!(hops_at(test_key, Lsn(0x20))?, 1);
+
+        // Going over the limit schedules the flattening, once
+        assert!(!new_tline.flattening_needed.load(atomic::Ordering::Relaxed));
+        new_tline.record_ancestor_hops(repo.conf.max_ancestor_hops);
+        assert!(!new_tline.flattening_needed.load(atomic::Ordering::Relaxed));
+        new_tline.record_ancestor_hops(repo.conf.max_ancestor_hops + 1);
+        assert!(new_tline.flattening_needed.load(atomic::Ordering::Relaxed));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_image_layer_excluded_forks() -> Result<()> {
+        let repo = RepoHarness::create("test_image_layer_excluded_forks")?.load();
+        repo.update_tenant_config(TenantConfOpt {
+            image_layer_excluded_forks: Some(ForkSet::REBUILDABLE),
+            ..TenantConfOpt::default()
+        })?;
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        let main_key = Key {
+            field1: 0x00,
+            field2: 1663,
+            field3: 13008,
+            field4: 16384,
+            field5: pg_constants::MAIN_FORKNUM,
+            field6: 0,
+        };
+        let fsm_key = Key {
+            field5: pg_constants::FSM_FORKNUM,
+            ..main_key
+        };
+        let mut keyspace = KeySpaceAccum::new();
+        keyspace.add_range(main_key..main_key.add(10));
+        keyspace.add_range(fsm_key..fsm_key.add(10));
+        let keyspace = keyspace.to_keyspace();
+
+        let mut lsn = Lsn(0x10);
+        for blknum in 0..10 {
+            let writer = tline.writer();
+            writer.put(main_key.add(blknum), lsn, Value::Image(TEST_IMG("main")))?;
+            writer.put(fsm_key.add(blknum), lsn, Value::Image(TEST_IMG("fsm")))?;
+            writer.finish_write(lsn);
+            drop(writer);
+            lsn = Lsn(lsn.0 + 0x10);
+        }
+        let image_lsn = tline.get_last_record_lsn();
+        assert_eq!(
+            tline.create_image_layers_at(&[keyspace], image_lsn)?.len(),
+            1
+        );
+
+        // The FSM pages are not in the image layer, and read as zero pages
+        for blknum in 0..10 {
+            assert_eq!(
+                tline.get(main_key.add(blknum), image_lsn)?,
+                TEST_IMG("main")
+            );
+            assert_eq!(tline.get(fsm_key.add(blknum), image_lsn)?, ZERO_PAGE);
+        }
+
+        // Newer versions are read from the delta layers above the image
+        let writer = tline.writer();
+        writer.put(fsm_key, lsn, Value::Image(TEST_IMG("fsm again")))?;
+        writer.finish_write(lsn);
+        drop(writer);
+        assert_eq!(tline.get(fsm_key, lsn)?, TEST_IMG("fsm again"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_append_only_relation_deltas() -> Result<()> {
+        let harness = RepoHarness::create("test_append_only_relation_deltas")?;
+        let repo = harness.load();
+        repo.update_tenant_config(TenantConfOpt {
+            checkpoint_distance: Some(64 * 1024),
+            ..TenantConfOpt::default()
+        })?;
+        let log: RelFileNode = "1663/13008/16384".parse()?;
+        assert!(repo.set_relation_append_only(log, true)?);
+        assert!(!repo.set_relation_append_only(log, true)?);
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        let log_key = Key {
+            field1: 0x00,
+            field2: log.spcnode,
+            field3: log.dbnode,
+            field4: log.relnode,
+            field5: pg_constants::MAIN_FORKNUM,
+            field6: 0,
+        };
+        let other_key = Key {
+            field4: log.relnode + 1,
+            ..log_key
+        };
+
+        // Enough level 0 layers to compact, with new blocks of both relations
+        let page = Bytes::from(vec![0xab; 8192]);
+        let mut lsn = Lsn(0x10);
+        for round in 0..tline.get_compaction_threshold() as u32 {
+            let writer = tline.writer();
+            for blknum in round * 16..(round + 1) * 16 {
+                writer.put(log_key.add(blknum), lsn, Value::Image(page.clone()))?;
+                writer.put(other_key.add(blknum), lsn, Value::Image(page.clone()))?;
+            }
+            writer.finish_write(lsn);
+            drop(writer);
+            lsn = Lsn(lsn.0 + 0x10);
+            tline.checkpoint(CheckpointConfig::Flush)?;
+        }
+        tline.compact_deltas()?;
+
+        // The delta layers of the append-only relation are larger, so there
+        // are fewer of them for the same data
+        let deltas_of = |start: Key| {
+            tline
+                .layer_files()
+                .iter()
+                .filter(|l| {
+                    l.is_incremental
+                        && l.key_range.start >= start
+                        && l.key_range.end <= start.add(u32::MAX)
+                })
+                .count()
+        };
+        let (log_deltas, other_deltas) = (deltas_of(log_key), deltas_of(other_key));
+        assert!(log_deltas > 0);
+        assert!(
+            log_deltas < other_deltas,
+            "{log_deltas} delta layers for the append-only relation, {other_deltas} for the other"
+        );
+
+        // The hints are saved
+        drop(repo);
+        let repo = harness.load();
+        assert_eq!(repo.get_append_only_relations(), vec![log]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_append_only_relation_images() -> Result<()> {
+        let repo = RepoHarness::create("test_append_only_relation_images")?.load();
+        repo.update_tenant_config(TenantConfOpt {
+            compaction_threshold: Some(2),
+            image_creation_threshold: Some(1),
+            ..TenantConfOpt::default()
+        })?;
+        let log: RelFileNode = "1663/13008/16384".parse()?;
+        repo.set_relation_append_only(log, true)?;
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        let log_key = Key {
+            field1: 0x00,
+            field2: log.spcnode,
+            field3: log.dbnode,
+            field4: log.relnode,
+            field5: pg_constants::MAIN_FORKNUM,
+            field6: 0,
+        };
+        let other_key = Key {
+            field4: log.relnode + 1,
+            ..log_key
+        };
+        let keyspace_of = |key: Key| {
+            let mut keyspace = KeySpaceAccum::new();
+            keyspace.add_key(key);
+            keyspace.to_keyspace()
+        };
+        let (log_keyspace, other_keyspace) = (keyspace_of(log_key), keyspace_of(other_key));
+
+        // Each round of compaction adds an L1 delta layer on top of both keys
+        let add_l1_delta = || -> Result<()> {
+            for _ in 0..tline.get_compaction_threshold() {
+                let lsn = tline.get_last_record_lsn() + 0x10;
+                let writer = tline.writer();
+                for key in [log_key, other_key] {
+                    writer.put(key, lsn, Value::Image(TEST_IMG(&format!("{key} at {lsn}"))))?;
+                }
+                writer.finish_write(lsn);
+                drop(writer);
+                tline.checkpoint(CheckpointConfig::Flush)?;
+            }
+            tline.compact_deltas()
+        };
+
+        add_l1_delta()?;
+        let lsn = tline.get_last_record_lsn();
+        assert!(tline.time_for_new_image_layer(&other_keyspace, lsn)?);
+        assert!(!tline.time_for_new_image_layer(&log_keyspace, lsn)?);
+
+        // The append-only relation gets an image too, after more deltas
+        for _ in 1..APPEND_ONLY_IMAGE_THRESHOLD_FACTOR {
+            add_l1_delta()?;
+        }
+        let lsn = tline.get_last_record_lsn();
+        assert!(tline.time_for_new_image_layer(&log_keyspace, lsn)?);
+        tline.create_image_layer(&log_keyspace, lsn)?;
+        tline.create_image_layer(&other_keyspace, lsn)?;
+        add_l1_delta()?;
+
+        // and GC can remove the delta layers under it
+        tline.update_gc_info(Vec::new(), tline.get_last_record_lsn(), Duration::ZERO);
+        let result = tline.gc()?;
+        assert!(result.layers_removed > 0);
+        let lsn = tline.get_last_record_lsn();
+        assert_eq!(
+            tline.get(log_key, lsn)?,
+            TEST_IMG(&format!("{log_key} at {lsn}"))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_random_updates() -> Result<()> {
+        let repo = RepoHarness::create("test_random_updates")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        const NUM_KEYS: usize = 1000;
+
+        let mut test_key = Key::from_hex("012222222233333333444444445500000000").unwrap();
+
+        let mut keyspace = KeySpaceAccum::new();
+
+        // Track when each page was last modified. Used to assert that
+        // a read sees the latest page version.
+        let mut updated = [Lsn(0); NUM_KEYS];
+
+        let mut lsn = Lsn(0);
+        #[allow(clippy::needless_range_loop)]
+        for blknum in 0..NUM_KEYS {
+            lsn = Lsn(lsn.0 + 0x10);
+            test_key.field6 = blknum as u32;
+            let writer = tline.writer();
             writer.put(
                 test_key,
                 lsn,
@@ -2892,4 +5705,229 @@ pub mod tests {
         }
         Ok(())
     }
+
+    /// Expected contents of a timeline in `test_random_branch_gc`.
+    struct ModelTimeline {
+        ancestor: Option<(ZTimelineId, Lsn)>,
+        /// Values written on this timeline, by key number and LSN.
+        writes: BTreeMap<(u32, Lsn), String>,
+        /// The highest GC cutoff seen on the timeline. Nothing is checked
+        /// below it: the cutoff kept in memory can move back on restart, if
+        /// the metadata wasn't saved after the GC.
+        gc_cutoff: Lsn,
+    }
+
+    fn model_value(
+        model: &BTreeMap<ZTimelineId, ModelTimeline>,
+        timeline_id: ZTimelineId,
+        blknum: u32,
+        lsn: Lsn,
+    ) -> Option<String> {
+        let timeline = &model[&timeline_id];
+        if let Some((_, value)) = timeline
+            .writes
+            .range((blknum, Lsn(0))..=(blknum, lsn))
+            .next_back()
+        {
+            return Some(value.clone());
+        }
+        let (ancestor_id, ancestor_lsn) = timeline.ancestor?;
+        model_value(model, ancestor_id, blknum, min(lsn, ancestor_lsn))
+    }
+
+    ///
+    /// Read all the keys of all the timelines at the LSNs that must still be
+    /// readable: from the GC cutoff, or the branch point if it's later, up to
+    /// the last record. Everything written in that range must read back as the
+    /// model says, including the versions inherited from the ancestors.
+    ///
+    fn check_model(
+        repo: &LayeredRepository,
+        model: &mut BTreeMap<ZTimelineId, ModelTimeline>,
+        num_keys: u32,
+        context: &str,
+    ) -> Result<()> {
+        let mut test_key = Key::from_hex("012222222233333333444444445500000000").unwrap();
+        let timeline_ids = model.keys().copied().collect::<Vec<_>>();
+        for timeline_id in timeline_ids {
+            let tline = repo
+                .get_timeline_load(timeline_id)
+                .with_context(|| format!("{context}: timeline {timeline_id} is missing"))?;
+            let entry = model.get_mut(&timeline_id).unwrap();
+            entry.gc_cutoff = max(entry.gc_cutoff, *tline.get_latest_gc_cutoff_lsn());
+
+            let last_lsn = tline.get_last_record_lsn();
+            let ancestor_lsn = entry.ancestor.map_or(Lsn(0), |(_, lsn)| lsn);
+            let start_lsn = max(entry.gc_cutoff, ancestor_lsn);
+            if start_lsn > last_lsn {
+                continue;
+            }
+            let mut lsns = entry
+                .writes
+                .keys()
+                .map(|&(_, lsn)| lsn)
+                .filter(|lsn| (start_lsn..=last_lsn).contains(lsn))
+                .collect::<BTreeSet<_>>();
+            lsns.insert(start_lsn);
+            lsns.insert(last_lsn);
+
+            for lsn in lsns {
+                for blknum in 0..num_keys {
+                    let expected = match model_value(model, timeline_id, blknum, lsn) {
+                        Some(value) => value,
+                        None => continue,
+                    };
+                    test_key.field6 = blknum;
+                    let value = tline.get(test_key, lsn).with_context(|| {
+                        format!("{context}: cannot read {blknum} at {lsn} on {timeline_id}")
+                    })?;
+                    assert_eq!(
+                        value,
+                        TEST_IMG(&expected),
+                        "{context}: wrong value of {blknum} at {lsn} on {timeline_id}"
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    ///
+    /// Run random sequences of writes, checkpoints, compactions, branches,
+    /// GC, detaches and restarts on a tree of timelines, and check after each
+    /// step that nothing that should be readable was lost.
+    ///
+    /// proptest isn't available to us, so the sequences come from a seeded
+    /// RNG instead. The seed is printed in the failure messages, add it to
+    /// SEEDS to reproduce a failure.
+    ///
+    #[test]
+    fn test_random_branch_gc() -> Result<()> {
+        const SEEDS: [u64; 3] = [1, 2, 3];
+        const NUM_STEPS: usize = 200;
+        const NUM_KEYS: u32 = 20;
+        const MAX_TIMELINES: usize = 8;
+
+        let seeds = SEEDS.into_iter().chain([thread_rng().gen()]);
+        for seed in seeds {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let harness = RepoHarness::create("test_random_branch_gc")?;
+            let mut repo = harness.load();
+            repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+            let mut model = BTreeMap::new();
+            model.insert(
+                TIMELINE_ID,
+                ModelTimeline {
+                    ancestor: None,
+                    writes: BTreeMap::new(),
+                    gc_cutoff: Lsn(0),
+                },
+            );
+
+            let mut test_key = Key::from_hex("012222222233333333444444445500000000").unwrap();
+            let mut lsn = Lsn(0);
+            for step in 0..NUM_STEPS {
+                let timeline_id = *model.keys().nth(rng.gen_range(0..model.len())).unwrap();
+                let context;
+                match rng.gen_range(0..100) {
+                    0..=49 => {
+                        context = format!("seed {seed}, step {step}: write to {timeline_id}");
+                        let tline = repo.get_timeline_load(timeline_id)?;
+                        for _ in 0..rng.gen_range(1..=5) {
+                            lsn = Lsn(lsn.0 + 0x10);
+                            let blknum = rng.gen_range(0..NUM_KEYS);
+                            let value = format!("{} {} at {}", timeline_id, blknum, lsn);
+                            test_key.field6 = blknum;
+                            let writer = tline.writer();
+                            writer.put(test_key, lsn, Value::Image(TEST_IMG(&value)))?;
+                            writer.finish_write(lsn);
+                            drop(writer);
+                            let entry = model.get_mut(&timeline_id).unwrap();
+                            entry.writes.insert((blknum, lsn), value);
+                        }
+                    }
+                    50..=59 => {
+                        context = format!("seed {seed}, step {step}: checkpoint {timeline_id}");
+                        let tline = repo.get_timeline_load(timeline_id)?;
+                        tline.checkpoint(CheckpointConfig::Forced)?;
+                        tline.compact()?;
+                    }
+                    60..=69 => {
+                        let tline = repo.get_timeline_load(timeline_id)?;
+                        let entry = &model[&timeline_id];
+                        let lowest_lsn = entry.ancestor.map_or(Lsn(0), |(_, lsn)| lsn);
+                        let last_lsn = tline.get_last_record_lsn();
+                        let branch_lsn = Lsn(lowest_lsn.0
+                            + rng.gen_range(0..=(last_lsn.0 - lowest_lsn.0) / 0x10) * 0x10);
+                        let new_timeline_id = ZTimelineId::generate();
+                        context = format!(
+                            "seed {seed}, step {step}: branch {new_timeline_id} from {timeline_id} at {branch_lsn}"
+                        );
+                        let gc_cutoff = *tline.get_latest_gc_cutoff_lsn();
+                        if branch_lsn < gc_cutoff {
+                            assert!(
+                                repo.branch_timeline(timeline_id, new_timeline_id, branch_lsn)
+                                    .is_err(),
+                                "{context}: branching below the GC cutoff {gc_cutoff} succeeded"
+                            );
+                        } else if branch_lsn >= entry.gc_cutoff && model.len() < MAX_TIMELINES {
+                            let child_gc_cutoff = entry.gc_cutoff;
+                            repo.branch_timeline(timeline_id, new_timeline_id, branch_lsn)
+                                .with_context(|| context.clone())?;
+                            model.insert(
+                                new_timeline_id,
+                                ModelTimeline {
+                                    ancestor: Some((timeline_id, branch_lsn)),
+                                    writes: BTreeMap::new(),
+                                    gc_cutoff: child_gc_cutoff,
+                                },
+                            );
+                        }
+                    }
+                    70..=84 => {
+                        let horizon = rng.gen_range(0..=8) * 0x10;
+                        let target = if rng.gen() { Some(timeline_id) } else { None };
+                        let checkpoint_before_gc = rng.gen();
+                        context = format!(
+                            "seed {seed}, step {step}: gc {target:?} with horizon {horizon}, checkpoint {checkpoint_before_gc}"
+                        );
+                        repo.gc_iteration(target, horizon, Duration::ZERO, checkpoint_before_gc)
+                            .with_context(|| context.clone())?;
+                    }
+                    85..=92 => {
+                        context = format!("seed {seed}, step {step}: detach {timeline_id}");
+                        let has_children = model
+                            .values()
+                            .any(|t| matches!(t.ancestor, Some((id, _)) if id == timeline_id));
+                        if has_children {
+                            assert!(
+                                repo.detach_timeline(timeline_id).is_err(),
+                                "{context}: detached a timeline with children"
+                            );
+                        } else if model.len() > 1 {
+                            repo.detach_timeline(timeline_id)
+                                .with_context(|| context.clone())?;
+                            fs::remove_dir_all(harness.timeline_path(&timeline_id))?;
+                            model.remove(&timeline_id);
+                            assert!(
+                                repo.get_timeline_load(timeline_id).is_err(),
+                                "{context}: timeline is still available"
+                            );
+                        }
+                    }
+                    _ => {
+                        context = format!("seed {seed}, step {step}: restart");
+                        repo.checkpoint()?;
+                        drop(repo);
+                        repo = harness.load();
+                    }
+                }
+
+                check_model(&repo, &mut model, NUM_KEYS, &context)?;
+            }
+        }
+
+        Ok(())
+    }
 }
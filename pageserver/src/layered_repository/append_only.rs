@@ -0,0 +1,261 @@
+//!
+//! Append-only hints for the relations of a tenant.
+//!
+//! The compute, or the operator, can tell that a relation is append-only, like
+//! an event log table: its new rows go to new blocks, and the old blocks are
+//! not modified anymore, except for hint bits and freezing. The layer policies
+//! take it into account:
+//!
+//! * The partitions of the key space that only hold blocks of append-only
+//!   relations need [`APPEND_ONLY_IMAGE_THRESHOLD_FACTOR`] times more deltas
+//!   than the others for a new image layer. Every page version in the delta
+//!   layers starts from a full page image or a record that initializes the
+//!   page, so the images of the old blocks mostly repeat what the deltas hold.
+//!   They are still created now and then: GC only removes the delta layers
+//!   that a newer image layer covers.
+//! * The L1 delta layers that compaction creates for the blocks of append-only
+//!   relations grow [`APPEND_ONLY_DELTA_SIZE_FACTOR`] times larger than the
+//!   others, so there are fewer of them to search.
+//!
+//! The hints are set for all the timelines of the tenant, since the branches
+//! share the relations, and saved in `append_only_relations.json` in the tenant
+//! directory.
+//!
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{anyhow, Context, Result};
+use postgres_ffi::Oid;
+use serde::{Deserialize, Serialize};
+
+use crate::config::PageServerConf;
+use crate::keyspace::KeySpace;
+use crate::repository::Key;
+use utils::zid::ZTenantId;
+
+const APPEND_ONLY_RELATIONS_FILE_NAME: &str = "append_only_relations.json";
+
+/// How much larger than the others the L1 delta layers of the append-only
+/// relations can get.
+pub const APPEND_ONLY_DELTA_SIZE_FACTOR: u64 = 4;
+
+/// How many times more deltas than `image_creation_threshold` the partitions
+/// of the append-only relations need for a new image layer.
+pub const APPEND_ONLY_IMAGE_THRESHOLD_FACTOR: usize = 4;
+
+///
+/// A relation with all its forks, like PostgreSQL's `RelFileNode`. Written as
+/// `<spcnode>/<dbnode>/<relnode>`, e.g. `1663/13008/16384`.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct RelFileNode {
+    pub spcnode: Oid,
+    pub dbnode: Oid,
+    pub relnode: Oid,
+}
+
+impl RelFileNode {
+    /// The relation that the key of a relation block or size belongs to.
+    fn of_key(key: &Key) -> Option<RelFileNode> {
+        if key.field1 != 0x00 || key.field4 == 0 {
+            return None;
+        }
+        Some(RelFileNode {
+            spcnode: key.field2,
+            dbnode: key.field3,
+            relnode: key.field4,
+        })
+    }
+}
+
+impl fmt::Display for RelFileNode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}/{}", self.spcnode, self.dbnode, self.relnode)
+    }
+}
+
+impl FromStr for RelFileNode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || anyhow!("invalid relation '{s}', expected <spcnode>/<dbnode>/<relnode>");
+        let oids = s
+            .split('/')
+            .map(|oid| oid.parse::<Oid>().map_err(|_| invalid()))
+            .collect::<Result<Vec<_>>>()?;
+        match oids[..] {
+            [spcnode, dbnode, relnode] if relnode != 0 => Ok(RelFileNode {
+                spcnode,
+                dbnode,
+                relnode,
+            }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+///
+/// The append-only relations of a tenant.
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AppendOnlyRelations {
+    rels: BTreeSet<RelFileNode>,
+}
+
+impl AppendOnlyRelations {
+    pub fn path(conf: &'static PageServerConf, tenant_id: ZTenantId) -> PathBuf {
+        conf.tenant_path(&tenant_id)
+            .join(APPEND_ONLY_RELATIONS_FILE_NAME)
+    }
+
+    /// Load the saved hints, none if the file doesn't exist.
+    pub fn load(path: &Path) -> Result<Self> {
+        match fs::read(path) {
+            Ok(contents) => {
+                let rels = serde_json::from_slice::<Vec<RelFileNode>>(&contents)
+                    .with_context(|| format!("Failed to parse {}", path.display()))?;
+                Ok(AppendOnlyRelations {
+                    rels: rels.into_iter().collect(),
+                })
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let temp_path = path.with_extension("tmp");
+        let mut file = File::create(&temp_path)
+            .with_context(|| format!("Failed to create file '{}'", temp_path.display()))?;
+        file.write_all(&serde_json::to_vec(&self.list())?)?;
+        file.sync_all()?;
+        fs::rename(&temp_path, path)
+            .with_context(|| format!("Failed to persist '{}'", path.display()))?;
+        if let Some(dir) = path.parent() {
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// Mark the relation as append-only or not. Returns whether that changed
+    /// anything.
+    pub fn set(&mut self, rel: RelFileNode, append_only: bool) -> bool {
+        if append_only {
+            self.rels.insert(rel)
+        } else {
+            self.rels.remove(&rel)
+        }
+    }
+
+    pub fn list(&self) -> Vec<RelFileNode> {
+        self.rels.iter().copied().collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rels.is_empty()
+    }
+
+    /// Is the key a block, or the size, of an append-only relation?
+    pub fn contains_key(&self, key: &Key) -> bool {
+        RelFileNode::of_key(key).map_or(false, |rel| self.rels.contains(&rel))
+    }
+
+    /// Do all the keys of the key space belong to append-only relations? Each
+    /// range has to be within a single relation.
+    pub fn covers(&self, keyspace: &KeySpace) -> bool {
+        !keyspace.ranges.is_empty()
+            && keyspace
+                .ranges
+                .iter()
+                .all(|range| match RelFileNode::of_key(&range.start) {
+                    Some(rel) => self.rels.contains(&rel) && range.end <= next_rel_start(&rel),
+                    None => false,
+                })
+    }
+}
+
+/// The first key after the keys of the relation.
+fn next_rel_start(rel: &RelFileNode) -> Key {
+    Key {
+        field1: 0x00,
+        field2: rel.spcnode,
+        field3: rel.dbnode,
+        field4: rel.relnode,
+        field5: u8::MAX,
+        field6: u32::MAX,
+    }
+    .next()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pgdatadir_mapping::rel_block_to_key;
+    use crate::reltag::RelTag;
+    use postgres_ffi::pg_constants;
+
+    #[test]
+    fn append_only_relations() -> Result<()> {
+        let log: RelFileNode = "1663/13008/16384".parse()?;
+        let other: RelFileNode = "1663/13008/16390".parse()?;
+        assert_eq!(log.to_string(), "1663/13008/16384");
+        for invalid in [
+            "",
+            "1663/13008",
+            "1663/13008/0",
+            "1663/13008/16384/1",
+            "a/b/c",
+        ] {
+            assert!(invalid.parse::<RelFileNode>().is_err(), "{invalid}");
+        }
+
+        let mut rels = AppendOnlyRelations::default();
+        assert!(rels.set(log, true));
+        assert!(!rels.set(log, true));
+
+        let block = |rel: RelFileNode, forknum, blknum| {
+            rel_block_to_key(
+                RelTag {
+                    spcnode: rel.spcnode,
+                    dbnode: rel.dbnode,
+                    relnode: rel.relnode,
+                    forknum,
+                },
+                blknum,
+            )
+        };
+        let main = pg_constants::MAIN_FORKNUM;
+        assert!(rels.contains_key(&block(log, main, 7)));
+        assert!(rels.contains_key(&block(log, pg_constants::FSM_FORKNUM, 0)));
+        assert!(!rels.contains_key(&block(other, main, 7)));
+
+        let keyspace = |ranges: Vec<std::ops::Range<Key>>| KeySpace { ranges };
+        assert!(rels.covers(&keyspace(vec![
+            block(log, main, 0)..block(log, main, 100),
+            block(log, main, 200)..block(log, main, 300),
+        ])));
+        assert!(rels.covers(&keyspace(vec![block(log, main, 0)..next_rel_start(&log)])));
+        assert!(!rels.covers(&keyspace(vec![block(log, main, 0)..block(other, main, 1)])));
+        assert!(!rels.covers(&keyspace(vec![
+            block(log, main, 0)..block(log, main, 100),
+            block(other, main, 0)..block(other, main, 100),
+        ])));
+        assert!(!rels.covers(&keyspace(Vec::new())));
+
+        // Saved and loaded back
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join(APPEND_ONLY_RELATIONS_FILE_NAME);
+        assert!(AppendOnlyRelations::load(&path)?.is_empty());
+        rels.save(&path)?;
+        assert_eq!(AppendOnlyRelations::load(&path)?, rels);
+
+        assert!(rels.set(log, false));
+        assert!(!rels.contains_key(&block(log, main, 7)));
+
+        Ok(())
+    }
+}
@@ -131,6 +131,16 @@ where
         self.offset
     }
 
+    /// Flush the underlying Write object.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.inner.flush()
+    }
+
+    /// Get a reference to the underlying Write object.
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
     /// Access the underlying Write object.
     ///
     /// NOTE: WriteBlobWriter keeps track of the current write offset. If
@@ -4,11 +4,18 @@
 
 use crate::page_cache;
 use crate::page_cache::{ReadBufResult, PAGE_SZ};
+use crate::request_priority;
 use bytes::Bytes;
 use lazy_static::lazy_static;
-use std::ops::{Deref, DerefMut};
+use metrics::{register_int_counter, IntCounter};
+use nix::sys::mman::{self, MapFlags, MmapAdvise, ProtFlags};
+use std::fs::File;
+use std::ops::{Deref, DerefMut, Range};
 use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
 use std::sync::atomic::AtomicU64;
+use std::sync::Arc;
 
 /// This is implemented by anything that can read 8 kB (PAGE_SZ)
 /// blocks, using the page cache
@@ -119,9 +126,138 @@ where
 
 lazy_static! {
     static ref NEXT_ID: AtomicU64 = AtomicU64::new(1);
+    static ref MAPPED_BLOCK_READS: IntCounter = register_int_counter!(
+        "pageserver_mapped_block_reads_total",
+        "Number of layer file blocks read through a memory mapping instead of the page cache"
+    )
+    .expect("failed to define a metric");
 }
 
-/// An adapter for reading a (virtual) file using the page cache.
+///
+/// A read-only memory mapping of a whole layer file.
+///
+/// The pages are read by the kernel on the first access, and stay in the OS
+/// page cache for as long as there is memory for them. That suits machines with
+/// more RAM than the page server's own page cache can use. A read of a page
+/// that is not in memory blocks the thread in a page fault; the fault counts
+/// are exported by the `metrics` crate.
+///
+/// The file is assumed to be immutable, and it must not be truncated while it
+/// is mapped: the access of a page past the end of the file kills the process
+/// with SIGBUS. Layer files are only ever replaced as a whole.
+///
+pub struct MappedFile {
+    ptr: *mut u8,
+    len: usize,
+}
+
+// SAFETY: the mapping is read-only and stays valid until the drop
+unsafe impl Send for MappedFile {}
+unsafe impl Sync for MappedFile {}
+
+impl MappedFile {
+    pub fn open(path: &Path) -> Result<Self, std::io::Error> {
+        let file = File::open(path)?;
+        let len = file.metadata()?.len() as usize;
+        if len == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("cannot map empty file '{}'", path.display()),
+            ));
+        }
+        // SAFETY: a new mapping, at an address chosen by the kernel. The
+        // mapping stays valid after the file is closed.
+        let ptr = unsafe {
+            mman::mmap(
+                std::ptr::null_mut(),
+                len,
+                ProtFlags::PROT_READ,
+                MapFlags::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )?
+        };
+        Ok(MappedFile {
+            ptr: ptr as *mut u8,
+            len,
+        })
+    }
+
+    /// Tell the kernel that the file will be read from start to end, so that
+    /// it reads ahead aggressively and drops the pages behind the reader.
+    pub fn advise_sequential(&self) {
+        self.advise(0..self.len, MmapAdvise::MADV_SEQUENTIAL);
+    }
+
+    /// Tell the kernel to start reading the given blocks in the background.
+    pub fn advise_willneed(&self, blocks: Range<u32>) {
+        let start = (blocks.start as usize * PAGE_SZ).min(self.len);
+        let end = (blocks.end as usize * PAGE_SZ).min(self.len);
+        self.advise(start..end, MmapAdvise::MADV_WILLNEED);
+    }
+
+    fn advise(&self, range: Range<usize>, advice: MmapAdvise) {
+        if range.is_empty() {
+            return;
+        }
+        // SAFETY: the range is within the mapping, and starts at a block
+        // boundary, which is also an OS page boundary. The hints don't change
+        // the contents of the mapping.
+        let res = unsafe {
+            mman::madvise(
+                self.ptr.add(range.start) as *mut _,
+                range.end - range.start,
+                advice,
+            )
+        };
+        if let Err(e) = res {
+            // Only a hint, the reads work without it
+            tracing::debug!("madvise {:?} failed: {}", advice, e);
+        }
+    }
+
+    /// Number of whole blocks in the file.
+    fn num_blocks(&self) -> u32 {
+        (self.len / PAGE_SZ) as u32
+    }
+
+    fn block(&self, blknum: u32) -> &[u8; PAGE_SZ] {
+        assert!(blknum < self.num_blocks());
+        // SAFETY: the block is within the mapping, checked above, and the
+        // mapping lives as long as 'self'.
+        unsafe { &*(self.ptr.add(blknum as usize * PAGE_SZ) as *const [u8; PAGE_SZ]) }
+    }
+}
+
+impl Drop for MappedFile {
+    fn drop(&mut self) {
+        // SAFETY: nothing refers to the mapping anymore, the leases hold an Arc
+        if let Err(e) = unsafe { mman::munmap(self.ptr as *mut _, self.len) } {
+            tracing::error!("munmap failed: {}", e);
+        }
+    }
+}
+
+/// A block read by a [`FileBlockReader`], either from the page cache or
+/// from the mapping of the file.
+pub enum FileBlockLease {
+    Cached(page_cache::PageReadGuard<'static>),
+    Mapped { map: Arc<MappedFile>, blknum: u32 },
+}
+
+impl Deref for FileBlockLease {
+    type Target = [u8; PAGE_SZ];
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            FileBlockLease::Cached(guard) => guard,
+            FileBlockLease::Mapped { map, blknum } => map.block(*blknum),
+        }
+    }
+}
+
+/// An adapter for reading a (virtual) file using the page cache, or a
+/// mapping of the file, see [`FileBlockReader::with_mapping`].
 ///
 /// The file is assumed to be immutable. This doesn't provide any functions
 /// for modifying the file, nor for invalidating the cache if it is modified.
@@ -130,6 +266,8 @@ pub struct FileBlockReader<F> {
 
     /// Unique ID of this file, used as key in the page cache.
     file_id: u64,
+
+    mapping: Option<Arc<MappedFile>>,
 }
 
 impl<F> FileBlockReader<F>
@@ -139,7 +277,39 @@ where
     pub fn new(file: F) -> Self {
         let file_id = NEXT_ID.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
 
-        FileBlockReader { file_id, file }
+        FileBlockReader {
+            file_id,
+            file,
+            mapping: None,
+        }
+    }
+
+    /// Read the blocks from the given mapping of the same file, instead of
+    /// the page cache.
+    pub fn with_mapping(mut self, mapping: MappedFile) -> Self {
+        self.mapping = Some(Arc::new(mapping));
+        self
+    }
+
+    /// Like [`Self::with_mapping`], with a new mapping of the file at 'path'.
+    /// If the file can't be mapped, e.g. when the address space is exhausted,
+    /// the blocks are read through the page cache as without a mapping.
+    pub fn try_with_mapping(self, path: &Path) -> Self {
+        match MappedFile::open(path) {
+            Ok(mapping) => self.with_mapping(mapping),
+            Err(e) => {
+                tracing::warn!(
+                    "failed to map file '{}', reading it through the page cache instead: {}",
+                    path.display(),
+                    e
+                );
+                self
+            }
+        }
+    }
+
+    pub fn mapping(&self) -> Option<&MappedFile> {
+        self.mapping.as_deref()
     }
 
     /// Read a page from the underlying file into given buffer.
@@ -153,16 +323,30 @@ impl<F> BlockReader for FileBlockReader<F>
 where
     F: FileExt,
 {
-    type BlockLease = page_cache::PageReadGuard<'static>;
+    type BlockLease = FileBlockLease;
 
     fn read_blk(&self, blknum: u32) -> Result<Self::BlockLease, std::io::Error> {
+        if let Some(map) = &self.mapping {
+            // A block past the end of the mapping is read as without it, and
+            // fails the same way.
+            if blknum < map.num_blocks() {
+                MAPPED_BLOCK_READS.inc();
+                return Ok(FileBlockLease::Mapped {
+                    map: Arc::clone(map),
+                    blknum,
+                });
+            }
+        }
+
         // Look up the right page
         let cache = page_cache::get();
         loop {
             match cache.read_immutable_buf(self.file_id, blknum) {
-                ReadBufResult::Found(guard) => break Ok(guard),
+                ReadBufResult::Found(guard) => break Ok(FileBlockLease::Cached(guard)),
                 ReadBufResult::NotFound(mut write_guard) => {
-                    // Read the page from disk into the buffer
+                    // Read the page from disk into the buffer. The disk is shared
+                    // with all the other requests, the interactive ones go first.
+                    let _slot = request_priority::layer_read_queue().acquire();
                     self.fill_buffer(write_guard.deref_mut(), blknum)?;
                     write_guard.mark_valid();
 
@@ -216,3 +400,30 @@ impl Default for BlockBuf {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mapping_fallback() -> Result<(), std::io::Error> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("layer");
+
+        let mut contents = vec![0u8; 2 * PAGE_SZ];
+        contents[PAGE_SZ..].fill(0xAB);
+        std::fs::write(&path, &contents)?;
+        let reader = FileBlockReader::new(File::open(&path)?).try_with_mapping(&path);
+        assert!(reader.mapping().is_some());
+        assert_eq!(&reader.read_blk(1)?[..], &contents[PAGE_SZ..]);
+
+        // An empty file can't be mapped, it's read without the mapping
+        let empty_path = dir.path().join("empty");
+        std::fs::write(&empty_path, "")?;
+        let reader = FileBlockReader::new(File::open(&empty_path)?).try_with_mapping(&empty_path);
+        assert!(reader.mapping().is_none());
+        assert!(reader.read_blk(0).is_err());
+
+        Ok(())
+    }
+}
@@ -23,15 +23,26 @@
 //! "values" part.  The actual page images and WAL records are stored in the
 //! "values" part.
 //!
-use crate::config::PageServerConf;
+//! Newer files also have a fourth part after the index, "stats", with the
+//! statistics of the keys in the layer, see [`LayerStats`]. Its offset in the
+//! summary is zero in the older files.
+//!
+use crate::config::{LayerReadMode, PageServerConf};
 use crate::layered_repository::blob_io::{BlobCursor, BlobWriter, WriteBlobWriter};
-use crate::layered_repository::block_io::{BlockBuf, BlockCursor, BlockReader, FileBlockReader};
+use crate::layered_repository::block_io::{
+    BlockBuf, BlockCursor, BlockReader, FileBlockLease, FileBlockReader,
+};
 use crate::layered_repository::disk_btree::{DiskBtreeBuilder, DiskBtreeReader, VisitDirection};
-use crate::layered_repository::filename::{DeltaFileName, PathOrConf};
+use crate::layered_repository::filename::{
+    stamp_of_path, DeltaFileName, LayerFileStamp, PathOrConf,
+};
+use crate::layered_repository::layer_deletion::LayerDeletion;
+use crate::layered_repository::layer_stats::{LayerStats, LayerStatsBuilder};
 use crate::layered_repository::storage_layer::{
     Layer, ValueReconstructResult, ValueReconstructState,
 };
-use crate::page_cache::{PageReadGuard, PAGE_SZ};
+use crate::page_cache::PAGE_SZ;
+use crate::pg_file_path::key_to_pg_file;
 use crate::repository::{Key, Value, KEY_SIZE};
 use crate::virtual_file::VirtualFile;
 use crate::walrecord;
@@ -39,7 +50,7 @@ use crate::{DELTA_FILE_MAGIC, STORAGE_FORMAT_VERSION};
 use anyhow::{bail, ensure, Context, Result};
 use rand::{distributions::Alphanumeric, Rng};
 use serde::{Deserialize, Serialize};
-use std::fs;
+use std::collections::HashSet;
 use std::io::{BufWriter, Write};
 use std::io::{Seek, SeekFrom};
 use std::ops::Range;
@@ -75,6 +86,13 @@ struct Summary {
     index_start_blk: u32,
     /// Block within the 'index', where the B-tree root page is stored
     index_root_blk: u32,
+
+    /// Block number where the 'stats' part of the file begins, 0 if the file
+    /// has none. Files written before the stats were added end the summary
+    /// before these fields, and the rest of the block is zeros.
+    stats_start_blk: u32,
+    /// Length of the serialized 'stats' in bytes
+    stats_len: u32,
 }
 
 impl From<&DeltaLayer> for Summary {
@@ -90,6 +108,9 @@ impl From<&DeltaLayer> for Summary {
 
             index_start_blk: 0,
             index_root_blk: 0,
+
+            stats_start_blk: 0,
+            stats_len: 0,
         }
     }
 }
@@ -179,8 +200,19 @@ pub struct DeltaLayer {
     pub timelineid: ZTimelineId,
     pub key_range: Range<Key>,
     pub lsn_range: Range<Lsn>,
+    /// Stamp of the file name, if it has one, see [`LayerFileStamp`].
+    stamp: Option<LayerFileStamp>,
 
     inner: RwLock<DeltaLayerInner>,
+
+    /// Deletion of the file, deferred until the layer is dropped.
+    deletion: LayerDeletion,
+}
+
+impl Drop for DeltaLayer {
+    fn drop(&mut self) {
+        self.deletion.complete(&self.path());
+    }
 }
 
 pub struct DeltaLayerInner {
@@ -191,6 +223,9 @@ pub struct DeltaLayerInner {
     index_start_blk: u32,
     index_root_blk: u32,
 
+    /// Statistics of the keys in the layer, None if the file has none
+    stats: Option<LayerStats>,
+
     /// Reader object for reading blocks from the file. (None if not loaded yet)
     file: Option<FileBlockReader<VirtualFile>>,
 }
@@ -235,6 +270,12 @@ impl Layer for DeltaLayer {
             // Open the file and lock the metadata in memory
             let inner = self.load()?;
 
+            if let Some(stats) = &inner.stats {
+                if !stats.may_contain(&key, lsn_range.start) {
+                    return Ok(ValueReconstructResult::Continue);
+                }
+            }
+
             // Scan the page versions backwards, starting from `lsn`.
             let file = inner.file.as_ref().unwrap();
             let tree_reader = DiskBtreeReader::<_, DELTA_KEY_SIZE>::new(
@@ -260,7 +301,18 @@ impl Layer for DeltaLayer {
                 !blob_ref.will_init()
             })?;
 
-            // Ok, 'offsets' now contains the offsets of all the entries we need to read
+            // Ok, 'offsets' now contains the offsets of all the entries we need to read.
+            // With a mapped file, let the kernel read them all in at once,
+            // rather than take a page fault for each in turn.
+            if let Some(mapping) = file.mapping() {
+                if offsets.len() > 1 {
+                    let blocks = offsets.iter().map(|(_, pos)| (pos / PAGE_SZ as u64) as u32);
+                    let first_blk = blocks.clone().min().unwrap();
+                    let last_blk = blocks.max().unwrap();
+                    // The last blob may continue in the next block
+                    mapping.advise_willneed(first_blk..last_blk + 2);
+                }
+            }
             let mut cursor = file.block_cursor();
             for (entry_lsn, pos) in offsets {
                 let buf = cursor.read_blob(pos).with_context(|| {
@@ -316,12 +368,34 @@ impl Layer for DeltaLayer {
         }
     }
 
-    fn delete(&self) -> Result<()> {
-        // delete underlying file
-        fs::remove_file(self.path())?;
+    fn collect_changed_keys(&self, lsn_range: &Range<Lsn>, keys: &mut HashSet<Key>) -> Result<()> {
+        let inner = self.load()?;
+        let file = inner.file.as_ref().unwrap();
+        let tree_reader = DiskBtreeReader::<_, DELTA_KEY_SIZE>::new(
+            inner.index_start_blk,
+            inner.index_root_blk,
+            file,
+        );
+
+        // Only the index is needed, the values are not read
+        tree_reader.visit(
+            &[0u8; DELTA_KEY_SIZE],
+            VisitDirection::Forwards,
+            |key, _| {
+                if lsn_range.contains(&DeltaKey::extract_lsn_from_buf(key)) {
+                    keys.insert(DeltaKey::extract_key_from_buf(key));
+                }
+                true
+            },
+        )?;
         Ok(())
     }
 
+    fn delete(&self) -> Result<()> {
+        // The file is deleted when the last reader drops the layer
+        self.deletion.schedule(&self.path())
+    }
+
     fn is_incremental(&self) -> bool {
         true
     }
@@ -349,8 +423,10 @@ impl Layer for DeltaLayer {
         let inner = self.load()?;
 
         println!(
-            "index_start_blk: {}, root {}",
-            inner.index_start_blk, inner.index_root_blk
+            "index_start_blk: {}, root {}, stats: {}",
+            inner.index_start_blk,
+            inner.index_root_blk,
+            if inner.stats.is_some() { "yes" } else { "no" }
         );
 
         let file = inner.file.as_ref().unwrap();
@@ -397,7 +473,10 @@ impl Layer for DeltaLayer {
                     Ok(desc) => desc,
                     Err(err) => format!("ERROR: {}", err),
                 };
-                println!("  key {} at {}: {}", key, lsn, desc);
+                match key_to_pg_file(&key) {
+                    Some(location) => println!("  key {} ({}) at {}: {}", key, location, lsn, desc),
+                    None => println!("  key {} at {}: {}", key, lsn, desc),
+                }
                 true
             },
         )?;
@@ -483,7 +562,13 @@ impl DeltaLayer {
         if inner.file.is_none() {
             let file = VirtualFile::open(&path)
                 .with_context(|| format!("Failed to open file '{}'", path.display()))?;
-            inner.file = Some(FileBlockReader::new(file));
+            let mut reader = FileBlockReader::new(file);
+            if let PathOrConf::Conf(conf) = &self.path_or_conf {
+                if conf.layer_read_mode == LayerReadMode::Mmap {
+                    reader = reader.try_with_mapping(&path);
+                }
+            }
+            inner.file = Some(reader);
         }
         let file = inner.file.as_mut().unwrap();
         let summary_blk = file.read_blk(0)?;
@@ -491,9 +576,20 @@ impl DeltaLayer {
 
         match &self.path_or_conf {
             PathOrConf::Conf(_) => {
+                if let Some(stamp) = &self.stamp {
+                    if stamp.format_version != actual_summary.format_version {
+                        bail!(
+                            "file name has format version {}, the in-file summary has {}",
+                            stamp.format_version,
+                            actual_summary.format_version
+                        );
+                    }
+                }
                 let mut expected_summary = Summary::from(self);
                 expected_summary.index_start_blk = actual_summary.index_start_blk;
                 expected_summary.index_root_blk = actual_summary.index_root_blk;
+                expected_summary.stats_start_blk = actual_summary.stats_start_blk;
+                expected_summary.stats_len = actual_summary.stats_len;
                 if actual_summary != expected_summary {
                     bail!("in-file summary does not match expected summary. actual = {:?} expected = {:?}", actual_summary, expected_summary);
                 }
@@ -514,6 +610,15 @@ impl DeltaLayer {
 
         inner.index_start_blk = actual_summary.index_start_blk;
         inner.index_root_blk = actual_summary.index_root_blk;
+        inner.stats = if actual_summary.stats_start_blk != 0 {
+            Some(Self::read_stats(
+                file,
+                actual_summary.stats_start_blk,
+                actual_summary.stats_len,
+            )?)
+        } else {
+            None
+        };
 
         debug!("loaded from {}", &path.display());
 
@@ -521,6 +626,22 @@ impl DeltaLayer {
         Ok(())
     }
 
+    fn read_stats(
+        file: &FileBlockReader<VirtualFile>,
+        start_blk: u32,
+        len: u32,
+    ) -> Result<LayerStats> {
+        let mut buf = Vec::with_capacity(len as usize);
+        let mut blknum = start_blk;
+        while buf.len() < len as usize {
+            let blk = file.read_blk(blknum)?;
+            let remaining = len as usize - buf.len();
+            buf.extend_from_slice(&blk[..std::cmp::min(remaining, PAGE_SZ)]);
+            blknum += 1;
+        }
+        LayerStats::des(&buf).context("Failed to deserialize layer stats")
+    }
+
     /// Create a DeltaLayer struct representing an existing file on disk.
     pub fn new(
         conf: &'static PageServerConf,
@@ -534,11 +655,14 @@ impl DeltaLayer {
             tenantid,
             key_range: filename.key_range.clone(),
             lsn_range: filename.lsn_range.clone(),
+            stamp: filename.stamp,
+            deletion: LayerDeletion::default(),
             inner: RwLock::new(DeltaLayerInner {
                 loaded: false,
                 file: None,
                 index_start_blk: 0,
                 index_root_blk: 0,
+                stats: None,
             }),
         }
     }
@@ -561,11 +685,14 @@ impl DeltaLayer {
             tenantid: summary.tenantid,
             key_range: summary.key_range,
             lsn_range: summary.lsn_range,
+            stamp: stamp_of_path(path),
+            deletion: LayerDeletion::default(),
             inner: RwLock::new(DeltaLayerInner {
                 loaded: false,
                 file: None,
                 index_start_blk: 0,
                 index_root_blk: 0,
+                stats: None,
             }),
         })
     }
@@ -574,6 +701,7 @@ impl DeltaLayer {
         DeltaFileName {
             key_range: self.key_range.clone(),
             lsn_range: self.lsn_range.clone(),
+            stamp: self.stamp,
         }
     }
 
@@ -609,6 +737,7 @@ pub struct DeltaLayerWriter {
     lsn_range: Range<Lsn>,
 
     tree: DiskBtreeBuilder<BlockBuf, DELTA_KEY_SIZE>,
+    stats: LayerStatsBuilder,
 
     blob_writer: WriteBlobWriter<BufWriter<VirtualFile>>,
 }
@@ -650,6 +779,7 @@ impl DeltaLayerWriter {
             key_start,
             lsn_range,
             tree: tree_builder,
+            stats: LayerStatsBuilder::default(),
             blob_writer,
         })
     }
@@ -668,6 +798,7 @@ impl DeltaLayerWriter {
 
         let delta_key = DeltaKey::from_key_lsn(&key, lsn);
         self.tree.append(&delta_key.0, blob_ref.0)?;
+        self.stats.add(&key, lsn);
 
         Ok(())
     }
@@ -676,6 +807,16 @@ impl DeltaLayerWriter {
         self.blob_writer.size() + self.tree.borrow_writer().size()
     }
 
+    ///
+    /// Write out the values appended so far and sync them to disk, so that
+    /// less is left for the fsync after finish().
+    ///
+    pub fn sync_data(&mut self) -> Result<()> {
+        self.blob_writer.flush()?;
+        self.blob_writer.get_ref().get_ref().sync_data()?;
+        Ok(())
+    }
+
     ///
     /// Finish writing the delta layer.
     ///
@@ -689,10 +830,17 @@ impl DeltaLayerWriter {
         // Write out the index
         let (index_root_blk, block_buf) = self.tree.finish()?;
         file.seek(SeekFrom::Start(index_start_blk as u64 * PAGE_SZ as u64))?;
+        let stats_start_blk = index_start_blk + block_buf.blocks.len() as u32;
         for buf in block_buf.blocks {
             file.write_all(buf.as_ref())?;
         }
 
+        // Write out the stats, padded to a full block
+        let mut stats_buf = LayerStats::ser(&self.stats.finish())?;
+        let stats_len = stats_buf.len() as u32;
+        stats_buf.resize((stats_buf.len() + PAGE_SZ - 1) / PAGE_SZ * PAGE_SZ, 0);
+        file.write_all(&stats_buf)?;
+
         // Fill in the summary on blk 0
         let summary = Summary {
             magic: DELTA_FILE_MAGIC,
@@ -703,6 +851,8 @@ impl DeltaLayerWriter {
             lsn_range: self.lsn_range.clone(),
             index_start_blk,
             index_root_blk,
+            stats_start_blk,
+            stats_len,
         };
         file.seek(SeekFrom::Start(0))?;
         Summary::ser_into(&summary, &mut file)?;
@@ -716,11 +866,14 @@ impl DeltaLayerWriter {
             timelineid: self.timelineid,
             key_range: self.key_start..key_end,
             lsn_range: self.lsn_range.clone(),
+            stamp: None,
+            deletion: LayerDeletion::default(),
             inner: RwLock::new(DeltaLayerInner {
                 loaded: false,
                 file: None,
                 index_start_blk,
                 index_root_blk,
+                stats: None,
             }),
         };
 
@@ -737,6 +890,7 @@ impl DeltaLayerWriter {
             &DeltaFileName {
                 key_range: self.key_start..key_end,
                 lsn_range: self.lsn_range,
+                stamp: None,
             },
         );
         std::fs::rename(self.path, &final_path)?;
@@ -763,7 +917,7 @@ struct DeltaValueIter<'a> {
 struct Adapter<'a>(RwLockReadGuard<'a, DeltaLayerInner>);
 
 impl<'a> BlockReader for Adapter<'a> {
-    type BlockLease = PageReadGuard<'static>;
+    type BlockLease = FileBlockLease;
 
     fn read_blk(&self, blknum: u32) -> Result<Self::BlockLease, std::io::Error> {
         self.0.file.as_ref().unwrap().read_blk(blknum)
@@ -797,6 +951,13 @@ impl<'a> DeltaValueIter<'a> {
             },
         )?;
 
+        // The values are read in the order of the keys, which is the order
+        // they were written in. This is used by the compaction, after which
+        // the layer is not read anymore, so the hint doesn't hurt other readers.
+        if let Some(mapping) = file.mapping() {
+            mapping.advise_sequential();
+        }
+
         let iter = DeltaValueIter {
             all_offsets,
             next_idx: 0,
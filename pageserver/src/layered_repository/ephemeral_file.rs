@@ -1,5 +1,12 @@
 //! Implementation of append-only file data structure
 //! used to keep in-memory layers spilled on disk.
+//!
+//! Every in-memory layer gets its own file, so under a high rate of layer
+//! flushes files are created and deleted all the time. Instead of deleting
+//! them, a few spare files are kept per timeline, see
+//! `ephemeral_file_pool_size`. A spare file is cleared by punching a hole over
+//! its contents, and its space is allocated again in advance, see
+//! `ephemeral_file_preallocate`.
 
 use crate::config::PageServerConf;
 use crate::layered_repository::blob_io::BlobWriter;
@@ -9,7 +16,8 @@ use crate::page_cache::PAGE_SZ;
 use crate::page_cache::{ReadBufResult, WriteBufResult};
 use crate::virtual_file::VirtualFile;
 use lazy_static::lazy_static;
-use std::cmp::min;
+use metrics::{register_int_counter, IntCounter};
+use std::cmp::{max, min};
 use std::collections::HashMap;
 use std::fs::OpenOptions;
 use std::io::{Error, ErrorKind};
@@ -28,22 +36,35 @@ lazy_static! {
     static ref EPHEMERAL_FILES: RwLock<EphemeralFiles> = RwLock::new(EphemeralFiles {
         next_file_id: 1,
         files: HashMap::new(),
+        spare_files: HashMap::new(),
     });
+    static ref EPHEMERAL_FILES_REUSED: IntCounter = register_int_counter!(
+        "pageserver_ephemeral_files_reused_total",
+        "Number of ephemeral files taken from the spare files instead of creating a new file"
+    )
+    .expect("failed to define a metric");
 }
 
 pub struct EphemeralFiles {
     next_file_id: u64,
 
     files: HashMap<u64, Arc<VirtualFile>>,
+
+    /// Cleared files ready for reuse, by timeline directory.
+    spare_files: HashMap<PathBuf, Vec<(u64, Arc<VirtualFile>)>>,
 }
 
 pub struct EphemeralFile {
     file_id: u64,
     _tenantid: ZTenantId,
     _timelineid: ZTimelineId,
+    timeline_dir: PathBuf,
     file: Arc<VirtualFile>,
 
     size: u64,
+
+    spare_files_limit: usize,
+    preallocate: u64,
 }
 
 impl EphemeralFile {
@@ -52,30 +73,86 @@ impl EphemeralFile {
         tenantid: ZTenantId,
         timelineid: ZTimelineId,
     ) -> Result<EphemeralFile, std::io::Error> {
+        let timeline_dir = conf.timeline_path(&timelineid, &tenantid);
         let mut l = EPHEMERAL_FILES.write().unwrap();
-        let file_id = l.next_file_id;
-        l.next_file_id += 1;
-
-        let filename = conf
-            .timeline_path(&timelineid, &tenantid)
-            .join(PathBuf::from(format!("ephemeral-{}", file_id)));
-
-        let file = VirtualFile::open_with_options(
-            &filename,
-            OpenOptions::new().read(true).write(true).create(true),
-        )?;
-        let file_rc = Arc::new(file);
+
+        let spare_file = l
+            .spare_files
+            .get_mut(&timeline_dir)
+            .and_then(|spare_files| {
+                // The spare files are gone if the timeline directory was removed
+                while let Some((file_id, file)) = spare_files.pop() {
+                    if file.path.exists() {
+                        return Some((file_id, file));
+                    }
+                }
+                None
+            });
+
+        let (file_id, file_rc) = match spare_file {
+            Some(spare_file) => {
+                EPHEMERAL_FILES_REUSED.inc();
+                spare_file
+            }
+            None => {
+                let file_id = l.next_file_id;
+                l.next_file_id += 1;
+
+                let filename = timeline_dir.join(PathBuf::from(format!("ephemeral-{}", file_id)));
+
+                let file = VirtualFile::open_with_options(
+                    &filename,
+                    OpenOptions::new().read(true).write(true).create(true),
+                )?;
+                if conf.ephemeral_file_preallocate > 0 {
+                    if let Err(e) = file.preallocate(conf.ephemeral_file_preallocate) {
+                        debug!(
+                            "could not preallocate ephemeral file '{}': {}",
+                            filename.display(),
+                            e
+                        );
+                    }
+                }
+                (file_id, Arc::new(file))
+            }
+        };
         l.files.insert(file_id, file_rc.clone());
 
         Ok(EphemeralFile {
             file_id,
             _tenantid: tenantid,
             _timelineid: timelineid,
+            timeline_dir,
             file: file_rc,
             size: 0,
+            spare_files_limit: conf.ephemeral_file_pool_size,
+            preallocate: conf.ephemeral_file_preallocate,
         })
     }
 
+    /// Number of bytes written into the file.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Discard the contents of the file, for reuse as a spare file.
+    fn clear(&self) -> Result<(), Error> {
+        let len = max(self.file.metadata()?.len(), self.preallocate);
+        if len > 0 {
+            self.file.punch_hole(0, len)?;
+        }
+        if self.preallocate > 0 {
+            self.file.preallocate(self.preallocate)?;
+        }
+        Ok(())
+    }
+
+    fn has_room_for_spare_file(&self) -> bool {
+        let l = EPHEMERAL_FILES.read().unwrap();
+        let spare_files = l.spare_files.get(&self.timeline_dir).map_or(0, Vec::len);
+        spare_files < self.spare_files_limit
+    }
+
     fn fill_buffer(&self, buf: &mut [u8], blkno: u32) -> Result<(), Error> {
         let mut off = 0;
         while off < PAGE_SZ {
@@ -253,8 +330,31 @@ impl Drop for EphemeralFile {
         let cache = page_cache::get();
         cache.drop_buffers_for_ephemeral(self.file_id);
 
+        // keep the file as a spare file of the timeline, if there's room
+        let reuse = self.has_room_for_spare_file()
+            && match self.clear() {
+                Ok(()) => true,
+                Err(e) => {
+                    debug!(
+                        "could not clear ephemeral file '{}' for reuse: {}",
+                        self.file.path.display(),
+                        e
+                    );
+                    false
+                }
+            };
+
         // remove entry from the hash map
-        EPHEMERAL_FILES.write().unwrap().files.remove(&self.file_id);
+        let mut l = EPHEMERAL_FILES.write().unwrap();
+        l.files.remove(&self.file_id);
+        if reuse {
+            let spare_files = l.spare_files.entry(self.timeline_dir.clone()).or_default();
+            if spare_files.len() < self.spare_files_limit {
+                spare_files.push((self.file_id, Arc::clone(&self.file)));
+                return;
+            }
+        }
+        drop(l);
 
         // unlink the file
         let res = std::fs::remove_file(&self.file.path);
@@ -381,6 +481,42 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_ephemeral_file_reuse() -> Result<(), Error> {
+        let (conf, tenantid, timelineid) = repo_harness("ephemeral_file_reuse")?;
+
+        let mut file = EphemeralFile::create(conf, tenantid, timelineid)?;
+        let file_id = file.file_id;
+        let path = file.file.path.clone();
+        let large_data = b"payload".repeat(PAGE_SZ);
+        file.write_blob(&large_data)?;
+        drop(file);
+        assert!(path.exists(), "dropped file should be kept as a spare file");
+
+        // The spare file gets reused, without the old contents
+        let mut file = EphemeralFile::create(conf, tenantid, timelineid)?;
+        assert_eq!(file.file_id, file_id);
+        assert_eq!("", read_string(&file, 0, 100)?);
+        let pos = file.write_blob(b"foo")?;
+        assert_eq!(pos, 0);
+        assert_eq!(b"foo", file.block_cursor().read_blob(pos)?.as_slice());
+
+        // Only up to ephemeral_file_pool_size spare files are kept
+        let files = (0..conf.ephemeral_file_pool_size + 2)
+            .map(|_| EphemeralFile::create(conf, tenantid, timelineid))
+            .collect::<Result<Vec<_>, _>>()?;
+        drop(file);
+        drop(files);
+        let ephemeral_files = fs::read_dir(conf.timeline_path(&timelineid, &tenantid))?
+            .filter(|entry| {
+                is_ephemeral_file(&entry.as_ref().unwrap().file_name().to_string_lossy())
+            })
+            .count();
+        assert_eq!(ephemeral_files, conf.ephemeral_file_pool_size);
+
+        Ok(())
+    }
+
     #[test]
     fn test_ephemeral_blobs() -> Result<(), Error> {
         let (conf, tenantid, timelineid) = repo_harness("ephemeral_blobs")?;
@@ -1,20 +1,150 @@
 //!
 //! Helper functions for dealing with filenames of the image and delta layer files.
 //!
+//! There are two naming schemes. The original one only has the key and LSN
+//! ranges of the layer. The second one adds a [`LayerFileStamp`] at the end:
+//! the storage format version of the file, the generation of the tenant that
+//! wrote it, if it had one, and a CRC32C of the whole file. Two page servers
+//! that write a layer for the same ranges, e.g. the old and the new owner of a
+//! tenant, then produce different names, and the file can be checked against
+//! its name after a download without trusting anything but the name.
+//!
+//! Both schemes are parsed. The layers are still written with the original
+//! names, so that a page server rolled back to a version that doesn't know
+//! the second scheme can read them.
+//!
 use crate::config::PageServerConf;
 use crate::repository::Key;
 use std::cmp::Ordering;
 use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
 use std::ops::Range;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use utils::lsn::Lsn;
 
+///
+/// The part of a layer file name added by the second naming scheme:
+///
+///    v<format version>[-<generation>]-<checksum>
+///
+/// The generation and the checksum are 8 hex digits.
+///
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub struct LayerFileStamp {
+    pub format_version: u16,
+    pub generation: Option<u32>,
+    pub checksum: u32,
+}
+
+impl LayerFileStamp {
+    fn parse_str(stamp: &str) -> Option<Self> {
+        let mut parts = stamp.strip_prefix('v')?.split('-');
+        let format_version = parts.next()?.parse().ok()?;
+        let first = parts.next()?;
+        let (generation, checksum) = match parts.next() {
+            Some(checksum) => (Some(first), checksum),
+            None => (None, first),
+        };
+        if parts.next().is_some() {
+            return None;
+        }
+
+        let generation = match generation {
+            Some(generation) => Some(parse_hex_u32(generation)?),
+            None => None,
+        };
+        Some(LayerFileStamp {
+            format_version,
+            generation,
+            checksum: parse_hex_u32(checksum)?,
+        })
+    }
+
+    ///
+    /// Check that the file at 'path' has the checksum of the stamp.
+    ///
+    pub fn verify(&self, path: &Path) -> anyhow::Result<()> {
+        let checksum = layer_file_checksum(path)?;
+        anyhow::ensure!(
+            checksum == self.checksum,
+            "layer file {} has checksum {:08X}, expected {:08X} from its name",
+            path.display(),
+            checksum,
+            self.checksum
+        );
+        Ok(())
+    }
+}
+
+impl fmt::Display for LayerFileStamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "v{}", self.format_version)?;
+        if let Some(generation) = self.generation {
+            write!(f, "-{:08X}", generation)?;
+        }
+        write!(f, "-{:08X}", self.checksum)
+    }
+}
+
+fn parse_hex_u32(s: &str) -> Option<u32> {
+    if s.len() != 8 {
+        return None;
+    }
+    u32::from_str_radix(s, 16).ok()
+}
+
+///
+/// CRC32C of the whole contents of a layer file, as in its [`LayerFileStamp`].
+///
+pub fn layer_file_checksum(path: &Path) -> io::Result<u32> {
+    let mut file = File::open(path)?;
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut checksum = 0;
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            return Ok(checksum);
+        }
+        checksum = crc32c::crc32c_append(checksum, &buf[..n]);
+    }
+}
+
+/// Is 'fname' the name of an image or a delta layer file?
+pub fn is_layer_file_name(fname: &str) -> bool {
+    DeltaFileName::parse_str(fname).is_some() || ImageFileName::parse_str(fname).is_some()
+}
+
+///
+/// The stamp in the name of the layer file at 'path', if it has one.
+///
+pub fn stamp_of_path(path: &Path) -> Option<LayerFileStamp> {
+    let fname = path.file_name()?.to_string_lossy();
+    match DeltaFileName::parse_str(&fname) {
+        Some(delta) => delta.stamp,
+        None => ImageFileName::parse_str(&fname)?.stamp,
+    }
+}
+
+///
+/// Parse the stamp of a file name, if it has the '__<stamp>' suffix of the
+/// second naming scheme. Returns Some(None) if it doesn't have one, and None
+/// if the suffix isn't a valid stamp.
+///
+fn parse_stamp_part(part: Option<&str>) -> Option<Option<LayerFileStamp>> {
+    match part {
+        Some(stamp) => Some(Some(LayerFileStamp::parse_str(stamp)?)),
+        None => Some(None),
+    }
+}
+
 // Note: LayeredTimeline::load_layer_map() relies on this sort order
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct DeltaFileName {
     pub key_range: Range<Key>,
     pub lsn_range: Range<Lsn>,
+    pub stamp: Option<LayerFileStamp>,
 }
 
 impl PartialOrd for DeltaFileName {
@@ -38,6 +168,10 @@ impl Ord for DeltaFileName {
             return cmp;
         }
         cmp = self.lsn_range.end.cmp(&other.lsn_range.end);
+        if cmp != Ordering::Equal {
+            return cmp;
+        }
+        cmp = self.stamp.cmp(&other.stamp);
 
         cmp
     }
@@ -45,7 +179,7 @@ impl Ord for DeltaFileName {
 
 /// Represents the filename of a DeltaLayer
 ///
-///    <key start>-<key end>__<LSN start>-<LSN end>
+///    <key start>-<key end>__<LSN start>-<LSN end>[__<stamp>]
 ///
 impl DeltaFileName {
     ///
@@ -61,7 +195,8 @@ impl DeltaFileName {
         let key_end_str = key_parts.next()?;
         let lsn_start_str = lsn_parts.next()?;
         let lsn_end_str = lsn_parts.next()?;
-        if parts.next().is_some() || key_parts.next().is_some() || key_parts.next().is_some() {
+        let stamp = parse_stamp_part(parts.next())?;
+        if parts.next().is_some() || key_parts.next().is_some() || lsn_parts.next().is_some() {
             return None;
         }
 
@@ -84,6 +219,7 @@ impl DeltaFileName {
         Some(DeltaFileName {
             key_range: key_start..key_end,
             lsn_range: start_lsn..end_lsn,
+            stamp,
         })
     }
 }
@@ -97,7 +233,11 @@ impl fmt::Display for DeltaFileName {
             self.key_range.end,
             u64::from(self.lsn_range.start),
             u64::from(self.lsn_range.end),
-        )
+        )?;
+        if let Some(stamp) = &self.stamp {
+            write!(f, "__{}", stamp)?;
+        }
+        Ok(())
     }
 }
 
@@ -105,6 +245,7 @@ impl fmt::Display for DeltaFileName {
 pub struct ImageFileName {
     pub key_range: Range<Key>,
     pub lsn: Lsn,
+    pub stamp: Option<LayerFileStamp>,
 }
 
 impl PartialOrd for ImageFileName {
@@ -124,6 +265,10 @@ impl Ord for ImageFileName {
             return cmp;
         }
         cmp = self.lsn.cmp(&other.lsn);
+        if cmp != Ordering::Equal {
+            return cmp;
+        }
+        cmp = self.stamp.cmp(&other.stamp);
 
         cmp
     }
@@ -132,7 +277,7 @@ impl Ord for ImageFileName {
 ///
 /// Represents the filename of an ImageLayer
 ///
-///    <key start>-<key end>__<LSN>
+///    <key start>-<key end>__<LSN>[__<stamp>]
 impl ImageFileName {
     ///
     /// Parse a string as an image file name. Returns None if the filename does not
@@ -145,6 +290,7 @@ impl ImageFileName {
         let key_start_str = key_parts.next()?;
         let key_end_str = key_parts.next()?;
         let lsn_str = parts.next()?;
+        let stamp = parse_stamp_part(parts.next())?;
         if parts.next().is_some() || key_parts.next().is_some() {
             return None;
         }
@@ -157,6 +303,7 @@ impl ImageFileName {
         Some(ImageFileName {
             key_range: key_start..key_end,
             lsn,
+            stamp,
         })
     }
 }
@@ -169,7 +316,11 @@ impl fmt::Display for ImageFileName {
             self.key_range.start,
             self.key_range.end,
             u64::from(self.lsn),
-        )
+        )?;
+        if let Some(stamp) = &self.stamp {
+            write!(f, "__{}", stamp)?;
+        }
+        Ok(())
     }
 }
 
@@ -185,3 +336,73 @@ pub enum PathOrConf {
     Path(PathBuf),
     Conf(&'static PageServerConf),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY_RANGE: &str =
+        "000000067F000032BE0000400000000070B6-000000067F000032BE0000400000000080B6";
+
+    #[test]
+    fn layer_file_names() {
+        // The original scheme
+        let delta_name = format!("{KEY_RANGE}__0000000001696070-00000000016960E9");
+        let delta = DeltaFileName::parse_str(&delta_name).unwrap();
+        assert_eq!(delta.stamp, None);
+        assert_eq!(delta.to_string(), delta_name);
+
+        // With a stamp, with and without a generation
+        let stamped_name = format!("{delta_name}__v1-00000003-1A2B3C4D");
+        let stamped = DeltaFileName::parse_str(&stamped_name).unwrap();
+        assert_eq!(
+            stamped.stamp,
+            Some(LayerFileStamp {
+                format_version: 1,
+                generation: Some(3),
+                checksum: 0x1A2B3C4D,
+            })
+        );
+        assert_eq!(stamped.to_string(), stamped_name);
+        assert_ne!(stamped, delta);
+
+        let image_name = format!("{KEY_RANGE}__00000000016960E8__v1-1A2B3C4D");
+        let image = ImageFileName::parse_str(&image_name).unwrap();
+        assert_eq!(image.stamp.unwrap().generation, None);
+        assert_eq!(image.to_string(), image_name);
+        assert_eq!(stamp_of_path(Path::new(&image_name)), image.stamp,);
+
+        // Not layer names
+        for fname in [
+            format!("{delta_name}__v1-1A2B"),
+            format!("{delta_name}__1-1A2B3C4D"),
+            format!("{delta_name}__v1-00000003-1A2B3C4D-0"),
+            format!("{stamped_name}.old"),
+            format!("{image_name}__v1-1A2B3C4D"),
+        ] {
+            assert_eq!(DeltaFileName::parse_str(&fname), None, "{fname}");
+            assert_eq!(ImageFileName::parse_str(&fname), None, "{fname}");
+        }
+    }
+
+    #[test]
+    fn layer_file_checksum_verification() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("layer");
+        std::fs::write(&path, b"layer contents")?;
+
+        let checksum = crc32c::crc32c(b"layer contents");
+        let stamp = LayerFileStamp {
+            format_version: 1,
+            generation: None,
+            checksum,
+        };
+        stamp.verify(&path)?;
+        let wrong = LayerFileStamp {
+            checksum: checksum ^ 1,
+            ..stamp
+        };
+        assert!(wrong.verify(&path).is_err());
+        Ok(())
+    }
+}
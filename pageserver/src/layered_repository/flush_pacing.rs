@@ -0,0 +1,173 @@
+//!
+//! Pacing of the in-memory layer flushes.
+//!
+//! Without pacing, a frozen in-memory layer is written out as fast as the disk
+//! allows, and fsync'd all at once at the end. With many timelines flushing at
+//! the same time, that shows up as latency spikes for everything else using the
+//! disk. Instead, a flush is written at a target rate, derived from the rate of
+//! WAL ingest of the timeline: it only has to be somewhat faster than the WAL
+//! arrives to keep up. The written data is also synced in intervals, so that
+//! the writeback is spread over the flush.
+//!
+//! Pacing only applies while the flush keeps up. If more frozen layers are
+//! waiting behind the one being flushed, it is written at full speed, so that
+//! the backlog of in-memory layers stays bounded.
+//!
+use std::cmp::{max, min};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Weight of the latest observation in the ingest rate average.
+const INGEST_RATE_SMOOTHING: f64 = 0.3;
+
+/// Never pace a flush below this rate, in bytes per second.
+const MIN_PACED_RATE: u64 = 4 * 1024 * 1024;
+
+/// Don't bother sleeping for less than this.
+const MIN_SLEEP: Duration = Duration::from_millis(10);
+
+/// Sleep at most this long at once, so that a flush doesn't stall on a stale
+/// rate estimate.
+const MAX_SLEEP: Duration = Duration::from_secs(1);
+
+pub struct FlushPacer {
+    /// Target write rate, in percent of the ingest rate. 0 disables pacing.
+    rate_percent: u64,
+    /// Sync the written data every this many bytes, 0 to sync only at the end.
+    sync_interval: u64,
+    /// Moving average of the WAL ingest rate, in bytes per second.
+    ingest_rate: Mutex<Option<f64>>,
+}
+
+impl FlushPacer {
+    pub fn new(rate_percent: u64, sync_interval: u64) -> Self {
+        FlushPacer {
+            rate_percent,
+            sync_interval,
+            ingest_rate: Mutex::new(None),
+        }
+    }
+
+    ///
+    /// Record that 'wal_bytes' of WAL were ingested in 'elapsed', the time the
+    /// open layer was open before it was frozen.
+    ///
+    pub fn observe_ingest(&self, wal_bytes: u64, elapsed: Duration) {
+        if elapsed.is_zero() {
+            return;
+        }
+        let rate = wal_bytes as f64 / elapsed.as_secs_f64();
+        let mut ingest_rate = self.ingest_rate.lock().unwrap();
+        *ingest_rate = Some(match *ingest_rate {
+            Some(old) => old * (1.0 - INGEST_RATE_SMOOTHING) + rate * INGEST_RATE_SMOOTHING,
+            None => rate,
+        });
+    }
+
+    /// Target write rate of a flush in bytes per second, None if not paced.
+    pub fn target_rate(&self) -> Option<u64> {
+        if self.rate_percent == 0 {
+            return None;
+        }
+        let ingest_rate = (*self.ingest_rate.lock().unwrap())?;
+        let rate = (ingest_rate * self.rate_percent as f64 / 100.0) as u64;
+        Some(max(rate, MIN_PACED_RATE))
+    }
+
+    ///
+    /// Start flushing a layer. 'backlog' is the number of frozen layers
+    /// waiting to be flushed after this one.
+    ///
+    pub fn start_flush(&self, backlog: usize) -> FlushPacing {
+        FlushPacing {
+            target_rate: if backlog == 0 {
+                self.target_rate()
+            } else {
+                None
+            },
+            sync_interval: self.sync_interval,
+            started: Instant::now(),
+            written: 0,
+            unsynced: 0,
+            waited: Duration::ZERO,
+        }
+    }
+}
+
+/// Pacing state of one layer flush.
+pub struct FlushPacing {
+    target_rate: Option<u64>,
+    sync_interval: u64,
+    started: Instant,
+    written: u64,
+    unsynced: u64,
+    waited: Duration,
+}
+
+impl FlushPacing {
+    ///
+    /// Account for 'bytes' written, and sleep if the flush is ahead of the
+    /// target rate. Returns true if the data written so far should be synced.
+    ///
+    pub fn wrote(&mut self, bytes: u64) -> bool {
+        self.written += bytes;
+        self.unsynced += bytes;
+
+        if let Some(target_rate) = self.target_rate {
+            let due = Duration::from_secs_f64(self.written as f64 / target_rate as f64);
+            let elapsed = self.started.elapsed();
+            if due > elapsed + MIN_SLEEP {
+                let sleep = min(due - elapsed, MAX_SLEEP);
+                std::thread::sleep(sleep);
+                self.waited += sleep;
+            }
+        }
+
+        if self.sync_interval > 0 && self.unsynced >= self.sync_interval {
+            self.unsynced = 0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Total time spent sleeping to keep to the target rate.
+    pub fn waited(&self) -> Duration {
+        self.waited
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flush_pacing() {
+        let pacer = FlushPacer::new(200, 1000);
+        assert_eq!(pacer.target_rate(), None, "no ingest observed yet");
+
+        pacer.observe_ingest(10 * MIN_PACED_RATE, Duration::from_secs(1));
+        assert_eq!(pacer.target_rate(), Some(20 * MIN_PACED_RATE));
+
+        // Slow ingest doesn't slow the flushes down below the minimum rate
+        for _ in 0..10 {
+            pacer.observe_ingest(0, Duration::from_secs(1));
+        }
+        assert_eq!(pacer.target_rate(), Some(MIN_PACED_RATE));
+
+        // Writing a tenth of a second worth of data takes about that long
+        let mut pacing = pacer.start_flush(0);
+        pacing.wrote(MIN_PACED_RATE / 10);
+        assert!(pacing.waited() >= Duration::from_millis(50));
+
+        // With a backlog, flushes go at full speed
+        let mut pacing = pacer.start_flush(1);
+        pacing.wrote(MIN_PACED_RATE);
+        assert_eq!(pacing.waited(), Duration::ZERO);
+
+        // Syncs are requested every 'sync_interval' bytes
+        assert!(!pacing.wrote(500));
+        assert!(pacing.wrote(500));
+        assert!(!pacing.wrote(999));
+    }
+}
@@ -19,16 +19,22 @@
 //! layer, and offsets to the other parts. The "index" is a B-tree,
 //! mapping from Key to an offset in the "values" part.  The
 //! actual page images are stored in the "values" part.
-use crate::config::PageServerConf;
+use crate::config::{LayerReadMode, PageServerConf};
 use crate::layered_repository::blob_io::{BlobCursor, BlobWriter, WriteBlobWriter};
 use crate::layered_repository::block_io::{BlockBuf, BlockReader, FileBlockReader};
 use crate::layered_repository::disk_btree::{DiskBtreeBuilder, DiskBtreeReader, VisitDirection};
-use crate::layered_repository::filename::{ImageFileName, PathOrConf};
+use crate::layered_repository::filename::{
+    stamp_of_path, ImageFileName, LayerFileStamp, PathOrConf,
+};
+use crate::layered_repository::layer_deletion::LayerDeletion;
 use crate::layered_repository::storage_layer::{
     Layer, ValueReconstructResult, ValueReconstructState,
 };
 use crate::page_cache::PAGE_SZ;
+use crate::pg_file_path::key_to_pg_file;
 use crate::repository::{Key, Value, KEY_SIZE};
+use crate::storage_sync::LayerUploadStream;
+use crate::tenant_mgr;
 use crate::virtual_file::VirtualFile;
 use crate::{IMAGE_FILE_MAGIC, STORAGE_FORMAT_VERSION};
 use anyhow::{bail, ensure, Context, Result};
@@ -36,7 +42,7 @@ use bytes::Bytes;
 use hex;
 use rand::{distributions::Alphanumeric, Rng};
 use serde::{Deserialize, Serialize};
-use std::fs;
+use std::collections::HashSet;
 use std::io::Write;
 use std::io::{Seek, SeekFrom};
 use std::ops::Range;
@@ -105,8 +111,19 @@ pub struct ImageLayer {
 
     // This entry contains an image of all pages as of this LSN
     pub lsn: Lsn,
+    /// Stamp of the file name, if it has one, see [`LayerFileStamp`].
+    stamp: Option<LayerFileStamp>,
 
     inner: RwLock<ImageLayerInner>,
+
+    /// Deletion of the file, deferred until the layer is dropped.
+    deletion: LayerDeletion,
+}
+
+impl Drop for ImageLayer {
+    fn drop(&mut self) {
+        self.deletion.complete(&self.path());
+    }
 }
 
 pub struct ImageLayerInner {
@@ -186,12 +203,15 @@ impl Layer for ImageLayer {
         todo!();
     }
 
-    fn delete(&self) -> Result<()> {
-        // delete underlying file
-        fs::remove_file(self.path())?;
+    fn collect_changed_keys(&self, _: &Range<Lsn>, _: &mut HashSet<Key>) -> Result<()> {
         Ok(())
     }
 
+    fn delete(&self) -> Result<()> {
+        // The file is deleted when the last reader drops the layer
+        self.deletion.schedule(&self.path())
+    }
+
     fn is_incremental(&self) -> bool {
         false
     }
@@ -219,7 +239,12 @@ impl Layer for ImageLayer {
         tree_reader.dump()?;
 
         tree_reader.visit(&[0u8; KEY_SIZE], VisitDirection::Forwards, |key, value| {
-            println!("key: {} offset {}", hex::encode(key), value);
+            match key_to_pg_file(&Key::from_slice(key)) {
+                Some(location) => {
+                    println!("key: {} ({}) offset {}", hex::encode(key), location, value)
+                }
+                None => println!("key: {} offset {}", hex::encode(key), value),
+            }
             true
         })?;
 
@@ -299,7 +324,13 @@ impl ImageLayer {
         if inner.file.is_none() {
             let file = VirtualFile::open(&path)
                 .with_context(|| format!("Failed to open file '{}'", path.display()))?;
-            inner.file = Some(FileBlockReader::new(file));
+            let mut reader = FileBlockReader::new(file);
+            if let PathOrConf::Conf(conf) = &self.path_or_conf {
+                if conf.layer_read_mode == LayerReadMode::Mmap {
+                    reader = reader.try_with_mapping(&path);
+                }
+            }
+            inner.file = Some(reader);
         }
         let file = inner.file.as_mut().unwrap();
         let summary_blk = file.read_blk(0)?;
@@ -307,6 +338,15 @@ impl ImageLayer {
 
         match &self.path_or_conf {
             PathOrConf::Conf(_) => {
+                if let Some(stamp) = &self.stamp {
+                    if stamp.format_version != actual_summary.format_version {
+                        bail!(
+                            "file name has format version {}, the in-file summary has {}",
+                            stamp.format_version,
+                            actual_summary.format_version
+                        );
+                    }
+                }
                 let mut expected_summary = Summary::from(self);
                 expected_summary.index_start_blk = actual_summary.index_start_blk;
                 expected_summary.index_root_blk = actual_summary.index_root_blk;
@@ -348,6 +388,8 @@ impl ImageLayer {
             tenantid,
             key_range: filename.key_range.clone(),
             lsn: filename.lsn,
+            stamp: filename.stamp,
+            deletion: LayerDeletion::default(),
             inner: RwLock::new(ImageLayerInner {
                 loaded: false,
                 file: None,
@@ -375,6 +417,8 @@ impl ImageLayer {
             tenantid: summary.tenantid,
             key_range: summary.key_range,
             lsn: summary.lsn,
+            stamp: stamp_of_path(path),
+            deletion: LayerDeletion::default(),
             inner: RwLock::new(ImageLayerInner {
                 file: None,
                 loaded: false,
@@ -388,6 +432,7 @@ impl ImageLayer {
         ImageFileName {
             key_range: self.key_range.clone(),
             lsn: self.lsn,
+            stamp: self.stamp,
         }
     }
 
@@ -423,6 +468,8 @@ pub struct ImageLayerWriter {
 
     blob_writer: WriteBlobWriter<VirtualFile>,
     tree: DiskBtreeBuilder<BlockBuf, KEY_SIZE>,
+
+    upload_stream: Option<LayerUploadStream>,
 }
 
 impl ImageLayerWriter {
@@ -442,6 +489,7 @@ impl ImageLayerWriter {
             &ImageFileName {
                 key_range: key_range.clone(),
                 lsn,
+                stamp: None,
             },
         );
         info!("new image layer {}", path.display());
@@ -463,11 +511,45 @@ impl ImageLayerWriter {
             lsn,
             tree: tree_builder,
             blob_writer,
+            upload_stream: None,
         };
 
         Ok(writer)
     }
 
+    ///
+    /// Upload the layer to the remote storage while it's being written, if
+    /// enabled with `stream_layer_uploads`. The layer still has to be scheduled
+    /// for upload when it's finished, that upload continues from the parts
+    /// streamed.
+    ///
+    pub fn stream_upload(&mut self) {
+        // The layer upload uses the generation of the tenant at that time. If
+        // it changes in between, that upload can't resume from the streamed
+        // parts and starts over.
+        let generation = match tenant_mgr::get_tenant_generation(self.conf, self.tenantid) {
+            Ok(generation) => generation,
+            Err(e) => {
+                warn!("not streaming layer {}: {:#}", self.path.display(), e);
+                return;
+            }
+        };
+        self.upload_stream = LayerUploadStream::start(&self.path, &self.final_path(), generation);
+    }
+
+    fn final_path(&self) -> PathBuf {
+        ImageLayer::path_for(
+            &PathOrConf::Conf(self.conf),
+            self.timelineid,
+            self.tenantid,
+            &ImageFileName {
+                key_range: self.key_range.clone(),
+                lsn: self.lsn,
+                stamp: None,
+            },
+        )
+    }
+
     ///
     /// Write next value to the file.
     ///
@@ -481,10 +563,15 @@ impl ImageLayerWriter {
         key.write_to_byte_slice(&mut keybuf);
         self.tree.append(&keybuf, off)?;
 
+        if let Some(upload_stream) = self.upload_stream.as_mut() {
+            upload_stream.written(self.blob_writer.size());
+        }
+
         Ok(())
     }
 
-    pub fn finish(self) -> anyhow::Result<ImageLayer> {
+    pub fn finish(mut self) -> anyhow::Result<ImageLayer> {
+        let final_path = self.final_path();
         let index_start_blk =
             ((self.blob_writer.size() + PAGE_SZ as u64 - 1) / PAGE_SZ as u64) as u32;
 
@@ -493,6 +580,7 @@ impl ImageLayerWriter {
         // Write out the index
         file.seek(SeekFrom::Start(index_start_blk as u64 * PAGE_SZ as u64))?;
         let (index_root_blk, block_buf) = self.tree.finish()?;
+        let file_size = (index_start_blk as u64 + block_buf.blocks.len() as u64) * PAGE_SZ as u64;
         for buf in block_buf.blocks {
             file.write_all(buf.as_ref())?;
         }
@@ -520,6 +608,8 @@ impl ImageLayerWriter {
             tenantid: self.tenantid,
             key_range: self.key_range.clone(),
             lsn: self.lsn,
+            stamp: None,
+            deletion: LayerDeletion::default(),
             inner: RwLock::new(ImageLayerInner {
                 loaded: false,
                 file: None,
@@ -531,20 +621,16 @@ impl ImageLayerWriter {
         // fsync the file
         file.sync_all()?;
 
+        // Hand the streamed parts over to the layer upload, without waiting for the stream
+        if let Some(upload_stream) = self.upload_stream.take() {
+            upload_stream.finish(file_size);
+        }
+
         // Rename the file to its final name
         //
         // Note: This overwrites any existing file. There shouldn't be any.
         // FIXME: throw an error instead?
-        let final_path = ImageLayer::path_for(
-            &PathOrConf::Conf(self.conf),
-            self.timelineid,
-            self.tenantid,
-            &ImageFileName {
-                key_range: self.key_range.clone(),
-                lsn: self.lsn,
-            },
-        );
-        std::fs::rename(self.path, &final_path)?;
+        std::fs::rename(&self.path, &final_path)?;
 
         trace!("created image layer {}", layer.path().display());
 
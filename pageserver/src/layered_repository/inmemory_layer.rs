@@ -4,18 +4,26 @@
 //! held in an ephemeral file, not in memory. The metadata for each page version, i.e.
 //! its position in the file, is kept in memory, though.
 //!
+//! The layer is written by the WAL receiver while GetPage@LSN requests read it,
+//! so the index is split into shards by key hash, each with its own lock. The
+//! ephemeral file is only locked exclusively for the duration of appending a
+//! value to it, and a read never holds a shard lock while reading the file.
+//!
 use crate::config::PageServerConf;
 use crate::layered_repository::blob_io::{BlobCursor, BlobWriter};
 use crate::layered_repository::block_io::BlockReader;
 use crate::layered_repository::delta_layer::{DeltaLayer, DeltaLayerWriter};
 use crate::layered_repository::ephemeral_file::EphemeralFile;
+use crate::layered_repository::flush_pacing::FlushPacing;
 use crate::layered_repository::storage_layer::{
     Layer, ValueReconstructResult, ValueReconstructState,
 };
 use crate::repository::{Key, Value};
 use crate::walrecord;
 use anyhow::{bail, ensure, Result};
-use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
 use tracing::*;
 use utils::{
     bin_ser::BeSer,
@@ -28,7 +36,14 @@ use utils::{
 use std::fmt::Write as _;
 use std::ops::Range;
 use std::path::PathBuf;
-use std::sync::RwLock;
+use std::sync::{RwLock, RwLockReadGuard};
+
+/// Number of independently locked shards of the layer's index.
+const INDEX_SHARDS: usize = 16;
+
+/// All versions of a page in the layer, ordered by LSN. The value is an offset
+/// into the ephemeral file where the page version is stored.
+type PageVersions = VecMap<Lsn, u64>;
 
 pub struct InMemoryLayer {
     conf: &'static PageServerConf,
@@ -41,9 +56,17 @@ pub struct InMemoryLayer {
     ///
     start_lsn: Lsn,
 
-    /// The above fields never change. The parts that do change are in 'inner',
-    /// and protected by mutex.
+    /// The above fields never change. The parts that do change are in 'inner'
+    /// and 'index_shards', protected by locks.
     inner: RwLock<InMemoryLayerInner>,
+
+    ///
+    /// All versions of all pages in the layer are kept here, indexed by key
+    /// and LSN. A key always goes to the shard given by `index_shard`.
+    ///
+    /// Lock ordering: when both are needed, 'inner' is locked before a shard.
+    ///
+    index_shards: Vec<RwLock<HashMap<Key, PageVersions>>>,
 }
 
 pub struct InMemoryLayerInner {
@@ -51,16 +74,9 @@ pub struct InMemoryLayerInner {
     /// Writes are only allowed when this is None
     end_lsn: Option<Lsn>,
 
-    ///
-    /// All versions of all pages in the layer are kept here.  Indexed
-    /// by block number and LSN. The value is an offset into the
-    /// ephemeral file where the page version is stored.
-    ///
-    index: HashMap<Key, VecMap<Lsn, u64>>,
-
     /// The values are stored in a serialized format in this file.
     /// Each serialized Value is preceded by a 'u32' length field.
-    /// PerSeg::page_versions map stores offsets into this file.
+    /// The index stores offsets into this file.
     file: EphemeralFile,
 }
 
@@ -122,36 +138,41 @@ impl Layer for InMemoryLayer {
         ensure!(lsn_range.start >= self.start_lsn);
         let mut need_image = true;
 
+        // Copy out the positions of the page versions, so that we don't hold
+        // the shard lock while reading the file.
+        let versions = match self.index_shard(&key).read().unwrap().get(&key) {
+            Some(vec_map) => vec_map.slice_range(lsn_range).to_vec(),
+            None => Vec::new(),
+        };
+        if versions.is_empty() {
+            return Ok(ValueReconstructResult::Continue);
+        }
+
         let inner = self.inner.read().unwrap();
 
         let mut reader = inner.file.block_cursor();
 
         // Scan the page versions backwards, starting from `lsn`.
-        if let Some(vec_map) = inner.index.get(&key) {
-            let slice = vec_map.slice_range(lsn_range);
-            for (entry_lsn, pos) in slice.iter().rev() {
-                let buf = reader.read_blob(*pos)?;
-                let value = Value::des(&buf)?;
-                match value {
-                    Value::Image(img) => {
-                        reconstruct_state.img = Some((*entry_lsn, img));
-                        return Ok(ValueReconstructResult::Complete);
-                    }
-                    Value::WalRecord(rec) => {
-                        let will_init = rec.will_init();
-                        reconstruct_state.records.push((*entry_lsn, rec));
-                        if will_init {
-                            // This WAL record initializes the page, so no need to go further back
-                            need_image = false;
-                            break;
-                        }
+        for (entry_lsn, pos) in versions.iter().rev() {
+            let buf = reader.read_blob(*pos)?;
+            let value = Value::des(&buf)?;
+            match value {
+                Value::Image(img) => {
+                    reconstruct_state.img = Some((*entry_lsn, img));
+                    return Ok(ValueReconstructResult::Complete);
+                }
+                Value::WalRecord(rec) => {
+                    let will_init = rec.will_init();
+                    reconstruct_state.records.push((*entry_lsn, rec));
+                    if will_init {
+                        // This WAL record initializes the page, so no need to go further back
+                        need_image = false;
+                        break;
                     }
                 }
             }
         }
 
-        // release lock on 'inner'
-
         // If an older page image is needed to reconstruct the page, let the
         // caller know.
         if need_image {
@@ -165,6 +186,17 @@ impl Layer for InMemoryLayer {
         todo!();
     }
 
+    fn collect_changed_keys(&self, lsn_range: &Range<Lsn>, keys: &mut HashSet<Key>) -> Result<()> {
+        for shard in self.read_index_shards() {
+            for (key, vec_map) in shard.iter() {
+                if !vec_map.slice_range(lsn_range.clone()).is_empty() {
+                    keys.insert(*key);
+                }
+            }
+        }
+        Ok(())
+    }
+
     /// Nothing to do here. When you drop the last reference to the layer, it will
     /// be deallocated.
     fn delete(&self) -> Result<()> {
@@ -201,7 +233,8 @@ impl Layer for InMemoryLayer {
 
         let mut cursor = inner.file.block_cursor();
         let mut buf = Vec::new();
-        for (key, vec_map) in inner.index.iter() {
+        let shards = self.read_index_shards();
+        for (key, vec_map) in shards.iter().flat_map(|shard| shard.iter()) {
             for (lsn, pos) in vec_map.as_slice() {
                 let mut desc = String::new();
                 cursor.read_blob_into_buf(*pos, &mut buf)?;
@@ -257,25 +290,48 @@ impl InMemoryLayer {
             start_lsn,
             inner: RwLock::new(InMemoryLayerInner {
                 end_lsn: None,
-                index: HashMap::new(),
                 file,
             }),
+            index_shards: (0..INDEX_SHARDS)
+                .map(|_| RwLock::new(HashMap::new()))
+                .collect(),
         })
     }
 
+    fn index_shard(&self, key: &Key) -> &RwLock<HashMap<Key, PageVersions>> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.index_shards[hasher.finish() as usize % INDEX_SHARDS]
+    }
+
+    /// Lock all the index shards for reading, in order.
+    fn read_index_shards(&self) -> Vec<RwLockReadGuard<'_, HashMap<Key, PageVersions>>> {
+        self.index_shards
+            .iter()
+            .map(|shard| shard.read().unwrap())
+            .collect()
+    }
+
     // Write operations
 
     /// Common subroutine of the public put_wal_record() and put_page_image() functions.
     /// Adds the page version to the in-memory tree
+    ///
+    /// The caller must ensure that there's only one writer at a time, and that
+    /// the layer is not frozen concurrently. LayeredTimeline's 'write_lock'
+    /// takes care of both.
     pub fn put_value(&self, key: Key, lsn: Lsn, val: Value) -> Result<()> {
         trace!("put_value key {} at {}/{}", key, self.timelineid, lsn);
-        let mut inner = self.inner.write().unwrap();
-
-        inner.assert_writeable();
+        let buf = Value::ser(&val)?;
 
-        let off = inner.file.write_blob(&Value::ser(&val)?)?;
+        let off = {
+            let mut inner = self.inner.write().unwrap();
+            inner.assert_writeable();
+            inner.file.write_blob(&buf)?
+        };
 
-        let vec_map = inner.index.entry(key).or_default();
+        let mut shard = self.index_shard(&key).write().unwrap();
+        let vec_map = shard.entry(key).or_default();
         let old = vec_map.append_or_update_last(lsn, off).unwrap().0;
         if old.is_some() {
             // We already had an entry for this LSN. That's odd..
@@ -291,6 +347,11 @@ impl InMemoryLayer {
         Ok(())
     }
 
+    /// Size of the ephemeral file that holds the values of the layer.
+    pub fn size(&self) -> u64 {
+        self.inner.read().unwrap().file.size()
+    }
+
     /// Make the layer non-writeable. Only call once.
     /// Records the end_lsn for non-dropped layers.
     /// `end_lsn` is exclusive
@@ -300,17 +361,21 @@ impl InMemoryLayer {
         assert!(self.start_lsn < end_lsn);
         inner.end_lsn = Some(end_lsn);
 
-        for vec_map in inner.index.values() {
+        for vec_map in self
+            .read_index_shards()
+            .iter()
+            .flat_map(|shard| shard.values())
+        {
             for (lsn, _pos) in vec_map.as_slice() {
                 assert!(*lsn < end_lsn);
             }
         }
     }
 
-    /// Write this frozen in-memory layer to disk.
+    /// Write this frozen in-memory layer to disk, at the pace set by 'pacing'.
     ///
     /// Returns a new delta layer with all the same data as this in-memory layer
-    pub fn write_to_disk(&self) -> Result<DeltaLayer> {
+    pub fn write_to_disk(&self, pacing: &mut FlushPacing) -> Result<DeltaLayer> {
         // Grab the lock in read-mode. We hold it over the I/O, but because this
         // layer is not writeable anymore, no one should be trying to acquire the
         // write lock on it, so we shouldn't block anyone. There's one exception
@@ -334,7 +399,9 @@ impl InMemoryLayer {
 
         let mut cursor = inner.file.block_cursor();
 
-        let mut keys: Vec<(&Key, &VecMap<Lsn, u64>)> = inner.index.iter().collect();
+        let shards = self.read_index_shards();
+        let mut keys: Vec<(&Key, &PageVersions)> =
+            shards.iter().flat_map(|shard| shard.iter()).collect();
         keys.sort_by_key(|k| k.0);
 
         for (key, vec_map) in keys.iter() {
@@ -344,6 +411,9 @@ impl InMemoryLayer {
                 cursor.read_blob_into_buf(*pos, &mut buf)?;
                 let val = Value::des(&buf)?;
                 delta_layer_writer.put_value(key, *lsn, val)?;
+                if pacing.wrote(buf.len() as u64) {
+                    delta_layer_writer.sync_data()?;
+                }
             }
         }
 
@@ -351,3 +421,120 @@ impl InMemoryLayer {
         Ok(delta_layer)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layered_repository::flush_pacing::FlushPacer;
+    use crate::repository::repo_harness::{RepoHarness, TEST_IMG, TIMELINE_ID};
+    use bytes::Bytes;
+    use std::sync::Arc;
+    use std::thread;
+
+    const NUM_KEYS: u32 = 100;
+    const NUM_VERSIONS: u64 = 20;
+
+    fn test_key(i: u32) -> Key {
+        Key::from_hex("112222222233333333444444445500000000")
+            .unwrap()
+            .add(i)
+    }
+
+    fn test_value(i: u32, lsn: Lsn) -> Bytes {
+        TEST_IMG(&format!("key {} at {}", i, lsn))
+    }
+
+    /// Read the latest version of a key, as of 'lsn'.
+    fn read_image(layer: &InMemoryLayer, key: Key, lsn: Lsn) -> Result<Option<(Lsn, Bytes)>> {
+        let mut reconstruct_state = ValueReconstructState {
+            records: Vec::new(),
+            img: None,
+        };
+        layer.get_value_reconstruct_data(key, layer.start_lsn..lsn, &mut reconstruct_state)?;
+        Ok(reconstruct_state.img)
+    }
+
+    #[test]
+    fn concurrent_reads_and_writes() -> Result<()> {
+        let harness = RepoHarness::create("inmemory_layer_concurrent_reads_and_writes")?;
+        std::fs::create_dir_all(harness.conf.timeline_path(&TIMELINE_ID, &harness.tenant_id))?;
+        let layer = Arc::new(InMemoryLayer::create(
+            harness.conf,
+            TIMELINE_ID,
+            harness.tenant_id,
+            Lsn(0x10),
+        )?);
+
+        // Keys spread over the shards
+        let mut used_shards = HashSet::new();
+        for i in 0..NUM_KEYS {
+            used_shards.insert(layer.index_shard(&test_key(i)) as *const _);
+        }
+        assert!(used_shards.len() > 1, "all keys went to one shard");
+
+        // Readers only ever see complete versions while the layer is written
+        let readers = (0..4)
+            .map(|_| {
+                let layer = Arc::clone(&layer);
+                thread::spawn(move || -> Result<()> {
+                    for _ in 0..NUM_VERSIONS {
+                        for i in 0..NUM_KEYS {
+                            if let Some((lsn, img)) =
+                                read_image(&layer, test_key(i), Lsn(u64::MAX))?
+                            {
+                                assert_eq!(img, test_value(i, lsn));
+                            }
+                        }
+                    }
+                    Ok(())
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for version in 0..NUM_VERSIONS {
+            let lsn = Lsn(0x10 + version * 0x10);
+            for i in 0..NUM_KEYS {
+                layer.put_value(test_key(i), lsn, Value::Image(test_value(i, lsn)))?;
+            }
+        }
+        for reader in readers {
+            reader.join().unwrap()?;
+        }
+
+        // Every version can be read back
+        for version in 0..NUM_VERSIONS {
+            let lsn = Lsn(0x10 + version * 0x10);
+            for i in 0..NUM_KEYS {
+                assert_eq!(
+                    read_image(&layer, test_key(i), lsn + 1)?,
+                    Some((lsn, test_value(i, lsn)))
+                );
+            }
+        }
+
+        // The delta layer gets all the versions of all the shards, in key order
+        let end_lsn = Lsn(0x10 + NUM_VERSIONS * 0x10);
+        layer.freeze(end_lsn);
+        let mut pacing = FlushPacer::new(0, 0).start_flush(0);
+        let delta_layer = layer.write_to_disk(&mut pacing)?;
+        let mut expected = Vec::new();
+        for i in 0..NUM_KEYS {
+            for version in 0..NUM_VERSIONS {
+                let lsn = Lsn(0x10 + version * 0x10);
+                expected.push((test_key(i), lsn));
+            }
+        }
+        let mut written = Vec::new();
+        for entry in delta_layer.iter() {
+            let (key, lsn, value) = entry?;
+            match value {
+                Value::Image(img) => assert_eq!(img, test_value(key.field6, lsn)),
+                Value::WalRecord(_) => panic!("unexpected WAL record for key {}", key),
+            }
+            written.push((key, lsn));
+        }
+        assert_eq!(written, expected);
+
+        Ok(())
+    }
+}
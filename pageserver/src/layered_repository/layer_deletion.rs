@@ -0,0 +1,246 @@
+//!
+//! Deferred deletion of the layer files that GC and compaction remove.
+//!
+//! A layer removed from the layer map can still be in use: a reader that
+//! looked it up before holds an `Arc` to it, and reads its file, reopening it
+//! if the virtual file cache closed the descriptor meanwhile. So the file is not
+//! deleted right away. [`LayerDeletion::schedule`] leaves a tombstone file
+//! next to it, named `<layer file>.deleted`, and the file is deleted when the
+//! last reference to the layer is dropped, in [`LayerDeletion::complete`].
+//!
+//! The tombstone holds the identity of the file, its device and inode numbers,
+//! so that a new layer file created with the same name meanwhile is never
+//! deleted in its place. If the pageserver stops before the deletion completes,
+//! the tombstones are found when the timeline is loaded again, and the files
+//! they name are deleted then, see [`complete_interrupted_deletions`].
+//!
+use std::ffi::OsStr;
+use std::fs;
+use std::io::ErrorKind;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Context, Result};
+use lazy_static::lazy_static;
+use metrics::{register_int_counter, register_int_gauge, IntCounter, IntGauge};
+use tracing::*;
+
+use super::storage_layer::Layer;
+
+/// Extension of the tombstone file of a layer file that is to be deleted.
+pub const TOMBSTONE_EXTENSION: &str = "deleted";
+
+lazy_static! {
+    static ref PENDING_DELETIONS: IntGauge = register_int_gauge!(
+        "pageserver_layer_deletions_pending",
+        "Number of removed layer files that are not deleted yet, because the layers are still in use"
+    )
+    .expect("failed to define a metric");
+    static ref DEFERRED_DELETIONS: IntCounter = register_int_counter!(
+        "pageserver_layer_deletions_deferred_total",
+        "Number of layer file deletions deferred until the last reader of the layer was done"
+    )
+    .expect("failed to define a metric");
+}
+
+/// Identity of a file, that stays the same when the file is renamed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FileId {
+    dev: u64,
+    ino: u64,
+}
+
+impl FileId {
+    fn of(path: &Path) -> std::io::Result<Self> {
+        let metadata = fs::metadata(path)?;
+        Ok(Self {
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+        })
+    }
+
+    fn parse(s: &str) -> Result<Self> {
+        let (dev, ino) = s
+            .trim()
+            .split_once(' ')
+            .ok_or_else(|| anyhow!("malformed tombstone '{s}'"))?;
+        Ok(Self {
+            dev: dev.parse()?,
+            ino: ino.parse()?,
+        })
+    }
+}
+
+pub(super) fn tombstone_path(path: &Path) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".");
+    file_name.push(TOMBSTONE_EXTENSION);
+    path.with_file_name(file_name)
+}
+
+///
+/// Deletion state of an on-disk layer.
+///
+#[derive(Default)]
+pub struct LayerDeletion {
+    /// Identity of the file to delete, once the deletion is scheduled.
+    doomed: Mutex<Option<FileId>>,
+}
+
+impl LayerDeletion {
+    /// Schedule the deletion of the layer file at 'path', for when the layer
+    /// is dropped.
+    pub fn schedule(&self, path: &Path) -> Result<()> {
+        let mut doomed = self.doomed.lock().unwrap();
+        if doomed.is_some() {
+            return Ok(());
+        }
+        let id = FileId::of(path)
+            .with_context(|| format!("Failed to stat layer file {}", path.display()))?;
+        let tombstone = tombstone_path(path);
+        fs::write(&tombstone, format!("{} {}", id.dev, id.ino))
+            .with_context(|| format!("Failed to write {}", tombstone.display()))?;
+        *doomed = Some(id);
+        PENDING_DELETIONS.inc();
+        Ok(())
+    }
+
+    /// Delete the layer file at 'path', if its deletion was scheduled. Called
+    /// when the layer is dropped.
+    pub fn complete(&self, path: &Path) {
+        if let Some(id) = self.doomed.lock().unwrap().take() {
+            if let Err(e) = delete_file(path, id) {
+                error!("Failed to delete layer file {}: {e:#}", path.display());
+            }
+            PENDING_DELETIONS.dec();
+        }
+    }
+}
+
+/// Delete the file at 'path' if it's the one with the given identity, and
+/// then its tombstone.
+fn delete_file(path: &Path, id: FileId) -> Result<()> {
+    match FileId::of(path) {
+        Ok(current_id) if current_id == id => fs::remove_file(path)?,
+        Ok(_) => info!(
+            "layer file {} was replaced, not deleting it",
+            path.display()
+        ),
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    match fs::remove_file(tombstone_path(path)) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+///
+/// Release the reference to a layer that was removed from the layer map and
+/// scheduled for deletion. The layer file is deleted right away, unless a
+/// reader still uses the layer.
+///
+pub fn release_removed_layer(layer: Arc<dyn Layer>) {
+    let weak = Arc::downgrade(&layer);
+    drop(layer);
+    if weak.strong_count() > 0 {
+        DEFERRED_DELETIONS.inc();
+    }
+}
+
+///
+/// Complete the deletions that were scheduled but not done when the pageserver
+/// stopped: delete the layer files that have a tombstone, and the tombstones.
+///
+pub fn complete_interrupted_deletions(timeline_path: &Path) -> Result<()> {
+    for entry in fs::read_dir(timeline_path)
+        .with_context(|| format!("Failed to list {}", timeline_path.display()))?
+    {
+        let tombstone = entry?.path();
+        if tombstone.extension() != Some(OsStr::new(TOMBSTONE_EXTENSION)) {
+            continue;
+        }
+        let path = tombstone.with_extension("");
+        let contents = fs::read_to_string(&tombstone)
+            .with_context(|| format!("Failed to read {}", tombstone.display()))?;
+        match FileId::parse(&contents) {
+            Ok(id) => {
+                info!("completing the deletion of layer file {}", path.display());
+                delete_file(&path, id)
+                    .with_context(|| format!("Failed to delete {}", path.display()))?;
+            }
+            // Written partially, the file was not removed from the layer map yet
+            Err(e) => {
+                warn!("removing invalid tombstone {}: {e:#}", tombstone.display());
+                fs::remove_file(&tombstone)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Replace the file with a new one, like a new layer file is created.
+    fn replace_file(path: &Path) -> Result<()> {
+        let temp_path = path.with_extension("temp");
+        fs::write(&temp_path, "new layer")?;
+        fs::rename(&temp_path, path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn deferred_deletion() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("layer");
+        fs::write(&path, "layer")?;
+
+        let deletion = LayerDeletion::default();
+        deletion.schedule(&path)?;
+        assert!(path.exists(), "the file is deleted only when completed");
+        assert!(tombstone_path(&path).exists());
+        deletion.complete(&path);
+        assert!(!path.exists());
+        assert!(!tombstone_path(&path).exists());
+
+        // A file created with the same name meanwhile is kept
+        fs::write(&path, "old layer")?;
+        deletion.schedule(&path)?;
+        replace_file(&path)?;
+        deletion.complete(&path);
+        assert_eq!(fs::read_to_string(&path)?, "new layer");
+        assert!(!tombstone_path(&path).exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn interrupted_deletion() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let doomed = dir.path().join("doomed");
+        let replaced = dir.path().join("replaced");
+        let kept = dir.path().join("kept");
+        for path in [&doomed, &replaced, &kept] {
+            fs::write(path, "layer")?;
+        }
+        LayerDeletion::default().schedule(&doomed)?;
+        LayerDeletion::default().schedule(&replaced)?;
+        replace_file(&replaced)?;
+        fs::write(tombstone_path(&kept), "")?;
+
+        complete_interrupted_deletions(dir.path())?;
+        assert!(!doomed.exists());
+        assert!(replaced.exists());
+        assert!(kept.exists());
+        assert_eq!(
+            fs::read_dir(dir.path())?.count(),
+            2,
+            "tombstones are removed"
+        );
+
+        Ok(())
+    }
+}
@@ -239,6 +239,25 @@ impl LayerMap {
         }
     }
 
+    ///
+    /// Copy of the layer map, with only the historic layers that overlap
+    /// 'key_range'. Searching it is cheaper when many keys in the range are
+    /// looked up, see LayeredTimeline::get_range().
+    ///
+    pub fn subset(&self, key_range: &Range<Key>) -> LayerMap {
+        LayerMap {
+            open_layer: self.open_layer.clone(),
+            next_open_layer_at: self.next_open_layer_at,
+            frozen_layers: self.frozen_layers.clone(),
+            historic_layers: self
+                .historic_layers
+                .iter()
+                .filter(|l| range_overlaps(&l.get_key_range(), key_range))
+                .cloned()
+                .collect(),
+        }
+    }
+
     pub fn iter_historic_layers(&self) -> impl Iterator<Item = &Arc<dyn Layer>> {
         self.historic_layers.iter()
     }
@@ -350,10 +369,7 @@ impl LayerMap {
     pub fn get_level0_deltas(&self) -> Result<Vec<Arc<dyn Layer>>> {
         let mut deltas = Vec::new();
         for l in self.historic_layers.iter() {
-            if !l.is_incremental() {
-                continue;
-            }
-            if l.get_key_range() != (Key::MIN..Key::MAX) {
+            if !Self::is_level0_delta(l.as_ref()) {
                 continue;
             }
             deltas.push(Arc::clone(l));
@@ -361,6 +377,12 @@ impl LayerMap {
         Ok(deltas)
     }
 
+    /// Is the layer an L0 delta layer, i.e. a delta layer that covers the
+    /// whole key space?
+    pub fn is_level0_delta(layer: &dyn Layer) -> bool {
+        layer.is_incremental() && layer.get_key_range() == (Key::MIN..Key::MAX)
+    }
+
     /// debugging function to print out the contents of the layer map
     #[allow(unused)]
     pub fn dump(&self, verbose: bool) -> Result<()> {
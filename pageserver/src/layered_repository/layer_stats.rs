@@ -0,0 +1,203 @@
+//!
+//! Per-layer statistics of the keys stored in a delta layer.
+//!
+//! A lookup of a key goes through all the delta layers whose key and LSN
+//! ranges cover it, down to the first image. L0 delta layers cover the whole
+//! key space, so most of them don't contain the key at all, and finding that
+//! out takes a walk down the layer's B-tree index. The statistics let the
+//! lookup skip such layers without touching the index:
+//!
+//! - a bloom filter of the keys present in the layer, and
+//! - a sketch of the latest LSN of each key: the keys are hashed into buckets,
+//!   and each bucket holds the max LSN of the keys in it. That's an upper
+//!   bound of the latest version of a key in the layer, so if the lookup only
+//!   needs versions newer than that, the layer has nothing for it.
+//!
+//! The statistics are stored in their own chapter of the delta layer file,
+//! after the index. Layers written before the chapter was added don't have
+//! it, and are always searched.
+//!
+//! The hash function is part of the file format, and must not change.
+//!
+use std::cmp::{max, min};
+
+use lazy_static::lazy_static;
+use metrics::{register_int_counter, IntCounter};
+use serde::{Deserialize, Serialize};
+use utils::lsn::Lsn;
+
+use crate::repository::{Key, KEY_SIZE};
+
+/// Bits of the bloom filter per key. With 7 hash functions, that gives
+/// about 1% false positives.
+const BLOOM_BITS_PER_KEY: usize = 10;
+const BLOOM_NUM_HASHES: u32 = 7;
+
+/// One max LSN bucket per this many keys.
+const KEYS_PER_LSN_BUCKET: usize = 8;
+const MAX_LSN_BUCKETS: usize = 4096;
+
+lazy_static! {
+    static ref LAYER_LOOKUPS_SKIPPED: IntCounter = register_int_counter!(
+        "pageserver_layer_lookups_skipped_total",
+        "Number of delta layer lookups skipped based on the layer statistics"
+    )
+    .expect("failed to define a metric");
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct LayerStats {
+    bloom_bits: Vec<u64>,
+    max_lsns: Vec<Lsn>,
+}
+
+impl LayerStats {
+    ///
+    /// Can the layer contain versions of 'key' at or above 'min_lsn'? False
+    /// means that it certainly doesn't.
+    ///
+    pub fn may_contain(&self, key: &Key, min_lsn: Lsn) -> bool {
+        let hash = key_hash(key);
+        let result = self.bloom_contains(hash) && self.max_lsn(hash) >= min_lsn;
+        if !result {
+            LAYER_LOOKUPS_SKIPPED.inc();
+        }
+        result
+    }
+
+    fn bloom_contains(&self, hash: u64) -> bool {
+        let num_bits = self.bloom_bits.len() as u64 * 64;
+        if num_bits == 0 {
+            return false;
+        }
+        bloom_positions(hash, num_bits)
+            .all(|bit| self.bloom_bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    fn max_lsn(&self, hash: u64) -> Lsn {
+        if self.max_lsns.is_empty() {
+            return Lsn(0);
+        }
+        self.max_lsns[lsn_bucket(hash, self.max_lsns.len())]
+    }
+}
+
+///
+/// Collects the statistics of a delta layer while it's written. The keys must
+/// be added in key, LSN order, like the values of the layer.
+///
+#[derive(Default)]
+pub struct LayerStatsBuilder {
+    /// Hash and latest LSN of every key added so far
+    keys: Vec<(u64, Lsn)>,
+    last_key: Option<Key>,
+}
+
+impl LayerStatsBuilder {
+    pub fn add(&mut self, key: &Key, lsn: Lsn) {
+        if self.last_key.as_ref() == Some(key) {
+            let last = self.keys.last_mut().unwrap();
+            last.1 = max(last.1, lsn);
+        } else {
+            self.keys.push((key_hash(key), lsn));
+            self.last_key = Some(*key);
+        }
+    }
+
+    pub fn finish(self) -> LayerStats {
+        let num_words = (self.keys.len() * BLOOM_BITS_PER_KEY + 63) / 64;
+        let mut bloom_bits = vec![0u64; max(num_words, 1)];
+        let num_bits = bloom_bits.len() as u64 * 64;
+
+        let num_buckets = min(
+            (self.keys.len() / KEYS_PER_LSN_BUCKET + 1).next_power_of_two(),
+            MAX_LSN_BUCKETS,
+        );
+        let mut max_lsns = vec![Lsn(0); num_buckets];
+
+        for (hash, lsn) in self.keys {
+            for bit in bloom_positions(hash, num_bits) {
+                bloom_bits[bit / 64] |= 1 << (bit % 64);
+            }
+            let bucket = &mut max_lsns[lsn_bucket(hash, num_buckets)];
+            *bucket = max(*bucket, lsn);
+        }
+
+        LayerStats {
+            bloom_bits,
+            max_lsns,
+        }
+    }
+}
+
+/// FNV-1a over the serialized key, followed by a finalizer to spread the bits.
+fn key_hash(key: &Key) -> u64 {
+    let mut buf = [0u8; KEY_SIZE];
+    key.write_to_byte_slice(&mut buf);
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for b in buf {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    // splitmix64 finalizer
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d049bb133111eb);
+    hash ^ (hash >> 31)
+}
+
+/// Bit positions of a key in a bloom filter of 'num_bits', by double hashing.
+fn bloom_positions(hash: u64, num_bits: u64) -> impl Iterator<Item = usize> {
+    let h1 = hash & 0xffffffff;
+    let h2 = (hash >> 32) | 1;
+    (0..BLOOM_NUM_HASHES as u64)
+        .map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % num_bits) as usize)
+}
+
+fn lsn_bucket(hash: u64, num_buckets: usize) -> usize {
+    // The low bits are used by the bloom filter already
+    (hash.rotate_right(17) % num_buckets as u64) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_key(blknum: u32) -> Key {
+        Key {
+            field1: 0,
+            field2: 1663,
+            field3: 13010,
+            field4: 1259,
+            field5: 0,
+            field6: blknum,
+        }
+    }
+
+    #[test]
+    fn layer_stats() {
+        let mut builder = LayerStatsBuilder::default();
+        for blknum in 0..1000 {
+            builder.add(&test_key(blknum), Lsn(0x10));
+            builder.add(&test_key(blknum), Lsn(0x20 + blknum as u64));
+        }
+        let stats = builder.finish();
+
+        // No false negatives
+        for blknum in 0..1000 {
+            assert!(stats.may_contain(&test_key(blknum), Lsn(0x20 + blknum as u64)));
+        }
+
+        // Few false positives
+        let false_positives = (1000..11000)
+            .filter(|blknum| stats.may_contain(&test_key(*blknum), Lsn(0)))
+            .count();
+        assert!(false_positives < 300, "{false_positives} false positives");
+
+        // Nothing newer than the latest version of the layer
+        assert!(!stats.may_contain(&test_key(5), Lsn(0x20 + 1000)));
+
+        // An empty layer contains nothing
+        let empty = LayerStatsBuilder::default().finish();
+        assert!(!empty.may_contain(&test_key(0), Lsn(0)));
+    }
+}
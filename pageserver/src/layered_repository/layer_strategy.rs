@@ -0,0 +1,219 @@
+//!
+//! Policies of the layer maintenance: which layer files GC keeps, and when the
+//! Level 0 delta layers get compacted.
+//!
+//! The decisions are made by a [`LayerStrategy`]. The default methods of the
+//! trait implement the rules that the page server always used, see
+//! [`default_gc_retain_reason`], and [`DefaultLayerStrategy`] is used unless a
+//! deployment installs a strategy of its own with [`install`], before any
+//! tenant is loaded.
+//!
+//! A custom strategy typically keeps more layers than the default one, e.g.
+//! the layers of the tenants under a legal hold, and returns
+//! [`GcRetainReason::Policy`] for them. For all the other layers it should
+//! defer to [`default_gc_retain_reason`]: a layer that the default rules keep
+//! for a child branch, or because no newer image layer covers it, still holds
+//! data that the timeline needs.
+//!
+use std::cmp::min;
+use std::collections::HashSet;
+use std::fs;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use once_cell::sync::OnceCell;
+use tracing::*;
+use utils::lsn::Lsn;
+use utils::zid::{ZTenantId, ZTimelineId};
+
+use super::layer_map::LayerMap;
+use crate::repository::{GcRetainReason, Key};
+
+static LAYER_STRATEGY: OnceCell<Arc<dyn LayerStrategy>> = OnceCell::new();
+
+///
+/// Install the strategy of the repositories that are created after this call.
+/// Can be called once.
+///
+pub fn install(strategy: Arc<dyn LayerStrategy>) -> Result<()> {
+    LAYER_STRATEGY
+        .set(strategy)
+        .map_err(|_| anyhow!("a layer strategy is installed already"))
+}
+
+/// The installed strategy, or the default one.
+pub fn installed() -> Arc<dyn LayerStrategy> {
+    match LAYER_STRATEGY.get() {
+        Some(strategy) => Arc::clone(strategy),
+        None => Arc::new(DefaultLayerStrategy),
+    }
+}
+
+/// An on-disk layer that GC decides on.
+#[derive(Debug, Clone)]
+pub struct GcLayer {
+    pub filename: PathBuf,
+    pub path: Option<PathBuf>,
+    pub key_range: Range<Key>,
+    pub lsn_range: Range<Lsn>,
+    pub is_incremental: bool,
+    pub size: u64,
+}
+
+///
+/// What GC of a timeline knows when it decides on its layers.
+///
+pub struct GcContext<'a> {
+    pub tenant_id: ZTenantId,
+    pub timeline_id: ZTimelineId,
+    /// The GC horizon, held back for the read replicas.
+    pub cutoff: Lsn,
+    /// The LSN at the start of the PITR interval.
+    pub pitr_cutoff: Lsn,
+    /// The LSNs that the child branches are forked at.
+    pub retain_lsns: &'a [Lsn],
+    pub gc_min_layer_age: Duration,
+    /// The layer files in the remote storage, when the layers are uploaded.
+    pub uploaded_layers: Option<&'a HashSet<PathBuf>>,
+    pub(super) layers: &'a LayerMap,
+}
+
+impl GcContext<'_> {
+    /// Do on-disk image layers newer than the layer, and older than the GC
+    /// cutoffs, cover its whole key range?
+    pub fn newer_images_cover(&self, layer: &GcLayer) -> Result<bool> {
+        let gc_cutoff = min(self.cutoff, self.pitr_cutoff);
+        self.layers
+            .image_layer_exists(&layer.key_range, &(layer.lsn_range.end..gc_cutoff))
+    }
+}
+
+pub trait LayerStrategy: Send + Sync {
+    /// Why GC has to keep the layer, or `None` if it can be removed.
+    fn gc_retain_reason(&self, layer: &GcLayer, ctx: &GcContext) -> Result<Option<GcRetainReason>> {
+        default_gc_retain_reason(layer, ctx)
+    }
+
+    /// Is it time to compact the Level 0 delta layers of a timeline?
+    fn should_compact_level0(&self, level0_deltas: usize, compaction_threshold: usize) -> bool {
+        level0_deltas >= compaction_threshold
+    }
+}
+
+/// The rules of the page server, see the module documentation.
+pub struct DefaultLayerStrategy;
+
+impl LayerStrategy for DefaultLayerStrategy {}
+
+///
+/// The default retention rules. GC removes a layer if all of these hold:
+/// 1. it is older than cutoff LSN;
+/// 2. it is older than PITR interval;
+/// 3. it doesn't need to be retained for 'retain_lsns';
+/// 4. newer on-disk image layers cover the layer's whole key range
+/// 5. it is older than 'gc_min_layer_age' and uploaded, if uploads are on
+///
+pub fn default_gc_retain_reason(
+    layer: &GcLayer,
+    ctx: &GcContext,
+) -> Result<Option<GcRetainReason>> {
+    // 1. Is it newer than cutoff point?
+    if layer.lsn_range.end > ctx.cutoff {
+        debug!(
+            "keeping {} because it's newer than cutoff {}",
+            layer.filename.display(),
+            ctx.cutoff
+        );
+        return Ok(Some(GcRetainReason::Cutoff));
+    }
+
+    // 2. It is newer than PiTR cutoff point?
+    if layer.lsn_range.end > ctx.pitr_cutoff {
+        debug!(
+            "keeping {} because it's newer than pitr_cutoff_lsn {}",
+            layer.filename.display(),
+            ctx.pitr_cutoff
+        );
+        return Ok(Some(GcRetainReason::Pitr));
+    }
+
+    // 3. Is it needed by a child branch?
+    // NOTE With that we would keep data that
+    // might be referenced by child branches forever.
+    // We can track this in child timeline GC and delete parent layers when
+    // they are no longer needed. This might be complicated with long inheritance chains.
+    for retain_lsn in ctx.retain_lsns {
+        // start_lsn is inclusive
+        if &layer.lsn_range.start <= retain_lsn {
+            debug!(
+                "keeping {} because it's still might be referenced by child branch forked at {} is_dropped: xx is_incremental: {}",
+                layer.filename.display(),
+                retain_lsn,
+                layer.is_incremental,
+            );
+            return Ok(Some(GcRetainReason::Branches));
+        }
+    }
+
+    // 4. Is there a later on-disk layer for this relation?
+    //
+    // The end-LSN is exclusive, while disk_consistent_lsn is
+    // inclusive. For example, if disk_consistent_lsn is 100, it is
+    // OK for a delta layer to have end LSN 101, but if the end LSN
+    // is 102, then it might not have been fully flushed to disk
+    // before crash.
+    //
+    // For example, imagine that the following layers exist:
+    //
+    // 1000      - image (A)
+    // 1000-2000 - delta (B)
+    // 2000      - image (C)
+    // 2000-3000 - delta (D)
+    // 3000      - image (E)
+    //
+    // If GC horizon is at 2500, we can remove layers A and B, but
+    // we cannot remove C, even though it's older than 2500, because
+    // the delta layer 2000-3000 depends on it.
+    if !ctx.newer_images_cover(layer)? {
+        debug!(
+            "keeping {} because it is the latest layer",
+            layer.filename.display()
+        );
+        return Ok(Some(GcRetainReason::Latest));
+    }
+
+    // 5. Is it too recent? A layer can become obsolete right after it was
+    // created, e.g. by a compaction, and be removed while it's still
+    // being uploaded.
+    if let Some(reason) = recent_layer_reason(layer, ctx) {
+        debug!("keeping {} because {}", layer.filename.display(), reason);
+        return Ok(Some(GcRetainReason::Recent));
+    }
+
+    Ok(None)
+}
+
+/// Why the layer is too recent to be removed by GC, if it is: it was created
+/// less than 'gc_min_layer_age' ago, or it isn't uploaded yet.
+fn recent_layer_reason(layer: &GcLayer, ctx: &GcContext) -> Option<&'static str> {
+    let path = layer.path.as_ref()?;
+    if let Some(uploaded_layers) = ctx.uploaded_layers {
+        if !uploaded_layers.contains(path) {
+            return Some("it's not uploaded yet");
+        }
+    }
+    if ctx.gc_min_layer_age > Duration::ZERO {
+        let age = fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .and_then(|modified| modified.elapsed().ok());
+        // If the age is unknown, e.g. the clock went backwards, be conservative
+        if age.map_or(true, |age| age < ctx.gc_min_layer_age) {
+            return Some("it's younger than gc_min_layer_age");
+        }
+    }
+    None
+}
@@ -10,6 +10,7 @@ use std::path::PathBuf;
 
 use anyhow::ensure;
 use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
 use utils::{
     bin_ser::BeSer,
     lsn::Lsn,
@@ -65,6 +66,25 @@ struct TimelineMetadataBody {
     initdb_lsn: Lsn,
 }
 
+/// The fields of [`TimelineMetadata`] in a readable form, for dumping the
+/// metadata as JSON and restoring it, see the `update_metadata` binary.
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TimelineMetadataDump {
+    #[serde_as(as = "DisplayFromStr")]
+    pub disk_consistent_lsn: Lsn,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub prev_record_lsn: Option<Lsn>,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub ancestor_timeline: Option<ZTimelineId>,
+    #[serde_as(as = "DisplayFromStr")]
+    pub ancestor_lsn: Lsn,
+    #[serde_as(as = "DisplayFromStr")]
+    pub latest_gc_cutoff_lsn: Lsn,
+    #[serde_as(as = "DisplayFromStr")]
+    pub initdb_lsn: Lsn,
+}
+
 /// Points to a place in pageserver's local directory,
 /// where certain timeline's metadata file should be located.
 pub fn metadata_path(
@@ -108,6 +128,12 @@ impl TimelineMetadata {
             "metadata bytes size is wrong"
         );
         let hdr = TimelineMetadataHeader::des(&metadata_bytes[0..METADATA_HDR_SIZE])?;
+        ensure!(
+            hdr.format_version <= STORAGE_FORMAT_VERSION,
+            "metadata was written by a newer pageserver, format version {} (supported: {})",
+            hdr.format_version,
+            STORAGE_FORMAT_VERSION
+        );
         ensure!(
             hdr.format_version == STORAGE_FORMAT_VERSION,
             "format version mismatch"
@@ -171,6 +197,65 @@ impl TimelineMetadata {
     pub fn initdb_lsn(&self) -> Lsn {
         self.body.initdb_lsn
     }
+
+    pub fn dump(&self) -> TimelineMetadataDump {
+        TimelineMetadataDump {
+            disk_consistent_lsn: self.body.disk_consistent_lsn,
+            prev_record_lsn: self.body.prev_record_lsn,
+            ancestor_timeline: self.body.ancestor_timeline,
+            ancestor_lsn: self.body.ancestor_lsn,
+            latest_gc_cutoff_lsn: self.body.latest_gc_cutoff_lsn,
+            initdb_lsn: self.body.initdb_lsn,
+        }
+    }
+
+    ///
+    /// Create the metadata from a dump, after checking that the fields are
+    /// consistent with each other, as they are in the metadata that the page
+    /// server writes.
+    ///
+    pub fn from_dump(dump: &TimelineMetadataDump) -> anyhow::Result<Self> {
+        ensure!(
+            dump.disk_consistent_lsn.is_aligned(),
+            "disk_consistent_lsn {} is not aligned",
+            dump.disk_consistent_lsn
+        );
+        if let Some(prev_record_lsn) = dump.prev_record_lsn {
+            ensure!(
+                prev_record_lsn < dump.disk_consistent_lsn,
+                "prev_record_lsn {} is not before disk_consistent_lsn {}",
+                prev_record_lsn,
+                dump.disk_consistent_lsn
+            );
+        }
+        match dump.ancestor_timeline {
+            Some(_) => ensure!(
+                dump.ancestor_lsn <= dump.disk_consistent_lsn,
+                "ancestor_lsn {} is after disk_consistent_lsn {}",
+                dump.ancestor_lsn,
+                dump.disk_consistent_lsn
+            ),
+            None => ensure!(
+                dump.ancestor_lsn == Lsn(0),
+                "ancestor_lsn is set without an ancestor_timeline"
+            ),
+        }
+        ensure!(
+            dump.initdb_lsn <= dump.disk_consistent_lsn,
+            "initdb_lsn {} is after disk_consistent_lsn {}",
+            dump.initdb_lsn,
+            dump.disk_consistent_lsn
+        );
+
+        Ok(TimelineMetadata::new(
+            dump.disk_consistent_lsn,
+            dump.prev_record_lsn,
+            dump.ancestor_timeline,
+            dump.ancestor_lsn,
+            dump.latest_gc_cutoff_lsn,
+            dump.initdb_lsn,
+        ))
+    }
 }
 
 #[cfg(test)]
@@ -202,4 +287,48 @@ mod tests {
             "Metadata that was serialized to bytes and deserialized back should not change"
         );
     }
+
+    #[test]
+    fn metadata_dump_restore() {
+        let original_metadata = TimelineMetadata::new(
+            Lsn(0x200),
+            Some(Lsn(0x100)),
+            Some(TIMELINE_ID),
+            Lsn(0x80),
+            Lsn(0x40),
+            Lsn(0x10),
+        );
+
+        let json = serde_json::to_string(&original_metadata.dump()).unwrap();
+        assert!(json.contains("\"disk_consistent_lsn\":\"0/200\""), "{json}");
+        let dump: TimelineMetadataDump = serde_json::from_str(&json).unwrap();
+        let restored_metadata = TimelineMetadata::from_dump(&dump).unwrap();
+        assert_eq!(restored_metadata.body, original_metadata.body);
+
+        // Inconsistent fields are refused
+        let bad_dumps = [
+            TimelineMetadataDump {
+                disk_consistent_lsn: Lsn(0x201),
+                ..dump.clone()
+            },
+            TimelineMetadataDump {
+                prev_record_lsn: Some(Lsn(0x200)),
+                ..dump.clone()
+            },
+            TimelineMetadataDump {
+                ancestor_timeline: None,
+                ..dump.clone()
+            },
+            TimelineMetadataDump {
+                initdb_lsn: Lsn(0x300),
+                ..dump.clone()
+            },
+        ];
+        for bad_dump in &bad_dumps {
+            assert!(
+                TimelineMetadata::from_dump(bad_dump).is_err(),
+                "{bad_dump:?}"
+            );
+        }
+    }
 }
@@ -1,9 +1,14 @@
 use std::{
+    collections::HashMap,
     io,
+    os::unix::fs::MetadataExt,
     path::{Path, PathBuf},
     sync::atomic::{AtomicUsize, Ordering},
+    sync::{Arc, Condvar, Mutex},
 };
 
+use lazy_static::lazy_static;
+
 use crate::virtual_file::VirtualFile;
 
 fn fsync_path(path: &Path) -> io::Result<()> {
@@ -53,3 +58,182 @@ pub fn par_fsync(paths: &[PathBuf]) -> io::Result<()> {
     })
     .unwrap()
 }
+
+///
+/// Make everything written to the filesystem that holds 'path' durable, with
+/// syncfs(2).
+///
+/// Concurrent callers share the calls: a caller waits for the next syncfs that
+/// starts after it was called, which covers all the writes it made before,
+/// instead of running one of its own. When many timelines sync their new
+/// layers at the same time, the filesystem is synced a few times at most.
+///
+pub fn syncfs(path: &Path) -> io::Result<()> {
+    let dev = path.metadata()?.dev();
+    let group = Arc::clone(
+        SYNCFS_GROUPS
+            .lock()
+            .unwrap()
+            .entry(dev)
+            .or_insert_with(|| Arc::new(SyncfsGroup::default())),
+    );
+    group.sync(path)
+}
+
+lazy_static! {
+    /// The syncfs calls are shared per filesystem, by device id
+    static ref SYNCFS_GROUPS: Mutex<HashMap<u64, Arc<SyncfsGroup>>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Default)]
+struct SyncfsGroup {
+    state: Mutex<SyncfsState>,
+    completed: Condvar,
+}
+
+#[derive(Default)]
+struct SyncfsState {
+    running: bool,
+    /// Number of the syncfs calls started so far
+    started: u64,
+    /// Number of the last completed syncfs call, and whether it failed
+    completed: u64,
+    last_failed: bool,
+}
+
+impl SyncfsGroup {
+    fn sync(&self, path: &Path) -> io::Result<()> {
+        self.sync_with(|| syncfs_path(path))
+    }
+
+    /// Like `sync`, with 'sync_fn' doing the actual syncfs call
+    fn sync_with(&self, sync_fn: impl FnOnce() -> io::Result<()>) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        // Only a call that starts from now on covers the caller's writes
+        let target = state.started + 1;
+        while state.running && state.completed < target {
+            state = self.completed.wait(state).unwrap();
+        }
+        if state.completed >= target {
+            if state.last_failed {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "syncfs failed in another thread",
+                ));
+            }
+            return Ok(());
+        }
+
+        state.running = true;
+        state.started += 1;
+        let call = state.started;
+        drop(state);
+        let result = sync_fn();
+        let mut state = self.state.lock().unwrap();
+        state.running = false;
+        state.completed = call;
+        state.last_failed = result.is_err();
+        self.completed.notify_all();
+        result
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn syncfs_path(path: &Path) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let file = std::fs::File::open(path)?;
+    // SAFETY: the file descriptor stays open for the duration of the call
+    if unsafe { nix::libc::syncfs(file.as_raw_fd()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn syncfs_path(_: &Path) -> io::Result<()> {
+    // No syncfs, sync all the filesystems
+    nix::unistd::sync();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    ///
+    /// Start a syncfs that blocks until released, then two more callers
+    /// while it runs, whose own syncfs calls fail if 'fail' is set.
+    ///
+    /// Returns the results of the first caller and the two others, and the
+    /// number of syncfs calls made.
+    ///
+    fn sync_during_running_call(fail: bool) -> (io::Result<()>, Vec<io::Result<()>>, usize) {
+        let group = Arc::new(SyncfsGroup::default());
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        let (started_tx, started_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let first = {
+            let group = Arc::clone(&group);
+            let calls = Arc::clone(&calls);
+            thread::spawn(move || {
+                group.sync_with(|| {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    started_tx.send(()).unwrap();
+                    release_rx.recv().unwrap();
+                    Ok(())
+                })
+            })
+        };
+        started_rx.recv().unwrap();
+
+        let others: Vec<_> = (0..2)
+            .map(|_| {
+                let group = Arc::clone(&group);
+                let calls = Arc::clone(&calls);
+                thread::spawn(move || {
+                    group.sync_with(|| {
+                        calls.fetch_add(1, Ordering::SeqCst);
+                        if fail {
+                            Err(io::Error::new(io::ErrorKind::Other, "syncfs failed"))
+                        } else {
+                            Ok(())
+                        }
+                    })
+                })
+            })
+            .collect();
+
+        // The syncfs that's running may have missed their writes, so the
+        // others wait for it instead of starting one of their own.
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        release_tx.send(()).unwrap();
+        let first = first.join().unwrap();
+        let others = others.into_iter().map(|h| h.join().unwrap()).collect();
+        (first, others, calls.load(Ordering::SeqCst))
+    }
+
+    #[test]
+    fn syncfs_waits_for_next_call() {
+        let (first, others, calls) = sync_during_running_call(false);
+        assert!(first.is_ok());
+        assert!(others.iter().all(|res| res.is_ok()));
+        // The two others shared one syncfs after the first one
+        assert_eq!(calls, 2);
+    }
+
+    #[test]
+    fn syncfs_failure_is_shared() {
+        let (first, others, calls) = sync_during_running_call(true);
+        assert!(first.is_ok());
+        // One of the others ran the failed syncfs, the other one shared it
+        assert!(others.iter().all(|res| res.is_err()));
+        assert_eq!(calls, 2);
+    }
+}
@@ -0,0 +1,307 @@
+//!
+//! Layer files set aside when a timeline is loaded.
+//!
+//! When a timeline is loaded, layer files that cover LSNs beyond its
+//! `disk_consistent_lsn` are left from a crash in the middle of a flush or a
+//! compaction. They may be complete, only not yet covered by the metadata, or
+//! written partially, so the timeline can't use them: the WAL is ingested again
+//! from the `disk_consistent_lsn` instead. They are moved into the
+//! `set_aside_layers` subdirectory of the timeline directory, out of the way of
+//! the layer map and of new layers with the same names. A manifest file in the
+//! directory records where each file came from and why it was set aside.
+//!
+//! The set-aside files are kept until purged with the management API, see
+//! [`list_set_aside_layers`] and [`purge_set_aside_layers`]. They are unrelated
+//! to the tenant quarantine of layers missing from the remote storage.
+//!
+use std::fs;
+use std::io::ErrorKind;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use anyhow::{anyhow, bail, Context, Result};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+use tracing::*;
+use utils::lsn::Lsn;
+
+use super::filename::{DeltaFileName, ImageFileName};
+
+/// Name of the subdirectory of a timeline directory with the set-aside layer files.
+pub const SET_ASIDE_DIR_NAME: &str = "set_aside_layers";
+
+const MANIFEST_FILE_NAME: &str = "set_aside_layers.json";
+
+lazy_static! {
+    /// Serializes the manifest updates.
+    static ref MANIFEST_LOCK: Mutex<()> = Mutex::new(());
+}
+
+/// A layer file set aside by a timeline.
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SetAsideLayer {
+    /// Name of the file in the set-aside directory.
+    pub file_name: String,
+    /// Name of the file in the timeline directory.
+    pub original_name: String,
+    pub reason: String,
+    /// The timeline's `disk_consistent_lsn` when the file was set aside.
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub disk_consistent_lsn: Option<Lsn>,
+    /// LSN range of the layer, the end is exclusive.
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub layer_start_lsn: Option<Lsn>,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub layer_end_lsn: Option<Lsn>,
+    #[serde(default, with = "humantime_serde")]
+    pub set_aside_at: Option<SystemTime>,
+}
+
+pub fn set_aside_dir(timeline_path: &Path) -> PathBuf {
+    timeline_path.join(SET_ASIDE_DIR_NAME)
+}
+
+///
+/// Move a layer file of the timeline into its set-aside directory, and record
+/// it in the manifest.
+///
+pub fn set_aside_layer(
+    timeline_path: &Path,
+    layer_path: &Path,
+    reason: &str,
+    disk_consistent_lsn: Lsn,
+) -> Result<SetAsideLayer> {
+    let _guard = MANIFEST_LOCK.lock().unwrap();
+
+    let original_name = layer_path
+        .file_name()
+        .ok_or_else(|| anyhow!("Path {} doesn't have a file name", layer_path.display()))?
+        .to_string_lossy()
+        .into_owned();
+    let dir = set_aside_dir(timeline_path);
+    fs::create_dir_all(&dir).with_context(|| {
+        format!(
+            "Failed to create set-aside layers directory {}",
+            dir.display()
+        )
+    })?;
+
+    // The same layer can be left over again after another crash
+    let mut file_name = original_name.clone();
+    let mut num = 0u32;
+    while dir.join(&file_name).exists() {
+        num += 1;
+        file_name = format!("{original_name}.{num}");
+    }
+    fs::rename(layer_path, dir.join(&file_name)).with_context(|| {
+        format!(
+            "Failed to move {} into the set-aside layers directory",
+            layer_path.display()
+        )
+    })?;
+
+    let lsn_range = layer_lsn_range(&original_name);
+    let set_aside = SetAsideLayer {
+        file_name,
+        original_name,
+        reason: reason.to_string(),
+        disk_consistent_lsn: Some(disk_consistent_lsn),
+        layer_start_lsn: lsn_range.as_ref().map(|range| range.start),
+        layer_end_lsn: lsn_range.map(|range| range.end),
+        set_aside_at: Some(SystemTime::now()),
+    };
+    let mut manifest = read_manifest(&dir)?;
+    manifest.push(set_aside.clone());
+    write_manifest(&dir, &manifest)?;
+
+    warn!(
+        "set aside layer file {} as {}: {}",
+        layer_path.display(),
+        set_aside.file_name,
+        reason
+    );
+    Ok(set_aside)
+}
+
+///
+/// List the set-aside layer files of a timeline. Files in the set-aside
+/// directory that the manifest doesn't mention, e.g. because of a crash right
+/// after they were moved there, are listed too, with what their names tell.
+///
+pub fn list_set_aside_layers(timeline_path: &Path) -> Result<Vec<SetAsideLayer>> {
+    let _guard = MANIFEST_LOCK.lock().unwrap();
+    list_files(&set_aside_dir(timeline_path))
+}
+
+///
+/// Delete the set-aside layer file with the given name, or all of them, from
+/// the set-aside directory of a timeline. Returns the deleted files.
+///
+pub fn purge_set_aside_layers(
+    timeline_path: &Path,
+    file_name: Option<&str>,
+) -> Result<Vec<SetAsideLayer>> {
+    let _guard = MANIFEST_LOCK.lock().unwrap();
+    let dir = set_aside_dir(timeline_path);
+
+    let (purged, kept): (Vec<_>, Vec<_>) = list_files(&dir)?
+        .into_iter()
+        .partition(|file| file_name.map_or(true, |name| name == file.file_name));
+    for file in &purged {
+        let path = dir.join(&file.file_name);
+        match fs::remove_file(&path) {
+            Ok(()) => info!("purged set-aside layer file {}", path.display()),
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to remove {}", path.display()))
+            }
+        }
+    }
+    if !purged.is_empty() {
+        write_manifest(&dir, &kept)?;
+    }
+    Ok(purged)
+}
+
+fn list_files(dir: &Path) -> Result<Vec<SetAsideLayer>> {
+    let mut files = read_manifest(dir)?;
+    files.retain(|file| dir.join(&file.file_name).exists());
+
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(files),
+        Err(e) => return Err(e).with_context(|| format!("Failed to list {}", dir.display())),
+    };
+    for entry in entries {
+        let file_name = entry?.file_name().to_string_lossy().into_owned();
+        if file_name == MANIFEST_FILE_NAME
+            || file_name.ends_with(".temp")
+            || files.iter().any(|file| file.file_name == file_name)
+        {
+            continue;
+        }
+        let lsn_range = layer_lsn_range(&file_name);
+        files.push(SetAsideLayer {
+            original_name: file_name.clone(),
+            file_name,
+            reason: "missing from the manifest".to_string(),
+            disk_consistent_lsn: None,
+            layer_start_lsn: lsn_range.as_ref().map(|range| range.start),
+            layer_end_lsn: lsn_range.map(|range| range.end),
+            set_aside_at: None,
+        });
+    }
+    Ok(files)
+}
+
+fn layer_lsn_range(file_name: &str) -> Option<Range<Lsn>> {
+    if let Some(image) = ImageFileName::parse_str(file_name) {
+        Some(image.lsn..image.lsn + 1)
+    } else {
+        DeltaFileName::parse_str(file_name).map(|delta| delta.lsn_range)
+    }
+}
+
+fn read_manifest(dir: &Path) -> Result<Vec<SetAsideLayer>> {
+    let path = dir.join(MANIFEST_FILE_NAME);
+    match fs::read(&path) {
+        Ok(bytes) => serde_json::from_slice(&bytes)
+            .with_context(|| format!("Failed to parse {}", path.display())),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    }
+}
+
+fn write_manifest(dir: &Path, files: &[SetAsideLayer]) -> Result<()> {
+    if !dir.exists() {
+        bail!(
+            "Set-aside layers directory {} does not exist",
+            dir.display()
+        );
+    }
+    let path = dir.join(MANIFEST_FILE_NAME);
+    let temp_path = path.with_extension("json.temp");
+    fs::write(&temp_path, serde_json::to_vec_pretty(files)?)
+        .with_context(|| format!("Failed to write {}", temp_path.display()))?;
+    fs::rename(&temp_path, &path).with_context(|| format!("Failed to replace {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::Key;
+
+    #[test]
+    fn set_aside_and_purge() -> Result<()> {
+        let timeline_path = tempfile::tempdir()?;
+        let timeline_path = timeline_path.path();
+
+        let key_range = Key::MIN..Key::MAX;
+        let image_name = ImageFileName {
+            key_range: key_range.clone(),
+            lsn: Lsn(0x30),
+            stamp: None,
+        }
+        .to_string();
+        let delta_name = DeltaFileName {
+            key_range,
+            lsn_range: Lsn(0x10)..Lsn(0x40),
+            stamp: None,
+        }
+        .to_string();
+
+        for name in [&image_name, &delta_name, &image_name] {
+            fs::write(timeline_path.join(name), name.as_bytes())?;
+            set_aside_layer(
+                timeline_path,
+                &timeline_path.join(name),
+                "layer beyond disk_consistent_lsn",
+                Lsn(0x20),
+            )?;
+            assert!(!timeline_path.join(name).exists());
+        }
+        // A file moved without a manifest entry
+        fs::write(set_aside_dir(timeline_path).join("stray"), "stray")?;
+
+        let files = list_set_aside_layers(timeline_path)?;
+        let names = files
+            .iter()
+            .map(|file| file.file_name.as_str())
+            .collect::<Vec<_>>();
+        let second_image_name = format!("{image_name}.1");
+        assert_eq!(
+            names,
+            [
+                image_name.as_str(),
+                delta_name.as_str(),
+                second_image_name.as_str(),
+                "stray"
+            ]
+        );
+        assert_eq!(files[1].original_name, delta_name);
+        assert_eq!(files[1].disk_consistent_lsn, Some(Lsn(0x20)));
+        assert_eq!(files[1].layer_start_lsn, Some(Lsn(0x10)));
+        assert_eq!(files[1].layer_end_lsn, Some(Lsn(0x40)));
+        assert_eq!(files[2].original_name, image_name);
+        assert_eq!(files[3].disk_consistent_lsn, None);
+
+        let purged = purge_set_aside_layers(timeline_path, Some(&delta_name))?;
+        assert_eq!(purged.len(), 1);
+        assert_eq!(list_set_aside_layers(timeline_path)?.len(), 3);
+        assert!(purge_set_aside_layers(timeline_path, Some(&delta_name))?.is_empty());
+
+        assert_eq!(purge_set_aside_layers(timeline_path, None)?.len(), 3);
+        assert!(list_set_aside_layers(timeline_path)?.is_empty());
+        assert_eq!(
+            fs::read_dir(set_aside_dir(timeline_path))?.count(),
+            1,
+            "Only the manifest should be left"
+        );
+
+        Ok(())
+    }
+}
@@ -6,6 +6,7 @@ use crate::repository::{Key, Value};
 use crate::walrecord::ZenithWalRecord;
 use anyhow::Result;
 use bytes::Bytes;
+use std::collections::HashSet;
 use std::ops::Range;
 use std::path::PathBuf;
 
@@ -139,7 +140,13 @@ pub trait Layer: Send + Sync {
     /// Iterate through all keys and values stored in the layer
     fn iter(&self) -> Box<dyn Iterator<Item = Result<(Key, Lsn, Value)>> + '_>;
 
-    /// Permanently remove this layer from disk.
+    /// Add the keys that have a version within 'lsn_range' in this layer to
+    /// 'keys'. Image layers hold a snapshot rather than changes, and add none.
+    fn collect_changed_keys(&self, lsn_range: &Range<Lsn>, keys: &mut HashSet<Key>) -> Result<()>;
+
+    /// Permanently remove this layer from disk. The file is deleted when the
+    /// last reference to the layer is dropped, so that concurrent readers can
+    /// finish with it.
     fn delete(&self) -> Result<()>;
 
     /// Dump summary of the contents of the layer to stdout
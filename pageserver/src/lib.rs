@@ -1,22 +1,45 @@
+pub mod access_history;
 pub mod basebackup;
+pub mod clock;
+pub mod compute_notify;
 pub mod config;
+pub mod format_marker;
+pub mod gc_coordinator;
+pub mod hot_page_cache;
 pub mod http;
 pub mod import_datadir;
+pub mod import_dump;
+pub mod incremental_backup;
 pub mod keyspace;
 pub mod layered_repository;
+pub mod lsn_cache;
+pub mod metric_labels;
 pub mod page_cache;
 pub mod page_service;
+pub mod page_service_drain;
+pub mod pg_file_path;
 pub mod pgdatadir_mapping;
 pub mod profiling;
+pub mod read_error;
+pub mod rel_dir_cache;
+pub mod rel_size_cache;
 pub mod reltag;
 pub mod repository;
+pub mod request_priority;
+pub mod size_soft_limit;
+pub mod standby_horizon;
 pub mod storage_sync;
 pub mod tenant_config;
 pub mod tenant_mgr;
 pub mod tenant_threads;
+pub mod tenant_usage;
 pub mod thread_mgr;
+pub mod timeline_doctor;
 pub mod timelines;
+pub mod trace_bench;
 pub mod virtual_file;
+pub mod wal_fence;
+pub mod wal_trace;
 pub mod walingest;
 pub mod walreceiver;
 pub mod walrecord;
@@ -39,6 +62,13 @@ use pgdatadir_mapping::DatadirTimeline;
 /// format, bump this!
 pub const STORAGE_FORMAT_VERSION: u16 = 3;
 
+/// Layout generation of the data directory
+///
+/// Recorded in the data directory's format marker, see `format_marker`. Bump
+/// this if the directory structure changes in a way that older pageservers
+/// cannot handle.
+pub const DATADIR_LAYOUT_GENERATION: u16 = 1;
+
 // Magic constants used to identify different kinds of files
 pub const IMAGE_FILE_MAGIC: u16 = 0x5A60;
 pub const DELTA_FILE_MAGIC: u16 = 0x5A61;
@@ -76,6 +106,9 @@ pub fn shutdown_pageserver(exit_code: i32) {
     postgres_backend::set_pgbackend_shutdown_requested();
     thread_mgr::shutdown_threads(Some(ThreadKind::PageRequestHandler), None, None);
 
+    // Stop the warm-up, and save the access history of the timelines served.
+    thread_mgr::shutdown_threads(Some(ThreadKind::AccessHistory), None, None);
+
     // Shut down all the tenants. This flushes everything to disk and kills
     // the checkpoint and GC threads.
     tenant_mgr::shutdown_all_tenants();
@@ -85,6 +118,8 @@ pub fn shutdown_pageserver(exit_code: i32) {
     // FIXME: Does this wait for the sync thread to finish syncing what's queued up?
     // Should it?
     thread_mgr::shutdown_threads(Some(ThreadKind::StorageSync), None, None);
+    thread_mgr::shutdown_threads(Some(ThreadKind::ComputeNotify), None, None);
+    thread_mgr::shutdown_threads(Some(ThreadKind::DetachedTimelinePurge), None, None);
 
     // Shut down the HTTP endpoint last, so that you can still check the server's
     // status while it's shutting down.
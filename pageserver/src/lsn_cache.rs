@@ -0,0 +1,346 @@
+//!
+//! Cache of values stored at keys of a timeline, that stay valid as the last
+//! record LSN advances.
+//!
+//! This is the common part of the [`crate::hot_page_cache`], the
+//! [`crate::rel_dir_cache`] and the [`crate::rel_size_cache`]. A value read at
+//! some LSN is the value at every later LSN, until WAL touches the key: the
+//! timeline invalidates the key, or updates the cached value, before storing
+//! a new version of it. Reads older than the last modification of a key are
+//! not served from the cache.
+//!
+//! A value read from the layers can only be added if WAL didn't touch the key
+//! after the read LSN. For the keys that are not in the cache, the cache only
+//! remembers the LSN of the last modification of any of them, so a value read
+//! before that LSN is not added.
+//!
+//! The number of keys is limited; when the cache is full, a key is evicted
+//! according to the [`Eviction`] policy.
+//!
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+
+use metrics::IntCounter;
+use utils::lsn::Lsn;
+
+use crate::repository::Key;
+
+/// Which key is evicted when the cache is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Eviction {
+    /// The least read key. The read counts are halved on every eviction, so
+    /// that keys that stopped being read get evicted eventually.
+    LeastRead,
+    /// The least recently read or updated key.
+    LeastRecentlyUsed,
+}
+
+struct Entry<V> {
+    /// The value and the LSN it is valid from, or None if WAL touched the key
+    /// after that.
+    value: Option<(Lsn, V)>,
+    /// LSN of the last WAL record that touched the key.
+    last_modified: Lsn,
+    /// Number of reads, or the value of the cache's clock at the last access,
+    /// depending on the eviction policy.
+    usage: AtomicU64,
+}
+
+struct Entries<V> {
+    entries: HashMap<Key, Entry<V>>,
+    /// LSN of the last WAL record that touched a key not in the cache. Values
+    /// read at an older LSN can't be added.
+    untracked_last_modified: Lsn,
+}
+
+pub struct LsnCache<V> {
+    capacity: usize,
+    eviction: Eviction,
+    evictions: &'static IntCounter,
+    /// Incremented on every access, for the least recently used eviction.
+    clock: AtomicU64,
+    inner: RwLock<Entries<V>>,
+}
+
+impl<V: Clone> LsnCache<V> {
+    ///
+    /// Create a cache for up to 'capacity' keys. 0 disables the cache.
+    /// 'evictions' counts the keys evicted to make room for others.
+    ///
+    pub fn new(capacity: usize, eviction: Eviction, evictions: &'static IntCounter) -> Self {
+        LsnCache {
+            capacity,
+            eviction,
+            evictions,
+            clock: AtomicU64::new(0),
+            inner: RwLock::new(Entries {
+                entries: HashMap::new(),
+                untracked_last_modified: Lsn(0),
+            }),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    ///
+    /// Get the value stored at 'key' as of 'lsn', if it's cached. The caller
+    /// must make sure that 'lsn' is not past the last record LSN.
+    ///
+    pub fn lookup(&self, key: &Key, lsn: Lsn) -> Option<V> {
+        if !self.is_enabled() {
+            return None;
+        }
+
+        let inner = self.inner.read().unwrap();
+        let entry = inner.entries.get(key)?;
+        match &entry.value {
+            Some((valid_from, value)) if *valid_from <= lsn => {
+                self.touch(entry);
+                Some(value.clone())
+            }
+            _ => None,
+        }
+    }
+
+    ///
+    /// Remember the value read from the layers at 'lsn'. The caller must make
+    /// sure that 'lsn' is not past the last record LSN at the time of the read.
+    /// Does nothing if WAL touched the key after 'lsn', or if the cache already
+    /// knows the value.
+    ///
+    pub fn memorize(&self, key: Key, lsn: Lsn, value: V) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let mut inner = self.inner.write().unwrap();
+        if let Some(entry) = inner.entries.get_mut(&key) {
+            if entry.value.is_none() && entry.last_modified <= lsn {
+                entry.value = Some((lsn, value));
+            }
+            return;
+        }
+
+        if inner.untracked_last_modified > lsn {
+            return;
+        }
+        let entry = self.new_entry(lsn, value, Lsn(0));
+        self.insert(&mut inner, key, entry);
+    }
+
+    ///
+    /// Called before storing a new version of the key at 'lsn', when the new
+    /// value is known.
+    ///
+    pub fn update(&self, key: Key, lsn: Lsn, value: V) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let mut inner = self.inner.write().unwrap();
+        match inner.entries.get_mut(&key) {
+            Some(entry) => {
+                if entry.last_modified <= lsn {
+                    entry.value = Some((lsn, value));
+                    entry.last_modified = lsn;
+                }
+                self.touch(entry);
+            }
+            None => {
+                let entry = self.new_entry(lsn, value, lsn);
+                self.insert(&mut inner, key, entry);
+            }
+        }
+    }
+
+    ///
+    /// Called before storing a new version of the key at 'lsn'.
+    ///
+    pub fn invalidate(&self, key: &Key, lsn: Lsn) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let mut inner = self.inner.write().unwrap();
+        match inner.entries.get_mut(key) {
+            Some(entry) => {
+                entry.value = None;
+                entry.last_modified = entry.last_modified.max(lsn);
+            }
+            None => inner.untracked_last_modified = inner.untracked_last_modified.max(lsn),
+        }
+    }
+
+    ///
+    /// Called before deleting the key range at 'lsn'.
+    ///
+    pub fn invalidate_range(&self, key_range: &Range<Key>, lsn: Lsn) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let mut inner = self.inner.write().unwrap();
+        for (_, entry) in inner
+            .entries
+            .iter_mut()
+            .filter(|(key, _)| key_range.contains(key))
+        {
+            entry.value = None;
+            entry.last_modified = entry.last_modified.max(lsn);
+        }
+        inner.untracked_last_modified = inner.untracked_last_modified.max(lsn);
+    }
+
+    fn new_entry(&self, lsn: Lsn, value: V, last_modified: Lsn) -> Entry<V> {
+        let usage = match self.eviction {
+            Eviction::LeastRead => 0,
+            Eviction::LeastRecentlyUsed => self.clock.fetch_add(1, Ordering::Relaxed),
+        };
+        Entry {
+            value: Some((lsn, value)),
+            last_modified,
+            usage: AtomicU64::new(usage),
+        }
+    }
+
+    fn touch(&self, entry: &Entry<V>) {
+        match self.eviction {
+            Eviction::LeastRead => entry.usage.fetch_add(1, Ordering::Relaxed),
+            Eviction::LeastRecentlyUsed => entry.usage.swap(
+                self.clock.fetch_add(1, Ordering::Relaxed),
+                Ordering::Relaxed,
+            ),
+        };
+    }
+
+    fn insert(&self, inner: &mut Entries<V>, key: Key, entry: Entry<V>) {
+        if inner.entries.len() >= self.capacity {
+            self.evict(inner);
+        }
+        inner.entries.insert(key, entry);
+    }
+
+    fn evict(&self, inner: &mut Entries<V>) {
+        let victim = inner
+            .entries
+            .iter()
+            .min_by_key(|(_, entry)| entry.usage.load(Ordering::Relaxed))
+            .map(|(key, _)| *key);
+        if let Some(entry) = victim.and_then(|key| inner.entries.remove(&key)) {
+            // A value read at an older LSN could be from before the last change
+            // of the evicted key
+            inner.untracked_last_modified = inner.untracked_last_modified.max(entry.last_modified);
+            self.evictions.inc();
+        }
+        if self.eviction == Eviction::LeastRead {
+            for entry in inner.entries.values() {
+                let reads = entry.usage.load(Ordering::Relaxed);
+                entry.usage.store(reads / 2, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lazy_static::lazy_static;
+    use metrics::register_int_counter;
+
+    lazy_static! {
+        static ref TEST_EVICTIONS: IntCounter = register_int_counter!(
+            "pageserver_test_lsn_cache_evictions_total",
+            "Number of keys evicted from the caches of the unit tests"
+        )
+        .expect("failed to define a metric");
+    }
+
+    fn key(field6: u32) -> Key {
+        Key {
+            field1: 0x00,
+            field2: 1663,
+            field3: 13010,
+            field4: 16384,
+            field5: 0,
+            field6,
+        }
+    }
+
+    #[test]
+    fn lsn_cache_validity() {
+        let cache = LsnCache::new(2, Eviction::LeastRead, &TEST_EVICTIONS);
+
+        cache.memorize(key(0), Lsn(0x20), "at 0/20");
+        assert_eq!(cache.lookup(&key(0), Lsn(0x20)), Some("at 0/20"));
+        assert_eq!(cache.lookup(&key(0), Lsn(0x80)), Some("at 0/20"));
+        assert_eq!(cache.lookup(&key(0), Lsn(0x10)), None);
+        // Read again later, the older LSN it's valid from is kept
+        cache.memorize(key(0), Lsn(0x30), "at 0/20");
+        assert_eq!(cache.lookup(&key(0), Lsn(0x20)), Some("at 0/20"));
+
+        // Values read before the modification are not accepted
+        cache.invalidate(&key(0), Lsn(0x90));
+        assert_eq!(cache.lookup(&key(0), Lsn(0x90)), None);
+        cache.memorize(key(0), Lsn(0x80), "at 0/20");
+        assert_eq!(cache.lookup(&key(0), Lsn(0x90)), None);
+        cache.memorize(key(0), Lsn(0x90), "at 0/90");
+        assert_eq!(cache.lookup(&key(0), Lsn(0xA0)), Some("at 0/90"));
+
+        // Updated with the new value
+        cache.update(key(0), Lsn(0xB0), "at 0/B0");
+        assert_eq!(cache.lookup(&key(0), Lsn(0xB0)), Some("at 0/B0"));
+        assert_eq!(cache.lookup(&key(0), Lsn(0xA0)), None);
+
+        cache.invalidate_range(&(key(0)..key(10)), Lsn(0xC0));
+        assert_eq!(cache.lookup(&key(0), Lsn(0xC0)), None);
+
+        // Modified before it got cached, can only be added at a newer LSN
+        cache.invalidate(&key(1), Lsn(0x30));
+        cache.memorize(key(1), Lsn(0x20), "at 0/20");
+        assert_eq!(cache.lookup(&key(1), Lsn(0x30)), None);
+        cache.memorize(key(1), Lsn(0x30), "at 0/30");
+        assert_eq!(cache.lookup(&key(1), Lsn(0x30)), Some("at 0/30"));
+
+        // Disabled cache
+        let cache = LsnCache::new(0, Eviction::LeastRead, &TEST_EVICTIONS);
+        cache.memorize(key(0), Lsn(0x20), "at 0/20");
+        cache.update(key(1), Lsn(0x20), "at 0/20");
+        assert_eq!(cache.lookup(&key(0), Lsn(0x20)), None);
+        assert_eq!(cache.lookup(&key(1), Lsn(0x20)), None);
+    }
+
+    #[test]
+    fn lsn_cache_eviction() {
+        // The least read key is evicted, even if it was read last
+        let cache = LsnCache::new(2, Eviction::LeastRead, &TEST_EVICTIONS);
+        cache.memorize(key(1), Lsn(0x20), 1);
+        cache.memorize(key(2), Lsn(0x20), 2);
+        assert!(cache.lookup(&key(2), Lsn(0x20)).is_some());
+        assert!(cache.lookup(&key(2), Lsn(0x20)).is_some());
+        assert!(cache.lookup(&key(1), Lsn(0x20)).is_some());
+        cache.memorize(key(3), Lsn(0x20), 3);
+        assert_eq!(cache.lookup(&key(1), Lsn(0x20)), None);
+        assert_eq!(cache.lookup(&key(2), Lsn(0x20)), Some(2));
+        assert_eq!(cache.lookup(&key(3), Lsn(0x20)), Some(3));
+
+        // The least recently used key is evicted
+        let cache = LsnCache::new(2, Eviction::LeastRecentlyUsed, &TEST_EVICTIONS);
+        cache.memorize(key(1), Lsn(0x20), 1);
+        cache.update(key(2), Lsn(0x30), 2);
+        assert!(cache.lookup(&key(2), Lsn(0x40)).is_some());
+        assert!(cache.lookup(&key(1), Lsn(0x40)).is_some());
+        cache.memorize(key(3), Lsn(0x40), 3);
+        assert_eq!(cache.lookup(&key(2), Lsn(0x40)), None);
+        assert_eq!(cache.lookup(&key(1), Lsn(0x40)), Some(1));
+
+        // A value of the evicted key read before its last change can't come back
+        cache.memorize(key(2), Lsn(0x20), 1);
+        assert_eq!(cache.lookup(&key(2), Lsn(0x40)), None);
+        cache.memorize(key(2), Lsn(0x30), 2);
+        assert_eq!(cache.lookup(&key(2), Lsn(0x40)), Some(2));
+    }
+}
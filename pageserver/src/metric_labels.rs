@@ -0,0 +1,247 @@
+//!
+//! Cardinality control of the per-tenant metrics.
+//!
+//! Most of the page server metrics have `tenant_id` and `timeline_id` labels.
+//! With thousands of tenants that's too many series for Prometheus. With
+//! `metrics_max_labeled_tenants` set, only that many tenants get their own
+//! label values: the first ones that report a metric after startup. The
+//! metrics of the other tenants are reported with both labels set to
+//! [`OTHER`], summed up. A tenant keeps its labels until its last local
+//! timeline is detached or the page server restarts, so that its series don't
+//! move between the buckets. Then [`release_tenant`] frees its slot for the
+//! next tenant that reports a metric.
+//!
+//! Gauges that hold a value of one timeline, like its last record LSN, can't
+//! be summed up. They are not reported for the tenants in the "other" bucket,
+//! see [`own_timeline_labels`].
+//!
+//! The `application_name` label of the page service metrics comes from the
+//! clients, so it is bounded too: the first [`MAX_APPLICATION_LABELS`] names
+//! get labels of their own, the rest are reported as [`OTHER`].
+//!
+use std::collections::HashSet;
+use std::sync::RwLock;
+
+use lazy_static::lazy_static;
+use metrics::{register_int_gauge, IntGauge};
+use once_cell::sync::OnceCell;
+use utils::zid::{ZTenantId, ZTimelineId};
+
+/// Label value of the tenants that don't have labels of their own.
+pub const OTHER: &str = "other";
+
+/// Max number of distinct `application_name` label values.
+pub const MAX_APPLICATION_LABELS: usize = 32;
+
+static METRIC_LABELS: OnceCell<MetricLabelPolicy> = OnceCell::new();
+
+lazy_static! {
+    static ref APPLICATION_LABELS: BoundedLabels = BoundedLabels::new(MAX_APPLICATION_LABELS);
+    static ref OTHER_TENANTS: IntGauge = register_int_gauge!(
+        "pageserver_metrics_other_tenants",
+        "Number of tenants whose metrics are reported under the \"other\" tenant_id label"
+    )
+    .expect("failed to define a metric");
+}
+
+///
+/// Initialize the label policy. This must be called once at page server startup.
+///
+/// 'max_labeled_tenants' is the max number of tenants with labels of their own,
+/// 0 for no limit.
+///
+pub fn init(max_labeled_tenants: usize) {
+    if METRIC_LABELS
+        .set(MetricLabelPolicy::new(max_labeled_tenants))
+        .is_err()
+    {
+        panic!("metric label policy already initialized");
+    }
+}
+
+fn get() -> &'static MetricLabelPolicy {
+    // In unit tests and in the tools that read layer files, page server
+    // startup doesn't happen and no one calls metric_labels::init(). Label all
+    // tenants there.
+    METRIC_LABELS.get_or_init(|| MetricLabelPolicy::new(0))
+}
+
+///
+/// Value of the `tenant_id` label for a tenant, for counters, histograms and
+/// gauges that can be summed up across tenants.
+///
+pub fn tenant_label(tenant_id: &ZTenantId) -> String {
+    own_tenant_label(tenant_id).unwrap_or_else(|| OTHER.to_string())
+}
+
+///
+/// Values of the `tenant_id` and `timeline_id` labels for a timeline, for
+/// counters, histograms and gauges that can be summed up across timelines.
+///
+pub fn timeline_labels(tenant_id: &ZTenantId, timeline_id: &ZTimelineId) -> (String, String) {
+    own_timeline_labels(tenant_id, timeline_id)
+        .unwrap_or_else(|| (OTHER.to_string(), OTHER.to_string()))
+}
+
+/// Value of the `tenant_id` label for a tenant, None if it's in the "other" bucket.
+pub fn own_tenant_label(tenant_id: &ZTenantId) -> Option<String> {
+    if get().has_own_labels(tenant_id) {
+        Some(tenant_id.to_string())
+    } else {
+        None
+    }
+}
+
+///
+/// Values of the `tenant_id` and `timeline_id` labels for a timeline, None
+/// if its tenant is in the "other" bucket.
+///
+pub fn own_timeline_labels(
+    tenant_id: &ZTenantId,
+    timeline_id: &ZTimelineId,
+) -> Option<(String, String)> {
+    own_tenant_label(tenant_id).map(|tenant_id| (tenant_id, timeline_id.to_string()))
+}
+
+///
+/// Forget the label choice of a tenant that has no local timelines anymore. If
+/// it had labels of its own, the next new tenant to report a metric gets them.
+///
+pub fn release_tenant(tenant_id: &ZTenantId) {
+    get().release(tenant_id)
+}
+
+/// Value of the `application_name` label for an application_name of a client.
+pub fn application_label(application_name: &str) -> String {
+    APPLICATION_LABELS.label(application_name)
+}
+
+struct BoundedLabels {
+    max_labels: usize,
+    labels: RwLock<HashSet<String>>,
+}
+
+impl BoundedLabels {
+    fn new(max_labels: usize) -> Self {
+        BoundedLabels {
+            max_labels,
+            labels: RwLock::new(HashSet::new()),
+        }
+    }
+
+    fn label(&self, value: &str) -> String {
+        if self.labels.read().unwrap().contains(value) {
+            return value.to_string();
+        }
+        let mut labels = self.labels.write().unwrap();
+        if labels.len() < self.max_labels || labels.contains(value) {
+            labels.insert(value.to_string());
+            value.to_string()
+        } else {
+            OTHER.to_string()
+        }
+    }
+}
+
+struct MetricLabelPolicy {
+    max_labeled_tenants: usize,
+    tenants: RwLock<LabeledTenants>,
+}
+
+#[derive(Default)]
+struct LabeledTenants {
+    labeled: HashSet<ZTenantId>,
+    other: HashSet<ZTenantId>,
+}
+
+impl MetricLabelPolicy {
+    fn new(max_labeled_tenants: usize) -> Self {
+        MetricLabelPolicy {
+            max_labeled_tenants,
+            tenants: RwLock::new(LabeledTenants::default()),
+        }
+    }
+
+    fn has_own_labels(&self, tenant_id: &ZTenantId) -> bool {
+        if self.max_labeled_tenants == 0 {
+            return true;
+        }
+
+        let tenants = self.tenants.read().unwrap();
+        if tenants.labeled.contains(tenant_id) {
+            return true;
+        }
+        if tenants.other.contains(tenant_id) {
+            return false;
+        }
+        drop(tenants);
+
+        let mut tenants = self.tenants.write().unwrap();
+        if tenants.labeled.contains(tenant_id) {
+            true
+        } else if tenants.other.contains(tenant_id) {
+            false
+        } else if tenants.labeled.len() < self.max_labeled_tenants {
+            tenants.labeled.insert(*tenant_id);
+            true
+        } else {
+            tenants.other.insert(*tenant_id);
+            OTHER_TENANTS.inc();
+            false
+        }
+    }
+
+    fn release(&self, tenant_id: &ZTenantId) {
+        let mut tenants = self.tenants.write().unwrap();
+        if !tenants.labeled.remove(tenant_id) && tenants.other.remove(tenant_id) {
+            OTHER_TENANTS.dec();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn labeled_tenants_limit() {
+        let policy = MetricLabelPolicy::new(2);
+        let tenants = [
+            ZTenantId::generate(),
+            ZTenantId::generate(),
+            ZTenantId::generate(),
+        ];
+
+        assert!(policy.has_own_labels(&tenants[0]));
+        assert!(policy.has_own_labels(&tenants[1]));
+        assert!(!policy.has_own_labels(&tenants[2]));
+
+        // The choice sticks
+        assert!(policy.has_own_labels(&tenants[0]));
+        assert!(!policy.has_own_labels(&tenants[2]));
+
+        // A released slot goes to the next new tenant
+        policy.release(&tenants[1]);
+        let new_tenant = ZTenantId::generate();
+        assert!(policy.has_own_labels(&new_tenant));
+        assert!(!policy.has_own_labels(&tenants[1]));
+        assert!(!policy.has_own_labels(&tenants[2]));
+
+        // A released "other" tenant takes the next free slot
+        policy.release(&tenants[0]);
+        policy.release(&tenants[2]);
+        assert!(policy.has_own_labels(&tenants[2]));
+
+        let unlimited = MetricLabelPolicy::new(0);
+        assert!(tenants.iter().all(|t| unlimited.has_own_labels(t)));
+    }
+
+    #[test]
+    fn application_labels_limit() {
+        let labels = BoundedLabels::new(2);
+        assert_eq!(labels.label("psql"), "psql");
+        assert_eq!(labels.label("pgbench"), "pgbench");
+        assert_eq!(labels.label("app"), OTHER);
+        assert_eq!(labels.label("psql"), "psql");
+    }
+}
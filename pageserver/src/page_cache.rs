@@ -116,6 +116,9 @@ struct MaterializedPageHashKey {
 #[derive(Clone)]
 struct Version {
     lsn: Lsn,
+    /// The page has no WAL records between 'lsn' and this LSN, so the image
+    /// is the page version at any LSN in that range.
+    valid_until: Lsn,
     slot_idx: usize,
 }
 
@@ -304,14 +307,15 @@ impl PageCache {
     ///
     /// The 'lsn' is an upper bound, this will return the latest version of
     /// the given block, but not newer than 'lsn'. Returns the actual LSN of the
-    /// returned page.
+    /// returned page, and the LSN up to which the page is known to stay the same.
+    /// If 'lsn' is not past the latter, the returned page is the exact version at 'lsn'.
     pub fn lookup_materialized_page(
         &self,
         tenant_id: ZTenantId,
         timeline_id: ZTimelineId,
         key: &Key,
         lsn: Lsn,
-    ) -> Option<(Lsn, PageReadGuard)> {
+    ) -> Option<(Lsn, Lsn, PageReadGuard)> {
         let mut cache_key = CacheKey::MaterializedPage {
             hash_key: MaterializedPageHashKey {
                 tenant_id,
@@ -322,8 +326,19 @@ impl PageCache {
         };
 
         if let Some(guard) = self.try_lock_for_read(&mut cache_key) {
-            if let CacheKey::MaterializedPage { hash_key: _, lsn } = cache_key {
-                Some((lsn, guard))
+            if let CacheKey::MaterializedPage { hash_key, lsn } = cache_key {
+                // We hold the slot lock, so the version can't be evicted from the mapping
+                let valid_until = self
+                    .materialized_page_map
+                    .read()
+                    .unwrap()
+                    .get(&hash_key)
+                    .and_then(|versions| {
+                        let version_idx = versions.binary_search_by_key(&lsn, |v| v.lsn).ok()?;
+                        Some(versions[version_idx].valid_until)
+                    })
+                    .unwrap_or(lsn);
+                Some((lsn, valid_until, guard))
             } else {
                 panic!("unexpected key type in slot");
             }
@@ -333,7 +348,8 @@ impl PageCache {
     }
 
     ///
-    /// Store an image of the given page in the cache.
+    /// Store an image of the given page in the cache. The image is the page
+    /// version at all LSNs from 'lsn' to 'valid_until'.
     ///
     pub fn memorize_materialized_page(
         &self,
@@ -341,6 +357,7 @@ impl PageCache {
         timeline_id: ZTimelineId,
         key: Key,
         lsn: Lsn,
+        valid_until: Lsn,
         img: &[u8],
     ) {
         let cache_key = CacheKey::MaterializedPage {
@@ -364,6 +381,34 @@ impl PageCache {
                 write_guard.mark_valid();
             }
         }
+
+        self.extend_materialized_page_validity(tenant_id, timeline_id, key, lsn, valid_until);
+    }
+
+    ///
+    /// Record that the cached page version at 'lsn' stays the same up to 'valid_until'.
+    /// Does nothing if that version is not in the cache.
+    ///
+    pub fn extend_materialized_page_validity(
+        &self,
+        tenant_id: ZTenantId,
+        timeline_id: ZTimelineId,
+        key: Key,
+        lsn: Lsn,
+        valid_until: Lsn,
+    ) {
+        let hash_key = MaterializedPageHashKey {
+            tenant_id,
+            timeline_id,
+            key,
+        };
+        let mut map = self.materialized_page_map.write().unwrap();
+        if let Some(versions) = map.get_mut(&hash_key) {
+            if let Ok(version_idx) = versions.binary_search_by_key(&lsn, |v| v.lsn) {
+                let version = &mut versions[version_idx];
+                version.valid_until = version.valid_until.max(valid_until);
+            }
+        }
     }
 
     // Section 1.2: Public interface functions for working with Ephemeral pages.
@@ -717,6 +762,7 @@ impl PageCache {
                             version_idx,
                             Version {
                                 lsn: *new_lsn,
+                                valid_until: *new_lsn,
                                 slot_idx,
                             },
                         );
@@ -6,8 +6,15 @@
 // commands are supported now:
 //     *status* -- show actual info about this pageserver,
 //     *pagestream* -- enter mode where smgr and pageserver talk with their
-//  custom protocol.
+//  custom protocol. With the *stale_reads* option, requests are served at
+//  the WAL already received instead of waiting for more, see `StaleReads`.
+//  The tenant, the timeline, the default read LSN and the application_name
+//  can also be set for the whole session in the connection options, see
+//  `SessionDefaults`. Requests with the `PAGESTREAM_BACKGROUND_FLAG` set in
+//  their tag, like prefetches, yield to the others, see `request_priority`.
 //     *callmemaybe <zenith timelineid> $url* -- ask pageserver to start walreceiver on $url
+//     *import wal <tenantid> <timelineid> <start_lsn> [<end_lsn>]* -- ingest raw WAL sent
+//  in CopyData messages, without a safekeeper
 //
 
 use anyhow::{bail, ensure, Context, Result};
@@ -16,31 +23,50 @@ use lazy_static::lazy_static;
 use regex::Regex;
 use std::io;
 use std::net::TcpListener;
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::str;
 use std::str::FromStr;
 use std::sync::{Arc, RwLockReadGuard};
+use std::time::{Duration, Instant};
 use tracing::*;
 use utils::{
     auth::{self, Claims, JwtAuth, Scope},
     lsn::Lsn,
     postgres_backend::{self, is_socket_read_timed_out, AuthType, PostgresBackend},
-    pq_proto::{BeMessage, FeMessage, RowDescriptor, SINGLE_COL_ROWDESC},
-    zid::{ZTenantId, ZTimelineId},
+    pq_proto::{BeMessage, FeMessage, FeStartupPacket, RowDescriptor, SINGLE_COL_ROWDESC},
+    seqwait::CancellationToken,
+    zid::{ZTenantId, ZTenantTimelineId, ZTimelineId},
 };
 
+use crate::access_history::AccessRecorder;
 use crate::basebackup;
 use crate::config::{PageServerConf, ProfilingConfig};
+use crate::incremental_backup;
+use crate::layered_repository::append_only::RelFileNode;
+use crate::metric_labels;
+use crate::page_service_drain;
 use crate::pgdatadir_mapping::{DatadirTimeline, LsnForTimestamp};
 use crate::profiling::profpoint_start;
+use crate::read_error::{ReadError, ReadErrorCode};
 use crate::reltag::RelTag;
 use crate::repository::Repository;
 use crate::repository::Timeline;
+use crate::request_priority::{self, Priority};
+use crate::storage_sync;
 use crate::tenant_mgr;
+use crate::tenant_mgr::TenantState;
 use crate::thread_mgr;
 use crate::thread_mgr::ThreadKind;
+use crate::wal_trace;
+use crate::walingest::WalIngest;
 use crate::walreceiver;
 use crate::CheckpointConfig;
-use metrics::{register_histogram_vec, HistogramVec};
+use crate::DatadirTimelineImpl;
+use metrics::{
+    register_histogram_vec, register_int_counter, register_int_counter_vec, HistogramVec,
+    IntCounter, IntCounterVec,
+};
+use postgres_ffi::waldecoder::WalStreamDecoder;
 use postgres_ffi::xlog_utils::to_pg_timestamp;
 
 use postgres_ffi::pg_constants;
@@ -91,6 +117,45 @@ struct PagestreamDbSizeRequest {
     dbnode: u32,
 }
 
+/// W3C trace context of a request, from the `traceparent` that the compute
+/// can append to any request message.
+#[derive(Debug)]
+struct TraceContext {
+    trace_id: String,
+    parent_id: String,
+}
+
+impl TraceContext {
+    /// Parse a `traceparent` value: `<version>-<trace-id>-<parent-id>-<flags>`,
+    /// all lowercase hex. Returns None for anything that doesn't look like one.
+    fn parse_traceparent(traceparent: &str) -> Option<TraceContext> {
+        let is_hex = |s: &str, len: usize| {
+            s.len() == len && s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+        };
+        let mut parts = traceparent.split('-');
+        let (version, trace_id, parent_id, flags) =
+            (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+        if !is_hex(version, 2) || version == "ff" || !is_hex(flags, 2) {
+            return None;
+        }
+        // All-zero ids are invalid
+        if !is_hex(trace_id, 32) || trace_id.bytes().all(|b| b == b'0') {
+            return None;
+        }
+        if !is_hex(parent_id, 16) || parent_id.bytes().all(|b| b == b'0') {
+            return None;
+        }
+        // Version 00 has exactly four fields, later versions may add more
+        if version == "00" && parts.next().is_some() {
+            return None;
+        }
+        Some(TraceContext {
+            trace_id: trace_id.to_string(),
+            parent_id: parent_id.to_string(),
+        })
+    }
+}
+
 #[derive(Debug)]
 struct PagestreamExistsResponse {
     exists: bool,
@@ -108,6 +173,8 @@ struct PagestreamGetPageResponse {
 
 #[derive(Debug)]
 struct PagestreamErrorResponse {
+    /// Sent only to the sessions with the `error_codes` option.
+    code: Option<ReadErrorCode>,
     message: String,
 }
 
@@ -116,53 +183,101 @@ struct PagestreamDbSizeResponse {
     db_size: i64,
 }
 
+/// Set in the tag of a request message that nobody waits for yet, e.g. a
+/// prefetch. It is served at [`Priority::Background`].
+const PAGESTREAM_BACKGROUND_FLAG: u8 = 0x80;
+
 impl PagestreamFeMessage {
-    fn parse(mut body: Bytes) -> anyhow::Result<PagestreamFeMessage> {
+    ///
+    /// Parse a request message. Any bytes after the request fields are an
+    /// optional, null-terminated `traceparent` of the request; clients that
+    /// don't send one are not affected. Returns whether the tag has the
+    /// `PAGESTREAM_BACKGROUND_FLAG`, too.
+    ///
+    fn parse(
+        mut body: Bytes,
+        default_read_lsn: Option<DefaultReadLsn>,
+    ) -> anyhow::Result<(PagestreamFeMessage, bool, Option<TraceContext>)> {
+        let background = body
+            .first()
+            .map_or(false, |tag| tag & PAGESTREAM_BACKGROUND_FLAG != 0);
+        let msg = Self::parse_request(&mut body, default_read_lsn)?;
+
+        let trace_context = if body.has_remaining() {
+            let traceparent = body.split(|b| *b == 0).next().unwrap_or_default();
+            let traceparent = str::from_utf8(traceparent).unwrap_or_default();
+            let trace_context = TraceContext::parse_traceparent(traceparent);
+            if trace_context.is_none() {
+                debug!("ignoring malformed traceparent '{}'", traceparent);
+            }
+            trace_context
+        } else {
+            None
+        };
+
+        Ok((msg, background, trace_context))
+    }
+
+    fn parse_request(
+        body: &mut Bytes,
+        default_read_lsn: Option<DefaultReadLsn>,
+    ) -> anyhow::Result<PagestreamFeMessage> {
         // TODO these gets can fail
 
         // these correspond to the ZenithMessageTag enum in pagestore_client.h
         //
         // TODO: consider using protobuf or serde bincode for less error prone
         // serialization.
-        let msg_tag = body.get_u8();
-        match msg_tag {
+        let msg_tag = body.get_u8() & !PAGESTREAM_BACKGROUND_FLAG;
+
+        // Tags 10-13 are the compact forms of requests 0-3, without the latest
+        // flag and the LSN. They are served at the default read LSN of the session.
+        let (latest, lsn) = match msg_tag {
+            0..=3 => (body.get_u8() != 0, Lsn::from(body.get_u64())),
+            10..=13 => default_read_lsn
+                .context("request without an LSN, but no read_lsn is set for the session")?
+                .latest_and_lsn(),
+            _ => bail!("unknown smgr message tag: {},'{:?}'", msg_tag, body),
+        };
+        let get_rel = |body: &mut Bytes| RelTag {
+            spcnode: body.get_u32(),
+            dbnode: body.get_u32(),
+            relnode: body.get_u32(),
+            forknum: body.get_u8(),
+        };
+
+        match msg_tag % 10 {
             0 => Ok(PagestreamFeMessage::Exists(PagestreamExistsRequest {
-                latest: body.get_u8() != 0,
-                lsn: Lsn::from(body.get_u64()),
-                rel: RelTag {
-                    spcnode: body.get_u32(),
-                    dbnode: body.get_u32(),
-                    relnode: body.get_u32(),
-                    forknum: body.get_u8(),
-                },
+                latest,
+                lsn,
+                rel: get_rel(body),
             })),
             1 => Ok(PagestreamFeMessage::Nblocks(PagestreamNblocksRequest {
-                latest: body.get_u8() != 0,
-                lsn: Lsn::from(body.get_u64()),
-                rel: RelTag {
-                    spcnode: body.get_u32(),
-                    dbnode: body.get_u32(),
-                    relnode: body.get_u32(),
-                    forknum: body.get_u8(),
-                },
+                latest,
+                lsn,
+                rel: get_rel(body),
             })),
             2 => Ok(PagestreamFeMessage::GetPage(PagestreamGetPageRequest {
-                latest: body.get_u8() != 0,
-                lsn: Lsn::from(body.get_u64()),
-                rel: RelTag {
-                    spcnode: body.get_u32(),
-                    dbnode: body.get_u32(),
-                    relnode: body.get_u32(),
-                    forknum: body.get_u8(),
-                },
+                latest,
+                lsn,
+                rel: get_rel(body),
                 blkno: body.get_u32(),
             })),
-            3 => Ok(PagestreamFeMessage::DbSize(PagestreamDbSizeRequest {
-                latest: body.get_u8() != 0,
-                lsn: Lsn::from(body.get_u64()),
+            _ => Ok(PagestreamFeMessage::DbSize(PagestreamDbSizeRequest {
+                latest,
+                lsn,
                 dbnode: body.get_u32(),
             })),
-            _ => bail!("unknown smgr message tag: {},'{:?}'", msg_tag, body),
+        }
+    }
+
+    /// Value of the `smgr_query_type` metric label of the request.
+    fn query_type(&self) -> &'static str {
+        match self {
+            PagestreamFeMessage::Exists(_) => "get_rel_exists",
+            PagestreamFeMessage::Nblocks(_) => "get_rel_size",
+            PagestreamFeMessage::GetPage(_) => "get_page_at_lsn",
+            PagestreamFeMessage::DbSize(_) => "get_db_size",
         }
     }
 }
@@ -188,7 +303,12 @@ impl PagestreamBeMessage {
             }
 
             Self::Error(resp) => {
-                bytes.put_u8(103); /* tag from pagestore_client.h */
+                if let Some(code) = resp.code {
+                    bytes.put_u8(105); /* tag from pagestore_client.h */
+                    bytes.put_u8(code as u8);
+                } else {
+                    bytes.put_u8(103); /* tag from pagestore_client.h */
+                }
                 bytes.put(resp.message.as_bytes());
                 bytes.put_u8(0); // null terminator
             }
@@ -200,6 +320,23 @@ impl PagestreamBeMessage {
 
         bytes.into()
     }
+
+    ///
+    /// Send the response to the client in a CopyData message. Pages are sent
+    /// straight from their buffer, without copying them into the message.
+    ///
+    fn write_to(&self, pgb: &mut PostgresBackend) -> io::Result<()> {
+        match self {
+            Self::GetPage(resp) => {
+                /* tag from pagestore_client.h */
+                pgb.write_copy_data_vectored(&[102], &resp.page)?;
+            }
+            _ => {
+                pgb.write_message(&BeMessage::CopyData(&self.serialize()))?;
+            }
+        }
+        Ok(())
+    }
 }
 
 ///////////////////////////////////////////////////////////////////////////////
@@ -250,7 +387,8 @@ pub fn thread_main(
                 // PageRequestHandler threads are not associated with any particular
                 // timeline in the thread manager. In practice most connections will
                 // only deal with a particular timeline, but we don't know which one
-                // yet.
+                // yet. They get associated with it when the compute requests pages
+                // or a base backup.
                 if let Err(err) = thread_mgr::spawn(
                     ThreadKind::PageRequestHandler,
                     None,
@@ -303,7 +441,14 @@ fn page_service_conn_main(
         .set_nodelay(true)
         .context("could not set TCP_NODELAY")?;
 
-    let mut conn_handler = PageServerHandler::new(conf, auth);
+    // Requests waiting for WAL give up when the client disconnects or the
+    // pageserver shuts down, instead of holding the thread until the timeout.
+    let socket_fd = socket.as_raw_fd();
+    let cancel = CancellationToken::with_probe(move || {
+        thread_mgr::is_shutdown_requested() || is_peer_disconnected(socket_fd)
+    });
+
+    let mut conn_handler = PageServerHandler::new(conf, auth, cancel);
     let pgbackend = PostgresBackend::new(socket, auth_type, None, true)?;
     match pgbackend.run(&mut conn_handler) {
         Ok(()) => {
@@ -330,11 +475,246 @@ fn page_service_conn_main(
     }
 }
 
+///
+/// Check if the peer has closed the connection, without reading from it.
+///
+/// The socket must be open: the caller owns it for as long as it might call this.
+///
+fn is_peer_disconnected(socket_fd: RawFd) -> bool {
+    use nix::poll::{poll, PollFd, PollFlags};
+
+    // POLLHUP and POLLERR are reported even if not requested.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    let events = PollFlags::POLLRDHUP;
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    let events = PollFlags::empty();
+
+    let mut poll_fds = [PollFd::new(socket_fd, events)];
+    match poll(&mut poll_fds, 0) {
+        Ok(0) => false,
+        Ok(_) => poll_fds[0]
+            .revents()
+            .map_or(false, |revents| !revents.is_empty()),
+        Err(e) => {
+            warn!("could not poll the connection socket: {}", e);
+            false
+        }
+    }
+}
+
+///
+/// Stale reads mode of a pagestream connection, for analytics replicas that
+/// prefer predictable latency over reading the latest data: requests are served
+/// at an older LSN than requested instead of waiting for the WAL to arrive.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StaleReads {
+    /// Serve at the last record LSN, if the requested LSN is at most this many
+    /// bytes of WAL ahead of it. Requests further ahead fail.
+    MaxLag(u64),
+    /// Serve at the disk consistent LSN, from the layers written to disk.
+    DiskConsistent,
+}
+
+impl FromStr for StaleReads {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s == "disk_consistent" {
+            return Ok(StaleReads::DiskConsistent);
+        }
+        let max_lag = s.parse::<u64>().with_context(|| {
+            format!("invalid stale_reads '{s}', expected a lag in bytes or 'disk_consistent'")
+        })?;
+        Ok(StaleReads::MaxLag(max_lag))
+    }
+}
+
+impl StaleReads {
+    /// Pick the LSN to serve a request at, without waiting for WAL.
+    fn read_lsn<R: Repository>(
+        self,
+        timeline: &DatadirTimeline<R>,
+        lsn: Lsn,
+        latest: bool,
+    ) -> Result<Lsn> {
+        if !latest && lsn == Lsn(0) {
+            bail!("invalid LSN(0) in request");
+        }
+
+        let available_lsn = match self {
+            StaleReads::MaxLag(max_lag) => {
+                let last_record_lsn = timeline.get_last_record_lsn();
+                ensure!(
+                    lsn <= last_record_lsn + max_lag,
+                    "requested LSN {} is more than {} bytes ahead of last record LSN {}",
+                    lsn,
+                    max_lag,
+                    last_record_lsn
+                );
+                last_record_lsn
+            }
+            StaleReads::DiskConsistent => timeline.tline.get_disk_consistent_lsn(),
+        };
+
+        if lsn > available_lsn {
+            STALE_READS_COUNT.inc();
+            Ok(available_lsn)
+        } else if latest {
+            Ok(available_lsn)
+        } else {
+            Ok(lsn)
+        }
+    }
+}
+
+///
+/// Read LSN of the compact pagestream requests, the ones without the latest
+/// flag and the LSN.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DefaultReadLsn {
+    /// The latest page version that the page server has, without waiting for
+    /// WAL. The same as the latest flag with LSN 0.
+    Latest,
+    /// The page versions at this LSN, for read-only computes.
+    At(Lsn),
+}
+
+impl FromStr for DefaultReadLsn {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s == "latest" {
+            return Ok(DefaultReadLsn::Latest);
+        }
+        let lsn = Lsn::from_str(s)
+            .with_context(|| format!("invalid read_lsn '{s}', expected an LSN or 'latest'"))?;
+        ensure!(lsn != Lsn(0), "invalid read_lsn {s}");
+        Ok(DefaultReadLsn::At(lsn))
+    }
+}
+
+impl DefaultReadLsn {
+    /// The latest flag and the LSN of a full request with the same meaning.
+    fn latest_and_lsn(self) -> (bool, Lsn) {
+        match self {
+            DefaultReadLsn::Latest => (true, Lsn(0)),
+            DefaultReadLsn::At(lsn) => (false, lsn),
+        }
+    }
+}
+
+///
+/// What the requests with the latest flag of a session read.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ReadGranularity {
+    /// The page versions at the last WAL record received.
+    Record,
+    /// The page versions at the last transaction boundary, see
+    /// [`DatadirTimeline::advance_commit_lsn`].
+    Commit,
+}
+
+impl FromStr for ReadGranularity {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "record" => Ok(ReadGranularity::Record),
+            "commit" => Ok(ReadGranularity::Commit),
+            _ => bail!("invalid read_granularity '{s}', expected 'record' or 'commit'"),
+        }
+    }
+}
+
+/// Longest application_name kept, in characters, as in Postgres.
+const MAX_APPLICATION_NAME_LEN: usize = 63;
+
+///
+/// Defaults of a page service session, so that the compute doesn't need to
+/// repeat them in every command and request.
+///
+/// They are set in the startup packet, usually with the connection options:
+/// `options='-c ztenantid=<id> ztimelineid=<id> read_lsn=<lsn>|latest'`,
+/// plus the standard `application_name`. The pagestream command can override
+/// the read_lsn, the application_name, error_codes, priority and
+/// read_granularity with options of its own.
+///
+/// With `error_codes=true`, failed requests are answered with an error
+/// message that carries a [`ReadErrorCode`], so that the compute can tell
+/// e.g. a request behind the GC cutoff from a corrupted layer.
+///
+/// With `priority=background`, all the requests of the session are served at
+/// [`Priority::Background`], as if they had the `PAGESTREAM_BACKGROUND_FLAG`.
+/// That's for bulk readers, like a compute that warms up its cache.
+///
+/// With `read_granularity=commit`, the requests with the latest flag wait for
+/// the first transaction boundary at or after their LSN, and read at the last
+/// one, so that the waits line up with the commits and a read never stops
+/// halfway through the records of any transaction. A boundary is where all
+/// the transactions with records so far have ended, so a wait may last until
+/// the end of a long transaction that is still writing. The requests at a
+/// given LSN, and the ones with stale reads, are not affected.
+///
+/// The application_name is recorded in the logs of the session and labels
+/// its request counts, see [`metric_labels::application_label`].
+///
+#[derive(Debug, Default, Clone)]
+struct SessionDefaults {
+    tenant_id: Option<ZTenantId>,
+    timeline_id: Option<ZTimelineId>,
+    read_lsn: Option<DefaultReadLsn>,
+    application_name: Option<String>,
+    error_codes: bool,
+    priority: Option<Priority>,
+    read_granularity: Option<ReadGranularity>,
+}
+
+impl SessionDefaults {
+    /// Set a session parameter from the startup packet. Returns false if the
+    /// parameter isn't one of ours: the clients send many standard ones.
+    fn set_startup_param(&mut self, name: &str, value: &str) -> Result<bool> {
+        match name {
+            "ztenantid" => self.tenant_id = Some(ZTenantId::from_str(value)?),
+            "ztimelineid" => self.timeline_id = Some(ZTimelineId::from_str(value)?),
+            _ => return self.set_option(name, value),
+        }
+        Ok(true)
+    }
+
+    /// Set an option that can be given both in the startup packet and in the
+    /// pagestream command. Returns false if it's not a session option.
+    fn set_option(&mut self, name: &str, value: &str) -> Result<bool> {
+        match name {
+            "read_lsn" => self.read_lsn = Some(DefaultReadLsn::from_str(value)?),
+            "application_name" => {
+                self.application_name = Some(value.chars().take(MAX_APPLICATION_NAME_LEN).collect())
+            }
+            "error_codes" => {
+                self.error_codes = value
+                    .parse()
+                    .with_context(|| format!("invalid error_codes '{value}'"))?
+            }
+            "priority" => self.priority = Some(Priority::from_str(value)?),
+            "read_granularity" => self.read_granularity = Some(ReadGranularity::from_str(value)?),
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+}
+
 #[derive(Debug)]
 struct PageServerHandler {
     conf: &'static PageServerConf,
     auth: Option<Arc<JwtAuth>>,
     claims: Option<Claims>,
+    /// Cancels waits for WAL when the connection is gone.
+    cancel: CancellationToken,
+    /// Set by the pagestream command, see `StaleReads`.
+    stale_reads: Option<StaleReads>,
+    session: SessionDefaults,
 }
 
 const TIME_BUCKETS: &[f64] = &[
@@ -345,6 +725,9 @@ const TIME_BUCKETS: &[f64] = &[
     0.1,  // 1/10 s
 ];
 
+/// How often a compute connection checks if the timeline it waits for is downloaded.
+const DOWNLOAD_WAIT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
 lazy_static! {
     static ref SMGR_QUERY_TIME: HistogramVec = register_histogram_vec!(
         "pageserver_smgr_query_seconds",
@@ -353,14 +736,111 @@ lazy_static! {
         TIME_BUCKETS.into()
     )
     .expect("failed to define a metric");
+    static ref SMGR_QUERIES_BY_APPLICATION: IntCounterVec = register_int_counter_vec!(
+        "pageserver_smgr_queries_by_application_total",
+        "Number of smgr queries, by the application_name of the connection",
+        &["smgr_query_type", "application_name"]
+    )
+    .expect("failed to define a metric");
+    static ref DOWNLOAD_WAITS: IntCounterVec = register_int_counter_vec!(
+        "pageserver_compute_download_waits_total",
+        "Number of compute connections that waited for a timeline download, by outcome",
+        &["outcome"]
+    )
+    .expect("failed to define a metric");
+    static ref DOWNLOAD_WAIT_TIME: HistogramVec = register_histogram_vec!(
+        "pageserver_compute_download_wait_seconds",
+        "Time compute connections were blocked waiting for a timeline download, before their first read",
+        &["tenant_id"],
+        // Up to the default download_wait_timeout of 30 seconds, and beyond
+        vec![0.1, 0.5, 1.0, 2.0, 5.0, 10.0, 30.0, 60.0, 300.0]
+    )
+    .expect("failed to define a metric");
+    static ref STALE_READS_COUNT: IntCounter = register_int_counter!(
+        "pageserver_stale_reads_total",
+        "Number of smgr requests served at an older LSN than requested, on connections with stale reads enabled"
+    )
+    .expect("failed to define a metric");
+}
+
+///
+/// Get the local timeline that a compute connected to. If it's not there, it may be
+/// still waiting for its download, or for the download of its ancestor if it was
+/// branched off a remote timeline: move the download ahead of the others, and wait
+/// for it up to `download_wait_timeout`, so that the compute doesn't fail right away.
+///
+/// The whole timeline is waited for, not just the layers that the compute needs: a
+/// timeline is only loaded with all of its layers, so that no read can miss a layer
+/// and return an older page version.
+///
+fn get_local_timeline_for_compute(
+    conf: &'static PageServerConf,
+    tenantid: ZTenantId,
+    timelineid: ZTimelineId,
+) -> Result<Arc<DatadirTimelineImpl>> {
+    let started_at = Instant::now();
+    let deadline = started_at + conf.download_wait_timeout;
+    let observe_wait = || {
+        DOWNLOAD_WAIT_TIME
+            .with_label_values(&[&metric_labels::tenant_label(&tenantid)])
+            .observe(started_at.elapsed().as_secs_f64());
+    };
+    let mut waited = false;
+    loop {
+        let e = match tenant_mgr::get_local_timeline_with_load(tenantid, timelineid) {
+            Ok(timeline) => {
+                if waited {
+                    DOWNLOAD_WAITS.with_label_values(&["ok"]).inc();
+                    observe_wait();
+                }
+                return Ok(timeline);
+            }
+            Err(e) => e,
+        };
+
+        let awaited_timelineid =
+            tenant_mgr::download_missing_ancestor(tenantid, timelineid).unwrap_or(timelineid);
+        storage_sync::prioritize_download(ZTenantTimelineId::new(tenantid, awaited_timelineid));
+
+        if !tenant_mgr::awaits_download(tenantid, awaited_timelineid) {
+            return Err(e).context(ReadError::NotFound {
+                tenant_id: tenantid,
+                timeline_id: timelineid,
+                what: "Cannot load local timeline".to_string(),
+            });
+        }
+        if Instant::now() >= deadline || thread_mgr::is_shutdown_requested() {
+            DOWNLOAD_WAITS.with_label_values(&["timeout"]).inc();
+            observe_wait();
+            return Err(e).context(ReadError::RemoteUnavailable {
+                tenant_id: tenantid,
+                timeline_id: timelineid,
+                what: format!(
+                    "Cannot load local timeline, timeline {awaited_timelineid} is still being downloaded"
+                ),
+            });
+        }
+        if !waited {
+            info!("waiting for the download of timeline {awaited_timelineid}");
+            waited = true;
+        }
+        std::thread::sleep(DOWNLOAD_WAIT_POLL_INTERVAL);
+    }
 }
 
 impl PageServerHandler {
-    pub fn new(conf: &'static PageServerConf, auth: Option<Arc<JwtAuth>>) -> Self {
+    pub fn new(
+        conf: &'static PageServerConf,
+        auth: Option<Arc<JwtAuth>>,
+        cancel: CancellationToken,
+    ) -> Self {
         PageServerHandler {
             conf,
             auth,
             claims: None,
+            cancel,
+            stale_reads: None,
+            session: SessionDefaults::default(),
         }
     }
 
@@ -370,16 +850,33 @@ impl PageServerHandler {
         timelineid: ZTimelineId,
         tenantid: ZTenantId,
     ) -> anyhow::Result<()> {
-        let _enter = info_span!("pagestream", timeline = %timelineid, tenant = %tenantid).entered();
+        let application_name = self.session.application_name.as_deref().unwrap_or_default();
+        let _enter = info_span!(
+            "pagestream",
+            timeline = %timelineid,
+            tenant = %tenantid,
+            application_name = %application_name
+        )
+        .entered();
+        let application_label = metric_labels::application_label(application_name);
 
         // Check that the timeline exists
-        let timeline = tenant_mgr::get_local_timeline_with_load(tenantid, timelineid)
-            .context("Cannot load local timeline")?;
+        let timeline = get_local_timeline_for_compute(self.conf, tenantid, timelineid)?;
+        // Detaching the timeline waits for this thread to finish the current request
+        thread_mgr::associate_with(Some(tenantid), Some(timelineid));
+        let mut access_recorder = AccessRecorder::new(tenantid, timelineid);
 
         /* switch client to COPYBOTH */
         pgb.write_message(&BeMessage::CopyBothResponse)?;
 
         while !thread_mgr::is_shutdown_requested() {
+            // Close the connection between requests when draining. The compute
+            // reconnects, to another pageserver by then.
+            if page_service_drain::get().is_draining() {
+                info!("page service is draining, closing the connection");
+                break;
+            }
+
             let msg = pgb.read_message();
 
             let profiling_guard = profpoint_start(self.conf, ProfilingConfig::PageRequests);
@@ -393,28 +890,57 @@ impl PageServerHandler {
                             _ => continue,
                         };
 
-                        let zenith_fe_msg = PagestreamFeMessage::parse(copy_data_bytes)?;
-                        let tenant_id = tenantid.to_string();
-                        let timeline_id = timelineid.to_string();
+                        let (zenith_fe_msg, background, trace_context) =
+                            PagestreamFeMessage::parse(copy_data_bytes, self.session.read_lsn)?;
+                        let _priority = request_priority::set_current(if background {
+                            Priority::Background
+                        } else {
+                            self.session.priority.unwrap_or(Priority::Interactive)
+                        });
+                        // Everything logged while serving the request, down to
+                        // the layer lookups and WAL redo, carries the trace ids.
+                        let _request_span = match &trace_context {
+                            Some(trace_context) => info_span!(
+                                "request",
+                                trace_id = %trace_context.trace_id,
+                                parent_id = %trace_context.parent_id
+                            ),
+                            None => Span::none(),
+                        }
+                        .entered();
+                        let (tenant_id, timeline_id) =
+                            metric_labels::timeline_labels(&tenantid, &timelineid);
+                        let query_type = zenith_fe_msg.query_type();
+                        SMGR_QUERIES_BY_APPLICATION
+                            .with_label_values(&[query_type, &application_label])
+                            .inc();
 
                         let response = match zenith_fe_msg {
                             PagestreamFeMessage::Exists(req) => SMGR_QUERY_TIME
-                                .with_label_values(&["get_rel_exists", &tenant_id, &timeline_id])
+                                .with_label_values(&[query_type, &tenant_id, &timeline_id])
                                 .observe_closure_duration(|| {
                                     self.handle_get_rel_exists_request(timeline.as_ref(), &req)
                                 }),
                             PagestreamFeMessage::Nblocks(req) => SMGR_QUERY_TIME
-                                .with_label_values(&["get_rel_size", &tenant_id, &timeline_id])
+                                .with_label_values(&[query_type, &tenant_id, &timeline_id])
                                 .observe_closure_duration(|| {
                                     self.handle_get_nblocks_request(timeline.as_ref(), &req)
                                 }),
-                            PagestreamFeMessage::GetPage(req) => SMGR_QUERY_TIME
-                                .with_label_values(&["get_page_at_lsn", &tenant_id, &timeline_id])
-                                .observe_closure_duration(|| {
-                                    self.handle_get_page_at_lsn_request(timeline.as_ref(), &req)
-                                }),
+                            PagestreamFeMessage::GetPage(req) => {
+                                access_recorder.record_get_page(req.rel, req.blkno);
+                                SMGR_QUERY_TIME
+                                    .with_label_values(&[query_type, &tenant_id, &timeline_id])
+                                    .observe_closure_duration(|| {
+                                        self.handle_get_page_at_lsn_request(
+                                            timeline.as_ref(),
+                                            &req,
+                                            tenantid,
+                                            timelineid,
+                                        )
+                                    })
+                            }
                             PagestreamFeMessage::DbSize(req) => SMGR_QUERY_TIME
-                                .with_label_values(&["get_db_size", &tenant_id, &timeline_id])
+                                .with_label_values(&[query_type, &tenant_id, &timeline_id])
                                 .observe_closure_duration(|| {
                                     self.handle_db_size_request(timeline.as_ref(), &req)
                                 }),
@@ -424,12 +950,25 @@ impl PageServerHandler {
                             // print the all details to the log with {:#}, but for the client the
                             // error message is enough
                             error!("error reading relation or page version: {:?}", e);
+                            let code = ReadErrorCode::of(&e);
+                            // The typed errors name the tenant and the timeline themselves
+                            let message = if code == ReadErrorCode::Internal {
+                                format!("{e}, tenant {tenantid} timeline {timelineid}")
+                            } else {
+                                e.to_string()
+                            };
                             PagestreamBeMessage::Error(PagestreamErrorResponse {
-                                message: e.to_string(),
+                                code: if self.session.error_codes {
+                                    Some(code)
+                                } else {
+                                    None
+                                },
+                                message,
                             })
                         });
 
-                        pgb.write_message(&BeMessage::CopyData(&response.serialize()))?;
+                        response.write_to(pgb)?;
+                        thread_mgr::record_activity();
                     } else {
                         break;
                     }
@@ -457,13 +996,26 @@ impl PageServerHandler {
     /// In either case, if the page server hasn't received the WAL up to the
     /// requested LSN yet, we will wait for it to arrive. The return value is
     /// the LSN that should be used to look up the page versions.
+    ///
+    /// The wait is abandoned if the connection is closed in the meantime. On
+    /// connections with stale reads enabled, there is no wait at all.
     fn wait_or_get_last_lsn<R: Repository>(
+        &self,
         timeline: &DatadirTimeline<R>,
         mut lsn: Lsn,
         latest: bool,
         latest_gc_cutoff_lsn: &RwLockReadGuard<Lsn>,
     ) -> Result<Lsn> {
-        if latest {
+        if let Some(stale_reads) = self.stale_reads {
+            lsn = stale_reads.read_lsn(timeline, lsn, latest)?;
+        } else if latest && self.session.read_granularity == Some(ReadGranularity::Commit) {
+            // Like below, LSN 0 doesn't wait
+            lsn = timeline.wait_commit_lsn_cancellable(
+                lsn,
+                self.conf.wait_lsn_timeout,
+                &self.cancel,
+            )?;
+        } else if latest {
             // Latest page version was requested. If LSN is given, it is a hint
             // to the page server that there have been no modifications to the
             // page after that LSN. If we haven't received WAL up to that point,
@@ -485,7 +1037,7 @@ impl PageServerHandler {
             if lsn <= last_record_lsn {
                 lsn = last_record_lsn;
             } else {
-                timeline.tline.wait_lsn(lsn)?;
+                timeline.tline.wait_lsn_cancellable(lsn, &self.cancel)?;
                 // Since we waited for 'lsn' to arrive, that is now the last
                 // record LSN. (Or close enough for our purposes; the
                 // last-record LSN can advance immediately after we return
@@ -495,13 +1047,9 @@ impl PageServerHandler {
             if lsn == Lsn(0) {
                 bail!("invalid LSN(0) in request");
             }
-            timeline.tline.wait_lsn(lsn)?;
+            timeline.tline.wait_lsn_cancellable(lsn, &self.cancel)?;
         }
-        ensure!(
-            lsn >= **latest_gc_cutoff_lsn,
-            "tried to request a page version that was garbage collected. requested at {} gc cutoff {}",
-            lsn, **latest_gc_cutoff_lsn
-        );
+        timeline.check_lsn_is_in_scope(lsn, latest_gc_cutoff_lsn)?;
         Ok(lsn)
     }
 
@@ -513,7 +1061,8 @@ impl PageServerHandler {
         let _enter = info_span!("get_rel_exists", rel = %req.rel, req_lsn = %req.lsn).entered();
 
         let latest_gc_cutoff_lsn = timeline.tline.get_latest_gc_cutoff_lsn();
-        let lsn = Self::wait_or_get_last_lsn(timeline, req.lsn, req.latest, &latest_gc_cutoff_lsn)?;
+        let lsn =
+            self.wait_or_get_last_lsn(timeline, req.lsn, req.latest, &latest_gc_cutoff_lsn)?;
 
         let exists = timeline.get_rel_exists(req.rel, lsn)?;
 
@@ -529,7 +1078,8 @@ impl PageServerHandler {
     ) -> Result<PagestreamBeMessage> {
         let _enter = info_span!("get_nblocks", rel = %req.rel, req_lsn = %req.lsn).entered();
         let latest_gc_cutoff_lsn = timeline.tline.get_latest_gc_cutoff_lsn();
-        let lsn = Self::wait_or_get_last_lsn(timeline, req.lsn, req.latest, &latest_gc_cutoff_lsn)?;
+        let lsn =
+            self.wait_or_get_last_lsn(timeline, req.lsn, req.latest, &latest_gc_cutoff_lsn)?;
 
         let n_blocks = timeline.get_rel_size(req.rel, lsn)?;
 
@@ -545,7 +1095,8 @@ impl PageServerHandler {
     ) -> Result<PagestreamBeMessage> {
         let _enter = info_span!("get_db_size", dbnode = %req.dbnode, req_lsn = %req.lsn).entered();
         let latest_gc_cutoff_lsn = timeline.tline.get_latest_gc_cutoff_lsn();
-        let lsn = Self::wait_or_get_last_lsn(timeline, req.lsn, req.latest, &latest_gc_cutoff_lsn)?;
+        let lsn =
+            self.wait_or_get_last_lsn(timeline, req.lsn, req.latest, &latest_gc_cutoff_lsn)?;
 
         let all_rels = timeline.list_rels(pg_constants::DEFAULTTABLESPACE_OID, req.dbnode, lsn)?;
         let mut total_blocks: i64 = 0;
@@ -568,11 +1119,14 @@ impl PageServerHandler {
         &self,
         timeline: &DatadirTimeline<R>,
         req: &PagestreamGetPageRequest,
+        tenantid: ZTenantId,
+        timelineid: ZTimelineId,
     ) -> Result<PagestreamBeMessage> {
         let _enter = info_span!("get_page", rel = %req.rel, blkno = &req.blkno, req_lsn = %req.lsn)
             .entered();
         let latest_gc_cutoff_lsn = timeline.tline.get_latest_gc_cutoff_lsn();
-        let lsn = Self::wait_or_get_last_lsn(timeline, req.lsn, req.latest, &latest_gc_cutoff_lsn)?;
+        let lsn =
+            self.wait_or_get_last_lsn(timeline, req.lsn, req.latest, &latest_gc_cutoff_lsn)?;
         /*
         // Add a 1s delay to some requests. The delayed causes the requests to
         // hit the race condition from github issue #1047 more easily.
@@ -582,6 +1136,16 @@ impl PageServerHandler {
         }
         */
         let page = timeline.get_rel_page_at_lsn(req.rel, req.blkno, lsn)?;
+        wal_trace::record_page_request(
+            self.conf,
+            tenantid,
+            timelineid,
+            &wal_trace::PageRequest {
+                lsn,
+                rel: req.rel,
+                blkno: req.blkno,
+            },
+        );
 
         Ok(PagestreamBeMessage::GetPage(PagestreamGetPageResponse {
             page,
@@ -598,10 +1162,12 @@ impl PageServerHandler {
         let span = info_span!("basebackup", timeline = %timelineid, tenant = %tenantid, lsn = field::Empty);
         let _enter = span.enter();
         info!("starting");
+        // Reads the whole timeline, let the page requests of the running computes go first
+        let _priority = request_priority::set_current(Priority::Background);
 
         // check that the timeline exists
-        let timeline = tenant_mgr::get_local_timeline_with_load(tenantid, timelineid)
-            .context("Cannot load local timeline")?;
+        let timeline = get_local_timeline_for_compute(self.conf, tenantid, timelineid)?;
+        thread_mgr::associate_with(Some(tenantid), Some(timelineid));
         let latest_gc_cutoff_lsn = timeline.tline.get_latest_gc_cutoff_lsn();
         if let Some(lsn) = lsn {
             timeline
@@ -626,6 +1192,140 @@ impl PageServerHandler {
         Ok(())
     }
 
+    ///
+    /// Send a tarball with the images of the relation blocks that were modified
+    /// after 'from_lsn', up to 'to_lsn' or the end of the timeline, for
+    /// incremental backups. See [`incremental_backup`] for the format.
+    ///
+    fn handle_incremental_backup_request(
+        &self,
+        pgb: &mut PostgresBackend,
+        timelineid: ZTimelineId,
+        tenantid: ZTenantId,
+        from_lsn: Lsn,
+        to_lsn: Option<Lsn>,
+    ) -> anyhow::Result<()> {
+        let span = info_span!("incremental_backup", timeline = %timelineid, tenant = %tenantid, from_lsn = %from_lsn, to_lsn = field::Empty);
+        let _enter = span.enter();
+        info!("starting");
+        let _priority = request_priority::set_current(Priority::Background);
+
+        let timeline = tenant_mgr::get_local_timeline_with_load(tenantid, timelineid)
+            .context("Cannot load local timeline")?;
+        thread_mgr::associate_with(Some(tenantid), Some(timelineid));
+        let backup = incremental_backup::IncrementalBackup::new(&timeline, from_lsn, to_lsn)?;
+        span.record("to_lsn", &backup.to_lsn.to_string().as_str());
+
+        pgb.write_message(&BeMessage::CopyOutResponse)?;
+        backup.send_tarball(CopyDataSink { pgb })?;
+        pgb.write_message(&BeMessage::CopyDone)?;
+        info!("done");
+
+        Ok(())
+    }
+
+    ///
+    /// Ingest WAL that the client sends in CopyData messages into the timeline,
+    /// the same way the WAL receiver ingests the WAL streamed from a safekeeper.
+    ///
+    /// The WAL must continue the timeline's WAL: 'start_lsn' has to be the end of
+    /// the last record on the timeline. If 'end_lsn' is given, records past it are
+    /// not ingested, and the client has to send the WAL at least up to it.
+    ///
+    fn handle_import_wal(
+        &self,
+        pgb: &mut PostgresBackend,
+        timelineid: ZTimelineId,
+        tenantid: ZTenantId,
+        start_lsn: Lsn,
+        end_lsn: Option<Lsn>,
+    ) -> anyhow::Result<()> {
+        let _enter = info_span!("import wal", timeline = %timelineid, tenant = %tenantid).entered();
+
+        if tenant_mgr::get_tenant_state(tenantid) == Some(TenantState::ReadOnly) {
+            bail!("Tenant {tenantid} is in read-only mode, refusing to import WAL");
+        }
+        // Keeps the WAL receiver from starting while the WAL is imported
+        let _import_guard = walreceiver::start_wal_import(tenantid, timelineid)?;
+
+        let timeline = tenant_mgr::get_local_timeline_with_load(tenantid, timelineid)
+            .context("Cannot load local timeline")?;
+
+        // There might be some padding after the last full record, the WAL may start after it.
+        let last_record_lsn = timeline.get_last_record_lsn();
+        let startpoint = last_record_lsn + last_record_lsn.calc_padding(8u32);
+        ensure!(
+            start_lsn == last_record_lsn || start_lsn == startpoint,
+            "WAL to import starts at {start_lsn}, but the timeline's WAL ends at {last_record_lsn}"
+        );
+        if let Some(end_lsn) = end_lsn {
+            ensure!(
+                end_lsn >= start_lsn,
+                "end LSN {end_lsn} is before start LSN {start_lsn}"
+            );
+        }
+
+        // Start the checkpointer, to write out the imported WAL as it arrives.
+        tenant_mgr::activate_tenant(tenantid)?;
+
+        let mut waldecoder = WalStreamDecoder::new(start_lsn);
+        let mut walingest = WalIngest::new(&*timeline, start_lsn)?;
+        let mut received_up_to = start_lsn;
+        let mut last_lsn = last_record_lsn;
+        let mut nrecords: u64 = 0;
+
+        info!("importing WAL starting at {start_lsn}");
+        pgb.write_message(&BeMessage::CopyInResponse)?;
+        loop {
+            if thread_mgr::is_shutdown_requested() {
+                bail!("pageserver is shutting down");
+            }
+            let msg = match pgb.read_message() {
+                Ok(msg) => msg,
+                Err(e) if is_socket_read_timed_out(&e) => continue,
+                Err(e) => return Err(e),
+            };
+            match msg {
+                Some(FeMessage::CopyData(bytes)) => {
+                    received_up_to += bytes.len() as u64;
+                    waldecoder.feed_bytes(&bytes);
+
+                    while let Some((lsn, recdata)) = waldecoder.poll_decode()? {
+                        if matches!(end_lsn, Some(end_lsn) if lsn > end_lsn) {
+                            break;
+                        }
+                        // See the WAL receiver, getPage@LSN requests use aligned LSNs.
+                        ensure!(lsn.is_aligned());
+
+                        walingest.ingest_record(&timeline, recdata, lsn)?;
+                        last_lsn = lsn;
+                        nrecords += 1;
+                    }
+
+                    timeline.tline.check_checkpoint_distance()?;
+                }
+                Some(FeMessage::CopyDone) => break,
+                Some(FeMessage::CopyFail) => bail!("client aborted the WAL import"),
+                Some(FeMessage::Sync) => continue,
+                Some(other) => bail!("unexpected message during WAL import: {:?}", other),
+                None => bail!("client disconnected during WAL import"),
+            }
+        }
+
+        if let Some(end_lsn) = end_lsn {
+            ensure!(
+                received_up_to >= end_lsn,
+                "WAL stream ended at {received_up_to}, before the end LSN {end_lsn}"
+            );
+        }
+        info!("imported {nrecords} WAL records, timeline's WAL ends at {last_lsn} now");
+
+        pgb.write_message_noflush(&BeMessage::CommandComplete(
+            format!("IMPORT {nrecords}").as_bytes(),
+        ))?;
+        Ok(())
+    }
+
     // when accessing management api supply None as an argument
     // when using to authorize tenant pass corresponding tenant id
     fn check_permission(&self, tenantid: Option<ZTenantId>) -> Result<()> {
@@ -645,6 +1345,17 @@ impl PageServerHandler {
 }
 
 impl postgres_backend::Handler for PageServerHandler {
+    fn startup(&mut self, _pgb: &mut PostgresBackend, sm: &FeStartupPacket) -> Result<()> {
+        if let FeStartupPacket::StartupMessage { params, .. } = sm {
+            for (name, value) in params {
+                self.session
+                    .set_startup_param(name, value)
+                    .with_context(|| format!("invalid connection option {name}"))?;
+            }
+        }
+        Ok(())
+    }
+
     fn check_auth_jwt(
         &mut self,
         _pgb: &mut PostgresBackend,
@@ -681,18 +1392,59 @@ impl postgres_backend::Handler for PageServerHandler {
     ) -> anyhow::Result<()> {
         debug!("process query {:?}", query_string);
 
-        if query_string.starts_with("pagestream ") {
-            let (_, params_raw) = query_string.split_at("pagestream ".len());
-            let params = params_raw.split(' ').collect::<Vec<_>>();
+        if query_string == "pagestream" || query_string.starts_with("pagestream ") {
+            // pagestream [<tenantid> <timelineid>] [<option>=<value> ...]
+            //
+            // The tenant and the timeline default to the ones of the session. The
+            // options are stale_reads=<max lag in bytes>|disk_consistent, and the
+            // session options read_lsn, application_name, error_codes, priority
+            // and read_granularity.
+            let params = query_string["pagestream".len()..]
+                .split_whitespace()
+                .collect::<Vec<_>>();
+            let n_ids = params.iter().take_while(|p| !p.contains('=')).count();
             ensure!(
-                params.len() == 2,
+                n_ids == 0 || n_ids == 2,
                 "invalid param number for pagestream command"
             );
-            let tenantid = ZTenantId::from_str(params[0])?;
-            let timelineid = ZTimelineId::from_str(params[1])?;
+
+            let mut stale_reads = None;
+            for option in &params[n_ids..] {
+                let (name, value) = option
+                    .split_once('=')
+                    .with_context(|| format!("invalid pagestream option '{option}'"))?;
+                if name == "stale_reads" {
+                    stale_reads = Some(StaleReads::from_str(value)?);
+                } else {
+                    ensure!(
+                        self.session.set_option(name, value)?,
+                        "unknown pagestream option '{option}'"
+                    );
+                }
+            }
+            self.stale_reads = stale_reads;
+
+            let (tenantid, timelineid) = if n_ids == 2 {
+                (
+                    ZTenantId::from_str(params[0])?,
+                    ZTimelineId::from_str(params[1])?,
+                )
+            } else {
+                (
+                    self.session.tenant_id.context(
+                        "tenant id is neither in the command nor in the connection options",
+                    )?,
+                    self.session.timeline_id.context(
+                        "timeline id is neither in the command nor in the connection options",
+                    )?,
+                )
+            };
 
             self.check_permission(Some(tenantid))?;
 
+            let _session = page_service_drain::get()
+                .begin_session()
+                .context("pageserver is draining, not accepting new connections")?;
             self.handle_pagerequests(pgb, timelineid, tenantid)?;
         } else if query_string.starts_with("basebackup ") {
             let (_, params_raw) = query_string.split_at("basebackup ".len());
@@ -714,9 +1466,48 @@ impl postgres_backend::Handler for PageServerHandler {
                 None
             };
 
+            let _session = page_service_drain::get()
+                .begin_session()
+                .context("pageserver is draining, not accepting new connections")?;
             // Check that the timeline exists
             self.handle_basebackup_request(pgb, timelineid, lsn, tenantid)?;
             pgb.write_message_noflush(&BeMessage::CommandComplete(b"SELECT 1"))?;
+        } else if query_string.starts_with("incremental_backup ") {
+            // incremental_backup <tenantid> <timelineid> <from_lsn> [<to_lsn>]
+            let (_, params_raw) = query_string.split_at("incremental_backup ".len());
+            let params = params_raw.split_whitespace().collect::<Vec<_>>();
+
+            ensure!(
+                params.len() == 3 || params.len() == 4,
+                "invalid param number for incremental_backup command"
+            );
+
+            let tenantid = ZTenantId::from_str(params[0])?;
+            let timelineid = ZTimelineId::from_str(params[1])?;
+            let from_lsn = Lsn::from_str(params[2])?;
+            let to_lsn = params.get(3).map(|lsn| Lsn::from_str(lsn)).transpose()?;
+
+            self.check_permission(Some(tenantid))?;
+
+            self.handle_incremental_backup_request(pgb, timelineid, tenantid, from_lsn, to_lsn)?;
+            pgb.write_message_noflush(&BeMessage::CommandComplete(b"SELECT 1"))?;
+        } else if query_string.starts_with("import wal ") {
+            // import wal <tenantid> <timelineid> <start_lsn> [<end_lsn>]
+            let (_, params_raw) = query_string.split_at("import wal ".len());
+            let params = params_raw.split_whitespace().collect::<Vec<_>>();
+            ensure!(
+                params.len() == 3 || params.len() == 4,
+                "invalid param number for import wal command"
+            );
+
+            let tenantid = ZTenantId::from_str(params[0])?;
+            let timelineid = ZTimelineId::from_str(params[1])?;
+            let start_lsn = Lsn::from_str(params[2])?;
+            let end_lsn = params.get(3).map(|lsn| Lsn::from_str(lsn)).transpose()?;
+
+            self.check_permission(Some(tenantid))?;
+
+            self.handle_import_wal(pgb, timelineid, tenantid, start_lsn, end_lsn)?;
         } else if query_string.starts_with("callmemaybe ") {
             // callmemaybe <zenith tenantid as hex string> <zenith timelineid as hex string> <connstr>
             // TODO lazy static
@@ -786,6 +1577,8 @@ impl postgres_backend::Handler for PageServerHandler {
                 RowDescriptor::int8_col(b"gc_period"),
                 RowDescriptor::int8_col(b"image_creation_threshold"),
                 RowDescriptor::int8_col(b"pitr_interval"),
+                RowDescriptor::int8_col(b"logical_size_soft_limit"),
+                RowDescriptor::text_col(b"image_layer_excluded_forks"),
             ]))?
             .write_message_noflush(&BeMessage::DataRow(&[
                 Some(repo.get_checkpoint_distance().to_string().as_bytes()),
@@ -801,6 +1594,11 @@ impl postgres_backend::Handler for PageServerHandler {
                 Some(repo.get_gc_period().as_secs().to_string().as_bytes()),
                 Some(repo.get_image_creation_threshold().to_string().as_bytes()),
                 Some(repo.get_pitr_interval().as_secs().to_string().as_bytes()),
+                repo.get_logical_size_soft_limit()
+                    .map(|limit| limit.to_string())
+                    .as_deref()
+                    .map(str::as_bytes),
+                Some(repo.get_image_layer_excluded_forks().to_string().as_bytes()),
             ]))?
             .write_message(&BeMessage::CommandComplete(b"SELECT 1"))?;
         } else if query_string.starts_with("do_gc ") {
@@ -840,6 +1638,15 @@ impl postgres_backend::Handler for PageServerHandler {
                 RowDescriptor::int8_col(b"layers_not_updated"),
                 RowDescriptor::int8_col(b"layers_removed"),
                 RowDescriptor::int8_col(b"elapsed"),
+                RowDescriptor::int8_col(b"bytes_needed_by_cutoff"),
+                RowDescriptor::int8_col(b"bytes_needed_by_pitr"),
+                RowDescriptor::int8_col(b"bytes_needed_by_branches"),
+                RowDescriptor::int8_col(b"bytes_not_updated"),
+                RowDescriptor::int8_col(b"bytes_removed"),
+                RowDescriptor::int8_col(b"layers_too_recent"),
+                RowDescriptor::int8_col(b"bytes_too_recent"),
+                RowDescriptor::int8_col(b"layers_needed_by_policy"),
+                RowDescriptor::int8_col(b"bytes_needed_by_policy"),
             ]))?
             .write_message_noflush(&BeMessage::DataRow(&[
                 Some(result.layers_total.to_string().as_bytes()),
@@ -849,6 +1656,15 @@ impl postgres_backend::Handler for PageServerHandler {
                 Some(result.layers_not_updated.to_string().as_bytes()),
                 Some(result.layers_removed.to_string().as_bytes()),
                 Some(result.elapsed.as_millis().to_string().as_bytes()),
+                Some(result.bytes_retained.cutoff.to_string().as_bytes()),
+                Some(result.bytes_retained.pitr.to_string().as_bytes()),
+                Some(result.bytes_retained.branches.to_string().as_bytes()),
+                Some(result.bytes_retained.latest.to_string().as_bytes()),
+                Some(result.bytes_removed.to_string().as_bytes()),
+                Some(result.layers_too_recent.to_string().as_bytes()),
+                Some(result.bytes_retained.recent.to_string().as_bytes()),
+                Some(result.layers_needed_by_policy.to_string().as_bytes()),
+                Some(result.bytes_retained.policy.to_string().as_bytes()),
             ]))?
             .write_message(&BeMessage::CommandComplete(b"SELECT 1"))?;
         } else if query_string.starts_with("compact ") {
@@ -869,6 +1685,35 @@ impl postgres_backend::Handler for PageServerHandler {
                 .context("Couldn't load timeline")?;
             timeline.tline.compact()?;
 
+            pgb.write_message_noflush(&SINGLE_COL_ROWDESC)?
+                .write_message_noflush(&BeMessage::CommandComplete(b"SELECT 1"))?;
+        } else if query_string.starts_with("record_wal ") {
+            // Start or stop recording the WAL that the timeline's WAL receiver
+            // ingests and the GetPage requests served from it, see the wal_trace
+            // module.
+            // FIXME This is for debugging and tests, like compact and checkpoint.
+
+            // record_wal <tenant_id> <timeline_id> on|off
+            let re =
+                Regex::new(r"^record_wal ([[:xdigit:]]+)\s([[:xdigit:]]+)\s(on|off)$").unwrap();
+
+            let caps = re
+                .captures(query_string)
+                .with_context(|| format!("invalid record_wal command: '{}'", query_string))?;
+
+            let tenantid = ZTenantId::from_str(caps.get(1).unwrap().as_str())?;
+            let timelineid = ZTimelineId::from_str(caps.get(2).unwrap().as_str())?;
+
+            self.check_permission(Some(tenantid))?;
+
+            if caps.get(3).unwrap().as_str() == "on" {
+                tenant_mgr::get_local_timeline_with_load(tenantid, timelineid)
+                    .context("Cannot load local timeline")?;
+                wal_trace::start_recording(tenantid, timelineid);
+            } else {
+                wal_trace::stop_recording(tenantid, timelineid);
+            }
+
             pgb.write_message_noflush(&SINGLE_COL_ROWDESC)?
                 .write_message_noflush(&BeMessage::CommandComplete(b"SELECT 1"))?;
         } else if query_string.starts_with("checkpoint ") {
@@ -895,6 +1740,50 @@ impl postgres_backend::Handler for PageServerHandler {
             // separate operation. Update the tests if you change this.
             timeline.tline.compact()?;
 
+            pgb.write_message_noflush(&SINGLE_COL_ROWDESC)?
+                .write_message_noflush(&BeMessage::CommandComplete(b"SELECT 1"))?;
+        } else if query_string.starts_with("standby_feedback ") {
+            // Feedback of a read replica, with the oldest LSN it still reads at.
+            // GC doesn't remove the versions needed at it for a while.
+
+            // standby_feedback <tenant_id> <timeline_id> <lsn>
+            let re =
+                Regex::new(r"^standby_feedback ([[:xdigit:]]+) ([[:xdigit:]]+) (\S+)$").unwrap();
+            let caps = re
+                .captures(query_string)
+                .with_context(|| format!("invalid standby_feedback: '{}'", query_string))?;
+
+            let tenantid = ZTenantId::from_str(caps.get(1).unwrap().as_str())?;
+            let timelineid = ZTimelineId::from_str(caps.get(2).unwrap().as_str())?;
+            let lsn = Lsn::from_str(caps.get(3).unwrap().as_str())?;
+
+            self.check_permission(Some(tenantid))?;
+
+            let timeline = tenant_mgr::get_local_timeline_with_load(tenantid, timelineid)
+                .context("Cannot load local timeline")?;
+            timeline.tline.report_standby_feedback(lsn);
+
+            pgb.write_message_noflush(&SINGLE_COL_ROWDESC)?
+                .write_message_noflush(&BeMessage::CommandComplete(b"SELECT 1"))?;
+        } else if query_string.starts_with("append_only ") {
+            // Hint from the compute that a relation is append-only, or not
+            // anymore, for the layer policies of the tenant.
+
+            // append_only <tenant_id> <spcnode>/<dbnode>/<relnode> on|off
+            let re = Regex::new(r"^append_only ([[:xdigit:]]+) (\S+) (on|off)$").unwrap();
+            let caps = re
+                .captures(query_string)
+                .with_context(|| format!("invalid append_only command: '{}'", query_string))?;
+
+            let tenantid = ZTenantId::from_str(caps.get(1).unwrap().as_str())?;
+            let rel = RelFileNode::from_str(caps.get(2).unwrap().as_str())?;
+            let append_only = caps.get(3).unwrap().as_str() == "on";
+
+            self.check_permission(Some(tenantid))?;
+
+            let repo = tenant_mgr::get_repository_for_tenant(tenantid)?;
+            repo.set_relation_append_only(rel, append_only)?;
+
             pgb.write_message_noflush(&SINGLE_COL_ROWDESC)?
                 .write_message_noflush(&BeMessage::CommandComplete(b"SELECT 1"))?;
         } else if query_string.starts_with("get_lsn_by_timestamp ") {
@@ -960,3 +1849,65 @@ impl<'a> io::Write for CopyDataSink<'a> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pgdatadir_mapping::create_test_timeline;
+    use crate::repository::repo_harness::*;
+
+    #[test]
+    fn stale_reads_option() {
+        assert_eq!(
+            StaleReads::from_str("8192").unwrap(),
+            StaleReads::MaxLag(8192)
+        );
+        assert_eq!(
+            StaleReads::from_str("disk_consistent").unwrap(),
+            StaleReads::DiskConsistent
+        );
+        assert!(StaleReads::from_str("latest").is_err());
+    }
+
+    #[test]
+    fn stale_reads_lsn() -> Result<()> {
+        let repo = RepoHarness::create("stale_reads_lsn")?.load();
+        let tline = create_test_timeline(repo, TIMELINE_ID)?;
+
+        let mut m = tline.begin_modification(Lsn(0x20));
+        m.put_relmap_file(0, 111, Bytes::from(""))?;
+        m.commit()?;
+        tline.tline.checkpoint(CheckpointConfig::Forced)?;
+        let mut m = tline.begin_modification(Lsn(0x40));
+        m.put_relmap_file(0, 111, Bytes::from(""))?;
+        m.commit()?;
+        let disk_consistent_lsn = tline.tline.get_disk_consistent_lsn();
+        assert!(disk_consistent_lsn < Lsn(0x40));
+
+        let max_lag = StaleReads::MaxLag(0x100);
+        // Requests for the WAL already received are served at the requested LSN
+        assert_eq!(max_lag.read_lsn(&tline, Lsn(0x30), false)?, Lsn(0x30));
+        assert_eq!(max_lag.read_lsn(&tline, Lsn(0x30), true)?, Lsn(0x40));
+        assert_eq!(max_lag.read_lsn(&tline, Lsn(0), true)?, Lsn(0x40));
+        assert!(max_lag.read_lsn(&tline, Lsn(0), false).is_err());
+        // Requests ahead of it, within the lag, are served at the last record LSN
+        assert_eq!(max_lag.read_lsn(&tline, Lsn(0x140), false)?, Lsn(0x40));
+        assert_eq!(max_lag.read_lsn(&tline, Lsn(0x140), true)?, Lsn(0x40));
+        // Requests further ahead fail, instead of waiting
+        assert!(max_lag.read_lsn(&tline, Lsn(0x141), true).is_err());
+
+        // Reads from the disk never fail for being too far ahead
+        let disk_consistent = StaleReads::DiskConsistent;
+        assert_eq!(
+            disk_consistent.read_lsn(&tline, Lsn(0x40), true)?,
+            disk_consistent_lsn
+        );
+        assert_eq!(
+            disk_consistent.read_lsn(&tline, Lsn(0x10000), false)?,
+            disk_consistent_lsn
+        );
+        assert_eq!(disk_consistent.read_lsn(&tline, Lsn(8), false)?, Lsn(8));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,141 @@
+//!
+//! Draining of the page service before a restart or a migration.
+//!
+//! While draining, the page service refuses to start new compute sessions: a
+//! `pagestream` or `basebackup` command fails with an error, so the compute
+//! reconnects, and by then the control plane has pointed it at another
+//! pageserver. Sessions that are already running finish the request they are
+//! serving, and the connection is closed. The pageserver is fully drained when
+//! no sessions are left.
+//!
+//! Other page service commands, like `callmemaybe` from the safekeepers, are
+//! still served, so the timelines keep receiving WAL until the restart.
+//!
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use metrics::{register_int_gauge, IntGauge};
+use serde::Serialize;
+use tracing::*;
+
+lazy_static! {
+    static ref PAGE_SERVICE_DRAIN: PageServiceDrain = PageServiceDrain::default();
+    static ref ACTIVE_SESSIONS: IntGauge = register_int_gauge!(
+        "pageserver_page_service_active_sessions",
+        "Number of pagestream and basebackup sessions being served"
+    )
+    .expect("failed to define a metric");
+}
+
+///
+/// Get a handle to the page service drain state.
+///
+pub fn get() -> &'static PageServiceDrain {
+    &PAGE_SERVICE_DRAIN
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DrainStatus {
+    pub draining: bool,
+    pub active_sessions: usize,
+    /// Draining, and no sessions are left.
+    pub drained: bool,
+}
+
+#[derive(Default)]
+pub struct PageServiceDrain {
+    /// Checked by the running sessions between requests, without the lock.
+    draining: AtomicBool,
+    /// Number of running sessions. Protected by a lock, so that no session
+    /// can start after a drain started and the count went down to zero.
+    active_sessions: Mutex<usize>,
+}
+
+///
+/// A running compute session. The session count goes down when it's dropped.
+///
+pub struct PageServiceSession<'a> {
+    drain: &'a PageServiceDrain,
+}
+
+impl Drop for PageServiceSession<'_> {
+    fn drop(&mut self) {
+        let mut active_sessions = self.drain.active_sessions.lock().unwrap();
+        *active_sessions -= 1;
+        ACTIVE_SESSIONS.dec();
+    }
+}
+
+impl PageServiceDrain {
+    ///
+    /// Register a new compute session. Returns None if the page service is draining.
+    ///
+    pub fn begin_session(&self) -> Option<PageServiceSession<'_>> {
+        let mut active_sessions = self.active_sessions.lock().unwrap();
+        if self.is_draining() {
+            return None;
+        }
+        *active_sessions += 1;
+        ACTIVE_SESSIONS.inc();
+        Some(PageServiceSession { drain: self })
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    pub fn start(&self) {
+        let _active_sessions = self.active_sessions.lock().unwrap();
+        if !self.draining.swap(true, Ordering::Relaxed) {
+            info!("draining page service connections");
+        }
+    }
+
+    pub fn resume(&self) {
+        let _active_sessions = self.active_sessions.lock().unwrap();
+        if self.draining.swap(false, Ordering::Relaxed) {
+            info!("accepting page service connections again");
+        }
+    }
+
+    pub fn status(&self) -> DrainStatus {
+        let active_sessions = *self.active_sessions.lock().unwrap();
+        let draining = self.is_draining();
+        DrainStatus {
+            draining,
+            active_sessions,
+            drained: draining && active_sessions == 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drain_refuses_new_sessions() {
+        let drain = PageServiceDrain::default();
+        let session = drain.begin_session().expect("not draining");
+        assert_eq!(drain.status().active_sessions, 1);
+
+        drain.start();
+        assert!(drain.begin_session().is_none());
+        assert_eq!(
+            drain.status(),
+            DrainStatus {
+                draining: true,
+                active_sessions: 1,
+                drained: false,
+            }
+        );
+
+        drop(session);
+        assert!(drain.status().drained);
+
+        drain.resume();
+        assert!(!drain.status().drained);
+        assert!(drain.begin_session().is_some());
+    }
+}
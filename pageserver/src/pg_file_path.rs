@@ -0,0 +1,292 @@
+//!
+//! Mapping between the keys of the pageserver and the files of a PostgreSQL
+//! data directory, for debugging.
+//!
+//! The relation blocks, SLRU pages and other files of the data directory are
+//! stored under [`Key`]s, see the key layout in [`crate::pgdatadir_mapping`].
+//! [`key_to_pg_file`] tells which file, and which block of it, a key stands
+//! for, like block 17 of `base/13008/16384_fsm.2`, so that layer dumps and
+//! diagnostics can be correlated with the familiar file names. In the other
+//! direction, [`pg_file_key_range`] gives the range of keys that hold the
+//! contents of a file.
+//!
+//! Files of user defined tablespaces are named without the tablespace version
+//! directory, `pg_tblspc/<spcnode>/<dbnode>/<relnode>`. They are not supported
+//! by the pageserver anyway.
+//!
+use std::fmt;
+use std::ops::Range;
+
+use anyhow::{anyhow, bail, Result};
+use postgres_ffi::relfile_utils::{forknumber_to_name, parse_relfilename};
+use postgres_ffi::{pg_constants, Oid, TransactionId};
+
+use crate::pgdatadir_mapping::{
+    rel_block_to_key, relmap_file_key, slru_block_to_key, twophase_key_range, BlockNumber,
+    CHECKPOINT_KEY, CONTROLFILE_KEY,
+};
+use crate::reltag::{RelTag, SlruKind};
+use crate::repository::Key;
+
+const RELMAP_FILE_NAME: &str = "pg_filenode.map";
+const CONTROL_FILE_PATH: &str = "global/pg_control";
+const TWOPHASE_DIR: &str = "pg_twophase";
+
+/// A file of the data directory, or a block of it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PgFileLocation {
+    /// Path of the file, relative to the data directory.
+    pub path: String,
+    /// Block number in the file, for the keys of relation and SLRU blocks.
+    pub blknum: Option<BlockNumber>,
+}
+
+impl fmt::Display for PgFileLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.blknum {
+            Some(blknum) => write!(f, "{} block {}", self.path, blknum),
+            None => write!(f, "{}", self.path),
+        }
+    }
+}
+
+/// Directory of the files of a database in a tablespace.
+pub fn db_dir_path(spcnode: Oid, dbnode: Oid) -> String {
+    match spcnode {
+        pg_constants::GLOBALTABLESPACE_OID => "global".to_string(),
+        pg_constants::DEFAULTTABLESPACE_OID => format!("base/{dbnode}"),
+        _ => format!("pg_tblspc/{spcnode}/{dbnode}"),
+    }
+}
+
+/// Path of a segment file of a relation fork, like `base/13008/16384_fsm.2`.
+/// See `relpath()` and `_mdfd_segpath()` in the PostgreSQL sources.
+pub fn rel_segment_path(rel: &RelTag, segno: u32) -> String {
+    let mut path = format!("{}/{}", db_dir_path(rel.spcnode, rel.dbnode), rel.relnode);
+    if let Some(forkname) = forknumber_to_name(rel.forknum) {
+        path = format!("{path}_{forkname}");
+    }
+    if segno > 0 {
+        path = format!("{path}.{segno}");
+    }
+    path
+}
+
+pub fn slru_segment_path(kind: SlruKind, segno: u32) -> String {
+    format!("{}/{:>04X}", kind.to_str(), segno)
+}
+
+pub fn twophase_file_path(xid: TransactionId) -> String {
+    format!("{TWOPHASE_DIR}/{xid:>08X}")
+}
+
+///
+/// The file, or the block of a file, in the data directory that the key holds.
+/// Returns `None` for the keys of the pageserver's own metadata, like the lists
+/// of the relations, and for the data that isn't stored in a file of its own.
+///
+pub fn key_to_pg_file(key: &Key) -> Option<PgFileLocation> {
+    let file = |path: String| Some(PgFileLocation { path, blknum: None });
+    match key.field1 {
+        // Relations. relnode 0 is used for the per-database metadata.
+        0x00 if key.field2 == 0 => None,
+        0x00 if key.field4 == 0 => match key.field6 {
+            0 => file(format!(
+                "{}/{RELMAP_FILE_NAME}",
+                db_dir_path(key.field2, key.field3)
+            )),
+            _ => None,
+        },
+        0x00 => {
+            let rel = RelTag {
+                spcnode: key.field2,
+                dbnode: key.field3,
+                relnode: key.field4,
+                forknum: key.field5,
+            };
+            match key.field6 {
+                // The size of the relation
+                0xffffffff => file(rel_segment_path(&rel, 0)),
+                blknum => Some(PgFileLocation {
+                    path: rel_segment_path(&rel, blknum / pg_constants::RELSEG_SIZE),
+                    blknum: Some(blknum % pg_constants::RELSEG_SIZE),
+                }),
+            }
+        }
+        // SLRU segments
+        0x01 if key.field3 == 1 => {
+            let kind = match key.field2 {
+                0x00 => SlruKind::Clog,
+                0x01 => SlruKind::MultiXactMembers,
+                0x02 => SlruKind::MultiXactOffsets,
+                _ => return None,
+            };
+            Some(PgFileLocation {
+                path: slru_segment_path(kind, key.field4),
+                blknum: match key.field6 {
+                    0xffffffff => None,
+                    blknum => Some(blknum),
+                },
+            })
+        }
+        // Two-phase state files, xid 0 is the list of them
+        0x02 if key.field5 == 0 && key.field6 != 0 => file(twophase_file_path(key.field6)),
+        // The control file and the checkpoint in it
+        0x03 => file(CONTROL_FILE_PATH.to_string()),
+        _ => None,
+    }
+}
+
+///
+/// The range of keys that hold the contents of a file of the data directory,
+/// given by its path relative to the data directory.
+///
+pub fn pg_file_key_range(path: &str) -> Result<Range<Key>> {
+    let parts = path.trim_start_matches("./").split('/').collect::<Vec<_>>();
+    let parse_oid = |s: &str| {
+        s.parse::<Oid>()
+            .map_err(|_| anyhow!("invalid OID '{s}' in path '{path}'"))
+    };
+    let (spcnode, dbnode, file_name) = match parts[..] {
+        ["global", "pg_control"] => return Ok(CONTROLFILE_KEY..CHECKPOINT_KEY.next()),
+        ["global", file_name] => (pg_constants::GLOBALTABLESPACE_OID, 0, file_name),
+        ["base", dbnode, file_name] => (
+            pg_constants::DEFAULTTABLESPACE_OID,
+            parse_oid(dbnode)?,
+            file_name,
+        ),
+        ["pg_tblspc", spcnode, dbnode, file_name] => {
+            (parse_oid(spcnode)?, parse_oid(dbnode)?, file_name)
+        }
+        ["pg_xact", segment] => return slru_segment_key_range(SlruKind::Clog, segment),
+        ["pg_multixact", "members", segment] => {
+            return slru_segment_key_range(SlruKind::MultiXactMembers, segment)
+        }
+        ["pg_multixact", "offsets", segment] => {
+            return slru_segment_key_range(SlruKind::MultiXactOffsets, segment)
+        }
+        [TWOPHASE_DIR, file_name] => {
+            let xid = TransactionId::from_str_radix(file_name, 16)
+                .map_err(|_| anyhow!("invalid two-phase state file name '{file_name}'"))?;
+            return Ok(twophase_key_range(xid));
+        }
+        _ => bail!("path '{path}' is not a file stored by the pageserver"),
+    };
+
+    if file_name == RELMAP_FILE_NAME {
+        let key = relmap_file_key(spcnode, dbnode);
+        return Ok(key..key.next());
+    }
+    let (relnode, forknum, segno) = parse_relfilename(file_name)
+        .map_err(|e| anyhow!("invalid relation file name '{file_name}': {e}"))?;
+    let rel = RelTag {
+        spcnode,
+        dbnode,
+        relnode,
+        forknum,
+    };
+    // Saturates at the last key of the relation, that holds its size
+    let start = segno.saturating_mul(pg_constants::RELSEG_SIZE);
+    let end = start.saturating_add(pg_constants::RELSEG_SIZE);
+    Ok(rel_block_to_key(rel, start)..rel_block_to_key(rel, end))
+}
+
+fn slru_segment_key_range(kind: SlruKind, segment: &str) -> Result<Range<Key>> {
+    let segno = u32::from_str_radix(segment, 16)
+        .map_err(|_| anyhow!("invalid SLRU segment name '{segment}'"))?;
+    Ok(slru_block_to_key(kind, segno, 0)
+        ..slru_block_to_key(kind, segno, pg_constants::SLRU_PAGES_PER_SEGMENT))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check_roundtrip(key: Key, path: &str, blknum: Option<BlockNumber>) {
+        let location = key_to_pg_file(&key).unwrap();
+        assert_eq!(location.path, path);
+        assert_eq!(location.blknum, blknum);
+        let range = pg_file_key_range(path).unwrap();
+        assert!(
+            range.contains(&key),
+            "{key} is not in the key range {}-{} of {path}",
+            range.start,
+            range.end
+        );
+    }
+
+    #[test]
+    fn relation_files() {
+        let rel = RelTag {
+            spcnode: pg_constants::DEFAULTTABLESPACE_OID,
+            dbnode: 13008,
+            relnode: 16384,
+            forknum: pg_constants::FSM_FORKNUM,
+        };
+        check_roundtrip(rel_block_to_key(rel, 3), "base/13008/16384_fsm", Some(3));
+        check_roundtrip(
+            rel_block_to_key(rel, 2 * pg_constants::RELSEG_SIZE + 17),
+            "base/13008/16384_fsm.2",
+            Some(17),
+        );
+        let range = pg_file_key_range("base/13008/16384_fsm.2").unwrap();
+        assert_eq!(
+            range,
+            rel_block_to_key(rel, 2 * pg_constants::RELSEG_SIZE)
+                ..rel_block_to_key(rel, 3 * pg_constants::RELSEG_SIZE)
+        );
+        assert!(!range.contains(&rel_block_to_key(rel, 3 * pg_constants::RELSEG_SIZE)));
+
+        let catalog = RelTag {
+            spcnode: pg_constants::GLOBALTABLESPACE_OID,
+            dbnode: 0,
+            relnode: 1262,
+            forknum: pg_constants::MAIN_FORKNUM,
+        };
+        check_roundtrip(rel_block_to_key(catalog, 0), "global/1262", Some(0));
+        check_roundtrip(
+            relmap_file_key(pg_constants::DEFAULTTABLESPACE_OID, 13008),
+            "base/13008/pg_filenode.map",
+            None,
+        );
+        check_roundtrip(
+            relmap_file_key(pg_constants::GLOBALTABLESPACE_OID, 0),
+            "global/pg_filenode.map",
+            None,
+        );
+    }
+
+    #[test]
+    fn nonrelation_files() {
+        check_roundtrip(
+            slru_block_to_key(SlruKind::MultiXactMembers, 0x1a, 5),
+            "pg_multixact/members/001A",
+            Some(5),
+        );
+        check_roundtrip(
+            slru_block_to_key(SlruKind::Clog, 0, 31),
+            "pg_xact/0000",
+            Some(31),
+        );
+        check_roundtrip(
+            twophase_key_range(0x2fe).start,
+            "pg_twophase/000002FE",
+            None,
+        );
+        check_roundtrip(CONTROLFILE_KEY, "global/pg_control", None);
+        check_roundtrip(CHECKPOINT_KEY, "global/pg_control", None);
+    }
+
+    #[test]
+    fn invalid_paths() {
+        for path in [
+            "postgresql.conf",
+            "base/abc/16384",
+            "base/13008/16384_xyz",
+            "pg_xact/zzzz",
+            "pg_wal/000000010000000000000001",
+        ] {
+            assert!(pg_file_key_range(path).is_err(), "{path} should be invalid");
+        }
+    }
+}
@@ -7,21 +7,31 @@
 //! Clarify that)
 //!
 use crate::keyspace::{KeyPartitioning, KeySpace, KeySpaceAccum};
+use crate::rel_dir_cache::{RelDirCache, RelDirectoryEntries};
+use crate::rel_size_cache::RelSizeCache;
 use crate::reltag::{RelTag, SlruKind};
 use crate::repository::*;
 use crate::repository::{Repository, Timeline};
+use crate::size_soft_limit::LogicalSizeSoftLimit;
+use crate::walingest::WalRecordStats;
 use crate::walrecord::ZenithWalRecord;
-use anyhow::{bail, ensure, Result};
+use anyhow::{anyhow, bail, ensure, Result};
 use bytes::{Buf, Bytes};
 use postgres_ffi::xlog_utils::TimestampTz;
 use postgres_ffi::{pg_constants, Oid, TransactionId};
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use std::cmp::min;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::ops::Range;
 use std::sync::atomic::{AtomicIsize, Ordering};
 use std::sync::{Arc, Mutex, RwLockReadGuard};
+use std::time::Duration;
 use tracing::{debug, error, trace, warn};
-use utils::{bin_ser::BeSer, lsn::Lsn};
+use utils::{
+    bin_ser::BeSer,
+    lsn::Lsn,
+    seqwait::{CancellationToken, SeqWait, SeqWaitError},
+};
 
 /// Block number within a relation or SLRU. This matches PostgreSQL's BlockNumber type.
 pub type BlockNumber = u32;
@@ -44,6 +54,28 @@ where
 
     /// Current logical size of the "datadir", at the last LSN.
     current_logical_size: AtomicIsize,
+
+    /// WAL records ingested into this timeline, by record type.
+    pub wal_record_stats: WalRecordStats,
+
+    /// Is the timeline over its tenant's logical size soft limit?
+    pub size_soft_limit: LogicalSizeSoftLimit,
+
+    /// Deserialized relation directories, see [`crate::rel_dir_cache`].
+    rel_dir_cache: RelDirCache,
+
+    /// Latest relation sizes, see [`crate::rel_size_cache`].
+    pub rel_size_cache: RelSizeCache,
+
+    /// End of the latest WAL record ingested at a transaction boundary, see
+    /// [`Self::advance_commit_lsn`]. Never ahead of the last record LSN.
+    last_commit_lsn: SeqWait<Lsn, Lsn>,
+
+    /// The transactions, and subtransactions, with records ingested since
+    /// they started that haven't ended yet. Kept here rather than in the
+    /// [`WalIngest`](crate::walingest::WalIngest), so that it survives the
+    /// reconnections of the WAL receiver.
+    pub open_xids: Mutex<HashSet<TransactionId>>,
 }
 
 #[derive(Debug)]
@@ -55,11 +87,59 @@ pub enum LsnForTimestamp {
 
 impl<R: Repository> DatadirTimeline<R> {
     pub fn new(tline: Arc<R::Timeline>, repartition_threshold: u64) -> Self {
+        // Only the WAL up to the disk consistent LSN survives a restart, and the
+        // open transactions are not known from before it: start from there, the
+        // WAL receiver resumes at it.
+        let last_commit_lsn = SeqWait::new(tline.get_disk_consistent_lsn());
         DatadirTimeline {
             tline,
             partitioning: Mutex::new((KeyPartitioning::new(), Lsn(0))),
             current_logical_size: AtomicIsize::new(0),
             repartition_threshold,
+            wal_record_stats: WalRecordStats::default(),
+            size_soft_limit: LogicalSizeSoftLimit::default(),
+            rel_dir_cache: RelDirCache::default(),
+            rel_size_cache: RelSizeCache::default(),
+            last_commit_lsn,
+            open_xids: Mutex::new(HashSet::new()),
+        }
+    }
+
+    pub fn get_last_commit_lsn(&self) -> Lsn {
+        self.last_commit_lsn.load()
+    }
+
+    /// Record that the WAL ingested up to 'lsn' ends at a transaction
+    /// boundary: no transaction that has records before it is still open, see
+    /// [`Self::open_xids`].
+    pub fn advance_commit_lsn(&self, lsn: Lsn) {
+        self.last_commit_lsn.advance(lsn);
+    }
+
+    /// Wait until a transaction boundary at or after 'lsn' is ingested, and
+    /// return the latest boundary.
+    pub fn wait_commit_lsn_cancellable(
+        &self,
+        lsn: Lsn,
+        timeout: Duration,
+        cancel: &CancellationToken,
+    ) -> Result<Lsn> {
+        match self
+            .last_commit_lsn
+            .wait_for_timeout_cancellable(lsn, timeout, cancel)
+        {
+            Ok(()) => Ok(self.get_last_commit_lsn()),
+            Err(SeqWaitError::Cancelled) => Err(anyhow!(
+                "Wait for a transaction boundary at LSN {} was cancelled, last commit LSN {}",
+                lsn,
+                self.get_last_commit_lsn()
+            )),
+            Err(_) => Err(anyhow!(
+                "Timed out waiting for a transaction boundary at LSN {}, last commit LSN {}, last record LSN {}",
+                lsn,
+                self.get_last_commit_lsn(),
+                self.tline.get_last_record_lsn()
+            )),
         }
     }
 
@@ -99,6 +179,7 @@ impl<R: Repository> DatadirTimeline<R> {
             pending_updates: HashMap::new(),
             pending_deletions: Vec::new(),
             pending_nblocks: 0,
+            pending_extension_data_bytes: 0,
         }
     }
 
@@ -139,8 +220,37 @@ impl<R: Repository> DatadirTimeline<R> {
         }
 
         let key = rel_size_to_key(tag);
+        if let Some(nblocks) = self.rel_size_cache.lookup(&key, lsn) {
+            return Ok(nblocks);
+        }
+
+        // As in get_rel_dir, the size read is valid as of the last record LSN
+        // taken before the read.
+        let last_record_lsn = self.tline.get_last_record_lsn();
         let mut buf = self.tline.get(key, lsn)?;
-        Ok(buf.get_u32_le())
+        let nblocks = buf.get_u32_le();
+        self.rel_size_cache
+            .memorize(key, lsn.min(last_record_lsn), nblocks);
+        Ok(nblocks)
+    }
+
+    /// Iterate through all the pages of a relation at given LSN, in block
+    /// number order.
+    ///
+    /// The pages are reconstructed in batches, searching the layers once per
+    /// batch rather than once per page. GC is held off while a batch is read,
+    /// and if it has moved the cutoff past 'lsn' in between, the iteration
+    /// fails instead of returning pages that might be incomplete.
+    pub fn rel_pages(&self, tag: RelTag, lsn: Lsn) -> Result<RelPagesIter<R>> {
+        let nblocks = self.get_rel_size(tag, lsn)?;
+        Ok(RelPagesIter {
+            timeline: self,
+            tag,
+            lsn,
+            nblocks,
+            next_blknum: 0,
+            batch: VecDeque::new(),
+        })
     }
 
     /// Does relation exist?
@@ -148,11 +258,9 @@ impl<R: Repository> DatadirTimeline<R> {
         ensure!(tag.relnode != 0, "invalid relnode");
 
         // fetch directory listing
-        let key = rel_dir_to_key(tag.spcnode, tag.dbnode);
-        let buf = self.tline.get(key, lsn)?;
-        let dir = RelDirectory::des(&buf)?;
+        let rels = self.get_rel_dir(tag.spcnode, tag.dbnode, lsn)?;
 
-        let exists = dir.rels.get(&(tag.relnode, tag.forknum)).is_some();
+        let exists = rels.contains(&(tag.relnode, tag.forknum));
 
         Ok(exists)
     }
@@ -160,12 +268,10 @@ impl<R: Repository> DatadirTimeline<R> {
     /// Get a list of all existing relations in given tablespace and database.
     pub fn list_rels(&self, spcnode: Oid, dbnode: Oid, lsn: Lsn) -> Result<HashSet<RelTag>> {
         // fetch directory listing
-        let key = rel_dir_to_key(spcnode, dbnode);
-        let buf = self.tline.get(key, lsn)?;
-        let dir = RelDirectory::des(&buf)?;
+        let dir_rels = self.get_rel_dir(spcnode, dbnode, lsn)?;
 
         let rels: HashSet<RelTag> =
-            HashSet::from_iter(dir.rels.iter().map(|(relnode, forknum)| RelTag {
+            HashSet::from_iter(dir_rels.iter().map(|(relnode, forknum)| RelTag {
                 spcnode,
                 dbnode,
                 relnode: *relnode,
@@ -175,6 +281,24 @@ impl<R: Repository> DatadirTimeline<R> {
         Ok(rels)
     }
 
+    /// Get the relations of given tablespace and database, from the cache if possible.
+    fn get_rel_dir(&self, spcnode: Oid, dbnode: Oid, lsn: Lsn) -> Result<Arc<RelDirectoryEntries>> {
+        let key = rel_dir_to_key(spcnode, dbnode);
+        if let Some(rels) = self.rel_dir_cache.lookup(&key, lsn) {
+            return Ok(rels);
+        }
+
+        // WAL ingestion reads at the LSN of the record being ingested, past the
+        // last record LSN. The directory read is valid as of the last record LSN,
+        // taken before the read.
+        let last_record_lsn = self.tline.get_last_record_lsn();
+        let buf = self.tline.get(key, lsn)?;
+        let rels = Arc::new(RelDirectory::des(&buf)?.rels);
+        self.rel_dir_cache
+            .memorize(key, lsn.min(last_record_lsn), &rels);
+        Ok(rels)
+    }
+
     /// Look up given SLRU page version.
     pub fn get_slru_page_at_lsn(
         &self,
@@ -358,6 +482,67 @@ impl<R: Repository> DatadirTimeline<R> {
         self.tline.get(CHECKPOINT_KEY, lsn)
     }
 
+    /// Get an auxiliary file at the given LSN, None if it doesn't exist.
+    pub fn get_aux_file(&self, path: &str, lsn: Lsn) -> Result<Option<Bytes>> {
+        Ok(self.list_aux_files(lsn)?.remove(path))
+    }
+
+    /// Get all the auxiliary files at the given LSN, by path.
+    pub fn list_aux_files(&self, lsn: Lsn) -> Result<HashMap<String, Bytes>> {
+        match self.tline.get(AUX_FILES_KEY, lsn) {
+            Ok(buf) => Ok(AuxFilesDirectory::des(&buf)?.files),
+            Err(e) => {
+                // Expected for timelines created before auxiliary files were
+                // stored, they don't have the key until the first file is put.
+                debug!("could not read auxiliary files at {}: {:#}", lsn, e);
+                Ok(HashMap::new())
+            }
+        }
+    }
+
+    /// Get a value of an extension at the given LSN, None if it doesn't exist.
+    pub fn get_extension_data(&self, namespace: &str, key: u64, lsn: Lsn) -> Result<Option<Bytes>> {
+        let dir = self.get_extension_data_dir(lsn)?;
+        match dir.namespaces.get(namespace) {
+            Some(&nsid) if dir.values.contains_key(&(nsid, key)) => {
+                Ok(Some(self.tline.get(extension_data_key(nsid, key), lsn)?))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// List the values of the extensions at the given LSN, as (namespace, key).
+    pub fn list_extension_data(&self, lsn: Lsn) -> Result<Vec<(String, u64)>> {
+        let dir = self.get_extension_data_dir(lsn)?;
+        let mut result = dir
+            .namespaces
+            .iter()
+            .flat_map(|(namespace, &nsid)| {
+                dir.values
+                    .keys()
+                    .filter(move |(id, _)| *id == nsid)
+                    .map(move |(_, key)| (namespace.clone(), *key))
+            })
+            .collect::<Vec<_>>();
+        result.sort_unstable();
+        Ok(result)
+    }
+
+    fn get_extension_data_dir(&self, lsn: Lsn) -> Result<ExtensionDataDirectory> {
+        match self.tline.get(EXTENSION_DATA_DIR_KEY, lsn) {
+            Ok(buf) => Ok(ExtensionDataDirectory::des(&buf)?),
+            Err(e) => {
+                // Expected for timelines created before extension data was
+                // stored, like with the auxiliary files
+                debug!(
+                    "could not read extension data directory at {}: {:#}",
+                    lsn, e
+                );
+                Ok(ExtensionDataDirectory::default())
+            }
+        }
+    }
+
     /// Get the LSN of the last ingested WAL record.
     ///
     /// This is just a convenience wrapper that calls through to the underlying
@@ -366,7 +551,7 @@ impl<R: Repository> DatadirTimeline<R> {
         self.tline.get_last_record_lsn()
     }
 
-    /// Check that it is valid to request operations with that lsn.
+    /// Check that it is valid to read at that lsn.
     ///
     /// This is just a convenience wrapper that calls through to the underlying
     /// repository.
@@ -375,7 +560,9 @@ impl<R: Repository> DatadirTimeline<R> {
         lsn: Lsn,
         latest_gc_cutoff_lsn: &RwLockReadGuard<Lsn>,
     ) -> Result<()> {
-        self.tline.check_lsn_is_in_scope(lsn, latest_gc_cutoff_lsn)
+        self.tline
+            .check_lsn_is_in_scope(lsn, latest_gc_cutoff_lsn)?;
+        self.tline.check_read_floor(lsn)
     }
 
     /// Retrieve current logical size of the timeline
@@ -398,8 +585,8 @@ impl<R: Repository> DatadirTimeline<R> {
     /// Does the same as get_current_logical_size but counted on demand.
     /// Used to initialize the logical size tracking on startup.
     ///
-    /// Only relation blocks are counted currently. That excludes metadata,
-    /// SLRUs, twophase files etc.
+    /// Only relation blocks and extension data are counted currently. That
+    /// excludes metadata, SLRUs, twophase files etc.
     pub fn get_current_logical_size_non_incremental(&self, lsn: Lsn) -> Result<usize> {
         // Fetch list of database dirs and iterate them
         let buf = self.tline.get(DBDIR_KEY, lsn)?;
@@ -415,14 +602,20 @@ impl<R: Repository> DatadirTimeline<R> {
                 total_size += relsize as usize;
             }
         }
-        Ok(total_size * pg_constants::BLCKSZ as usize)
+        let extension_data_size: usize = self
+            .get_extension_data_dir(lsn)?
+            .values
+            .values()
+            .map(|size| *size as usize)
+            .sum();
+        Ok(total_size * pg_constants::BLCKSZ as usize + extension_data_size)
     }
 
     ///
     /// Get a KeySpace that covers all the Keys that are in use at the given LSN.
     /// Anything that's not listed maybe removed from the underlying storage (from
     /// that LSN forwards).
-    fn collect_keyspace(&self, lsn: Lsn) -> Result<KeySpace> {
+    pub fn collect_keyspace(&self, lsn: Lsn) -> Result<KeySpace> {
         // Iterate through key ranges, greedily packing them into partitions
         let mut result = KeySpaceAccum::new();
 
@@ -491,10 +684,47 @@ impl<R: Repository> DatadirTimeline<R> {
 
         result.add_key(CONTROLFILE_KEY);
         result.add_key(CHECKPOINT_KEY);
+        if self.tline.get(AUX_FILES_KEY, lsn).is_ok() {
+            result.add_key(AUX_FILES_KEY);
+        }
+
+        if let Ok(buf) = self.tline.get(EXTENSION_DATA_DIR_KEY, lsn) {
+            result.add_key(EXTENSION_DATA_DIR_KEY);
+            let dir = ExtensionDataDirectory::des(&buf)?;
+            let mut values: Vec<(u32, u64)> = dir.values.keys().cloned().collect();
+            values.sort_unstable();
+            for (nsid, key) in values {
+                result.add_key(extension_data_key(nsid, key));
+            }
+        }
 
         Ok(result.to_keyspace())
     }
 
+    ///
+    /// Get the Keys of each of the given relations at the given LSN, one KeySpace
+    /// per relation, in the order of the relations.
+    ///
+    pub fn collect_rel_keyspaces(&self, rels: &[RelTag], lsn: Lsn) -> Result<Vec<KeySpace>> {
+        let mut keyspaces = Vec::with_capacity(rels.len());
+        for &rel in rels {
+            ensure!(
+                self.get_rel_exists(rel, lsn)?,
+                "relation {} does not exist at {}",
+                rel,
+                lsn
+            );
+            let mut keyspace = KeySpaceAccum::new();
+            let nblocks = self.get_rel_size(rel, lsn)?;
+            if nblocks > 0 {
+                keyspace.add_range(rel_block_to_key(rel, 0)..rel_block_to_key(rel, nblocks));
+            }
+            keyspace.add_key(rel_size_to_key(rel));
+            keyspaces.push(keyspace.to_keyspace());
+        }
+        Ok(keyspaces)
+    }
+
     pub fn repartition(&self, lsn: Lsn, partition_size: u64) -> Result<(KeyPartitioning, Lsn)> {
         let mut partitioning_guard = self.partitioning.lock().unwrap();
         if partitioning_guard.1 == Lsn(0)
@@ -509,6 +739,55 @@ impl<R: Repository> DatadirTimeline<R> {
     }
 }
 
+/// Number of pages that RelPagesIter reconstructs at a time.
+const REL_PAGES_BATCH_SIZE: u32 = 256;
+
+/// Iterator over the pages of a relation, see [`DatadirTimeline::rel_pages`].
+pub struct RelPagesIter<'a, R: Repository> {
+    timeline: &'a DatadirTimeline<R>,
+    tag: RelTag,
+    lsn: Lsn,
+    nblocks: BlockNumber,
+    next_blknum: BlockNumber,
+    batch: VecDeque<(BlockNumber, Bytes)>,
+}
+
+impl<'a, R: Repository> RelPagesIter<'a, R> {
+    fn read_batch(&mut self) -> Result<()> {
+        let tline = &self.timeline.tline;
+        let latest_gc_cutoff_lsn = tline.get_latest_gc_cutoff_lsn();
+        self.timeline
+            .check_lsn_is_in_scope(self.lsn, &latest_gc_cutoff_lsn)?;
+
+        let end_blknum = min(
+            self.next_blknum.saturating_add(REL_PAGES_BATCH_SIZE),
+            self.nblocks,
+        );
+        let key_range =
+            rel_block_to_key(self.tag, self.next_blknum)..rel_block_to_key(self.tag, end_blknum);
+        for (key, img) in tline.get_range(key_range, self.lsn)? {
+            self.batch.push_back((key.field6, img));
+        }
+        self.next_blknum = end_blknum;
+        Ok(())
+    }
+}
+
+impl<'a, R: Repository> Iterator for RelPagesIter<'a, R> {
+    type Item = Result<(BlockNumber, Bytes)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.batch.is_empty() && self.next_blknum < self.nblocks {
+            if let Err(e) = self.read_batch() {
+                // Don't continue past a batch that couldn't be read
+                self.next_blknum = self.nblocks;
+                return Some(Err(e));
+            }
+        }
+        self.batch.pop_front().map(Ok)
+    }
+}
+
 /// DatadirModification represents an operation to ingest an atomic set of
 /// updates to the repository. It is created by the 'begin_record'
 /// function. It is called for each WAL record, so that all the modifications
@@ -527,6 +806,7 @@ pub struct DatadirModification<'a, R: Repository> {
     pending_updates: HashMap<Key, Value>,
     pending_deletions: Vec<Range<Key>>,
     pending_nblocks: isize,
+    pending_extension_data_bytes: isize,
 }
 
 impl<'a, R: Repository> DatadirModification<'a, R> {
@@ -554,6 +834,12 @@ impl<'a, R: Repository> DatadirModification<'a, R> {
         );
         self.put(slru_dir_to_key(SlruKind::MultiXactOffsets), empty_dir);
 
+        let buf = AuxFilesDirectory::ser(&AuxFilesDirectory::default())?;
+        self.put(AUX_FILES_KEY, Value::Image(buf.into()));
+
+        let buf = ExtensionDataDirectory::ser(&ExtensionDataDirectory::default())?;
+        self.put(EXTENSION_DATA_DIR_KEY, Value::Image(buf.into()));
+
         Ok(())
     }
 
@@ -666,6 +952,123 @@ impl<'a, R: Repository> DatadirModification<'a, R> {
         Ok(())
     }
 
+    /// Store an auxiliary file, replacing the previous version if it exists.
+    ///
+    /// Auxiliary files are small files in the data directory that are not
+    /// covered by the other key ranges, like the logical replication mappings
+    /// under pg_logical/ and the replication origin state. The path is relative
+    /// to the data directory.
+    pub fn put_aux_file(&mut self, path: &str, content: Bytes) -> Result<()> {
+        ensure!(
+            !path.is_empty()
+                && !path.starts_with('/')
+                && !path.split('/').any(|part| part.is_empty() || part == ".."),
+            "invalid auxiliary file path '{}'",
+            path
+        );
+        let mut dir = self.get_aux_files_dir()?;
+        dir.files.insert(path.to_string(), content);
+        self.put(
+            AUX_FILES_KEY,
+            Value::Image(Bytes::from(AuxFilesDirectory::ser(&dir)?)),
+        );
+        Ok(())
+    }
+
+    /// Remove an auxiliary file.
+    pub fn delete_aux_file(&mut self, path: &str) -> Result<()> {
+        let mut dir = self.get_aux_files_dir()?;
+        if dir.files.remove(path).is_none() {
+            warn!("auxiliary file '{}' does not exist", path);
+            return Ok(());
+        }
+        self.put(
+            AUX_FILES_KEY,
+            Value::Image(Bytes::from(AuxFilesDirectory::ser(&dir)?)),
+        );
+        Ok(())
+    }
+
+    fn get_aux_files_dir(&self) -> Result<AuxFilesDirectory> {
+        match self.get(AUX_FILES_KEY) {
+            Ok(buf) => Ok(AuxFilesDirectory::des(&buf)?),
+            Err(e) => {
+                // Not created yet, see DatadirTimeline::list_aux_files
+                debug!("could not read auxiliary files: {:#}", e);
+                Ok(AuxFilesDirectory::default())
+            }
+        }
+    }
+
+    /// Store a value of an extension, replacing the previous version if it exists.
+    ///
+    /// Extensions that keep data outside of the relations, in custom files or
+    /// forks, store it in the page server as values identified by a namespace,
+    /// the name of the extension, and a key within it. The values count towards
+    /// the logical size of the timeline.
+    pub fn put_extension_data(&mut self, namespace: &str, key: u64, value: Bytes) -> Result<()> {
+        ensure!(
+            !namespace.is_empty()
+                && namespace.len() <= MAX_EXTENSION_NAMESPACE_LEN
+                && namespace
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'_'),
+            "invalid extension data namespace '{}'",
+            namespace
+        );
+        let mut dir = self.get_extension_data_dir()?;
+        let next_nsid = dir.namespaces.values().max().map_or(1, |nsid| nsid + 1);
+        let nsid = *dir
+            .namespaces
+            .entry(namespace.to_string())
+            .or_insert(next_nsid);
+        let old_size = dir.values.insert((nsid, key), value.len() as u32);
+        self.pending_extension_data_bytes += value.len() as isize - old_size.unwrap_or(0) as isize;
+
+        self.put(
+            EXTENSION_DATA_DIR_KEY,
+            Value::Image(Bytes::from(ExtensionDataDirectory::ser(&dir)?)),
+        );
+        self.put(extension_data_key(nsid, key), Value::Image(value));
+        Ok(())
+    }
+
+    /// Remove a value of an extension.
+    pub fn delete_extension_data(&mut self, namespace: &str, key: u64) -> Result<()> {
+        let mut dir = self.get_extension_data_dir()?;
+        let removed = dir
+            .namespaces
+            .get(namespace)
+            .and_then(|&nsid| dir.values.remove(&(nsid, key)).map(|size| (nsid, size)));
+        let (nsid, size) = match removed {
+            Some(removed) => removed,
+            None => {
+                warn!("extension data {}/{} does not exist", namespace, key);
+                return Ok(());
+            }
+        };
+        self.pending_extension_data_bytes -= size as isize;
+
+        self.put(
+            EXTENSION_DATA_DIR_KEY,
+            Value::Image(Bytes::from(ExtensionDataDirectory::ser(&dir)?)),
+        );
+        let key = extension_data_key(nsid, key);
+        self.delete(key..key.next());
+        Ok(())
+    }
+
+    fn get_extension_data_dir(&self) -> Result<ExtensionDataDirectory> {
+        match self.get(EXTENSION_DATA_DIR_KEY) {
+            Ok(buf) => Ok(ExtensionDataDirectory::des(&buf)?),
+            Err(e) => {
+                // Not created yet, see DatadirTimeline::get_extension_data_dir
+                debug!("could not read extension data directory: {:#}", e);
+                Ok(ExtensionDataDirectory::default())
+            }
+        }
+    }
+
     pub fn drop_dbdir(&mut self, spcnode: Oid, dbnode: Oid) -> Result<()> {
         // Remove entry from dbdir
         let buf = self.get(DBDIR_KEY)?;
@@ -692,6 +1095,7 @@ impl<'a, R: Repository> DatadirModification<'a, R> {
     /// 'nblocks' is the initial size.
     pub fn put_rel_creation(&mut self, rel: RelTag, nblocks: BlockNumber) -> Result<()> {
         ensure!(rel.relnode != 0, "invalid relnode");
+        self.record_size_growth();
         // It's possible that this is the first rel for this db in this
         // tablespace.  Create the reldir entry for it if so.
         let mut dbdir = DbDirectory::des(&self.get(DBDIR_KEY)?)?;
@@ -755,6 +1159,9 @@ impl<'a, R: Repository> DatadirModification<'a, R> {
         // Put size
         let size_key = rel_size_to_key(rel);
         let old_size = self.get(size_key)?.get_u32_le();
+        if nblocks > old_size {
+            self.record_size_growth();
+        }
 
         let buf = nblocks.to_le_bytes();
         self.put(size_key, Value::Image(Bytes::from(buf.to_vec())));
@@ -884,11 +1291,40 @@ impl<'a, R: Repository> DatadirModification<'a, R> {
     /// underlying timeline.
     ///
     pub fn commit(self) -> Result<()> {
+        self.check_rels_exist()?;
+
         let writer = self.tline.tline.writer();
 
-        let pending_nblocks = self.pending_nblocks;
+        let pending_size = self.pending_nblocks * pg_constants::BLCKSZ as isize
+            + self.pending_extension_data_bytes;
 
-        for (key, value) in self.pending_updates {
+        let rel_dir_cache = &self.tline.rel_dir_cache;
+        let rel_size_cache = &self.tline.rel_size_cache;
+        for key_range in &self.pending_deletions {
+            rel_dir_cache.invalidate_range(key_range, self.lsn);
+            rel_size_cache.invalidate_range(key_range, self.lsn);
+        }
+        for (key, value) in &self.pending_updates {
+            if is_rel_dir_key(key) {
+                rel_dir_cache.invalidate(key, self.lsn);
+            } else if is_rel_size_key(key) {
+                // Keep the cache up to date, so that the compute's size requests
+                // don't need to look in the layers
+                if let Value::Image(buf) = value {
+                    rel_size_cache.update(*key, self.lsn, (&buf[..]).get_u32_le());
+                }
+            }
+        }
+
+        // Write the metadata, like relation directories and sizes, before
+        // the page data. If writing fails midway, the open layer may be left
+        // with a part of the updates, and they must not include pages of a
+        // relation without its creation.
+        let (pages, metadata): (Vec<_>, Vec<_>) = self
+            .pending_updates
+            .into_iter()
+            .partition(|(key, _)| is_rel_block_key(key));
+        for (key, value) in metadata.into_iter().chain(pages) {
             writer.put(key, self.lsn, value)?;
         }
         for key_range in self.pending_deletions {
@@ -897,16 +1333,59 @@ impl<'a, R: Repository> DatadirModification<'a, R> {
 
         writer.finish_write(self.lsn);
 
-        if pending_nblocks != 0 {
-            self.tline.current_logical_size.fetch_add(
-                pending_nblocks * pg_constants::BLCKSZ as isize,
-                Ordering::SeqCst,
+        if pending_size != 0 {
+            let new_size = self
+                .tline
+                .current_logical_size
+                .fetch_add(pending_size, Ordering::SeqCst)
+                + pending_size;
+            // Report the timeline back under the soft limit as soon as it is
+            self.tline.size_soft_limit.update(
+                new_size.max(0) as u64,
+                self.tline.tline.get_logical_size_soft_limit(),
             );
         }
 
         Ok(())
     }
 
+    ///
+    /// Check that all the relations that get pages in this modification exist,
+    /// or are created in it. Relations are created implicitly on the first
+    /// write, based on the cached relation sizes in WalIngest, which must never
+    /// lead to pages without a creation record.
+    ///
+    fn check_rels_exist(&self) -> Result<()> {
+        let last_lsn = self.tline.get_last_record_lsn();
+        let mut checked = HashSet::new();
+        for key in self
+            .pending_updates
+            .keys()
+            .filter(|key| is_rel_block_key(key))
+        {
+            let (rel, _) = key_to_rel_block(*key)?;
+            if !checked.insert(rel) || self.pending_updates.contains_key(&rel_size_to_key(rel)) {
+                continue;
+            }
+            ensure!(
+                self.tline.get_rel_exists(rel, last_lsn)?,
+                "pages of relation {} written without its creation",
+                rel
+            );
+        }
+        Ok(())
+    }
+
+    /// Account for a relation growing, in the timeline's logical size soft limit.
+    fn record_size_growth(&self) {
+        let current_size = self.tline.current_logical_size.load(Ordering::Acquire)
+            + self.pending_nblocks * pg_constants::BLCKSZ as isize;
+        self.tline.size_soft_limit.record_growth(
+            current_size.max(0) as u64,
+            self.tline.tline.get_logical_size_soft_limit(),
+        );
+    }
+
     // Internal helper functions to batch the modifications
 
     fn get(&self, key: Key) -> Result<Bytes> {
@@ -960,8 +1439,10 @@ struct RelDirectory {
     // Set of relations that exist. (relfilenode, forknum)
     //
     // TODO: Store it as a btree or radix tree or something else that spans multiple
-    // key-value pairs, if you have a lot of relations
-    rels: HashSet<(Oid, u8)>,
+    // key-value pairs, if you have a lot of relations. Reads are served from the
+    // deserialized copy in the rel_dir_cache, but creating or dropping a relation
+    // still rewrites the whole set.
+    rels: RelDirectoryEntries,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -975,7 +1456,25 @@ struct SlruSegmentDirectory {
     segments: HashSet<u32>,
 }
 
-static ZERO_PAGE: Bytes = Bytes::from_static(&[0u8; pg_constants::BLCKSZ as usize]);
+#[derive(Debug, Serialize, Deserialize, Default)]
+struct AuxFilesDirectory {
+    // Path relative to the data directory -> file contents. The files are
+    // small, so all of them are stored in one value.
+    files: HashMap<String, Bytes>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+struct ExtensionDataDirectory {
+    // Namespace -> its id in the keys of its values. Ids are never reused.
+    namespaces: HashMap<String, u32>,
+    // (namespace id, key) -> size of the value
+    values: HashMap<(u32, u64), u32>,
+}
+
+/// Longest extension data namespace, as the longest name in Postgres.
+const MAX_EXTENSION_NAMESPACE_LEN: usize = 63;
+
+pub static ZERO_PAGE: Bytes = Bytes::from_static(&[0u8; pg_constants::BLCKSZ as usize]);
 
 // Layout of the Key address space
 //
@@ -1015,6 +1514,10 @@ static ZERO_PAGE: Bytes = Bytes::from_static(&[0u8; pg_constants::BLCKSZ as usiz
 //    controlfile
 //    checkpoint
 //
+// 04 auxiliary files
+//
+// 05 extension data
+//
 // Below is a full list of the keyspace allocation:
 //
 // DbDir:
@@ -1052,6 +1555,15 @@ static ZERO_PAGE: Bytes = Bytes::from_static(&[0u8; pg_constants::BLCKSZ as usiz
 //
 // Checkpoint:
 // 03 00000000 00000000 00000000 00   00000001
+//
+// AuxFiles:
+// 04 00000000 00000000 00000000 00   00000000
+//
+// ExtensionDataDir:
+// 05 00000000 00000000 00000000 00   00000000
+//
+// ExtensionData:
+// 05 NSID     KEY_HI   KEY_LO   00   00000001
 
 //-- Section 01: relation data and metadata
 
@@ -1082,7 +1594,7 @@ fn dbdir_key_range(spcnode: Oid, dbnode: Oid) -> Range<Key> {
     }
 }
 
-fn relmap_file_key(spcnode: Oid, dbnode: Oid) -> Key {
+pub(crate) fn relmap_file_key(spcnode: Oid, dbnode: Oid) -> Key {
     Key {
         field1: 0x00,
         field2: spcnode,
@@ -1104,7 +1616,11 @@ fn rel_dir_to_key(spcnode: Oid, dbnode: Oid) -> Key {
     }
 }
 
-fn rel_block_to_key(rel: RelTag, blknum: BlockNumber) -> Key {
+fn is_rel_dir_key(key: &Key) -> bool {
+    key.field1 == 0x00 && key.field4 == 0 && key.field5 == 0 && key.field6 == 1
+}
+
+pub(crate) fn rel_block_to_key(rel: RelTag, blknum: BlockNumber) -> Key {
     Key {
         field1: 0x00,
         field2: rel.spcnode,
@@ -1115,6 +1631,14 @@ fn rel_block_to_key(rel: RelTag, blknum: BlockNumber) -> Key {
     }
 }
 
+pub fn is_rel_block_key(key: &Key) -> bool {
+    key.field1 == 0x00 && key.field4 != 0 && key.field6 != 0xffffffff
+}
+
+fn is_rel_size_key(key: &Key) -> bool {
+    key.field1 == 0x00 && key.field4 != 0 && key.field6 == 0xffffffff
+}
+
 fn rel_size_to_key(rel: RelTag) -> Key {
     Key {
         field1: 0x00,
@@ -1161,7 +1685,7 @@ fn slru_dir_to_key(kind: SlruKind) -> Key {
     }
 }
 
-fn slru_block_to_key(kind: SlruKind, segno: u32, blknum: BlockNumber) -> Key {
+pub(crate) fn slru_block_to_key(kind: SlruKind, segno: u32, blknum: BlockNumber) -> Key {
     Key {
         field1: 0x01,
         field2: match kind {
@@ -1237,7 +1761,7 @@ fn twophase_file_key(xid: TransactionId) -> Key {
     }
 }
 
-fn twophase_key_range(xid: TransactionId) -> Range<Key> {
+pub(crate) fn twophase_key_range(xid: TransactionId) -> Range<Key> {
     let (next_xid, overflowed) = xid.overflowing_add(1);
 
     Key {
@@ -1258,7 +1782,7 @@ fn twophase_key_range(xid: TransactionId) -> Range<Key> {
 }
 
 //-- Section 03: Control file
-const CONTROLFILE_KEY: Key = Key {
+pub(crate) const CONTROLFILE_KEY: Key = Key {
     field1: 0x03,
     field2: 0,
     field3: 0,
@@ -1267,7 +1791,7 @@ const CONTROLFILE_KEY: Key = Key {
     field6: 0,
 };
 
-const CHECKPOINT_KEY: Key = Key {
+pub(crate) const CHECKPOINT_KEY: Key = Key {
     field1: 0x03,
     field2: 0,
     field3: 0,
@@ -1276,6 +1800,37 @@ const CHECKPOINT_KEY: Key = Key {
     field6: 1,
 };
 
+//-- Section 04: Auxiliary files
+const AUX_FILES_KEY: Key = Key {
+    field1: 0x04,
+    field2: 0,
+    field3: 0,
+    field4: 0,
+    field5: 0,
+    field6: 0,
+};
+
+//-- Section 05: Extension data
+const EXTENSION_DATA_DIR_KEY: Key = Key {
+    field1: 0x05,
+    field2: 0,
+    field3: 0,
+    field4: 0,
+    field5: 0,
+    field6: 0,
+};
+
+fn extension_data_key(nsid: u32, key: u64) -> Key {
+    Key {
+        field1: 0x05,
+        field2: nsid,
+        field3: (key >> 32) as u32,
+        field4: key as u32,
+        field5: 0,
+        field6: 1,
+    }
+}
+
 // Reverse mappings for a few Keys.
 // These are needed by WAL redo manager.
 
@@ -1294,6 +1849,31 @@ pub fn key_to_rel_block(key: Key) -> Result<(RelTag, BlockNumber)> {
     })
 }
 
+/// If all the keys in the range belong to a single relation fork, returns it.
+pub fn key_range_to_rel(range: &Range<Key>) -> Option<RelTag> {
+    let (rel, _) = key_to_rel_block(range.start).ok()?;
+    // relnode 0 is used for the per-database metadata, not for relations
+    if rel.relnode == 0 || range.end > rel_key_range(rel).end {
+        return None;
+    }
+    Some(rel)
+}
+
+/// Blocks of the system catalogs and of the SLRUs, that are read at high rates
+/// near the last record LSN. See [`crate::hot_page_cache`].
+pub fn is_hot_page_key(key: &Key) -> bool {
+    match key.field1 {
+        // relnode 0 is used for the per-database metadata, not for relations
+        0x00 => {
+            key.field4 != 0
+                && key.field4 < pg_constants::FIRST_NORMAL_OBJECT_ID
+                && key.field6 != 0xffffffff
+        }
+        0x01 => key.field3 == 1 && key.field6 != 0xffffffff,
+        _ => false,
+    }
+}
+
 pub fn key_to_slru_block(key: Key) -> Result<(SlruKind, u32, BlockNumber)> {
     Ok(match key.field1 {
         0x01 => {
@@ -0,0 +1,179 @@
+//!
+//! Typed errors of the read path.
+//!
+//! Most errors of the page server are `anyhow` errors, with a chain of context
+//! messages that is good for the logs but that a client can't act on. The
+//! errors that a compute is expected to handle are [`ReadError`]s instead,
+//! attached to the `anyhow` error as its context where they are detected, e.g.
+//! in the layer traversal or in the wait for WAL. They carry the tenant and the
+//! timeline, and [`ReadErrorCode::of`] finds them anywhere in the chain, so
+//! that the page service can send a distinct error code for each kind.
+//!
+use lazy_static::lazy_static;
+use metrics::{register_int_counter_vec, IntCounterVec};
+use utils::{
+    lsn::Lsn,
+    zid::{ZTenantId, ZTimelineId},
+};
+
+lazy_static! {
+    static ref READ_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "pageserver_read_errors_total",
+        "Number of failed page service requests, by error code",
+        &["code"]
+    )
+    .expect("failed to define a metric");
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ReadError {
+    /// The timeline isn't there, or the requested object doesn't exist in it.
+    #[error("{what}, tenant {tenant_id} timeline {timeline_id}")]
+    NotFound {
+        tenant_id: ZTenantId,
+        timeline_id: ZTimelineId,
+        what: String,
+    },
+    /// The requested LSN is ahead of the WAL received, and it didn't arrive in time.
+    #[error("Timed out while waiting for WAL record at LSN {lsn} to arrive, last_record_lsn {last_record_lsn} disk consistent LSN={disk_consistent_lsn}, tenant {tenant_id} timeline {timeline_id}")]
+    AheadOfLastRecord {
+        tenant_id: ZTenantId,
+        timeline_id: ZTimelineId,
+        lsn: Lsn,
+        last_record_lsn: Lsn,
+        disk_consistent_lsn: Lsn,
+    },
+    /// The requested LSN is behind the GC cutoff, its page versions may be gone.
+    #[error("LSN {lsn} is earlier than latest GC horizon {gc_cutoff_lsn} (we might've already garbage collected needed data), tenant {tenant_id} timeline {timeline_id}")]
+    GcCutoffViolation {
+        tenant_id: ZTenantId,
+        timeline_id: ZTimelineId,
+        lsn: Lsn,
+        gc_cutoff_lsn: Lsn,
+    },
+    /// The requested LSN is behind the read floor that the control plane set
+    /// for the timeline, see [`crate::timelines::fence_timeline`].
+    #[error("LSN {lsn} is earlier than the read floor {read_floor_lsn} of the timeline, tenant {tenant_id} timeline {timeline_id}")]
+    BelowReadFloor {
+        tenant_id: ZTenantId,
+        timeline_id: ZTimelineId,
+        lsn: Lsn,
+        read_floor_lsn: Lsn,
+    },
+    /// The timeline is only in the remote storage, and couldn't be downloaded in time.
+    #[error("{what}, tenant {tenant_id} timeline {timeline_id}")]
+    RemoteUnavailable {
+        tenant_id: ZTenantId,
+        timeline_id: ZTimelineId,
+        what: String,
+    },
+    /// The layers don't have the data that they should.
+    #[error("{what}, tenant {tenant_id} timeline {timeline_id}")]
+    Corruption {
+        tenant_id: ZTenantId,
+        timeline_id: ZTimelineId,
+        what: String,
+    },
+}
+
+impl ReadError {
+    pub fn code(&self) -> ReadErrorCode {
+        match self {
+            ReadError::NotFound { .. } => ReadErrorCode::NotFound,
+            ReadError::AheadOfLastRecord { .. } => ReadErrorCode::AheadOfLastRecord,
+            ReadError::GcCutoffViolation { .. } => ReadErrorCode::GcCutoffViolation,
+            ReadError::RemoteUnavailable { .. } => ReadErrorCode::RemoteUnavailable,
+            ReadError::Corruption { .. } => ReadErrorCode::Corruption,
+            ReadError::BelowReadFloor { .. } => ReadErrorCode::BelowReadFloor,
+        }
+    }
+}
+
+/// Error codes of the page service protocol. The values are sent to the
+/// clients and must not change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ReadErrorCode {
+    /// Any error that is not a [`ReadError`].
+    Internal = 0,
+    NotFound = 1,
+    AheadOfLastRecord = 2,
+    GcCutoffViolation = 3,
+    RemoteUnavailable = 4,
+    Corruption = 5,
+    BelowReadFloor = 6,
+}
+
+impl ReadErrorCode {
+    /// The code of the [`ReadError`] in the chain of 'e', if any. Counts the
+    /// error in the metrics: it's called once per failed request.
+    pub fn of(e: &anyhow::Error) -> Self {
+        let code = e
+            .downcast_ref::<ReadError>()
+            .map_or(ReadErrorCode::Internal, ReadError::code);
+        READ_ERRORS.with_label_values(&[code.as_str()]).inc();
+        code
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReadErrorCode::Internal => "internal",
+            ReadErrorCode::NotFound => "not_found",
+            ReadErrorCode::AheadOfLastRecord => "ahead_of_last_record",
+            ReadErrorCode::GcCutoffViolation => "gc_cutoff_violation",
+            ReadErrorCode::RemoteUnavailable => "remote_unavailable",
+            ReadErrorCode::Corruption => "corruption",
+            ReadErrorCode::BelowReadFloor => "below_read_floor",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::{anyhow, Context};
+    use std::str::FromStr;
+
+    #[test]
+    fn read_error_code_in_chain() {
+        let tenant_id = ZTenantId::from_str("11000000000000000000000000000000").unwrap();
+        let timeline_id = ZTimelineId::from_str("22000000000000000000000000000000").unwrap();
+
+        let e = Err::<(), _>(anyhow!("layer traversal"))
+            .context(ReadError::Corruption {
+                tenant_id,
+                timeline_id,
+                what: "could not find data for key".to_string(),
+            })
+            .context("reading page")
+            .unwrap_err();
+        assert_eq!(ReadErrorCode::of(&e), ReadErrorCode::Corruption);
+
+        let e = anyhow::Error::new(ReadError::GcCutoffViolation {
+            tenant_id,
+            timeline_id,
+            lsn: Lsn(0x10),
+            gc_cutoff_lsn: Lsn(0x20),
+        });
+        assert_eq!(ReadErrorCode::of(&e), ReadErrorCode::GcCutoffViolation);
+        assert_eq!(
+            e.to_string(),
+            format!("LSN 0/10 is earlier than latest GC horizon 0/20 (we might've already garbage collected needed data), tenant {tenant_id} timeline {timeline_id}")
+        );
+
+        let e = Err::<(), _>(ReadError::BelowReadFloor {
+            tenant_id,
+            timeline_id,
+            lsn: Lsn(0x10),
+            read_floor_lsn: Lsn(0x20),
+        })
+        .context("invalid basebackup lsn")
+        .unwrap_err();
+        assert_eq!(ReadErrorCode::of(&e), ReadErrorCode::BelowReadFloor);
+
+        assert_eq!(
+            ReadErrorCode::of(&anyhow!("something else")),
+            ReadErrorCode::Internal
+        );
+    }
+}
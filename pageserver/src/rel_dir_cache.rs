@@ -0,0 +1,208 @@
+//!
+//! Cache of the relation directories of a timeline.
+//!
+//! The relations of a database are listed in a single key-value pair, the
+//! relation directory. `get_rel_exists` is called for every relation access of
+//! a compute, and each call used to read and deserialize the whole directory,
+//! which takes a while for databases with thousands of relations. This cache
+//! keeps the deserialized directories, so that checking whether a relation
+//! exists or listing the relations is a lookup in a set.
+//!
+//! A cached directory stays valid the same way as the images of the
+//! [`crate::hot_page_cache`]: a directory read at some LSN is valid for every
+//! later LSN, until WAL that creates or drops a relation touches it. The
+//! timeline invalidates the directory before storing the new version of it.
+//!
+//! Up to [`MAX_CACHED_REL_DIRS`] directories are cached per timeline; when the
+//! cache is full, the least recently used one is evicted. The validity
+//! tracking and the eviction are in [`crate::lsn_cache`].
+//!
+//! The directories are only cached in memory, and deserialized again after a
+//! restart: they are stored in the layers in the same format as before.
+//!
+use std::collections::HashSet;
+use std::ops::Range;
+use std::sync::Arc;
+
+use lazy_static::lazy_static;
+use metrics::{register_int_counter, IntCounter};
+use postgres_ffi::Oid;
+use utils::lsn::Lsn;
+
+use crate::lsn_cache::{Eviction, LsnCache};
+use crate::repository::Key;
+
+/// Maximum number of relation directories cached per timeline. There's one per
+/// database, and a directory of a big database can take megabytes of memory.
+pub const MAX_CACHED_REL_DIRS: usize = 64;
+
+lazy_static! {
+    static ref REL_DIR_CACHE_HITS: IntCounter = register_int_counter!(
+        "pageserver_rel_dir_cache_hits_total",
+        "Number of relation directory reads served from the cache"
+    )
+    .expect("failed to define a metric");
+    static ref REL_DIR_CACHE_MISSES: IntCounter = register_int_counter!(
+        "pageserver_rel_dir_cache_misses_total",
+        "Number of relation directory reads that had to deserialize the directory"
+    )
+    .expect("failed to define a metric");
+    static ref REL_DIR_CACHE_EVICTIONS: IntCounter = register_int_counter!(
+        "pageserver_rel_dir_cache_evictions_total",
+        "Number of relation directories evicted from the cache to make room for others"
+    )
+    .expect("failed to define a metric");
+}
+
+/// Set of the relations of a database: (relfilenode, forknum)
+pub type RelDirectoryEntries = HashSet<(Oid, u8)>;
+
+pub struct RelDirCache {
+    dirs: LsnCache<Arc<RelDirectoryEntries>>,
+}
+
+impl Default for RelDirCache {
+    fn default() -> Self {
+        RelDirCache::new(MAX_CACHED_REL_DIRS)
+    }
+}
+
+impl RelDirCache {
+    pub fn new(capacity: usize) -> Self {
+        RelDirCache {
+            dirs: LsnCache::new(
+                capacity,
+                Eviction::LeastRecentlyUsed,
+                &REL_DIR_CACHE_EVICTIONS,
+            ),
+        }
+    }
+
+    ///
+    /// Get the relation directory stored at 'key' as of 'lsn', if it's cached.
+    ///
+    pub fn lookup(&self, key: &Key, lsn: Lsn) -> Option<Arc<RelDirectoryEntries>> {
+        let cached = self.dirs.lookup(key, lsn);
+        if cached.is_some() {
+            REL_DIR_CACHE_HITS.inc();
+        } else {
+            REL_DIR_CACHE_MISSES.inc();
+        }
+        cached
+    }
+
+    ///
+    /// Remember the relation directory read at 'lsn'. The caller must make sure
+    /// that 'lsn' is not past the last record LSN at the time of the read.
+    /// Does nothing if WAL touched the directory after 'lsn'.
+    ///
+    pub fn memorize(&self, key: Key, lsn: Lsn, rels: &Arc<RelDirectoryEntries>) {
+        self.dirs.memorize(key, lsn, Arc::clone(rels));
+    }
+
+    ///
+    /// Called before storing a new version of the directory at 'lsn'.
+    ///
+    pub fn invalidate(&self, key: &Key, lsn: Lsn) {
+        self.dirs.invalidate(key, lsn);
+    }
+
+    ///
+    /// Called before deleting the key range at 'lsn', e.g. when a database is dropped.
+    ///
+    pub fn invalidate_range(&self, key_range: &Range<Key>, lsn: Lsn) {
+        self.dirs.invalidate_range(key_range, lsn);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Relation directory of database 13010 in the default tablespace
+    const REL_DIR_KEY: Key = Key {
+        field1: 0x00,
+        field2: 1663,
+        field3: 13010,
+        field4: 0,
+        field5: 0,
+        field6: 1,
+    };
+
+    fn rels(relnodes: &[Oid]) -> Arc<RelDirectoryEntries> {
+        Arc::new(relnodes.iter().map(|relnode| (*relnode, 0)).collect())
+    }
+
+    #[test]
+    fn rel_dir_invalidation() {
+        let cache = RelDirCache::default();
+        let old_rels = rels(&[1259, 16384]);
+
+        cache.memorize(REL_DIR_KEY, Lsn(0x20), &old_rels);
+        assert_eq!(
+            cache.lookup(&REL_DIR_KEY, Lsn(0x20)),
+            Some(old_rels.clone())
+        );
+        assert_eq!(
+            cache.lookup(&REL_DIR_KEY, Lsn(0x80)),
+            Some(old_rels.clone())
+        );
+        assert_eq!(cache.lookup(&REL_DIR_KEY, Lsn(0x10)), None);
+
+        // A relation gets created: directories read before that are not accepted
+        cache.invalidate(&REL_DIR_KEY, Lsn(0x90));
+        assert_eq!(cache.lookup(&REL_DIR_KEY, Lsn(0x90)), None);
+        cache.memorize(REL_DIR_KEY, Lsn(0x80), &old_rels);
+        assert_eq!(cache.lookup(&REL_DIR_KEY, Lsn(0x90)), None);
+
+        let new_rels = rels(&[1259, 16384, 16390]);
+        cache.memorize(REL_DIR_KEY, Lsn(0x90), &new_rels);
+        assert_eq!(cache.lookup(&REL_DIR_KEY, Lsn(0xA0)), Some(new_rels));
+
+        // The database gets dropped
+        let db_range = Key {
+            field4: 0,
+            field5: 0,
+            field6: 0,
+            ..REL_DIR_KEY
+        }..Key {
+            field3: 13011,
+            field4: 0,
+            field5: 0,
+            field6: 0,
+            ..REL_DIR_KEY
+        };
+        cache.invalidate_range(&db_range, Lsn(0xB0));
+        assert_eq!(cache.lookup(&REL_DIR_KEY, Lsn(0xB0)), None);
+    }
+
+    #[test]
+    fn rel_dir_cache_bound() {
+        let cache = RelDirCache::new(2);
+        let db_key = |dbnode: Oid| Key {
+            field3: dbnode,
+            ..REL_DIR_KEY
+        };
+
+        cache.memorize(db_key(1), Lsn(0x20), &rels(&[16384]));
+        cache.memorize(db_key(2), Lsn(0x20), &rels(&[16385]));
+        assert!(cache.lookup(&db_key(1), Lsn(0x20)).is_some());
+        cache.memorize(db_key(3), Lsn(0x20), &rels(&[16386]));
+        assert_eq!(cache.lookup(&db_key(2), Lsn(0x20)), None);
+        assert!(cache.lookup(&db_key(1), Lsn(0x20)).is_some());
+        assert!(cache.lookup(&db_key(3), Lsn(0x20)).is_some());
+    }
+
+    #[test]
+    fn rel_dir_untracked_modification() {
+        let cache = RelDirCache::default();
+        let rels = rels(&[1259]);
+
+        // Modified before it got cached, can only be added at a newer LSN
+        cache.invalidate(&REL_DIR_KEY, Lsn(0x30));
+        cache.memorize(REL_DIR_KEY, Lsn(0x20), &rels);
+        assert_eq!(cache.lookup(&REL_DIR_KEY, Lsn(0x30)), None);
+        cache.memorize(REL_DIR_KEY, Lsn(0x30), &rels);
+        assert_eq!(cache.lookup(&REL_DIR_KEY, Lsn(0x30)), Some(rels));
+    }
+}
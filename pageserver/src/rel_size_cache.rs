@@ -0,0 +1,251 @@
+//!
+//! Cache of the relation sizes of a timeline.
+//!
+//! Computes ask for the size of a relation, with smgrnblocks(), much more
+//! often than for any page of it, and each request used to look up the size
+//! key in the layers. This cache keeps the latest size of each relation,
+//! together with the LSN it became valid at, so that a request at that LSN or
+//! any later one is answered without touching the layers.
+//!
+//! Unlike the [`crate::rel_dir_cache`], the cache is kept up to date during
+//! the WAL ingest rather than just invalidated: the timeline stores every new
+//! size in the cache before storing it in the layers, see
+//! [`RelSizeCache::update`]. So the sizes of the relations that are being
+//! written to are always cached, and the others are cached on their first
+//! read. Requests older than the last change of a relation are served from
+//! the layers.
+//!
+//! The cache is split into partitions by relation, each with its own lock, so
+//! that the WAL ingest updating one relation doesn't block the size requests
+//! of the others. The number of relations per timeline is limited by the
+//! `rel_size_cache_size` setting; when a partition is full, its least
+//! recently used relation is evicted. The validity tracking and the eviction
+//! are in [`crate::lsn_cache`].
+//!
+use std::ops::Range;
+
+use lazy_static::lazy_static;
+use metrics::{register_int_counter, IntCounter};
+use utils::lsn::Lsn;
+
+use crate::config::defaults::DEFAULT_REL_SIZE_CACHE_SIZE;
+use crate::lsn_cache::{Eviction, LsnCache};
+use crate::pgdatadir_mapping::BlockNumber;
+use crate::repository::Key;
+
+const REL_SIZE_CACHE_PARTITIONS: usize = 16;
+
+lazy_static! {
+    static ref REL_SIZE_CACHE_HITS: IntCounter = register_int_counter!(
+        "pageserver_rel_size_cache_hits_total",
+        "Number of relation size reads served from the cache"
+    )
+    .expect("failed to define a metric");
+    static ref REL_SIZE_CACHE_MISSES: IntCounter = register_int_counter!(
+        "pageserver_rel_size_cache_misses_total",
+        "Number of relation size reads that had to look in the layers"
+    )
+    .expect("failed to define a metric");
+    static ref REL_SIZE_CACHE_EVICTIONS: IntCounter = register_int_counter!(
+        "pageserver_rel_size_cache_evictions_total",
+        "Number of relation sizes evicted from the cache to make room for others"
+    )
+    .expect("failed to define a metric");
+}
+
+pub struct RelSizeCache {
+    partitions: Vec<LsnCache<BlockNumber>>,
+}
+
+impl Default for RelSizeCache {
+    fn default() -> Self {
+        RelSizeCache::new(DEFAULT_REL_SIZE_CACHE_SIZE)
+    }
+}
+
+impl RelSizeCache {
+    ///
+    /// Create a cache for up to about 'capacity' relations, rounded up to a
+    /// multiple of the number of partitions. 0 disables the cache.
+    ///
+    pub fn new(capacity: usize) -> Self {
+        let partition_capacity =
+            (capacity + REL_SIZE_CACHE_PARTITIONS - 1) / REL_SIZE_CACHE_PARTITIONS;
+        RelSizeCache {
+            partitions: (0..REL_SIZE_CACHE_PARTITIONS)
+                .map(|_| {
+                    LsnCache::new(
+                        partition_capacity,
+                        Eviction::LeastRecentlyUsed,
+                        &REL_SIZE_CACHE_EVICTIONS,
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    fn partition(&self, key: &Key) -> &LsnCache<BlockNumber> {
+        // The forks of a relation go to different partitions, that's fine
+        let hash = (key.field4 as usize).wrapping_add(key.field5 as usize);
+        &self.partitions[hash % REL_SIZE_CACHE_PARTITIONS]
+    }
+
+    ///
+    /// Get the size of the relation stored at 'key' as of 'lsn', if it's cached.
+    ///
+    pub fn lookup(&self, key: &Key, lsn: Lsn) -> Option<BlockNumber> {
+        let partition = self.partition(key);
+        if !partition.is_enabled() {
+            return None;
+        }
+
+        let cached = partition.lookup(key, lsn);
+        if cached.is_some() {
+            REL_SIZE_CACHE_HITS.inc();
+        } else {
+            REL_SIZE_CACHE_MISSES.inc();
+        }
+        cached
+    }
+
+    ///
+    /// Remember the relation size read from the layers at 'lsn'. The caller
+    /// must make sure that 'lsn' is not past the last record LSN at the time
+    /// of the read. Does nothing if WAL touched the size after 'lsn', or if
+    /// the cache already knows the size.
+    ///
+    pub fn memorize(&self, key: Key, lsn: Lsn, nblocks: BlockNumber) {
+        self.partition(&key).memorize(key, lsn, nblocks);
+    }
+
+    ///
+    /// Called by the WAL ingest before storing the new size of the relation
+    /// at 'lsn', on creation, extension and truncation.
+    ///
+    pub fn update(&self, key: Key, lsn: Lsn, nblocks: BlockNumber) {
+        self.partition(&key).update(key, lsn, nblocks);
+    }
+
+    ///
+    /// Called before deleting the key range at 'lsn', e.g. when a relation
+    /// is dropped.
+    ///
+    pub fn invalidate_range(&self, key_range: &Range<Key>, lsn: Lsn) {
+        for partition in &self.partitions {
+            partition.invalidate_range(key_range, lsn);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Size of relation 16384 in database 13010
+    const REL_SIZE_KEY: Key = Key {
+        field1: 0x00,
+        field2: 1663,
+        field3: 13010,
+        field4: 16384,
+        field5: 0,
+        field6: 0xffffffff,
+    };
+
+    #[test]
+    fn rel_size_updates() {
+        let cache = RelSizeCache::default();
+
+        // Read from the layers first
+        assert_eq!(cache.lookup(&REL_SIZE_KEY, Lsn(0x20)), None);
+        cache.memorize(REL_SIZE_KEY, Lsn(0x20), 10);
+        assert_eq!(cache.lookup(&REL_SIZE_KEY, Lsn(0x20)), Some(10));
+        assert_eq!(cache.lookup(&REL_SIZE_KEY, Lsn(0x10)), None);
+
+        // The relation gets extended, older requests go to the layers
+        cache.update(REL_SIZE_KEY, Lsn(0x30), 11);
+        assert_eq!(cache.lookup(&REL_SIZE_KEY, Lsn(0x30)), Some(11));
+        assert_eq!(cache.lookup(&REL_SIZE_KEY, Lsn(0x40)), Some(11));
+        assert_eq!(cache.lookup(&REL_SIZE_KEY, Lsn(0x20)), None);
+        // and what they read doesn't replace the latest size
+        cache.memorize(REL_SIZE_KEY, Lsn(0x20), 10);
+        assert_eq!(cache.lookup(&REL_SIZE_KEY, Lsn(0x40)), Some(11));
+
+        // The relation gets dropped
+        let rel_range = Key {
+            field6: 0,
+            ..REL_SIZE_KEY
+        }..Key {
+            field5: 1,
+            field6: 0,
+            ..REL_SIZE_KEY
+        };
+        cache.invalidate_range(&rel_range, Lsn(0x50));
+        assert_eq!(cache.lookup(&REL_SIZE_KEY, Lsn(0x50)), None);
+        cache.memorize(REL_SIZE_KEY, Lsn(0x40), 11);
+        assert_eq!(cache.lookup(&REL_SIZE_KEY, Lsn(0x50)), None);
+
+        // and created again
+        cache.update(REL_SIZE_KEY, Lsn(0x60), 0);
+        assert_eq!(cache.lookup(&REL_SIZE_KEY, Lsn(0x60)), Some(0));
+    }
+
+    #[test]
+    fn rel_size_untracked_deletion() {
+        let cache = RelSizeCache::default();
+
+        // Dropped before it got cached, can only be added at a newer LSN
+        let db_range = Key {
+            field4: 0,
+            field5: 0,
+            field6: 0,
+            ..REL_SIZE_KEY
+        }..Key {
+            field3: 13011,
+            field4: 0,
+            field5: 0,
+            field6: 0,
+            ..REL_SIZE_KEY
+        };
+        cache.invalidate_range(&db_range, Lsn(0x30));
+        cache.memorize(REL_SIZE_KEY, Lsn(0x20), 10);
+        assert_eq!(cache.lookup(&REL_SIZE_KEY, Lsn(0x30)), None);
+        cache.memorize(REL_SIZE_KEY, Lsn(0x30), 10);
+        assert_eq!(cache.lookup(&REL_SIZE_KEY, Lsn(0x30)), Some(10));
+    }
+
+    #[test]
+    fn rel_size_eviction() {
+        // Two relations per partition
+        let cache = RelSizeCache::new(2 * REL_SIZE_CACHE_PARTITIONS);
+        let rel_key = |relnode: u32| Key {
+            field4: relnode,
+            ..REL_SIZE_KEY
+        };
+        // All in the same partition
+        let (a, b, c) = (rel_key(16384), rel_key(16400), rel_key(16416));
+
+        cache.memorize(a, Lsn(0x20), 1);
+        cache.update(b, Lsn(0x30), 2);
+        assert_eq!(cache.lookup(&a, Lsn(0x40)), Some(1));
+
+        // B is the least recently used
+        cache.memorize(c, Lsn(0x40), 3);
+        assert_eq!(cache.lookup(&b, Lsn(0x40)), None);
+        assert_eq!(cache.lookup(&a, Lsn(0x40)), Some(1));
+        assert_eq!(cache.lookup(&c, Lsn(0x40)), Some(3));
+
+        // A size of B read before its last change can't come back
+        cache.memorize(b, Lsn(0x20), 1);
+        assert_eq!(cache.lookup(&b, Lsn(0x40)), None);
+        cache.memorize(b, Lsn(0x30), 2);
+        assert_eq!(cache.lookup(&b, Lsn(0x40)), Some(2));
+    }
+
+    #[test]
+    fn rel_size_cache_disabled() {
+        let cache = RelSizeCache::new(0);
+        cache.update(REL_SIZE_KEY, Lsn(0x20), 10);
+        cache.memorize(REL_SIZE_KEY, Lsn(0x20), 10);
+        assert_eq!(cache.lookup(&REL_SIZE_KEY, Lsn(0x20)), None);
+    }
+}
@@ -6,6 +6,7 @@ use anyhow::{bail, Result};
 use byteorder::{ByteOrder, BE};
 use bytes::Bytes;
 use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
 use std::fmt;
 use std::fmt::Display;
 use std::ops::{AddAssign, Range};
@@ -13,6 +14,7 @@ use std::sync::{Arc, RwLockReadGuard};
 use std::time::Duration;
 use utils::{
     lsn::{Lsn, RecordLsn},
+    seqwait::CancellationToken,
     zid::ZTimelineId,
 };
 
@@ -259,7 +261,12 @@ pub trait Repository: Send + Sync {
     /// api's 'compact' command.
     fn compaction_iteration(&self) -> Result<()>;
 
-    /// detaches timeline-related in-memory data.
+    /// Create the image layers that are due, on all the loaded timelines.
+    /// This function is periodically called by the image builder thread.
+    fn image_creation_iteration(&self) -> Result<()>;
+
+    /// detaches timeline-related in-memory data. Reads of the timeline that is
+    /// loaded fail from then on, waits for its WAL are woken up with an error.
     fn detach_timeline(&self, timeline_id: ZTimelineId) -> Result<()>;
 
     // Allows to retrieve remote timeline index from the repo. Used in walreceiver to grab remote consistent lsn.
@@ -309,9 +316,18 @@ pub struct GcResult {
     pub layers_needed_by_pitr: u64,
     pub layers_needed_by_branches: u64,
     pub layers_not_updated: u64,
+    pub layers_too_recent: u64,
+    pub layers_needed_by_policy: u64,
     pub layers_removed: u64, // # of layer files removed because they have been made obsolete by newer ondisk files.
 
+    // Sizes of the layer files above.
+    pub bytes_retained: GcRetainedBytes,
+    pub bytes_removed: u64,
+
     pub elapsed: Duration,
+
+    // The iteration was cancelled before it went through all the timelines.
+    pub cancelled: bool,
 }
 
 impl AddAssign for GcResult {
@@ -321,12 +337,143 @@ impl AddAssign for GcResult {
         self.layers_needed_by_cutoff += other.layers_needed_by_cutoff;
         self.layers_needed_by_branches += other.layers_needed_by_branches;
         self.layers_not_updated += other.layers_not_updated;
+        self.layers_too_recent += other.layers_too_recent;
+        self.layers_needed_by_policy += other.layers_needed_by_policy;
         self.layers_removed += other.layers_removed;
 
+        self.bytes_retained += other.bytes_retained;
+        self.bytes_removed += other.bytes_removed;
+
         self.elapsed += other.elapsed;
+        self.cancelled |= other.cancelled;
+    }
+}
+
+///
+/// Progress of the GC iteration of a tenant that is running, or of the last
+/// one if none is running. Updated after each timeline.
+///
+#[serde_as]
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct GcProgress {
+    pub running: bool,
+    /// The iteration is asked to stop before its next timeline
+    pub cancel_requested: bool,
+    pub timelines_total: usize,
+    pub timelines_done: usize,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub current_timeline: Option<ZTimelineId>,
+    pub layers_examined: u64,
+    pub layers_removed: u64,
+    pub bytes_removed: u64,
+}
+
+/// The rule that made GC keep a layer file, checked in this order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GcRetainReason {
+    /// Newer than the GC horizon.
+    Cutoff,
+    /// Newer than the PITR interval.
+    Pitr,
+    /// Might be needed by a child branch.
+    Branches,
+    /// Not yet covered by a newer image layer.
+    Latest,
+    /// Younger than `gc_min_layer_age`, or not uploaded yet.
+    Recent,
+    /// Kept by a custom [`crate::layered_repository::layer_strategy::LayerStrategy`],
+    /// e.g. for a legal hold.
+    Policy,
+}
+
+/// Bytes in layer files that GC kept, by the rule that kept them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct GcRetainedBytes {
+    pub cutoff: u64,
+    pub pitr: u64,
+    pub branches: u64,
+    pub latest: u64,
+    pub recent: u64,
+    pub policy: u64,
+}
+
+impl GcRetainedBytes {
+    pub fn add(&mut self, reason: GcRetainReason, bytes: u64) {
+        match reason {
+            GcRetainReason::Cutoff => self.cutoff += bytes,
+            GcRetainReason::Pitr => self.pitr += bytes,
+            GcRetainReason::Branches => self.branches += bytes,
+            GcRetainReason::Latest => self.latest += bytes,
+            GcRetainReason::Recent => self.recent += bytes,
+            GcRetainReason::Policy => self.policy += bytes,
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.cutoff + self.pitr + self.branches + self.latest + self.recent + self.policy
+    }
+}
+
+impl AddAssign for GcRetainedBytes {
+    fn add_assign(&mut self, other: Self) {
+        self.cutoff += other.cutoff;
+        self.pitr += other.pitr;
+        self.branches += other.branches;
+        self.latest += other.latest;
+        self.recent += other.recent;
+        self.policy += other.policy;
     }
 }
 
+///
+/// Where the space retained by the last GC of a timeline went: what kept the
+/// layer files, and which relations they hold.
+///
+#[serde_as]
+#[derive(Debug, Clone, Serialize)]
+pub struct GcSpaceReport {
+    #[serde_as(as = "DisplayFromStr")]
+    pub cutoff_lsn: Lsn,
+    #[serde_as(as = "DisplayFromStr")]
+    pub pitr_cutoff_lsn: Lsn,
+    #[serde_as(as = "Vec<DisplayFromStr>")]
+    pub retain_lsns: Vec<Lsn>,
+    pub retained: GcRetainedBytes,
+    pub removed_bytes: u64,
+    /// Consumers of the retained space, largest first.
+    pub top_consumers: Vec<GcSpaceConsumer>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct GcSpaceConsumer {
+    /// The relation, e.g. '1663/13010/1259', if the layers held data of a
+    /// single relation only. Otherwise, 'multiple relations' or 'non-relational data'.
+    pub owner: String,
+    pub layers: u64,
+    pub retained: GcRetainedBytes,
+}
+
+///
+/// Estimate of the layer space that GC of a timeline would free now, computed
+/// from the layer map without running GC. It's refreshed after each checkpoint.
+///
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct GcEstimate {
+    /// The cutoff that GC would use: the last record LSN minus the GC horizon.
+    #[serde_as(as = "DisplayFromStr")]
+    pub cutoff_lsn: Lsn,
+    /// Branch points that GC keeps, as of its last iteration.
+    #[serde_as(as = "Vec<DisplayFromStr>")]
+    pub retain_lsns: Vec<Lsn>,
+    pub reclaimable_layers: u64,
+    pub reclaimable_bytes: u64,
+    /// The disk consistent LSN when the estimate was made.
+    #[serde_as(as = "DisplayFromStr")]
+    pub disk_consistent_lsn: Lsn,
+}
+
 pub trait Timeline: Send + Sync {
     //------------------------------------------------------------------------------
     // Public GET functions
@@ -338,7 +485,15 @@ pub trait Timeline: Send + Sync {
     /// You should call this before any of the other get_* or list_* functions. Calling
     /// those functions with an LSN that has been processed yet is an error.
     ///
-    fn wait_lsn(&self, lsn: Lsn) -> Result<()>;
+    fn wait_lsn(&self, lsn: Lsn) -> Result<()> {
+        self.wait_lsn_cancellable(lsn, &CancellationToken::new())
+    }
+
+    ///
+    /// Like `wait_lsn`, but gives up as soon as 'cancel' is cancelled, e.g.
+    /// because the client that needs the LSN has disconnected.
+    ///
+    fn wait_lsn_cancellable(&self, lsn: Lsn, cancel: &CancellationToken) -> Result<()>;
 
     /// Lock and get timeline's GC cuttof
     fn get_latest_gc_cutoff_lsn(&self) -> RwLockReadGuard<Lsn>;
@@ -353,6 +508,12 @@ pub trait Timeline: Send + Sync {
     ///
     fn get(&self, key: Key, lsn: Lsn) -> Result<Bytes>;
 
+    /// Look up the values of all the keys in 'key_range', in key order.
+    ///
+    /// Same as calling 'get' for each key, but the layers are searched once
+    /// for the whole range. All the keys in the range must exist.
+    fn get_range(&self, key_range: Range<Key>, lsn: Lsn) -> Result<Vec<(Key, Bytes)>>;
+
     /// Get the ancestor's timeline id
     fn get_ancestor_timeline_id(&self) -> Option<ZTimelineId>;
 
@@ -395,6 +556,13 @@ pub trait Timeline: Send + Sync {
         lsn: Lsn,
         latest_gc_cutoff_lsn: &RwLockReadGuard<Lsn>,
     ) -> Result<()>;
+
+    /// Check that reads at that lsn are not below the read floor set by the
+    /// control plane. Other operations, like branching, are allowed there.
+    fn check_read_floor(&self, lsn: Lsn) -> Result<()>;
+
+    /// Logical size soft limit of the timeline's tenant, see [`crate::size_soft_limit`].
+    fn get_logical_size_soft_limit(&self) -> Option<u64>;
 }
 
 /// Various functions to mutate the timeline.
@@ -425,10 +593,12 @@ pub mod repo_harness {
     use bytes::BytesMut;
     use lazy_static::lazy_static;
     use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+    use std::time::Duration;
     use std::{fs, path::PathBuf};
 
     use crate::RepositoryImpl;
     use crate::{
+        clock::ManualClock,
         config::PageServerConf,
         layered_repository::LayeredRepository,
         walredo::{WalRedoError, WalRedoManager},
@@ -469,6 +639,8 @@ pub mod repo_harness {
                 gc_period: Some(tenant_conf.gc_period),
                 image_creation_threshold: Some(tenant_conf.image_creation_threshold),
                 pitr_interval: Some(tenant_conf.pitr_interval),
+                logical_size_soft_limit: tenant_conf.logical_size_soft_limit,
+                image_layer_excluded_forks: Some(tenant_conf.image_layer_excluded_forks),
             }
         }
     }
@@ -477,6 +649,8 @@ pub mod repo_harness {
         pub conf: &'static PageServerConf,
         pub tenant_conf: TenantConf,
         pub tenant_id: ZTenantId,
+        /// Virtual time of the repositories loaded with the harness
+        pub clock: Arc<ManualClock>,
 
         pub lock_guard: (
             Option<RwLockReadGuard<'a, ()>>,
@@ -517,6 +691,7 @@ pub mod repo_harness {
                 conf,
                 tenant_conf,
                 tenant_id,
+                clock: Arc::new(ManualClock::new()),
                 lock_guard,
             })
         }
@@ -535,7 +710,9 @@ pub mod repo_harness {
                 self.tenant_id,
                 RemoteIndex::empty(),
                 false,
-            );
+            )
+            .with_clock(self.clock.clone());
+            repo.load_append_only_relations()?;
             // populate repo with locally available timelines
             for timeline_dir_entry in fs::read_dir(self.conf.timelines_path(&self.tenant_id))
                 .expect("should be able to read timelines dir")
@@ -561,6 +738,40 @@ pub mod repo_harness {
         pub fn timeline_path(&self, timeline_id: &ZTimelineId) -> PathBuf {
             self.conf.timeline_path(timeline_id, &self.tenant_id)
         }
+
+        /// Move the virtual time of the repositories forward.
+        pub fn advance_time(&self, duration: Duration) {
+            self.clock.advance(duration);
+        }
+    }
+
+    ///
+    /// Generates increasing, 8-byte aligned LSNs for the test records, like
+    /// the WAL records they stand for.
+    ///
+    pub struct LsnGenerator {
+        next: Lsn,
+        step: u64,
+    }
+
+    impl LsnGenerator {
+        pub fn new(start: Lsn, step: u64) -> Self {
+            assert!(step > 0 && step % 8 == 0, "LSN step must be 8-byte aligned");
+            LsnGenerator {
+                next: start.align(),
+                step,
+            }
+        }
+    }
+
+    impl Iterator for LsnGenerator {
+        type Item = Lsn;
+
+        fn next(&mut self) -> Option<Lsn> {
+            let lsn = self.next;
+            self.next = Lsn(lsn.0 + self.step);
+            Some(lsn)
+        }
     }
 
     // Mock WAL redo manager that doesn't do much
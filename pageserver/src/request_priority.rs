@@ -0,0 +1,254 @@
+//!
+//! Priority classes of the work done for the clients of the page server.
+//!
+//! A compute that waits for a GetPage@LSN response is blocked on it, while a
+//! basebackup or a prefetch request can take a little longer without anyone
+//! noticing. The page service sets the [`Priority`] of the request it is
+//! serving for the current thread, see [`set_current`], and the shared
+//! resources that requests contend for, the layer file reads and the WAL redo
+//! process, are put behind a [`PriorityQueue`]. A queue lets background
+//! requests in only when no interactive request is waiting, so that a bulk
+//! operation can't add its queueing delay to the latency of the foreground
+//! requests.
+//!
+//! Everything that doesn't set a priority, e.g. the WAL receiver, is
+//! interactive.
+//!
+use std::cell::Cell;
+use std::str::FromStr;
+use std::sync::{Condvar, Mutex};
+use std::time::Instant;
+
+use anyhow::bail;
+use lazy_static::lazy_static;
+use metrics::{register_histogram_vec, HistogramVec};
+use once_cell::sync::OnceCell;
+
+static LAYER_READ_QUEUE: OnceCell<PriorityQueue> = OnceCell::new();
+const TEST_MAX_CONCURRENT_LAYER_READS: usize = 16;
+
+lazy_static! {
+    static ref QUEUE_WAIT_TIME: HistogramVec = register_histogram_vec!(
+        "pageserver_priority_queue_wait_seconds",
+        "Time spent waiting in a priority queue, by queue and priority",
+        &["queue", "priority"],
+        vec![0.000_010, 0.000_100, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0]
+    )
+    .expect("failed to define a metric");
+}
+
+thread_local! {
+    static CURRENT_PRIORITY: Cell<Priority> = Cell::new(Priority::Interactive);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    /// A client is waiting for the response.
+    Interactive,
+    /// Bulk work, like a basebackup or prefetching.
+    Background,
+}
+
+impl Priority {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Priority::Interactive => "interactive",
+            Priority::Background => "background",
+        }
+    }
+}
+
+impl FromStr for Priority {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Priority, Self::Err> {
+        let result = match s {
+            "interactive" => Priority::Interactive,
+            "background" => Priority::Background,
+            _ => bail!(
+                "invalid priority \"{s}\", valid values are \"interactive\" and \"background\""
+            ),
+        };
+        Ok(result)
+    }
+}
+
+/// The priority of the work done by the current thread.
+pub fn current() -> Priority {
+    CURRENT_PRIORITY.with(|p| p.get())
+}
+
+/// Set the priority of the current thread, until the returned guard is dropped.
+pub fn set_current(priority: Priority) -> PriorityGuard {
+    let prev = CURRENT_PRIORITY.with(|p| p.replace(priority));
+    PriorityGuard { prev }
+}
+
+pub struct PriorityGuard {
+    prev: Priority,
+}
+
+impl Drop for PriorityGuard {
+    fn drop(&mut self) {
+        CURRENT_PRIORITY.with(|p| p.set(self.prev));
+    }
+}
+
+///
+/// Initialize the queue in front of the layer file reads. This must be called
+/// once at page server startup.
+///
+pub fn init(max_concurrent_layer_reads: usize) {
+    if LAYER_READ_QUEUE
+        .set(PriorityQueue::new("layer_read", max_concurrent_layer_reads))
+        .is_err()
+    {
+        panic!("layer read queue already initialized");
+    }
+}
+
+///
+/// The queue that the reads of layer file blocks that miss the page cache go through.
+///
+pub fn layer_read_queue() -> &'static PriorityQueue {
+    // In unit tests, page server startup doesn't happen and no one calls
+    // request_priority::init(). Initialize it here with a small limit.
+    if cfg!(test) {
+        LAYER_READ_QUEUE
+            .get_or_init(|| PriorityQueue::new("layer_read", TEST_MAX_CONCURRENT_LAYER_READS))
+    } else {
+        LAYER_READ_QUEUE
+            .get()
+            .expect("layer read queue not initialized")
+    }
+}
+
+///
+/// Limits the number of threads that use a resource at the same time, and
+/// lets the interactive ones in first.
+///
+/// Interactive waiters are woken in no particular order, and background
+/// waiters only get a slot when no interactive one is waiting, so a steady
+/// stream of interactive requests that keeps all the slots busy starves the
+/// background ones. That is the point: they are the ones that can wait.
+///
+pub struct PriorityQueue {
+    name: &'static str,
+    slots: usize,
+    state: Mutex<QueueState>,
+    cond: Condvar,
+}
+
+#[derive(Default)]
+struct QueueState {
+    in_use: usize,
+    interactive_waiting: usize,
+}
+
+impl PriorityQueue {
+    pub fn new(name: &'static str, slots: usize) -> Self {
+        assert!(slots > 0, "a priority queue needs at least one slot");
+        PriorityQueue {
+            name,
+            slots,
+            state: Mutex::new(QueueState::default()),
+            cond: Condvar::new(),
+        }
+    }
+
+    /// Wait for a slot, at the priority of the current thread.
+    pub fn acquire(&self) -> QueueSlot<'_> {
+        self.acquire_as(current())
+    }
+
+    /// Wait for a slot. It is released when the returned guard is dropped.
+    pub fn acquire_as(&self, priority: Priority) -> QueueSlot<'_> {
+        let start = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        match priority {
+            Priority::Interactive => {
+                state.interactive_waiting += 1;
+                while state.in_use >= self.slots {
+                    state = self.cond.wait(state).unwrap();
+                }
+                state.interactive_waiting -= 1;
+            }
+            Priority::Background => {
+                while state.in_use >= self.slots || state.interactive_waiting > 0 {
+                    state = self.cond.wait(state).unwrap();
+                }
+            }
+        }
+        state.in_use += 1;
+        drop(state);
+
+        QUEUE_WAIT_TIME
+            .with_label_values(&[self.name, priority.as_str()])
+            .observe(start.elapsed().as_secs_f64());
+        QueueSlot { queue: self }
+    }
+}
+
+pub struct QueueSlot<'a> {
+    queue: &'a PriorityQueue,
+}
+
+impl Drop for QueueSlot<'_> {
+    fn drop(&mut self) {
+        let mut state = self.queue.state.lock().unwrap();
+        state.in_use -= 1;
+        drop(state);
+        // Wake everyone: a background waiter may not take the slot
+        self.queue.cond.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn interactive_goes_first() {
+        let queue = Arc::new(PriorityQueue::new("test", 1));
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        let slot = queue.acquire_as(Priority::Interactive);
+
+        let spawn_waiter = |priority: Priority| {
+            let queue = Arc::clone(&queue);
+            let order = Arc::clone(&order);
+            thread::spawn(move || {
+                let _slot = queue.acquire_as(priority);
+                order.lock().unwrap().push(priority);
+            })
+        };
+        // The background request comes first, but has to wait for the interactive one
+        let background = spawn_waiter(Priority::Background);
+        thread::sleep(Duration::from_millis(100));
+        let interactive = spawn_waiter(Priority::Interactive);
+        while queue.state.lock().unwrap().interactive_waiting == 0 {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        drop(slot);
+        background.join().unwrap();
+        interactive.join().unwrap();
+        assert_eq!(
+            *order.lock().unwrap(),
+            vec![Priority::Interactive, Priority::Background]
+        );
+    }
+
+    #[test]
+    fn thread_priority() {
+        assert_eq!(current(), Priority::Interactive);
+        {
+            let _guard = set_current(Priority::Background);
+            assert_eq!(current(), Priority::Background);
+        }
+        assert_eq!(current(), Priority::Interactive);
+    }
+}
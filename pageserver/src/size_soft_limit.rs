@@ -0,0 +1,147 @@
+//!
+//! Logical size soft limit.
+//!
+//! A tenant can be given a `logical_size_soft_limit`, in bytes, that applies to
+//! each of its timelines. It's only reported, nothing is refused: by the time the
+//! WAL gets to the pageserver, it's committed in the safekeepers, and refusing to
+//! ingest it would only stall the timeline, with the compute's reads waiting for
+//! the WAL forever. So all the WAL is ingested, and the pageserver tracks whether
+//! the timeline is over its soft limit, for the timeline details and the metrics.
+//! To stop a compute from growing the database, use its own
+//! `neon.max_cluster_size` setting.
+//!
+//! To avoid flapping when the size hovers around the limit, a timeline that went
+//! over it is considered over the limit until its size drops below
+//! [`SOFT_LIMIT_RELEASE_PERCENT`] of the limit.
+//!
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use lazy_static::lazy_static;
+use metrics::{register_int_counter_vec, register_int_gauge_vec, IntCounterVec, IntGaugeVec};
+use tracing::*;
+use utils::zid::{ZTenantId, ZTimelineId};
+
+use crate::metric_labels;
+
+/// A timeline over its soft limit is reported under it again once its size
+/// drops below this percentage of the limit.
+pub const SOFT_LIMIT_RELEASE_PERCENT: u64 = 95;
+
+lazy_static! {
+    static ref SOFT_LIMIT_OVERRUNS: IntCounterVec = register_int_counter_vec!(
+        "pageserver_logical_size_soft_limit_overruns_total",
+        "Number of relation creations and extensions ingested while the timeline is over its tenant's logical size soft limit",
+        &["tenant_id", "timeline_id"]
+    )
+    .expect("failed to define a metric");
+    static ref SOFT_LIMIT_EXCEEDED: IntGaugeVec = register_int_gauge_vec!(
+        "pageserver_logical_size_soft_limit_exceeded",
+        "Whether the timeline is over its tenant's logical size soft limit, the number of such timelines for the \"other\" tenants",
+        &["tenant_id", "timeline_id"]
+    )
+    .expect("failed to define a metric");
+}
+
+///
+/// Tracks whether a timeline is over its soft limit.
+///
+#[derive(Default)]
+pub struct LogicalSizeSoftLimit {
+    metric_labels: Option<(String, String)>,
+    exceeded: AtomicBool,
+}
+
+impl LogicalSizeSoftLimit {
+    pub fn new(tenant_id: ZTenantId, timeline_id: ZTimelineId) -> Self {
+        LogicalSizeSoftLimit {
+            metric_labels: Some(metric_labels::timeline_labels(&tenant_id, &timeline_id)),
+            exceeded: AtomicBool::new(false),
+        }
+    }
+
+    pub fn is_exceeded(&self) -> bool {
+        self.exceeded.load(Ordering::Relaxed)
+    }
+
+    ///
+    /// Re-evaluate the soft limit state for the current logical size of the
+    /// timeline. Returns true if the timeline is over its soft limit.
+    ///
+    pub fn update(&self, current_size: u64, limit: Option<u64>) -> bool {
+        let was_exceeded = self.is_exceeded();
+        let exceeded = match limit {
+            None => false,
+            Some(limit) if was_exceeded => {
+                current_size >= (limit as u128 * SOFT_LIMIT_RELEASE_PERCENT as u128 / 100) as u64
+            }
+            Some(limit) => current_size > limit,
+        };
+
+        if exceeded != was_exceeded
+            && self
+                .exceeded
+                .compare_exchange(was_exceeded, exceeded, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            if exceeded {
+                warn!("logical size {current_size} exceeds the soft limit of {limit:?} bytes");
+            } else {
+                info!(
+                    "logical size {current_size} is within the soft limit of {limit:?} bytes again"
+                );
+            }
+            if let Some((tenant_id, timeline_id)) = &self.metric_labels {
+                let gauge = SOFT_LIMIT_EXCEEDED.with_label_values(&[tenant_id, timeline_id]);
+                if tenant_id == metric_labels::OTHER {
+                    // Count the timelines over their limit in the shared series
+                    gauge.add(if exceeded { 1 } else { -1 });
+                } else {
+                    gauge.set(exceeded as i64);
+                }
+            }
+        }
+        exceeded
+    }
+
+    ///
+    /// Account for a relation being created or extended, given the current
+    /// logical size of the timeline. The growth is never refused, the WAL
+    /// it comes from is committed already. Returns true if the timeline is
+    /// over its soft limit.
+    ///
+    pub fn record_growth(&self, current_size: u64, limit: Option<u64>) -> bool {
+        let exceeded = self.update(current_size, limit);
+        if exceeded {
+            if let Some((tenant_id, timeline_id)) = &self.metric_labels {
+                SOFT_LIMIT_OVERRUNS
+                    .with_label_values(&[tenant_id, timeline_id])
+                    .inc();
+            }
+        }
+        exceeded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn soft_limit_hysteresis() {
+        let limit = LogicalSizeSoftLimit::default();
+        assert!(!limit.record_growth(u64::MAX, None));
+        assert!(!limit.record_growth(1000, Some(1000)));
+
+        assert!(limit.record_growth(1001, Some(1000)));
+        assert!(limit.is_exceeded());
+
+        // Dropping just under the limit is not enough to be under it again
+        assert!(limit.record_growth(990, Some(1000)));
+        assert!(!limit.update(940, Some(1000)));
+        assert!(!limit.record_growth(990, Some(1000)));
+
+        // Removing the limit clears the state right away
+        assert!(limit.update(2000, Some(1000)));
+        assert!(!limit.update(2000, None));
+    }
+}
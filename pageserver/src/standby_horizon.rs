@@ -0,0 +1,95 @@
+//!
+//! Oldest LSN that the read replicas of a timeline still read at.
+//!
+//! A read replica (hot standby) replays the WAL of its primary, and reads the
+//! pages it needs from the page server at the LSN it has replayed up to. A
+//! long-running query on the replica keeps reading at the LSN its snapshot was
+//! taken at, and once GC moves the cutoff past that LSN, its reads fail with
+//! "requested LSN is earlier than GC horizon" errors.
+//!
+//! To prevent that, the replicas send feedback with the oldest LSN they still
+//! read at, periodically, with the `standby_feedback` page service command.
+//! GC doesn't move the cutoff past the oldest LSN reported. Each report holds
+//! GC back for `standby_horizon_lease` only, so that a replica that goes away
+//! doesn't stall GC forever: the replicas have to report more often than that.
+//!
+//! The hot standby feedback of PostgreSQL itself carries transaction ids, which
+//! the page server can't map to LSNs, so the replicas report LSNs instead.
+//!
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use utils::lsn::Lsn;
+
+pub struct StandbyHorizon {
+    lease: Duration,
+    /// The reported LSNs with the time of the report, oldest report first.
+    reports: Mutex<VecDeque<(Instant, Lsn)>>,
+}
+
+impl StandbyHorizon {
+    pub fn new(lease: Duration) -> Self {
+        StandbyHorizon {
+            lease,
+            reports: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Record the feedback of a replica that still reads at 'lsn'.
+    pub fn report(&self, now: Instant, lsn: Lsn) {
+        let mut reports = self.reports.lock().unwrap();
+        self.expire(&mut reports, now);
+        reports.push_back((now, lsn));
+    }
+
+    /// The oldest LSN reported within the lease, if any.
+    pub fn get(&self, now: Instant) -> Option<Lsn> {
+        let mut reports = self.reports.lock().unwrap();
+        self.expire(&mut reports, now);
+        reports.iter().map(|(_, lsn)| *lsn).min()
+    }
+
+    fn expire(&self, reports: &mut VecDeque<(Instant, Lsn)>, now: Instant) {
+        while let Some((reported_at, _)) = reports.front() {
+            if now.saturating_duration_since(*reported_at) <= self.lease {
+                break;
+            }
+            reports.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn standby_horizon_lease() {
+        let horizon = StandbyHorizon::new(Duration::from_secs(30));
+        let start = Instant::now();
+        assert_eq!(horizon.get(start), None);
+
+        // Two replicas, the oldest LSN wins
+        horizon.report(start, Lsn(0x30));
+        horizon.report(start + Duration::from_secs(10), Lsn(0x20));
+        assert_eq!(
+            horizon.get(start + Duration::from_secs(10)),
+            Some(Lsn(0x20))
+        );
+
+        // The first report expires, the second one still holds
+        horizon.report(start + Duration::from_secs(35), Lsn(0x40));
+        assert_eq!(
+            horizon.get(start + Duration::from_secs(35)),
+            Some(Lsn(0x20))
+        );
+
+        // Without new reports, the horizon goes away
+        assert_eq!(
+            horizon.get(start + Duration::from_secs(45)),
+            Some(Lsn(0x40))
+        );
+        assert_eq!(horizon.get(start + Duration::from_secs(70)), None);
+    }
+}
@@ -47,6 +47,7 @@
 //! If the storage sync loop was successfully started before, pageserver schedules the layer files and the updated metadata file for upload, every time a layer is flushed to disk.
 //! The uploads are disabled, if no remote storage configuration is provided (no sync loop is started this way either).
 //! See [`crate::layered_repository`] for the upload calls and the adjacent logic.
+//! With `stream_layer_uploads`, big image layers start uploading while they are still being written, see [`LayerUploadStream`].
 //!
 //! Synchronization logic is able to communicate back with updated timeline sync states, [`crate::repository::TimelineSyncStatusUpdate`],
 //! submitted via [`crate::tenant_mgr::apply_timeline_sync_status_updates`] function. Tenant manager applies corresponding timeline updates in pageserver's in-memory state.
@@ -94,6 +95,8 @@
 //! The queue gets emptied by a single thread with the loop, that polls the tasks in batches of deduplicated tasks.
 //! A task from the batch corresponds to a single timeline, with its files to sync merged together: given that only one task sync loop step is active at a time,
 //! timeline uploads and downloads can happen concurrently, in no particular order due to incremental nature of the timeline layers.
+//! The number of timeline downloads in a batch is limited, overall and per tenant, so that attaching a tenant with many timelines
+//! doesn't saturate the bandwidth; the downloads that compute requests are waiting for get moved to the front of the queue, see [`prioritize_download`].
 //! Deletion happens only after a successful upload only, otherwise the compaction output might make the timeline inconsistent until both tasks are fully processed without errors.
 //! Upload and download update the remote data (inmemory index and S3 json index part file) only after every layer is successfully synchronized, while the deletion task
 //! does otherwise: it requires to have the remote data updated first successfully: blob files will be invisible to pageserver this way.
@@ -131,8 +134,29 @@
 //!
 //! Uploads and downloads sync layer files in arbitrary order, but only after all layer files are synched the local metadada (for download) and remote index part (for upload) are updated,
 //! to avoid having a corrupt state without the relevant layer files.
+//! An uploaded layer counts only once the storage reports its full size, and an upload that gives up after too many errors records its failed layers
+//! in the index part, but keeps the metadata of the last checkpoint with all layers uploaded.
+//! Index parts published by the older versions may still have their metadata ahead of the layers: they get checked against the storage at startup,
+//! and the layers they reference, but the storage doesn't have, are uploaded again from the local files.
 //! Refer to [`upload`] and [`download`] for more details.
 //!
+//! Layers removed by the deletion tasks are not deleted from the remote storage right away: they wait for `remote_deletion_delay`
+//! after the index part stops referencing them, and get deleted in batches between the loop steps, see [`delete`].
+//! The index part records the pending deletions, so they are resumed after a restart.
+//!
+//! The "only one pageserver at a time" agreement is enforced with tenant generations, issued by the control plane every time it
+//! assigns the tenant to a pageserver, see [`crate::tenant_mgr::set_tenant_generation`].
+//! Every index part upload is stamped with the tenant's generation and is refused if the remote index part has a newer one:
+//! in that case another pageserver has taken over the tenant, and the task is dropped instead of being retried.
+//! NOTE: the check and the upload are not atomic, so there's a short window when a stale pageserver can still overwrite the index part.
+//! Layer files are uploaded before the check, but they are not visible to other pageservers until referenced by an index part.
+//! They are also uploaded under storage paths with the generation in them (see [`index::remote_layer_path`]), and the index part
+//! records the generation of every layer, so a stale pageserver never overwrites the layers that a newer one references.
+//!
+//! A tenant can be cloned in the remote storage, see [`clone_remote_tenant`]: the timelines of the clone reference the layers
+//! stored under the source tenant instead of copying them. Such layers are never deleted by the clone, and the source timelines
+//! that have clones never delete their layers from the remote storage either, since the clones can still use them.
+//!
 //! Synchronization never removes any local files from pageserver workdir or remote files from the remote storage, yet there could be overwrites of the same files (index part and metadata file updates, future checksum mismatch fixes).
 //! NOTE: No real contents or checksum check happens right now and is a subject to improve later.
 //!
@@ -141,6 +165,7 @@
 mod delete;
 mod download;
 pub mod index;
+mod layer_stream;
 mod upload;
 
 use std::{
@@ -153,11 +178,14 @@ use std::{
     sync::{Arc, Condvar, Mutex},
 };
 
-use anyhow::{anyhow, bail, Context};
+use anyhow::{anyhow, bail, ensure, Context};
 use futures::stream::{FuturesUnordered, StreamExt};
 use lazy_static::lazy_static;
 use once_cell::sync::OnceCell;
-use remote_storage::{GenericRemoteStorage, RemoteStorage};
+use remote_storage::{
+    GenericRemoteStorage, MirroredStorage, RemoteStorage, RemoteStorageConfig,
+    DEFAULT_REMOTE_STORAGE_MULTIPART_UPLOAD_PART_SIZE,
+};
 use tokio::{
     fs,
     runtime::Runtime,
@@ -166,33 +194,42 @@ use tokio::{
 use tracing::*;
 
 use self::{
-    delete::delete_timeline_layers,
+    delete::{
+        delete_due_layers, delete_timeline_layers, resume_pending_deletions, RemoteDeletionQueue,
+    },
     download::{download_timeline_layers, DownloadedTimeline},
     index::{IndexPart, RemoteTimeline, RemoteTimelineIndex},
     upload::{upload_index_part, upload_timeline_layers, UploadedTimeline},
 };
 use crate::{
+    compute_notify::{self, TimelineState},
     config::PageServerConf,
     layered_repository::{
         ephemeral_file::is_ephemeral_file,
+        layer_deletion::{self, TOMBSTONE_EXTENSION},
         metadata::{metadata_path, TimelineMetadata, METADATA_FILE_NAME},
         LayeredRepository,
     },
+    metric_labels,
     repository::TimelineSyncStatusUpdate,
     storage_sync::{self, index::RemoteIndex},
-    tenant_mgr::apply_timeline_sync_status_updates,
+    tenant_mgr::{self, apply_timeline_sync_status_updates},
     thread_mgr,
     thread_mgr::ThreadKind,
 };
 
 use metrics::{
-    register_histogram_vec, register_int_counter, register_int_gauge, HistogramVec, IntCounter,
-    IntGauge,
+    register_histogram_vec, register_int_counter, register_int_gauge, register_int_gauge_vec,
+    HistogramVec, IntCounter, IntGauge, IntGaugeVec,
 };
 use utils::zid::{ZTenantId, ZTenantTimelineId, ZTimelineId};
 
 pub use self::download::download_index_part;
 pub use self::download::TEMP_DOWNLOAD_EXTENSION;
+pub use self::download::{download_progress, DownloadProgress};
+pub use self::download::{quarantined_layers, QuarantinedLayers};
+pub use self::layer_stream::LayerUploadStream;
+pub use self::upload::UPLOAD_STATE_EXTENSION;
 
 lazy_static! {
     static ref REMAINING_SYNC_ITEMS: IntGauge = register_int_gauge!(
@@ -216,9 +253,24 @@ lazy_static! {
         ]
     )
     .expect("failed to register pageserver image sync time histogram vec");
+    static ref REMOTE_CONSISTENT_LSN: IntGaugeVec = register_int_gauge_vec!(
+        "pageserver_remote_consistent_lsn",
+        "LSN up to which the timeline data is confirmed to be uploaded to the remote storage",
+        &["tenant_id", "timeline_id"]
+    )
+    .expect("failed to register pageserver remote consistent lsn int gauge vec");
 }
 
 static SYNC_QUEUE: OnceCell<SyncQueue> = OnceCell::new();
+/// Storages to download layers from when the primary remote storage fails, see `remote_storage_fallback`.
+static FALLBACK_STORAGES: OnceCell<Vec<GenericRemoteStorage>> = OnceCell::new();
+
+/// A file, left in the local directory of an archived timeline instead of all its layers and metadata.
+pub const ARCHIVED_TIMELINE_MARKER: &str = "archived";
+
+/// A file in the local directory of a detached timeline, with the time of the detach. The files of the timeline
+/// are kept until `detach_grace_period` passes, see [`crate::tenant_mgr::purge_detached_timelines`].
+pub const DETACHED_TIMELINE_MARKER: &str = "detached";
 
 /// A timeline status to share with pageserver's sync counterpart,
 /// after comparing local and remote timeline state.
@@ -253,30 +305,40 @@ pub fn start_local_timeline_sync(
 ) -> anyhow::Result<SyncStartupData> {
     let local_timeline_files = local_tenant_timeline_files(config)
         .context("Failed to collect local tenant timeline files")?;
+    let archived_timelines =
+        archived_timelines(config).context("Failed to collect archived timelines")?;
 
     match config.remote_storage_config.as_ref() {
         Some(storage_config) => {
+            let fallback_storages = config
+                .remote_storage_fallbacks
+                .iter()
+                .map(|fallback_config| {
+                    GenericRemoteStorage::new(config.workdir.clone(), fallback_config)
+                })
+                .collect::<anyhow::Result<Vec<_>>>()
+                .context("Failed to init the fallback remote storages")?;
+            FALLBACK_STORAGES
+                .set(fallback_storages)
+                .map_err(|_| anyhow!("Could not initialize fallback remote storages"))?;
+
             match GenericRemoteStorage::new(config.workdir.clone(), storage_config)
                 .context("Failed to init the generic remote storage")?
             {
-                GenericRemoteStorage::Local(local_fs_storage) => {
-                    storage_sync::spawn_storage_sync_thread(
-                        config,
-                        local_timeline_files,
-                        local_fs_storage,
-                        storage_config.max_concurrent_syncs,
-                        storage_config.max_sync_errors,
-                    )
-                }
-                GenericRemoteStorage::S3(s3_bucket_storage) => {
-                    storage_sync::spawn_storage_sync_thread(
-                        config,
-                        local_timeline_files,
-                        s3_bucket_storage,
-                        storage_config.max_concurrent_syncs,
-                        storage_config.max_sync_errors,
-                    )
-                }
+                GenericRemoteStorage::Local(local_fs_storage) => spawn_storage_sync_with_mirror(
+                    config,
+                    local_timeline_files,
+                    archived_timelines,
+                    local_fs_storage,
+                    storage_config,
+                ),
+                GenericRemoteStorage::S3(s3_bucket_storage) => spawn_storage_sync_with_mirror(
+                    config,
+                    local_timeline_files,
+                    archived_timelines,
+                    s3_bucket_storage,
+                    storage_config,
+                ),
             }
             .context("Failed to spawn the storage sync thread")
         }
@@ -304,6 +366,44 @@ pub fn start_local_timeline_sync(
     }
 }
 
+/// Wraps the storage into a [`MirroredStorage`], if `remote_storage_mirror` is configured.
+fn spawn_storage_sync_with_mirror<P, S>(
+    config: &'static PageServerConf,
+    local_timeline_files: HashMap<ZTenantTimelineId, (TimelineMetadata, HashSet<PathBuf>)>,
+    archived_timelines: HashSet<ZTenantTimelineId>,
+    storage: S,
+    storage_config: &RemoteStorageConfig,
+) -> anyhow::Result<SyncStartupData>
+where
+    P: Debug + Send + Sync + 'static,
+    S: RemoteStorage<RemoteObjectId = P> + Send + Sync + 'static,
+{
+    match &config.remote_storage_mirror {
+        Some(mirror_config) => {
+            let secondary =
+                GenericRemoteStorage::new(config.workdir.clone(), &mirror_config.storage)
+                    .context("Failed to init the remote storage mirror")?;
+            let mirrored_storage = MirroredStorage::new(storage, secondary, move |local_path| {
+                mirror_config.is_mirrored(config, local_path)
+            });
+            storage_sync::spawn_storage_sync_thread(
+                config,
+                local_timeline_files,
+                archived_timelines,
+                mirrored_storage,
+                storage_config,
+            )
+        }
+        None => storage_sync::spawn_storage_sync_thread(
+            config,
+            local_timeline_files,
+            archived_timelines,
+            storage,
+            storage_config,
+        ),
+    }
+}
+
 fn local_tenant_timeline_files(
     config: &'static PageServerConf,
 ) -> anyhow::Result<HashMap<ZTenantTimelineId, (TimelineMetadata, HashSet<PathBuf>)>> {
@@ -338,6 +438,61 @@ fn local_tenant_timeline_files(
     Ok(local_tenant_timeline_files)
 }
 
+/// Lists the timelines that got archived: their local directories contain [`ARCHIVED_TIMELINE_MARKER`] only,
+/// all the data is in the remote storage.
+fn archived_timelines(
+    config: &'static PageServerConf,
+) -> anyhow::Result<HashSet<ZTenantTimelineId>> {
+    let mut archived_timelines = HashSet::new();
+    let tenants_dir = config.tenants_path();
+    for tenants_dir_entry in std::fs::read_dir(&tenants_dir)
+        .with_context(|| format!("Failed to list tenants dir {}", tenants_dir.display()))?
+    {
+        let tenant_path = tenants_dir_entry
+            .context("Failed to list tenants dir entry")?
+            .path();
+        let tenant_id = match tenant_path
+            .file_name()
+            .and_then(OsStr::to_str)
+            .unwrap_or_default()
+            .parse::<ZTenantId>()
+        {
+            Ok(tenant_id) => tenant_id,
+            Err(_) => continue,
+        };
+
+        let timelines_dir = config.timelines_path(&tenant_id);
+        let timelines_dir_entries = match std::fs::read_dir(&timelines_dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Failed to list timelines dir for tenant {tenant_id}: {e}");
+                continue;
+            }
+        };
+        for timelines_dir_entry in timelines_dir_entries {
+            let timeline_path = timelines_dir_entry
+                .context("Failed to list timelines dir entry")?
+                .path();
+            if !timeline_path.join(ARCHIVED_TIMELINE_MARKER).exists() {
+                continue;
+            }
+            if let Ok(timeline_id) = timeline_path
+                .file_name()
+                .and_then(OsStr::to_str)
+                .unwrap_or_default()
+                .parse::<ZTimelineId>()
+            {
+                archived_timelines.insert(ZTenantTimelineId {
+                    tenant_id,
+                    timeline_id,
+                });
+            }
+        }
+    }
+
+    Ok(archived_timelines)
+}
+
 fn collect_timelines_for_tenant(
     config: &'static PageServerConf,
     tenant_path: &Path,
@@ -360,6 +515,20 @@ fn collect_timelines_for_tenant(
         match timelines_dir_entry {
             Ok(timelines_dir_entry) => {
                 let timeline_path = timelines_dir_entry.path();
+                if timeline_path.join(ARCHIVED_TIMELINE_MARKER).exists() {
+                    debug!(
+                        "skipping archived timeline at '{}'",
+                        timeline_path.display()
+                    );
+                    continue;
+                }
+                if timeline_path.join(DETACHED_TIMELINE_MARKER).exists() {
+                    debug!(
+                        "skipping detached timeline at '{}'",
+                        timeline_path.display()
+                    );
+                    continue;
+                }
                 match collect_timeline_files(&timeline_path) {
                     Ok((timeline_id, metadata, timeline_files)) => {
                         timelines.insert(
@@ -401,6 +570,8 @@ fn collect_timeline_files(
         .unwrap_or_default()
         .parse::<ZTimelineId>()
         .context("Could not parse timeline id out of the timeline dir name")?;
+    // Layers removed by GC or compaction before a restart must not be uploaded again
+    layer_deletion::complete_interrupted_deletions(timeline_dir)?;
     let timeline_dir_entries =
         std::fs::read_dir(&timeline_dir).context("Failed to list timeline dir contents")?;
     for entry in timeline_dir_entries {
@@ -421,6 +592,25 @@ fn collect_timeline_files(
                         entry_path.display()
                     )
                 })?;
+            } else if entry_path.extension().and_then(OsStr::to_str) == Some(UPLOAD_STATE_EXTENSION)
+            {
+                // Keep the progress of the interrupted layer upload, to resume it later,
+                // unless the layer is gone. Its parts will be cleaned up by the remote storage lifecycle rules.
+                if !entry_path.with_extension("").exists() {
+                    info!(
+                        "removing stale upload state file at {}",
+                        entry_path.display()
+                    );
+                    std::fs::remove_file(&entry_path).with_context(|| {
+                        format!(
+                            "failed to remove stale upload state file at {}",
+                            entry_path.display()
+                        )
+                    })?;
+                }
+            } else if entry_path.extension().and_then(OsStr::to_str) == Some(TOMBSTONE_EXTENSION) {
+                debug!("skipping layer deletion tombstone {}", entry_path.display());
+                continue;
             } else if entry_path.extension().and_then(OsStr::to_str) == Some("temp") {
                 info!("removing temp layer file at {}", entry_path.display());
                 std::fs::remove_file(&entry_path).with_context(|| {
@@ -459,17 +649,39 @@ fn collect_timeline_files(
 /// 'queue' is protected by a mutex, and 'condvar' is used to wait for tasks to arrive.
 struct SyncQueue {
     max_timelines_per_batch: NonZeroUsize,
+    /// Limits for the timeline downloads in a batch, overall and per tenant.
+    /// The tasks over the limits stay in the queue for the next batches.
+    max_downloads_per_batch: NonZeroUsize,
+    max_tenant_downloads_per_batch: NonZeroUsize,
 
     queue: Mutex<VecDeque<(ZTenantTimelineId, SyncTask)>>,
     condvar: Condvar,
+
+    /// Remote layers waiting to be deleted, after the deletion tasks registered their deletion.
+    deletions: RemoteDeletionQueue,
 }
 
 impl SyncQueue {
     fn new(max_timelines_per_batch: NonZeroUsize) -> Self {
+        Self::with_download_limits(
+            max_timelines_per_batch,
+            max_timelines_per_batch,
+            max_timelines_per_batch,
+        )
+    }
+
+    fn with_download_limits(
+        max_timelines_per_batch: NonZeroUsize,
+        max_downloads_per_batch: NonZeroUsize,
+        max_tenant_downloads_per_batch: NonZeroUsize,
+    ) -> Self {
         Self {
             max_timelines_per_batch,
+            max_downloads_per_batch,
+            max_tenant_downloads_per_batch,
             queue: Mutex::new(VecDeque::new()),
             condvar: Condvar::new(),
+            deletions: RemoteDeletionQueue::new(Duration::ZERO),
         }
     }
 
@@ -483,21 +695,37 @@ impl SyncQueue {
         }
     }
 
+    /// Moves the download tasks of the timeline to the front of the queue, so that they get into the next batch.
+    /// Returns `false` if there are no such tasks queued.
+    fn prioritize_download(&self, sync_id: ZTenantTimelineId) -> bool {
+        let mut q = self.queue.lock().unwrap();
+        let (mut downloads, other_tasks): (VecDeque<_>, VecDeque<_>) =
+            q.drain(..).partition(|(task_sync_id, task)| {
+                *task_sync_id == sync_id && matches!(task, SyncTask::Download(_))
+            });
+        let found = !downloads.is_empty();
+        downloads.extend(other_tasks);
+        *q = downloads;
+        found
+    }
+
     /// Fetches a task batch, getting every existing entry from the queue, grouping by timelines and merging the tasks for every timeline.
     /// A timeline has to care to not to delete certain layers from the remote storage before the corresponding uploads happen.
     /// Other than that, due to "immutable" nature of the layers, the order of their deletion/uploading/downloading does not matter.
     /// Hence, we merge the layers together into single task per timeline and run those concurrently (with the deletion happening only after successful uploading).
+    /// Downloads over the batch download limits are left for the next batches.
+    /// Returns an empty batch if no task arrives for a second, to let the queued remote layer deletions proceed.
     fn next_task_batch(&self) -> (HashMap<ZTenantTimelineId, SyncTaskBatch>, usize) {
         // Wait for the first task in blocking fashion
         let mut q = self.queue.lock().unwrap();
-        while q.is_empty() {
+        if q.is_empty() {
             q = self
                 .condvar
                 .wait_timeout(q, Duration::from_millis(1000))
                 .unwrap()
                 .0;
 
-            if thread_mgr::is_shutdown_requested() {
+            if q.is_empty() || thread_mgr::is_shutdown_requested() {
                 return (HashMap::new(), q.len());
             }
         }
@@ -506,26 +734,62 @@ impl SyncQueue {
         let mut timelines_left_to_batch = self.max_timelines_per_batch.get() - 1;
         let tasks_to_process = q.len();
 
+        let mut downloads_in_batch = 0;
+        let mut tenant_downloads_in_batch = HashMap::<ZTenantId, usize>::new();
+        if matches!(first_task, SyncTask::Download(_)) {
+            downloads_in_batch += 1;
+            tenant_downloads_in_batch.insert(first_sync_id.tenant_id, 1);
+        }
+
         let mut batches = HashMap::with_capacity(tasks_to_process);
         batches.insert(first_sync_id, SyncTaskBatch::new(first_task));
 
         let mut tasks_to_reenqueue = Vec::with_capacity(tasks_to_process);
 
         // Greedily grab as many other tasks that we can.
-        // Yet do not put all timelines in the batch, but only the first ones that fit the timeline limit.
+        // Yet do not put all timelines in the batch, but only the first ones that fit the timeline limit,
+        // and only the first downloads that fit the download limits.
         // Re-enqueue the tasks that don't fit in this batch.
         while let Some((sync_id, new_task)) = q.pop_front() {
+            let is_new_download = matches!(new_task, SyncTask::Download(_))
+                && !matches!(
+                    batches.get(&sync_id),
+                    Some(SyncTaskBatch {
+                        download: Some(_),
+                        ..
+                    })
+                );
+            if is_new_download {
+                let tenant_downloads = tenant_downloads_in_batch
+                    .get(&sync_id.tenant_id)
+                    .copied()
+                    .unwrap_or(0);
+                if downloads_in_batch >= self.max_downloads_per_batch.get()
+                    || tenant_downloads >= self.max_tenant_downloads_per_batch.get()
+                {
+                    tasks_to_reenqueue.push((sync_id, new_task));
+                    continue;
+                }
+            }
+
             match batches.entry(sync_id) {
                 hash_map::Entry::Occupied(mut v) => v.get_mut().add(new_task),
                 hash_map::Entry::Vacant(v) => {
                     timelines_left_to_batch = timelines_left_to_batch.saturating_sub(1);
                     if timelines_left_to_batch == 0 {
                         tasks_to_reenqueue.push((sync_id, new_task));
+                        continue;
                     } else {
                         v.insert(SyncTaskBatch::new(new_task));
                     }
                 }
             }
+            if is_new_download {
+                downloads_in_batch += 1;
+                *tenant_downloads_in_batch
+                    .entry(sync_id.tenant_id)
+                    .or_default() += 1;
+            }
         }
 
         debug!(
@@ -624,6 +888,9 @@ impl SyncTaskBatch {
                     batch_data
                         .uploaded_layers
                         .extend(new_data.uploaded_layers.into_iter());
+                    batch_data
+                        .layer_generations
+                        .extend(new_data.layer_generations.into_iter());
                     if batch_data
                         .metadata
                         .as_ref()
@@ -665,6 +932,10 @@ impl SyncTaskBatch {
                         };
                         batch_upload.data.layers_to_upload.retain(not_deleted);
                         batch_upload.data.uploaded_layers.retain(not_deleted);
+                        batch_upload
+                            .data
+                            .layer_generations
+                            .retain(|layer, _| not_deleted(layer));
                     }
 
                     batch_delete
@@ -675,6 +946,10 @@ impl SyncTaskBatch {
                         .data
                         .deleted_layers
                         .extend(new_delete.data.deleted_layers.into_iter());
+                    batch_delete
+                        .data
+                        .layer_generations
+                        .extend(new_delete.data.layer_generations.into_iter());
                 }
                 None => self.delete = Some(new_delete),
             },
@@ -691,6 +966,8 @@ struct LayersUpload {
     /// Already uploaded layers. Used to store the data about the uploads between task retries
     /// and to record the data into the remote index after the task got completed or evicted.
     uploaded_layers: HashSet<PathBuf>,
+    /// Tenant generations the uploaded layers were uploaded with, see [`index::remote_layer_path`].
+    layer_generations: HashMap<PathBuf, u32>,
     metadata: Option<TimelineMetadata>,
 }
 
@@ -714,6 +991,9 @@ struct LayersDeletion {
     /// the corresponding files on S3 won't exist for pageserver albeit being physically present on that remote storage still.
     /// Then all that's left is to remove the files from the remote storage, without concerns about consistency.
     deletion_registered: bool,
+    /// Tenant generations the layers to delete were uploaded with, taken from the remote index
+    /// when the deletion gets registered, before the layers are removed from it.
+    layer_generations: HashMap<PathBuf, u32>,
 }
 
 /// Adds the new checkpoint files as an upload sync task to the queue.
@@ -741,6 +1021,7 @@ pub fn schedule_layer_upload(
         SyncTask::upload(LayersUpload {
             layers_to_upload,
             uploaded_layers: HashSet::new(),
+            layer_generations: HashMap::new(),
             metadata,
         }),
     );
@@ -772,11 +1053,22 @@ pub fn schedule_layer_delete(
             layers_to_delete,
             deleted_layers: HashSet::new(),
             deletion_registered: false,
+            layer_generations: HashMap::new(),
         }),
     );
     debug!("Deletion task for tenant {tenant_id}, timeline {timeline_id} sent")
 }
 
+/// Moves the queued download of the timeline ahead of the other tasks, e.g. because a compute is waiting for it.
+/// Does nothing if the timeline has no download queued.
+pub fn prioritize_download(sync_id: ZTenantTimelineId) {
+    if let Some(sync_queue) = SYNC_QUEUE.get() {
+        if sync_queue.prioritize_download(sync_id) {
+            info!("Prioritized the download of timeline {sync_id}");
+        }
+    }
+}
+
 /// Requests the download of the entire timeline for a given tenant.
 /// No existing local files are currently overwritten, except the metadata file (if its disk_consistent_lsn is less than the downloaded one).
 /// The metadata file is always updated last, to avoid inconsistencies.
@@ -805,20 +1097,216 @@ pub fn schedule_layer_download(tenant_id: ZTenantId, timeline_id: ZTimelineId) {
     debug!("Download task for tenant {tenant_id}, timeline {timeline_id} sent")
 }
 
+/// Returned when an index part upload is refused, because the remote index part was written
+/// by a newer tenant generation: some other pageserver owns the tenant now.
+#[derive(Debug, thiserror::Error)]
+#[error("tenant {tenant_id} is owned by a newer generation {remote_generation:?}, our generation is {our_generation:?}")]
+pub struct StaleGenerationError {
+    pub tenant_id: ZTenantId,
+    pub our_generation: Option<u32>,
+    pub remote_generation: Option<u32>,
+}
+
+fn is_stale_generation_error(error: &anyhow::Error) -> bool {
+    error.downcast_ref::<StaleGenerationError>().is_some()
+}
+
+/// Uploads the index part, stamped with the current tenant generation.
+/// Before that, checks that the remote index part was not written by a newer generation,
+/// and that it does not have a newer remote consistent LSN: that happens when another pageserver
+/// uploads the same timeline without generations configured, and overwriting its index part
+/// would lose the references to its layers.
+///
+/// If `remote_index_part_exists` is false, the timeline has no index part in the remote storage yet
+/// as far as this pageserver knows, so failing to download it is not an error.
+async fn publish_index_part<P, S>(
+    conf: &'static PageServerConf,
+    storage: &S,
+    sync_id: ZTenantTimelineId,
+    mut index_part: IndexPart,
+    remote_index_part_exists: bool,
+) -> anyhow::Result<()>
+where
+    P: Debug + Send + Sync + 'static,
+    S: RemoteStorage<RemoteObjectId = P> + Send + Sync + 'static,
+{
+    let our_generation = tenant_mgr::get_tenant_generation(conf, sync_id.tenant_id)
+        .context("Failed to get the tenant generation")?;
+
+    match download_index_part(conf, storage, sync_id).await {
+        Ok(remote_index_part) => {
+            let remote_generation = remote_index_part.generation();
+            if remote_generation > our_generation {
+                return Err(StaleGenerationError {
+                    tenant_id: sync_id.tenant_id,
+                    our_generation,
+                    remote_generation,
+                }
+                .into());
+            }
+            if remote_generation == our_generation
+                && remote_index_part.remote_consistent_lsn() > index_part.remote_consistent_lsn()
+            {
+                bail!(
+                    "Remote index part of timeline {sync_id} has a newer remote consistent lsn {:?} than ours {:?}, \
+                    is another pageserver uploading the timeline?",
+                    remote_index_part.remote_consistent_lsn(),
+                    index_part.remote_consistent_lsn(),
+                );
+            }
+        }
+        Err(e) if !remote_index_part_exists => {
+            debug!("No remote index part for timeline {sync_id} to check the generation against: {e:#}");
+        }
+        Err(e) => {
+            return Err(
+                e.context("Failed to download the remote index part to check its generation")
+            )
+        }
+    }
+
+    index_part.set_generation(our_generation);
+    upload_index_part(conf, storage, sync_id, index_part).await
+}
+
+/// Sets the archived flag for the timeline in the remote index and uploads the updated index part.
+/// The timeline has to be present in the remote index already.
+pub async fn set_timeline_archived<P, S>(
+    conf: &'static PageServerConf,
+    storage: &S,
+    index: &RemoteIndex,
+    sync_id: ZTenantTimelineId,
+    archived: bool,
+) -> anyhow::Result<()>
+where
+    P: Debug + Send + Sync + 'static,
+    S: RemoteStorage<RemoteObjectId = P> + Send + Sync + 'static,
+{
+    let mut index_accessor = index.write().await;
+    let remote_timeline = index_accessor
+        .timeline_entry_mut(&sync_id)
+        .ok_or_else(|| anyhow!("Timeline {sync_id} is not present in the remote index"))?;
+
+    let mut updated_remote_timeline = remote_timeline.clone();
+    updated_remote_timeline.archived = archived;
+    let timeline_path = conf.timeline_path(&sync_id.timeline_id, &sync_id.tenant_id);
+    let new_index_part =
+        IndexPart::from_remote_timeline(&timeline_path, updated_remote_timeline)
+            .context("Failed to create an index part from the updated remote timeline")?;
+
+    // Update the in-memory index only after the remote one got updated successfully
+    publish_index_part(conf, storage, sync_id, new_index_part, true)
+        .await
+        .context("Failed to upload new index part")?;
+    remote_timeline.archived = archived;
+    Ok(())
+}
+
+/// Creates the remote timelines of a new tenant as clones of all remote timelines of the source tenant,
+/// without copying any layer files: the cloned timelines reference the layers stored under the source
+/// tenant. The source timelines get marked as having clones, so their layers are never deleted from
+/// the remote storage, and the cloned ones never delete the layers they don't store themselves.
+///
+/// The cloned timelines are added to the remote index, it's up to the caller to schedule their download.
+/// Returns the ids of the cloned timelines, with the flag telling if the timeline is archived.
+pub async fn clone_remote_tenant<P, S>(
+    conf: &'static PageServerConf,
+    storage: &S,
+    index: &RemoteIndex,
+    source_tenant_id: ZTenantId,
+    new_tenant_id: ZTenantId,
+) -> anyhow::Result<Vec<(ZTimelineId, bool)>>
+where
+    P: Debug + Send + Sync + 'static,
+    S: RemoteStorage<RemoteObjectId = P> + Send + Sync + 'static,
+{
+    let mut index_accessor = index.write().await;
+    ensure!(
+        index_accessor
+            .tenant_timelines(new_tenant_id)
+            .next()
+            .is_none(),
+        "Tenant {new_tenant_id} already has remote timelines"
+    );
+    let source_timelines = index_accessor
+        .tenant_timelines(source_tenant_id)
+        .map(|(timeline_id, remote_timeline)| (timeline_id, remote_timeline.clone()))
+        .collect::<Vec<_>>();
+    ensure!(
+        !source_timelines.is_empty(),
+        "Tenant {source_tenant_id} has no remote timelines to clone"
+    );
+
+    let mut cloned_timelines = Vec::with_capacity(source_timelines.len());
+    for (timeline_id, mut source_timeline) in source_timelines {
+        let source_sync_id = ZTenantTimelineId {
+            tenant_id: source_tenant_id,
+            timeline_id,
+        };
+        let source_timeline_path = conf.timeline_path(&timeline_id, &source_tenant_id);
+        // Mark the source first, so it doesn't delete any layers the clone can reference
+        if !source_timeline.has_clones {
+            source_timeline.has_clones = true;
+            let source_index_part =
+                IndexPart::from_remote_timeline(&source_timeline_path, source_timeline.clone())
+                    .context("Failed to create an index part from the source remote timeline")?;
+            publish_index_part(conf, storage, source_sync_id, source_index_part, true)
+                .await
+                .with_context(|| {
+                    format!("Failed to upload index part for source timeline {source_sync_id}")
+                })?;
+            if let Some(remote_timeline) = index_accessor.timeline_entry_mut(&source_sync_id) {
+                remote_timeline.has_clones = true;
+            }
+        }
+
+        let clone_sync_id = ZTenantTimelineId {
+            tenant_id: new_tenant_id,
+            timeline_id,
+        };
+        let clone_timeline_path = conf.timeline_path(&timeline_id, &new_tenant_id);
+        let cloned_timeline = source_timeline.clone_for_tenant(
+            &source_timeline_path,
+            &clone_timeline_path,
+            source_tenant_id,
+        )?;
+        let clone_index_part =
+            IndexPart::from_remote_timeline(&clone_timeline_path, cloned_timeline.clone())
+                .context("Failed to create an index part from the cloned remote timeline")?;
+        publish_index_part(conf, storage, clone_sync_id, clone_index_part, false)
+            .await
+            .with_context(|| {
+                format!("Failed to upload index part for cloned timeline {clone_sync_id}")
+            })?;
+
+        info!("Cloned remote timeline {source_sync_id} into {clone_sync_id}");
+        cloned_timelines.push((timeline_id, cloned_timeline.archived));
+        index_accessor.add_timeline_entry(clone_sync_id, cloned_timeline);
+    }
+
+    Ok(cloned_timelines)
+}
+
 /// Launch a thread to perform remote storage sync tasks.
 /// See module docs for loop step description.
 pub(super) fn spawn_storage_sync_thread<P, S>(
     conf: &'static PageServerConf,
     local_timeline_files: HashMap<ZTenantTimelineId, (TimelineMetadata, HashSet<PathBuf>)>,
+    archived_timelines: HashSet<ZTenantTimelineId>,
     storage: S,
-    max_concurrent_timelines_sync: NonZeroUsize,
-    max_sync_errors: NonZeroU32,
+    storage_config: &RemoteStorageConfig,
 ) -> anyhow::Result<SyncStartupData>
 where
     P: Debug + Send + Sync + 'static,
     S: RemoteStorage<RemoteObjectId = P> + Send + Sync + 'static,
 {
-    let sync_queue = SyncQueue::new(max_concurrent_timelines_sync);
+    let max_sync_errors = storage_config.max_sync_errors;
+    let mut sync_queue = SyncQueue::with_download_limits(
+        storage_config.max_concurrent_syncs,
+        storage_config.max_concurrent_downloads,
+        storage_config.max_concurrent_tenant_downloads,
+    );
+    sync_queue.deletions = RemoteDeletionQueue::new(conf.remote_deletion_delay);
     SYNC_QUEUE
         .set(sync_queue)
         .map_err(|_queue| anyhow!("Could not initialize sync queue"))?;
@@ -835,10 +1323,16 @@ where
     let applicable_index_parts = runtime.block_on(try_fetch_index_parts(
         conf,
         &storage,
-        local_timeline_files.keys().copied().collect(),
+        local_timeline_files
+            .keys()
+            .copied()
+            .chain(archived_timelines)
+            .collect(),
     ));
 
     let remote_index = RemoteIndex::from_parts(conf, applicable_index_parts)?;
+    runtime.block_on(reconcile_remote_timelines(conf, &storage, &remote_index));
+    resume_pending_deletions(&runtime.block_on(remote_index.read()), sync_queue);
 
     let local_timeline_init_statuses = schedule_first_sync_tasks(
         &mut runtime.block_on(remote_index.write()),
@@ -846,6 +1340,14 @@ where
         local_timeline_files,
     );
 
+    let storage = Arc::new(storage);
+    if conf.stream_layer_uploads {
+        layer_stream::spawn_layer_stream_thread(
+            Arc::clone(&storage),
+            storage_config.multipart_upload_part_size,
+        )?;
+    }
+
     let remote_index_clone = remote_index.clone();
     thread_mgr::spawn(
         ThreadKind::StorageSync,
@@ -857,7 +1359,7 @@ where
             storage_sync_loop(
                 runtime,
                 conf,
-                (Arc::new(storage), remote_index_clone, sync_queue),
+                (storage, remote_index_clone, sync_queue),
                 max_sync_errors,
             );
             Ok(())
@@ -890,7 +1392,13 @@ fn storage_sync_loop<P, S>(
             break;
         }
 
+        runtime.block_on(
+            delete_due_layers(storage.as_ref(), &index, sync_queue, max_sync_errors)
+                .instrument(info_span!("delete_due_layers")),
+        );
+
         REMAINING_SYNC_ITEMS.set(remaining_queue_length as i64);
+        thread_mgr::record_activity();
         if remaining_queue_length > 0 || !batched_tasks.is_empty() {
             info!("Processing tasks for {} timelines in batch, more tasks left to process: {remaining_queue_length}", batched_tasks.len());
         } else {
@@ -1067,12 +1575,23 @@ where
                         )
                         .await;
                     }
-                    ControlFlow::Break(_) => {
-                        index
+                    ControlFlow::Break(failed_download_data) => {
+                        download::forget_download_progress(&sync_id);
+                        if let Some(remote_timeline) = current_remote_timeline.as_ref() {
+                            download::quarantine_missing_layers(
+                                sync_id,
+                                remote_timeline,
+                                &failed_download_data.data.layers_to_skip,
+                            );
+                        }
+                        if index
                             .write()
                             .await
                             .set_awaits_download(&sync_id, false)
-                            .ok();
+                            .is_ok()
+                        {
+                            notify_download_abandoned(conf, sync_id);
+                        }
                     }
                 }
             }
@@ -1138,6 +1657,10 @@ where
     match download_timeline_layers(
         conf,
         storage,
+        FALLBACK_STORAGES
+            .get()
+            .map(Vec::as_slice)
+            .unwrap_or_default(),
         sync_queue,
         current_remote_timeline,
         sync_id,
@@ -1147,8 +1670,9 @@ where
     {
         DownloadedTimeline::Abort => {
             register_sync_status(sync_start, task_name, None);
-            if let Err(e) = index.write().await.set_awaits_download(&sync_id, false) {
-                error!("Timeline {sync_id} was expected to be in the remote index after a download attempt, but it's absent: {e:?}");
+            match index.write().await.set_awaits_download(&sync_id, false) {
+                Ok(()) => notify_download_abandoned(conf, sync_id),
+                Err(e) => error!("Timeline {sync_id} was expected to be in the remote index after a download attempt, but it's absent: {e:?}"),
             }
         }
         DownloadedTimeline::FailedAndRescheduled => {
@@ -1178,6 +1702,17 @@ where
     None
 }
 
+/// Announces that the timeline won't be downloaded, unless it's present locally anyway.
+fn notify_download_abandoned(conf: &'static PageServerConf, sync_id: ZTenantTimelineId) {
+    if !metadata_path(conf, sync_id.timeline_id, sync_id.tenant_id).exists() {
+        compute_notify::notify(
+            sync_id.tenant_id,
+            sync_id.timeline_id,
+            TimelineState::CloudOnly,
+        );
+    }
+}
+
 async fn update_local_metadata(
     conf: &'static PageServerConf,
     sync_id: ZTenantTimelineId,
@@ -1253,25 +1788,63 @@ async fn delete_timeline_data<P, S>(
     let timeline_delete = &mut new_delete_data.data;
 
     if !timeline_delete.deletion_registered {
+        // Determine the layers that other tenants may still use, and the storage paths of the layers,
+        // before they get removed from the index
+        let layers_to_keep = match index.read().await.timeline_entry(&sync_id) {
+            Some(remote_timeline) => {
+                for layer in &timeline_delete.layers_to_delete {
+                    if let Some(generation) = remote_timeline.layer_generation(layer) {
+                        timeline_delete
+                            .layer_generations
+                            .insert(layer.clone(), generation);
+                    }
+                }
+                remote_timeline.layers_to_keep_remotely(&timeline_delete.layers_to_delete)
+            }
+            None => HashSet::new(),
+        };
         if let Err(e) = update_remote_data(
             conf,
             storage,
             index,
             sync_id,
-            RemoteDataUpdate::Delete(&timeline_delete.layers_to_delete),
+            RemoteDataUpdate::RegisterDeletion {
+                layers_to_delete: &timeline_delete.layers_to_delete,
+                due_at: sync_queue.deletions.due_at(),
+            },
         )
         .await
         {
-            error!("Failed to update remote timeline {sync_id}: {e:?}");
-            new_delete_data.retries += 1;
-            sync_queue.push(sync_id, SyncTask::Delete(new_delete_data));
+            if is_stale_generation_error(&e) {
+                error!("Not updating remote timeline {sync_id}, dropping the delete task: {e:?}");
+            } else {
+                error!("Failed to update remote timeline {sync_id}: {e:?}");
+                new_delete_data.retries += 1;
+                sync_queue.push(sync_id, SyncTask::Delete(new_delete_data));
+            }
             register_sync_status(sync_start, task_name, Some(false));
             return;
         }
+        // The tenant could have been cloned in the meantime, with the layers still in the index
+        let layers_to_keep = match index.read().await.timeline_entry(&sync_id) {
+            Some(remote_timeline) if remote_timeline.has_clones => {
+                timeline_delete.layers_to_delete.clone()
+            }
+            _ => layers_to_keep,
+        };
+        if !layers_to_keep.is_empty() {
+            info!(
+                "Keeping {} layers shared with other tenants in the remote storage",
+                layers_to_keep.len()
+            );
+            timeline_delete
+                .layers_to_delete
+                .retain(|layer| !layers_to_keep.contains(layer));
+        }
     }
     timeline_delete.deletion_registered = true;
 
-    let sync_status = delete_timeline_layers(storage, sync_queue, sync_id, new_delete_data).await;
+    let sync_status = delete_timeline_layers(sync_queue, sync_id, new_delete_data);
     register_sync_status(sync_start, task_name, Some(sync_status));
 }
 
@@ -1289,19 +1862,41 @@ async fn upload_timeline_data<P, S>(
     (storage, index, sync_queue): (&S, &RemoteIndex, &SyncQueue),
     current_remote_timeline: Option<&RemoteTimeline>,
     sync_id: ZTenantTimelineId,
-    new_upload_data: SyncData<LayersUpload>,
+    mut new_upload_data: SyncData<LayersUpload>,
     sync_start: Instant,
     task_name: &str,
 ) where
     P: Debug + Send + Sync + 'static,
     S: RemoteStorage<RemoteObjectId = P> + Send + Sync + 'static,
 {
+    // The layers get uploaded under the keys of the current generation
+    let generation = match tenant_mgr::get_tenant_generation(conf, sync_id.tenant_id) {
+        Ok(generation) => generation,
+        Err(e) => {
+            error!(
+                "Failed to get the generation of tenant {}: {e:?}",
+                sync_id.tenant_id
+            );
+            new_upload_data.retries += 1;
+            sync_queue.push(sync_id, SyncTask::Upload(new_upload_data));
+            register_sync_status(sync_start, task_name, Some(false));
+            return;
+        }
+    };
+
     let mut uploaded_data = match upload_timeline_layers(
         storage,
         sync_queue,
         current_remote_timeline,
         sync_id,
         new_upload_data,
+        generation,
+        conf.remote_storage_config
+            .as_ref()
+            .map(|storage_config| storage_config.multipart_upload_part_size)
+            .unwrap_or_else(|| {
+                NonZeroUsize::new(DEFAULT_REMOTE_STORAGE_MULTIPART_UPLOAD_PART_SIZE).unwrap()
+            }),
     )
     .await
     {
@@ -1327,6 +1922,10 @@ async fn upload_timeline_data<P, S>(
         Ok(()) => {
             register_sync_status(sync_start, task_name, Some(true));
         }
+        Err(e) if is_stale_generation_error(&e) => {
+            error!("Not updating remote timeline {sync_id}, dropping the upload task: {e:?}");
+            register_sync_status(sync_start, task_name, Some(false));
+        }
         Err(e) => {
             error!("Failed to update remote timeline {sync_id}: {e:?}");
             uploaded_data.retries += 1;
@@ -1342,6 +1941,12 @@ enum RemoteDataUpdate<'a> {
         upload_failed: bool,
     },
     Delete(&'a HashSet<PathBuf>),
+    /// Removes the layers from the index and records them as pending deletions, due at 'due_at'
+    /// seconds since the epoch, see [`RemoteTimeline::register_layer_deletions`].
+    RegisterDeletion {
+        layers_to_delete: &'a HashSet<PathBuf>,
+        due_at: u64,
+    },
 }
 
 async fn update_remote_data<P, S>(
@@ -1355,7 +1960,20 @@ where
     P: Debug + Send + Sync + 'static,
     S: RemoteStorage<RemoteObjectId = P> + Send + Sync + 'static,
 {
-    let updated_remote_timeline = {
+    // All layers of the checkpoint got uploaded, so the remote consistent LSN can advance to it
+    // once the index part that references them is uploaded too
+    let uploaded_lsn = match &update {
+        RemoteDataUpdate::Upload {
+            uploaded_data,
+            upload_failed: false,
+        } => uploaded_data
+            .metadata
+            .as_ref()
+            .map(|metadata| metadata.disk_consistent_lsn()),
+        _ => None,
+    };
+
+    let (mut updated_remote_timeline, remote_index_part_exists) = {
         let mut index_accessor = index.write().await;
 
         match index_accessor.timeline_entry_mut(&sync_id) {
@@ -1365,50 +1983,58 @@ where
                         uploaded_data,
                         upload_failed,
                     } => {
-                        if let Some(new_metadata) = uploaded_data.metadata.as_ref() {
-                            if existing_entry.metadata.disk_consistent_lsn()
-                                < new_metadata.disk_consistent_lsn()
-                            {
-                                existing_entry.metadata = new_metadata.clone();
-                            }
-                        }
                         if upload_failed {
+                            // The metadata stays at the last checkpoint with all layers uploaded
                             existing_entry.add_upload_failures(
                                 uploaded_data.layers_to_upload.iter().cloned(),
                             );
                         } else {
-                            existing_entry
-                                .add_timeline_layers(uploaded_data.uploaded_layers.iter().cloned());
+                            if let Some(new_metadata) = uploaded_data.metadata.as_ref() {
+                                if existing_entry.metadata.disk_consistent_lsn()
+                                    < new_metadata.disk_consistent_lsn()
+                                {
+                                    existing_entry.metadata = new_metadata.clone();
+                                }
+                            }
+                            existing_entry.add_timeline_layers_with_generations(
+                                uploaded_data.uploaded_layers.iter().cloned(),
+                                &uploaded_data.layer_generations,
+                            );
                         }
                     }
                     RemoteDataUpdate::Delete(layers_to_remove) => {
                         existing_entry.remove_layers(layers_to_remove)
                     }
+                    RemoteDataUpdate::RegisterDeletion {
+                        layers_to_delete,
+                        due_at,
+                    } => existing_entry.register_layer_deletions(layers_to_delete, due_at),
                 }
-                existing_entry.clone()
+                (existing_entry.clone(), true)
             }
             None => match update {
                 RemoteDataUpdate::Upload {
                     uploaded_data,
                     upload_failed,
                 } => {
+                    if upload_failed {
+                        warn!("Upload of timeline {sync_id} failed and it has no remote index entry, not creating one");
+                        return Ok(());
+                    }
                     let new_metadata = match uploaded_data.metadata.as_ref() {
                         Some(new_metadata) => new_metadata,
                         None => bail!("For timeline {sync_id} upload, there's no upload metadata and no remote index entry, cannot create a new one"),
                     };
                     let mut new_remote_timeline = RemoteTimeline::new(new_metadata.clone());
-                    if upload_failed {
-                        new_remote_timeline
-                            .add_upload_failures(uploaded_data.layers_to_upload.iter().cloned());
-                    } else {
-                        new_remote_timeline
-                            .add_timeline_layers(uploaded_data.uploaded_layers.iter().cloned());
-                    }
+                    new_remote_timeline.add_timeline_layers_with_generations(
+                        uploaded_data.uploaded_layers.iter().cloned(),
+                        &uploaded_data.layer_generations,
+                    );
 
                     index_accessor.add_timeline_entry(sync_id, new_remote_timeline.clone());
-                    new_remote_timeline
+                    (new_remote_timeline, false)
                 }
-                RemoteDataUpdate::Delete(_) => {
+                RemoteDataUpdate::Delete(_) | RemoteDataUpdate::RegisterDeletion { .. } => {
                     warn!("No remote index entry for timeline {sync_id}, skipping deletion");
                     return Ok(());
                 }
@@ -1416,15 +2042,39 @@ where
         }
     };
 
+    if let Some(uploaded_lsn) = uploaded_lsn {
+        updated_remote_timeline.advance_remote_consistent_lsn(uploaded_lsn);
+    }
     let timeline_path = conf.timeline_path(&sync_id.timeline_id, &sync_id.tenant_id);
     let new_index_part =
         IndexPart::from_remote_timeline(&timeline_path, updated_remote_timeline)
             .context("Failed to create an index part from the updated remote timeline")?;
 
     info!("Uploading remote index for the timeline");
-    upload_index_part(conf, storage, sync_id, new_index_part)
-        .await
-        .context("Failed to upload new index part")
+    publish_index_part(
+        conf,
+        storage,
+        sync_id,
+        new_index_part,
+        remote_index_part_exists,
+    )
+    .await
+    .context("Failed to upload new index part")?;
+
+    if let Some(uploaded_lsn) = uploaded_lsn {
+        if let Some(remote_timeline) = index.write().await.timeline_entry_mut(&sync_id) {
+            remote_timeline.advance_remote_consistent_lsn(uploaded_lsn);
+            // The LSN of one timeline can't be summed up with the others
+            if let Some((tenant_id, timeline_id)) =
+                metric_labels::own_timeline_labels(&sync_id.tenant_id, &sync_id.timeline_id)
+            {
+                REMOTE_CONSISTENT_LSN
+                    .with_label_values(&[&tenant_id, &timeline_id])
+                    .set(remote_timeline.remote_consistent_lsn().0 as i64);
+            }
+        }
+    }
+    Ok(())
 }
 
 async fn validate_task_retries<T>(
@@ -1477,6 +2127,118 @@ where
     index_parts
 }
 
+///
+/// Checks the remote timelines whose metadata is ahead of their uploaded layers, see
+/// [`RemoteTimeline::metadata_ahead_of_layers`], for layers that their index part references,
+/// but the remote storage doesn't have. Such index parts were uploaded by the older versions of
+/// the upload, which published the metadata even if some of its layers failed to upload.
+///
+/// The absent layers are moved to the missing ones, so that the first sync tasks upload them again
+/// from the local files. If all layers are in place and none are missing, the remote consistent
+/// LSN catches up with the metadata. Either way, the corrected index part gets published.
+///
+async fn reconcile_remote_timelines<P, S>(
+    conf: &'static PageServerConf,
+    storage: &S,
+    index: &RemoteIndex,
+) where
+    P: Debug + Send + Sync + 'static,
+    S: RemoteStorage<RemoteObjectId = P> + Send + Sync + 'static,
+{
+    let timelines_to_check = {
+        let index_accessor = index.read().await;
+        index_accessor
+            .all_sync_ids()
+            .filter_map(|sync_id| {
+                let remote_timeline = index_accessor.timeline_entry(&sync_id)?;
+                if remote_timeline.metadata_ahead_of_layers() {
+                    Some((sync_id, remote_timeline.clone()))
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<_>>()
+    };
+
+    for (sync_id, remote_timeline) in timelines_to_check {
+        let absent_layers = match find_absent_layers(storage, &remote_timeline).await {
+            Ok(absent_layers) => absent_layers,
+            Err(e) => {
+                warn!("Failed to check the remote layers of timeline {sync_id}: {e:?}");
+                continue;
+            }
+        };
+
+        let reconciled_timeline = {
+            let mut index_accessor = index.write().await;
+            let remote_timeline = match index_accessor.timeline_entry_mut(&sync_id) {
+                Some(remote_timeline) => remote_timeline,
+                None => continue,
+            };
+            if !absent_layers.is_empty() {
+                error!(
+                    "Remote index of timeline {sync_id} references layers absent in the remote storage: {absent_layers:?}"
+                );
+                remote_timeline.mark_layers_missing(absent_layers);
+            } else if remote_timeline.missing_layers().is_empty() {
+                let disk_consistent_lsn = remote_timeline.metadata.disk_consistent_lsn();
+                info!("All remote layers of timeline {sync_id} are in place, remote consistent lsn is {disk_consistent_lsn}");
+                remote_timeline.advance_remote_consistent_lsn(disk_consistent_lsn);
+            } else {
+                continue;
+            }
+            remote_timeline.clone()
+        };
+
+        let timeline_path = conf.timeline_path(&sync_id.timeline_id, &sync_id.tenant_id);
+        let publish_result =
+            match IndexPart::from_remote_timeline(&timeline_path, reconciled_timeline) {
+                Ok(index_part) => {
+                    publish_index_part(conf, storage, sync_id, index_part, true).await
+                }
+                Err(e) => Err(e),
+            };
+        if let Err(e) = publish_result {
+            warn!("Failed to publish the reconciled remote index of timeline {sync_id}: {e:?}");
+        }
+    }
+}
+
+/// Returns the layers of the timeline, stored under it in the remote storage, that the storage doesn't have.
+async fn find_absent_layers<P, S>(
+    storage: &S,
+    remote_timeline: &RemoteTimeline,
+) -> anyhow::Result<HashSet<PathBuf>>
+where
+    P: Debug + Send + Sync + 'static,
+    S: RemoteStorage<RemoteObjectId = P> + Send + Sync + 'static,
+{
+    let mut layer_checks = remote_timeline
+        .stored_files()
+        .iter()
+        // Borrowed layers are checked with the timelines that store them
+        .filter(|layer| remote_timeline.layer_owner(layer).is_none())
+        .map(|layer| async move {
+            let storage_path =
+                storage.remote_object_id(&remote_timeline.remote_layer_path(layer))?;
+            let size = storage
+                .object_size(&storage_path)
+                .await
+                .with_context(|| format!("Failed to check the layer at '{storage_path:?}'"))?;
+            anyhow::Ok((layer, size.is_none()))
+        })
+        .collect::<FuturesUnordered<_>>();
+
+    let mut absent_layers = HashSet::new();
+    while let Some(layer_check) = layer_checks.next().await {
+        let (layer, is_absent) = layer_check?;
+        if is_absent {
+            absent_layers.insert(layer.clone());
+        }
+    }
+    Ok(absent_layers)
+}
+
 fn schedule_first_sync_tasks(
     index: &mut RemoteTimelineIndex,
     sync_queue: &SyncQueue,
@@ -1519,6 +2281,7 @@ fn schedule_first_sync_tasks(
                     SyncTask::upload(LayersUpload {
                         layers_to_upload: local_files,
                         uploaded_layers: HashSet::new(),
+                        layer_generations: HashMap::new(),
                         metadata: Some(local_metadata),
                     }),
                 ));
@@ -1581,6 +2344,7 @@ fn compare_local_and_remote_timeline(
             SyncTask::upload(LayersUpload {
                 layers_to_upload,
                 uploaded_layers: HashSet::new(),
+                layer_generations: HashMap::new(),
                 metadata: Some(local_metadata),
             }),
         ));
@@ -1634,6 +2398,7 @@ mod test_utils {
         Ok(LayersUpload {
             layers_to_upload,
             uploaded_layers: HashSet::new(),
+            layer_generations: HashMap::new(),
             metadata: Some(metadata),
         })
     }
@@ -1684,12 +2449,14 @@ mod tests {
         let upload_task = SyncTask::upload(LayersUpload {
             layers_to_upload: HashSet::from([PathBuf::from("up")]),
             uploaded_layers: HashSet::from([PathBuf::from("upl")]),
+            layer_generations: HashMap::new(),
             metadata: Some(dummy_metadata(Lsn(2))),
         });
         let delete_task = SyncTask::delete(LayersDeletion {
             layers_to_delete: HashSet::from([PathBuf::from("de")]),
             deleted_layers: HashSet::from([PathBuf::from("del")]),
             deletion_registered: false,
+            layer_generations: HashMap::new(),
         });
 
         sync_queue.push(TEST_SYNC_ID, download_task.clone());
@@ -1733,12 +2500,14 @@ mod tests {
         let upload = LayersUpload {
             layers_to_upload: HashSet::from([PathBuf::from("up")]),
             uploaded_layers: HashSet::from([PathBuf::from("upl")]),
+            layer_generations: HashMap::new(),
             metadata: Some(dummy_metadata(Lsn(2))),
         };
         let delete = LayersDeletion {
             layers_to_delete: HashSet::from([PathBuf::from("de")]),
             deleted_layers: HashSet::from([PathBuf::from("del")]),
             deletion_registered: false,
+            layer_generations: HashMap::new(),
         };
 
         sync_queue.push(TEST_SYNC_ID, SyncTask::download(download.clone()));
@@ -1839,4 +2608,337 @@ mod tests {
             "Should have one task left out of the batch"
         );
     }
+
+    #[tokio::test]
+    async fn download_limits_and_priority_batch() {
+        let sync_queue = SyncQueue::with_download_limits(
+            NonZeroUsize::new(100).unwrap(),
+            NonZeroUsize::new(2).unwrap(),
+            NonZeroUsize::new(1).unwrap(),
+        );
+        let tenant_a = ZTenantId::from_array(hex!("aa223344556677881122334455667788"));
+        let tenant_b = ZTenantId::from_array(hex!("bb223344556677881122334455667788"));
+        let tenant_c = ZTenantId::from_array(hex!("cc223344556677881122334455667788"));
+        let a1 = ZTenantTimelineId::new(tenant_a, TIMELINE_ID);
+        let a2 = ZTenantTimelineId::new(tenant_a, ZTimelineId::generate());
+        let b1 = ZTenantTimelineId::new(tenant_b, TIMELINE_ID);
+        let b2 = ZTenantTimelineId::new(tenant_b, ZTimelineId::generate());
+        let c1 = ZTenantTimelineId::new(tenant_c, TIMELINE_ID);
+
+        let download = || {
+            SyncTask::download(LayersDownload {
+                layers_to_skip: HashSet::new(),
+            })
+        };
+        let upload_task = SyncTask::upload(LayersUpload {
+            layers_to_upload: HashSet::from([PathBuf::from("up")]),
+            uploaded_layers: HashSet::new(),
+            layer_generations: HashMap::new(),
+            metadata: Some(dummy_metadata(Lsn(2))),
+        });
+
+        sync_queue.push(a1, download());
+        sync_queue.push(a2, download());
+        sync_queue.push(a2, upload_task.clone());
+        sync_queue.push(b1, download());
+        sync_queue.push(c1, download());
+
+        let (mut batch, remaining) = sync_queue.next_task_batch();
+        assert_eq!(remaining, 2, "Downloads over the limits should stay queued");
+        assert!(batch.remove(&a1).unwrap().download.is_some());
+        assert!(batch.remove(&b1).unwrap().download.is_some());
+        assert_eq!(
+            Some(SyncTaskBatch::new(upload_task)),
+            batch.remove(&a2),
+            "Uploads should not be held back by the download limits"
+        );
+        assert!(batch.is_empty(), "Should check all batch tasks");
+
+        sync_queue.push(b2, download());
+        assert!(!sync_queue.prioritize_download(b1));
+        assert!(sync_queue.prioritize_download(b2));
+
+        let (mut batch, remaining) = sync_queue.next_task_batch();
+        assert_eq!(remaining, 1);
+        assert!(
+            batch.remove(&b2).is_some(),
+            "Prioritized download should be in the batch"
+        );
+        assert!(batch.remove(&a2).is_some());
+        assert!(batch.is_empty(), "Should check all batch tasks");
+    }
+
+    #[tokio::test]
+    async fn index_part_publishing_checks_generation() -> anyhow::Result<()> {
+        use crate::repository::repo_harness::RepoHarness;
+        use remote_storage::LocalFs;
+
+        let harness = RepoHarness::create("index_part_publishing_checks_generation")?;
+        let sync_id = ZTenantTimelineId::new(harness.tenant_id, TIMELINE_ID);
+        let storage_dir = tempfile::tempdir()?;
+        let storage = LocalFs::new(storage_dir.path().to_owned(), harness.conf.workdir.clone())?;
+        let generation_path = harness
+            .conf
+            .tenant_path(&harness.tenant_id)
+            .join(tenant_mgr::TENANT_GENERATION_FILE_NAME);
+
+        let metadata = dummy_metadata(Lsn(0x40));
+        let index_part = IndexPart::new(
+            HashSet::new(),
+            HashSet::new(),
+            metadata.disk_consistent_lsn(),
+            metadata.to_bytes()?,
+        );
+
+        // Another pageserver with generation 2 has published the index part
+        let mut newer_index_part = index_part.clone();
+        newer_index_part.set_generation(Some(2));
+        upload_index_part(harness.conf, &storage, sync_id, newer_index_part).await?;
+
+        fs::write(&generation_path, "1").await?;
+        let error = publish_index_part(harness.conf, &storage, sync_id, index_part.clone(), true)
+            .await
+            .expect_err("Should not publish an index part with a stale generation");
+        assert!(
+            is_stale_generation_error(&error),
+            "Unexpected error: {error:?}"
+        );
+
+        fs::write(&generation_path, "3").await?;
+        publish_index_part(harness.conf, &storage, sync_id, index_part, true).await?;
+        let published = download_index_part(harness.conf, &storage, sync_id).await?;
+        assert_eq!(published.generation(), Some(3));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn index_part_publishing_checks_remote_consistent_lsn() -> anyhow::Result<()> {
+        use crate::repository::repo_harness::RepoHarness;
+        use remote_storage::LocalFs;
+
+        let harness = RepoHarness::create("index_part_publishing_checks_remote_consistent_lsn")?;
+        let sync_id = ZTenantTimelineId::new(harness.tenant_id, TIMELINE_ID);
+        let storage_dir = tempfile::tempdir()?;
+        let storage = LocalFs::new(storage_dir.path().to_owned(), harness.conf.workdir.clone())?;
+        let timeline_path = harness.timeline_path(&TIMELINE_ID);
+
+        let index_part_at = |lsn| {
+            let mut remote_timeline = RemoteTimeline::new(dummy_metadata(lsn));
+            remote_timeline.advance_remote_consistent_lsn(lsn);
+            IndexPart::from_remote_timeline(&timeline_path, remote_timeline)
+        };
+
+        // Another pageserver has uploaded the timeline further than we did
+        upload_index_part(harness.conf, &storage, sync_id, index_part_at(Lsn(0x40))?).await?;
+
+        let error = publish_index_part(
+            harness.conf,
+            &storage,
+            sync_id,
+            index_part_at(Lsn(0x20))?,
+            true,
+        )
+        .await
+        .expect_err("Should not publish an index part with an older remote consistent lsn");
+        assert!(
+            !is_stale_generation_error(&error),
+            "Unexpected error: {error:?}"
+        );
+
+        publish_index_part(
+            harness.conf,
+            &storage,
+            sync_id,
+            index_part_at(Lsn(0x60))?,
+            true,
+        )
+        .await?;
+        let published = download_index_part(harness.conf, &storage, sync_id).await?;
+        assert_eq!(published.remote_consistent_lsn(), Some(Lsn(0x60)));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failed_upload_keeps_remote_metadata() -> anyhow::Result<()> {
+        use crate::repository::repo_harness::RepoHarness;
+        use remote_storage::LocalFs;
+
+        let harness = RepoHarness::create("failed_upload_keeps_remote_metadata")?;
+        let sync_id = ZTenantTimelineId::new(harness.tenant_id, TIMELINE_ID);
+        let storage_dir = tempfile::tempdir()?;
+        let storage = LocalFs::new(storage_dir.path().to_owned(), harness.conf.workdir.clone())?;
+        let timeline_path = harness.timeline_path(&TIMELINE_ID);
+        let layer_a = timeline_path.join("a");
+        let layer_b = timeline_path.join("b");
+
+        let mut remote_timeline = RemoteTimeline::new(dummy_metadata(Lsn(0x20)));
+        remote_timeline.add_timeline_layers([layer_a.clone()]);
+        remote_timeline.advance_remote_consistent_lsn(Lsn(0x20));
+        upload_index_part(
+            harness.conf,
+            &storage,
+            sync_id,
+            IndexPart::from_remote_timeline(&timeline_path, remote_timeline.clone())?,
+        )
+        .await?;
+        let index = RemoteIndex::empty();
+        index
+            .write()
+            .await
+            .add_timeline_entry(sync_id, remote_timeline);
+
+        let upload_of_b = |upload_failed| RemoteDataUpdate::Upload {
+            uploaded_data: LayersUpload {
+                layers_to_upload: if upload_failed {
+                    HashSet::from([layer_b.clone()])
+                } else {
+                    HashSet::new()
+                },
+                uploaded_layers: if upload_failed {
+                    HashSet::new()
+                } else {
+                    HashSet::from([layer_b.clone()])
+                },
+                layer_generations: HashMap::new(),
+                metadata: Some(dummy_metadata(Lsn(0x40))),
+            },
+            upload_failed,
+        };
+
+        // The upload of the checkpoint at 0x40 gave up on its layer
+        update_remote_data(harness.conf, &storage, &index, sync_id, upload_of_b(true)).await?;
+        let published = RemoteTimeline::from_index_part(
+            &timeline_path,
+            download_index_part(harness.conf, &storage, sync_id).await?,
+        )?;
+        let remote_timeline = index
+            .read()
+            .await
+            .timeline_entry(&sync_id)
+            .cloned()
+            .unwrap();
+        assert_eq!(published, remote_timeline);
+        assert_eq!(remote_timeline.metadata.disk_consistent_lsn(), Lsn(0x20));
+        assert_eq!(remote_timeline.remote_consistent_lsn(), Lsn(0x20));
+        assert_eq!(
+            remote_timeline.stored_files(),
+            &HashSet::from([layer_a.clone()])
+        );
+        assert_eq!(
+            remote_timeline.missing_layers(),
+            &HashSet::from([layer_b.clone()])
+        );
+
+        // Uploaded after a restart
+        update_remote_data(harness.conf, &storage, &index, sync_id, upload_of_b(false)).await?;
+        let remote_timeline = index
+            .read()
+            .await
+            .timeline_entry(&sync_id)
+            .cloned()
+            .unwrap();
+        assert_eq!(remote_timeline.metadata.disk_consistent_lsn(), Lsn(0x40));
+        assert_eq!(remote_timeline.remote_consistent_lsn(), Lsn(0x40));
+        assert_eq!(
+            remote_timeline.stored_files(),
+            &HashSet::from([layer_a, layer_b])
+        );
+        assert!(remote_timeline.missing_layers().is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reconcile_remote_timelines_with_metadata_ahead() -> anyhow::Result<()> {
+        use super::test_utils::create_local_timeline;
+        use crate::repository::repo_harness::{RepoHarness, NEW_TIMELINE_ID};
+        use remote_storage::LocalFs;
+
+        let harness = RepoHarness::create("reconcile_remote_timelines_with_metadata_ahead")?;
+        let storage_dir = tempfile::tempdir()?;
+        let storage = LocalFs::new(storage_dir.path().to_owned(), harness.conf.workdir.clone())?;
+        let index = RemoteIndex::empty();
+
+        // Both timelines have their metadata published at 0x40, with the layers uploaded up to 0x20,
+        // and only one of them has all of its layers in the storage.
+        let mut timeline_paths = HashMap::new();
+        for (timeline_id, layers_in_storage) in [
+            (TIMELINE_ID, &["a"][..]),
+            (NEW_TIMELINE_ID, &["a", "b"][..]),
+        ] {
+            let sync_id = ZTenantTimelineId::new(harness.tenant_id, timeline_id);
+            let timeline_path = harness.timeline_path(&timeline_id);
+            let local_timeline = create_local_timeline(
+                &harness,
+                timeline_id,
+                &["a", "b"],
+                dummy_metadata(Lsn(0x40)),
+            )
+            .await?;
+            for layer in layers_in_storage {
+                let layer_path = timeline_path.join(layer);
+                let size = fs::metadata(&layer_path).await?.len() as usize;
+                storage
+                    .upload(
+                        fs::File::open(&layer_path).await?,
+                        size,
+                        &storage.remote_object_id(&layer_path)?,
+                        None,
+                    )
+                    .await?;
+            }
+
+            let mut remote_timeline = RemoteTimeline::new(dummy_metadata(Lsn(0x40)));
+            remote_timeline.add_timeline_layers(local_timeline.layers_to_upload.iter().cloned());
+            remote_timeline.advance_remote_consistent_lsn(Lsn(0x20));
+            upload_index_part(
+                harness.conf,
+                &storage,
+                sync_id,
+                IndexPart::from_remote_timeline(&timeline_path, remote_timeline.clone())?,
+            )
+            .await?;
+            index
+                .write()
+                .await
+                .add_timeline_entry(sync_id, remote_timeline);
+            timeline_paths.insert(timeline_id, timeline_path);
+        }
+
+        reconcile_remote_timelines(harness.conf, &storage, &index).await;
+
+        for (timeline_id, timeline_path) in timeline_paths {
+            let sync_id = ZTenantTimelineId::new(harness.tenant_id, timeline_id);
+            let remote_timeline = index
+                .read()
+                .await
+                .timeline_entry(&sync_id)
+                .cloned()
+                .unwrap();
+            let published = RemoteTimeline::from_index_part(
+                &timeline_path,
+                download_index_part(harness.conf, &storage, sync_id).await?,
+            )?;
+            assert_eq!(published, remote_timeline);
+
+            if timeline_id == TIMELINE_ID {
+                assert_eq!(
+                    remote_timeline.stored_files(),
+                    &HashSet::from([timeline_path.join("a")])
+                );
+                assert_eq!(
+                    remote_timeline.missing_layers(),
+                    &HashSet::from([timeline_path.join("b")])
+                );
+                assert_eq!(remote_timeline.remote_consistent_lsn(), Lsn(0x20));
+            } else {
+                assert!(remote_timeline.missing_layers().is_empty());
+                assert_eq!(remote_timeline.remote_consistent_lsn(), Lsn(0x40));
+            }
+        }
+
+        Ok(())
+    }
 }
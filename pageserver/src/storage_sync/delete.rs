@@ -1,27 +1,143 @@
 //! Timeline synchronization logic to delete a bulk of timeline's remote files from the remote storage.
+//!
+//! Layers are deleted from the remote storage in two steps. First, the deletion gets registered: the layers
+//! are removed from the remote index, and the index part without them is uploaded, so that nothing refers to
+//! the layers anymore. Then the layers wait in the [`RemoteDeletionQueue`] for `remote_deletion_delay` to pass,
+//! to let the readers of the older index parts finish, and get deleted in batches, with as few storage requests
+//! as possible: a single S3 `DeleteObjects` request takes up to 1000 keys.
+//!
+//! Right before the deletion, the layers are checked against the remote index again: a layer that got uploaded
+//! again to the same storage path in the meantime is kept. A layer that clones of the timeline may use now is
+//! kept too, and checked again after [`CLONED_LAYERS_RECHECK_PERIOD`].
+//!
+//! The index part records the pending deletions with their due time, see [`super::index::PendingLayerDeletion`],
+//! so the deletions still waiting when the pageserver stops are resumed after the restart, with
+//! [`resume_pending_deletions`]. A deleted layer is dropped from the index with the next index part upload;
+//! deleting it again after a restart in between is harmless.
 
-use anyhow::Context;
-use futures::stream::{FuturesUnordered, StreamExt};
-use tracing::{debug, error, info};
+use std::{
+    collections::VecDeque,
+    num::NonZeroU32,
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use lazy_static::lazy_static;
+use metrics::{register_int_counter, register_int_gauge, IntCounter, IntGauge};
+use tracing::{debug, error, info, warn};
 
 use crate::storage_sync::{SyncQueue, SyncTask};
 use remote_storage::RemoteStorage;
 use utils::zid::ZTenantTimelineId;
 
-use super::{LayersDeletion, SyncData};
+use super::{
+    index::{remote_layer_path, RemoteIndex, RemoteTimelineIndex},
+    LayersDeletion, SyncData,
+};
+
+/// Max number of layers to delete with one [`RemoteStorage::delete_objects`] call.
+const MAX_LAYERS_PER_DELETE: usize = 1000;
+
+/// How long the deletion of a layer waits before the next check, if the timeline got clones that may use it.
+const CLONED_LAYERS_RECHECK_PERIOD: Duration = Duration::from_secs(60 * 60);
+
+lazy_static! {
+    static ref PENDING_DELETIONS: IntGauge = register_int_gauge!(
+        "pageserver_remote_storage_pending_deletions",
+        "Number of remote layers waiting for the deletion delay to pass"
+    )
+    .expect("failed to define a metric");
+    static ref DELETED_LAYERS: IntCounter = register_int_counter!(
+        "pageserver_remote_storage_deleted_layers_total",
+        "Number of layers deleted from the remote storage"
+    )
+    .expect("failed to define a metric");
+}
+
+/// Remote layers with their deletion registered in the remote index, waiting to be deleted from the storage.
+pub(super) struct RemoteDeletionQueue {
+    delay: Duration,
+    pending: Mutex<VecDeque<PendingDeletion>>,
+}
+
+struct PendingDeletion {
+    sync_id: ZTenantTimelineId,
+    layer: PathBuf,
+    /// Tenant generation the layer was uploaded with, a part of its storage path.
+    generation: Option<u32>,
+    /// Seconds since the epoch, persisted in the index part.
+    due_at: u64,
+    retries: u32,
+}
+
+fn seconds_since_epoch(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_secs())
+        .unwrap_or(0)
+}
+
+impl RemoteDeletionQueue {
+    pub(super) fn new(delay: Duration) -> Self {
+        Self {
+            delay,
+            pending: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// The time the layers whose deletion gets registered now are due, in seconds since the epoch.
+    pub(super) fn due_at(&self) -> u64 {
+        seconds_since_epoch(SystemTime::now() + self.delay)
+    }
+
+    fn schedule(
+        &self,
+        sync_id: ZTenantTimelineId,
+        due_at: u64,
+        layers: impl IntoIterator<Item = (PathBuf, Option<u32>)>,
+    ) {
+        let mut pending = self.pending.lock().unwrap();
+        for (layer, generation) in layers {
+            pending.push_back(PendingDeletion {
+                sync_id,
+                layer,
+                generation,
+                due_at,
+                retries: 0,
+            });
+        }
+        PENDING_DELETIONS.set(pending.len() as i64);
+    }
 
-/// Attempts to remove the timleline layers from the remote storage.
+    fn requeue(&self, deletion: PendingDeletion) {
+        let mut pending = self.pending.lock().unwrap();
+        pending.push_back(deletion);
+        PENDING_DELETIONS.set(pending.len() as i64);
+    }
+
+    fn take_due(&self, now: SystemTime) -> Vec<PendingDeletion> {
+        let now = seconds_since_epoch(now);
+        let mut pending = self.pending.lock().unwrap();
+        let (due, not_due): (VecDeque<_>, VecDeque<_>) = pending
+            .drain(..)
+            .partition(|deletion| deletion.due_at <= now);
+        *pending = not_due;
+        PENDING_DELETIONS.set(pending.len() as i64);
+        due.into()
+    }
+
+    pub(super) fn len(&self) -> usize {
+        self.pending.lock().unwrap().len()
+    }
+}
+
+/// Queues the timeline layers for the deletion from the remote storage, see [`delete_due_layers`].
 /// If the task had not adjusted the metadata before, the deletion will fail.
-pub(super) async fn delete_timeline_layers<'a, P, S>(
-    storage: &'a S,
+pub(super) fn delete_timeline_layers(
     sync_queue: &SyncQueue,
     sync_id: ZTenantTimelineId,
     mut delete_data: SyncData<LayersDeletion>,
-) -> bool
-where
-    P: std::fmt::Debug + Send + Sync + 'static,
-    S: RemoteStorage<RemoteObjectId = P> + Send + Sync + 'static,
-{
+) -> bool {
     if !delete_data.data.deletion_registered {
         error!("Cannot delete timeline layers before the deletion metadata is not registered, reenqueueing");
         delete_data.retries += 1;
@@ -34,74 +150,164 @@ where
         return true;
     }
 
-    let layers_to_delete = delete_data
-        .data
+    let delete = &mut delete_data.data;
+    let layers_to_delete = delete
         .layers_to_delete
         .drain()
+        .map(|layer| {
+            let generation = delete.layer_generations.get(&layer).copied();
+            (layer, generation)
+        })
         .collect::<Vec<_>>();
     debug!("Layers to delete: {layers_to_delete:?}");
-    info!("Deleting {} timeline layers", layers_to_delete.len());
-
-    let mut delete_tasks = layers_to_delete
-        .into_iter()
-        .map(|local_layer_path| async {
-            let storage_path =
-                match storage
-                    .remote_object_id(&local_layer_path)
-                    .with_context(|| {
-                        format!(
-                            "Failed to get the layer storage path for local path '{}'",
-                            local_layer_path.display()
-                        )
-                    }) {
-                    Ok(path) => path,
-                    Err(e) => return Err((e, local_layer_path)),
-                };
-
-            match storage.delete(&storage_path).await.with_context(|| {
-                format!(
-                    "Failed to delete remote layer from storage at '{:?}'",
-                    storage_path
-                )
-            }) {
-                Ok(()) => Ok(local_layer_path),
-                Err(e) => Err((e, local_layer_path)),
-            }
-        })
-        .collect::<FuturesUnordered<_>>();
-
-    let mut errored = false;
-    while let Some(deletion_result) = delete_tasks.next().await {
-        match deletion_result {
-            Ok(local_layer_path) => {
-                debug!(
-                    "Successfully deleted layer {} for timeline {sync_id}",
-                    local_layer_path.display()
-                );
-                delete_data.data.deleted_layers.insert(local_layer_path);
+    info!(
+        "Scheduling {} timeline layers for deletion",
+        layers_to_delete.len()
+    );
+    sync_queue
+        .deletions
+        .schedule(sync_id, sync_queue.deletions.due_at(), layers_to_delete);
+    true
+}
+
+/// Queues the deletions recorded in the index parts, that were still pending when the pageserver stopped.
+pub(super) fn resume_pending_deletions(index: &RemoteTimelineIndex, sync_queue: &SyncQueue) {
+    let mut resumed = 0;
+    for sync_id in index.all_sync_ids() {
+        let remote_timeline = match index.timeline_entry(&sync_id) {
+            Some(remote_timeline) => remote_timeline,
+            None => continue,
+        };
+        for (layer, pending) in remote_timeline.pending_deletions() {
+            sync_queue.deletions.schedule(
+                sync_id,
+                pending.due_at,
+                [(layer.clone(), pending.generation)],
+            );
+            resumed += 1;
+        }
+    }
+    if resumed > 0 {
+        info!("Resumed {resumed} pending remote layer deletions");
+    }
+}
+
+/// Deletes the queued layers whose deletion delay has passed, in batches.
+/// The layers that fail to get deleted are retried, until they fail `max_sync_errors` times.
+pub(super) async fn delete_due_layers<P, S>(
+    storage: &S,
+    index: &RemoteIndex,
+    sync_queue: &SyncQueue,
+    max_sync_errors: NonZeroU32,
+) where
+    P: std::fmt::Debug + Send + Sync + 'static,
+    S: RemoteStorage<RemoteObjectId = P> + Send + Sync + 'static,
+{
+    let due = sync_queue.deletions.take_due(SystemTime::now());
+    if due.is_empty() {
+        return;
+    }
+
+    // Reconcile with the remote index: the layers could be referenced again since their deletion was registered
+    let mut to_delete = Vec::with_capacity(due.len());
+    let mut referenced_again = 0;
+    let mut to_recheck = 0;
+    {
+        let index_accessor = index.read().await;
+        for deletion in due {
+            match index_accessor.timeline_entry(&deletion.sync_id) {
+                // A layer uploaded again by a newer generation is stored under another path
+                Some(remote_timeline)
+                    if remote_timeline.stored_files().contains(&deletion.layer)
+                        && remote_timeline.layer_generation(&deletion.layer)
+                            == deletion.generation =>
+                {
+                    referenced_again += 1
+                }
+                Some(remote_timeline) if remote_timeline.has_clones => {
+                    to_recheck += 1;
+                    sync_queue.deletions.requeue(PendingDeletion {
+                        due_at: seconds_since_epoch(
+                            SystemTime::now() + CLONED_LAYERS_RECHECK_PERIOD,
+                        ),
+                        ..deletion
+                    });
+                }
+                _ => to_delete.push(deletion),
             }
-            Err((e, local_layer_path)) => {
-                errored = true;
-                error!(
-                    "Failed to delete layer {} for timeline {sync_id}: {e:?}",
-                    local_layer_path.display()
-                );
-                delete_data.data.layers_to_delete.insert(local_layer_path);
+        }
+    }
+    if referenced_again > 0 {
+        info!("Keeping {referenced_again} layers referenced by the remote index again");
+    }
+    if to_recheck > 0 {
+        info!(
+            "Postponing the deletion of {to_recheck} layers that clones of their timelines may use"
+        );
+    }
+
+    let mut storage_paths = Vec::with_capacity(to_delete.len());
+    let mut deletions = Vec::with_capacity(to_delete.len());
+    for deletion in to_delete {
+        match storage.remote_object_id(&remote_layer_path(&deletion.layer, deletion.generation)) {
+            Ok(storage_path) => {
+                storage_paths.push(storage_path);
+                deletions.push(deletion);
             }
+            Err(e) => error!(
+                "Failed to get the storage path for layer '{}', not deleting it: {e:?}",
+                deletion.layer.display()
+            ),
         }
     }
 
-    if errored {
-        debug!("Reenqueuing failed delete task for timeline {sync_id}");
-        delete_data.retries += 1;
-        sync_queue.push(sync_id, SyncTask::Delete(delete_data));
+    while !storage_paths.is_empty() {
+        let batch_size = storage_paths.len().min(MAX_LAYERS_PER_DELETE);
+        let batch_paths = storage_paths.drain(..batch_size).collect::<Vec<_>>();
+        let batch = deletions.drain(..batch_size).collect::<Vec<_>>();
+
+        match storage.delete_objects(&batch_paths).await {
+            Ok(()) => {
+                info!("Deleted {batch_size} layers from the remote storage");
+                DELETED_LAYERS.inc_by(batch_size as u64);
+                // The next index part upload drops them
+                let mut index_accessor = index.write().await;
+                for deletion in batch {
+                    if let Some(remote_timeline) =
+                        index_accessor.timeline_entry_mut(&deletion.sync_id)
+                    {
+                        remote_timeline
+                            .complete_layer_deletion(&deletion.layer, deletion.generation);
+                    }
+                }
+            }
+            Err(e) => {
+                error!("Failed to delete {batch_size} layers from the remote storage: {e:?}");
+                for deletion in batch {
+                    if deletion.retries + 1 >= max_sync_errors.get() {
+                        warn!(
+                            "Giving up deleting layer '{}' of timeline {} until the restart",
+                            deletion.layer.display(),
+                            deletion.sync_id
+                        );
+                    } else {
+                        sync_queue.deletions.requeue(PendingDeletion {
+                            retries: deletion.retries + 1,
+                            ..deletion
+                        });
+                    }
+                }
+            }
+        }
     }
-    errored
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{collections::HashSet, num::NonZeroUsize};
+    use std::{
+        collections::{HashMap, HashSet},
+        num::NonZeroUsize,
+    };
 
     use itertools::Itertools;
     use tempfile::tempdir;
@@ -110,7 +316,10 @@ mod tests {
 
     use crate::{
         repository::repo_harness::{RepoHarness, TIMELINE_ID},
-        storage_sync::test_utils::{create_local_timeline, dummy_metadata},
+        storage_sync::{
+            index::{IndexPart, RemoteTimeline},
+            test_utils::{create_local_timeline, dummy_metadata},
+        },
     };
     use remote_storage::LocalFs;
 
@@ -121,13 +330,8 @@ mod tests {
         let harness = RepoHarness::create("delete_timeline_negative")?;
         let sync_queue = SyncQueue::new(NonZeroUsize::new(100).unwrap());
         let sync_id = ZTenantTimelineId::new(harness.tenant_id, TIMELINE_ID);
-        let storage = LocalFs::new(
-            tempdir()?.path().to_path_buf(),
-            harness.conf.workdir.clone(),
-        )?;
 
         let deleted = delete_timeline_layers(
-            &storage,
             &sync_queue,
             sync_id,
             SyncData {
@@ -136,10 +340,10 @@ mod tests {
                     deleted_layers: HashSet::new(),
                     layers_to_delete: HashSet::new(),
                     deletion_registered: false,
+                    layer_generations: HashMap::new(),
                 },
             },
-        )
-        .await;
+        );
 
         assert!(
             !deleted,
@@ -191,7 +395,6 @@ mod tests {
         );
 
         let deleted = delete_timeline_layers(
-            &storage,
             &sync_queue,
             sync_id,
             SyncData {
@@ -204,11 +407,16 @@ mod tests {
                         local_timeline_path.join("something_different"),
                     ]),
                     deletion_registered: true,
+                    layer_generations: HashMap::new(),
                 },
             },
-        )
-        .await;
+        );
         assert!(deleted, "Should be able to delete timeline files");
+        assert_eq!(sync_queue.deletions.len(), 3);
+
+        let index = RemoteIndex::empty();
+        delete_due_layers(&storage, &index, &sync_queue, NonZeroU32::new(3).unwrap()).await;
+        assert_eq!(sync_queue.deletions.len(), 0);
 
         assert_eq!(
             storage
@@ -225,4 +433,181 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn delete_layer_with_generation() -> anyhow::Result<()> {
+        let harness = RepoHarness::create("delete_layer_with_generation")?;
+        let sync_queue = SyncQueue::new(NonZeroUsize::new(100).unwrap());
+        let sync_id = ZTenantTimelineId::new(harness.tenant_id, TIMELINE_ID);
+        let storage = LocalFs::new(tempdir()?.path().to_owned(), harness.conf.workdir.clone())?;
+        let layer = harness.timeline_path(&TIMELINE_ID).join("a");
+
+        // The same layer, uploaded by two generations
+        for generation in [Some(1), Some(2)] {
+            let remote_path = storage.remote_object_id(&remote_layer_path(&layer, generation))?;
+            fs::create_dir_all(remote_path.parent().unwrap()).await?;
+            fs::write(&remote_path, "contents").await?;
+        }
+
+        let deleted = delete_timeline_layers(
+            &sync_queue,
+            sync_id,
+            SyncData {
+                retries: 0,
+                data: LayersDeletion {
+                    deleted_layers: HashSet::new(),
+                    layers_to_delete: HashSet::from([layer.clone()]),
+                    deletion_registered: true,
+                    layer_generations: HashMap::from([(layer.clone(), 1)]),
+                },
+            },
+        );
+        assert!(deleted, "Should be able to delete timeline files");
+        delete_due_layers(
+            &storage,
+            &RemoteIndex::empty(),
+            &sync_queue,
+            NonZeroU32::new(3).unwrap(),
+        )
+        .await;
+
+        assert_eq!(
+            storage
+                .list()
+                .await?
+                .into_iter()
+                .map(|remote_path| storage.local_path(&remote_path).unwrap())
+                .collect::<Vec<_>>(),
+            vec![remote_layer_path(&layer, Some(2))],
+            "Only the layer of the deleted generation should be deleted"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn delete_after_delay() -> anyhow::Result<()> {
+        let harness = RepoHarness::create("delete_after_delay")?;
+        let mut sync_queue = SyncQueue::new(NonZeroUsize::new(100).unwrap());
+        sync_queue.deletions = RemoteDeletionQueue::new(Duration::from_secs(3600));
+        let sync_id = ZTenantTimelineId::new(harness.tenant_id, TIMELINE_ID);
+        let storage = LocalFs::new(
+            tempdir()?.path().to_path_buf(),
+            harness.conf.workdir.clone(),
+        )?;
+        let local_timeline_path = harness.timeline_path(&TIMELINE_ID);
+        let layer = local_timeline_path.join("a");
+
+        let due_at = sync_queue.deletions.due_at();
+        sync_queue
+            .deletions
+            .schedule(sync_id, due_at, [(layer.clone(), None)]);
+        let index = RemoteIndex::empty();
+        delete_due_layers(&storage, &index, &sync_queue, NonZeroU32::new(3).unwrap()).await;
+        assert_eq!(sync_queue.deletions.len(), 1, "Should wait for the delay");
+
+        let due = sync_queue
+            .deletions
+            .take_due(SystemTime::now() + Duration::from_secs(3600));
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].layer, layer);
+        assert_eq!(sync_queue.deletions.len(), 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn resume_deletions_after_restart() -> anyhow::Result<()> {
+        let harness = RepoHarness::create("resume_deletions_after_restart")?;
+        let sync_id = ZTenantTimelineId::new(harness.tenant_id, TIMELINE_ID);
+        let storage = LocalFs::new(tempdir()?.path().to_owned(), harness.conf.workdir.clone())?;
+        let timeline_path = harness.timeline_path(&TIMELINE_ID);
+        let layers = ["a", "b", "c"].map(|name| timeline_path.join(name));
+        for layer in &layers {
+            let remote_path = storage.remote_object_id(layer)?;
+            fs::create_dir_all(remote_path.parent().unwrap()).await?;
+            fs::write(&remote_path, "contents").await?;
+        }
+
+        // The deletion of two layers is registered, then the pageserver stops before they are deleted
+        let mut remote_timeline = RemoteTimeline::new(dummy_metadata(Lsn(0x30)));
+        remote_timeline.add_timeline_layers(layers.iter().cloned());
+        let due_at = seconds_since_epoch(SystemTime::now());
+        remote_timeline.register_layer_deletions(
+            &HashSet::from([layers[0].clone(), layers[1].clone()]),
+            due_at,
+        );
+        let index_part = IndexPart::from_remote_timeline(&timeline_path, remote_timeline)?;
+
+        // After the restart, the index part brings the pending deletions back
+        let index = RemoteIndex::from_parts(harness.conf, HashMap::from([(sync_id, index_part)]))?;
+        let sync_queue = SyncQueue::new(NonZeroUsize::new(100).unwrap());
+        resume_pending_deletions(&*index.read().await, &sync_queue);
+        assert_eq!(sync_queue.deletions.len(), 2);
+
+        delete_due_layers(&storage, &index, &sync_queue, NonZeroU32::new(3).unwrap()).await;
+        assert_eq!(sync_queue.deletions.len(), 0);
+        assert_eq!(
+            storage
+                .list()
+                .await?
+                .into_iter()
+                .map(|remote_path| storage.local_path(&remote_path).unwrap())
+                .collect::<Vec<_>>(),
+            vec![layers[2].clone()],
+            "Only the layer still in the index should be left remotely"
+        );
+        assert!(
+            index
+                .read()
+                .await
+                .timeline_entry(&sync_id)
+                .unwrap()
+                .pending_deletions()
+                .is_empty(),
+            "Deleted layers should be dropped from the pending deletions"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn requeue_deletions_of_cloned_timeline() -> anyhow::Result<()> {
+        let harness = RepoHarness::create("requeue_deletions_of_cloned_timeline")?;
+        let sync_id = ZTenantTimelineId::new(harness.tenant_id, TIMELINE_ID);
+        let storage = LocalFs::new(tempdir()?.path().to_owned(), harness.conf.workdir.clone())?;
+        let layer = harness.timeline_path(&TIMELINE_ID).join("a");
+        let remote_path = storage.remote_object_id(&layer)?;
+        fs::create_dir_all(remote_path.parent().unwrap()).await?;
+        fs::write(&remote_path, "contents").await?;
+
+        let index = RemoteIndex::empty();
+        let mut remote_timeline = RemoteTimeline::new(dummy_metadata(Lsn(0x30)));
+        remote_timeline.has_clones = true;
+        index
+            .write()
+            .await
+            .add_timeline_entry(sync_id, remote_timeline);
+
+        let sync_queue = SyncQueue::new(NonZeroUsize::new(100).unwrap());
+        let due_at = sync_queue.deletions.due_at();
+        sync_queue
+            .deletions
+            .schedule(sync_id, due_at, [(layer.clone(), None)]);
+        delete_due_layers(&storage, &index, &sync_queue, NonZeroU32::new(3).unwrap()).await;
+
+        assert!(remote_path.exists(), "Clones may use the layer");
+        assert_eq!(
+            sync_queue.deletions.len(),
+            1,
+            "The deletion should be checked again later"
+        );
+        let due = sync_queue
+            .deletions
+            .take_due(SystemTime::now() + CLONED_LAYERS_RECHECK_PERIOD);
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].layer, layer);
+
+        Ok(())
+    }
 }
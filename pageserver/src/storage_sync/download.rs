@@ -1,10 +1,19 @@
 //! Timeline synchronization logic to fetch the layer files from remote storage into pageserver's local directory.
 
-use std::{collections::HashSet, fmt::Debug, path::Path};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
 
 use anyhow::Context;
 use futures::stream::{FuturesUnordered, StreamExt};
-use remote_storage::{path_with_suffix_extension, RemoteStorage};
+use lazy_static::lazy_static;
+use metrics::{register_int_counter, IntCounter};
+use remote_storage::{path_with_suffix_extension, GenericRemoteStorage, RemoteStorage};
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
 use tokio::{
     fs,
     io::{self, AsyncWriteExt},
@@ -12,17 +21,109 @@ use tokio::{
 use tracing::{debug, error, info, warn};
 
 use crate::{
-    config::PageServerConf, layered_repository::metadata::metadata_path, storage_sync::SyncTask,
+    config::PageServerConf,
+    layered_repository::{metadata::metadata_path, stamp_of_path},
+    storage_sync::SyncTask,
 };
-use utils::zid::ZTenantTimelineId;
+use utils::zid::{ZTenantId, ZTenantTimelineId, ZTimelineId};
 
 use super::{
-    index::{IndexPart, RemoteTimeline},
+    index::{remote_layer_path, IndexPart, RemoteTimeline},
     LayersDownload, SyncData, SyncQueue,
 };
 
 pub const TEMP_DOWNLOAD_EXTENSION: &str = "temp_download";
 
+lazy_static! {
+    /// Progress of the timeline downloads that have started, kept until they succeed or get abandoned.
+    static ref DOWNLOAD_PROGRESS: Mutex<HashMap<ZTenantTimelineId, DownloadProgress>> =
+        Mutex::new(HashMap::new());
+    /// Layers that could not be downloaded from any storage, after all the download retries.
+    static ref QUARANTINED_LAYERS: Mutex<HashMap<ZTenantTimelineId, HashSet<PathBuf>>> =
+        Mutex::new(HashMap::new());
+    static ref FALLBACK_DOWNLOADS: IntCounter = register_int_counter!(
+        "pageserver_remote_storage_fallback_downloads_total",
+        "Number of layers downloaded from a fallback storage after the primary storage failed"
+    )
+    .expect("failed to define a metric");
+}
+
+/// Progress of a timeline download: how many of its layers are present locally.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DownloadProgress {
+    pub layers_total: usize,
+    pub layers_downloaded: usize,
+}
+
+/// Returns the progress of the timeline download, if it has started and not finished yet.
+/// A timeline that awaits download without progress is still queued.
+pub fn download_progress(sync_id: &ZTenantTimelineId) -> Option<DownloadProgress> {
+    DOWNLOAD_PROGRESS.lock().unwrap().get(sync_id).copied()
+}
+
+pub(super) fn forget_download_progress(sync_id: &ZTenantTimelineId) {
+    DOWNLOAD_PROGRESS.lock().unwrap().remove(sync_id);
+}
+
+/// A timeline's layers that could not be downloaded from any storage.
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct QuarantinedLayers {
+    #[serde_as(as = "DisplayFromStr")]
+    pub timeline_id: ZTimelineId,
+    /// File names of the layers.
+    pub layers: Vec<String>,
+}
+
+/// Returns the layers of the tenant's timelines that could not be downloaded from any storage.
+/// The list is kept in memory: a timeline leaves it when its download succeeds, or after a restart.
+pub fn quarantined_layers(tenant_id: ZTenantId) -> Vec<QuarantinedLayers> {
+    let quarantine = QUARANTINED_LAYERS.lock().unwrap();
+    let mut quarantined = quarantine
+        .iter()
+        .filter(|(sync_id, _)| sync_id.tenant_id == tenant_id)
+        .map(|(sync_id, layers)| {
+            let mut layers = layers
+                .iter()
+                .filter_map(|layer| layer.file_name())
+                .map(|layer_name| layer_name.to_string_lossy().into_owned())
+                .collect::<Vec<_>>();
+            layers.sort();
+            QuarantinedLayers {
+                timeline_id: sync_id.timeline_id,
+                layers,
+            }
+        })
+        .collect::<Vec<_>>();
+    quarantined.sort_by_key(|layers| layers.timeline_id);
+    quarantined
+}
+
+/// Records the remote timeline's layers that are still missing locally after the download got abandoned.
+pub(super) fn quarantine_missing_layers(
+    sync_id: ZTenantTimelineId,
+    remote_timeline: &RemoteTimeline,
+    downloaded_layers: &HashSet<PathBuf>,
+) {
+    let missing_layers = remote_timeline
+        .stored_files()
+        .difference(downloaded_layers)
+        .filter(|layer| !layer.exists())
+        .cloned()
+        .collect::<HashSet<_>>();
+    if missing_layers.is_empty() {
+        return;
+    }
+    error!(
+        "Quarantining {} layers of timeline {sync_id} that could not be downloaded: {missing_layers:?}",
+        missing_layers.len()
+    );
+    QUARANTINED_LAYERS
+        .lock()
+        .unwrap()
+        .insert(sync_id, missing_layers);
+}
+
 /// Retrieves index data from the remote storage for a given timeline.
 pub async fn download_index_part<P, S>(
     conf: &'static PageServerConf,
@@ -85,6 +186,7 @@ pub(super) enum DownloadedTimeline {
 pub(super) async fn download_timeline_layers<'a, P, S>(
     conf: &'static PageServerConf,
     storage: &'a S,
+    fallback_storages: &'a [GenericRemoteStorage],
     sync_queue: &'a SyncQueue,
     remote_timeline: Option<&'a RemoteTimeline>,
     sync_id: ZTenantTimelineId,
@@ -121,9 +223,18 @@ where
 
     if layers_to_download.is_empty() {
         info!("No layers to download after filtering, skipping");
+        forget_download_progress(&sync_id);
+        QUARANTINED_LAYERS.lock().unwrap().remove(&sync_id);
         return DownloadedTimeline::Successful(download_data);
     }
 
+    let layers_total = remote_timeline.stored_files().len();
+    let mut progress = DownloadProgress {
+        layers_total,
+        layers_downloaded: layers_total - layers_to_download.len(),
+    };
+    DOWNLOAD_PROGRESS.lock().unwrap().insert(sync_id, progress);
+
     let mut download_tasks = layers_to_download
         .into_iter()
         .map(|layer_desination_path| async move {
@@ -133,14 +244,21 @@ where
                     layer_desination_path.display()
                 );
             } else {
-                let layer_storage_path = storage
-                    .remote_object_id(&layer_desination_path)
-                    .with_context(|| {
-                        format!(
-                            "Failed to get the layer storage path for local path '{}'",
-                            layer_desination_path.display()
-                        )
-                    })?;
+                // Layers borrowed from another tenant are stored under that tenant's timeline,
+                // and the layers uploaded with a generation have it in their storage path
+                let layer_source_path = match (
+                    remote_timeline.layer_owner(&layer_desination_path),
+                    layer_desination_path.file_name(),
+                ) {
+                    (Some(owner), Some(layer_name)) => conf
+                        .timeline_path(&sync_id.timeline_id, &owner)
+                        .join(layer_name),
+                    _ => layer_desination_path.clone(),
+                };
+                let layer_source_path = remote_layer_path(
+                    &layer_source_path,
+                    remote_timeline.layer_generation(&layer_desination_path),
+                );
 
                 // Perform a rename inspired by durable_rename from file_utils.c.
                 // The sequence:
@@ -155,22 +273,13 @@ where
                 let temp_file_path =
                     path_with_suffix_extension(&layer_desination_path, TEMP_DOWNLOAD_EXTENSION);
 
-                let mut destination_file =
-                    fs::File::create(&temp_file_path).await.with_context(|| {
-                        format!(
-                            "Failed to create a destination file for layer '{}'",
-                            temp_file_path.display()
-                        )
-                    })?;
-
-                storage
-                    .download(&layer_storage_path, &mut destination_file)
-                    .await
-                    .with_context(|| {
-                        format!(
-                            "Failed to download a layer from storage path '{layer_storage_path:?}'"
-                        )
-                    })?;
+                let mut destination_file = download_layer_file(
+                    storage,
+                    fallback_storages,
+                    &layer_source_path,
+                    &temp_file_path,
+                )
+                .await?;
 
                 // Tokio doc here: https://docs.rs/tokio/1.17.0/tokio/fs/struct.File.html states that:
                 // A file will not be closed immediately when it goes out of scope if there are any IO operations
@@ -196,6 +305,19 @@ where
                 })?;
                 drop(destination_file);
 
+                // A layer with a checksum in its name can be checked before it
+                // gets its final name
+                if let Some(stamp) = stamp_of_path(&layer_desination_path) {
+                    let path = temp_file_path.clone();
+                    let verified = tokio::task::spawn_blocking(move || stamp.verify(&path)).await?;
+                    if let Err(e) = verified {
+                        if let Err(rm_err) = fs::remove_file(&temp_file_path).await {
+                            warn!("failed to remove '{}': {rm_err}", temp_file_path.display());
+                        }
+                        return Err(e);
+                    }
+                }
+
                 fail::fail_point!("remote-storage-download-pre-rename", |_| {
                     anyhow::bail!("remote-storage-download-pre-rename failpoint triggered")
                 });
@@ -221,6 +343,8 @@ where
             Ok(downloaded_path) => {
                 undo.insert(downloaded_path.clone());
                 download.layers_to_skip.insert(downloaded_path);
+                progress.layers_downloaded += 1;
+                DOWNLOAD_PROGRESS.lock().unwrap().insert(sync_id, progress);
             }
             Err(e) => {
                 errors_happened = true;
@@ -254,10 +378,81 @@ where
         DownloadedTimeline::FailedAndRescheduled
     } else {
         info!("Successfully downloaded all layers");
+        forget_download_progress(&sync_id);
+        QUARANTINED_LAYERS.lock().unwrap().remove(&sync_id);
         DownloadedTimeline::Successful(download_data)
     }
 }
 
+/// Downloads the layer into a new temporary file. If the primary storage fails, e.g. because the
+/// object is missing or the transfer breaks, the fallback storages are tried in order.
+async fn download_layer_file<P, S>(
+    storage: &S,
+    fallback_storages: &[GenericRemoteStorage],
+    layer_source_path: &Path,
+    temp_file_path: &Path,
+) -> anyhow::Result<fs::File>
+where
+    P: Debug + Send + Sync + 'static,
+    S: RemoteStorage<RemoteObjectId = P> + Send + Sync + 'static,
+{
+    let create_temp_file = || async move {
+        fs::File::create(temp_file_path).await.with_context(|| {
+            format!(
+                "Failed to create a destination file for layer '{}'",
+                temp_file_path.display()
+            )
+        })
+    };
+
+    let layer_storage_path = storage
+        .remote_object_id(layer_source_path)
+        .with_context(|| {
+            format!(
+                "Failed to get the layer storage path for local path '{}'",
+                layer_source_path.display()
+            )
+        })?;
+    let mut destination_file = create_temp_file().await?;
+    let mut last_error = match storage
+        .download(&layer_storage_path, &mut destination_file)
+        .await
+    {
+        Ok(_) => return Ok(destination_file),
+        Err(e) => e.context(format!(
+            "Failed to download a layer from storage path '{layer_storage_path:?}'"
+        )),
+    };
+    drop(destination_file);
+
+    for (i, fallback_storage) in fallback_storages.iter().enumerate() {
+        warn!("{last_error:#}, trying fallback storage {i}");
+        // Start over, the failed download may have written a part of the file
+        let mut destination_file = create_temp_file().await?;
+        match fallback_storage
+            .download_local_path(layer_source_path, &mut destination_file)
+            .await
+        {
+            Ok(_) => {
+                info!(
+                    "Downloaded layer '{}' from fallback storage {i}",
+                    layer_source_path.display()
+                );
+                FALLBACK_DOWNLOADS.inc();
+                return Ok(destination_file);
+            }
+            Err(e) => {
+                last_error = e.context(format!(
+                    "Failed to download layer '{}' from fallback storage {i}",
+                    layer_source_path.display()
+                ))
+            }
+        }
+    }
+
+    Err(last_error)
+}
+
 async fn fsync_path(path: impl AsRef<Path>) -> Result<(), io::Error> {
     fs::File::open(path).await?.sync_all().await
 }
@@ -265,7 +460,7 @@ async fn fsync_path(path: impl AsRef<Path>) -> Result<(), io::Error> {
 #[cfg(test)]
 mod tests {
     use std::{
-        collections::{BTreeSet, HashSet},
+        collections::{BTreeSet, HashMap, HashSet},
         num::NonZeroUsize,
     };
 
@@ -328,6 +523,7 @@ mod tests {
         let download_data = match download_timeline_layers(
             harness.conf,
             &storage,
+            &[],
             &sync_queue,
             Some(&remote_timeline),
             sync_id,
@@ -382,6 +578,79 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn download_timeline_with_generations() -> anyhow::Result<()> {
+        let harness = RepoHarness::create("download_timeline_with_generations")?;
+        let sync_queue = SyncQueue::new(NonZeroUsize::new(100).unwrap());
+        let sync_id = ZTenantTimelineId::new(harness.tenant_id, TIMELINE_ID);
+        let storage = LocalFs::new(tempdir()?.path().to_owned(), harness.conf.workdir.clone())?;
+        let metadata = dummy_metadata(Lsn(0x30));
+        let local_timeline_path = harness.timeline_path(&TIMELINE_ID);
+        let layer_files = ["old_layer", "new_layer"];
+        create_local_timeline(&harness, TIMELINE_ID, &layer_files, metadata.clone()).await?;
+
+        // 'old_layer' was uploaded without a generation, 'new_layer' with generation 2.
+        // Generation 1 has a different version of 'new_layer', which must not be downloaded.
+        let layer_generations = HashMap::from([(local_timeline_path.join("new_layer"), 2)]);
+        for layer in layer_files {
+            let local_path = local_timeline_path.join(layer);
+            let remote_path = storage.remote_object_id(&remote_layer_path(
+                &local_path,
+                layer_generations.get(&local_path).copied(),
+            ))?;
+            fs::create_dir_all(remote_path.parent().unwrap()).await?;
+            fs::copy(&local_path, &remote_path).await?;
+        }
+        let stale_path = storage.remote_object_id(&remote_layer_path(
+            &local_timeline_path.join("new_layer"),
+            Some(1),
+        ))?;
+        fs::write(&stale_path, "stale contents").await?;
+        let expected_contents = fs::read(local_timeline_path.join("new_layer")).await?;
+        for layer in layer_files {
+            fs::remove_file(local_timeline_path.join(layer)).await?;
+        }
+
+        let mut remote_timeline = RemoteTimeline::new(metadata);
+        remote_timeline.awaits_download = true;
+        remote_timeline.add_timeline_layers_with_generations(
+            layer_files
+                .iter()
+                .map(|layer| local_timeline_path.join(layer)),
+            &layer_generations,
+        );
+
+        let download_result = download_timeline_layers(
+            harness.conf,
+            &storage,
+            &[],
+            &sync_queue,
+            Some(&remote_timeline),
+            sync_id,
+            SyncData::new(
+                0,
+                LayersDownload {
+                    layers_to_skip: HashSet::new(),
+                },
+            ),
+        )
+        .await;
+        assert!(
+            matches!(download_result, DownloadedTimeline::Successful(_)),
+            "Expected a successful download, but got: {download_result:?}"
+        );
+        for layer in layer_files {
+            assert!(local_timeline_path.join(layer).exists());
+        }
+        assert_eq!(
+            fs::read(local_timeline_path.join("new_layer")).await?,
+            expected_contents,
+            "The layer of the generation in the index should be downloaded"
+        );
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn download_timeline_negatives() -> anyhow::Result<()> {
         let harness = RepoHarness::create("download_timeline_negatives")?;
@@ -392,6 +661,7 @@ mod tests {
         let empty_remote_timeline_download = download_timeline_layers(
             harness.conf,
             &storage,
+            &[],
             &sync_queue,
             None,
             sync_id,
@@ -416,6 +686,7 @@ mod tests {
         let already_downloading_remote_timeline_download = download_timeline_layers(
             harness.conf,
             &storage,
+            &[],
             &sync_queue,
             Some(&not_expecting_download_remote_timeline),
             sync_id,
@@ -438,6 +709,93 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn download_timeline_from_fallback() -> anyhow::Result<()> {
+        let harness = RepoHarness::create("download_timeline_from_fallback")?;
+        let sync_queue = SyncQueue::new(NonZeroUsize::new(100).unwrap());
+        let sync_id = ZTenantTimelineId::new(harness.tenant_id, TIMELINE_ID);
+        let layer_files = ["a", "b"];
+        // The primary storage lost the layers, the fallback one has them
+        let storage = LocalFs::new(tempdir()?.path().to_owned(), harness.conf.workdir.clone())?;
+        let fallback_storage = LocalFs::new(
+            tempdir()?.path().to_path_buf(),
+            harness.conf.workdir.clone(),
+        )?;
+        let metadata = dummy_metadata(Lsn(0x30));
+        let local_timeline_path = harness.timeline_path(&TIMELINE_ID);
+        let timeline_upload =
+            create_local_timeline(&harness, TIMELINE_ID, &layer_files, metadata.clone()).await?;
+        for local_path in timeline_upload.layers_to_upload {
+            let remote_path = fallback_storage.remote_object_id(&local_path)?;
+            fs::create_dir_all(remote_path.parent().unwrap()).await?;
+            fs::copy(&local_path, &remote_path).await?;
+            fs::remove_file(&local_path).await?;
+        }
+
+        let mut remote_timeline = RemoteTimeline::new(metadata);
+        remote_timeline.awaits_download = true;
+        remote_timeline.add_timeline_layers(
+            layer_files
+                .iter()
+                .map(|layer| local_timeline_path.join(layer)),
+        );
+        let download_data = || {
+            SyncData::new(
+                0,
+                LayersDownload {
+                    layers_to_skip: HashSet::new(),
+                },
+            )
+        };
+
+        let failed_download = download_timeline_layers(
+            harness.conf,
+            &storage,
+            &[],
+            &sync_queue,
+            Some(&remote_timeline),
+            sync_id,
+            download_data(),
+        )
+        .await;
+        assert!(
+            matches!(failed_download, DownloadedTimeline::FailedAndRescheduled),
+            "Should fail to download layers missing in the primary storage, without fallbacks"
+        );
+        quarantine_missing_layers(sync_id, &remote_timeline, &HashSet::new());
+        assert_eq!(
+            quarantined_layers(harness.tenant_id),
+            vec![QuarantinedLayers {
+                timeline_id: TIMELINE_ID,
+                layers: vec!["a".to_string(), "b".to_string()],
+            }]
+        );
+
+        let fallback_download = download_timeline_layers(
+            harness.conf,
+            &storage,
+            &[GenericRemoteStorage::Local(fallback_storage)],
+            &sync_queue,
+            Some(&remote_timeline),
+            sync_id,
+            download_data(),
+        )
+        .await;
+        assert!(
+            matches!(fallback_download, DownloadedTimeline::Successful(_)),
+            "Should download the layers from the fallback storage, but got: {fallback_download:?}"
+        );
+        for layer in layer_files {
+            assert!(local_timeline_path.join(layer).exists());
+        }
+        assert!(
+            quarantined_layers(harness.tenant_id).is_empty(),
+            "Downloaded layers should leave the quarantine"
+        );
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_download_index_part() -> anyhow::Result<()> {
         let harness = RepoHarness::create("test_download_index_part")?;
@@ -14,7 +14,10 @@ use serde_with::{serde_as, DisplayFromStr};
 use tokio::sync::RwLock;
 
 use crate::{config::PageServerConf, layered_repository::metadata::TimelineMetadata};
-use utils::{lsn::Lsn, zid::ZTenantTimelineId};
+use utils::{
+    lsn::Lsn,
+    zid::{ZTenantId, ZTenantTimelineId, ZTimelineId},
+};
 
 /// A part of the filesystem path, that needs a root to become a path again.
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
@@ -41,6 +44,50 @@ impl RelativePath {
     }
 }
 
+/// Path of a layer in the remote storage, when uploaded by a pageserver with the given tenant generation:
+/// the local layer path with `-g<generation>` appended to the file name, the generation in 8 hex digits.
+/// Two pageservers that upload a layer with the same name, e.g. the old and the new owner of a tenant,
+/// then never overwrite each other's objects. Layers uploaded without a generation keep the local name.
+///
+/// The result is the local path to get the storage key for, with [`remote_storage::RemoteStorage::remote_object_id`].
+pub fn remote_layer_path(layer: &Path, generation: Option<u32>) -> PathBuf {
+    match (generation, layer.file_name()) {
+        (Some(generation), Some(file_name)) => {
+            let mut file_name = file_name.to_os_string();
+            file_name.push(format!("-g{generation:08x}"));
+            layer.with_file_name(file_name)
+        }
+        _ => layer.to_path_buf(),
+    }
+}
+
+/// The reverse of [`remote_layer_path`]: splits the path of a layer listed in the remote storage into
+/// the local layer path and the generation that uploaded it, if any.
+pub fn parse_remote_layer_path(remote_path: &Path) -> (PathBuf, Option<u32>) {
+    let file_name = match remote_path.file_name().and_then(|name| name.to_str()) {
+        Some(file_name) => file_name,
+        None => return (remote_path.to_path_buf(), None),
+    };
+    // Layer file names have no 'g' otherwise, neither in the key and LSN ranges nor in the stamp
+    if let Some((layer_name, generation)) = file_name.rsplit_once("-g") {
+        if generation.len() == 8 {
+            if let Ok(generation) = u32::from_str_radix(generation, 16) {
+                return (remote_path.with_file_name(layer_name), Some(generation));
+            }
+        }
+    }
+    (remote_path.to_path_buf(), None)
+}
+
+/// A layer removed from the remote index that waits to be deleted from the remote storage, see [`super::delete`].
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub struct PendingLayerDeletion {
+    /// Tenant generation the layer was uploaded with, see [`remote_layer_path`].
+    pub generation: Option<u32>,
+    /// The time after which the layer gets deleted, in seconds since the epoch.
+    pub due_at: u64,
+}
+
 /// An index to track tenant files that exist on the remote storage.
 #[derive(Debug, Clone)]
 pub struct RemoteTimelineIndex {
@@ -82,6 +129,18 @@ impl RemoteIndex {
     pub async fn write(&self) -> tokio::sync::RwLockWriteGuard<'_, RemoteTimelineIndex> {
         self.0.write().await
     }
+
+    /// Same as [`RemoteIndex::read`], but for the synchronous code.
+    /// Panics if called from within an async execution context.
+    pub fn blocking_read(&self) -> tokio::sync::RwLockReadGuard<'_, RemoteTimelineIndex> {
+        self.0.blocking_read()
+    }
+
+    /// Same as [`RemoteIndex::write`], but for the synchronous code.
+    /// Panics if called from within an async execution context.
+    pub fn blocking_write(&self) -> tokio::sync::RwLockWriteGuard<'_, RemoteTimelineIndex> {
+        self.0.blocking_write()
+    }
 }
 
 impl Clone for RemoteIndex {
@@ -107,6 +166,17 @@ impl RemoteTimelineIndex {
         self.timeline_entries.keys().copied()
     }
 
+    /// Lists all remote timelines of the given tenant.
+    pub fn tenant_timelines(
+        &self,
+        tenant_id: ZTenantId,
+    ) -> impl Iterator<Item = (ZTimelineId, &RemoteTimeline)> + '_ {
+        self.timeline_entries
+            .iter()
+            .filter(move |(id, _)| id.tenant_id == tenant_id)
+            .map(|(id, timeline)| (id.timeline_id, timeline))
+    }
+
     pub fn set_awaits_download(
         &mut self,
         id: &ZTenantTimelineId,
@@ -124,9 +194,26 @@ impl RemoteTimelineIndex {
 pub struct RemoteTimeline {
     timeline_layers: HashSet<PathBuf>,
     missing_layers: HashSet<PathBuf>,
+    /// Layers of a cloned tenant's timeline that are stored under the same timeline of another tenant,
+    /// the one it was cloned from, with the id of that tenant.
+    borrowed_layers: HashMap<PathBuf, ZTenantId>,
+    /// Tenant generations the layers were uploaded with, see [`remote_layer_path`].
+    /// Layers uploaded without a generation have no entry.
+    layer_generations: HashMap<PathBuf, u32>,
+    /// Layers removed from the index that are still in the remote storage, waiting for their deletion.
+    pending_deletions: HashMap<PathBuf, PendingLayerDeletion>,
+    /// The latest disk consistent LSN, for which all layers and the metadata are confirmed to be uploaded.
+    /// The metadata below may be ahead of it, while the index part with that metadata is being uploaded.
+    remote_consistent_lsn: Lsn,
 
     pub metadata: TimelineMetadata,
     pub awaits_download: bool,
+    /// Archived timelines have all their data in the remote storage and no local state.
+    /// They need to be unarchived before getting downloaded (attached) again.
+    pub archived: bool,
+    /// Other tenants were cloned from this timeline and may reference its layers,
+    /// so the layers are never deleted from the remote storage.
+    pub has_clones: bool,
 }
 
 impl RemoteTimeline {
@@ -134,24 +221,202 @@ impl RemoteTimeline {
         Self {
             timeline_layers: HashSet::new(),
             missing_layers: HashSet::new(),
+            borrowed_layers: HashMap::new(),
+            layer_generations: HashMap::new(),
+            pending_deletions: HashMap::new(),
+            remote_consistent_lsn: Lsn(0),
             metadata,
             awaits_download: false,
+            archived: false,
+            has_clones: false,
         }
     }
 
+    /// Adds the layers uploaded without a generation, see [`Self::add_timeline_layers_with_generations`].
     pub fn add_timeline_layers(&mut self, new_layers: impl IntoIterator<Item = PathBuf>) {
-        self.timeline_layers.extend(new_layers.into_iter());
+        self.add_timeline_layers_with_generations(new_layers, &HashMap::new())
+    }
+
+    /// Adds the uploaded layers, with the generations they were uploaded with, if any.
+    pub fn add_timeline_layers_with_generations(
+        &mut self,
+        new_layers: impl IntoIterator<Item = PathBuf>,
+        generations: &HashMap<PathBuf, u32>,
+    ) {
+        for new_layer in new_layers {
+            // Uploaded under this timeline now, even if it was borrowed before or failed to upload
+            self.borrowed_layers.remove(&new_layer);
+            let generation = generations.get(&new_layer).copied();
+            match generation {
+                Some(generation) => self.layer_generations.insert(new_layer.clone(), generation),
+                None => self.layer_generations.remove(&new_layer),
+            };
+            // The same object is referenced again, it must not be deleted
+            if self
+                .pending_deletions
+                .get(&new_layer)
+                .map_or(false, |pending| pending.generation == generation)
+            {
+                self.pending_deletions.remove(&new_layer);
+            }
+            self.missing_layers.remove(&new_layer);
+            self.timeline_layers.insert(new_layer);
+        }
     }
 
     pub fn add_upload_failures(&mut self, upload_failures: impl IntoIterator<Item = PathBuf>) {
         self.missing_layers.extend(upload_failures.into_iter());
     }
 
+    /// Moves the layers that turned out to be absent in the remote storage to the missing ones.
+    pub fn mark_layers_missing(&mut self, absent_layers: HashSet<PathBuf>) {
+        self.timeline_layers
+            .retain(|layer| !absent_layers.contains(layer));
+        self.missing_layers.extend(absent_layers);
+    }
+
+    /// Layers that failed to upload or are absent in the remote storage, see [`IndexPart::missing_files`].
+    pub fn missing_layers(&self) -> &HashSet<PathBuf> {
+        &self.missing_layers
+    }
+
+    /// Whether the metadata is ahead of the layers confirmed to be uploaded, see [`Self::remote_consistent_lsn`].
+    /// The index parts published now never are, but the older versions published the metadata of
+    /// the checkpoints, even if some of their layers failed to upload.
+    pub fn metadata_ahead_of_layers(&self) -> bool {
+        self.remote_consistent_lsn < self.metadata.disk_consistent_lsn()
+    }
+
     pub fn remove_layers(&mut self, layers_to_remove: &HashSet<PathBuf>) {
         self.timeline_layers
             .retain(|layer| !layers_to_remove.contains(layer));
         self.missing_layers
             .retain(|layer| !layers_to_remove.contains(layer));
+        self.borrowed_layers
+            .retain(|layer, _| !layers_to_remove.contains(layer));
+        self.layer_generations
+            .retain(|layer, _| !layers_to_remove.contains(layer));
+    }
+
+    /// Removes the layers from the index, like [`Self::remove_layers`], and records the ones that have to be
+    /// deleted from the remote storage as pending deletions, due at 'due_at' seconds since the epoch.
+    /// The layers that other tenants may use, see [`Self::layers_to_keep_remotely`], stay in the storage.
+    pub fn register_layer_deletions(&mut self, layers_to_delete: &HashSet<PathBuf>, due_at: u64) {
+        let layers_to_keep = self.layers_to_keep_remotely(layers_to_delete);
+        for layer in layers_to_delete {
+            if self.timeline_layers.contains(layer) && !layers_to_keep.contains(layer) {
+                self.pending_deletions.insert(
+                    layer.clone(),
+                    PendingLayerDeletion {
+                        generation: self.layer_generation(layer),
+                        due_at,
+                    },
+                );
+            }
+        }
+        self.remove_layers(layers_to_delete);
+    }
+
+    /// Layers waiting to be deleted from the remote storage, see [`Self::register_layer_deletions`].
+    pub fn pending_deletions(&self) -> &HashMap<PathBuf, PendingLayerDeletion> {
+        &self.pending_deletions
+    }
+
+    /// Forgets the pending deletion of the layer uploaded with the given generation, once it's deleted.
+    pub fn complete_layer_deletion(&mut self, layer: &Path, generation: Option<u32>) {
+        if self
+            .pending_deletions
+            .get(layer)
+            .map_or(false, |pending| pending.generation == generation)
+        {
+            self.pending_deletions.remove(layer);
+        }
+    }
+
+    /// The tenant generation the layer was uploaded with, if any.
+    pub fn layer_generation(&self, layer: &Path) -> Option<u32> {
+        self.layer_generations.get(layer).copied()
+    }
+
+    /// Path of the layer in the remote storage, see [`remote_layer_path`].
+    /// Borrowed layers are stored under the timeline of their owner, see [`Self::layer_owner`].
+    pub fn remote_layer_path(&self, layer: &Path) -> PathBuf {
+        remote_layer_path(layer, self.layer_generation(layer))
+    }
+
+    /// The LSN up to which the timeline data is guaranteed to be in the remote storage.
+    pub fn remote_consistent_lsn(&self) -> Lsn {
+        self.remote_consistent_lsn
+    }
+
+    /// Should be called only after the layers up to `lsn` and the index part referencing them got uploaded.
+    pub fn advance_remote_consistent_lsn(&mut self, lsn: Lsn) {
+        self.remote_consistent_lsn = self.remote_consistent_lsn.max(lsn);
+    }
+
+    /// Returns the tenant that stores the layer in the remote storage, if that's not the timeline's own tenant.
+    pub fn layer_owner(&self, layer: &Path) -> Option<ZTenantId> {
+        self.borrowed_layers.get(layer).copied()
+    }
+
+    /// Picks the layers among the given ones that have to stay in the remote storage after the timeline
+    /// stops using them: layers borrowed from another tenant, or all of them if the timeline has clones.
+    pub fn layers_to_keep_remotely<'a>(
+        &self,
+        layers: impl IntoIterator<Item = &'a PathBuf>,
+    ) -> HashSet<PathBuf> {
+        layers
+            .into_iter()
+            .filter(|layer| self.has_clones || self.borrowed_layers.contains_key(*layer))
+            .cloned()
+            .collect()
+    }
+
+    /// Creates the remote timeline of a tenant cloned from this timeline's tenant, `source_tenant_id`.
+    /// The clone has the same metadata and layers, but doesn't store any layers itself: the layers are
+    /// read from the tenant that stores them, until the clone's GC replaces them with its own ones.
+    pub fn clone_for_tenant(
+        &self,
+        source_timeline_path: &Path,
+        clone_timeline_path: &Path,
+        source_tenant_id: ZTenantId,
+    ) -> anyhow::Result<Self> {
+        let to_clone_path = |layer: &PathBuf| {
+            RelativePath::new(source_timeline_path, layer)
+                .map(|relative_path| relative_path.as_path(clone_timeline_path))
+        };
+
+        let mut timeline_layers = HashSet::with_capacity(self.timeline_layers.len());
+        let mut borrowed_layers = HashMap::with_capacity(self.timeline_layers.len());
+        let mut layer_generations = HashMap::with_capacity(self.layer_generations.len());
+        for layer in &self.timeline_layers {
+            let clone_layer = to_clone_path(layer)?;
+            let owner = self.layer_owner(layer).unwrap_or(source_tenant_id);
+            borrowed_layers.insert(clone_layer.clone(), owner);
+            // The clone reads the same objects as the source
+            if let Some(generation) = self.layer_generation(layer) {
+                layer_generations.insert(clone_layer.clone(), generation);
+            }
+            timeline_layers.insert(clone_layer);
+        }
+
+        Ok(Self {
+            timeline_layers,
+            missing_layers: self
+                .missing_layers
+                .iter()
+                .map(to_clone_path)
+                .collect::<anyhow::Result<_>>()?,
+            borrowed_layers,
+            layer_generations,
+            // The source timeline deletes its layers itself
+            pending_deletions: HashMap::new(),
+            remote_consistent_lsn: self.remote_consistent_lsn,
+            metadata: self.metadata.clone(),
+            awaits_download: false,
+            archived: self.archived,
+            has_clones: false,
+        })
     }
 
     /// Lists all layer files in the given remote timeline. Omits the metadata file.
@@ -161,11 +426,38 @@ impl RemoteTimeline {
 
     pub fn from_index_part(timeline_path: &Path, index_part: IndexPart) -> anyhow::Result<Self> {
         let metadata = TimelineMetadata::from_bytes(&index_part.metadata_bytes)?;
+        // Older index parts were uploaded only after all layers of their metadata were, unless some uploads failed
+        let remote_consistent_lsn =
+            index_part
+                .remote_consistent_lsn
+                .unwrap_or(if index_part.missing_layers.is_empty() {
+                    index_part.disk_consistent_lsn
+                } else {
+                    Lsn(0)
+                });
         Ok(Self {
             timeline_layers: to_local_paths(timeline_path, index_part.timeline_layers),
             missing_layers: to_local_paths(timeline_path, index_part.missing_layers),
+            borrowed_layers: index_part
+                .borrowed_layers
+                .into_iter()
+                .map(|(layer, owner)| (layer.as_path(timeline_path), owner))
+                .collect(),
+            layer_generations: index_part
+                .layer_generations
+                .into_iter()
+                .map(|(layer, generation)| (layer.as_path(timeline_path), generation))
+                .collect(),
+            pending_deletions: index_part
+                .pending_deletions
+                .into_iter()
+                .map(|(layer, pending)| (layer.as_path(timeline_path), pending))
+                .collect(),
+            remote_consistent_lsn,
             metadata,
             awaits_download: false,
+            archived: index_part.archived,
+            has_clones: index_part.has_clones,
         })
     }
 }
@@ -185,6 +477,33 @@ pub struct IndexPart {
     #[serde_as(as = "DisplayFromStr")]
     disk_consistent_lsn: Lsn,
     metadata_bytes: Vec<u8>,
+    /// Older index parts have no such field, their timelines are not archived.
+    #[serde(default)]
+    archived: bool,
+    /// Generation of the tenant on the pageserver that wrote the index part, issued by the control plane.
+    /// Index parts written without a generation have no such field.
+    #[serde(default)]
+    generation: Option<u32>,
+    /// Layers stored under the same timeline of another tenant, with the id of that tenant.
+    /// Only cloned tenants have them.
+    #[serde(default)]
+    #[serde_as(as = "HashMap<_, DisplayFromStr>")]
+    borrowed_layers: HashMap<RelativePath, ZTenantId>,
+    /// Tenant generations the layers were uploaded with, see [`remote_layer_path`].
+    /// Layers uploaded without a generation, including all layers of the older index parts, have no entry.
+    #[serde(default)]
+    layer_generations: HashMap<RelativePath, u32>,
+    /// Older index parts have no such field, their timelines have no clones.
+    #[serde(default)]
+    has_clones: bool,
+    /// Older index parts have no such field, see [`RemoteTimeline::remote_consistent_lsn`] for the meaning.
+    #[serde(default)]
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    remote_consistent_lsn: Option<Lsn>,
+    /// Layers removed from the index that still wait to be deleted from the remote storage,
+    /// so that their deletion is resumed after a restart. Older index parts have no such field.
+    #[serde(default)]
+    pending_deletions: HashMap<RelativePath, PendingLayerDeletion>,
 }
 
 impl IndexPart {
@@ -203,6 +522,13 @@ impl IndexPart {
             missing_layers,
             disk_consistent_lsn,
             metadata_bytes,
+            archived: false,
+            generation: None,
+            borrowed_layers: HashMap::new(),
+            layer_generations: HashMap::new(),
+            has_clones: false,
+            remote_consistent_lsn: None,
+            pending_deletions: HashMap::new(),
         }
     }
 
@@ -210,6 +536,18 @@ impl IndexPart {
         &self.missing_layers
     }
 
+    pub fn generation(&self) -> Option<u32> {
+        self.generation
+    }
+
+    pub fn set_generation(&mut self, generation: Option<u32>) {
+        self.generation = generation;
+    }
+
+    pub fn remote_consistent_lsn(&self) -> Option<Lsn> {
+        self.remote_consistent_lsn
+    }
+
     pub fn from_remote_timeline(
         timeline_path: &Path,
         remote_timeline: RemoteTimeline,
@@ -222,6 +560,30 @@ impl IndexPart {
                 .context("Failed to convert missing layers' paths to relative ones")?,
             disk_consistent_lsn: remote_timeline.metadata.disk_consistent_lsn(),
             metadata_bytes,
+            archived: remote_timeline.archived,
+            generation: None,
+            borrowed_layers: remote_timeline
+                .borrowed_layers
+                .into_iter()
+                .map(|(layer, owner)| Ok((RelativePath::new(timeline_path, layer)?, owner)))
+                .collect::<anyhow::Result<_>>()
+                .context("Failed to convert borrowed layers' paths to relative ones")?,
+            layer_generations: remote_timeline
+                .layer_generations
+                .into_iter()
+                .map(|(layer, generation)| {
+                    Ok((RelativePath::new(timeline_path, layer)?, generation))
+                })
+                .collect::<anyhow::Result<_>>()
+                .context("Failed to convert layer generations' paths to relative ones")?,
+            has_clones: remote_timeline.has_clones,
+            remote_consistent_lsn: Some(remote_timeline.remote_consistent_lsn),
+            pending_deletions: remote_timeline
+                .pending_deletions
+                .into_iter()
+                .map(|(layer, pending)| Ok((RelativePath::new(timeline_path, layer)?, pending)))
+                .collect::<anyhow::Result<_>>()
+                .context("Failed to convert pending deletions' paths to relative ones")?,
         })
     }
 }
@@ -268,8 +630,23 @@ mod tests {
                 timeline_path.join("missing_1"),
                 timeline_path.join("missing_2"),
             ]),
+            borrowed_layers: HashMap::from([(
+                timeline_path.join("layer_2"),
+                ZTenantId::generate(),
+            )]),
+            layer_generations: HashMap::from([(timeline_path.join("layer_1"), 3)]),
+            pending_deletions: HashMap::from([(
+                timeline_path.join("deleted_1"),
+                PendingLayerDeletion {
+                    generation: Some(2),
+                    due_at: 1_000_000,
+                },
+            )]),
+            remote_consistent_lsn: Lsn(2),
             metadata: metadata.clone(),
             awaits_download: false,
+            archived: true,
+            has_clones: true,
         };
 
         let index_part = IndexPart::from_remote_timeline(&timeline_path, remote_timeline.clone())
@@ -346,6 +723,30 @@ mod tests {
             remote_timeline.awaits_download, restored_timeline.awaits_download,
             "remote timeline -> index part -> remote timeline conversion should not loose download flag"
         );
+        assert_eq!(
+            remote_timeline.archived, restored_timeline.archived,
+            "remote timeline -> index part -> remote timeline conversion should not loose archived flag"
+        );
+        assert_eq!(
+            remote_timeline.has_clones, restored_timeline.has_clones,
+            "remote timeline -> index part -> remote timeline conversion should not loose has_clones flag"
+        );
+        assert_eq!(
+            remote_timeline.borrowed_layers, restored_timeline.borrowed_layers,
+            "remote timeline -> index part -> remote timeline conversion should not loose borrowed layers"
+        );
+        assert_eq!(
+            remote_timeline.layer_generations, restored_timeline.layer_generations,
+            "remote timeline -> index part -> remote timeline conversion should not loose layer generations"
+        );
+        assert_eq!(
+            remote_timeline.pending_deletions, restored_timeline.pending_deletions,
+            "remote timeline -> index part -> remote timeline conversion should not loose pending deletions"
+        );
+        assert_eq!(
+            remote_timeline.remote_consistent_lsn, restored_timeline.remote_consistent_lsn,
+            "remote timeline -> index part -> remote timeline conversion should not alter remote consistent lsn"
+        );
 
         assert_eq!(
             remote_timeline
@@ -371,6 +772,200 @@ mod tests {
         );
     }
 
+    #[test]
+    fn pending_layer_deletions() {
+        let harness = RepoHarness::create("pending_layer_deletions").unwrap();
+        let timeline_path = harness.timeline_path(&TIMELINE_ID);
+        let metadata =
+            TimelineMetadata::new(Lsn(5).align(), Some(Lsn(4)), None, Lsn(3), Lsn(2), Lsn(1));
+        let [layer_1, layer_2, layer_3] =
+            ["layer_1", "layer_2", "layer_3"].map(|name| timeline_path.join(name));
+
+        let mut remote_timeline = RemoteTimeline::new(metadata);
+        remote_timeline.add_timeline_layers_with_generations(
+            [layer_1.clone(), layer_2.clone()],
+            &HashMap::from([(layer_1.clone(), 1)]),
+        );
+        remote_timeline.register_layer_deletions(
+            &HashSet::from([layer_1.clone(), layer_2.clone(), layer_3]),
+            100,
+        );
+        assert!(remote_timeline.stored_files().is_empty());
+        assert_eq!(
+            remote_timeline.pending_deletions(),
+            &HashMap::from([
+                (
+                    layer_1.clone(),
+                    PendingLayerDeletion {
+                        generation: Some(1),
+                        due_at: 100
+                    }
+                ),
+                (
+                    layer_2.clone(),
+                    PendingLayerDeletion {
+                        generation: None,
+                        due_at: 100
+                    }
+                ),
+            ]),
+            "Only the layers stored remotely should wait for the deletion"
+        );
+
+        // Uploaded again into the same object, the layer must stay
+        remote_timeline.add_timeline_layers_with_generations(
+            [layer_1.clone()],
+            &HashMap::from([(layer_1.clone(), 1)]),
+        );
+        // Uploaded by another generation, the old object still has to go
+        remote_timeline.add_timeline_layers_with_generations(
+            [layer_2.clone()],
+            &HashMap::from([(layer_2.clone(), 2)]),
+        );
+        assert_eq!(
+            remote_timeline
+                .pending_deletions()
+                .keys()
+                .collect::<Vec<_>>(),
+            vec![&layer_2]
+        );
+
+        remote_timeline.complete_layer_deletion(&layer_2, Some(2));
+        assert_eq!(remote_timeline.pending_deletions().len(), 1);
+        remote_timeline.complete_layer_deletion(&layer_2, None);
+        assert!(remote_timeline.pending_deletions().is_empty());
+
+        // Layers of a timeline with clones are never deleted
+        remote_timeline.has_clones = true;
+        remote_timeline.register_layer_deletions(&HashSet::from([layer_1]), 100);
+        assert!(remote_timeline.pending_deletions().is_empty());
+    }
+
+    #[test]
+    fn index_part_without_archived_flag() {
+        let index_part_json = r#"{
+            "timeline_layers": ["layer_1"],
+            "missing_layers": [],
+            "disk_consistent_lsn": "0/16960E8",
+            "metadata_bytes": []
+        }"#;
+
+        let index_part: IndexPart = serde_json::from_str(index_part_json)
+            .expect("Index part without archived flag should be deserializable");
+        assert!(
+            !index_part.archived,
+            "Index part without archived flag should not be considered archived"
+        );
+        assert_eq!(
+            index_part.generation(),
+            None,
+            "Index part without generation should have no generation"
+        );
+        assert_eq!(
+            index_part.remote_consistent_lsn(),
+            None,
+            "Index part without remote consistent lsn should have no remote consistent lsn"
+        );
+        assert!(
+            index_part.pending_deletions.is_empty(),
+            "Index part without pending deletions should have none"
+        );
+    }
+
+    #[test]
+    fn remote_layer_paths() {
+        let layer = PathBuf::from("/timeline/000000067F000032BE0000400000000020B6-000000067F000032BE0000400000000030B6__00000000016B4151-00000000016B5A51");
+        assert_eq!(remote_layer_path(&layer, None), layer);
+        assert_eq!(parse_remote_layer_path(&layer), (layer.clone(), None));
+
+        let remote_path = remote_layer_path(&layer, Some(10));
+        assert_eq!(
+            remote_path.file_name().unwrap().to_str().unwrap(),
+            "000000067F000032BE0000400000000020B6-000000067F000032BE0000400000000030B6__00000000016B4151-00000000016B5A51-g0000000a"
+        );
+        assert_eq!(parse_remote_layer_path(&remote_path), (layer, Some(10)));
+
+        // The stamp of a layer name ends with 8 hex digits too
+        let stamped_layer = PathBuf::from("/timeline/000000067F000032BE0000400000000020B6-000000067F000032BE0000400000000030B6__00000000016B4151__v3-0000000A-1B2C3D4E");
+        assert_eq!(
+            parse_remote_layer_path(&stamped_layer),
+            (stamped_layer.clone(), None)
+        );
+        assert_eq!(
+            parse_remote_layer_path(&remote_layer_path(&stamped_layer, Some(u32::MAX))),
+            (stamped_layer, Some(u32::MAX))
+        );
+    }
+
+    #[test]
+    fn remote_timeline_clone() {
+        let harness = RepoHarness::create("remote_timeline_clone").unwrap();
+        let source_path = harness.timeline_path(&TIMELINE_ID);
+        let clone_tenant_id = ZTenantId::generate();
+        let clone_path = harness.conf.timeline_path(&TIMELINE_ID, &clone_tenant_id);
+        let metadata =
+            TimelineMetadata::new(Lsn(5).align(), Some(Lsn(4)), None, Lsn(3), Lsn(2), Lsn(1));
+
+        let mut source = RemoteTimeline::new(metadata);
+        source.add_timeline_layers_with_generations(
+            [source_path.join("layer_1"), source_path.join("layer_2")],
+            &HashMap::from([(source_path.join("layer_2"), 7)]),
+        );
+
+        let mut clone = source
+            .clone_for_tenant(&source_path, &clone_path, harness.tenant_id)
+            .expect("Remote timeline should be clonable");
+        assert_eq!(
+            clone.stored_files(),
+            &HashSet::from([clone_path.join("layer_1"), clone_path.join("layer_2")])
+        );
+        assert_eq!(
+            clone.layer_owner(&clone_path.join("layer_1")),
+            Some(harness.tenant_id),
+            "Cloned layers should be read from the source tenant"
+        );
+        assert!(!clone.has_clones);
+        assert_eq!(
+            clone.remote_layer_path(&clone_path.join("layer_2")),
+            remote_layer_path(&clone_path.join("layer_2"), Some(7)),
+            "Cloned layers should be read with the generation they were uploaded with"
+        );
+        assert_eq!(clone.layer_generation(&clone_path.join("layer_1")), None);
+
+        // New layers of the clone are its own, and only borrowed layers are kept on deletion
+        clone.add_timeline_layers([clone_path.join("layer_3")]);
+        assert_eq!(clone.layer_owner(&clone_path.join("layer_3")), None);
+        let to_delete = HashSet::from([clone_path.join("layer_1"), clone_path.join("layer_3")]);
+        assert_eq!(
+            clone.layers_to_keep_remotely(&to_delete),
+            HashSet::from([clone_path.join("layer_1")])
+        );
+        clone.remove_layers(&to_delete);
+        assert_eq!(
+            clone.stored_files(),
+            &HashSet::from([clone_path.join("layer_2")])
+        );
+
+        // A clone of a clone reads the layers from the tenant that stores them
+        let second_clone_path = harness
+            .conf
+            .timeline_path(&TIMELINE_ID, &ZTenantId::generate());
+        let second_clone = clone
+            .clone_for_tenant(&clone_path, &second_clone_path, clone_tenant_id)
+            .expect("Cloned remote timeline should be clonable");
+        assert_eq!(
+            second_clone.layer_owner(&second_clone_path.join("layer_2")),
+            Some(harness.tenant_id)
+        );
+
+        // A timeline with clones keeps all of its layers
+        source.has_clones = true;
+        assert_eq!(
+            source.layers_to_keep_remotely(&HashSet::from([source_path.join("layer_1")])),
+            HashSet::from([source_path.join("layer_1")])
+        );
+    }
+
     #[test]
     fn index_part_conversion_negatives() {
         let harness = RepoHarness::create("index_part_conversion_negatives").unwrap();
@@ -389,8 +984,14 @@ mod tests {
                     timeline_path.join("missing_1"),
                     timeline_path.join("missing_2"),
                 ]),
+                borrowed_layers: HashMap::new(),
+                layer_generations: HashMap::new(),
+                pending_deletions: HashMap::new(),
+                remote_consistent_lsn: Lsn(0),
                 metadata: metadata.clone(),
                 awaits_download: false,
+                archived: false,
+                has_clones: false,
             },
         );
         assert!(conversion_result.is_err(), "Should not be able to convert metadata with layer paths that are not in the timeline directory");
@@ -406,8 +1007,14 @@ mod tests {
                     PathBuf::from("bad_path"),
                     timeline_path.join("missing_2"),
                 ]),
+                borrowed_layers: HashMap::new(),
+                layer_generations: HashMap::new(),
+                pending_deletions: HashMap::new(),
+                remote_consistent_lsn: Lsn(0),
                 metadata,
                 awaits_download: false,
+                archived: false,
+                has_clones: false,
             },
         );
         assert!(conversion_result.is_err(), "Should not be able to convert metadata with missing layer paths that are not in the timeline directory");
@@ -0,0 +1,490 @@
+//! Uploads of image layers to the remote storage while they are being written, enabled with `stream_layer_uploads`.
+//!
+//! An image layer file is written front to back, except for its first block: the summary there is filled in last.
+//! So every part of the multipart upload, besides the first one, is final as soon as the file grows past its end,
+//! and [`LayerUploadStream`] hands it over to a separate thread that uploads it right away.
+//!
+//! Finishing the layer doesn't wait for the stream: the layer writer renames the file and moves on, and the thread
+//! uploads the parts it was given before, from the renamed file. The regular upload of the layer (see
+//! [`super::upload`]) then takes the streamed parts over with [`record_streamed_parts`], recording them in the
+//! upload state file of the layer, and uploads the first part and the parts not streamed only.
+//!
+//! Streaming is best effort: a part that fails to upload is left for the regular upload. If the stream doesn't
+//! finish within [`STREAM_FINISH_TIMEOUT`] after the layer, the regular upload uploads the whole layer, and the
+//! streamed upload is aborted, as it is when the layer is never finished. So is it if the pageserver stops before
+//! the regular upload takes the parts over: the layer gets uploaded in full after the restart.
+
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Context;
+use lazy_static::lazy_static;
+use metrics::{register_int_counter, IntCounter};
+use once_cell::sync::OnceCell;
+use remote_storage::{RemoteStorage, StreamingUpload};
+use tokio::sync::{mpsc, watch};
+use tracing::*;
+
+use super::{index::remote_layer_path, upload::upload_state_path};
+use crate::{thread_mgr, thread_mgr::ThreadKind};
+
+lazy_static! {
+    static ref STREAMED_PARTS: IntCounter = register_int_counter!(
+        "pageserver_layer_upload_streamed_parts_total",
+        "Number of parts of image layers uploaded while the layers were being written"
+    )
+    .expect("failed to define a metric");
+    /// The streams of the layers complete locally, by the final layer paths.
+    static ref FINISHED_STREAMS: Mutex<HashMap<PathBuf, FinishedStream>> =
+        Mutex::new(HashMap::new());
+}
+
+/// How long the upload of a complete layer waits for its stream to upload the parts it was given.
+const STREAM_FINISH_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Sender of the layers to stream, set when streaming is enabled.
+static LAYER_STREAMS: OnceCell<(NonZeroUsize, mpsc::UnboundedSender<LayerToStream>)> =
+    OnceCell::new();
+
+struct LayerToStream {
+    temp_path: PathBuf,
+    final_path: PathBuf,
+    /// Tenant generation to upload the layer with, see [`remote_layer_path`].
+    generation: Option<u32>,
+    commands: mpsc::UnboundedReceiver<StreamCommand>,
+}
+
+enum StreamCommand {
+    /// The part with the given number is written completely.
+    UploadPart(u32),
+    /// The layer is complete, with the given size. The sender is dropped when the parts are handed over.
+    Finish {
+        file_size: u64,
+        done: watch::Sender<()>,
+    },
+}
+
+enum FinishedStream {
+    /// The stream is uploading the parts it was given before the layer was complete.
+    Finishing(watch::Receiver<()>),
+    /// The parts are uploaded, waiting for the layer upload to take them over.
+    Done {
+        upload: StreamingUpload,
+        file_size: u64,
+        generation: Option<u32>,
+    },
+    /// The layer upload didn't wait for the stream any longer, the stream has to abort its upload.
+    Abandoned,
+}
+
+/// Launch a thread that uploads the parts of the image layers being written.
+pub(super) fn spawn_layer_stream_thread<P, S>(
+    storage: Arc<S>,
+    part_size: NonZeroUsize,
+) -> anyhow::Result<()>
+where
+    P: Debug + Send + Sync + 'static,
+    S: RemoteStorage<RemoteObjectId = P> + Send + Sync + 'static,
+{
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    LAYER_STREAMS
+        .set((part_size, sender))
+        .map_err(|_| anyhow::anyhow!("Layer upload streaming is initialized already"))?;
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .context("Failed to create layer upload stream runtime")?;
+
+    thread_mgr::spawn(
+        ThreadKind::StorageSync,
+        None,
+        None,
+        "Layer upload stream thread",
+        false,
+        move || {
+            runtime.block_on(async {
+                loop {
+                    tokio::select! {
+                        layer = receiver.recv() => match layer {
+                            Some(layer) => {
+                                tokio::spawn(stream_layer(Arc::clone(&storage), part_size, layer));
+                            }
+                            None => break,
+                        },
+                        _ = thread_mgr::shutdown_watcher() => {
+                            info!("Shutdown requested, stopping");
+                            break;
+                        }
+                    }
+                }
+            });
+            Ok(())
+        },
+    )
+    .context("Failed to spawn layer upload stream thread")?;
+    Ok(())
+}
+
+async fn stream_layer<P, S>(storage: Arc<S>, part_size: NonZeroUsize, mut layer: LayerToStream)
+where
+    P: Debug + Send + Sync + 'static,
+    S: RemoteStorage<RemoteObjectId = P> + Send + Sync + 'static,
+{
+    let to = match storage.remote_object_id(&remote_layer_path(&layer.final_path, layer.generation))
+    {
+        Ok(to) => to,
+        Err(e) => {
+            warn!(
+                "Not streaming layer '{}': {e:?}",
+                layer.final_path.display()
+            );
+            return;
+        }
+    };
+
+    let mut upload = None;
+    while let Some(command) = layer.commands.recv().await {
+        match command {
+            StreamCommand::UploadPart(part_number) => {
+                if upload.is_none() {
+                    match StreamingUpload::start(storage.as_ref(), &to, part_size, None).await {
+                        Ok(new_upload) => upload = Some(new_upload),
+                        Err(e) => {
+                            warn!(
+                                "Failed to start streaming layer '{}': {e:?}",
+                                layer.final_path.display()
+                            );
+                            continue;
+                        }
+                    }
+                }
+                // The complete layer is renamed without waiting for the parts given before
+                let from_path = if layer.temp_path.exists() {
+                    &layer.temp_path
+                } else {
+                    &layer.final_path
+                };
+                if let Some(upload) = upload.as_mut() {
+                    match upload
+                        .upload_part(storage.as_ref(), from_path, &to, part_number)
+                        .await
+                    {
+                        Ok(()) => STREAMED_PARTS.inc(),
+                        Err(e) => warn!(
+                            "Failed to stream part {part_number} of layer '{}', leaving it for the layer upload: {e:?}",
+                            layer.final_path.display()
+                        ),
+                    }
+                }
+            }
+            StreamCommand::Finish { file_size, done } => {
+                let upload_to_abort = {
+                    let mut finished_streams = FINISHED_STREAMS.lock().unwrap();
+                    match (finished_streams.remove(&layer.final_path), upload.take()) {
+                        (Some(FinishedStream::Finishing(_)), Some(upload)) => {
+                            debug!(
+                                "Streamed {} parts of layer '{}'",
+                                upload.uploaded_parts(),
+                                layer.final_path.display()
+                            );
+                            finished_streams.insert(
+                                layer.final_path.clone(),
+                                FinishedStream::Done {
+                                    upload,
+                                    file_size,
+                                    generation: layer.generation,
+                                },
+                            );
+                            None
+                        }
+                        (_, upload) => upload,
+                    }
+                };
+                drop(done);
+                upload = upload_to_abort;
+                break;
+            }
+        }
+    }
+
+    // The layer was abandoned before it was complete, or its upload didn't wait for the stream
+    if let Some(upload) = upload {
+        if let Err(e) = upload.abort(storage.as_ref(), &to).await {
+            warn!(
+                "Failed to abort the streamed upload of layer '{}': {e:?}",
+                layer.final_path.display()
+            );
+        }
+    }
+}
+
+///
+/// Streams the parts of an image layer file to the remote storage as the file
+/// is written. Dropping it without [`LayerUploadStream::finish`] discards the
+/// parts uploaded so far.
+///
+pub struct LayerUploadStream {
+    final_path: PathBuf,
+    part_size: u64,
+    /// Number of the next part to stream. The first part is never streamed,
+    /// it holds the summary block that is written last.
+    next_part: u32,
+    commands: mpsc::UnboundedSender<StreamCommand>,
+}
+
+impl LayerUploadStream {
+    /// Starts streaming the file that is being written at 'temp_path', and is
+    /// going to be renamed to 'final_path' when complete, to the storage path
+    /// of the given tenant generation. Returns `None` if streaming is not enabled.
+    pub fn start(temp_path: &Path, final_path: &Path, generation: Option<u32>) -> Option<Self> {
+        let (part_size, layers) = LAYER_STREAMS.get()?;
+        let (commands, commands_receiver) = mpsc::unbounded_channel();
+        layers
+            .send(LayerToStream {
+                temp_path: temp_path.to_path_buf(),
+                final_path: final_path.to_path_buf(),
+                generation,
+                commands: commands_receiver,
+            })
+            .ok()?;
+        Some(Self {
+            final_path: final_path.to_path_buf(),
+            part_size: part_size.get() as u64,
+            next_part: 2,
+            commands,
+        })
+    }
+
+    /// Streams the parts that the file, written up to 'written_bytes', has complete.
+    pub fn written(&mut self, written_bytes: u64) {
+        while written_bytes >= self.next_part as u64 * self.part_size {
+            if self
+                .commands
+                .send(StreamCommand::UploadPart(self.next_part))
+                .is_err()
+            {
+                break;
+            }
+            self.next_part += 1;
+        }
+    }
+
+    /// Hands the streamed parts over to the upload of the complete file of
+    /// 'file_size' bytes, see [`record_streamed_parts`]. Doesn't wait for the
+    /// parts still being uploaded, the file can be renamed to its final path
+    /// right away.
+    pub fn finish(self, file_size: u64) {
+        let (done, done_receiver) = watch::channel(());
+        FINISHED_STREAMS.lock().unwrap().insert(
+            self.final_path.clone(),
+            FinishedStream::Finishing(done_receiver),
+        );
+        if self
+            .commands
+            .send(StreamCommand::Finish { file_size, done })
+            .is_err()
+        {
+            // The stream thread is gone, the layer is uploaded in full then
+            FINISHED_STREAMS.lock().unwrap().remove(&self.final_path);
+        }
+    }
+}
+
+/// Records the parts streamed for the complete layer at 'layer_path' in its upload state file, for its upload
+/// to the storage path of 'generation' to continue from. Waits up to [`STREAM_FINISH_TIMEOUT`] for the stream
+/// to upload the parts it was given. If it doesn't finish by then, or streamed the layer to the path of another
+/// generation, the streamed parts are dropped, and the layer gets uploaded in full.
+pub(super) async fn record_streamed_parts<P, S>(
+    storage: &S,
+    layer_path: &Path,
+    generation: Option<u32>,
+) where
+    P: Debug + Send + Sync + 'static,
+    S: RemoteStorage<RemoteObjectId = P> + Send + Sync + 'static,
+{
+    let done_receiver = match FINISHED_STREAMS.lock().unwrap().get(layer_path) {
+        Some(FinishedStream::Finishing(done_receiver)) => Some(done_receiver.clone()),
+        Some(FinishedStream::Done { .. }) => None,
+        Some(FinishedStream::Abandoned) | None => return,
+    };
+    if let Some(mut done_receiver) = done_receiver {
+        // Nothing is ever sent, the sender is dropped when the stream is done
+        let stream_done = async { while done_receiver.changed().await.is_ok() {} };
+        if tokio::time::timeout(STREAM_FINISH_TIMEOUT, stream_done)
+            .await
+            .is_err()
+        {
+            warn!(
+                "Layer '{}' stream didn't finish in {STREAM_FINISH_TIMEOUT:?}, uploading the whole layer",
+                layer_path.display()
+            );
+        }
+    }
+
+    let (upload, file_size, stream_generation) = {
+        let mut finished_streams = FINISHED_STREAMS.lock().unwrap();
+        match finished_streams.remove(layer_path) {
+            Some(FinishedStream::Done {
+                upload,
+                file_size,
+                generation,
+            }) => (upload, file_size, generation),
+            Some(FinishedStream::Finishing(_)) => {
+                finished_streams.insert(layer_path.to_path_buf(), FinishedStream::Abandoned);
+                return;
+            }
+            Some(FinishedStream::Abandoned) | None => return,
+        }
+    };
+
+    if stream_generation == generation {
+        if let Err(e) = upload
+            .finish(file_size, &upload_state_path(layer_path))
+            .await
+        {
+            warn!(
+                "Failed to record the streamed parts of layer '{}': {e:?}",
+                layer_path.display()
+            );
+        }
+        return;
+    }
+
+    info!(
+        "Layer '{}' was streamed with generation {stream_generation:?}, not {generation:?}, uploading the whole layer",
+        layer_path.display()
+    );
+    let abort_result =
+        match storage.remote_object_id(&remote_layer_path(layer_path, stream_generation)) {
+            Ok(to) => upload.abort(storage, &to).await,
+            Err(e) => Err(e),
+        };
+    if let Err(e) = abort_result {
+        warn!(
+            "Failed to abort the streamed upload of layer '{}': {e:?}",
+            layer_path.display()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use remote_storage::{upload_file_in_parts, LocalFs};
+    use tempfile::tempdir;
+
+    use super::*;
+
+    const PART_SIZE: usize = 1024;
+
+    /// A stream of the layer, and the layer for [`stream_layer`] to stream.
+    fn test_stream(
+        temp_path: &Path,
+        final_path: &Path,
+        generation: Option<u32>,
+    ) -> (LayerUploadStream, LayerToStream) {
+        let (commands, commands_receiver) = mpsc::unbounded_channel();
+        (
+            LayerUploadStream {
+                final_path: final_path.to_path_buf(),
+                part_size: PART_SIZE as u64,
+                next_part: 2,
+                commands,
+            },
+            LayerToStream {
+                temp_path: temp_path.to_path_buf(),
+                final_path: final_path.to_path_buf(),
+                generation,
+                commands: commands_receiver,
+            },
+        )
+    }
+
+    #[tokio::test]
+    async fn streamed_parts_handed_over() -> anyhow::Result<()> {
+        let workdir = tempdir()?;
+        let storage = Arc::new(LocalFs::new(
+            workdir.path().join("storage"),
+            workdir.path().to_owned(),
+        )?);
+        let part_size = NonZeroUsize::new(PART_SIZE).unwrap();
+        let contents = (0..3 * PART_SIZE + 100)
+            .map(|i| i as u8)
+            .collect::<Vec<_>>();
+        let temp_path = workdir.path().join("layer.temp");
+        let final_path = workdir.path().join("layer");
+
+        let (mut stream, layer) = test_stream(&temp_path, &final_path, None);
+        let stream_task = tokio::spawn(stream_layer(Arc::clone(&storage), part_size, layer));
+        std::fs::write(&temp_path, &contents)?;
+        stream.written(contents.len() as u64);
+        // Finishing doesn't wait for the stream, the parts are read from the renamed file
+        stream.finish(contents.len() as u64);
+        std::fs::rename(&temp_path, &final_path)?;
+        stream_task.await?;
+
+        record_streamed_parts(storage.as_ref(), &final_path, None).await;
+        let state_path = upload_state_path(&final_path);
+        let state: serde_json::Value = serde_json::from_slice(&std::fs::read(&state_path)?)?;
+        assert_eq!(
+            state["uploaded_parts"].as_array().map(Vec::len),
+            Some(2),
+            "Parts 2 and 3 should be streamed"
+        );
+        assert!(
+            !FINISHED_STREAMS.lock().unwrap().contains_key(&final_path),
+            "The streamed parts should be taken over"
+        );
+
+        let to = storage.remote_object_id(&final_path)?;
+        upload_file_in_parts(
+            storage.as_ref(),
+            &final_path,
+            &to,
+            part_size,
+            &state_path,
+            None,
+        )
+        .await?;
+        assert_eq!(std::fs::read(&to)?, contents);
+        assert!(!state_path.exists());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn streamed_parts_of_other_generation_dropped() -> anyhow::Result<()> {
+        let workdir = tempdir()?;
+        let storage = Arc::new(LocalFs::new(
+            workdir.path().join("storage"),
+            workdir.path().to_owned(),
+        )?);
+        let part_size = NonZeroUsize::new(PART_SIZE).unwrap();
+        let contents = vec![1; 3 * PART_SIZE];
+        let temp_path = workdir.path().join("layer.temp");
+        let final_path = workdir.path().join("layer");
+
+        let (mut stream, layer) = test_stream(&temp_path, &final_path, Some(1));
+        let stream_task = tokio::spawn(stream_layer(Arc::clone(&storage), part_size, layer));
+        std::fs::write(&temp_path, &contents)?;
+        stream.written(contents.len() as u64);
+        stream.finish(contents.len() as u64);
+        std::fs::rename(&temp_path, &final_path)?;
+        stream_task.await?;
+
+        record_streamed_parts(storage.as_ref(), &final_path, Some(2)).await;
+        assert!(
+            !upload_state_path(&final_path).exists(),
+            "The parts streamed to another storage path should not be used"
+        );
+        assert!(!FINISHED_STREAMS.lock().unwrap().contains_key(&final_path));
+
+        Ok(())
+    }
+}
@@ -1,25 +1,39 @@
 //! Timeline synchronization logic to compress and upload to the remote storage all new timeline files from the checkpoints.
 
-use std::{fmt::Debug, path::PathBuf};
+use std::{
+    fmt::Debug,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+};
 
-use anyhow::Context;
+use anyhow::{anyhow, Context};
 use futures::stream::{FuturesUnordered, StreamExt};
-use remote_storage::RemoteStorage;
+use remote_storage::{path_with_suffix_extension, upload_file_in_parts, RemoteStorage};
 use tokio::fs;
 use tracing::{debug, error, info, warn};
 
 use utils::zid::ZTenantTimelineId;
 
 use super::{
-    index::{IndexPart, RemoteTimeline},
+    index::{remote_layer_path, IndexPart, RemoteTimeline},
+    layer_stream::record_streamed_parts,
     LayersUpload, SyncData, SyncQueue,
 };
 use crate::{
     config::PageServerConf, layered_repository::metadata::metadata_path, storage_sync::SyncTask,
+    tenant_usage,
 };
 
+/// Extension of the files that record the progress of a layer upload in parts, placed next to the layer.
+pub const UPLOAD_STATE_EXTENSION: &str = "upload_state";
+
+/// Path of the file to record the progress of the given layer's upload in parts in.
+pub fn upload_state_path(layer_path: &Path) -> PathBuf {
+    path_with_suffix_extension(layer_path, UPLOAD_STATE_EXTENSION)
+}
+
 /// Serializes and uploads the given index part data to the remote storage.
-pub(super) async fn upload_index_part<P, S>(
+pub async fn upload_index_part<P, S>(
     conf: &'static PageServerConf,
     storage: &S,
     sync_id: ZTenantTimelineId,
@@ -71,6 +85,9 @@ pub(super) enum UploadedTimeline {
 
 /// Attempts to upload given layer files.
 /// No extra checks for overlapping files is made and any files that are already present remotely will be overwritten, if submitted during the upload.
+/// Layers bigger than `multipart_upload_part_size` are uploaded in parts of that size, resuming the previous, interrupted upload of the same layer, if any.
+/// A layer counts as uploaded only after the storage reports the same size for it as the local file has.
+/// The layers are uploaded under the keys of the given tenant generation, see [`remote_layer_path`].
 ///
 /// On an error, bumps the retries count and reschedules the entire task.
 pub(super) async fn upload_timeline_layers<'a, P, S>(
@@ -79,6 +96,8 @@ pub(super) async fn upload_timeline_layers<'a, P, S>(
     remote_timeline: Option<&'a RemoteTimeline>,
     sync_id: ZTenantTimelineId,
     mut upload_data: SyncData<LayersUpload>,
+    generation: Option<u32>,
+    multipart_upload_part_size: NonZeroUsize,
 ) -> UploadedTimeline
 where
     P: Debug + Send + Sync + 'static,
@@ -116,7 +135,7 @@ where
         .into_iter()
         .map(|source_path| async move {
             let storage_path = storage
-                .remote_object_id(&source_path)
+                .remote_object_id(&remote_layer_path(&source_path, generation))
                 .with_context(|| {
                     format!(
                         "Failed to get the layer storage path for local path '{}'",
@@ -147,18 +166,51 @@ where
                 .map_err(UploadError::Other)?
                 .len() as usize;
 
-            match storage
-                .upload(source_file, source_size, &storage_path, None)
+            let upload_result = if source_size > multipart_upload_part_size.get() {
+                drop(source_file);
+                // Continue from the parts uploaded while the layer was written, if any
+                record_streamed_parts(storage, &source_path, generation).await;
+                upload_file_in_parts(
+                    storage,
+                    &source_path,
+                    &storage_path,
+                    multipart_upload_part_size,
+                    &upload_state_path(&source_path),
+                    None,
+                )
+                .await
+            } else {
+                storage
+                    .upload(source_file, source_size, &storage_path, None)
+                    .await
+            };
+
+            if let Err(e) = upload_result.with_context(|| {
+                format!(
+                    "Failed to upload a layer from local path '{}'",
+                    source_path.display()
+                )
+            }) {
+                return Err(UploadError::MissingLocalFile(source_path, e));
+            }
+
+            // The index part is going to reference the layer, so check that the storage has all of it
+            let remote_size = storage
+                .object_size(&storage_path)
                 .await
                 .with_context(|| {
-                    format!(
-                        "Failed to upload a layer from local path '{}'",
-                        source_path.display()
-                    )
-                }) {
-                Ok(()) => Ok(source_path),
-                Err(e) => Err(UploadError::MissingLocalFile(source_path, e)),
+                    format!("Failed to check the uploaded layer at '{storage_path:?}'")
+                })
+                .map_err(UploadError::Other)?;
+            if remote_size != Some(source_size as u64) {
+                return Err(UploadError::Other(anyhow!(
+                    "Uploaded layer '{}' has size {remote_size:?} in the storage, expected {source_size}",
+                    source_path.display()
+                )));
             }
+
+            tenant_usage::record_io(&sync_id.tenant_id, "Upload", source_size as u64, 0);
+            Ok(source_path)
         })
         .collect::<FuturesUnordered<_>>();
 
@@ -167,6 +219,12 @@ where
         match upload_result {
             Ok(uploaded_path) => {
                 upload.layers_to_upload.remove(&uploaded_path);
+                match generation {
+                    Some(generation) => upload
+                        .layer_generations
+                        .insert(uploaded_path.clone(), generation),
+                    None => upload.layer_generations.remove(&uploaded_path),
+                };
                 upload.uploaded_layers.insert(uploaded_path);
             }
             Err(e) => match e {
@@ -219,7 +277,7 @@ enum UploadError {
 #[cfg(test)]
 mod tests {
     use std::{
-        collections::{BTreeSet, HashSet},
+        collections::{BTreeSet, HashMap, HashSet},
         num::NonZeroUsize,
     };
 
@@ -230,7 +288,7 @@ mod tests {
     use crate::{
         repository::repo_harness::{RepoHarness, TIMELINE_ID},
         storage_sync::{
-            index::RelativePath,
+            index::{parse_remote_layer_path, RelativePath},
             test_utils::{create_local_timeline, dummy_metadata},
         },
     };
@@ -266,6 +324,8 @@ mod tests {
             None,
             sync_id,
             SyncData::new(current_retries, timeline_upload.clone()),
+            None,
+            test_part_size(),
         )
         .await;
 
@@ -323,6 +383,130 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn layer_upload_with_generation() -> anyhow::Result<()> {
+        let harness = RepoHarness::create("layer_upload_with_generation")?;
+        let sync_queue = SyncQueue::new(NonZeroUsize::new(100).unwrap());
+        let sync_id = ZTenantTimelineId::new(harness.tenant_id, TIMELINE_ID);
+
+        let layer_files = ["a", "b"];
+        let storage = LocalFs::new(tempdir()?.path().to_owned(), harness.conf.workdir.clone())?;
+        let local_timeline_path = harness.timeline_path(&TIMELINE_ID);
+        let timeline_upload = create_local_timeline(
+            &harness,
+            TIMELINE_ID,
+            &layer_files,
+            dummy_metadata(Lsn(0x30)),
+        )
+        .await?;
+
+        let upload_result = upload_timeline_layers(
+            &storage,
+            &sync_queue,
+            None,
+            sync_id,
+            SyncData::new(0, timeline_upload),
+            Some(5),
+            test_part_size(),
+        )
+        .await;
+        let upload_data = match upload_result {
+            UploadedTimeline::Successful(upload_data) => upload_data,
+            wrong_result => {
+                panic!("Expected a successful upload for timeline, but got: {wrong_result:?}")
+            }
+        };
+        let expected_layers = layer_files
+            .iter()
+            .map(|file| (local_timeline_path.join(file), 5))
+            .collect::<HashMap<_, _>>();
+        assert_eq!(
+            upload_data.data.layer_generations, expected_layers,
+            "Uploaded layers should be recorded with their generation"
+        );
+
+        // The storage keys have the generation, and map back to the local layers
+        let listed_layers = storage
+            .list()
+            .await?
+            .into_iter()
+            .map(|storage_path| {
+                let (layer, generation) =
+                    parse_remote_layer_path(&storage.local_path(&storage_path)?);
+                Ok((
+                    layer,
+                    generation.expect("Uploaded layer should have a generation"),
+                ))
+            })
+            .collect::<anyhow::Result<HashMap<_, _>>>()?;
+        assert_eq!(listed_layers, expected_layers);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn big_layer_upload_in_parts() -> anyhow::Result<()> {
+        let harness = RepoHarness::create("big_layer_upload_in_parts")?;
+        let sync_queue = SyncQueue::new(NonZeroUsize::new(100).unwrap());
+        let sync_id = ZTenantTimelineId::new(harness.tenant_id, TIMELINE_ID);
+
+        let layer_files = ["big_layer"];
+        let storage = LocalFs::new(tempdir()?.path().to_owned(), harness.conf.workdir.clone())?;
+        let metadata = dummy_metadata(Lsn(0x30));
+        let local_timeline_path = harness.timeline_path(&TIMELINE_ID);
+        let timeline_upload =
+            create_local_timeline(&harness, TIMELINE_ID, &layer_files, metadata).await?;
+
+        let layer_path = local_timeline_path.join("big_layer");
+        let layer_contents = fs::read(&layer_path).await?;
+        let tiny_part_size = NonZeroUsize::new(3).unwrap();
+        assert!(
+            layer_contents.len() > tiny_part_size.get(),
+            "Layer should not fit into a single part"
+        );
+
+        let upload_result = upload_timeline_layers(
+            &storage,
+            &sync_queue,
+            None,
+            sync_id,
+            SyncData::new(0, timeline_upload),
+            None,
+            tiny_part_size,
+        )
+        .await;
+        assert!(
+            matches!(upload_result, UploadedTimeline::Successful(_)),
+            "Expected a successful upload in parts, but got: {upload_result:?}"
+        );
+
+        let storage_files = storage.list().await?;
+        assert_eq!(
+            storage_files.len(),
+            1,
+            "Only the layer itself should be in the storage"
+        );
+        let mut uploaded_contents = Vec::new();
+        storage
+            .download(&storage_files[0], &mut uploaded_contents)
+            .await?;
+        assert_eq!(
+            uploaded_contents, layer_contents,
+            "Layer assembled from parts should match the local one"
+        );
+        assert!(
+            !upload_state_path(&layer_path).exists(),
+            "Upload state should be removed after the upload"
+        );
+
+        Ok(())
+    }
+
+    fn test_part_size() -> NonZeroUsize {
+        NonZeroUsize::new(remote_storage::DEFAULT_REMOTE_STORAGE_MULTIPART_UPLOAD_PART_SIZE)
+            .unwrap()
+    }
+
     // Currently, GC can run between upload retries, removing local layers scheduled for upload. Test this scenario.
     #[tokio::test]
     async fn layer_upload_after_local_fs_update() -> anyhow::Result<()> {
@@ -357,6 +541,8 @@ mod tests {
             None,
             sync_id,
             SyncData::new(current_retries, timeline_upload.clone()),
+            None,
+            test_part_size(),
         )
         .await;
 
@@ -8,8 +8,15 @@
 //! We cannot use global or default config instead, because wrong settings
 //! may lead to a data loss.
 //!
+//! New settings are validated before they are applied, see [`TenantConf::validate`].
+//! The configuration files written before that are loaded as they are.
+//!
 use crate::config::PageServerConf;
-use serde::{Deserialize, Serialize};
+use postgres_ffi::pg_constants;
+use postgres_ffi::relfile_utils::{forkname_to_number, forknumber_to_name};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::fmt;
 use std::path::PathBuf;
 use std::time::Duration;
 use utils::zid::ZTenantId;
@@ -68,6 +75,104 @@ pub struct TenantConf {
     // Page versions older than this are garbage collected away.
     #[serde(with = "humantime_serde")]
     pub pitr_interval: Duration,
+    // Logical size of each of the tenant's timelines, in bytes, over which the
+    // timeline is reported. Nothing is refused over it. No limit, if not set.
+    pub logical_size_soft_limit: Option<u64>,
+    // Relation forks left out of the image layers. Their pages are only kept
+    // in delta layers, and GC removes their history like any other.
+    pub image_layer_excluded_forks: ForkSet,
+}
+
+///
+/// A set of relation forks, serialized as a list of fork names, like
+/// `["fsm", "vm"]`.
+///
+/// Only the FSM and the visibility map can be left out of the image layers:
+/// PostgreSQL rebuilds their pages when it finds them zeroed, and that's what a
+/// read of a page with no image and no WAL left returns for these forks. The
+/// main and init forks have to be materialized.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ForkSet(u8);
+
+impl ForkSet {
+    /// The forks that PostgreSQL can rebuild from zeroed pages.
+    pub const REBUILDABLE: ForkSet =
+        ForkSet(1 << pg_constants::FSM_FORKNUM | 1 << pg_constants::VISIBILITYMAP_FORKNUM);
+
+    pub fn contains(&self, forknum: u8) -> bool {
+        forknum < 8 && self.0 & (1 << forknum) != 0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn from_names<S: AsRef<str>>(names: &[S]) -> Result<ForkSet, InvalidTenantConf> {
+        let mut forks = ForkSet::default();
+        for name in names {
+            let name = name.as_ref();
+            let forknum = forkname_to_number(Some(name))
+                .map_err(|_| InvalidTenantConf(format!("unknown relation fork '{name}'")))?;
+            if !ForkSet::REBUILDABLE.contains(forknum) {
+                return Err(InvalidTenantConf(format!(
+                    "the '{name}' fork can't be excluded from image layers, only 'fsm' and 'vm' can"
+                )));
+            }
+            forks.0 |= 1 << forknum;
+        }
+        Ok(forks)
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        (0..8)
+            .filter(|forknum| self.contains(*forknum))
+            .filter_map(forknumber_to_name)
+            .collect()
+    }
+}
+
+impl fmt::Display for ForkSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.names().join(","))
+    }
+}
+
+impl Serialize for ForkSet {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.names())
+    }
+}
+
+impl<'de> Deserialize<'de> for ForkSet {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let names = Vec::<String>::deserialize(deserializer)?;
+        ForkSet::from_names(&names).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Error returned for settings that [`TenantConf::validate`] rejects.
+#[derive(Debug, thiserror::Error)]
+#[error("invalid tenant config: {0}")]
+pub struct InvalidTenantConf(String);
+
+/// Where the effective value of a tenant config option comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TenantConfSource {
+    /// Set for the tenant, at creation or with a config update
+    Tenant,
+    /// Not set for the tenant: the `tenant_config` of the pageserver
+    /// config file, or the built-in default
+    Pageserver,
+}
+
+/// Effective value of a tenant config option, in the serialized form of
+/// [`TenantConf`], and where it comes from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct EffectiveTenantConfValue {
+    pub value: serde_json::Value,
+    pub source: TenantConfSource,
 }
 
 /// Same as TenantConf, but this struct preserves the information about
@@ -85,6 +190,8 @@ pub struct TenantConfOpt {
     pub image_creation_threshold: Option<usize>,
     #[serde(with = "humantime_serde")]
     pub pitr_interval: Option<Duration>,
+    pub logical_size_soft_limit: Option<u64>,
+    pub image_layer_excluded_forks: Option<ForkSet>,
 }
 
 impl TenantConfOpt {
@@ -108,9 +215,44 @@ impl TenantConfOpt {
                 .image_creation_threshold
                 .unwrap_or(global_conf.image_creation_threshold),
             pitr_interval: self.pitr_interval.unwrap_or(global_conf.pitr_interval),
+            logical_size_soft_limit: self
+                .logical_size_soft_limit
+                .or(global_conf.logical_size_soft_limit),
+            image_layer_excluded_forks: self
+                .image_layer_excluded_forks
+                .unwrap_or(global_conf.image_layer_excluded_forks),
         }
     }
 
+    ///
+    /// The settings in effect for a tenant with this config, keyed by the option
+    /// name, with the source of each value.
+    ///
+    pub fn effective(
+        &self,
+        global_conf: TenantConf,
+    ) -> anyhow::Result<BTreeMap<String, EffectiveTenantConfValue>> {
+        let tenant_values = match serde_json::to_value(self)? {
+            serde_json::Value::Object(values) => values,
+            other => anyhow::bail!("unexpected serialized tenant config: {other}"),
+        };
+        let effective_values = match serde_json::to_value(self.merge(global_conf))? {
+            serde_json::Value::Object(values) => values,
+            other => anyhow::bail!("unexpected serialized tenant config: {other}"),
+        };
+
+        Ok(effective_values
+            .into_iter()
+            .map(|(name, value)| {
+                let source = match tenant_values.get(&name) {
+                    Some(tenant_value) if !tenant_value.is_null() => TenantConfSource::Tenant,
+                    _ => TenantConfSource::Pageserver,
+                };
+                (name, EffectiveTenantConfValue { value, source })
+            })
+            .collect())
+    }
+
     pub fn update(&mut self, other: &TenantConfOpt) {
         if let Some(checkpoint_distance) = other.checkpoint_distance {
             self.checkpoint_distance = Some(checkpoint_distance);
@@ -136,6 +278,12 @@ impl TenantConfOpt {
         if let Some(pitr_interval) = other.pitr_interval {
             self.pitr_interval = Some(pitr_interval);
         }
+        if let Some(logical_size_soft_limit) = other.logical_size_soft_limit {
+            self.logical_size_soft_limit = Some(logical_size_soft_limit);
+        }
+        if let Some(image_layer_excluded_forks) = other.image_layer_excluded_forks {
+            self.image_layer_excluded_forks = Some(image_layer_excluded_forks);
+        }
     }
 }
 
@@ -155,9 +303,55 @@ impl TenantConf {
             image_creation_threshold: DEFAULT_IMAGE_CREATION_THRESHOLD,
             pitr_interval: humantime::parse_duration(DEFAULT_PITR_INTERVAL)
                 .expect("cannot parse default PITR interval"),
+            logical_size_soft_limit: None,
+            image_layer_excluded_forks: ForkSet::default(),
         }
     }
 
+    ///
+    /// Check that the settings are in range and work together. A zero period
+    /// would make the background threads spin, a zero size or threshold
+    /// would flush or compact all the time.
+    ///
+    pub fn validate(&self) -> Result<(), InvalidTenantConf> {
+        let check = |ok: bool, msg: &str| {
+            if ok {
+                Ok(())
+            } else {
+                Err(InvalidTenantConf(msg.to_string()))
+            }
+        };
+        check(
+            self.checkpoint_distance > 0,
+            "checkpoint_distance must be positive",
+        )?;
+        check(
+            self.compaction_target_size > 0,
+            "compaction_target_size must be positive",
+        )?;
+        check(
+            !self.compaction_period.is_zero(),
+            "compaction_period must be positive",
+        )?;
+        check(
+            self.compaction_threshold > 0,
+            "compaction_threshold must be positive",
+        )?;
+        check(
+            self.image_creation_threshold > 0,
+            "image_creation_threshold must be positive",
+        )?;
+        check(
+            self.gc_horizon == 0 || !self.gc_period.is_zero(),
+            "gc_period must be positive while GC is enabled with a positive gc_horizon",
+        )?;
+        check(
+            self.logical_size_soft_limit != Some(0),
+            "logical_size_soft_limit must be positive, leave it unset for no limit",
+        )?;
+        Ok(())
+    }
+
     /// Points to a place in pageserver's local directory,
     /// where certain tenant's tenantconf file should be located.
     pub fn path(conf: &'static PageServerConf, tenantid: ZTenantId) -> PathBuf {
@@ -175,6 +369,97 @@ impl TenantConf {
             gc_period: Duration::from_secs(10),
             image_creation_threshold: defaults::DEFAULT_IMAGE_CREATION_THRESHOLD,
             pitr_interval: Duration::from_secs(60 * 60),
+            logical_size_soft_limit: None,
+            image_layer_excluded_forks: ForkSet::default(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_tenant_conf() {
+        assert!(TenantConf::default().validate().is_ok());
+
+        let mut conf = TenantConf::default();
+        conf.compaction_period = Duration::ZERO;
+        assert!(conf.validate().is_err());
+
+        // A zero GC period is fine while GC is disabled
+        let mut conf = TenantConf::default();
+        conf.gc_period = Duration::ZERO;
+        assert!(conf.validate().is_err());
+        conf.gc_horizon = 0;
+        assert!(conf.validate().is_ok());
+
+        let mut conf = TenantConf::default();
+        conf.logical_size_soft_limit = Some(0);
+        assert!(conf.validate().is_err());
+    }
+
+    #[test]
+    fn effective_tenant_conf() -> anyhow::Result<()> {
+        let tenant_conf = TenantConfOpt {
+            gc_period: Some(Duration::from_secs(30)),
+            logical_size_soft_limit: Some(1024),
+            ..TenantConfOpt::default()
+        };
+        let effective = tenant_conf.effective(TenantConf::default())?;
+
+        assert_eq!(
+            effective["gc_period"],
+            EffectiveTenantConfValue {
+                value: serde_json::json!("30s"),
+                source: TenantConfSource::Tenant,
+            }
+        );
+        assert_eq!(
+            effective["logical_size_soft_limit"],
+            EffectiveTenantConfValue {
+                value: serde_json::json!(1024),
+                source: TenantConfSource::Tenant,
+            }
+        );
+        assert_eq!(
+            effective["gc_horizon"],
+            EffectiveTenantConfValue {
+                value: serde_json::json!(defaults::DEFAULT_GC_HORIZON),
+                source: TenantConfSource::Pageserver,
+            }
+        );
+        assert_eq!(
+            effective["image_layer_excluded_forks"],
+            EffectiveTenantConfValue {
+                value: serde_json::json!([]),
+                source: TenantConfSource::Pageserver,
+            }
+        );
+        assert_eq!(effective.len(), 10);
+        Ok(())
+    }
+
+    #[test]
+    fn fork_set() -> anyhow::Result<()> {
+        let forks = ForkSet::from_names(&["vm", "fsm"])?;
+        assert_eq!(forks, ForkSet::REBUILDABLE);
+        assert!(forks.contains(pg_constants::FSM_FORKNUM));
+        assert!(forks.contains(pg_constants::VISIBILITYMAP_FORKNUM));
+        assert!(!forks.contains(pg_constants::MAIN_FORKNUM));
+        assert_eq!(
+            serde_json::to_value(forks)?,
+            serde_json::json!(["fsm", "vm"])
+        );
+
+        let forks: ForkSet = serde_json::from_str(r#"["vm"]"#)?;
+        assert_eq!(forks.names(), vec!["vm"]);
+        assert!(ForkSet::default().is_empty());
+
+        // Only the forks that PostgreSQL rebuilds can be excluded
+        assert!(ForkSet::from_names(&["main"]).is_err());
+        assert!(ForkSet::from_names(&["init"]).is_err());
+        assert!(ForkSet::from_names(&["nosuchfork"]).is_err());
+        Ok(())
+    }
+}
@@ -1,10 +1,15 @@
 //! This module acts as a switchboard to access different repositories managed by this
 //! page server.
 
+use crate::access_history;
+use crate::compute_notify::{self, TimelineState};
 use crate::config::PageServerConf;
-use crate::layered_repository::{load_metadata, LayeredRepository};
+use crate::layered_repository::{load_metadata, LayeredRepository, LayeredTimeline};
+use crate::metric_labels;
 use crate::pgdatadir_mapping::DatadirTimeline;
-use crate::repository::{Repository, TimelineSyncStatusUpdate};
+use crate::rel_size_cache::RelSizeCache;
+use crate::repository::{Repository, RepositoryTimeline, Timeline, TimelineSyncStatusUpdate};
+use crate::size_soft_limit::LogicalSizeSoftLimit;
 use crate::storage_sync::index::RemoteIndex;
 use crate::storage_sync::{self, LocalTimelineInitStatus, SyncStartupData};
 use crate::tenant_config::TenantConfOpt;
@@ -12,19 +17,57 @@ use crate::thread_mgr;
 use crate::thread_mgr::ThreadKind;
 use crate::timelines;
 use crate::timelines::CreateRepo;
+use crate::walingest::WalRecordStats;
+use crate::walreceiver::IS_WAL_RECEIVER;
 use crate::walredo::PostgresRedoManager;
 use crate::{DatadirTimelineImpl, RepositoryImpl};
 use anyhow::{bail, Context};
+use lazy_static::lazy_static;
+use metrics::{register_int_counter_vec, register_int_gauge_vec, IntCounterVec, IntGaugeVec};
+use nix::errno::Errno;
 use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, DisplayFromStr};
 use std::collections::hash_map::Entry;
 use std::collections::HashMap;
+use std::ffi::OsStr;
 use std::fmt;
-use std::sync::Arc;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tracing::*;
+use utils::crashsafe_dir;
 use utils::lsn::Lsn;
 
-use utils::zid::{ZTenantId, ZTimelineId};
+use utils::zid::{ZTenantId, ZTenantTimelineId, ZTimelineId};
+
+/// Name of the file in the tenant directory that holds the tenant generation.
+pub const TENANT_GENERATION_FILE_NAME: &str = "generation";
+
+/// Number of consecutive persistent write errors (EIO, ENOSPC and alike) after which
+/// the tenant is switched into the read-only mode.
+const READ_ONLY_WRITE_ERRORS_THRESHOLD: u32 = 3;
+
+/// How often to look for the detached timelines whose files can be removed.
+const DETACHED_TIMELINES_PURGE_PERIOD: Duration = Duration::from_secs(60);
+
+lazy_static! {
+    static ref PERSISTENT_WRITE_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "pageserver_persistent_write_errors_total",
+        "Number of layer write failures, classified as persistent I/O errors",
+        &["tenant_id"]
+    )
+    .expect("failed to define a metric");
+    static ref READ_ONLY_TENANTS: IntGaugeVec = register_int_gauge_vec!(
+        "pageserver_tenant_read_only",
+        "Set to 1 when the tenant got switched into the read-only mode due to persistent write errors, the number of such tenants for the \"other\" tenants",
+        &["tenant_id"]
+    )
+    .expect("failed to define a metric");
+    /// Serializes the removal of the detached timeline files with their reattaching.
+    static ref DETACHED_TIMELINES_LOCK: Mutex<()> = Mutex::new(());
+}
 
 mod tenants_state {
     use std::{
@@ -63,6 +106,10 @@ struct Tenant {
     /// Local timelines have more metadata that's loaded into memory,
     /// that is located in the `repo.timelines` field, [`crate::layered_repository::LayeredTimelineEntry`].
     local_timelines: HashMap<ZTimelineId, Arc<DatadirTimelineImpl>>,
+    /// Number of persistent write errors happened in a row, reset on every successful layer write.
+    consecutive_write_errors: u32,
+    /// Tenant generation issued by the control plane, if any. See [`set_tenant_generation`].
+    generation: Option<u32>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
@@ -79,6 +126,10 @@ pub enum TenantState {
     // The local disk might have some newer files that don't exist in cloud storage yet.
     // The tenant cannot be accessed anymore for any reason, but graceful shutdown.
     Stopping,
+    // Layer writes kept failing with errors that are unlikely to go away by themselves (EIO, ENOSPC).
+    // WAL ingest, compaction and GC are stopped, the data already on disk can still be read.
+    // An operator has to clear the state explicitly to resume the writes.
+    ReadOnly,
 
     // Something went wrong loading the tenant state
     Broken,
@@ -90,6 +141,7 @@ impl fmt::Display for TenantState {
             TenantState::Active => f.write_str("Active"),
             TenantState::Idle => f.write_str("Idle"),
             TenantState::Stopping => f.write_str("Stopping"),
+            TenantState::ReadOnly => f.write_str("ReadOnly"),
             TenantState::Broken => f.write_str("Broken"),
         }
     }
@@ -120,6 +172,16 @@ pub fn init_tenant_mgr(conf: &'static PageServerConf) -> anyhow::Result<RemoteIn
         }
     }
 
+    thread_mgr::spawn(
+        ThreadKind::DetachedTimelinePurge,
+        None,
+        None,
+        "Detached timeline purge thread",
+        false,
+        move || purge_detached_timelines_loop(conf),
+    )
+    .context("Failed to spawn the detached timeline purge thread")?;
+
     Ok(remote_index)
 }
 
@@ -168,7 +230,7 @@ pub fn shutdown_all_tenants() {
                 tenant.state = TenantState::Stopping;
                 tenantids.push(*tenantid)
             }
-            TenantState::Broken => {}
+            TenantState::ReadOnly | TenantState::Broken => {}
         }
     }
     drop(m);
@@ -176,6 +238,7 @@ pub fn shutdown_all_tenants() {
     thread_mgr::shutdown_threads(Some(ThreadKind::WalReceiver), None, None);
     thread_mgr::shutdown_threads(Some(ThreadKind::GarbageCollector), None, None);
     thread_mgr::shutdown_threads(Some(ThreadKind::Compactor), None, None);
+    thread_mgr::shutdown_threads(Some(ThreadKind::ImageBuilder), None, None);
 
     // Ok, no background threads running anymore. Flush any remaining data in
     // memory to disk.
@@ -204,6 +267,7 @@ pub fn create_tenant_repository(
     conf: &'static PageServerConf,
     tenant_conf: TenantConfOpt,
     tenant_id: ZTenantId,
+    generation: Option<u32>,
     remote_index: RemoteIndex,
 ) -> anyhow::Result<Option<ZTenantId>> {
     match tenants_state::write_tenants().entry(tenant_id) {
@@ -222,10 +286,15 @@ pub fn create_tenant_repository(
                     remote_index,
                 },
             )?;
+            if let Some(generation) = generation {
+                persist_tenant_generation(conf, tenant_id, generation)?;
+            }
             v.insert(Tenant {
                 state: TenantState::Idle,
                 repo,
                 local_timelines: HashMap::new(),
+                consecutive_write_errors: 0,
+                generation,
             });
             Ok(Some(tenant_id))
         }
@@ -248,8 +317,8 @@ pub fn get_tenant_state(tenantid: ZTenantId) -> Option<TenantState> {
 }
 
 ///
-/// Change the state of a tenant to Active and launch its compactor and GC
-/// threads. If the tenant was already in Active state or Stopping, does nothing.
+/// Change the state of a tenant to Active and launch its compactor, image
+/// builder and GC threads. If the tenant was already in Active state or Stopping, does nothing.
 ///
 pub fn activate_tenant(tenant_id: ZTenantId) -> anyhow::Result<()> {
     let mut m = tenants_state::write_tenants();
@@ -274,6 +343,25 @@ pub fn activate_tenant(tenant_id: ZTenantId) -> anyhow::Result<()> {
                 move || crate::tenant_threads::compact_loop(tenant_id),
             )?;
 
+            let image_builder_spawn_result = thread_mgr::spawn(
+                ThreadKind::ImageBuilder,
+                Some(tenant_id),
+                None,
+                "Image builder thread",
+                false,
+                move || crate::tenant_threads::image_builder_loop(tenant_id),
+            )
+            .map(|_thread_id| ())
+            .with_context(|| {
+                format!("Failed to launch image builder thread for tenant {tenant_id}")
+            });
+
+            if let Err(e) = &image_builder_spawn_result {
+                error!("Failed to start image builder thread for tenant {tenant_id}, stopping its checkpointer thread: {e:?}");
+                thread_mgr::shutdown_threads(Some(ThreadKind::Compactor), Some(tenant_id), None);
+                return image_builder_spawn_result;
+            }
+
             let gc_spawn_result = thread_mgr::spawn(
                 ThreadKind::GarbageCollector,
                 Some(tenant_id),
@@ -288,6 +376,7 @@ pub fn activate_tenant(tenant_id: ZTenantId) -> anyhow::Result<()> {
             if let Err(e) = &gc_spawn_result {
                 error!("Failed to start GC thread for tenant {tenant_id}, stopping its checkpointer thread: {e:?}");
                 thread_mgr::shutdown_threads(Some(ThreadKind::Compactor), Some(tenant_id), None);
+                thread_mgr::shutdown_threads(Some(ThreadKind::ImageBuilder), Some(tenant_id), None);
                 return gc_spawn_result;
             }
             tenant.state = TenantState::Active;
@@ -297,6 +386,10 @@ pub fn activate_tenant(tenant_id: ZTenantId) -> anyhow::Result<()> {
             // don't re-activate it if it's being stopped
         }
 
+        TenantState::ReadOnly => {
+            // has to be cleared by an operator first, see `clear_tenant_read_only`
+        }
+
         TenantState::Broken => {
             // cannot activate
         }
@@ -304,6 +397,196 @@ pub fn activate_tenant(tenant_id: ZTenantId) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Checks whether the error has an I/O error in its chain, that is unlikely to go away on retry
+/// and means that the local storage cannot be trusted to persist the tenant data anymore.
+pub fn is_persistent_write_error(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        cause
+            .downcast_ref::<std::io::Error>()
+            .and_then(std::io::Error::raw_os_error)
+            .map(Errno::from_i32)
+            .map_or(false, |errno| {
+                matches!(
+                    errno,
+                    Errno::EIO | Errno::ENOSPC | Errno::EROFS | Errno::EDQUOT
+                )
+            })
+    })
+}
+
+/// Registers a failed layer write for the tenant.
+/// Transient errors are ignored, persistent ones are counted and after
+/// [`READ_ONLY_WRITE_ERRORS_THRESHOLD`] of them in a row the tenant is switched into
+/// [`TenantState::ReadOnly`]: its WAL receivers, compactor and GC threads are stopped.
+pub fn report_layer_write_error(tenant_id: ZTenantId, error: &anyhow::Error) {
+    if !is_persistent_write_error(error) {
+        return;
+    }
+    PERSISTENT_WRITE_ERRORS
+        .with_label_values(&[&metric_labels::tenant_label(&tenant_id)])
+        .inc();
+
+    let mut m = tenants_state::write_tenants();
+    let tenant = match m.get_mut(&tenant_id) {
+        Some(tenant) => tenant,
+        None => return,
+    };
+    tenant.consecutive_write_errors += 1;
+    if tenant.consecutive_write_errors < READ_ONLY_WRITE_ERRORS_THRESHOLD {
+        return;
+    }
+    match tenant.state {
+        TenantState::Active | TenantState::Idle => {
+            error!(
+                "Switching tenant {tenant_id} into read-only mode after {} persistent write errors, last one: {error:?}",
+                tenant.consecutive_write_errors
+            );
+            tenant.state = TenantState::ReadOnly;
+        }
+        TenantState::Stopping | TenantState::ReadOnly | TenantState::Broken => return,
+    }
+    drop(m);
+
+    set_read_only_metric(tenant_id, true);
+
+    // Compactor, image builder and GC threads exit on their own after noticing the state change.
+    // A WAL receiver that reported the error stops on the error itself, avoid joining it from its own thread.
+    if !IS_WAL_RECEIVER.with(|c| c.get()) {
+        thread_mgr::shutdown_threads(Some(ThreadKind::WalReceiver), Some(tenant_id), None);
+    }
+}
+
+/// Registers a successful layer write for the tenant, resetting its persistent write error streak.
+pub fn report_layer_write_success(tenant_id: ZTenantId) {
+    let has_errors = tenants_state::read_tenants()
+        .get(&tenant_id)
+        .map_or(false, |tenant| tenant.consecutive_write_errors > 0);
+    if has_errors {
+        if let Some(tenant) = tenants_state::write_tenants().get_mut(&tenant_id) {
+            tenant.consecutive_write_errors = 0;
+        }
+    }
+}
+
+/// Moves the tenant out of [`TenantState::ReadOnly`] after an operator has fixed the underlying storage issue.
+/// The tenant becomes Idle and gets activated again by the next WAL receiver launch.
+pub fn clear_tenant_read_only(tenant_id: ZTenantId) -> anyhow::Result<()> {
+    let mut m = tenants_state::write_tenants();
+    let tenant = m
+        .get_mut(&tenant_id)
+        .with_context(|| format!("Tenant {tenant_id} not found"))?;
+    if tenant.state != TenantState::ReadOnly {
+        bail!(
+            "Tenant {tenant_id} is not in read-only mode, its state is {}",
+            tenant.state
+        );
+    }
+
+    info!("clearing read-only mode for tenant {tenant_id}");
+    tenant.state = TenantState::Idle;
+    tenant.consecutive_write_errors = 0;
+    set_read_only_metric(tenant_id, false);
+    Ok(())
+}
+
+fn set_read_only_metric(tenant_id: ZTenantId, read_only: bool) {
+    let tenant_label = metric_labels::tenant_label(&tenant_id);
+    let gauge = READ_ONLY_TENANTS.with_label_values(&[&tenant_label]);
+    if tenant_label == metric_labels::OTHER {
+        // Count the read-only tenants in the shared series
+        gauge.add(if read_only { 1 } else { -1 });
+    } else {
+        gauge.set(read_only as i64);
+    }
+}
+
+/// Returns the tenant's generation, see [`set_tenant_generation`].
+/// Tenants that are not loaded yet (e.g. during startup) get their generation read from disk.
+pub fn get_tenant_generation(
+    conf: &'static PageServerConf,
+    tenant_id: ZTenantId,
+) -> anyhow::Result<Option<u32>> {
+    if let Some(tenant) = tenants_state::read_tenants().get(&tenant_id) {
+        return Ok(tenant.generation);
+    }
+    load_tenant_generation(conf, tenant_id)
+}
+
+/// Sets the tenant generation, issued by the control plane every time it assigns the tenant to a pageserver.
+///
+/// Every index part uploaded to the remote storage is stamped with the generation, and the upload is refused
+/// if the remote index part was written by a newer generation: that means another pageserver took over the tenant.
+/// Generations never go backwards.
+pub fn set_tenant_generation(
+    conf: &'static PageServerConf,
+    tenant_id: ZTenantId,
+    generation: u32,
+) -> anyhow::Result<()> {
+    let mut m = tenants_state::write_tenants();
+    let tenant = m
+        .get_mut(&tenant_id)
+        .with_context(|| format!("Tenant {tenant_id} not found"))?;
+    if let Some(current_generation) = tenant.generation {
+        if generation < current_generation {
+            bail!(
+                "Tenant {tenant_id} generation cannot go backwards, current: {current_generation}, requested: {generation}"
+            );
+        }
+    }
+
+    info!("setting tenant {tenant_id} generation to {generation}");
+    persist_tenant_generation(conf, tenant_id, generation)?;
+    tenant.generation = Some(generation);
+    Ok(())
+}
+
+fn tenant_generation_path(conf: &'static PageServerConf, tenant_id: ZTenantId) -> PathBuf {
+    conf.tenant_path(&tenant_id)
+        .join(TENANT_GENERATION_FILE_NAME)
+}
+
+fn load_tenant_generation(
+    conf: &'static PageServerConf,
+    tenant_id: ZTenantId,
+) -> anyhow::Result<Option<u32>> {
+    let path = tenant_generation_path(conf, tenant_id);
+    match std::fs::read_to_string(&path) {
+        Ok(contents) => {
+            let generation = contents.trim().parse().with_context(|| {
+                format!(
+                    "Failed to parse tenant generation file '{}'",
+                    path.display()
+                )
+            })?;
+            Ok(Some(generation))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e)
+            .with_context(|| format!("Failed to read tenant generation file '{}'", path.display())),
+    }
+}
+
+fn persist_tenant_generation(
+    conf: &'static PageServerConf,
+    tenant_id: ZTenantId,
+    generation: u32,
+) -> anyhow::Result<()> {
+    let path = tenant_generation_path(conf, tenant_id);
+    let temp_path = path.with_extension("tmp");
+    let mut file = File::create(&temp_path)
+        .with_context(|| format!("Failed to create file '{}'", temp_path.display()))?;
+    file.write_all(generation.to_string().as_bytes())?;
+    file.sync_all()?;
+    std::fs::rename(&temp_path, &path).with_context(|| {
+        format!(
+            "Failed to persist tenant generation file '{}'",
+            path.display()
+        )
+    })?;
+    File::open(conf.tenant_path(&tenant_id))?.sync_all()?;
+    Ok(())
+}
+
 pub fn get_repository_for_tenant(tenant_id: ZTenantId) -> anyhow::Result<Arc<RepositoryImpl>> {
     let m = tenants_state::read_tenants();
     let tenant = m
@@ -333,28 +616,305 @@ pub fn get_local_timeline_with_load(
     tenant
         .local_timelines
         .insert(timeline_id, Arc::clone(&page_tline));
+
+    if tenant.repo.conf.prefetch_ancestor_layers
+        && page_tline.tline.get_ancestor_timeline_id().is_some()
+    {
+        spawn_ancestor_prefetch(tenant_id, timeline_id, Arc::clone(&page_tline.tline));
+    }
     Ok(page_tline)
 }
 
+/// If the timeline can't be loaded because one of its ancestors is only present in the
+/// remote storage, schedules the download of that ancestor, unless it's already scheduled.
+/// Returns the ancestor's id.
+pub fn download_missing_ancestor(
+    tenant_id: ZTenantId,
+    timeline_id: ZTimelineId,
+) -> Option<ZTimelineId> {
+    let repo = get_repository_for_tenant(tenant_id).ok()?;
+    let ancestor_id = repo.missing_local_ancestor(timeline_id)?;
+
+    let mut index_accessor = repo.get_remote_index().blocking_write();
+    match index_accessor.timeline_entry_mut(&ZTenantTimelineId::new(tenant_id, ancestor_id)) {
+        Some(remote_timeline) if remote_timeline.archived => {
+            warn!(
+                "ancestor {ancestor_id} of timeline {timeline_id} is archived, cannot download it"
+            );
+        }
+        Some(remote_timeline) => {
+            if !remote_timeline.awaits_download {
+                remote_timeline.awaits_download = true;
+                storage_sync::schedule_layer_download(tenant_id, ancestor_id);
+                compute_notify::notify(tenant_id, ancestor_id, TimelineState::AwaitsDownload);
+            }
+        }
+        None => {
+            warn!("ancestor {ancestor_id} of timeline {timeline_id} is found neither locally nor remotely");
+        }
+    }
+    Some(ancestor_id)
+}
+
+/// Whether the timeline is waiting for its download from the remote storage.
+pub fn awaits_download(tenant_id: ZTenantId, timeline_id: ZTimelineId) -> bool {
+    let repo = match get_repository_for_tenant(tenant_id) {
+        Ok(repo) => repo,
+        Err(_) => return false,
+    };
+    let index_accessor = repo.get_remote_index().blocking_read();
+    index_accessor
+        .timeline_entry(&ZTenantTimelineId::new(tenant_id, timeline_id))
+        .map_or(false, |remote_timeline| remote_timeline.awaits_download)
+}
+
+/// Reads the ancestor layers of a freshly loaded branch in the background.
+/// Failing to do so only makes the first reads slower, so the errors are just logged.
+fn spawn_ancestor_prefetch(
+    tenant_id: ZTenantId,
+    timeline_id: ZTimelineId,
+    timeline: Arc<LayeredTimeline>,
+) {
+    let spawn_result = thread_mgr::spawn(
+        ThreadKind::AncestorPrefetch,
+        Some(tenant_id),
+        Some(timeline_id),
+        "ancestor prefetch thread",
+        false,
+        move || {
+            let _enter =
+                info_span!("ancestor_prefetch", tenant = %tenant_id, timeline = %timeline_id)
+                    .entered();
+            let started_at = Instant::now();
+            match timeline.prefetch_ancestor_layers() {
+                Ok((layers_read, bytes_read)) => info!(
+                    "prefetched {layers_read} ancestor layers, {bytes_read} bytes in {:?}",
+                    started_at.elapsed()
+                ),
+                Err(e) => warn!("Failed to prefetch the ancestor layers: {e:?}"),
+            }
+            Ok(())
+        },
+    );
+    if let Err(e) = spawn_result {
+        warn!("Failed to spawn the ancestor prefetch thread: {e:?}");
+    }
+}
+
+/// Detaches the timeline: it stops ingesting WAL and serving requests at once, but its files are kept for
+/// `detach_grace_period`, with a [`storage_sync::DETACHED_TIMELINE_MARKER`] file in its local directory. Until then,
+/// the detach can be undone with [`reattach_timeline`]. The marker also keeps the timeline from being loaded
+/// on startup.
 pub fn detach_timeline(
     conf: &'static PageServerConf,
     tenant_id: ZTenantId,
     timeline_id: ZTimelineId,
 ) -> anyhow::Result<()> {
-    // shutdown the timeline threads (this shuts down the walreceiver)
-    thread_mgr::shutdown_threads(None, Some(tenant_id), Some(timeline_id));
+    if conf.detach_grace_period.is_zero() {
+        remove_local_timeline(conf, tenant_id, timeline_id)?;
+    } else {
+        stop_local_timeline(tenant_id, timeline_id)?;
+
+        let _guard = DETACHED_TIMELINES_LOCK.lock().unwrap();
+        let local_timeline_directory = conf.timeline_path(&timeline_id, &tenant_id);
+        let marker_path = local_timeline_directory.join(storage_sync::DETACHED_TIMELINE_MARKER);
+        let detached_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .context("System time is before the epoch")?
+            .as_secs();
+        File::create(&marker_path)
+            .and_then(|mut marker| {
+                marker.write_all(detached_at.to_string().as_bytes())?;
+                marker.sync_all()
+            })
+            .with_context(|| {
+                format!(
+                    "Failed to create detached timeline marker '{}'",
+                    marker_path.display()
+                )
+            })?;
+        File::open(&local_timeline_directory)?.sync_all()?;
+        info!(
+            "timeline {timeline_id} detached, its files are kept for {:?}",
+            conf.detach_grace_period
+        );
+    }
+    access_history::forget_timeline(tenant_id, timeline_id);
+    compute_notify::notify(tenant_id, timeline_id, TimelineState::Detached);
+    Ok(())
+}
+
+/// Undoes the detach of a timeline whose files are still kept, see [`detach_timeline`], and loads it again.
+///
+/// Returns false if the timeline is not detached locally.
+pub fn reattach_timeline(
+    conf: &'static PageServerConf,
+    remote_index: &RemoteIndex,
+    tenant_id: ZTenantId,
+    timeline_id: ZTimelineId,
+) -> anyhow::Result<bool> {
+    let _guard = DETACHED_TIMELINES_LOCK.lock().unwrap();
+    let local_timeline_directory = conf.timeline_path(&timeline_id, &tenant_id);
+    let marker_path = local_timeline_directory.join(storage_sync::DETACHED_TIMELINE_MARKER);
+    if !marker_path.exists() {
+        return Ok(false);
+    }
+
+    std::fs::remove_file(&marker_path).with_context(|| {
+        format!(
+            "Failed to remove detached timeline marker '{}'",
+            marker_path.display()
+        )
+    })?;
+    File::open(&local_timeline_directory)?.sync_all()?;
+
+    let repo = load_local_repo(conf, tenant_id, remote_index)
+        .with_context(|| format!("Failed to load repo for tenant {tenant_id}"))?;
+    apply_timeline_remote_sync_status_updates(
+        &repo,
+        HashMap::from([(timeline_id, TimelineSyncStatusUpdate::Downloaded)]),
+    )
+    .with_context(|| format!("Failed to load reattached timeline {timeline_id}"))?;
+    info!("timeline {timeline_id} reattached");
+    Ok(true)
+}
+
+/// Removes the files of the detached timelines whose `detach_grace_period` has passed.
+pub fn purge_detached_timelines(conf: &'static PageServerConf) -> anyhow::Result<()> {
+    let tenants_dir = conf.tenants_path();
+    for tenants_dir_entry in std::fs::read_dir(&tenants_dir)
+        .with_context(|| format!("Failed to list tenants dir {}", tenants_dir.display()))?
+    {
+        let tenant_path = tenants_dir_entry
+            .context("Failed to list tenants dir entry")?
+            .path();
+        let tenant_id = match tenant_path
+            .file_name()
+            .and_then(OsStr::to_str)
+            .unwrap_or_default()
+            .parse::<ZTenantId>()
+        {
+            Ok(tenant_id) => tenant_id,
+            Err(_) => continue,
+        };
+        let timelines_dir_entries = match std::fs::read_dir(conf.timelines_path(&tenant_id)) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Failed to list timelines dir for tenant {tenant_id}: {e}");
+                continue;
+            }
+        };
+        for timelines_dir_entry in timelines_dir_entries {
+            let timeline_path = timelines_dir_entry
+                .context("Failed to list timelines dir entry")?
+                .path();
+            if !timeline_path
+                .join(storage_sync::DETACHED_TIMELINE_MARKER)
+                .exists()
+            {
+                continue;
+            }
+            if let Err(e) = purge_detached_timeline(conf, &timeline_path) {
+                error!(
+                    "Failed to purge detached timeline at '{}': {e:?}",
+                    timeline_path.display()
+                );
+            }
+        }
+    }
+    Ok(())
+}
 
-    match tenants_state::write_tenants().get_mut(&tenant_id) {
+fn purge_detached_timeline(
+    conf: &'static PageServerConf,
+    timeline_path: &Path,
+) -> anyhow::Result<()> {
+    let _guard = DETACHED_TIMELINES_LOCK.lock().unwrap();
+    let marker_path = timeline_path.join(storage_sync::DETACHED_TIMELINE_MARKER);
+    let detached_at = match std::fs::read_to_string(&marker_path) {
+        Ok(contents) => {
+            UNIX_EPOCH
+                + Duration::from_secs(contents.trim().parse().with_context(|| {
+                    format!(
+                        "Failed to parse detached timeline marker '{}'",
+                        marker_path.display()
+                    )
+                })?)
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).context("Failed to read detached timeline marker"),
+    };
+    if SystemTime::now() < detached_at + conf.detach_grace_period {
+        return Ok(());
+    }
+
+    info!(
+        "removing detached timeline at '{}', its grace period has passed",
+        timeline_path.display()
+    );
+    std::fs::remove_dir_all(timeline_path).with_context(|| {
+        format!(
+            "Failed to remove local timeline directory '{}'",
+            timeline_path.display()
+        )
+    })
+}
+
+/// The main loop of the thread that removes the files of detached timelines, see [`purge_detached_timelines`].
+fn purge_detached_timelines_loop(conf: &'static PageServerConf) -> anyhow::Result<()> {
+    while !thread_mgr::is_shutdown_requested() {
+        if let Err(e) = purge_detached_timelines(conf) {
+            error!("Failed to purge detached timelines: {e:?}");
+        }
+        let mut sleep_time = DETACHED_TIMELINES_PURGE_PERIOD.as_secs();
+        while sleep_time > 0 && !thread_mgr::is_shutdown_requested() {
+            sleep_time -= 1;
+            std::thread::sleep(Duration::from_secs(1));
+        }
+    }
+    Ok(())
+}
+
+fn stop_local_timeline(tenant_id: ZTenantId, timeline_id: ZTimelineId) -> anyhow::Result<()> {
+    // Stop the timeline first, so that no new requests start using its files
+    let loaded_timeline = match tenants_state::write_tenants().get_mut(&tenant_id) {
         Some(tenant) => {
+            let loaded_timeline = match tenant.repo.get_timeline(timeline_id) {
+                Some(RepositoryTimeline::Loaded(timeline)) => Some(timeline),
+                _ => None,
+            };
             tenant
                 .repo
                 .detach_timeline(timeline_id)
                 .context("Failed to detach inmem tenant timeline")?;
             tenant.local_timelines.remove(&timeline_id);
+            if tenant.local_timelines.is_empty() {
+                metric_labels::release_tenant(&tenant_id);
+            }
+            loaded_timeline
         }
         None => bail!("Tenant {tenant_id} not found in local tenant state"),
+    };
+
+    // Shut down the timeline threads: the walreceiver, and the page service threads
+    // serving the timeline, after they finish the requests they're handling.
+    thread_mgr::shutdown_threads(None, Some(tenant_id), Some(timeline_id));
+
+    // Nothing reads the layer files anymore, close them
+    if let Some(timeline) = loaded_timeline {
+        timeline.close_layers();
     }
 
+    Ok(())
+}
+
+fn remove_local_timeline(
+    conf: &'static PageServerConf,
+    tenant_id: ZTenantId,
+    timeline_id: ZTimelineId,
+) -> anyhow::Result<()> {
+    stop_local_timeline(tenant_id, timeline_id)?;
+
     let local_timeline_directory = conf.timeline_path(&timeline_id, &tenant_id);
     std::fs::remove_dir_all(&local_timeline_directory).with_context(|| {
         format!(
@@ -366,6 +926,38 @@ pub fn detach_timeline(
     Ok(())
 }
 
+/// Detaches the timeline, leaving only the [`storage_sync::ARCHIVED_TIMELINE_MARKER`] file in its local directory.
+/// The marker allows to find the archived timeline on startup and prevents the timeline id from being reused.
+///
+/// Expects the caller to ensure all timeline data is present in the remote storage.
+pub fn archive_timeline(
+    conf: &'static PageServerConf,
+    tenant_id: ZTenantId,
+    timeline_id: ZTimelineId,
+) -> anyhow::Result<()> {
+    remove_local_timeline(conf, tenant_id, timeline_id)?;
+
+    let local_timeline_directory = conf.timeline_path(&timeline_id, &tenant_id);
+    crashsafe_dir::create_dir(&local_timeline_directory).with_context(|| {
+        format!(
+            "Failed to create archived timeline directory '{}'",
+            local_timeline_directory.display()
+        )
+    })?;
+    let marker_path = local_timeline_directory.join(storage_sync::ARCHIVED_TIMELINE_MARKER);
+    std::fs::File::create(&marker_path)
+        .and_then(|marker| marker.sync_all())
+        .with_context(|| {
+            format!(
+                "Failed to create archived timeline marker '{}'",
+                marker_path.display()
+            )
+        })?;
+
+    compute_notify::notify(tenant_id, timeline_id, TimelineState::Archived);
+    Ok(())
+}
+
 fn load_local_timeline(
     repo: &RepositoryImpl,
     timeline_id: ZTimelineId,
@@ -374,10 +966,11 @@ fn load_local_timeline(
         format!("Inmem timeline {timeline_id} not found in tenant's repository")
     })?;
     let repartition_distance = repo.get_checkpoint_distance() / 10;
-    let page_tline = Arc::new(DatadirTimelineImpl::new(
-        inmem_timeline,
-        repartition_distance,
-    ));
+    let mut page_tline = DatadirTimelineImpl::new(inmem_timeline, repartition_distance);
+    page_tline.wal_record_stats = WalRecordStats::new(repo.tenant_id(), timeline_id);
+    page_tline.size_soft_limit = LogicalSizeSoftLimit::new(repo.tenant_id(), timeline_id);
+    page_tline.rel_size_cache = RelSizeCache::new(repo.conf.rel_size_cache_size);
+    let page_tline = Arc::new(page_tline);
     page_tline.init_logical_size()?;
     Ok(page_tline)
 }
@@ -479,6 +1072,12 @@ fn apply_timeline_remote_sync_status_updates(
 
     for timeline_id in registration_queue {
         let tenant_id = repo.tenant_id();
+        if let Some(ancestor_id) = repo.missing_local_ancestor(timeline_id) {
+            // A branch created off a remote timeline: it gets loaded on the first access
+            // after its ancestor is downloaded.
+            info!("timeline {timeline_id} for tenant {tenant_id} waits for its ancestor {ancestor_id} to be downloaded, not loading it yet");
+            continue;
+        }
         match tenants_state::write_tenants().get_mut(&tenant_id) {
             Some(tenant) => match tenant.local_timelines.entry(timeline_id) {
                 Entry::Occupied(_) => {
@@ -488,6 +1087,7 @@ fn apply_timeline_remote_sync_status_updates(
                     v.insert(load_local_timeline(repo, timeline_id).with_context(|| {
                         format!("Failed to register add local timeline for tenant {tenant_id}")
                     })?);
+                    compute_notify::notify(tenant_id, timeline_id, TimelineState::Ready);
                 }
             },
             None => bail!(
@@ -525,12 +1125,39 @@ fn load_local_repo(
             state: TenantState::Idle,
             repo,
             local_timelines: HashMap::new(),
+            consecutive_write_errors: 0,
+            generation: None,
         }
     });
 
     // Restore tenant config
     let tenant_conf = LayeredRepository::load_tenant_config(conf, tenant_id)?;
     tenant.repo.update_tenant_config(tenant_conf)?;
+    tenant.repo.load_append_only_relations()?;
+    tenant.generation = load_tenant_generation(conf, tenant_id)?;
 
     Ok(Arc::clone(&tenant.repo))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn persistent_write_errors() {
+        let os_error = |errno: Errno| {
+            anyhow::Error::new(std::io::Error::from_raw_os_error(errno as i32))
+                .context("Failed to write layer file")
+        };
+        for errno in [Errno::EIO, Errno::ENOSPC, Errno::EROFS, Errno::EDQUOT] {
+            assert!(is_persistent_write_error(&os_error(errno)), "{errno}");
+        }
+        assert!(!is_persistent_write_error(&os_error(Errno::EINTR)));
+        assert!(!is_persistent_write_error(&anyhow::anyhow!(
+            "no space left"
+        )));
+        assert!(!is_persistent_write_error(&anyhow::Error::new(
+            std::io::Error::new(std::io::ErrorKind::Other, "no errno")
+        )));
+    }
+}
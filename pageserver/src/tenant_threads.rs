@@ -1,8 +1,10 @@
 //! This module contains functions to serve per-tenant background processes,
-//! such as compaction and GC
+//! such as compaction, image layer creation and GC
+use crate::gc_coordinator;
 use crate::repository::Repository;
 use crate::tenant_mgr;
 use crate::tenant_mgr::TenantState;
+use crate::thread_mgr;
 use anyhow::Result;
 use std::time::Duration;
 use tracing::*;
@@ -33,7 +35,11 @@ fn compact_loop_ext(tenantid: ZTenantId) -> Result<()> {
 
         // Compact timelines
         let repo = tenant_mgr::get_repository_for_tenant(tenantid)?;
-        repo.compaction_iteration()?;
+        if let Err(e) = repo.compaction_iteration() {
+            tenant_mgr::report_layer_write_error(tenantid, &e);
+            return Err(e);
+        }
+        thread_mgr::record_activity();
     }
 
     trace!(
@@ -44,6 +50,35 @@ fn compact_loop_ext(tenantid: ZTenantId) -> Result<()> {
     Ok(())
 }
 
+///
+/// Image builder thread's main loop
+///
+pub fn image_builder_loop(tenantid: ZTenantId) -> Result<()> {
+    loop {
+        if tenant_mgr::get_tenant_state(tenantid) != Some(TenantState::Active) {
+            break;
+        }
+        let repo = tenant_mgr::get_repository_for_tenant(tenantid)?;
+        std::thread::sleep(repo.get_compaction_period());
+        trace!("image builder thread for tenant {} waking up", tenantid);
+
+        let repo = tenant_mgr::get_repository_for_tenant(tenantid)?;
+        if let Err(e) = repo.image_creation_iteration() {
+            error!("image builder loop terminated with error: {:?}", e);
+            tenant_mgr::report_layer_write_error(tenantid, &e);
+            return Err(e);
+        }
+        thread_mgr::record_activity();
+    }
+
+    trace!(
+        "image builder thread stopped for tenant {} state is {:?}",
+        tenantid,
+        tenant_mgr::get_tenant_state(tenantid)
+    );
+    Ok(())
+}
+
 ///
 /// GC thread's main loop
 ///
@@ -58,7 +93,17 @@ pub fn gc_loop(tenantid: ZTenantId) -> Result<()> {
         let gc_horizon = repo.get_gc_horizon();
         // Garbage collect old files that are not needed for PITR anymore
         if gc_horizon > 0 {
+            // Wait for our turn, the number of concurrent GC runs is limited server-wide.
+            let reclaimable_bytes = repo.estimate_gc_reclaimable_bytes(gc_horizon);
+            let _permit = match gc_coordinator::get().acquire(tenantid, reclaimable_bytes, || {
+                tenant_mgr::get_tenant_state(tenantid) != Some(TenantState::Active)
+            }) {
+                Some(permit) => permit,
+                // The tenant is shutting down
+                None => break,
+            };
             repo.gc_iteration(None, gc_horizon, repo.get_pitr_interval(), false)?;
+            thread_mgr::record_activity();
         }
 
         // TODO Write it in more adequate way using
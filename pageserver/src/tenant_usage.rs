@@ -0,0 +1,198 @@
+//!
+//! Accounting of the CPU time and the disk I/O spent on behalf of each tenant.
+//!
+//! The page server threads that work for a tenant, see [`crate::thread_mgr`],
+//! and the tenant's WAL redo process are measured with the per-thread and
+//! per-process counters of Linux, in `/proc/<...>/schedstat` and
+//! `/proc/<...>/io`. A [`UsageMeter`] samples them, and adds the difference
+//! since the previous sample to the counters of the tenant, labelled with the
+//! kind of work. Uploads run on the storage sync runtime, shared by all
+//! tenants, so only their bytes are accounted, see [`record_io`].
+//!
+//! Where the counters are not available, e.g. on macOS, nothing is accounted.
+//!
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use lazy_static::lazy_static;
+use metrics::{register_counter_vec, register_int_counter_vec, CounterVec, IntCounterVec};
+use utils::zid::ZTenantId;
+
+use crate::metric_labels;
+
+/// Minimum time between two samples of a meter, reading the counters costs a
+/// couple of system calls.
+const ACCOUNTING_INTERVAL: Duration = Duration::from_secs(1);
+
+lazy_static! {
+    static ref TENANT_CPU_SECONDS: CounterVec = register_counter_vec!(
+        "pageserver_tenant_cpu_seconds_total",
+        "CPU time spent on behalf of a tenant, by kind of work",
+        &["tenant_id", "kind"]
+    )
+    .expect("failed to define a metric");
+    static ref TENANT_READ_BYTES: IntCounterVec = register_int_counter_vec!(
+        "pageserver_tenant_read_bytes_total",
+        "Bytes read from disk on behalf of a tenant, by kind of work",
+        &["tenant_id", "kind"]
+    )
+    .expect("failed to define a metric");
+    static ref TENANT_WRITTEN_BYTES: IntCounterVec = register_int_counter_vec!(
+        "pageserver_tenant_written_bytes_total",
+        "Bytes written to disk on behalf of a tenant, by kind of work",
+        &["tenant_id", "kind"]
+    )
+    .expect("failed to define a metric");
+}
+
+/// Resources used by a thread or a process since it started.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Usage {
+    cpu: Duration,
+    read_bytes: u64,
+    write_bytes: u64,
+}
+
+impl Usage {
+    fn read(proc_dir: &Path) -> Option<Usage> {
+        // The first field is the time spent on the CPU, in nanoseconds
+        let schedstat = fs::read_to_string(proc_dir.join("schedstat")).ok()?;
+        let cpu_ns: u64 = schedstat.split_whitespace().next()?.parse().ok()?;
+
+        let io = fs::read_to_string(proc_dir.join("io")).ok()?;
+        let io_field = |name: &str| -> Option<u64> {
+            io.lines()
+                .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))?
+                .trim()
+                .parse()
+                .ok()
+        };
+
+        Some(Usage {
+            cpu: Duration::from_nanos(cpu_ns),
+            read_bytes: io_field("read_bytes")?,
+            write_bytes: io_field("write_bytes")?,
+        })
+    }
+}
+
+///
+/// Accounts the resources used by a thread or a process to tenants.
+///
+pub struct UsageMeter {
+    proc_dir: PathBuf,
+    /// Value of the "kind" label.
+    kind: String,
+    last: Option<Usage>,
+    last_sampled: Instant,
+}
+
+impl UsageMeter {
+    /// A meter of the thread that calls it. It must only be used from that thread.
+    pub fn current_thread(kind: &str) -> Self {
+        Self::new(PathBuf::from("/proc/thread-self"), kind)
+    }
+
+    /// A meter of a child process.
+    pub fn process(pid: u32, kind: &str) -> Self {
+        Self::new(PathBuf::from(format!("/proc/{pid}")), kind)
+    }
+
+    fn new(proc_dir: PathBuf, kind: &str) -> Self {
+        let last = Usage::read(&proc_dir);
+        UsageMeter {
+            proc_dir,
+            kind: kind.to_string(),
+            last,
+            last_sampled: Instant::now(),
+        }
+    }
+
+    /// Like [`UsageMeter::account`], if the last sample is old enough.
+    pub fn maybe_account(&mut self, tenant_id: Option<ZTenantId>) {
+        if self.last_sampled.elapsed() >= ACCOUNTING_INTERVAL {
+            self.account(tenant_id);
+        }
+    }
+
+    ///
+    /// Add the resources used since the last sample to the tenant's counters.
+    /// With no tenant, they are not accounted to anyone.
+    ///
+    pub fn account(&mut self, tenant_id: Option<ZTenantId>) {
+        let current = Usage::read(&self.proc_dir);
+        if let (Some(tenant_id), Some(last), Some(current)) = (tenant_id, self.last, current) {
+            let tenant_label = metric_labels::tenant_label(&tenant_id);
+            let labels = [tenant_label.as_str(), self.kind.as_str()];
+            let cpu = current.cpu.saturating_sub(last.cpu);
+            if !cpu.is_zero() {
+                TENANT_CPU_SECONDS
+                    .with_label_values(&labels)
+                    .inc_by(cpu.as_secs_f64());
+            }
+            record_io_labels(
+                &labels,
+                current.read_bytes.saturating_sub(last.read_bytes),
+                current.write_bytes.saturating_sub(last.write_bytes),
+            );
+        }
+        if current.is_some() {
+            self.last = current;
+        }
+        self.last_sampled = Instant::now();
+    }
+}
+
+///
+/// Account disk I/O done for a tenant by code that doesn't run on a thread of
+/// its own, like the uploads.
+///
+pub fn record_io(tenant_id: &ZTenantId, kind: &str, read_bytes: u64, write_bytes: u64) {
+    let tenant_label = metric_labels::tenant_label(tenant_id);
+    record_io_labels(&[tenant_label.as_str(), kind], read_bytes, write_bytes);
+}
+
+fn record_io_labels(labels: &[&str], read_bytes: u64, write_bytes: u64) {
+    if read_bytes > 0 {
+        TENANT_READ_BYTES
+            .with_label_values(labels)
+            .inc_by(read_bytes);
+    }
+    if write_bytes > 0 {
+        TENANT_WRITTEN_BYTES
+            .with_label_values(labels)
+            .inc_by(write_bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn thread_usage() {
+        let proc_dir = Path::new("/proc/thread-self");
+        let before = match Usage::read(proc_dir) {
+            Some(usage) => usage,
+            // No per-thread counters on this system
+            None => return,
+        };
+
+        // Burn some CPU
+        let mut x = 0u64;
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_millis(50) {
+            x = x.wrapping_mul(31).wrapping_add(7);
+        }
+        assert_ne!(x, 1);
+
+        let after = Usage::read(proc_dir).unwrap();
+        assert!(
+            after.cpu >= before.cpu + Duration::from_millis(10),
+            "{before:?} {after:?}"
+        );
+        assert!(after.read_bytes >= before.read_bytes);
+        assert!(after.write_bytes >= before.write_bytes);
+    }
+}
@@ -7,6 +7,13 @@
 //!
 //! - The ability to request a thread to shut down.
 //!
+//! - Introspection: [`list_threads`] reports the live threads with their uptime,
+//!   and how long ago they last did some work, as reported by [`record_activity`].
+//!
+//! - Accounting: the CPU time and disk I/O of the threads associated with a
+//!   tenant are added to the tenant's counters, see [`crate::tenant_usage`],
+//!   when they report activity and when they exit.
+//!
 //!
 //! # How it works?
 //!
@@ -40,16 +47,21 @@ use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::thread::JoinHandle;
+use std::time::Instant;
 
 use tokio::sync::watch;
 
 use tracing::{debug, error, info, warn};
 
 use lazy_static::lazy_static;
+use metrics::{register_int_gauge_vec, IntGaugeVec};
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
 
 use utils::zid::{ZTenantId, ZTimelineId};
 
 use crate::shutdown_pageserver;
+use crate::tenant_usage::UsageMeter;
 
 lazy_static! {
     /// Each thread that we track is associated with a "thread ID". It's just
@@ -59,6 +71,14 @@ lazy_static! {
 
     /// Global registry of threads
     static ref THREADS: Mutex<HashMap<u64, Arc<PageServerThread>>> = Mutex::new(HashMap::new());
+
+    /// Number of threads in the registry, by kind
+    static ref THREADS_RUNNING: IntGaugeVec = register_int_gauge_vec!(
+        "pageserver_threads_running",
+        "Number of running pageserver threads by kind",
+        &["kind"]
+    )
+    .expect("failed to define a metric");
 }
 
 // There is a Tokio watch channel for each thread, which can be used to signal the
@@ -77,7 +97,7 @@ thread_local!(static CURRENT_THREAD: RefCell<Option<Arc<PageServerThread>>> = Re
 /// Note that we don't try to limit how may threads of a certain kind can be running
 /// at the same time.
 ///
-#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
 pub enum ThreadKind {
     // libpq listener thread. It just accepts connection and spawns a
     // PageRequestHandler thread for each connection.
@@ -100,25 +120,50 @@ pub enum ThreadKind {
     // Thread that handles GC of a tenant
     GarbageCollector,
 
+    // Thread that creates image layers for all timelines of a tenant.
+    ImageBuilder,
+
     // Thread that flushes frozen in-memory layers to disk
     LayerFlushThread,
 
+    // Thread that reads the ancestor layers of a freshly loaded branch
+    AncestorPrefetch,
+
     // Thread for synchronizing pageserver layer files with the remote storage.
     // Shared by all tenants.
     StorageSync,
+
+    // Thread that delivers timeline state change notifications to the
+    // compute notify endpoint.
+    ComputeNotify,
+
+    // Thread that removes the files of detached timelines after their grace
+    // period. Shared by all tenants.
+    DetachedTimelinePurge,
+
+    // Thread that warms up the recently used timelines on start, and saves
+    // their access history. Shared by all tenants.
+    AccessHistory,
 }
 
 struct PageServerThread {
-    _thread_id: u64,
+    thread_id: u64,
 
     kind: ThreadKind,
 
     /// Tenant and timeline that this thread is associated with.
-    tenant_id: Option<ZTenantId>,
-    timeline_id: Option<ZTimelineId>,
+    tenant_id: Mutex<Option<ZTenantId>>,
+    timeline_id: Mutex<Option<ZTimelineId>>,
 
     name: String,
 
+    started_at: Instant,
+    /// When the thread last reported doing some work, see [`record_activity`].
+    last_activity: Mutex<Option<Instant>>,
+    /// Resources used by the thread, not accounted to its tenant yet. Set
+    /// when the thread starts, it must only be used by the thread itself.
+    usage_meter: Mutex<Option<UsageMeter>>,
+
     // To request thread shutdown, set the flag, and send a dummy message to the
     // channel to notify it.
     shutdown_requested: AtomicBool,
@@ -146,12 +191,16 @@ where
     let (shutdown_tx, shutdown_rx) = watch::channel(());
     let thread_id = NEXT_THREAD_ID.fetch_add(1, Ordering::Relaxed);
     let thread = PageServerThread {
-        _thread_id: thread_id,
+        thread_id,
         kind,
-        tenant_id,
-        timeline_id,
+        tenant_id: Mutex::new(tenant_id),
+        timeline_id: Mutex::new(timeline_id),
         name: name.to_string(),
 
+        started_at: Instant::now(),
+        last_activity: Mutex::new(None),
+        usage_meter: Mutex::new(None),
+
         shutdown_requested: AtomicBool::new(false),
         shutdown_tx,
 
@@ -166,6 +215,8 @@ where
         .lock()
         .unwrap()
         .insert(thread_id, Arc::clone(&thread_rc));
+    let threads_running = THREADS_RUNNING.with_label_values(&[&format!("{:?}", kind)]);
+    threads_running.inc();
 
     let thread_rc2 = Arc::clone(&thread_rc);
     let thread_name = name.to_string();
@@ -186,6 +237,7 @@ where
             error!("Failed to spawn thread '{}': {}", name, err);
             // Could not spawn the thread. Remove the entry
             THREADS.lock().unwrap().remove(&thread_id);
+            threads_running.dec();
             return Err(err);
         }
     };
@@ -211,6 +263,8 @@ fn thread_wrapper<F>(
     SHUTDOWN_RX.with(|rx| {
         *rx.borrow_mut() = Some(shutdown_rx);
     });
+    *thread.usage_meter.lock().unwrap() =
+        Some(UsageMeter::current_thread(&format!("{:?}", thread.kind)));
     CURRENT_THREAD.with(|ct| {
         *ct.borrow_mut() = Some(thread);
     });
@@ -228,6 +282,12 @@ fn thread_wrapper<F>(
         .unwrap()
         .remove(&thread_id)
         .expect("no thread in registry");
+    account_usage(&thread, true);
+    let tenant_id = *thread.tenant_id.lock().unwrap();
+    let timeline_id = *thread.timeline_id.lock().unwrap();
+    THREADS_RUNNING
+        .with_label_values(&[&format!("{:?}", thread.kind)])
+        .dec();
 
     match result {
         Ok(Ok(())) => debug!("Thread '{}' exited normally", thread_name),
@@ -235,13 +295,13 @@ fn thread_wrapper<F>(
             if shutdown_process_on_error {
                 error!(
                     "Shutting down: thread '{}' tenant_id: {:?}, timeline_id: {:?} exited with error: {:?}",
-                    thread_name, thread.tenant_id, thread.timeline_id, err
+                    thread_name, tenant_id, timeline_id, err
                 );
                 shutdown_pageserver(1);
             } else {
                 error!(
                     "Thread '{}' tenant_id: {:?}, timeline_id: {:?} exited with error: {:?}",
-                    thread_name, thread.tenant_id, thread.timeline_id, err
+                    thread_name, tenant_id, timeline_id, err
                 );
             }
         }
@@ -249,13 +309,13 @@ fn thread_wrapper<F>(
             if shutdown_process_on_error {
                 error!(
                     "Shutting down: thread '{}' tenant_id: {:?}, timeline_id: {:?} panicked: {:?}",
-                    thread_name, thread.tenant_id, thread.timeline_id, err
+                    thread_name, tenant_id, timeline_id, err
                 );
                 shutdown_pageserver(1);
             } else {
                 error!(
                     "Thread '{}' tenant_id: {:?}, timeline_id: {:?} panicked: {:?}",
-                    thread_name, thread.tenant_id, thread.timeline_id, err
+                    thread_name, tenant_id, timeline_id, err
                 );
             }
         }
@@ -286,8 +346,8 @@ pub fn shutdown_threads(
     let threads = THREADS.lock().unwrap();
     for thread in threads.values() {
         if (kind.is_none() || Some(thread.kind) == kind)
-            && (tenant_id.is_none() || thread.tenant_id == tenant_id)
-            && (timeline_id.is_none() || thread.timeline_id == timeline_id)
+            && (tenant_id.is_none() || *thread.tenant_id.lock().unwrap() == tenant_id)
+            && (timeline_id.is_none() || *thread.timeline_id.lock().unwrap() == timeline_id)
         {
             thread.shutdown_requested.store(true, Ordering::Relaxed);
             // FIXME: handle error?
@@ -308,6 +368,97 @@ pub fn shutdown_threads(
     }
 }
 
+/// Associate the current thread with given tenant and timeline, once it has learned
+/// which ones it serves. Shutting down the threads of the timeline then shuts down
+/// this thread too.
+pub fn associate_with(tenant_id: Option<ZTenantId>, timeline_id: Option<ZTimelineId>) {
+    CURRENT_THREAD.with(|ct| {
+        if let Some(ct) = ct.borrow().as_ref() {
+            // What the thread did so far was for the previous tenant
+            account_usage(ct, true);
+            *ct.tenant_id.lock().unwrap() = tenant_id;
+            *ct.timeline_id.lock().unwrap() = timeline_id;
+        } else {
+            warn!("associate_with() called in an unexpected thread");
+        }
+    });
+}
+
+/// Note that the current thread did some work: served a request, processed WAL,
+/// ran a compaction or GC iteration. Shown as the idle time in [`list_threads`].
+/// The resources it used are accounted to its tenant, at most once a second.
+pub fn record_activity() {
+    CURRENT_THREAD.with(|ct| {
+        if let Some(ct) = ct.borrow().as_ref() {
+            *ct.last_activity.lock().unwrap() = Some(Instant::now());
+            account_usage(ct, false);
+        }
+    });
+}
+
+/// Account the resources used by the current thread to its tenant. Unless
+/// 'force' is set, only if the last time was long enough ago.
+fn account_usage(thread: &PageServerThread, force: bool) {
+    let tenant_id = *thread.tenant_id.lock().unwrap();
+    if let Some(meter) = thread.usage_meter.lock().unwrap().as_mut() {
+        if force {
+            meter.account(tenant_id);
+        } else {
+            meter.maybe_account(tenant_id);
+        }
+    }
+}
+
+/// A live thread, as reported by [`list_threads`].
+#[serde_as]
+#[derive(Debug, Serialize)]
+pub struct ThreadInfo {
+    pub id: u64,
+    pub kind: ThreadKind,
+    pub name: String,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub tenant_id: Option<ZTenantId>,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub timeline_id: Option<ZTimelineId>,
+    pub uptime_secs: u64,
+    /// Seconds since the thread last did some work, None if it never reported any.
+    pub idle_secs: Option<u64>,
+}
+
+/// List the running threads. Any None arguments are ignored, like in [`shutdown_threads`].
+pub fn list_threads(
+    kind: Option<ThreadKind>,
+    tenant_id: Option<ZTenantId>,
+    timeline_id: Option<ZTimelineId>,
+) -> Vec<ThreadInfo> {
+    let threads = THREADS.lock().unwrap();
+    let mut thread_infos = threads
+        .values()
+        .map(|thread| ThreadInfo {
+            id: thread.thread_id,
+            kind: thread.kind,
+            name: thread.name.clone(),
+            tenant_id: *thread.tenant_id.lock().unwrap(),
+            timeline_id: *thread.timeline_id.lock().unwrap(),
+            uptime_secs: thread.started_at.elapsed().as_secs(),
+            idle_secs: thread
+                .last_activity
+                .lock()
+                .unwrap()
+                .map(|last_activity| last_activity.elapsed().as_secs()),
+        })
+        .filter(|thread| {
+            (kind.is_none() || Some(thread.kind) == kind)
+                && (tenant_id.is_none() || thread.tenant_id == tenant_id)
+                && (timeline_id.is_none() || thread.timeline_id == timeline_id)
+        })
+        .collect::<Vec<_>>();
+    drop(threads);
+
+    thread_infos.sort_by_key(|thread| thread.id);
+    thread_infos
+}
+
 /// A Future that can be used to check if the current thread has been requested to
 /// shut down.
 pub async fn shutdown_watcher() {
@@ -0,0 +1,541 @@
+//!
+//! Diagnostics of a timeline: the "timeline doctor".
+//!
+//! [`examine_timeline`] runs a series of read-only checks on a timeline and
+//! returns a report with the result of each check, what it found, and what
+//! can be done about it:
+//!
+//! - metadata: the metadata file is readable, consistent, and agrees with the
+//!   timeline loaded in memory.
+//! - layer_map: the layer files in the layer map are on disk and vice versa,
+//!   and the delta layers above the GC cutoff cover the WAL without gaps.
+//! - ancestors: the ancestor timelines can be loaded, and have the WAL up to
+//!   the branch points.
+//! - remote_index: the remote storage index of the timeline agrees with the
+//!   local layers.
+//! - page_reconstruction: a sample of the keys in use at the last record LSN
+//!   can be reconstructed from the layers, bypassing the page caches.
+//!
+//! Nothing is repaired, the remediations are suggestions for an operator.
+//!
+use std::collections::HashSet;
+use std::fs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+use tracing::*;
+use utils::lsn::Lsn;
+use utils::zid::{ZTenantId, ZTimelineId};
+
+use crate::keyspace::KeySpace;
+use crate::layered_repository::metadata::TimelineMetadata;
+use crate::layered_repository::{is_layer_file_name, load_metadata, LayeredTimeline};
+use crate::pg_file_path;
+use crate::repository::{key_range_size, Key, Repository, Timeline};
+use crate::storage_sync::index::RemoteTimeline;
+use crate::{DatadirTimelineImpl, RepositoryImpl};
+
+/// Maximum number of findings listed per check, the rest are only counted.
+const MAX_FINDINGS_PER_CHECK: usize = 20;
+
+/// Maximum length of the ancestor chain that is followed.
+const MAX_ANCESTOR_DEPTH: usize = 1000;
+
+const REPAIR_METADATA: &str = "Restore the metadata file from the remote storage by detaching and attaching the timeline, or repair it with the update_metadata tool while the tenant is detached";
+const RELOAD_TIMELINE: &str =
+    "Restart the page server, or detach and attach the tenant, to reload the timeline from disk";
+const REDOWNLOAD_LAYERS: &str =
+    "Detach and attach the timeline to download the missing layers from the remote storage";
+const RERUN_CHECK: &str =
+    "Run the check again: compaction or GC may have been replacing the layers at the same time";
+const ATTACH_ANCESTOR: &str =
+    "Attach the ancestor timeline, ancestors have to be present locally before their branches";
+const WAIT_FOR_UPLOAD: &str =
+    "Wait for the storage sync to catch up, and check its log if it doesn't";
+const CHECK_READ_ERRORS: &str = "Check the page server log for the layer traversal path and WAL redo errors of the failed keys, and download the layers they were read from again";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckStatus {
+    Ok,
+    Warning,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DoctorCheck {
+    pub name: String,
+    /// The worst status of the findings.
+    pub status: CheckStatus,
+    pub findings: Vec<String>,
+    pub remediations: Vec<String>,
+    /// Findings not listed, above `MAX_FINDINGS_PER_CHECK`.
+    pub findings_omitted: usize,
+}
+
+impl DoctorCheck {
+    fn new(name: &str) -> Self {
+        DoctorCheck {
+            name: name.to_string(),
+            status: CheckStatus::Ok,
+            findings: Vec::new(),
+            remediations: Vec::new(),
+            findings_omitted: 0,
+        }
+    }
+
+    fn report(&mut self, status: CheckStatus, finding: String, remediation: &str) {
+        self.status = self.status.max(status);
+        if self.findings.len() < MAX_FINDINGS_PER_CHECK {
+            self.findings.push(finding);
+        } else {
+            self.findings_omitted += 1;
+        }
+        if !self.remediations.iter().any(|r| r == remediation) {
+            self.remediations.push(remediation.to_string());
+        }
+    }
+
+    fn warn(&mut self, finding: String, remediation: &str) {
+        self.report(CheckStatus::Warning, finding, remediation)
+    }
+
+    fn fail(&mut self, finding: String, remediation: &str) {
+        self.report(CheckStatus::Failed, finding, remediation)
+    }
+}
+
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TimelineDoctorReport {
+    #[serde_as(as = "DisplayFromStr")]
+    pub tenant_id: ZTenantId,
+    #[serde_as(as = "DisplayFromStr")]
+    pub timeline_id: ZTimelineId,
+    #[serde_as(as = "DisplayFromStr")]
+    pub last_record_lsn: Lsn,
+    #[serde_as(as = "DisplayFromStr")]
+    pub disk_consistent_lsn: Lsn,
+    /// The worst status of the checks.
+    pub status: CheckStatus,
+    pub checks: Vec<DoctorCheck>,
+    pub elapsed_ms: u128,
+}
+
+///
+/// Run all checks on a local timeline. 'remote_timeline' is the entry of the
+/// timeline in the remote index, if any. Up to 'sample_pages' keys are
+/// reconstructed.
+///
+pub fn examine_timeline(
+    repo: &RepositoryImpl,
+    timeline_id: ZTimelineId,
+    timeline: &DatadirTimelineImpl,
+    remote_timeline: Option<&RemoteTimeline>,
+    sample_pages: usize,
+) -> TimelineDoctorReport {
+    let started_at = Instant::now();
+    let tline = &timeline.tline;
+    let last_record_lsn = tline.get_last_record_lsn();
+    let disk_consistent_lsn = tline.get_disk_consistent_lsn();
+
+    let checks = vec![
+        check_metadata(repo, timeline_id, tline),
+        check_layer_map(repo, timeline_id, tline),
+        check_ancestors(repo, tline),
+        check_remote_index(repo, tline, remote_timeline),
+        check_page_reconstruction(timeline, last_record_lsn, sample_pages),
+    ];
+    let status = checks
+        .iter()
+        .map(|check| check.status)
+        .max()
+        .unwrap_or(CheckStatus::Ok);
+
+    let report = TimelineDoctorReport {
+        tenant_id: repo.tenant_id(),
+        timeline_id,
+        last_record_lsn,
+        disk_consistent_lsn,
+        status,
+        checks,
+        elapsed_ms: started_at.elapsed().as_millis(),
+    };
+    info!(
+        "examined timeline {timeline_id}: {:?} in {} ms",
+        report.status, report.elapsed_ms
+    );
+    report
+}
+
+fn check_metadata(
+    repo: &RepositoryImpl,
+    timeline_id: ZTimelineId,
+    tline: &LayeredTimeline,
+) -> DoctorCheck {
+    let mut check = DoctorCheck::new("metadata");
+
+    let metadata = match load_metadata(repo.conf, timeline_id, repo.tenant_id()) {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            check.fail(format!("{e:#}"), REPAIR_METADATA);
+            return check;
+        }
+    };
+    if let Err(e) = TimelineMetadata::from_dump(&metadata.dump()) {
+        check.fail(format!("inconsistent metadata: {e:#}"), REPAIR_METADATA);
+    }
+
+    // The file is saved after each flush, so it may lag behind the timeline but
+    // never be ahead of it.
+    let disk_consistent_lsn = tline.get_disk_consistent_lsn();
+    if metadata.disk_consistent_lsn() > disk_consistent_lsn {
+        check.fail(
+            format!(
+                "disk_consistent_lsn {} in the metadata file is ahead of the loaded timeline's {}",
+                metadata.disk_consistent_lsn(),
+                disk_consistent_lsn
+            ),
+            RELOAD_TIMELINE,
+        );
+    }
+    let last_record_lsn = tline.get_last_record_lsn();
+    if disk_consistent_lsn > last_record_lsn {
+        check.fail(
+            format!("disk_consistent_lsn {disk_consistent_lsn} is ahead of last_record_lsn {last_record_lsn}"),
+            RELOAD_TIMELINE,
+        );
+    }
+    if metadata.ancestor_timeline() != tline.get_ancestor_timeline_id()
+        || metadata.ancestor_lsn() != tline.get_ancestor_lsn()
+    {
+        check.fail(
+            format!(
+                "ancestor {:?} at {} in the metadata file differs from the loaded timeline's {:?} at {}",
+                metadata.ancestor_timeline(),
+                metadata.ancestor_lsn(),
+                tline.get_ancestor_timeline_id(),
+                tline.get_ancestor_lsn()
+            ),
+            REPAIR_METADATA,
+        );
+    }
+    check
+}
+
+fn check_layer_map(
+    repo: &RepositoryImpl,
+    timeline_id: ZTimelineId,
+    tline: &LayeredTimeline,
+) -> DoctorCheck {
+    let mut check = DoctorCheck::new("layer_map");
+
+    let layer_files = tline.layer_files();
+    let timeline_path = repo.conf.timeline_path(&timeline_id, &repo.tenant_id());
+    let files_on_disk = match list_layer_files(&timeline_path) {
+        Ok(files) => files,
+        Err(e) => {
+            check.fail(format!("{e:#}"), RELOAD_TIMELINE);
+            return check;
+        }
+    };
+
+    let mut in_layer_map = HashSet::with_capacity(layer_files.len());
+    for layer in &layer_files {
+        if !layer.path.exists() {
+            check.fail(
+                format!("layer file {} is missing", layer.path.display()),
+                REDOWNLOAD_LAYERS,
+            );
+        }
+        in_layer_map.insert(layer.path.clone());
+    }
+    for path in files_on_disk.difference(&in_layer_map) {
+        check.warn(
+            format!("layer file {} is not in the layer map", path.display()),
+            RERUN_CHECK,
+        );
+    }
+
+    // Every flush writes the WAL from where the previous one ended, and
+    // compaction replaces layers with ones covering the same LSN range. GC only
+    // removes layers below its cutoff, so the delta layers above it have no gaps.
+    let gc_cutoff = *tline.get_latest_gc_cutoff_lsn();
+    let lsn_ranges = layer_files
+        .iter()
+        .filter(|layer| layer.is_incremental && layer.lsn_range.end > gc_cutoff)
+        .map(|layer| layer.lsn_range.clone())
+        .collect::<Vec<_>>();
+    for gap in lsn_gaps(lsn_ranges) {
+        check.fail(
+            format!(
+                "no delta layer covers the WAL between {} and {}",
+                gap.start, gap.end
+            ),
+            REDOWNLOAD_LAYERS,
+        );
+    }
+    check
+}
+
+/// The layer files in a timeline directory.
+fn list_layer_files(timeline_path: &Path) -> anyhow::Result<HashSet<PathBuf>> {
+    let mut files = HashSet::new();
+    for entry in fs::read_dir(timeline_path)
+        .with_context(|| format!("Failed to list '{}'", timeline_path.display()))?
+    {
+        let entry = entry?;
+        if is_layer_file_name(&entry.file_name().to_string_lossy()) {
+            files.insert(entry.path());
+        }
+    }
+    Ok(files)
+}
+
+/// The LSN ranges between the given ranges that none of them covers.
+fn lsn_gaps(mut ranges: Vec<Range<Lsn>>) -> Vec<Range<Lsn>> {
+    ranges.sort_by_key(|range| range.start);
+    let mut gaps = Vec::new();
+    let mut covered_up_to = None;
+    for range in ranges {
+        match covered_up_to {
+            Some(end) if range.start > end => {
+                gaps.push(end..range.start);
+                covered_up_to = Some(range.end);
+            }
+            Some(end) => covered_up_to = Some(range.end.max(end)),
+            None => covered_up_to = Some(range.end),
+        }
+    }
+    gaps
+}
+
+fn check_ancestors(repo: &RepositoryImpl, tline: &LayeredTimeline) -> DoctorCheck {
+    let mut check = DoctorCheck::new("ancestors");
+
+    let mut visited = HashSet::new();
+    let mut ancestor_id = tline.get_ancestor_timeline_id();
+    let mut branch_lsn = tline.get_ancestor_lsn();
+    while let Some(id) = ancestor_id {
+        if !visited.insert(id) || visited.len() > MAX_ANCESTOR_DEPTH {
+            check.fail(
+                format!("the ancestors of the timeline form a cycle at {id}"),
+                REPAIR_METADATA,
+            );
+            break;
+        }
+        let ancestor = match repo.get_timeline_load(id) {
+            Ok(ancestor) => ancestor,
+            Err(e) => {
+                check.fail(
+                    format!("ancestor timeline {id} can't be loaded: {e:#}"),
+                    ATTACH_ANCESTOR,
+                );
+                break;
+            }
+        };
+        let ancestor_last_record_lsn = ancestor.get_last_record_lsn();
+        if branch_lsn > ancestor_last_record_lsn {
+            check.fail(
+                format!(
+                    "branch point {branch_lsn} is beyond the last record LSN {ancestor_last_record_lsn} of ancestor timeline {id}"
+                ),
+                ATTACH_ANCESTOR,
+            );
+        }
+        ancestor_id = ancestor.get_ancestor_timeline_id();
+        branch_lsn = ancestor.get_ancestor_lsn();
+    }
+    check
+}
+
+fn check_remote_index(
+    repo: &RepositoryImpl,
+    tline: &LayeredTimeline,
+    remote_timeline: Option<&RemoteTimeline>,
+) -> DoctorCheck {
+    let mut check = DoctorCheck::new("remote_index");
+
+    let remote_timeline = match remote_timeline {
+        Some(remote_timeline) => remote_timeline,
+        None => {
+            if repo.conf.remote_storage_config.is_some() {
+                check.warn(
+                    "the timeline is not in the remote index".to_string(),
+                    WAIT_FOR_UPLOAD,
+                );
+            }
+            return check;
+        }
+    };
+
+    let disk_consistent_lsn = tline.get_disk_consistent_lsn();
+    let remote_consistent_lsn = remote_timeline.remote_consistent_lsn();
+    if remote_consistent_lsn > disk_consistent_lsn {
+        check.fail(
+            format!("remote_consistent_lsn {remote_consistent_lsn} is ahead of the local disk_consistent_lsn {disk_consistent_lsn}"),
+            REDOWNLOAD_LAYERS,
+        );
+    }
+    if remote_timeline.awaits_download {
+        check.warn(
+            "the timeline is still being downloaded".to_string(),
+            WAIT_FOR_UPLOAD,
+        );
+    }
+
+    // The layers up to the remote consistent LSN have been uploaded, unless
+    // compaction or GC replaced them since.
+    let remote_layers = remote_timeline.stored_files();
+    for layer in tline.layer_files() {
+        if layer.lsn_range.end <= remote_consistent_lsn && !remote_layers.contains(&layer.path) {
+            check.warn(
+                format!(
+                    "layer {} below remote_consistent_lsn is not in the remote index",
+                    layer.path.display()
+                ),
+                WAIT_FOR_UPLOAD,
+            );
+        }
+    }
+    check
+}
+
+fn check_page_reconstruction(
+    timeline: &DatadirTimelineImpl,
+    lsn: Lsn,
+    sample_pages: usize,
+) -> DoctorCheck {
+    let mut check = DoctorCheck::new("page_reconstruction");
+    if sample_pages == 0 {
+        return check;
+    }
+
+    let keyspace = match timeline.collect_keyspace(lsn) {
+        Ok(keyspace) => keyspace,
+        Err(e) => {
+            check.fail(
+                format!("failed to collect the keys in use at {lsn}: {e:#}"),
+                CHECK_READ_ERRORS,
+            );
+            return check;
+        }
+    };
+    for key in sample_keys(&keyspace, sample_pages) {
+        if let Err(e) = timeline.tline.get_from_layers(key, lsn) {
+            let location = match pg_file_path::key_to_pg_file(&key) {
+                Some(location) => format!(" ({location})"),
+                None => String::new(),
+            };
+            check.fail(
+                format!("failed to reconstruct key {key}{location} at {lsn}: {e:#}"),
+                CHECK_READ_ERRORS,
+            );
+        }
+    }
+    check
+}
+
+/// Up to 'count' keys of the key space, evenly spread.
+fn sample_keys(keyspace: &KeySpace, count: usize) -> Vec<Key> {
+    let total: u64 = keyspace
+        .ranges
+        .iter()
+        .map(|range| key_range_size(range) as u64)
+        .sum();
+    if total == 0 || count == 0 {
+        return Vec::new();
+    }
+    let step = (total / count as u64).max(1);
+
+    let mut keys = Vec::new();
+    let mut range_offset = 0;
+    let mut next = step / 2;
+    for range in &keyspace.ranges {
+        let size = key_range_size(range) as u64;
+        while next < range_offset + size && keys.len() < count {
+            let key = range.start.add((next - range_offset) as u32);
+            if key < range.end {
+                keys.push(key);
+            }
+            next += step;
+        }
+        range_offset += size;
+    }
+    keys
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reltag::RelTag;
+    use crate::repository::repo_harness::*;
+    use crate::CheckpointConfig;
+
+    fn check_status(report: &TimelineDoctorReport, name: &str) -> CheckStatus {
+        report
+            .checks
+            .iter()
+            .find(|check| check.name == name)
+            .unwrap()
+            .status
+    }
+
+    #[test]
+    fn timeline_doctor() -> anyhow::Result<()> {
+        let repo = RepoHarness::create("timeline_doctor")?.load();
+        let tline =
+            DatadirTimelineImpl::new(repo.create_empty_timeline(TIMELINE_ID, Lsn(8))?, 256 * 1024);
+        let mut m = tline.begin_modification(Lsn(8));
+        m.init_empty()?;
+        m.commit()?;
+
+        let rel = RelTag {
+            spcnode: 1663,
+            dbnode: 1,
+            relnode: 1000,
+            forknum: 0,
+        };
+        let mut m = tline.begin_modification(Lsn(0x20));
+        m.put_control_file(TEST_IMG("control file"))?;
+        m.put_checkpoint(TEST_IMG("checkpoint"))?;
+        m.put_relmap_file(rel.spcnode, rel.dbnode, TEST_IMG("relmap"))?;
+        m.put_rel_creation(rel, 10)?;
+        for blknum in 0..10 {
+            m.put_rel_page_image(rel, blknum, TEST_IMG(&format!("block {blknum}")))?;
+        }
+        m.commit()?;
+        tline.tline.checkpoint(CheckpointConfig::Forced)?;
+
+        let report = examine_timeline(&repo, TIMELINE_ID, &tline, None, 100);
+        assert_eq!(report.status, CheckStatus::Ok, "{report:?}");
+        assert_eq!(report.last_record_lsn, Lsn(0x20));
+
+        // Lose a layer file
+        let layer = tline.tline.layer_files().pop().unwrap();
+        fs::remove_file(&layer.path)?;
+        let report = examine_timeline(&repo, TIMELINE_ID, &tline, None, 100);
+        assert_eq!(report.status, CheckStatus::Failed);
+        assert_eq!(check_status(&report, "layer_map"), CheckStatus::Failed);
+        assert_eq!(check_status(&report, "metadata"), CheckStatus::Ok);
+        assert_eq!(check_status(&report, "ancestors"), CheckStatus::Ok);
+        Ok(())
+    }
+
+    #[test]
+    fn timeline_doctor_lsn_gaps() {
+        let gaps = lsn_gaps(vec![
+            Lsn(0x30)..Lsn(0x40),
+            Lsn(0x10)..Lsn(0x20),
+            Lsn(0x10)..Lsn(0x18),
+            Lsn(0x50)..Lsn(0x60),
+            Lsn(0x20)..Lsn(0x30),
+        ]);
+        assert_eq!(gaps, vec![Lsn(0x40)..Lsn(0x50)]);
+        assert!(lsn_gaps(Vec::new()).is_empty());
+    }
+}
@@ -3,32 +3,38 @@
 //
 
 use anyhow::{bail, ensure, Context, Result};
-use postgres_ffi::ControlFileData;
+use postgres_ffi::{pg_constants, ControlFileData};
 use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, DisplayFromStr};
 use std::{
+    collections::HashSet,
     fs,
     path::Path,
     process::{Command, Stdio},
+    str::FromStr,
     sync::Arc,
+    thread,
+    time::{Duration, Instant},
 };
 use tracing::*;
 
 use utils::{
     crashsafe_dir, logging,
     lsn::Lsn,
-    zid::{ZTenantId, ZTimelineId},
+    zid::{ZTenantId, ZTenantTimelineId, ZTimelineId},
 };
 
+use crate::compute_notify::{self, TimelineState};
 use crate::{
     config::PageServerConf,
     layered_repository::metadata::TimelineMetadata,
+    reltag::RelTag,
     repository::{LocalTimelineState, Repository},
-    storage_sync::index::RemoteIndex,
+    storage_sync::{index::RemoteIndex, DownloadProgress},
     tenant_config::TenantConfOpt,
     DatadirTimeline, RepositoryImpl,
 };
-use crate::{import_datadir, LOG_FILE_NAME};
+use crate::{import_datadir, import_dump, LOG_FILE_NAME};
 use crate::{layered_repository::LayeredRepository, walredo::WalRedoManager};
 use crate::{repository::RepositoryTimeline, tenant_mgr};
 use crate::{repository::Timeline, CheckpointConfig};
@@ -50,6 +56,8 @@ pub struct LocalTimelineInfo {
     pub disk_consistent_lsn: Lsn,
     pub current_logical_size: Option<usize>, // is None when timeline is Unloaded
     pub current_logical_size_non_incremental: Option<usize>,
+    /// Is the timeline over its tenant's logical size soft limit, see [`crate::size_soft_limit`]
+    pub size_soft_limit_exceeded: bool,
     pub timeline_state: LocalTimelineState,
 }
 
@@ -78,6 +86,7 @@ impl LocalTimelineInfo {
             } else {
                 None
             },
+            size_soft_limit_exceeded: datadir_tline.size_soft_limit.is_exceeded(),
         };
         Ok(info)
     }
@@ -98,6 +107,7 @@ impl LocalTimelineInfo {
             timeline_state: LocalTimelineState::Unloaded,
             current_logical_size: None,
             current_logical_size_non_incremental: None,
+            size_soft_limit_exceeded: false,
         }
     }
 
@@ -124,6 +134,9 @@ pub struct RemoteTimelineInfo {
     #[serde_as(as = "DisplayFromStr")]
     pub remote_consistent_lsn: Lsn,
     pub awaits_download: bool,
+    /// Progress of the download, once it has started
+    pub download_progress: Option<DownloadProgress>,
+    pub archived: bool,
 }
 
 #[serde_as]
@@ -137,6 +150,76 @@ pub struct TimelineInfo {
     pub remote: Option<RemoteTimelineInfo>,
 }
 
+#[serde_as]
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TimelineWarmUpInfo {
+    #[serde_as(as = "DisplayFromStr")]
+    pub lsn: Lsn,
+    pub layers_read: usize,
+    pub layer_bytes_read: u64,
+    pub pages_materialized: usize,
+    pub elapsed_ms: u128,
+}
+
+#[serde_as]
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TimelineMaterializeInfo {
+    #[serde_as(as = "DisplayFromStr")]
+    pub lsn: Lsn,
+    pub image_layers_created: usize,
+    pub image_layer_bytes: u64,
+    pub elapsed_ms: u128,
+}
+
+#[serde_as]
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TimelineDurabilityInfo {
+    #[serde_as(as = "DisplayFromStr")]
+    pub lsn: Lsn,
+    #[serde_as(as = "DisplayFromStr")]
+    pub disk_consistent_lsn: Lsn,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub remote_consistent_lsn: Option<Lsn>,
+    pub elapsed_ms: u128,
+}
+
+#[serde_as]
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TimelineFenceInfo {
+    /// All the WAL before this LSN is ingested and durable on the local disk.
+    #[serde_as(as = "DisplayFromStr")]
+    pub fence_lsn: Lsn,
+    #[serde_as(as = "DisplayFromStr")]
+    pub disk_consistent_lsn: Lsn,
+    /// Reads below this LSN are rejected.
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub read_floor_lsn: Option<Lsn>,
+}
+
+/// Where an LSN has to be durable for [`wait_for_durability`] to return.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DurabilityScope {
+    /// The WAL up to the LSN is in layer files on the local disk.
+    Local,
+    /// The layer files with the WAL up to the LSN are uploaded to the remote storage.
+    Remote,
+}
+
+impl FromStr for DurabilityScope {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "local" => Ok(Self::Local),
+            "remote" => Ok(Self::Remote),
+            _ => bail!("unknown durability scope '{s}', expected 'local' or 'remote'"),
+        }
+    }
+}
+
+/// How often to check the remote consistent LSN while waiting for uploads.
+const REMOTE_DURABILITY_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
 #[derive(Debug, Clone, Copy)]
 pub struct PointInTime {
     pub timeline_id: ZTimelineId,
@@ -159,7 +242,7 @@ pub fn init_pageserver(
         let repo = create_repo(conf, TenantConfOpt::default(), tenant_id, CreateRepo::Dummy)
             .context("failed to create repo")?;
         let new_timeline_id = initial_timeline_id.unwrap_or_else(ZTimelineId::generate);
-        bootstrap_timeline(conf, tenant_id, new_timeline_id, repo.as_ref())
+        bootstrap_timeline(conf, tenant_id, new_timeline_id, repo.as_ref(), None)
             .context("failed to create initial timeline")?;
         println!("initial timeline {} created", new_timeline_id)
     } else if initial_timeline_id.is_some() {
@@ -275,6 +358,7 @@ fn run_initdb(conf: &'static PageServerConf, initdbpath: &Path) -> Result<()> {
 
 //
 // - run initdb to init temporary instance and get bootstrap data
+// - if a logical dump is given, restore it into the temporary instance
 // - after initialization complete, remove the temp dir.
 //
 fn bootstrap_timeline<R: Repository>(
@@ -282,6 +366,7 @@ fn bootstrap_timeline<R: Repository>(
     tenantid: ZTenantId,
     tli: ZTimelineId,
     repo: &R,
+    dump_path: Option<&Path>,
 ) -> Result<()> {
     let _enter = info_span!("bootstrapping", timeline = %tli, tenant = %tenantid).entered();
 
@@ -289,11 +374,45 @@ fn bootstrap_timeline<R: Repository>(
         .tenant_path(&tenantid)
         .join(format!("tmp-timeline-{}", tli));
 
+    // An earlier attempt that crashed may have left the directory behind,
+    // and initdb refuses to run in a non-empty one.
+    if initdb_path.exists() {
+        warn!(
+            "removing leftover temporary directory '{}'",
+            initdb_path.display()
+        );
+        fs::remove_dir_all(&initdb_path)?;
+    }
+
+    let result = import_initdb_cluster(conf, tli, repo, &initdb_path, dump_path);
+
+    // Remove temp dir, whether the timeline was created or not. We don't need it anymore
+    if initdb_path.exists() {
+        if let Err(e) = fs::remove_dir_all(&initdb_path) {
+            warn!("failed to remove '{}': {e}", initdb_path.display());
+        }
+    }
+
+    result
+}
+
+/// Run initdb in 'pgdata_path', and import the cluster into a new timeline at
+/// the LSN of its initial checkpoint.
+fn import_initdb_cluster<R: Repository>(
+    conf: &'static PageServerConf,
+    tli: ZTimelineId,
+    repo: &R,
+    pgdata_path: &Path,
+    dump_path: Option<&Path>,
+) -> Result<()> {
     // Init temporarily repo to get bootstrap data
-    run_initdb(conf, &initdb_path)?;
-    let pgdata_path = initdb_path;
+    run_initdb(conf, pgdata_path)?;
 
-    let lsn = get_lsn_from_controlfile(&pgdata_path)?.align();
+    if let Some(dump_path) = dump_path {
+        import_dump::restore_dump(conf, pgdata_path, dump_path, tli)?;
+    }
+
+    let lsn = get_lsn_from_controlfile(pgdata_path)?.align();
 
     // Import the contents of the data directory at the initial checkpoint
     // LSN, and any WAL after that.
@@ -301,7 +420,7 @@ fn bootstrap_timeline<R: Repository>(
     // Because we know it upfront avoid having an option or dummy zero value by passing it to create_empty_timeline.
     let timeline = repo.create_empty_timeline(tli, lsn)?;
     let mut page_tline: DatadirTimeline<R> = DatadirTimeline::new(timeline, u64::MAX);
-    import_datadir::import_timeline_from_postgres_datadir(&pgdata_path, &mut page_tline, lsn)?;
+    import_datadir::import_timeline_from_postgres_datadir(pgdata_path, &mut page_tline, lsn)?;
 
     fail::fail_point!("before-checkpoint-new-timeline", |_| {
         bail!("failpoint before-checkpoint-new-timeline");
@@ -315,9 +434,6 @@ fn bootstrap_timeline<R: Repository>(
         page_tline.tline.get_last_record_lsn()
     );
 
-    // Remove temp dir. We don't need it anymore
-    fs::remove_dir_all(pgdata_path)?;
-
     Ok(())
 }
 
@@ -350,49 +466,43 @@ pub(crate) fn create_timeline(
     new_timeline_id: Option<ZTimelineId>,
     ancestor_timeline_id: Option<ZTimelineId>,
     ancestor_start_lsn: Option<Lsn>,
+    import_dump_path: Option<&Path>,
 ) -> Result<Option<TimelineInfo>> {
     let new_timeline_id = new_timeline_id.unwrap_or_else(ZTimelineId::generate);
     let repo = tenant_mgr::get_repository_for_tenant(tenant_id)?;
+    ensure!(
+        import_dump_path.is_none() || ancestor_timeline_id.is_none(),
+        "A dump can only be imported into a new root timeline, not into a branch"
+    );
 
     if conf.timeline_path(&new_timeline_id, &tenant_id).exists() {
         debug!("timeline {} already exists", new_timeline_id);
         return Ok(None);
     }
 
-    let mut start_lsn = ancestor_start_lsn.unwrap_or(Lsn(0));
+    let start_lsn = ancestor_start_lsn.unwrap_or(Lsn(0));
 
     let new_timeline_info = match ancestor_timeline_id {
+        Some(ancestor_timeline_id) if repo.get_timeline(ancestor_timeline_id).is_none() => {
+            let new_timeline_info = branch_remote_timeline(
+                &repo,
+                tenant_id,
+                ancestor_timeline_id,
+                new_timeline_id,
+                start_lsn,
+            )?;
+            // The data of the new timeline is available once its ancestor gets downloaded.
+            tenant_mgr::download_missing_ancestor(tenant_id, new_timeline_id);
+            compute_notify::notify(tenant_id, new_timeline_id, TimelineState::AwaitsDownload);
+            return Ok(Some(TimelineInfo {
+                tenant_id,
+                timeline_id: new_timeline_id,
+                local: Some(new_timeline_info),
+                remote: None,
+            }));
+        }
         Some(ancestor_timeline_id) => {
-            let ancestor_timeline = repo
-                .get_timeline_load(ancestor_timeline_id)
-                .context("Cannot branch off the timeline that's not present locally")?;
-
-            if start_lsn == Lsn(0) {
-                // Find end of WAL on the old timeline
-                let end_of_wal = ancestor_timeline.get_last_record_lsn();
-                info!("branching at end of WAL: {}", end_of_wal);
-                start_lsn = end_of_wal;
-            } else {
-                // Wait for the WAL to arrive and be processed on the parent branch up
-                // to the requested branch point. The repository code itself doesn't
-                // require it, but if we start to receive WAL on the new timeline,
-                // decoding the new WAL might need to look up previous pages, relation
-                // sizes etc. and that would get confused if the previous page versions
-                // are not in the repository yet.
-                ancestor_timeline.wait_lsn(start_lsn)?;
-            }
-            start_lsn = start_lsn.align();
-
-            let ancestor_ancestor_lsn = ancestor_timeline.get_ancestor_lsn();
-            if ancestor_ancestor_lsn > start_lsn {
-                // can we safely just branch from the ancestor instead?
-                anyhow::bail!(
-                    "invalid start lsn {} for ancestor timeline {}: less than timeline ancestor lsn {}",
-                    start_lsn,
-                    ancestor_timeline_id,
-                    ancestor_ancestor_lsn,
-                );
-            }
+            let start_lsn = branch_start_lsn(repo.as_ref(), ancestor_timeline_id, start_lsn)?;
             repo.branch_timeline(ancestor_timeline_id, new_timeline_id, start_lsn)?;
             // load the timeline into memory
             let loaded_timeline =
@@ -401,7 +511,13 @@ pub(crate) fn create_timeline(
                 .context("cannot fill timeline info")?
         }
         None => {
-            bootstrap_timeline(conf, tenant_id, new_timeline_id, repo.as_ref())?;
+            bootstrap_timeline(
+                conf,
+                tenant_id,
+                new_timeline_id,
+                repo.as_ref(),
+                import_dump_path,
+            )?;
             // load the timeline into memory
             let new_timeline =
                 tenant_mgr::get_local_timeline_with_load(tenant_id, new_timeline_id)?;
@@ -409,6 +525,7 @@ pub(crate) fn create_timeline(
                 .context("cannot fill timeline info")?
         }
     };
+    compute_notify::notify(tenant_id, new_timeline_id, TimelineState::Ready);
     Ok(Some(TimelineInfo {
         tenant_id,
         timeline_id: new_timeline_id,
@@ -416,3 +533,439 @@ pub(crate) fn create_timeline(
         remote: None,
     }))
 }
+
+///
+/// The LSN to branch a local timeline at: its end of WAL if 'start_lsn' is 0,
+/// otherwise 'start_lsn', once the WAL up to it is processed.
+///
+fn branch_start_lsn(
+    repo: &RepositoryImpl,
+    ancestor_timeline_id: ZTimelineId,
+    start_lsn: Lsn,
+) -> Result<Lsn> {
+    let ancestor_timeline = repo
+        .get_timeline_load(ancestor_timeline_id)
+        .context("Cannot branch off the timeline that's not present locally")?;
+
+    let start_lsn = if start_lsn == Lsn(0) {
+        // Find end of WAL on the old timeline
+        let end_of_wal = ancestor_timeline.get_last_record_lsn();
+        info!("branching at end of WAL: {}", end_of_wal);
+        end_of_wal
+    } else {
+        // Wait for the WAL to arrive and be processed on the parent branch up
+        // to the requested branch point. The repository code itself doesn't
+        // require it, but if we start to receive WAL on the new timeline,
+        // decoding the new WAL might need to look up previous pages, relation
+        // sizes etc. and that would get confused if the previous page versions
+        // are not in the repository yet.
+        ancestor_timeline.wait_lsn(start_lsn)?;
+        start_lsn
+    }
+    .align();
+
+    let ancestor_ancestor_lsn = ancestor_timeline.get_ancestor_lsn();
+    if ancestor_ancestor_lsn > start_lsn {
+        // can we safely just branch from the ancestor instead?
+        bail!(
+            "invalid start lsn {} for ancestor timeline {}: less than timeline ancestor lsn {}",
+            start_lsn,
+            ancestor_timeline_id,
+            ancestor_ancestor_lsn,
+        );
+    }
+    Ok(start_lsn)
+}
+
+/// Maximum number of branches created with one [`create_branches`] call.
+pub const MAX_BRANCH_BATCH_SIZE: usize = 1000;
+
+/// Outcome of the creation of one branch in a batch.
+#[serde_as]
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BranchCreationResult {
+    #[serde_as(as = "DisplayFromStr")]
+    pub timeline_id: ZTimelineId,
+    /// False if the timeline already exists, or if its creation failed
+    pub created: bool,
+    pub error: Option<String>,
+}
+
+#[serde_as]
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BranchBatchInfo {
+    #[serde_as(as = "DisplayFromStr")]
+    pub ancestor_timeline_id: ZTimelineId,
+    #[serde_as(as = "DisplayFromStr")]
+    pub ancestor_lsn: Lsn,
+    pub branches: Vec<BranchCreationResult>,
+}
+
+///
+/// Create branches of a local timeline, all at the same LSN, for workloads like
+/// CI that create many branches at once. The ancestor and the LSN are validated
+/// once, and the new timelines are made durable together, see
+/// [`LayeredRepository::branch_timelines`]. The new timelines are not loaded
+/// until they are used.
+///
+pub(crate) fn create_branches(
+    tenant_id: ZTenantId,
+    ancestor_timeline_id: ZTimelineId,
+    ancestor_start_lsn: Option<Lsn>,
+    new_timeline_ids: &[ZTimelineId],
+) -> Result<BranchBatchInfo> {
+    ensure!(!new_timeline_ids.is_empty(), "No timelines to create");
+    ensure!(
+        new_timeline_ids.len() <= MAX_BRANCH_BATCH_SIZE,
+        "Too many timelines to create at once: {}, at most {} are allowed",
+        new_timeline_ids.len(),
+        MAX_BRANCH_BATCH_SIZE
+    );
+    let mut unique_ids = HashSet::with_capacity(new_timeline_ids.len());
+    for timeline_id in new_timeline_ids {
+        ensure!(
+            unique_ids.insert(timeline_id),
+            "Timeline {timeline_id} is listed more than once"
+        );
+    }
+
+    let repo = tenant_mgr::get_repository_for_tenant(tenant_id)?;
+    let start_lsn = branch_start_lsn(
+        repo.as_ref(),
+        ancestor_timeline_id,
+        ancestor_start_lsn.unwrap_or(Lsn(0)),
+    )?;
+    let results = repo.branch_timelines(ancestor_timeline_id, new_timeline_ids, start_lsn)?;
+
+    let branches = new_timeline_ids
+        .iter()
+        .zip(results)
+        .map(|(&timeline_id, result)| {
+            let (created, error) = match result {
+                Ok(created) => (created, None),
+                Err(e) => (false, Some(format!("{e:#}"))),
+            };
+            if created {
+                compute_notify::notify(tenant_id, timeline_id, TimelineState::Ready);
+            }
+            BranchCreationResult {
+                timeline_id,
+                created,
+                error,
+            }
+        })
+        .collect();
+    Ok(BranchBatchInfo {
+        ancestor_timeline_id,
+        ancestor_lsn: start_lsn,
+        branches,
+    })
+}
+
+///
+/// Branch off a timeline that is not present locally, using the metadata of the
+/// remote timeline. Cold tenants may not have downloaded the timeline yet, the
+/// new branch becomes loadable once they do.
+///
+fn branch_remote_timeline(
+    repo: &RepositoryImpl,
+    tenant_id: ZTenantId,
+    ancestor_timeline_id: ZTimelineId,
+    new_timeline_id: ZTimelineId,
+    start_lsn: Lsn,
+) -> Result<LocalTimelineInfo> {
+    let ancestor_metadata = {
+        let index_accessor = repo.get_remote_index().blocking_read();
+        let remote_timeline = index_accessor
+            .timeline_entry(&ZTenantTimelineId::new(tenant_id, ancestor_timeline_id))
+            .with_context(|| {
+                format!("Timeline {ancestor_timeline_id} is found neither locally nor remotely")
+            })?;
+        ensure!(
+            !remote_timeline.archived,
+            "Cannot branch off the archived timeline {ancestor_timeline_id}, unarchive it first"
+        );
+        remote_timeline.metadata.clone()
+    };
+
+    let start_lsn = if start_lsn == Lsn(0) {
+        // The WAL of a remote timeline can't be waited for: branch at its end in the remote storage
+        let disk_consistent_lsn = ancestor_metadata.disk_consistent_lsn();
+        info!("branching at remote disk consistent LSN: {disk_consistent_lsn}");
+        disk_consistent_lsn
+    } else {
+        start_lsn.align()
+    };
+
+    let ancestor_ancestor_lsn = ancestor_metadata.ancestor_lsn();
+    if ancestor_ancestor_lsn > start_lsn {
+        bail!(
+            "invalid start lsn {} for ancestor timeline {}: less than timeline ancestor lsn {}",
+            start_lsn,
+            ancestor_timeline_id,
+            ancestor_ancestor_lsn,
+        );
+    }
+    repo.branch_remote_timeline(
+        ancestor_timeline_id,
+        new_timeline_id,
+        start_lsn,
+        &ancestor_metadata,
+    )?;
+
+    match repo.get_timeline(new_timeline_id) {
+        Some(RepositoryTimeline::Unloaded { metadata }) => {
+            Ok(LocalTimelineInfo::from_unloaded_timeline(&metadata))
+        }
+        _ => bail!("timeline {new_timeline_id} branched off a remote timeline is not registered"),
+    }
+}
+
+///
+/// Prepare a timeline for a compute that is about to connect to it: read the
+/// layer files needed to serve pages at 'lsn' (the last record LSN by default)
+/// into the OS page cache and reconstruct up to 'max_pages' pages of the system
+/// catalogs, which every compute reads on startup, into the page cache.
+///
+pub fn warm_up_timeline(
+    tenant_id: ZTenantId,
+    timeline_id: ZTimelineId,
+    lsn: Option<Lsn>,
+    max_pages: usize,
+) -> Result<TimelineWarmUpInfo> {
+    let started_at = Instant::now();
+    let timeline = tenant_mgr::get_local_timeline_with_load(tenant_id, timeline_id)?;
+
+    let last_record_lsn = timeline.tline.get_last_record_lsn();
+    let lsn = lsn.unwrap_or(last_record_lsn);
+    ensure!(
+        lsn <= last_record_lsn,
+        "Requested LSN {lsn} is beyond the last record LSN {last_record_lsn} of the timeline"
+    );
+    timeline.check_lsn_is_in_scope(lsn, &timeline.tline.get_latest_gc_cutoff_lsn())?;
+
+    let (layers_read, layer_bytes_read) = timeline.tline.warm_up_layers(lsn)?;
+    let pages_materialized = if max_pages > 0 {
+        materialize_catalog_pages(&timeline, lsn, max_pages)?
+    } else {
+        0
+    };
+
+    let info = TimelineWarmUpInfo {
+        lsn,
+        layers_read,
+        layer_bytes_read,
+        pages_materialized,
+        elapsed_ms: started_at.elapsed().as_millis(),
+    };
+    info!("warmed up timeline at {lsn}: {info:?}");
+    Ok(info)
+}
+
+///
+/// Write image layers of the timeline at 'lsn' (the last record LSN by default),
+/// for all relations and other keys in use, or only for the given relations.
+/// Reads at 'lsn' don't need any older layers of the covered keys afterwards,
+/// which bounds the restore time of that point and lets GC remove the layers
+/// once the GC horizon moves past it.
+///
+pub fn materialize_timeline(
+    tenant_id: ZTenantId,
+    timeline_id: ZTimelineId,
+    lsn: Option<Lsn>,
+    relations: Option<Vec<RelTag>>,
+) -> Result<TimelineMaterializeInfo> {
+    let started_at = Instant::now();
+    let timeline = tenant_mgr::get_local_timeline_with_load(tenant_id, timeline_id)?;
+
+    let last_record_lsn = timeline.tline.get_last_record_lsn();
+    let lsn = lsn.unwrap_or(last_record_lsn);
+    ensure!(
+        lsn <= last_record_lsn,
+        "Requested LSN {lsn} is beyond the last record LSN {last_record_lsn} of the timeline"
+    );
+    timeline.check_lsn_is_in_scope(lsn, &timeline.tline.get_latest_gc_cutoff_lsn())?;
+
+    let keyspaces = match relations {
+        None => vec![timeline.collect_keyspace(lsn)?],
+        Some(mut rels) => {
+            rels.sort_unstable();
+            rels.dedup();
+            timeline.collect_rel_keyspaces(&rels, lsn)?
+        }
+    };
+
+    let new_paths = timeline.tline.create_image_layers_at(&keyspaces, lsn)?;
+    let mut image_layer_bytes = 0;
+    for path in &new_paths {
+        image_layer_bytes += fs::metadata(path)
+            .with_context(|| format!("Failed to stat image layer '{}'", path.display()))?
+            .len();
+    }
+
+    let info = TimelineMaterializeInfo {
+        lsn,
+        image_layers_created: new_paths.len(),
+        image_layer_bytes,
+        elapsed_ms: started_at.elapsed().as_millis(),
+    };
+    info!("materialized timeline at {lsn}: {info:?}");
+    Ok(info)
+}
+
+///
+/// Block until the WAL of the timeline up to 'lsn' is durable: flushed to layer
+/// files on the local disk, and for the remote scope, uploaded to the remote
+/// storage as well. Flushes the in-memory layers right away instead of waiting
+/// for the checkpointer, which also schedules their upload.
+///
+/// Waiting for the WAL up to 'lsn' to arrive is bounded by `wait_lsn_timeout`,
+/// waiting for the uploads by 'timeout'.
+///
+pub fn wait_for_durability(
+    conf: &'static PageServerConf,
+    tenant_id: ZTenantId,
+    timeline_id: ZTimelineId,
+    lsn: Lsn,
+    scope: DurabilityScope,
+    remote_index: &RemoteIndex,
+    timeout: Duration,
+) -> Result<TimelineDurabilityInfo> {
+    let started_at = Instant::now();
+    if scope == DurabilityScope::Remote {
+        ensure!(
+            conf.remote_storage_config.is_some(),
+            "Cannot wait for remote durability without remote storage configured"
+        );
+    }
+    let timeline = tenant_mgr::get_local_timeline_with_load(tenant_id, timeline_id)?;
+
+    timeline.tline.wait_lsn(lsn)?;
+    if timeline.tline.get_disk_consistent_lsn() < lsn {
+        timeline.tline.checkpoint(CheckpointConfig::Flush)?;
+    }
+    let disk_consistent_lsn = timeline.tline.get_disk_consistent_lsn();
+    ensure!(
+        disk_consistent_lsn >= lsn,
+        "Disk consistent LSN {disk_consistent_lsn} is behind {lsn} after the flush"
+    );
+
+    let remote_consistent_lsn = match scope {
+        DurabilityScope::Local => None,
+        DurabilityScope::Remote => {
+            let sync_id = ZTenantTimelineId {
+                tenant_id,
+                timeline_id,
+            };
+            loop {
+                let remote_consistent_lsn = remote_index
+                    .blocking_read()
+                    .timeline_entry(&sync_id)
+                    .map(|remote_entry| remote_entry.remote_consistent_lsn());
+                if remote_consistent_lsn >= Some(lsn) {
+                    break remote_consistent_lsn;
+                }
+                ensure!(
+                    started_at.elapsed() < timeout,
+                    "Timed out waiting for LSN {lsn} to be uploaded, remote consistent LSN {remote_consistent_lsn:?}"
+                );
+                thread::sleep(REMOTE_DURABILITY_POLL_INTERVAL);
+            }
+        }
+    };
+
+    Ok(TimelineDurabilityInfo {
+        lsn,
+        disk_consistent_lsn,
+        remote_consistent_lsn,
+        elapsed_ms: started_at.elapsed().as_millis(),
+    })
+}
+
+///
+/// Get the fence LSN of the timeline: its last record LSN, once the WAL up to
+/// it is flushed to disk. All the WAL before the fence is ingested and durable,
+/// so a compute restarted at the fence sees every change of the previous one.
+/// With 'set_read_floor', the reads below the fence are rejected from now on.
+///
+pub fn fence_timeline(
+    tenant_id: ZTenantId,
+    timeline_id: ZTimelineId,
+    set_read_floor: bool,
+) -> Result<TimelineFenceInfo> {
+    let timeline = tenant_mgr::get_local_timeline_with_load(tenant_id, timeline_id)?;
+
+    let fence_lsn = timeline.tline.get_last_record_lsn();
+    if timeline.tline.get_disk_consistent_lsn() < fence_lsn {
+        timeline.tline.checkpoint(CheckpointConfig::Flush)?;
+    }
+    let disk_consistent_lsn = timeline.tline.get_disk_consistent_lsn();
+    ensure!(
+        disk_consistent_lsn >= fence_lsn,
+        "Disk consistent LSN {disk_consistent_lsn} is behind the fence {fence_lsn} after the flush"
+    );
+    if set_read_floor {
+        timeline.tline.set_read_floor_lsn(Some(fence_lsn))?;
+    }
+
+    Ok(TimelineFenceInfo {
+        fence_lsn,
+        disk_consistent_lsn,
+        read_floor_lsn: timeline.tline.get_read_floor_lsn(),
+    })
+}
+
+/// Set the read floor of the timeline, or clear it with `None`, see
+/// [`crate::layered_repository::LayeredTimeline::set_read_floor_lsn`].
+pub fn set_read_floor(
+    tenant_id: ZTenantId,
+    timeline_id: ZTimelineId,
+    lsn: Option<Lsn>,
+) -> Result<TimelineFenceInfo> {
+    let timeline = tenant_mgr::get_local_timeline_with_load(tenant_id, timeline_id)?;
+    timeline.tline.set_read_floor_lsn(lsn)?;
+    Ok(TimelineFenceInfo {
+        fence_lsn: timeline.tline.get_last_record_lsn(),
+        disk_consistent_lsn: timeline.tline.get_disk_consistent_lsn(),
+        read_floor_lsn: timeline.tline.get_read_floor_lsn(),
+    })
+}
+
+///
+/// Reconstruct the pages of the system catalogs of all databases, up to
+/// 'max_pages' in total, so that the page cache has them materialized.
+/// Returns the number of pages reconstructed.
+///
+fn materialize_catalog_pages<R: Repository>(
+    timeline: &DatadirTimeline<R>,
+    lsn: Lsn,
+    max_pages: usize,
+) -> Result<usize> {
+    let mut dbdirs = timeline.list_dbdirs(lsn)?.into_keys().collect::<Vec<_>>();
+    dbdirs.sort_unstable();
+
+    let mut materialized = 0;
+    for (spcnode, dbnode) in dbdirs {
+        // Catalogs keep the relfilenodes assigned by initdb, unless rewritten.
+        let mut catalogs = timeline
+            .list_rels(spcnode, dbnode, lsn)?
+            .into_iter()
+            .filter(|rel| {
+                rel.forknum == pg_constants::MAIN_FORKNUM
+                    && rel.relnode < pg_constants::FIRST_NORMAL_OBJECT_ID
+            })
+            .collect::<Vec<_>>();
+        catalogs.sort_unstable();
+
+        for rel in catalogs {
+            for blknum in 0..timeline.get_rel_size(rel, lsn)? {
+                if materialized >= max_pages {
+                    return Ok(materialized);
+                }
+                timeline.get_rel_page_at_lsn(rel, blknum, lsn)?;
+                materialized += 1;
+            }
+        }
+    }
+    Ok(materialized)
+}
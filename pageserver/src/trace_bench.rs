@@ -0,0 +1,239 @@
+//!
+//! Replay of recorded workloads, for benchmarking.
+//!
+//! Changes that are motivated by performance, to the layer map, the page cache
+//! or WAL redo, are best evaluated on real workloads, the same one before and
+//! after the change. [`run_trace_bench`] replays the WAL trace and the page trace
+//! recorded for a timeline (see [`crate::wal_trace`]) against a copy of its
+//! tenant:
+//!
+//! 1. The tenant directory of the page server that recorded the traces is copied
+//!    into a scratch directory, so that the original is not modified.
+//! 2. The timeline is branched at the start of the WAL trace, and the WAL trace
+//!    is ingested into the branch, with the checkpoints that the WAL receiver
+//!    would do.
+//! 3. The GetPage requests of the page trace are served from the branch, at the
+//!    LSNs they were served at originally, one after another.
+//!
+//! Both phases report their throughput and latency percentiles. The requests are
+//! replayed after the whole ingest rather than interleaved with it, so that the
+//! results don't depend on the timing of the run.
+//!
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{ensure, Context, Result};
+use tracing::*;
+use utils::zid::{ZTenantId, ZTimelineId};
+
+use crate::config::PageServerConf;
+use crate::layered_repository::LayeredRepository;
+use crate::repository::{Repository, Timeline, TimelineSyncStatusUpdate};
+use crate::storage_sync::index::RemoteIndex;
+use crate::wal_trace::{self, PageTraceReader, WalTraceReader};
+use crate::walredo::PostgresRedoManager;
+use crate::{CheckpointConfig, DatadirTimelineImpl};
+
+/// Throughput and latencies of one phase of a bench run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LatencySummary {
+    pub operations: u64,
+    pub elapsed: Duration,
+    pub p50: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl LatencySummary {
+    fn from_samples(mut samples: Vec<Duration>, elapsed: Duration) -> Self {
+        samples.sort_unstable();
+        let percentile = |p: usize| {
+            if samples.is_empty() {
+                Duration::ZERO
+            } else {
+                samples[(samples.len() * p / 100).min(samples.len() - 1)]
+            }
+        };
+        LatencySummary {
+            operations: samples.len() as u64,
+            elapsed,
+            p50: percentile(50),
+            p99: percentile(99),
+            max: samples.last().copied().unwrap_or_default(),
+        }
+    }
+
+    /// Operations per second.
+    pub fn throughput(&self) -> f64 {
+        if self.elapsed.is_zero() {
+            0.0
+        } else {
+            self.operations as f64 / self.elapsed.as_secs_f64()
+        }
+    }
+}
+
+impl fmt::Display for LatencySummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ops in {:.3}s, {:.0} ops/s, p50 {:?}, p99 {:?}, max {:?}",
+            self.operations,
+            self.elapsed.as_secs_f64(),
+            self.throughput(),
+            self.p50,
+            self.p99,
+            self.max
+        )
+    }
+}
+
+/// Result of a [`run_trace_bench`] run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TraceBenchReport {
+    /// Per ingested WAL record.
+    pub ingest: LatencySummary,
+    pub wal_bytes: u64,
+    /// Per GetPage request.
+    pub get_page: LatencySummary,
+}
+
+impl fmt::Display for TraceBenchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "ingest: {}, {:.1} MB/s",
+            self.ingest,
+            self.wal_bytes as f64 / 1_000_000.0 / self.ingest.elapsed.as_secs_f64().max(1e-9)
+        )?;
+        write!(f, "get_page: {}", self.get_page)
+    }
+}
+
+///
+/// Replay the traces of a timeline against a copy of its tenant.
+///
+/// 'conf' is the configuration of the bench run, its workdir is the scratch
+/// directory, and 'source_workdir' is the workdir of the page server that
+/// recorded the traces. The tenant must not exist in the scratch directory yet.
+///
+pub fn run_trace_bench(
+    conf: &'static PageServerConf,
+    source_workdir: &Path,
+    tenant_id: ZTenantId,
+    timeline_id: ZTimelineId,
+    wal_trace_path: &Path,
+    page_trace_path: Option<&Path>,
+) -> Result<TraceBenchReport> {
+    let source_tenant_path =
+        source_workdir.join(conf.tenant_path(&tenant_id).strip_prefix(&conf.workdir)?);
+    let tenant_path = conf.tenant_path(&tenant_id);
+    ensure!(
+        !tenant_path.exists(),
+        "tenant directory '{}' already exists in the scratch directory",
+        tenant_path.display()
+    );
+    copy_dir(&source_tenant_path, &tenant_path).with_context(|| {
+        format!(
+            "Failed to copy tenant directory '{}'",
+            source_tenant_path.display()
+        )
+    })?;
+
+    let repo = LayeredRepository::new(
+        conf,
+        LayeredRepository::load_tenant_config(conf, tenant_id)?,
+        Arc::new(PostgresRedoManager::new(conf, tenant_id)),
+        tenant_id,
+        RemoteIndex::empty(),
+        false,
+    );
+    for entry in fs::read_dir(conf.timelines_path(&tenant_id))? {
+        let entry = entry?;
+        if let Some(id) = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse::<ZTimelineId>().ok())
+        {
+            repo.apply_timeline_remote_sync_status_update(
+                id,
+                TimelineSyncStatusUpdate::Downloaded,
+            )?;
+        }
+    }
+
+    let start_lsn = WalTraceReader::open(wal_trace_path)?.start_lsn();
+    let bench_timeline_id = ZTimelineId::generate();
+    repo.branch_timeline(timeline_id, bench_timeline_id, start_lsn)
+        .with_context(|| format!("Failed to branch timeline {timeline_id} at {start_lsn}"))?;
+    let timeline = DatadirTimelineImpl::new(
+        repo.get_timeline_load(bench_timeline_id)?,
+        repo.get_checkpoint_distance() / 10,
+    );
+    info!("replaying into timeline {bench_timeline_id}, branched at {start_lsn}");
+
+    let mut report = TraceBenchReport::default();
+
+    let mut samples = Vec::new();
+    let started_at = Instant::now();
+    wal_trace::replay_wal_trace_observed(wal_trace_path, &timeline, |len, latency| {
+        report.wal_bytes += len as u64;
+        samples.push(latency);
+    })?;
+    report.ingest = LatencySummary::from_samples(samples, started_at.elapsed());
+
+    if let Some(page_trace_path) = page_trace_path {
+        let mut reader = PageTraceReader::open(page_trace_path)?;
+        let mut samples = Vec::new();
+        let started_at = Instant::now();
+        while let Some(request) = reader.next_request()? {
+            let request_started_at = Instant::now();
+            timeline
+                .get_rel_page_at_lsn(request.rel, request.blkno, request.lsn)
+                .with_context(|| format!("Failed to replay GetPage request {request:?}"))?;
+            samples.push(request_started_at.elapsed());
+        }
+        report.get_page = LatencySummary::from_samples(samples, started_at.elapsed());
+    }
+
+    // Leave the written layers on disk, they may be of interest
+    timeline.tline.checkpoint(CheckpointConfig::Flush)?;
+    Ok(report)
+}
+
+fn copy_dir(src: &Path, dst: &Path) -> Result<()> {
+    fs::create_dir_all(dst)?;
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let dst = dst.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &dst)?;
+        } else {
+            fs::copy(entry.path(), &dst)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn latency_summary() {
+        let samples = (1..=200).map(Duration::from_micros).collect::<Vec<_>>();
+        let summary = LatencySummary::from_samples(samples, Duration::from_millis(100));
+        assert_eq!(summary.operations, 200);
+        assert_eq!(summary.p50, Duration::from_micros(101));
+        assert_eq!(summary.p99, Duration::from_micros(199));
+        assert_eq!(summary.max, Duration::from_micros(200));
+        assert_eq!(summary.throughput(), 2000.0);
+
+        let empty = LatencySummary::from_samples(Vec::new(), Duration::ZERO);
+        assert_eq!(empty, LatencySummary::default());
+        assert_eq!(empty.throughput(), 0.0);
+    }
+}
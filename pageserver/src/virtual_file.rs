@@ -10,14 +10,19 @@
 //! This is similar to PostgreSQL's virtual file descriptor facility in
 //! src/backend/storage/file/fd.c
 //!
+use crate::metric_labels;
 use lazy_static::lazy_static;
+use nix::fcntl::{fallocate, FallocateFlags};
 use once_cell::sync::OnceCell;
 use std::fs::{File, OpenOptions};
 use std::io::{Error, ErrorKind, Read, Seek, SeekFrom, Write};
 use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{RwLock, RwLockWriteGuard};
+use utils::zid::{ZTenantId, ZTimelineId};
 
 use metrics::{register_histogram_vec, register_int_gauge_vec, HistogramVec, IntGaugeVec};
 
@@ -229,15 +234,22 @@ impl VirtualFile {
     ) -> Result<VirtualFile, std::io::Error> {
         let path_str = path.to_string_lossy();
         let parts = path_str.split('/').collect::<Vec<&str>>();
-        let tenantid;
-        let timelineid;
-        if parts.len() > 5 && parts[parts.len() - 5] == "tenants" {
-            tenantid = parts[parts.len() - 4].to_string();
-            timelineid = parts[parts.len() - 2].to_string();
+        let (tenantid, timelineid) = if parts.len() > 5 && parts[parts.len() - 5] == "tenants" {
+            match (
+                ZTenantId::from_str(parts[parts.len() - 4]),
+                ZTimelineId::from_str(parts[parts.len() - 2]),
+            ) {
+                (Ok(tenant_id), Ok(timeline_id)) => {
+                    metric_labels::timeline_labels(&tenant_id, &timeline_id)
+                }
+                _ => (
+                    parts[parts.len() - 4].to_string(),
+                    parts[parts.len() - 2].to_string(),
+                ),
+            }
         } else {
-            tenantid = "*".to_string();
-            timelineid = "*".to_string();
-        }
+            ("*".to_string(), "*".to_string())
+        };
         let (handle, mut slot_guard) = get_open_files().find_victim_slot();
         let file = STORAGE_IO_TIME
             .with_label_values(&["open", &tenantid, &timelineid])
@@ -272,6 +284,44 @@ impl VirtualFile {
         self.with_file("fsync", |file| file.sync_all())?
     }
 
+    /// Call File::sync_data() on the underlying File.
+    pub fn sync_data(&self) -> Result<(), Error> {
+        self.with_file("fdatasync", |file| file.sync_data())?
+    }
+
+    /// Call File::metadata() on the underlying File.
+    pub fn metadata(&self) -> Result<std::fs::Metadata, Error> {
+        self.with_file("metadata", |file| file.metadata())?
+    }
+
+    /// Deallocate the disk space of the given range, keeping the file size.
+    /// Reads of the range return zeros afterwards.
+    pub fn punch_hole(&self, offset: u64, len: u64) -> Result<(), Error> {
+        self.with_file("punch_hole", |file| {
+            fallocate(
+                file.as_raw_fd(),
+                FallocateFlags::FALLOC_FL_PUNCH_HOLE | FallocateFlags::FALLOC_FL_KEEP_SIZE,
+                offset as i64,
+                len as i64,
+            )
+            .map_err(Error::from)
+        })?
+    }
+
+    /// Allocate disk space for the first 'len' bytes of the file in advance,
+    /// keeping the file size.
+    pub fn preallocate(&self, len: u64) -> Result<(), Error> {
+        self.with_file("preallocate", |file| {
+            fallocate(
+                file.as_raw_fd(),
+                FallocateFlags::FALLOC_FL_KEEP_SIZE,
+                0,
+                len as i64,
+            )
+            .map_err(Error::from)
+        })?
+    }
+
     /// Helper function that looks up the underlying File for this VirtualFile,
     /// opening it and evicting some other File if necessary. It calls 'func'
     /// with the physical File.
@@ -0,0 +1,270 @@
+//!
+//! Protection against WAL sources that send WAL the page server already has.
+//!
+//! The WAL receiver asks the safekeeper for the WAL from the last record LSN
+//! of the timeline, and feeds whatever comes back to the WAL decoder, which
+//! assumes that each message continues where the previous one ended. A
+//! misbehaving safekeeper, e.g. one that lost its WAL or that is on another
+//! history after a split brain, could send WAL that starts earlier instead,
+//! and the records would be applied twice, or different records applied at
+//! LSNs that were already ingested.
+//!
+//! [`WalFence`] checks the position of every message. WAL that starts before
+//! the disk consistent LSN, or after a gap, is refused. WAL that overlaps the
+//! WAL received so far is decoded, and its records are compared with the
+//! checksums of the records ingested at the same LSNs: if they are the same,
+//! the overlap is skipped, otherwise the ingest is refused with a
+//! [`WalFencingError`].
+//!
+use std::collections::VecDeque;
+
+use lazy_static::lazy_static;
+use metrics::{register_int_counter, IntCounter};
+use postgres_ffi::waldecoder::WalStreamDecoder;
+use postgres_ffi::xlog_utils::XLOG_SIZE_OF_XLOG_RECORD;
+use postgres_ffi::XLogRecord;
+use utils::lsn::Lsn;
+
+/// Number of the latest ingested records whose checksums are kept.
+const MAX_RECENT_RECORDS: usize = 100_000;
+
+lazy_static! {
+    static ref WAL_FENCING_ERRORS: IntCounter = register_int_counter!(
+        "pageserver_wal_fencing_errors_total",
+        "Number of WAL receiver connections stopped because the WAL source resent WAL inconsistently"
+    )
+    .expect("failed to define a metric");
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum WalFencingError {
+    #[error(
+        "WAL source sent WAL from {start}, before the disk consistent LSN {disk_consistent_lsn}"
+    )]
+    BeforeDiskConsistentLsn {
+        start: Lsn,
+        disk_consistent_lsn: Lsn,
+    },
+    #[error(
+        "WAL source sent WAL from {start}, but the WAL received so far ends at {received_up_to}"
+    )]
+    Gap { start: Lsn, received_up_to: Lsn },
+    #[error("WAL source resent WAL from {start}, the oldest record that can be compared ends at {oldest_known}")]
+    Unverifiable { start: Lsn, oldest_known: Lsn },
+    #[error("WAL record ending at {lsn} doesn't match the WAL already ingested: {reason}")]
+    RecordMismatch { lsn: Lsn, reason: String },
+}
+
+pub struct WalFence {
+    /// The LSN right after the last byte received.
+    received_up_to: Lsn,
+    /// End LSN and checksum of the latest ingested records, oldest first.
+    recent_records: VecDeque<(Lsn, u32)>,
+}
+
+impl WalFence {
+    /// 'startpoint' is the LSN that the streaming was requested from.
+    pub fn new(startpoint: Lsn) -> Self {
+        WalFence {
+            received_up_to: startpoint,
+            recent_records: VecDeque::new(),
+        }
+    }
+
+    ///
+    /// Check a WAL message of the source, with 'data' starting at 'start'.
+    /// Returns the part of 'data' that wasn't received before, to be fed to
+    /// the decoder.
+    ///
+    pub fn check<'a>(
+        &mut self,
+        start: Lsn,
+        data: &'a [u8],
+        disk_consistent_lsn: Lsn,
+    ) -> Result<&'a [u8], WalFencingError> {
+        let result = self.check_overlap(start, data, disk_consistent_lsn);
+        if result.is_err() {
+            WAL_FENCING_ERRORS.inc();
+        }
+        result
+    }
+
+    fn check_overlap<'a>(
+        &mut self,
+        start: Lsn,
+        data: &'a [u8],
+        disk_consistent_lsn: Lsn,
+    ) -> Result<&'a [u8], WalFencingError> {
+        if start == self.received_up_to {
+            self.received_up_to += data.len() as u64;
+            return Ok(data);
+        }
+        if start > self.received_up_to {
+            return Err(WalFencingError::Gap {
+                start,
+                received_up_to: self.received_up_to,
+            });
+        }
+        if start < disk_consistent_lsn {
+            return Err(WalFencingError::BeforeDiskConsistentLsn {
+                start,
+                disk_consistent_lsn,
+            });
+        }
+
+        // The source resends WAL we have. Compare the records that are complete
+        // in the overlap with the ones ingested.
+        let overlap_len = ((self.received_up_to.0 - start.0) as usize).min(data.len());
+        let mut decoder = WalStreamDecoder::new(start);
+        decoder.feed_bytes(&data[..overlap_len]);
+        loop {
+            let (lsn, recdata) = match decoder.poll_decode() {
+                Ok(Some(record)) => record,
+                Ok(None) => break,
+                Err(e) => {
+                    return Err(WalFencingError::RecordMismatch {
+                        lsn: start,
+                        reason: e.to_string(),
+                    })
+                }
+            };
+            let crc = XLogRecord::from_slice(&recdata[0..XLOG_SIZE_OF_XLOG_RECORD])
+                .map_err(|e| WalFencingError::RecordMismatch {
+                    lsn,
+                    reason: e.to_string(),
+                })?
+                .xl_crc;
+            self.compare_record(start, lsn, crc)?;
+        }
+
+        if overlap_len == data.len() {
+            return Ok(&[]);
+        }
+        self.received_up_to = start + data.len() as u64;
+        Ok(&data[overlap_len..])
+    }
+
+    fn compare_record(&self, start: Lsn, lsn: Lsn, crc: u32) -> Result<(), WalFencingError> {
+        let oldest_known = self
+            .recent_records
+            .front()
+            .map_or(self.received_up_to, |(oldest_lsn, _)| *oldest_lsn);
+        if lsn < oldest_known || self.recent_records.is_empty() {
+            return Err(WalFencingError::Unverifiable {
+                start,
+                oldest_known,
+            });
+        }
+
+        match self
+            .recent_records
+            .binary_search_by_key(&lsn, |(record_lsn, _)| *record_lsn)
+        {
+            Ok(i) if self.recent_records[i].1 == crc => Ok(()),
+            Ok(i) => Err(WalFencingError::RecordMismatch {
+                lsn,
+                reason: format!(
+                    "checksum {:08X}, ingested {:08X}",
+                    crc, self.recent_records[i].1
+                ),
+            }),
+            Err(_) => Err(WalFencingError::RecordMismatch {
+                lsn,
+                reason: "no record ingested ends there".to_string(),
+            }),
+        }
+    }
+
+    /// Remember a record that was ingested, 'lsn' is its end as returned by the decoder.
+    pub fn record_ingested(&mut self, lsn: Lsn, recdata: &[u8]) {
+        // The decoder has validated the record, it has a header
+        if let Ok(record) = XLogRecord::from_slice(&recdata[0..XLOG_SIZE_OF_XLOG_RECORD]) {
+            if self.recent_records.len() == MAX_RECENT_RECORDS {
+                self.recent_records.pop_front();
+            }
+            self.recent_records.push_back((lsn, record.xl_crc));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crc32c::crc32c_append;
+    use postgres_ffi::xlog_utils::XLOG_RECORD_CRC_OFFS;
+
+    /// A WAL record with the given payload, padded to 8 bytes.
+    fn record(payload: &[u8]) -> Vec<u8> {
+        let mut rec = vec![0u8; XLOG_SIZE_OF_XLOG_RECORD];
+        rec[0..4]
+            .copy_from_slice(&((XLOG_SIZE_OF_XLOG_RECORD + payload.len()) as u32).to_le_bytes());
+        rec.extend_from_slice(payload);
+        let mut crc = 0;
+        crc = crc32c_append(crc, &rec[XLOG_RECORD_CRC_OFFS + 4..]);
+        crc = crc32c_append(crc, &rec[0..XLOG_RECORD_CRC_OFFS]);
+        rec[XLOG_RECORD_CRC_OFFS..XLOG_RECORD_CRC_OFFS + 4].copy_from_slice(&crc.to_le_bytes());
+        rec.resize((rec.len() + 7) / 8 * 8, 0);
+        rec
+    }
+
+    /// Decode and ingest 'data', as the WAL receiver does.
+    fn ingest(fence: &mut WalFence, decoder: &mut WalStreamDecoder, data: &[u8]) {
+        decoder.feed_bytes(data);
+        while let Some((lsn, recdata)) = decoder.poll_decode().unwrap() {
+            fence.record_ingested(lsn, &recdata);
+        }
+    }
+
+    #[test]
+    fn wal_fence_overlaps() {
+        // In the middle of a page, so that there are no page headers
+        let startpoint = Lsn(0x0100_0100);
+        let mut fence = WalFence::new(startpoint);
+        let mut decoder = WalStreamDecoder::new(startpoint);
+
+        let first = record(b"first");
+        let second = record(b"second");
+        let third = record(b"third");
+        let mut wal = first.clone();
+        wal.extend_from_slice(&second);
+
+        let new_data = fence.check(startpoint, &wal, startpoint).unwrap();
+        assert_eq!(new_data.len(), wal.len());
+        ingest(&mut fence, &mut decoder, new_data);
+
+        // The same WAL again, and some more: only the new part is ingested
+        let mut resent = wal.clone();
+        resent.extend_from_slice(&third);
+        let new_data = fence.check(startpoint, &resent, startpoint).unwrap();
+        assert_eq!(new_data, &third[..]);
+        ingest(&mut fence, &mut decoder, new_data);
+
+        // A different second record
+        let after_first = startpoint + first.len() as u64;
+        let other = record(b"other!");
+        assert!(matches!(
+            fence.check(after_first, &other, startpoint),
+            Err(WalFencingError::RecordMismatch { .. })
+        ));
+
+        // Before the disk consistent LSN
+        assert!(matches!(
+            fence.check(startpoint, &wal, after_first),
+            Err(WalFencingError::BeforeDiskConsistentLsn { .. })
+        ));
+
+        // After a gap
+        let end = startpoint + (wal.len() + third.len()) as u64;
+        assert!(matches!(
+            fence.check(end + 8u64, &third, startpoint),
+            Err(WalFencingError::Gap { .. })
+        ));
+
+        // Older than anything received in this connection
+        let mut fence = WalFence::new(after_first);
+        assert!(matches!(
+            fence.check(startpoint, &wal, startpoint),
+            Err(WalFencingError::Unverifiable { .. })
+        ));
+    }
+}
@@ -0,0 +1,626 @@
+//!
+//! Recording and replaying of decoded WAL.
+//!
+//! Ingest bugs are often hard to reproduce: they depend on the exact sequence
+//! of WAL records a timeline received, and on when checkpoints and compactions
+//! happened in between. To capture that sequence, the WAL receiver can record
+//! the records it decodes for a timeline into a trace file, see [`start_recording`].
+//! A trace can be replayed into another timeline with [`replay_wal_trace`], in
+//! tests or when benchmarking checkpoint and compaction settings on real WAL.
+//!
+//! A trace file starts with a header of the magic bytes and the LSN that the first
+//! record starts at, followed by the records, each as its end LSN, its length and
+//! the record bytes, integers in big-endian. A record torn by a crash at the end
+//! of the file is ignored on replay.
+//!
+//! While the WAL of a timeline is recorded, the GetPage requests served from it
+//! are recorded too, into a page trace: the LSN that each request was served at,
+//! its relation and block number. With both traces, [`crate::trace_bench`] can
+//! replay the workload of a timeline. A page trace starts with its own magic
+//! bytes, followed by the requests in the same big-endian encoding.
+//!
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, ensure, Context, Result};
+use bytes::Bytes;
+use lazy_static::lazy_static;
+use tracing::*;
+use utils::lsn::Lsn;
+use utils::zid::{ZTenantId, ZTimelineId};
+
+use crate::config::PageServerConf;
+use crate::reltag::RelTag;
+use crate::repository::Timeline;
+use crate::walingest::WalIngest;
+use crate::DatadirTimelineImpl;
+
+const WAL_TRACE_MAGIC: &[u8; 8] = b"ZWALTRC1";
+const PAGE_TRACE_MAGIC: &[u8; 8] = b"ZPAGTRC1";
+
+/// Directory in the pageserver workdir that the traces are written to.
+pub const WAL_TRACES_DIR: &str = "wal_traces";
+
+lazy_static! {
+    /// Timelines whose WAL receivers should record the WAL they ingest.
+    static ref RECORDED_TIMELINES: RwLock<HashSet<(ZTenantId, ZTimelineId)>> =
+        RwLock::new(HashSet::new());
+
+    /// Page traces of the recorded timelines, created at their first GetPage request.
+    static ref PAGE_TRACES: Mutex<HashMap<(ZTenantId, ZTimelineId), PageTraceRecorder>> =
+        Mutex::new(HashMap::new());
+}
+
+/// Make the WAL receiver of the timeline record the WAL it ingests, from the next WAL it receives.
+pub fn start_recording(tenant_id: ZTenantId, timeline_id: ZTimelineId) {
+    info!("starting to record the WAL of timeline {timeline_id} of tenant {tenant_id}");
+    RECORDED_TIMELINES
+        .write()
+        .unwrap()
+        .insert((tenant_id, timeline_id));
+}
+
+pub fn stop_recording(tenant_id: ZTenantId, timeline_id: ZTimelineId) {
+    info!("stopping to record the WAL of timeline {timeline_id} of tenant {tenant_id}");
+    RECORDED_TIMELINES
+        .write()
+        .unwrap()
+        .remove(&(tenant_id, timeline_id));
+
+    let page_trace = PAGE_TRACES
+        .lock()
+        .unwrap()
+        .remove(&(tenant_id, timeline_id));
+    if let Some(mut page_trace) = page_trace {
+        match page_trace.flush() {
+            Ok(()) => info!("finished page trace '{}'", page_trace.path.display()),
+            Err(e) => error!("Failed to finish page trace: {e:?}"),
+        }
+    }
+}
+
+pub fn is_recording(tenant_id: ZTenantId, timeline_id: ZTimelineId) -> bool {
+    RECORDED_TIMELINES
+        .read()
+        .unwrap()
+        .contains(&(tenant_id, timeline_id))
+}
+
+/// Path of the trace of the timeline's WAL that starts at 'start_lsn'.
+pub fn wal_trace_path(
+    conf: &PageServerConf,
+    tenant_id: ZTenantId,
+    timeline_id: ZTimelineId,
+    start_lsn: Lsn,
+) -> PathBuf {
+    conf.workdir.join(WAL_TRACES_DIR).join(format!(
+        "{tenant_id}-{timeline_id}-{:016X}.trace",
+        u64::from(start_lsn)
+    ))
+}
+
+///
+/// Writes decoded WAL records into a trace file.
+///
+pub struct WalTraceRecorder {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    last_lsn: Lsn,
+}
+
+impl WalTraceRecorder {
+    /// Create a new trace file, for records that start at 'start_lsn'.
+    pub fn create(path: &Path, start_lsn: Lsn) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).with_context(|| {
+                format!(
+                    "Failed to create WAL trace directory '{}'",
+                    parent.display()
+                )
+            })?;
+        }
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .with_context(|| format!("Failed to create WAL trace file '{}'", path.display()))?;
+
+        let mut writer = BufWriter::new(file);
+        writer.write_all(WAL_TRACE_MAGIC)?;
+        writer.write_all(&u64::from(start_lsn).to_be_bytes())?;
+        Ok(WalTraceRecorder {
+            path: path.to_owned(),
+            writer,
+            last_lsn: start_lsn,
+        })
+    }
+
+    /// Append a decoded record, that ends at 'lsn'.
+    pub fn record(&mut self, lsn: Lsn, recdata: &[u8]) -> Result<()> {
+        ensure!(
+            lsn > self.last_lsn,
+            "WAL record at {lsn} does not follow the previously recorded one at {}",
+            self.last_lsn
+        );
+        self.writer.write_all(&u64::from(lsn).to_be_bytes())?;
+        self.writer
+            .write_all(&(recdata.len() as u32).to_be_bytes())?;
+        self.writer.write_all(recdata)?;
+        self.last_lsn = lsn;
+        Ok(())
+    }
+
+    /// Flush the records written so far into the file.
+    pub fn flush(&mut self) -> Result<()> {
+        self.writer
+            .flush()
+            .with_context(|| format!("Failed to flush WAL trace '{}'", self.path.display()))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+///
+/// Reads the records of a trace file, in order.
+///
+pub struct WalTraceReader<R: Read> {
+    reader: R,
+    start_lsn: Lsn,
+}
+
+impl WalTraceReader<BufReader<File>> {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = File::open(path)
+            .with_context(|| format!("Failed to open WAL trace file '{}'", path.display()))?;
+        Self::new(BufReader::new(file))
+    }
+}
+
+impl<R: Read> WalTraceReader<R> {
+    pub fn new(mut reader: R) -> Result<Self> {
+        let mut magic = [0u8; 8];
+        reader
+            .read_exact(&mut magic)
+            .context("Failed to read WAL trace header")?;
+        ensure!(&magic == WAL_TRACE_MAGIC, "Not a WAL trace file");
+        let start_lsn = Lsn(read_u64(&mut reader).context("Failed to read WAL trace header")?);
+        Ok(WalTraceReader { reader, start_lsn })
+    }
+
+    /// LSN that the first record starts at.
+    pub fn start_lsn(&self) -> Lsn {
+        self.start_lsn
+    }
+
+    /// Read the next record and its end LSN, None at the end of the trace.
+    pub fn next_record(&mut self) -> Result<Option<(Lsn, Bytes)>> {
+        let lsn = match read_u64(&mut self.reader) {
+            Ok(lsn) => Lsn(lsn),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e).context("Failed to read WAL trace record"),
+        };
+
+        let mut len = [0u8; 4];
+        let mut recdata = Vec::new();
+        let torn = match self.reader.read_exact(&mut len) {
+            Ok(()) => {
+                recdata.resize(u32::from_be_bytes(len) as usize, 0);
+                match self.reader.read_exact(&mut recdata) {
+                    Ok(()) => false,
+                    Err(e) if e.kind() == ErrorKind::UnexpectedEof => true,
+                    Err(e) => return Err(e).context("Failed to read WAL trace record"),
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => true,
+            Err(e) => return Err(e).context("Failed to read WAL trace record"),
+        };
+        if torn {
+            warn!("ignoring torn WAL record at {lsn} at the end of the trace");
+            return Ok(None);
+        }
+        Ok(Some((lsn, Bytes::from(recdata))))
+    }
+}
+
+///
+/// Records the WAL that a WAL receiver ingests, while recording is requested
+/// for its timeline. Recording errors never fail the ingest, they stop the
+/// recording instead.
+///
+pub struct WalReceiverRecorder {
+    conf: &'static PageServerConf,
+    tenant_id: ZTenantId,
+    timeline_id: ZTimelineId,
+    recorder: Option<WalTraceRecorder>,
+}
+
+impl WalReceiverRecorder {
+    pub fn new(
+        conf: &'static PageServerConf,
+        tenant_id: ZTenantId,
+        timeline_id: ZTimelineId,
+    ) -> Self {
+        WalReceiverRecorder {
+            conf,
+            tenant_id,
+            timeline_id,
+            recorder: None,
+        }
+    }
+
+    /// Start or finish the trace, if requested since the last call. 'last_lsn'
+    /// is the end of the last record ingested.
+    pub fn update(&mut self, last_lsn: Lsn) {
+        let recording = is_recording(self.tenant_id, self.timeline_id);
+        if recording && self.recorder.is_none() {
+            let path = wal_trace_path(self.conf, self.tenant_id, self.timeline_id, last_lsn);
+            match WalTraceRecorder::create(&path, last_lsn) {
+                Ok(recorder) => {
+                    info!("recording WAL into '{}'", path.display());
+                    self.recorder = Some(recorder);
+                }
+                Err(e) => self.fail(e),
+            }
+        } else if !recording {
+            if let Some(mut recorder) = self.recorder.take() {
+                match recorder.flush() {
+                    Ok(()) => info!("finished WAL trace '{}'", recorder.path().display()),
+                    Err(e) => self.fail(e),
+                }
+            }
+        }
+    }
+
+    pub fn record(&mut self, lsn: Lsn, recdata: &[u8]) {
+        if let Some(recorder) = &mut self.recorder {
+            if let Err(e) = recorder.record(lsn, recdata) {
+                self.fail(e);
+            }
+        }
+    }
+
+    pub fn flush(&mut self) {
+        if let Some(recorder) = &mut self.recorder {
+            if let Err(e) = recorder.flush() {
+                self.fail(e);
+            }
+        }
+    }
+
+    fn fail(&mut self, e: anyhow::Error) {
+        error!("Failed to record WAL, stopping the recording: {e:?}");
+        self.recorder = None;
+        stop_recording(self.tenant_id, self.timeline_id);
+    }
+}
+
+/// Path of the trace of the timeline's GetPage requests, starting with one at 'start_lsn'.
+pub fn page_trace_path(
+    conf: &PageServerConf,
+    tenant_id: ZTenantId,
+    timeline_id: ZTimelineId,
+    start_lsn: Lsn,
+) -> PathBuf {
+    conf.workdir.join(WAL_TRACES_DIR).join(format!(
+        "{tenant_id}-{timeline_id}-{:016X}.pages.trace",
+        u64::from(start_lsn)
+    ))
+}
+
+///
+/// Record a GetPage request served at 'lsn', if the WAL of the timeline is
+/// being recorded. Like the WAL recording, a failure stops the recording
+/// instead of failing the request.
+///
+pub fn record_page_request(
+    conf: &PageServerConf,
+    tenant_id: ZTenantId,
+    timeline_id: ZTimelineId,
+    request: &PageRequest,
+) {
+    if !is_recording(tenant_id, timeline_id) {
+        return;
+    }
+
+    let mut page_traces = PAGE_TRACES.lock().unwrap();
+    let result = match page_traces.entry((tenant_id, timeline_id)) {
+        Entry::Occupied(mut entry) => entry.get_mut().record(request),
+        Entry::Vacant(entry) => {
+            let path = page_trace_path(conf, tenant_id, timeline_id, request.lsn);
+            PageTraceRecorder::create(&path).and_then(|recorder| {
+                info!("recording GetPage requests into '{}'", path.display());
+                entry.insert(recorder).record(request)
+            })
+        }
+    };
+    if let Err(e) = result {
+        error!("Failed to record GetPage request, stopping the recording: {e:?}");
+        page_traces.remove(&(tenant_id, timeline_id));
+        drop(page_traces);
+        stop_recording(tenant_id, timeline_id);
+    }
+}
+
+/// A GetPage request of a page trace.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageRequest {
+    /// The LSN that the request was served at.
+    pub lsn: Lsn,
+    pub rel: RelTag,
+    pub blkno: u32,
+}
+
+///
+/// Writes GetPage requests into a page trace file.
+///
+pub struct PageTraceRecorder {
+    path: PathBuf,
+    writer: BufWriter<File>,
+}
+
+impl PageTraceRecorder {
+    pub fn create(path: &Path) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).with_context(|| {
+                format!(
+                    "Failed to create page trace directory '{}'",
+                    parent.display()
+                )
+            })?;
+        }
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .with_context(|| format!("Failed to create page trace file '{}'", path.display()))?;
+
+        let mut writer = BufWriter::new(file);
+        writer.write_all(PAGE_TRACE_MAGIC)?;
+        Ok(PageTraceRecorder {
+            path: path.to_owned(),
+            writer,
+        })
+    }
+
+    pub fn record(&mut self, request: &PageRequest) -> Result<()> {
+        let mut buf = [0u8; 8 + 13 + 4];
+        buf[0..8].copy_from_slice(&u64::from(request.lsn).to_be_bytes());
+        buf[8..12].copy_from_slice(&request.rel.spcnode.to_be_bytes());
+        buf[12..16].copy_from_slice(&request.rel.dbnode.to_be_bytes());
+        buf[16..20].copy_from_slice(&request.rel.relnode.to_be_bytes());
+        buf[20] = request.rel.forknum;
+        buf[21..25].copy_from_slice(&request.blkno.to_be_bytes());
+        self.writer.write_all(&buf)?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.writer
+            .flush()
+            .with_context(|| format!("Failed to flush page trace '{}'", self.path.display()))
+    }
+}
+
+///
+/// Reads the requests of a page trace file, in order.
+///
+pub struct PageTraceReader<R: Read> {
+    reader: R,
+}
+
+impl PageTraceReader<BufReader<File>> {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = File::open(path)
+            .with_context(|| format!("Failed to open page trace file '{}'", path.display()))?;
+        Self::new(BufReader::new(file))
+    }
+}
+
+impl<R: Read> PageTraceReader<R> {
+    pub fn new(mut reader: R) -> Result<Self> {
+        let mut magic = [0u8; 8];
+        reader
+            .read_exact(&mut magic)
+            .context("Failed to read page trace header")?;
+        ensure!(&magic == PAGE_TRACE_MAGIC, "Not a page trace file");
+        Ok(PageTraceReader { reader })
+    }
+
+    /// Read the next request, None at the end of the trace.
+    pub fn next_request(&mut self) -> Result<Option<PageRequest>> {
+        let mut buf = [0u8; 8 + 13 + 4];
+        let mut filled = 0;
+        while filled < buf.len() {
+            match self.reader.read(&mut buf[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e).context("Failed to read page trace request"),
+            }
+        }
+        if filled < buf.len() {
+            if filled > 0 {
+                warn!("ignoring torn GetPage request at the end of the trace");
+            }
+            return Ok(None);
+        }
+
+        let u32_at = |pos: usize| u32::from_be_bytes(buf[pos..pos + 4].try_into().unwrap());
+        Ok(Some(PageRequest {
+            lsn: Lsn(u64::from_be_bytes(buf[0..8].try_into().unwrap())),
+            rel: RelTag {
+                spcnode: u32_at(8),
+                dbnode: u32_at(12),
+                relnode: u32_at(16),
+                forknum: buf[20],
+            },
+            blkno: u32_at(21),
+        }))
+    }
+}
+
+fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+/// Result of a [`replay_wal_trace`] run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WalTraceReplay {
+    pub records: u64,
+    pub start_lsn: Lsn,
+    pub end_lsn: Lsn,
+}
+
+///
+/// Feed the records of a trace into the timeline, through the same ingest path
+/// as the WAL receiver, checkpointing the timeline as the WAL receiver would.
+///
+/// The trace must continue the WAL of the timeline: it has to start at the end
+/// of the timeline's last record.
+///
+pub fn replay_wal_trace(path: &Path, timeline: &DatadirTimelineImpl) -> Result<WalTraceReplay> {
+    replay_wal_trace_observed(path, timeline, |_, _| {})
+}
+
+///
+/// Like [`replay_wal_trace`], calling 'observe' with the length of each record
+/// and the time it took to ingest it.
+///
+pub fn replay_wal_trace_observed(
+    path: &Path,
+    timeline: &DatadirTimelineImpl,
+    mut observe: impl FnMut(usize, Duration),
+) -> Result<WalTraceReplay> {
+    let mut reader = WalTraceReader::open(path)?;
+    let start_lsn = reader.start_lsn();
+
+    let last_record_lsn = timeline.tline.get_last_record_lsn();
+    let startpoint = last_record_lsn + last_record_lsn.calc_padding(8u32);
+    if start_lsn != last_record_lsn && start_lsn != startpoint {
+        bail!(
+            "WAL trace '{}' starts at {start_lsn}, but the timeline's WAL ends at {last_record_lsn}",
+            path.display()
+        );
+    }
+
+    let mut walingest = WalIngest::new(timeline, start_lsn)?;
+    let mut replay = WalTraceReplay {
+        records: 0,
+        start_lsn,
+        end_lsn: start_lsn,
+    };
+    while let Some((lsn, recdata)) = reader.next_record()? {
+        let started_at = Instant::now();
+        let len = recdata.len();
+        walingest.ingest_record(timeline, recdata, lsn)?;
+        replay.records += 1;
+        replay.end_lsn = lsn;
+
+        timeline.tline.check_checkpoint_distance()?;
+        observe(len, started_at.elapsed());
+    }
+    info!("replayed WAL trace '{}': {replay:?}", path.display());
+    Ok(replay)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn trace_roundtrip() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("traces").join("test.trace");
+
+        let mut recorder = WalTraceRecorder::create(&path, Lsn(0x100))?;
+        recorder.record(Lsn(0x128), b"first record")?;
+        recorder.record(Lsn(0x150), b"")?;
+        assert!(recorder
+            .record(Lsn(0x150), b"not after the previous one")
+            .is_err());
+        recorder.record(Lsn(0x190), b"third record")?;
+        recorder.flush()?;
+        assert!(WalTraceRecorder::create(&path, Lsn(0x100)).is_err());
+
+        let mut reader = WalTraceReader::open(&path)?;
+        assert_eq!(reader.start_lsn(), Lsn(0x100));
+        assert_eq!(
+            reader.next_record()?,
+            Some((Lsn(0x128), Bytes::from_static(b"first record")))
+        );
+        assert_eq!(reader.next_record()?, Some((Lsn(0x150), Bytes::new())));
+        assert_eq!(
+            reader.next_record()?,
+            Some((Lsn(0x190), Bytes::from_static(b"third record")))
+        );
+        assert_eq!(reader.next_record()?, None);
+
+        // A record torn by a crash is not replayed
+        let contents = fs::read(&path)?;
+        let mut reader = WalTraceReader::new(&contents[..contents.len() - 3])?;
+        reader.next_record()?;
+        reader.next_record()?;
+        assert_eq!(reader.next_record()?, None);
+
+        assert!(WalTraceReader::new(&b"ZWALTRC0\0\0\0\0\0\0\0\0"[..]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn page_trace_roundtrip() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("traces").join("test.pages.trace");
+
+        let requests = [
+            PageRequest {
+                lsn: Lsn(0x100),
+                rel: RelTag {
+                    spcnode: 1663,
+                    dbnode: 13010,
+                    relnode: 16384,
+                    forknum: 0,
+                },
+                blkno: 7,
+            },
+            PageRequest {
+                lsn: Lsn(0x2_0000_0100),
+                rel: RelTag {
+                    spcnode: 1664,
+                    dbnode: 0,
+                    relnode: 1262,
+                    forknum: 2,
+                },
+                blkno: u32::MAX,
+            },
+        ];
+        let mut recorder = PageTraceRecorder::create(&path)?;
+        for request in &requests {
+            recorder.record(request)?;
+        }
+        recorder.flush()?;
+
+        let mut reader = PageTraceReader::open(&path)?;
+        assert_eq!(reader.next_request()?, Some(requests[0]));
+        assert_eq!(reader.next_request()?, Some(requests[1]));
+        assert_eq!(reader.next_request()?, None);
+
+        // A request torn by a crash is not replayed
+        let contents = fs::read(&path)?;
+        let mut reader = PageTraceReader::new(&contents[..contents.len() - 3])?;
+        assert_eq!(reader.next_request()?, Some(requests[0]));
+        assert_eq!(reader.next_request()?, None);
+
+        assert!(PageTraceReader::new(&b"ZWALTRC1"[..]).is_err());
+        Ok(())
+    }
+}
@@ -28,22 +28,211 @@ use postgres_ffi::{page_is_new, page_set_lsn};
 
 use anyhow::Result;
 use bytes::{Buf, Bytes, BytesMut};
+use lazy_static::lazy_static;
+use once_cell::sync::OnceCell;
+use serde::Serialize;
 use tracing::*;
 
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
 
+use crate::metric_labels;
 use crate::pgdatadir_mapping::*;
 use crate::reltag::{RelTag, SlruKind};
 use crate::repository::Repository;
 use crate::walrecord::*;
+use metrics::{register_int_counter_vec, IntCounter, IntCounterVec};
 use postgres_ffi::nonrelfile_utils::mx_offset_to_member_segment;
 use postgres_ffi::xlog_utils::*;
-use postgres_ffi::TransactionId;
 use postgres_ffi::{pg_constants, CheckPoint};
+use postgres_ffi::{transaction_id_precedes, Oid, TransactionId};
 use utils::lsn::Lsn;
+use utils::zid::{ZTenantId, ZTimelineId};
 
 static ZERO_PAGE: Bytes = Bytes::from_static(&[0u8; 8192]);
 
+lazy_static! {
+    static ref WAL_RECORDS_INGESTED: IntCounterVec = register_int_counter_vec!(
+        "pageserver_wal_records_ingested_total",
+        "Number of WAL records ingested, by resource manager",
+        &["tenant_id", "timeline_id", "rmgr"]
+    )
+    .expect("failed to define a metric");
+    static ref WAL_RECORD_BYTES_INGESTED: IntCounterVec = register_int_counter_vec!(
+        "pageserver_wal_record_bytes_ingested_total",
+        "Size of WAL records ingested, by resource manager",
+        &["tenant_id", "timeline_id", "rmgr"]
+    )
+    .expect("failed to define a metric");
+}
+
+/// Prefix of the logical messages that carry auxiliary files, see
+/// WalIngest::ingest_logical_message.
+pub const AUX_FILE_MESSAGE_PREFIX: &str = "neon-file:";
+
+/// Prefix of the logical messages that carry extension data, see
+/// WalIngest::ingest_logical_message.
+pub const EXTENSION_DATA_MESSAGE_PREFIX: &str = "neon-ext:";
+
+/// Resource manager names, indexed by resource manager id. Matches PostgreSQL's rmgrlist.h.
+const RMGR_NAMES: [&str; 22] = [
+    "XLOG",
+    "Transaction",
+    "Storage",
+    "CLOG",
+    "Database",
+    "Tablespace",
+    "MultiXact",
+    "RelMap",
+    "Standby",
+    "Heap2",
+    "Heap",
+    "Btree",
+    "Hash",
+    "Gin",
+    "Gist",
+    "Sequence",
+    "SPGist",
+    "BRIN",
+    "CommitTs",
+    "ReplicationOrigin",
+    "Generic",
+    "LogicalMessage",
+];
+
+pub fn rmgr_name(rmid: u8) -> &'static str {
+    RMGR_NAMES.get(rmid as usize).copied().unwrap_or("unknown")
+}
+
+/// Number of record types within a resource manager, the `XLR_RMGR_INFO_MASK` bits of xl_info.
+const RECORD_TYPES_PER_RMGR: usize = 16;
+
+/// Number and total size of ingested WAL records of one type.
+#[derive(Default)]
+struct WalRecordTypeCounts {
+    records: AtomicU64,
+    bytes: AtomicU64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct WalRecordTypeStats {
+    pub rmgr: &'static str,
+    pub rmgr_id: u8,
+    /// Record type within the resource manager, the `XLR_RMGR_INFO_MASK` bits of xl_info.
+    pub info: u8,
+    pub records: u64,
+    pub bytes: u64,
+}
+
+///
+/// Tally of the WAL records ingested into a timeline, by resource manager and
+/// record type. Helps to find out what is generating the WAL, e.g. to diagnose
+/// bloat or decide which record types are worth handling without the WAL redo
+/// process.
+///
+/// The per-record-type counts are kept in memory only and start from zero when
+/// the timeline is loaded. If metric labels are set, the per-resource manager
+/// totals are also exported as metrics, until the timeline is unloaded.
+///
+/// This is updated for every ingested record, so it's lock-free: the counters
+/// are atomics in a fixed table, and the metric handles are resolved once per
+/// resource manager. Records of the resource managers not in [`RMGR_NAMES`]
+/// are counted together, under the "unknown" resource manager.
+///
+pub struct WalRecordStats {
+    metric_labels: Option<(String, String)>,
+    // Indexed by resource manager id and record type. The last row is for
+    // the unknown resource managers.
+    counts: Vec<[WalRecordTypeCounts; RECORD_TYPES_PER_RMGR]>,
+    // Metric handles, indexed by resource manager id like 'counts'.
+    metrics: Vec<OnceCell<(IntCounter, IntCounter)>>,
+}
+
+impl Default for WalRecordStats {
+    fn default() -> Self {
+        WalRecordStats {
+            metric_labels: None,
+            counts: (0..=RMGR_NAMES.len()).map(|_| Default::default()).collect(),
+            metrics: (0..=RMGR_NAMES.len()).map(|_| OnceCell::new()).collect(),
+        }
+    }
+}
+
+impl WalRecordStats {
+    pub fn new(tenant_id: ZTenantId, timeline_id: ZTimelineId) -> Self {
+        WalRecordStats {
+            metric_labels: Some(metric_labels::timeline_labels(&tenant_id, &timeline_id)),
+            ..Default::default()
+        }
+    }
+
+    pub fn observe(&self, rmid: u8, xl_info: u8, record_len: usize) {
+        let row = (rmid as usize).min(RMGR_NAMES.len());
+        let info = (xl_info & pg_constants::XLR_RMGR_INFO_MASK) >> 4;
+        let record_len = record_len as u64;
+
+        let counts = &self.counts[row][info as usize];
+        counts.records.fetch_add(1, Ordering::Relaxed);
+        counts.bytes.fetch_add(record_len, Ordering::Relaxed);
+
+        if let Some((tenant_id, timeline_id)) = &self.metric_labels {
+            let (records, bytes) = self.metrics[row].get_or_init(|| {
+                let labels = [tenant_id.as_str(), timeline_id.as_str(), rmgr_name(rmid)];
+                (
+                    WAL_RECORDS_INGESTED.with_label_values(&labels),
+                    WAL_RECORD_BYTES_INGESTED.with_label_values(&labels),
+                )
+            });
+            records.inc();
+            bytes.inc_by(record_len);
+        }
+    }
+
+    /// Returns the per-record-type stats, ordered by the total size, largest first.
+    pub fn snapshot(&self) -> Vec<WalRecordTypeStats> {
+        let mut stats = Vec::new();
+        for (row, types) in self.counts.iter().enumerate() {
+            for (info, counts) in types.iter().enumerate() {
+                let records = counts.records.load(Ordering::Relaxed);
+                if records == 0 {
+                    continue;
+                }
+                stats.push(WalRecordTypeStats {
+                    rmgr: rmgr_name(row as u8),
+                    rmgr_id: row as u8,
+                    info: (info as u8) << 4,
+                    records,
+                    bytes: counts.bytes.load(Ordering::Relaxed),
+                });
+            }
+        }
+        stats.sort_by(|a, b| b.bytes.cmp(&a.bytes));
+        stats
+    }
+}
+
+impl Drop for WalRecordStats {
+    fn drop(&mut self) {
+        // The series of the tenants in the "other" bucket are shared, leave them.
+        let (tenant_id, timeline_id) = match &self.metric_labels {
+            Some((tenant_id, _)) if tenant_id == metric_labels::OTHER => return,
+            Some(labels) => labels,
+            None => return,
+        };
+        for (row, metrics) in self.metrics.iter().enumerate() {
+            if metrics.get().is_some() {
+                let labels = [
+                    tenant_id.as_str(),
+                    timeline_id.as_str(),
+                    rmgr_name(row as u8),
+                ];
+                let _ = WAL_RECORDS_INGESTED.remove_label_values(&labels);
+                let _ = WAL_RECORD_BYTES_INGESTED.remove_label_values(&labels);
+            }
+        }
+    }
+}
+
 pub struct WalIngest<'a, R: Repository> {
     timeline: &'a DatadirTimeline<R>,
 
@@ -85,6 +274,13 @@ impl<'a, R: Repository> WalIngest<'a, R> {
         let mut modification = timeline.begin_modification(lsn);
 
         let mut decoded = decode_wal_record(recdata).context("failed decoding wal record")?;
+        timeline
+            .wal_record_stats
+            .observe(decoded.xl_rmid, decoded.xl_info, decoded.record.len());
+        // The transactions that the record ends, for the last commit LSN below
+        let mut ended_xids = Vec::new();
+        let mut oldest_running_xid = None;
+        let mut shutdown = false;
         let mut buf = decoded.record.clone();
         buf.advance(decoded.main_data_offset);
 
@@ -125,7 +321,7 @@ impl<'a, R: Repository> WalIngest<'a, R> {
                 let dropdb = XlDropDatabase::decode(&mut buf);
                 for tablespace_id in dropdb.tablespace_ids {
                     trace!("Drop db {}, {}", tablespace_id, dropdb.db_id);
-                    modification.drop_dbdir(tablespace_id, dropdb.db_id)?;
+                    self.drop_dbdir(&mut modification, tablespace_id, dropdb.db_id)?;
                 }
             }
         } else if decoded.xl_rmid == pg_constants::RM_TBLSPC_ID {
@@ -158,6 +354,8 @@ impl<'a, R: Repository> WalIngest<'a, R> {
                     &parsed_xact,
                     info == pg_constants::XLOG_XACT_COMMIT,
                 )?;
+                ended_xids.push(parsed_xact.xid);
+                ended_xids.extend(&parsed_xact.subxacts);
             } else if info == pg_constants::XLOG_XACT_COMMIT_PREPARED
                 || info == pg_constants::XLOG_XACT_ABORT_PREPARED
             {
@@ -168,6 +366,8 @@ impl<'a, R: Repository> WalIngest<'a, R> {
                     &parsed_xact,
                     info == pg_constants::XLOG_XACT_COMMIT_PREPARED,
                 )?;
+                ended_xids.push(parsed_xact.xid);
+                ended_xids.extend(&parsed_xact.subxacts);
                 // Remove twophase file. see RemoveTwoPhaseFile() in postgres code
                 trace!(
                     "Drop twophaseFile for xid {} parsed_xact.xid {} here at {}",
@@ -178,6 +378,11 @@ impl<'a, R: Repository> WalIngest<'a, R> {
                 modification.drop_twophase_file(parsed_xact.xid)?;
             } else if info == pg_constants::XLOG_XACT_PREPARE {
                 modification.put_twophase_file(decoded.xl_xid, Bytes::copy_from_slice(&buf[..]))?;
+                // The prepared transaction has no more records until it's
+                // committed or aborted, and nothing of it is visible until then
+                let prepare = XlXactPrepare::decode(&mut buf);
+                ended_xids.push(prepare.xid);
+                ended_xids.extend(&prepare.subxacts);
             }
         } else if decoded.xl_rmid == pg_constants::RM_MULTIXACT_ID {
             let info = decoded.xl_info & pg_constants::XLR_RMGR_INFO_MASK;
@@ -214,6 +419,12 @@ impl<'a, R: Repository> WalIngest<'a, R> {
         } else if decoded.xl_rmid == pg_constants::RM_RELMAP_ID {
             let xlrec = XlRelmapUpdate::decode(&mut buf);
             self.ingest_relmap_page(&mut modification, &xlrec, &decoded)?;
+        } else if decoded.xl_rmid == pg_constants::RM_LOGICALMSG_ID {
+            let info = decoded.xl_info & pg_constants::XLR_RMGR_INFO_MASK;
+            if info == pg_constants::XLOG_LOGICAL_MESSAGE {
+                let xlrec = XlLogicalMessage::decode(&mut buf);
+                self.ingest_logical_message(&mut modification, &xlrec, &mut buf)?;
+            }
         } else if decoded.xl_rmid == pg_constants::RM_XLOG_ID {
             let info = decoded.xl_info & pg_constants::XLR_RMGR_INFO_MASK;
             if info == pg_constants::XLOG_NEXTOID {
@@ -242,6 +453,13 @@ impl<'a, R: Repository> WalIngest<'a, R> {
                     self.checkpoint.oldestXid = xlog_checkpoint.oldestXid;
                     self.checkpoint_modified = true;
                 }
+                shutdown = info == pg_constants::XLOG_CHECKPOINT_SHUTDOWN;
+            }
+        } else if decoded.xl_rmid == pg_constants::RM_STANDBY_ID {
+            let info = decoded.xl_info & pg_constants::XLR_RMGR_INFO_MASK;
+            if info == pg_constants::XLOG_RUNNING_XACTS {
+                let xlrec = XlRunningXacts::decode(&mut buf);
+                oldest_running_xid = Some(xlrec.oldest_running_xid);
             }
         }
 
@@ -263,6 +481,72 @@ impl<'a, R: Repository> WalIngest<'a, R> {
         // checkpoint data, let the repository know that it is up-to-date to this LSN
         modification.commit()?;
 
+        // And the computes that read at transaction boundaries, if it's one:
+        // when all the transactions with records so far have ended. The
+        // transactions can interleave, and the subtransactions have their own
+        // xids, ended by the record of their top transaction or by their own
+        // abort record.
+        let mut open_xids = timeline.open_xids.lock().unwrap();
+        if shutdown {
+            // No transaction runs at a shutdown checkpoint, and the prepared
+            // ones have no more records
+            open_xids.clear();
+        }
+        if decoded.xl_xid != pg_constants::INVALID_TRANSACTION_ID {
+            open_xids.insert(decoded.xl_xid);
+        }
+        for xid in ended_xids {
+            open_xids.remove(&xid);
+        }
+        if let Some(oldest_running_xid) = oldest_running_xid {
+            // That also forgets the transactions that ended without a record,
+            // in a crash of the compute
+            open_xids.retain(|xid| !transaction_id_precedes(*xid, oldest_running_xid));
+        }
+        if open_xids.is_empty() {
+            timeline.advance_commit_lsn(lsn);
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// Logical messages with the AUX_FILE_MESSAGE_PREFIX carry the auxiliary
+    /// files of the compute: the prefix is followed by the path of the file,
+    /// and the message is the new contents, empty if the file was removed.
+    /// Messages with the EXTENSION_DATA_MESSAGE_PREFIX carry extension data the
+    /// same way, the prefix is followed by '<namespace>/<key>', the key in
+    /// decimal. Other messages are only of interest to logical decoding, and
+    /// ignored.
+    ///
+    fn ingest_logical_message(
+        &mut self,
+        modification: &mut DatadirModification<R>,
+        xlrec: &XlLogicalMessage,
+        buf: &mut Bytes,
+    ) -> Result<()> {
+        let prefix = buf.split_to(xlrec.prefix_size as usize);
+        let message = buf.split_to(xlrec.message_size as usize);
+        // The prefix is NUL-terminated
+        let prefix = std::str::from_utf8(prefix.strip_suffix(b"\0").unwrap_or(&prefix[..]))
+            .context("invalid logical message prefix")?;
+        if let Some(path) = prefix.strip_prefix(AUX_FILE_MESSAGE_PREFIX) {
+            if message.is_empty() {
+                modification.delete_aux_file(path)?;
+            } else {
+                modification.put_aux_file(path, message)?;
+            }
+        } else if let Some(name) = prefix.strip_prefix(EXTENSION_DATA_MESSAGE_PREFIX) {
+            let (namespace, key) = name
+                .split_once('/')
+                .and_then(|(namespace, key)| Some((namespace, key.parse::<u64>().ok()?)))
+                .with_context(|| format!("invalid extension data message prefix '{prefix}'"))?;
+            if message.is_empty() {
+                modification.delete_extension_data(namespace, key)?;
+            } else {
+                modification.put_extension_data(namespace, key, message)?;
+            }
+        }
         Ok(())
     }
 
@@ -931,6 +1215,21 @@ impl<'a, R: Repository> WalIngest<'a, R> {
         Ok(())
     }
 
+    fn drop_dbdir(
+        &mut self,
+        modification: &mut DatadirModification<R>,
+        spcnode: Oid,
+        dbnode: Oid,
+    ) -> Result<()> {
+        modification.drop_dbdir(spcnode, dbnode)?;
+        // Forget the sizes of the dropped relations. Otherwise, if a relation
+        // with the same relfilenode is written to later, it would be taken to
+        // exist, and its pages written without a creation record.
+        self.relsize_cache
+            .retain(|rel, _| rel.spcnode != spcnode || rel.dbnode != dbnode);
+        Ok(())
+    }
+
     fn get_relsize(&mut self, rel: RelTag) -> Result<BlockNumber> {
         if let Some(nblocks) = self.relsize_cache.get(&rel) {
             Ok(*nblocks)
@@ -1052,6 +1351,9 @@ mod tests {
     use super::*;
     use crate::pgdatadir_mapping::create_test_timeline;
     use crate::repository::repo_harness::*;
+    use crate::repository::Timeline;
+    use crate::CheckpointConfig;
+    use bytes::BufMut;
     use postgres_ffi::pg_constants;
 
     /// Arbitrary relation tag, for testing.
@@ -1206,6 +1508,112 @@ mod tests {
 
     // Test what happens if we dropped a relation
     // and then created it again within the same layer.
+    #[test]
+    fn test_aux_files() -> Result<()> {
+        let repo = RepoHarness::create("test_aux_files")?.load();
+        let tline = create_test_timeline(repo, TIMELINE_ID)?;
+        init_walingest_test(&tline)?;
+
+        let mut m = tline.begin_modification(Lsn(0x20));
+        m.put_aux_file("pg_logical/mappings/test1", Bytes::from_static(b"first"))?;
+        m.put_aux_file(
+            "pg_logical/replorigin_checkpoint",
+            Bytes::from_static(b"origin"),
+        )?;
+        m.commit()?;
+        let mut m = tline.begin_modification(Lsn(0x30));
+        m.put_aux_file("pg_logical/mappings/test1", Bytes::from_static(b"second"))?;
+        m.commit()?;
+        let mut m = tline.begin_modification(Lsn(0x40));
+        m.delete_aux_file("pg_logical/replorigin_checkpoint")?;
+        m.commit()?;
+
+        // Each LSN sees the versions of the files at that point
+        assert!(tline.list_aux_files(Lsn(0x10))?.is_empty());
+        assert_eq!(
+            tline.get_aux_file("pg_logical/mappings/test1", Lsn(0x20))?,
+            Some(Bytes::from_static(b"first"))
+        );
+        assert_eq!(
+            tline.get_aux_file("pg_logical/mappings/test1", Lsn(0x30))?,
+            Some(Bytes::from_static(b"second"))
+        );
+        assert_eq!(tline.list_aux_files(Lsn(0x30))?.len(), 2);
+        assert_eq!(
+            tline.get_aux_file("pg_logical/replorigin_checkpoint", Lsn(0x40))?,
+            None
+        );
+        assert_eq!(tline.list_aux_files(Lsn(0x40))?.len(), 1);
+
+        // Paths must stay inside the data directory
+        let mut m = tline.begin_modification(Lsn(0x50));
+        assert!(m.put_aux_file("../outside", Bytes::new()).is_err());
+        assert!(m.put_aux_file("/etc/passwd", Bytes::new()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_extension_data() -> Result<()> {
+        let repo = RepoHarness::create("test_extension_data")?.load();
+        let tline = create_test_timeline(repo, TIMELINE_ID)?;
+        init_walingest_test(&tline)?;
+        let initial_size = tline.get_current_logical_size();
+
+        let mut m = tline.begin_modification(Lsn(0x20));
+        m.put_extension_data("myext", 1, Bytes::from_static(b"first"))?;
+        m.put_extension_data("myext", u64::MAX, Bytes::from_static(b"last"))?;
+        m.put_extension_data("otherext", 1, Bytes::from_static(b"other"))?;
+        m.commit()?;
+        let mut m = tline.begin_modification(Lsn(0x30));
+        m.put_extension_data("myext", 1, Bytes::from_static(b"second"))?;
+        m.delete_extension_data("otherext", 1)?;
+        m.commit()?;
+
+        // Each LSN sees the versions of the values at that point
+        assert!(tline.list_extension_data(Lsn(0x10))?.is_empty());
+        assert_eq!(
+            tline.get_extension_data("myext", 1, Lsn(0x20))?,
+            Some(Bytes::from_static(b"first"))
+        );
+        assert_eq!(
+            tline.get_extension_data("otherext", 1, Lsn(0x20))?,
+            Some(Bytes::from_static(b"other"))
+        );
+        assert_eq!(
+            tline.get_extension_data("myext", 1, Lsn(0x30))?,
+            Some(Bytes::from_static(b"second"))
+        );
+        assert_eq!(tline.get_extension_data("otherext", 1, Lsn(0x30))?, None);
+        assert_eq!(
+            tline.list_extension_data(Lsn(0x30))?,
+            vec![("myext".to_string(), 1), ("myext".to_string(), u64::MAX)]
+        );
+
+        // The values count towards the logical size, and are kept by GC
+        assert_eq!(tline.get_current_logical_size(), initial_size + 10);
+        assert_eq!(
+            tline.get_current_logical_size_non_incremental(Lsn(0x30))?,
+            initial_size + 10
+        );
+        let extension_data_ranges = |lsn| -> Result<usize> {
+            Ok(tline
+                .collect_keyspace(lsn)?
+                .ranges
+                .iter()
+                .filter(|range| range.start.field1 == 0x05)
+                .count())
+        };
+        // The directory, and the directory and the two values
+        assert_eq!(extension_data_ranges(Lsn(0x10))?, 1);
+        assert_eq!(extension_data_ranges(Lsn(0x30))?, 3);
+
+        let mut m = tline.begin_modification(Lsn(0x40));
+        assert!(m.put_extension_data("../outside", 1, Bytes::new()).is_err());
+
+        Ok(())
+    }
+
     #[test]
     fn test_drop_extend() -> Result<()> {
         let repo = RepoHarness::create("test_drop_extend")?.load();
@@ -1243,6 +1651,46 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_drop_database_recreate_rel() -> Result<()> {
+        let repo = RepoHarness::create("test_drop_database_recreate_rel")?.load();
+        let tline = create_test_timeline(repo, TIMELINE_ID)?;
+        let mut walingest = init_walingest_test(&tline)?;
+
+        let mut m = tline.begin_modification(Lsn(0x20));
+        walingest.put_rel_page_image(&mut m, TESTREL_A, 0, TEST_IMG("foo blk 0 at 2"))?;
+        m.commit()?;
+
+        // Drop the database, and write to a relation with the same relfilenode
+        let mut m = tline.begin_modification(Lsn(0x30));
+        walingest.drop_dbdir(&mut m, TESTREL_A.spcnode, TESTREL_A.dbnode)?;
+        m.commit()?;
+        assert_eq!(tline.get_rel_exists(TESTREL_A, Lsn(0x30))?, false);
+
+        let mut m = tline.begin_modification(Lsn(0x40));
+        walingest.put_rel_page_image(&mut m, TESTREL_A, 1, TEST_IMG("foo blk 1 at 4"))?;
+        m.commit()?;
+
+        // The relation got created again, with the gap filled
+        assert_eq!(tline.get_rel_exists(TESTREL_A, Lsn(0x40))?, true);
+        assert_eq!(tline.get_rel_size(TESTREL_A, Lsn(0x40))?, 2);
+        assert_eq!(
+            tline.get_rel_page_at_lsn(TESTREL_A, 0, Lsn(0x40))?,
+            ZERO_PAGE
+        );
+
+        // Pages of a relation that doesn't exist are refused
+        let mut m = tline.begin_modification(Lsn(0x50));
+        let other_rel = RelTag {
+            relnode: 1001,
+            ..TESTREL_A
+        };
+        m.put_rel_page_image(other_rel, 0, TEST_IMG("foo blk 0 at 5"))?;
+        assert!(m.commit().is_err());
+
+        Ok(())
+    }
+
     // Test what happens if we truncated a relation
     // so that one of its segments was dropped
     // and then extended it again within the same layer.
@@ -1397,4 +1845,231 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_rel_pages() -> Result<()> {
+        let repo = RepoHarness::create("test_rel_pages")?.load();
+        let tline = create_test_timeline(repo, TIMELINE_ID)?;
+        let mut walingest = init_walingest_test(&tline)?;
+
+        // More than two batches of pages, in layer files
+        let nblocks = 600;
+        let mut lsn = 0x10;
+        for blknum in 0..nblocks {
+            lsn += 0x10;
+            let mut m = tline.begin_modification(Lsn(lsn));
+            let img = TEST_IMG(&format!("foo blk {} at {}", blknum, Lsn(lsn)));
+            walingest.put_rel_page_image(&mut m, TESTREL_A, blknum, img)?;
+            m.commit()?;
+        }
+        tline.tline.checkpoint(CheckpointConfig::Forced)?;
+        let flushed_lsn = Lsn(lsn);
+
+        // Overwrite some of them in the in-memory layer
+        for blknum in (0..nblocks).step_by(7) {
+            lsn += 0x10;
+            let mut m = tline.begin_modification(Lsn(lsn));
+            let img = TEST_IMG(&format!("foo blk {} at {}", blknum, Lsn(lsn)));
+            walingest.put_rel_page_image(&mut m, TESTREL_A, blknum, img)?;
+            m.commit()?;
+        }
+
+        for lsn in [Lsn(0x100), flushed_lsn, Lsn(lsn)] {
+            let nblocks = tline.get_rel_size(TESTREL_A, lsn)?;
+            let mut expected_blknum = 0;
+            for page in tline.rel_pages(TESTREL_A, lsn)? {
+                let (blknum, img) = page?;
+                assert_eq!(blknum, expected_blknum);
+                assert_eq!(img, tline.get_rel_page_at_lsn(TESTREL_A, blknum, lsn)?);
+                expected_blknum += 1;
+            }
+            assert_eq!(expected_blknum, nblocks);
+        }
+
+        Ok(())
+    }
+
+    /// A WAL record without blocks, with the given main data.
+    fn main_data_record(xid: TransactionId, rmid: u8, info: u8, main_data: &[u8]) -> Bytes {
+        let mut rec = BytesMut::new();
+        rec.put_u32_le((XLOG_SIZE_OF_XLOG_RECORD + 2 + main_data.len()) as u32);
+        rec.put_u32_le(xid);
+        rec.put_u64_le(0); // xl_prev
+        rec.put_u8(info);
+        rec.put_u8(rmid);
+        rec.put_u16_le(0); // padding
+        rec.put_u32_le(0); // xl_crc
+        rec.put_u8(pg_constants::XLR_BLOCK_ID_DATA_SHORT);
+        rec.put_u8(main_data.len() as u8);
+        rec.put_slice(main_data);
+        rec.freeze()
+    }
+
+    /// A record that changes nothing, standing for the changes of a transaction.
+    fn change_record(xid: TransactionId) -> Bytes {
+        const XLOG_NOOP: u8 = 0x20;
+        main_data_record(xid, pg_constants::RM_XLOG_ID, XLOG_NOOP, &[])
+    }
+
+    fn commit_record(xid: TransactionId, subxacts: &[TransactionId]) -> Bytes {
+        let mut data = BytesMut::new();
+        data.put_i64_le(0); // xact_time
+        data.put_u32_le(pg_constants::XACT_XINFO_HAS_SUBXACTS);
+        data.put_i32_le(subxacts.len() as i32);
+        for subxact in subxacts {
+            data.put_u32_le(*subxact);
+        }
+        main_data_record(
+            xid,
+            pg_constants::RM_XACT_ID,
+            pg_constants::XLOG_XACT_COMMIT | pg_constants::XLOG_XACT_HAS_INFO,
+            &data,
+        )
+    }
+
+    fn prepare_record(xid: TransactionId) -> Bytes {
+        let mut data = BytesMut::new();
+        data.put_u32_le(0x57F94534); // magic
+        data.put_u32_le(72); // total_len
+        data.put_u32_le(xid);
+        data.put_u32_le(111); // database
+        data.put_i64_le(0); // prepared_at
+        data.put_u32_le(10); // owner
+        data.put_i32_le(0); // nsubxacts
+        data.put_slice(&[0; 12]); // ncommitrels, nabortrels, ninvalmsgs
+        data.put_u8(0); // initfileinval
+        data.put_u8(0); // padding
+        data.put_u16_le(2); // gidlen
+        data.put_u64_le(0); // origin_lsn
+        data.put_i64_le(0); // origin_timestamp
+        data.put_slice(b"b\0\0\0\0\0\0\0"); // gid, MAXALIGNed
+        main_data_record(
+            xid,
+            pg_constants::RM_XACT_ID,
+            pg_constants::XLOG_XACT_PREPARE,
+            &data,
+        )
+    }
+
+    fn commit_prepared_record(xid: TransactionId) -> Bytes {
+        let mut data = BytesMut::new();
+        data.put_i64_le(0); // xact_time
+        data.put_u32_le(pg_constants::XACT_XINFO_HAS_TWOPHASE);
+        data.put_u32_le(xid);
+        main_data_record(
+            pg_constants::INVALID_TRANSACTION_ID,
+            pg_constants::RM_XACT_ID,
+            pg_constants::XLOG_XACT_COMMIT_PREPARED | pg_constants::XLOG_XACT_HAS_INFO,
+            &data,
+        )
+    }
+
+    fn running_xacts_record(oldest_running_xid: TransactionId) -> Bytes {
+        let mut data = BytesMut::new();
+        data.put_i32_le(0); // xcnt
+        data.put_i32_le(0); // subxcnt
+        data.put_u32_le(0); // subxid_overflow, padding
+        data.put_u32_le(oldest_running_xid); // nextXid
+        data.put_u32_le(oldest_running_xid);
+        data.put_u32_le(oldest_running_xid - 1); // latestCompletedXid
+        main_data_record(
+            pg_constants::INVALID_TRANSACTION_ID,
+            pg_constants::RM_STANDBY_ID,
+            pg_constants::XLOG_RUNNING_XACTS,
+            &data,
+        )
+    }
+
+    #[test]
+    fn test_interleaved_xacts_commit_lsn() -> Result<()> {
+        let repo = RepoHarness::create("test_interleaved_xacts_commit_lsn")?.load();
+        let tline = create_test_timeline(repo, TIMELINE_ID)?;
+        let mut walingest = init_walingest_test(&tline)?;
+
+        let (xid_a, xid_b, subxid_a, xid_c) = (1000, 1001, 1002, 1003);
+        let mut lsn = Lsn(0x20);
+        let mut ingest = |recdata: Bytes| -> Result<Lsn> {
+            walingest.ingest_record(&tline, recdata, lsn)?;
+            lsn += 0x10;
+            Ok(tline.get_last_commit_lsn())
+        };
+
+        let start = ingest(change_record(pg_constants::INVALID_TRANSACTION_ID))?;
+        assert_eq!(start, Lsn(0x20));
+
+        // A and B interleave, and A has a subtransaction
+        assert_eq!(ingest(change_record(xid_a))?, start);
+        assert_eq!(ingest(change_record(xid_b))?, start);
+        assert_eq!(ingest(change_record(subxid_a))?, start);
+        // A's commit is no boundary while B is open, nor a record outside of
+        // the transactions
+        assert_eq!(ingest(commit_record(xid_a, &[subxid_a]))?, start);
+        assert_eq!(
+            ingest(change_record(pg_constants::INVALID_TRANSACTION_ID))?,
+            start
+        );
+        // B ends with its PREPARE record, and its COMMIT PREPARED is one too
+        assert_eq!(ingest(prepare_record(xid_b))?, Lsn(0x80));
+        assert_eq!(ingest(commit_prepared_record(xid_b))?, Lsn(0x90));
+
+        // C is gone with the compute that crashed, the running transactions
+        // record ends it
+        assert_eq!(ingest(change_record(xid_c))?, Lsn(0x90));
+        assert_eq!(ingest(running_xacts_record(xid_c + 1))?, Lsn(0xB0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_wal_record_stats() {
+        let stats = WalRecordStats::default();
+        // The low bits of xl_info are not part of the record type
+        stats.observe(pg_constants::RM_HEAP_ID, 0x00, 100);
+        stats.observe(pg_constants::RM_HEAP_ID, 0x01, 50);
+        stats.observe(pg_constants::RM_HEAP_ID, 0x10, 20);
+        stats.observe(pg_constants::RM_XACT_ID, 0x00, 500);
+
+        assert_eq!(
+            stats.snapshot(),
+            vec![
+                WalRecordTypeStats {
+                    rmgr: "Transaction",
+                    rmgr_id: pg_constants::RM_XACT_ID,
+                    info: 0x00,
+                    records: 1,
+                    bytes: 500,
+                },
+                WalRecordTypeStats {
+                    rmgr: "Heap",
+                    rmgr_id: pg_constants::RM_HEAP_ID,
+                    info: 0x00,
+                    records: 2,
+                    bytes: 150,
+                },
+                WalRecordTypeStats {
+                    rmgr: "Heap",
+                    rmgr_id: pg_constants::RM_HEAP_ID,
+                    info: 0x10,
+                    records: 1,
+                    bytes: 20,
+                },
+            ]
+        );
+        assert_eq!(rmgr_name(200), "unknown");
+
+        // The resource managers we don't know are counted together
+        let stats = WalRecordStats::default();
+        stats.observe(200, 0x00, 10);
+        stats.observe(201, 0x00, 10);
+        assert_eq!(
+            stats.snapshot(),
+            vec![WalRecordTypeStats {
+                rmgr: "unknown",
+                rmgr_id: RMGR_NAMES.len() as u8,
+                info: 0x00,
+                records: 2,
+                bytes: 20,
+            }]
+        );
+    }
 }
@@ -4,29 +4,65 @@
 //! timeline.
 //!
 //! We keep one WAL receiver active per timeline.
+//!
+//! The ingest of a timeline can be paused, for maintenance or to examine its
+//! state at a fixed LSN: its WAL receiver is stopped and no new one is started
+//! until the ingest is resumed. The WAL keeps accumulating on the safekeepers
+//! meanwhile, and requests for pages at newer LSNs wait for it like for any WAL
+//! that hasn't arrived yet. Pauses are not persisted, a restart resumes the
+//! ingest of all timelines.
+//!
+//! When a WAL receiver connects, it logs how much WAL the timeline needs: from
+//! its last record LSN, which is its disk consistent LSN after a restart, up to
+//! the LSN the safekeeper has flushed. The remaining gap is tracked while the
+//! receiver catches up, in the WAL receiver entry and in a gauge.
+//!
+//! WAL that the safekeeper sends again, overlapping the WAL already received,
+//! is only accepted if its records are the same as the ones ingested, see
+//! [`crate::wal_fence`]. Otherwise the connection is dropped with a fencing
+//! error, rather than applying the records twice.
+//!
+//! The WAL receiver sends its status to the safekeeper at least every
+//! `wal_receiver_status_interval`, and considers the connection dead if nothing
+//! arrives from the safekeeper for `wal_receiver_timeout`, e.g. because of a
+//! half-open TCP connection. A connection that fails or times out is retried
+//! with a backoff, a few times in a row, before the WAL receiver gives up until
+//! the next callmemaybe. The connection state and the number of reconnects are
+//! in the WAL receiver entry, and in metrics.
+//!
+//! WAL can also be imported into a timeline over the page service, see
+//! [`start_wal_import`]. The import and the WAL receiver exclude each other: an
+//! import is refused while the timeline has a WAL receiver, and no WAL receiver
+//! is started while an import is in progress.
 
 use crate::config::PageServerConf;
+use crate::metric_labels;
 use crate::repository::{Repository, Timeline};
 use crate::tenant_mgr;
+use crate::tenant_mgr::TenantState;
 use crate::thread_mgr;
 use crate::thread_mgr::ThreadKind;
+use crate::wal_fence::{WalFence, WalFencingError};
+use crate::wal_trace::WalReceiverRecorder;
 use crate::walingest::WalIngest;
 use anyhow::{bail, Context, Error, Result};
 use bytes::BytesMut;
 use fail::fail_point;
 use lazy_static::lazy_static;
+use metrics::{register_int_counter_vec, register_int_gauge_vec, IntCounterVec, IntGaugeVec};
 use postgres_ffi::waldecoder::*;
 use postgres_protocol::message::backend::ReplicationMessage;
 use postgres_types::PgLsn;
 use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, DisplayFromStr};
 use std::cell::Cell;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::str::FromStr;
 use std::sync::Mutex;
 use std::thread_local;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 use tokio::pin;
+use tokio::time::Instant;
 use tokio_postgres::replication::ReplicationStream;
 use tokio_postgres::{Client, NoTls, SimpleQueryMessage, SimpleQueryRow};
 use tokio_stream::StreamExt;
@@ -50,11 +86,70 @@ pub struct WalReceiverEntry {
     last_received_msg_lsn: Option<Lsn>,
     /// the timestamp (in microseconds) of the last received message
     last_received_msg_ts: Option<u128>,
+    /// the LSN the streaming started from
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    needs_wal_from: Option<Lsn>,
+    /// the end of the WAL flushed on the safekeeper, as last reported by it
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    safekeeper_flush_lsn: Option<Lsn>,
+    connection_state: WalConnectionState,
+    /// number of times the WAL receiver reconnected after a failed connection
+    reconnects: u64,
+    last_connection_error: Option<String>,
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WalConnectionState {
+    Connecting,
+    Streaming,
+    /// Waiting to reconnect after the connection failed.
+    Reconnecting,
+}
+
+/// Consecutive failed connections before the WAL receiver gives up.
+const MAX_RECONNECT_ATTEMPTS: u32 = 5;
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(30);
+
 lazy_static! {
+    static ref WAL_PENDING_BYTES: IntGaugeVec = register_int_gauge_vec!(
+        "pageserver_wal_receiver_pending_bytes",
+        "Amount of WAL flushed on the safekeeper but not ingested yet, as of the last message from it",
+        &["tenant_id", "timeline_id"]
+    )
+    .expect("failed to define a metric");
+    static ref WAL_RECEIVER_CONNECTED: IntGaugeVec = register_int_gauge_vec!(
+        "pageserver_wal_receiver_connected",
+        "Whether the WAL receiver of the timeline is streaming from a safekeeper",
+        &["tenant_id", "timeline_id"]
+    )
+    .expect("failed to define a metric");
+    static ref WAL_RECEIVER_RECONNECTS: IntCounterVec = register_int_counter_vec!(
+        "pageserver_wal_receiver_reconnects_total",
+        "Number of reconnects of the WAL receivers after a failed or timed out connection",
+        &["tenant_id", "timeline_id"]
+    )
+    .expect("failed to define a metric");
     static ref WAL_RECEIVERS: Mutex<HashMap<(ZTenantId, ZTimelineId), WalReceiverEntry>> =
         Mutex::new(HashMap::new());
+    /// Timelines whose ingest is paused, with the time of the pause.
+    static ref PAUSED_INGESTS: Mutex<HashMap<(ZTenantId, ZTimelineId), SystemTime>> =
+        Mutex::new(HashMap::new());
+    /// Timelines with a WAL import in progress. Locked after `WAL_RECEIVERS`.
+    static ref WAL_IMPORTS: Mutex<HashSet<(ZTenantId, ZTimelineId)>> = Mutex::new(HashSet::new());
+}
+
+///
+/// Ingest state of a timeline, returned by [`pause_ingest`] and [`resume_ingest`].
+///
+#[serde_as]
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct IngestStatus {
+    pub paused: bool,
+    /// the timestamp (in seconds) of the pause
+    pub paused_since: Option<u64>,
+    #[serde_as(as = "DisplayFromStr")]
+    pub last_record_lsn: Lsn,
 }
 
 thread_local! {
@@ -76,8 +171,26 @@ pub fn launch_wal_receiver(
     timelineid: ZTimelineId,
     wal_producer_connstr: &str,
 ) -> Result<()> {
+    if tenant_mgr::get_tenant_state(tenantid) == Some(TenantState::ReadOnly) {
+        bail!("Tenant {tenantid} is in read-only mode, refusing to start WAL receiver");
+    }
+
     let mut receivers = WAL_RECEIVERS.lock().unwrap();
 
+    if is_ingest_paused(tenantid, timelineid) {
+        // The safekeepers call back periodically, that's not worth more than debug
+        debug!("ingest of timeline {timelineid} is paused, not starting WAL receiver");
+        return Ok(());
+    }
+    if WAL_IMPORTS
+        .lock()
+        .unwrap()
+        .contains(&(tenantid, timelineid))
+    {
+        debug!("WAL is being imported into timeline {timelineid}, not starting WAL receiver");
+        return Ok(());
+    }
+
     match receivers.get_mut(&(tenantid, timelineid)) {
         Some(receiver) => {
             debug!("wal receiver already running, updating connection string");
@@ -102,6 +215,11 @@ pub fn launch_wal_receiver(
                 wal_producer_connstr: wal_producer_connstr.into(),
                 last_received_msg_lsn: None,
                 last_received_msg_ts: None,
+                needs_wal_from: None,
+                safekeeper_flush_lsn: None,
+                connection_state: WalConnectionState::Connecting,
+                reconnects: 0,
+                last_connection_error: None,
             };
             receivers.insert((tenantid, timelineid), receiver);
 
@@ -112,6 +230,43 @@ pub fn launch_wal_receiver(
     Ok(())
 }
 
+///
+/// A WAL import in progress, keeps the WAL receiver of the timeline from
+/// starting until dropped.
+///
+pub struct WalImportGuard {
+    tenant_id: ZTenantId,
+    timeline_id: ZTimelineId,
+}
+
+impl Drop for WalImportGuard {
+    fn drop(&mut self) {
+        WAL_IMPORTS
+            .lock()
+            .unwrap()
+            .remove(&(self.tenant_id, self.timeline_id));
+    }
+}
+
+///
+/// Register a WAL import into the timeline. Fails if the timeline has a WAL
+/// receiver, or another import in progress: two sources of WAL would step on
+/// each other.
+///
+pub fn start_wal_import(tenant_id: ZTenantId, timeline_id: ZTimelineId) -> Result<WalImportGuard> {
+    let receivers = WAL_RECEIVERS.lock().unwrap();
+    if receivers.contains_key(&(tenant_id, timeline_id)) {
+        bail!("Timeline {timeline_id} has a running WAL receiver, refusing to import WAL");
+    }
+    if !WAL_IMPORTS.lock().unwrap().insert((tenant_id, timeline_id)) {
+        bail!("WAL is being imported into timeline {timeline_id} already");
+    }
+    Ok(WalImportGuard {
+        tenant_id,
+        timeline_id,
+    })
+}
+
 /// Look up a WAL receiver's data in the global `WAL_RECEIVERS`
 pub fn get_wal_receiver_entry(
     tenant_id: ZTenantId,
@@ -121,6 +276,94 @@ pub fn get_wal_receiver_entry(
     receivers.get(&(tenant_id, timeline_id)).cloned()
 }
 
+fn update_wal_receiver_entry(
+    tenant_id: ZTenantId,
+    timeline_id: ZTimelineId,
+    update: impl FnOnce(&mut WalReceiverEntry),
+) {
+    if let Some(entry) = WAL_RECEIVERS
+        .lock()
+        .unwrap()
+        .get_mut(&(tenant_id, timeline_id))
+    {
+        update(entry);
+    }
+}
+
+pub fn is_ingest_paused(tenant_id: ZTenantId, timeline_id: ZTimelineId) -> bool {
+    PAUSED_INGESTS
+        .lock()
+        .unwrap()
+        .contains_key(&(tenant_id, timeline_id))
+}
+
+///
+/// Pause the ingest of a timeline: stop its WAL receiver and don't start a new
+/// one until [`resume_ingest`]. Waits for the WAL receiver to stop, the returned
+/// last record LSN is where the ingest stopped. Pausing a paused timeline is a
+/// no-op.
+///
+pub fn pause_ingest(tenant_id: ZTenantId, timeline_id: ZTimelineId) -> Result<IngestStatus> {
+    let timeline = tenant_mgr::get_local_timeline_with_load(tenant_id, timeline_id)
+        .context("Cannot load local timeline")?;
+
+    let paused_since = {
+        // Hold the WAL receivers lock, so that no receiver is launched in between
+        let _receivers = WAL_RECEIVERS.lock().unwrap();
+        *PAUSED_INGESTS
+            .lock()
+            .unwrap()
+            .entry((tenant_id, timeline_id))
+            .or_insert_with(|| {
+                info!("pausing the ingest of timeline {timeline_id} of tenant {tenant_id}");
+                SystemTime::now()
+            })
+    };
+    thread_mgr::shutdown_threads(
+        Some(ThreadKind::WalReceiver),
+        Some(tenant_id),
+        Some(timeline_id),
+    );
+
+    Ok(IngestStatus {
+        paused: true,
+        paused_since: Some(unix_secs(paused_since)),
+        last_record_lsn: timeline.get_last_record_lsn(),
+    })
+}
+
+///
+/// Resume the ingest of a timeline. The WAL receiver is started again by the
+/// next callmemaybe, the safekeepers send it periodically.
+///
+pub fn resume_ingest(tenant_id: ZTenantId, timeline_id: ZTimelineId) -> Result<IngestStatus> {
+    let timeline = tenant_mgr::get_local_timeline_with_load(tenant_id, timeline_id)
+        .context("Cannot load local timeline")?;
+
+    if let Some(paused_since) = PAUSED_INGESTS
+        .lock()
+        .unwrap()
+        .remove(&(tenant_id, timeline_id))
+    {
+        info!(
+            "resuming the ingest of timeline {timeline_id} of tenant {tenant_id}, paused for {:?}",
+            paused_since.elapsed().unwrap_or_default()
+        );
+    }
+
+    Ok(IngestStatus {
+        paused: false,
+        paused_since: None,
+        last_record_lsn: timeline.get_last_record_lsn(),
+    })
+}
+
+fn unix_secs(ts: SystemTime) -> u64 {
+    ts.duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
 //
 // This is the entry point for the WAL receiver thread.
 //
@@ -128,9 +371,10 @@ fn thread_main(conf: &'static PageServerConf, tenant_id: ZTenantId, timeline_id:
     let _enter = info_span!("WAL receiver", timeline = %timeline_id, tenant = %tenant_id).entered();
     info!("WAL receiver thread started");
 
-    // Look up the current WAL producer address
-    let wal_producer_connstr = {
-        match get_wal_receiver_entry(tenant_id, timeline_id) {
+    let mut failed_attempts = 0;
+    loop {
+        // Look up the current WAL producer address, callmemaybe can change it
+        let wal_producer_connstr = match get_wal_receiver_entry(tenant_id, timeline_id) {
             Some(e) => e.wal_producer_connstr,
             None => {
                 info!(
@@ -139,21 +383,68 @@ fn thread_main(conf: &'static PageServerConf, tenant_id: ZTenantId, timeline_id:
                 );
                 return;
             }
+        };
+        update_wal_receiver_entry(tenant_id, timeline_id, |entry| {
+            entry.connection_state = WalConnectionState::Connecting
+        });
+
+        // Make a connection to the WAL safekeeper, or directly to the primary PostgreSQL server,
+        // and start streaming WAL from it.
+        let res = walreceiver_main(conf, tenant_id, timeline_id, &wal_producer_connstr);
+        let (tenant_label, timeline_label) =
+            metric_labels::timeline_labels(&tenant_id, &timeline_id);
+        WAL_RECEIVER_CONNECTED
+            .with_label_values(&[&tenant_label, &timeline_label])
+            .set(0);
+
+        // TODO cleanup info messages
+        let e = match res {
+            Ok(()) => {
+                info!(
+                    "walreceiver disconnected tenant {}, timelineid {}",
+                    tenant_id, timeline_id
+                );
+                break;
+            }
+            Err(e) => e,
+        };
+        if let Some(fencing_error) = e.downcast_ref::<WalFencingError>() {
+            error!("refusing WAL from {wal_producer_connstr}: {fencing_error}");
+            break;
+        }
+        info!("WAL streaming connection failed ({})", e);
+        if thread_mgr::is_shutdown_requested() {
+            break;
         }
-    };
 
-    // Make a connection to the WAL safekeeper, or directly to the primary PostgreSQL server,
-    // and start streaming WAL from it.
-    let res = walreceiver_main(conf, tenant_id, timeline_id, &wal_producer_connstr);
+        // A connection that got to streaming starts the count over
+        let streamed = get_wal_receiver_entry(tenant_id, timeline_id).map_or(false, |entry| {
+            entry.connection_state == WalConnectionState::Streaming
+        });
+        failed_attempts = if streamed { 1 } else { failed_attempts + 1 };
+        if failed_attempts > MAX_RECONNECT_ATTEMPTS {
+            info!("giving up after {MAX_RECONNECT_ATTEMPTS} failed connections until the next callmemaybe");
+            break;
+        }
 
-    // TODO cleanup info messages
-    if let Err(e) = res {
-        info!("WAL streaming connection failed ({})", e);
-    } else {
-        info!(
-            "walreceiver disconnected tenant {}, timelineid {}",
-            tenant_id, timeline_id
-        );
+        let backoff = Duration::from_secs(1 << (failed_attempts - 1)).min(MAX_RECONNECT_BACKOFF);
+        info!("reconnecting in {backoff:?}");
+        WAL_RECEIVER_RECONNECTS
+            .with_label_values(&[&tenant_label, &timeline_label])
+            .inc();
+        update_wal_receiver_entry(tenant_id, timeline_id, |entry| {
+            entry.connection_state = WalConnectionState::Reconnecting;
+            entry.reconnects += 1;
+            entry.last_connection_error = Some(format!("{e:#}"));
+        });
+        let mut sleep_time = backoff.as_secs();
+        while sleep_time > 0 && !thread_mgr::is_shutdown_requested() {
+            sleep_time -= 1;
+            std::thread::sleep(Duration::from_secs(1));
+        }
+        if thread_mgr::is_shutdown_requested() {
+            break;
+        }
     }
 
     // Drop it from list of active WAL_RECEIVERS
@@ -162,17 +453,24 @@ fn thread_main(conf: &'static PageServerConf, tenant_id: ZTenantId, timeline_id:
 }
 
 fn walreceiver_main(
-    _conf: &PageServerConf,
+    conf: &'static PageServerConf,
     tenant_id: ZTenantId,
     timeline_id: ZTimelineId,
     wal_producer_connstr: &str,
 ) -> anyhow::Result<(), Error> {
     // Connect to the database in replication mode.
     info!("connecting to {:?}", wal_producer_connstr);
-    let connect_cfg = format!(
+    let mut connect_cfg = format!(
         "{} application_name=pageserver replication=true",
         wal_producer_connstr
     );
+    if !conf.wal_receiver_timeout.is_zero() {
+        // Don't wait for the connection longer than for the messages, and let
+        // the OS probe the idle connection
+        let timeout_secs = conf.wal_receiver_timeout.as_secs().max(1);
+        connect_cfg +=
+            &format!(" connect_timeout={timeout_secs} keepalives=1 keepalives_idle={timeout_secs}");
+    }
 
     let runtime = tokio::runtime::Builder::new_current_thread()
         .enable_all()
@@ -233,6 +531,27 @@ fn walreceiver_main(
         "last_record_lsn {} starting replication from {}, server is at {}...",
         last_rec_lsn, startpoint, end_of_wal
     );
+    let disk_consistent_lsn = timeline.tline.get_disk_consistent_lsn();
+    info!(
+        "needs WAL from {}: {} bytes to ingest up to the safekeeper's {}, {} bytes since disk consistent LSN {}",
+        startpoint,
+        end_of_wal.0.saturating_sub(startpoint.0),
+        end_of_wal,
+        end_of_wal.0.saturating_sub(disk_consistent_lsn.0),
+        disk_consistent_lsn
+    );
+    let (tenant_label, timeline_label) = metric_labels::timeline_labels(&tenant_id, &timeline_id);
+    let pending_bytes = WAL_PENDING_BYTES.with_label_values(&[&tenant_label, &timeline_label]);
+    let mut safekeeper_flush_lsn = end_of_wal;
+    pending_bytes.set(end_of_wal.0.saturating_sub(last_rec_lsn.0) as i64);
+    if let Some(entry) = WAL_RECEIVERS
+        .lock()
+        .unwrap()
+        .get_mut(&(tenant_id, timeline_id))
+    {
+        entry.needs_wal_from = Some(startpoint);
+        entry.safekeeper_flush_lsn = Some(end_of_wal);
+    }
 
     let query = format!("START_REPLICATION PHYSICAL {}", startpoint);
 
@@ -241,33 +560,77 @@ fn walreceiver_main(
     pin!(physical_stream);
 
     let mut waldecoder = WalStreamDecoder::new(startpoint);
+    let mut wal_fence = WalFence::new(startpoint);
 
     let mut walingest = WalIngest::new(&*timeline, startpoint)?;
+    let mut wal_recorder = WalReceiverRecorder::new(conf, tenant_id, timeline_id);
 
-    while let Some(replication_message) = runtime.block_on(async {
-        let shutdown_watcher = thread_mgr::shutdown_watcher();
-        tokio::select! {
-            // check for shutdown first
-            biased;
-            _ = shutdown_watcher => {
+    update_wal_receiver_entry(tenant_id, timeline_id, |entry| {
+        entry.connection_state = WalConnectionState::Streaming
+    });
+    WAL_RECEIVER_CONNECTED
+        .with_label_values(&[&tenant_label, &timeline_label])
+        .set(1);
+
+    let status_interval = conf.wal_receiver_status_interval;
+    let timeout = conf.wal_receiver_timeout;
+    let mut last_message_at = Instant::now();
+    let mut last_status_at = Instant::now();
+    loop {
+        let message_deadline = last_message_at + timeout;
+        let status_deadline = last_status_at + status_interval;
+        let event = runtime.block_on(async {
+            let shutdown_watcher = thread_mgr::shutdown_watcher();
+            tokio::select! {
+                // check for shutdown first
+                biased;
+                _ = shutdown_watcher => StreamEvent::Shutdown,
+                message = physical_stream.next() => StreamEvent::Message(message),
+                _ = tokio::time::sleep_until(message_deadline), if !timeout.is_zero() => {
+                    StreamEvent::TimedOut
+                }
+                _ = tokio::time::sleep_until(status_deadline), if !status_interval.is_zero() => {
+                    StreamEvent::StatusDue
+                }
+            }
+        });
+        let replication_message = match event {
+            StreamEvent::Shutdown => {
                 info!("walreceiver interrupted");
-                None
+                break;
             }
-            replication_message = physical_stream.next() => replication_message,
-        }
-    }) {
-        let replication_message = replication_message?;
+            StreamEvent::TimedOut => {
+                bail!("no messages from the safekeeper in {timeout:?}, considering the connection dead")
+            }
+            StreamEvent::StatusDue => None,
+            StreamEvent::Message(None) => break,
+            StreamEvent::Message(Some(replication_message)) => {
+                last_message_at = Instant::now();
+                thread_mgr::record_activity();
+                Some(replication_message?)
+            }
+        };
         let status_update = match replication_message {
-            ReplicationMessage::XLogData(xlog_data) => {
+            // Send the status periodically, the safekeeper tracks the
+            // pageserver's progress and liveness from it
+            None => Some(last_rec_lsn),
+
+            Some(ReplicationMessage::XLogData(xlog_data)) => {
                 // Pass the WAL data to the decoder, and see if we can decode
                 // more records as a result.
                 let data = xlog_data.data();
                 let startlsn = Lsn::from(xlog_data.wal_start());
                 let endlsn = startlsn + data.len() as u64;
+                safekeeper_flush_lsn = safekeeper_flush_lsn.max(Lsn::from(xlog_data.wal_end()));
 
                 trace!("received XLogData between {} and {}", startlsn, endlsn);
 
-                waldecoder.feed_bytes(data);
+                // Skip the part that we have already received, if the
+                // safekeeper sent the same WAL again.
+                let new_data =
+                    wal_fence.check(startlsn, data, timeline.tline.get_disk_consistent_lsn())?;
+                waldecoder.feed_bytes(new_data);
+                wal_recorder.update(last_rec_lsn);
 
                 while let Some((lsn, recdata)) = waldecoder.poll_decode()? {
                     let _enter = info_span!("processing record", lsn = %lsn).entered();
@@ -277,12 +640,15 @@ fn walreceiver_main(
                     // at risk of hitting a deadlock.
                     anyhow::ensure!(lsn.is_aligned());
 
-                    walingest.ingest_record(&timeline, recdata, lsn)?;
+                    wal_recorder.record(lsn, &recdata);
+                    walingest.ingest_record(&timeline, recdata.clone(), lsn)?;
+                    wal_fence.record_ingested(lsn, &recdata);
 
                     fail_point!("walreceiver-after-ingest");
 
                     last_rec_lsn = lsn;
                 }
+                wal_recorder.flush();
 
                 if !caught_up && endlsn >= end_of_wal {
                     info!("caught up at LSN {}", endlsn);
@@ -294,8 +660,9 @@ fn walreceiver_main(
                 Some(endlsn)
             }
 
-            ReplicationMessage::PrimaryKeepAlive(keepalive) => {
+            Some(ReplicationMessage::PrimaryKeepAlive(keepalive)) => {
                 let wal_end = keepalive.wal_end();
+                safekeeper_flush_lsn = safekeeper_flush_lsn.max(Lsn::from(wal_end));
                 let timestamp = keepalive.timestamp();
                 let reply_requested = keepalive.reply() != 0;
 
@@ -315,6 +682,7 @@ fn walreceiver_main(
 
             _ => None,
         };
+        pending_bytes.set(safekeeper_flush_lsn.0.saturating_sub(last_rec_lsn.0) as i64);
 
         if let Some(last_lsn) = status_update {
             let timeline_remote_consistent_lsn = runtime.block_on(async {
@@ -327,7 +695,7 @@ fn walreceiver_main(
                         tenant_id,
                         timeline_id,
                     })
-                    .map(|remote_timeline| remote_timeline.metadata.disk_consistent_lsn())
+                    .map(|remote_timeline| remote_timeline.remote_consistent_lsn())
                     .unwrap_or(Lsn(0)) // no checkpoint was uploaded
             });
 
@@ -355,6 +723,7 @@ fn walreceiver_main(
                 };
 
                 entry.last_received_msg_lsn = Some(last_lsn);
+                entry.safekeeper_flush_lsn = Some(safekeeper_flush_lsn);
                 entry.last_received_msg_ts = Some(
                     ts.duration_since(SystemTime::UNIX_EPOCH)
                         .expect("Received message time should be before UNIX EPOCH!")
@@ -381,12 +750,22 @@ fn walreceiver_main(
                     .as_mut()
                     .zenith_status_update(data.len() as u64, &data),
             )?;
+            last_status_at = Instant::now();
         }
     }
 
     Ok(())
 }
 
+enum StreamEvent {
+    Message(Option<Result<ReplicationMessage<bytes::Bytes>, tokio_postgres::Error>>),
+    /// The status update to the safekeeper is due.
+    StatusDue,
+    /// Nothing was received from the safekeeper for `wal_receiver_timeout`.
+    TimedOut,
+    Shutdown,
+}
+
 /// Data returned from the postgres `IDENTIFY_SYSTEM` command
 ///
 /// See the [postgres docs] for more details.
@@ -436,3 +815,50 @@ pub async fn identify_system(client: &mut Client) -> Result<IdentifySystem, Erro
         Err(IdentifyError.into())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wal_import_excludes_wal_receiver() -> Result<()> {
+        let repo_dir = PageServerConf::test_repo_dir("wal_import_excludes_wal_receiver");
+        let conf: &'static PageServerConf =
+            Box::leak(Box::new(PageServerConf::dummy_conf(repo_dir)));
+        let tenant_id = ZTenantId::generate();
+        let timeline_id = ZTimelineId::generate();
+
+        let import = start_wal_import(tenant_id, timeline_id)?;
+        assert!(
+            start_wal_import(tenant_id, timeline_id).is_err(),
+            "only one import at a time"
+        );
+
+        // The safekeeper's call is ignored until the import is done
+        launch_wal_receiver(conf, tenant_id, timeline_id, "host=localhost port=1")?;
+        assert!(get_wal_receiver_entry(tenant_id, timeline_id).is_none());
+
+        drop(import);
+        let import = start_wal_import(tenant_id, timeline_id)?;
+        drop(import);
+
+        // An import is refused while the timeline has a WAL receiver
+        WAL_RECEIVERS.lock().unwrap().insert(
+            (tenant_id, timeline_id),
+            WalReceiverEntry {
+                thread_id: 0,
+                wal_producer_connstr: String::new(),
+                last_received_msg_lsn: None,
+                last_received_msg_ts: None,
+                needs_wal_from: None,
+                safekeeper_flush_lsn: None,
+                connection_state: WalConnectionState::Connecting,
+                reconnects: 0,
+                last_connection_error: None,
+            },
+        );
+        assert!(start_wal_import(tenant_id, timeline_id).is_err());
+        drop_wal_receiver(tenant_id, timeline_id);
+        Ok(())
+    }
+}
@@ -132,6 +132,30 @@ impl XlRelmapUpdate {
     }
 }
 
+#[repr(C)]
+#[derive(Debug)]
+pub struct XlLogicalMessage {
+    pub db_id: Oid,
+    pub transactional: bool,
+    pub prefix_size: u64,
+    pub message_size: u64,
+}
+
+impl XlLogicalMessage {
+    pub fn decode(buf: &mut Bytes) -> XlLogicalMessage {
+        XlLogicalMessage {
+            db_id: buf.get_u32_le(),
+            transactional: {
+                let transactional = buf.get_u8() != 0;
+                buf.advance(3); // padding
+                transactional
+            },
+            prefix_size: buf.get_u64_le(),
+            message_size: buf.get_u64_le(),
+        }
+    }
+}
+
 #[repr(C)]
 #[derive(Debug)]
 pub struct XlSmgrCreate {
@@ -401,6 +425,71 @@ impl XlXactParsedRecord {
     }
 }
 
+/// The transactions that a XLOG_XACT_PREPARE record prepares. The record
+/// holds the contents of the two-phase state file, which starts with the
+/// TwoPhaseFileHeader struct, see StartPrepare in PostgreSQL (in
+/// src/backend/access/transam/twophase.c).
+#[derive(Debug)]
+pub struct XlXactPrepare {
+    pub xid: TransactionId,
+    pub subxacts: Vec<TransactionId>,
+}
+
+impl XlXactPrepare {
+    pub fn decode(buf: &mut Bytes) -> XlXactPrepare {
+        let _magic = buf.get_u32_le();
+        let _total_len = buf.get_u32_le();
+        let xid = buf.get_u32_le();
+        let _database = buf.get_u32_le();
+        let _prepared_at = buf.get_i64_le();
+        let _owner = buf.get_u32_le();
+        let nsubxacts = buf.get_i32_le();
+        let _ncommitrels = buf.get_i32_le();
+        let _nabortrels = buf.get_i32_le();
+        let _ninvalmsgs = buf.get_i32_le();
+        let _initfileinval = buf.get_u8();
+        buf.advance(1); // padding
+        let gidlen = buf.get_u16_le();
+        let _origin_lsn = buf.get_u64_le();
+        let _origin_timestamp = buf.get_i64_le();
+        // The GID follows, MAXALIGNed, and then the subtransactions
+        buf.advance((gidlen as usize + 7) & !7);
+        let mut subxacts = Vec::<TransactionId>::new();
+        for _i in 0..nsubxacts {
+            subxacts.push(buf.get_u32_le());
+        }
+        XlXactPrepare { xid, subxacts }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct XlRunningXacts {
+    pub xcnt: i32,
+    pub subxcnt: i32,
+    pub subxid_overflow: bool,
+    pub next_xid: TransactionId,
+    pub oldest_running_xid: TransactionId,
+    pub latest_completed_xid: TransactionId,
+}
+
+impl XlRunningXacts {
+    pub fn decode(buf: &mut Bytes) -> XlRunningXacts {
+        let xcnt = buf.get_i32_le();
+        let subxcnt = buf.get_i32_le();
+        let subxid_overflow = buf.get_u8() != 0;
+        buf.advance(3); // padding
+        XlRunningXacts {
+            xcnt,
+            subxcnt,
+            subxid_overflow,
+            next_xid: buf.get_u32_le(),
+            oldest_running_xid: buf.get_u32_le(),
+            latest_completed_xid: buf.get_u32_le(),
+        }
+    }
+}
+
 #[repr(C)]
 #[derive(Debug)]
 pub struct XlClogTruncate {
@@ -20,6 +20,7 @@
 //!
 use byteorder::{ByteOrder, LittleEndian};
 use bytes::{BufMut, Bytes, BytesMut};
+use fail::fail_point;
 use lazy_static::lazy_static;
 use nix::poll::*;
 use serde::Serialize;
@@ -38,11 +39,17 @@ use tracing::*;
 use utils::{bin_ser::BeSer, lsn::Lsn, nonblock::set_nonblock, zid::ZTenantId};
 
 use crate::config::PageServerConf;
+use crate::metric_labels;
 use crate::pgdatadir_mapping::{key_to_rel_block, key_to_slru_block};
 use crate::reltag::{RelTag, SlruKind};
 use crate::repository::Key;
+use crate::request_priority::PriorityQueue;
+use crate::tenant_usage::UsageMeter;
 use crate::walrecord::ZenithWalRecord;
-use metrics::{register_histogram, register_int_counter, Histogram, IntCounter};
+use metrics::{
+    register_histogram, register_int_counter, register_int_counter_vec, Histogram, IntCounter,
+    IntCounterVec,
+};
 use postgres_ffi::nonrelfile_utils::mx_offset_to_flags_bitshift;
 use postgres_ffi::nonrelfile_utils::mx_offset_to_flags_offset;
 use postgres_ffi::nonrelfile_utils::mx_offset_to_member_offset;
@@ -118,12 +125,25 @@ lazy_static! {
         "Number of WAL records replayed in WAL redo process"
     )
     .unwrap();
+    static ref WAL_REDO_TIMEOUTS: IntCounterVec = register_int_counter_vec!(
+        "pageserver_wal_redo_timeouts_total",
+        "Number of WAL redo requests that timed out, killing the WAL redo process",
+        &["tenant_id"]
+    )
+    .expect("failed to define a metric");
+    static ref WAL_REDO_PROCESS_LAUNCHES: IntCounterVec = register_int_counter_vec!(
+        "pageserver_wal_redo_process_launches_total",
+        "Number of WAL redo processes launched",
+        &["tenant_id"]
+    )
+    .expect("failed to define a metric");
 }
 
 ///
 /// This is the real implementation that uses a Postgres process to
 /// perform WAL replay. Only one thread can use the process at a time,
-/// that is controlled by the Mutex. In the future, we might want to
+/// that is controlled by the Mutex, and the threads queue for it by the
+/// priority of their requests. In the future, we might want to
 /// launch a pool of processes to allow concurrent replay of multiple
 /// records.
 ///
@@ -132,6 +152,10 @@ pub struct PostgresRedoManager {
     conf: &'static PageServerConf,
 
     process: Mutex<Option<PostgresRedoProcess>>,
+    /// The requests wait here for the process, the interactive ones first.
+    queue: PriorityQueue,
+    timeouts_counter: IntCounter,
+    launches_counter: IntCounter,
 }
 
 /// Can this request be served by zenith redo functions
@@ -234,12 +258,21 @@ impl PostgresRedoManager {
             tenantid,
             conf,
             process: Mutex::new(None),
+            queue: PriorityQueue::new("wal_redo", 1),
+            timeouts_counter: WAL_REDO_TIMEOUTS
+                .with_label_values(&[&metric_labels::tenant_label(&tenantid)]),
+            launches_counter: WAL_REDO_PROCESS_LAUNCHES
+                .with_label_values(&[&metric_labels::tenant_label(&tenantid)]),
         }
     }
 
     ///
     /// Process one request for WAL redo using wal-redo postgres
     ///
+    /// If the process doesn't respond within 'wal_redo_timeout', it's killed
+    /// and the request is retried once with a new process: a wedged process
+    /// must not block all page reads of the tenant.
+    ///
     fn apply_batch_postgres(
         &self,
         key: Key,
@@ -252,46 +285,64 @@ impl PostgresRedoManager {
 
         let start_time = Instant::now();
 
+        let _slot = self.queue.acquire();
         let mut process_guard = self.process.lock().unwrap();
         let lock_time = Instant::now();
 
-        // launch the WAL redo process on first use
-        if process_guard.is_none() {
-            let p = PostgresRedoProcess::launch(self.conf, &self.tenantid)?;
-            *process_guard = Some(p);
-        }
-        let process = process_guard.as_mut().unwrap();
-
         WAL_REDO_WAIT_TIME.observe(lock_time.duration_since(start_time).as_secs_f64());
 
         // Relational WAL records are applied using wal-redo-postgres
         let buf_tag = BufferTag { rel, blknum };
-        let result = process
-            .apply_wal_records(buf_tag, base_img, records, wal_redo_timeout)
-            .map_err(WalRedoError::IoError);
-
-        let end_time = Instant::now();
-        let duration = end_time.duration_since(lock_time);
-        WAL_REDO_TIME.observe(duration.as_secs_f64());
-        debug!(
-            "postgres applied {} WAL records in {} us to reconstruct page image at LSN {}",
-            records.len(),
-            duration.as_micros(),
-            lsn
-        );
-
-        // If something went wrong, don't try to reuse the process. Kill it, and
-        // next request will launch a new one.
-        if result.is_err() {
-            error!(
-                "error applying {} WAL records to reconstruct page image at LSN {}",
+        let mut retried = false;
+        loop {
+            // launch the WAL redo process on first use, or after the previous one was killed
+            if process_guard.is_none() {
+                let p = PostgresRedoProcess::launch(self.conf, &self.tenantid)?;
+                self.launches_counter.inc();
+                *process_guard = Some(p);
+            }
+            let process = process_guard.as_mut().unwrap();
+
+            let attempt_start_time = Instant::now();
+            let result = process
+                .apply_wal_records(buf_tag, base_img.clone(), records, wal_redo_timeout)
+                .map_err(WalRedoError::IoError);
+
+            let duration = attempt_start_time.elapsed();
+            WAL_REDO_TIME.observe(duration.as_secs_f64());
+            process.usage.maybe_account(Some(self.tenantid));
+            debug!(
+                "postgres applied {} WAL records in {} us to reconstruct page image at LSN {}",
                 records.len(),
+                duration.as_micros(),
                 lsn
             );
-            let process = process_guard.take().unwrap();
-            process.kill();
+
+            // If something went wrong, don't try to reuse the process. Kill it, and
+            // next request will launch a new one.
+            if let Err(e) = &result {
+                error!(
+                    "error applying {} WAL records to reconstruct page image at LSN {}: {}",
+                    records.len(),
+                    lsn,
+                    e
+                );
+                let process = process_guard.take().unwrap();
+                process.kill();
+
+                let timed_out =
+                    matches!(e, WalRedoError::IoError(e) if e.kind() == ErrorKind::TimedOut);
+                if timed_out {
+                    self.timeouts_counter.inc();
+                    if !retried {
+                        warn!("retrying the timed out WAL redo request with a new process");
+                        retried = true;
+                        continue;
+                    }
+                }
+            }
+            return result;
         }
-        result
     }
 
     ///
@@ -558,7 +609,10 @@ impl PostgresRedoManager {
 /// Handle to the Postgres WAL redo process
 ///
 struct PostgresRedoProcess {
+    tenantid: ZTenantId,
     child: Child,
+    /// CPU time and disk I/O of the process, accounted to the tenant.
+    usage: UsageMeter,
     stdin: ChildStdin,
     stdout: ChildStdout,
     stderr: ChildStderr,
@@ -641,8 +695,11 @@ impl PostgresRedoProcess {
         set_nonblock(stdout.as_raw_fd())?;
         set_nonblock(stderr.as_raw_fd())?;
 
+        let usage = UsageMeter::process(child.id(), "WalRedo");
         Ok(PostgresRedoProcess {
+            tenantid: *tenantid,
             child,
+            usage,
             stdin,
             stdout,
             stderr,
@@ -650,6 +707,7 @@ impl PostgresRedoProcess {
     }
 
     fn kill(mut self) {
+        self.usage.account(Some(self.tenantid));
         let _ = self.child.kill();
         if let Ok(exit_status) = self.child.wait() {
             error!("wal-redo-postgres exited with code {}", exit_status);
@@ -695,12 +753,20 @@ impl PostgresRedoProcess {
         build_get_page_msg(tag, &mut writebuf);
         WAL_REDO_RECORD_COUNTER.inc_by(records.len() as u64);
 
+        // The timeout applies to the whole request, not to each wait for the
+        // process: a process that keeps logging but never returns the page is
+        // as stuck as a silent one.
+        let deadline = Instant::now() + wal_redo_timeout;
+
         // The input is now in 'writebuf'. Do a blind write first, writing as much as
         // we can, before calling poll(). That skips one call to poll() if the stdin is
         // already available for writing, which it almost certainly is because the
         // process is idle.
         let mut nwrite = self.stdin.write(&writebuf)?;
 
+        // Lets the tests make the process look stuck: a sleep here uses up the deadline.
+        fail_point!("wal-redo-before-poll");
+
         // We expect the WAL redo process to respond with an 8k page image. We read it
         // into this buffer.
         let mut resultbuf = vec![0; pg_constants::BLCKSZ.into()];
@@ -721,14 +787,21 @@ impl PostgresRedoProcess {
             // we have data to read. Otherwise only wake up if there's data to read.
             let nfds = if nwrite < writebuf.len() { 3 } else { 2 };
             let n = loop {
-                match nix::poll::poll(&mut pollfds[0..nfds], wal_redo_timeout.as_millis() as i32) {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    break Ok(0);
+                }
+                match nix::poll::poll(&mut pollfds[0..nfds], remaining.as_millis() as i32) {
                     Err(e) if e == nix::errno::Errno::EINTR => continue,
                     res => break res,
                 }
             }?;
 
             if n == 0 {
-                return Err(Error::new(ErrorKind::Other, "WAL redo timed out"));
+                return Err(Error::new(
+                    ErrorKind::TimedOut,
+                    format!("WAL redo timed out after {wal_redo_timeout:?}"),
+                ));
             }
 
             // If we have some messages in stderr, forward them to the log.
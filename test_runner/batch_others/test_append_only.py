@@ -0,0 +1,47 @@
+from contextlib import closing
+
+import pytest
+from fixtures.zenith_fixtures import ZenithEnvBuilder, ZenithPageserverApiException
+
+
+# Test marking relations as append-only, from the compute with the page service
+# command and with the HTTP API, and that the hints survive a restart.
+def test_append_only(zenith_env_builder: ZenithEnvBuilder):
+    env = zenith_env_builder.init_start()
+    env.zenith_cli.create_branch('test_append_only')
+    pg = env.postgres.create_start('test_append_only')
+    tenant_id = env.initial_tenant
+    ps_http = env.pageserver.http_client()
+
+    pg.safe_psql("CREATE TABLE events (id serial, payload text)")
+    pg.safe_psql("CREATE TABLE accounts (id int, balance int)")
+
+    def rel_path(table):
+        return pg.safe_psql(f"""
+            SELECT (CASE reltablespace WHEN 0 THEN 1663 ELSE reltablespace END)
+                   || '/' || (SELECT oid FROM pg_database WHERE datname = current_database())
+                   || '/' || pg_relation_filenode(oid)
+            FROM pg_class WHERE relname = '{table}'
+        """)[0][0]
+
+    events, accounts = rel_path('events'), rel_path('accounts')
+    assert ps_http.tenant_append_only_relations(tenant_id) == []
+
+    with closing(env.pageserver.connect()) as psconn:
+        with psconn.cursor() as pscur:
+            pscur.execute(f"append_only {tenant_id.hex} {events} on")
+    assert ps_http.tenant_append_only_relations(tenant_id) == [events]
+
+    relations = ps_http.tenant_set_append_only(tenant_id, [accounts])
+    assert sorted(relations) == sorted([events, accounts])
+    assert ps_http.tenant_set_append_only(tenant_id, [accounts], append_only=False) == [events]
+
+    with pytest.raises(ZenithPageserverApiException, match='invalid relation'):
+        ps_http.tenant_set_append_only(tenant_id, ['1663/not-a-relation'])
+
+    # The table keeps working, and the hint is still there after a restart
+    pg.safe_psql("INSERT INTO events (payload) SELECT 'event ' || g FROM generate_series(1, 10000) g")
+    env.pageserver.stop()
+    env.pageserver.start()
+    assert ps_http.tenant_append_only_relations(tenant_id) == [events]
+    assert pg.safe_psql("SELECT count(*) FROM events")[0][0] == 10000
@@ -1,4 +1,5 @@
 from contextlib import closing
+from pathlib import Path
 from typing import Iterator
 from uuid import UUID, uuid4
 from fixtures.zenith_fixtures import ZenithEnvBuilder, ZenithPageserverApiException
@@ -71,3 +72,24 @@ def test_compute_auth_to_pageserver(zenith_env_builder: ZenithEnvBuilder, with_s
             cur.execute("INSERT INTO t SELECT generate_series(1,100000), 'payload'")
             cur.execute('SELECT sum(key) FROM t')
             assert cur.fetchone() == (5000050000, )
+
+
+def test_pageserver_metrics_auth(zenith_env_builder: ZenithEnvBuilder, tmp_path: Path):
+    metrics_auth_file = tmp_path / 'metrics_auth'
+    metrics_auth_file.write_text('prometheus:secret\n')
+    zenith_env_builder.pageserver_config_override = f"metrics_auth_file='{metrics_auth_file}'"
+    env = zenith_env_builder.init_start()
+
+    client = env.pageserver.http_client()
+    with pytest.raises(ZenithPageserverApiException, match='missing authorization header'):
+        client.get_metrics()
+    with pytest.raises(ZenithPageserverApiException, match='invalid credentials'):
+        client.get_metrics(basic_auth=('prometheus', 'wrong'))
+
+    metrics = client.get_metrics(basic_auth=('prometheus', 'secret'))
+    assert 'pageserver_threads_running' in metrics
+    assert 'process_resident_memory_bytes' in metrics
+
+    # the rest of the API doesn't need the credentials
+    client.check_status()
+    client.tenant_list()
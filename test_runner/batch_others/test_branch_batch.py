@@ -0,0 +1,45 @@
+import uuid
+
+import pytest
+from fixtures.zenith_fixtures import ZenithEnvBuilder, ZenithPageserverApiException
+from fixtures.utils import lsn_from_hex
+
+
+# Test creating many branches at the same LSN with one request, and that they
+# survive a pageserver restart.
+def test_branch_batch(zenith_env_builder: ZenithEnvBuilder):
+    env = zenith_env_builder.init_start()
+
+    main_timeline = env.zenith_cli.create_branch('test_branch_batch')
+    pg = env.postgres.create_start('test_branch_batch')
+    pg.safe_psql("CREATE TABLE foo AS SELECT 'payload' || g AS t FROM generate_series(1, 1000) g")
+    lsn = pg.safe_psql('SELECT pg_current_wal_insert_lsn()')[0][0]
+
+    tenant_id = env.initial_tenant
+    ps_http = env.pageserver.http_client()
+
+    new_timelines = [uuid.uuid4() for _ in range(10)]
+    batch = ps_http.timeline_create_batch(tenant_id,
+                                          main_timeline,
+                                          new_timelines + [main_timeline],
+                                          ancestor_start_lsn=lsn)
+    assert batch['ancestor_timeline_id'] == main_timeline.hex
+    assert lsn_from_hex(batch['ancestor_lsn']) >= lsn_from_hex(lsn)
+    created = {branch['timeline_id']: branch['created'] for branch in batch['branches']}
+    assert created == {**{t.hex: True for t in new_timelines}, main_timeline.hex: False}
+
+    def check_branches():
+        for timeline_id in new_timelines:
+            detail = ps_http.timeline_detail(tenant_id, timeline_id)
+            assert detail['local']['ancestor_timeline_id'] == main_timeline.hex
+            assert detail['local']['ancestor_lsn'] == batch['ancestor_lsn']
+
+    check_branches()
+    env.pageserver.stop()
+    env.pageserver.start()
+    check_branches()
+
+    # The ids must be unique, and the whole batch fails otherwise
+    with pytest.raises(ZenithPageserverApiException, match='more than once'):
+        duplicate = uuid.uuid4()
+        ps_http.timeline_create_batch(tenant_id, main_timeline, [duplicate, duplicate])
@@ -106,6 +106,10 @@ def test_fix_broken_timelines_on_startup(zenith_simple_env: ZenithEnv):
     with pytest.raises(Exception, match="before-checkpoint-new-timeline"):
         _ = env.zenith_cli.create_timeline("test_fix_broken_timelines", tenant_id)
 
+    # The temporary initdb directory is removed even though the creation failed
+    tenant_path = env.repo_dir / 'tenants' / tenant_id.hex
+    assert not list(tenant_path.glob('tmp-timeline-*'))
+
     # Restart the page server
     env.zenith_cli.pageserver_stop(immediate=True)
     env.zenith_cli.pageserver_start()
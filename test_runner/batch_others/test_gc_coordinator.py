@@ -0,0 +1,37 @@
+from fixtures.zenith_fixtures import ZenithEnvBuilder, wait_until
+
+
+# Test pausing and resuming the background GC of all the tenants with the HTTP API.
+def test_gc_coordinator(zenith_env_builder: ZenithEnvBuilder):
+    zenith_env_builder.pageserver_config_override = "max_concurrent_gc=1"
+    env = zenith_env_builder.init_start()
+    ps_http = env.pageserver.http_client()
+
+    status = ps_http.gc_status()
+    assert not status['paused']
+    assert status['max_concurrent_gc'] == 1
+
+    status = ps_http.gc_pause()
+    assert status['paused']
+    assert ps_http.gc_status()['paused']
+
+    # The GC thread of an active tenant waits for its turn while GC is paused
+    tenant, _ = env.zenith_cli.create_tenant(conf={'gc_period': '1 s'})
+    env.zenith_cli.create_timeline('test_gc_coordinator', tenant_id=tenant)
+    pg = env.postgres.create_start('test_gc_coordinator', tenant_id=tenant)
+    pg.safe_psql("CREATE TABLE t AS SELECT g FROM generate_series(1, 1000) g")
+
+    def gc_waiting():
+        status = ps_http.gc_status()
+        assert status['waiting'] >= 1
+        assert status['running'] == 0
+
+    wait_until(20, 1, gc_waiting)
+
+    status = ps_http.gc_resume()
+    assert not status['paused']
+
+    def gc_not_waiting():
+        assert ps_http.gc_status()['waiting'] == 0
+
+    wait_until(20, 1, gc_not_waiting)
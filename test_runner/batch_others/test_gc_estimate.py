@@ -0,0 +1,64 @@
+from contextlib import closing
+import uuid
+
+import psycopg2.extras
+from fixtures.log_helper import log
+from fixtures.utils import lsn_from_hex, print_gc_result
+from fixtures.zenith_fixtures import ZenithEnvBuilder, wait_for_last_record_lsn
+
+
+#
+# Check the GC estimate against what GC removes, and that it's refreshed by
+# the checkpoints and by GC.
+#
+def test_gc_estimate(zenith_env_builder: ZenithEnvBuilder):
+    env = zenith_env_builder.init_start()
+
+    # Disable background compaction and GC, create images at every compaction
+    tenant, _ = env.zenith_cli.create_tenant(
+        conf={
+            'gc_period': '10 m',
+            'gc_horizon': '0',
+            'pitr_interval': '0 sec',
+            'compaction_period': '10 m',
+            'image_creation_threshold': '1',
+        })
+    pg = env.postgres.create_start('main', tenant_id=tenant)
+    timeline = uuid.UUID(pg.safe_psql("show neon.timeline_id")[0][0])
+    ps_http = env.pageserver.http_client()
+
+    def write_and_checkpoint(sql: str):
+        pg.safe_psql(sql)
+        flush_lsn = lsn_from_hex(pg.safe_psql("SELECT pg_current_wal_flush_lsn()")[0][0])
+        wait_for_last_record_lsn(ps_http, tenant, timeline, flush_lsn)
+        # The checkpoint command compacts the timeline too, creating image layers
+        env.pageserver.safe_psql(f"checkpoint {tenant.hex} {timeline.hex}")
+
+    write_and_checkpoint("CREATE TABLE foo AS SELECT g, 'payload' || g AS t FROM generate_series(1, 10000) g")
+    write_and_checkpoint("UPDATE foo SET t = 'updated' || g")
+    # The estimate is refreshed by the flush, with the image layers of the update in place
+    write_and_checkpoint("INSERT INTO foo VALUES (0, 'last')")
+
+    estimate = ps_http.gc_estimate(tenant, timeline)
+    log.info(f'GC estimate: {estimate}')
+    timeline_details = ps_http.timeline_detail(tenant, timeline)
+    assert estimate['disk_consistent_lsn'] == timeline_details['local']['disk_consistent_lsn']
+    # No GC horizon
+    assert estimate['cutoff_lsn'] == estimate['disk_consistent_lsn']
+    assert estimate['retain_lsns'] == []
+    assert estimate['reclaimable_layers'] > 0
+    assert estimate['reclaimable_bytes'] > 0
+
+    # GC cuts off at the last record LSN, so it removes at least what was estimated
+    with closing(env.pageserver.connect()) as psconn:
+        with psconn.cursor(cursor_factory=psycopg2.extras.DictCursor) as pscur:
+            pscur.execute(f"do_gc {tenant.hex} {timeline.hex} 0")
+            row = pscur.fetchone()
+            print_gc_result(row)
+    assert row['layers_removed'] >= estimate['reclaimable_layers']
+    assert row['bytes_removed'] >= estimate['reclaimable_bytes']
+
+    # Refreshed by GC, there's nothing left to collect
+    estimate = ps_http.gc_estimate(tenant, timeline)
+    assert estimate['reclaimable_layers'] == 0
+    assert estimate['reclaimable_bytes'] == 0
@@ -0,0 +1,48 @@
+from contextlib import closing
+
+import psycopg2.extras
+import pytest
+from fixtures.utils import print_gc_result
+from fixtures.zenith_fixtures import ZenithEnv, ZenithPageserverApiException
+
+
+# Test the report of the space retained by GC, against the totals of the GC run
+def test_gc_space_report(zenith_simple_env: ZenithEnv):
+    env = zenith_simple_env
+    env.zenith_cli.create_branch('test_gc_space_report', 'empty')
+    pg = env.postgres.create_start('test_gc_space_report')
+    tenant_id = env.initial_tenant
+    timeline_id = pg.safe_psql("show neon.timeline_id")[0][0]
+    ps_http = env.pageserver.http_client()
+
+    with pytest.raises(ZenithPageserverApiException, match='GC has not run'):
+        ps_http.gc_space_report(tenant_id, timeline_id)
+
+    pg.safe_psql("CREATE TABLE foo AS SELECT g, 'payload' || g AS t FROM generate_series(1, 10000) g")
+    pg.safe_psql("UPDATE foo SET t = 'updated' || g")
+
+    with closing(env.pageserver.connect()) as psconn:
+        with psconn.cursor(cursor_factory=psycopg2.extras.DictCursor) as pscur:
+            pscur.execute(f"checkpoint {tenant_id.hex} {timeline_id}")
+            pscur.execute(f"do_gc {tenant_id.hex} {timeline_id} 0")
+            row = pscur.fetchone()
+            print_gc_result(row)
+
+    report = ps_http.gc_space_report(tenant_id, timeline_id)
+    assert report['retained'] == {
+        'cutoff': row['bytes_needed_by_cutoff'],
+        'pitr': row['bytes_needed_by_pitr'],
+        'branches': row['bytes_needed_by_branches'],
+        'latest': row['bytes_not_updated'],
+    }
+    assert report['removed_bytes'] == row['bytes_removed']
+    assert sum(report['retained'].values()) > 0
+
+    consumers = report['top_consumers']
+    assert consumers
+    retained = [sum(c['retained'].values()) for c in consumers]
+    assert retained == sorted(retained, reverse=True), 'consumers should be ordered, largest first'
+    assert sum(retained) <= sum(report['retained'].values())
+
+    report = ps_http.gc_space_report(tenant_id, timeline_id, limit=1)
+    assert len(report['top_consumers']) == 1
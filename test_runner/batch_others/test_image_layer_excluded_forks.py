@@ -0,0 +1,67 @@
+from contextlib import closing
+
+import psycopg2.extras
+from fixtures.log_helper import log
+from fixtures.utils import print_gc_result
+from fixtures.zenith_fixtures import ZenithEnvBuilder
+
+
+#
+# Leave the FSM and VM forks out of the image layers, garbage collect their
+# history, and check that PostgreSQL still works with the zeroed pages it gets
+# for them.
+#
+def test_image_layer_excluded_forks(zenith_env_builder: ZenithEnvBuilder):
+    env = zenith_env_builder.init_start()
+
+    # Disable background compaction and GC, create images at every checkpoint
+    tenant, _ = env.zenith_cli.create_tenant(
+        conf={
+            'gc_period': '10 m',
+            'gc_horizon': '0',
+            'pitr_interval': '0 sec',
+            'compaction_period': '10 m',
+            'image_creation_threshold': '1',
+            'image_layer_excluded_forks': 'fsm,vm',
+        })
+
+    with closing(env.pageserver.connect()) as psconn:
+        with psconn.cursor(cursor_factory=psycopg2.extras.RealDictCursor) as pscur:
+            pscur.execute(f"show {tenant.hex}")
+            assert pscur.fetchone()['image_layer_excluded_forks'] == 'fsm,vm'
+
+    pg = env.postgres.create_start('main', tenant_id=tenant)
+    with closing(pg.connect()) as conn:
+        with conn.cursor() as cur:
+            cur.execute("SHOW neon.timeline_id")
+            timeline = cur.fetchone()[0]
+
+            cur.execute('CREATE TABLE foo (id int, t text) WITH (autovacuum_enabled = off)')
+            cur.execute("INSERT INTO foo SELECT g, 'payload' || g FROM generate_series(1, 100000) g")
+            cur.execute('DELETE FROM foo WHERE id % 2 = 0')
+            # Fills in the FSM and the VM
+            cur.execute('VACUUM foo')
+
+    with closing(env.pageserver.connect()) as psconn:
+        with psconn.cursor(cursor_factory=psycopg2.extras.DictCursor) as pscur:
+            pscur.execute(f"checkpoint {tenant.hex} {timeline}")
+            pscur.execute(f"do_gc {tenant.hex} {timeline} 0")
+            print_gc_result(pscur.fetchone())
+
+    # Start from an empty buffer cache, so that the FSM and VM pages are read
+    # from the page server
+    pg.stop_and_destroy().create_start('main')
+    with closing(pg.connect()) as conn:
+        with conn.cursor() as cur:
+            cur.execute("SELECT count(*) FROM foo")
+            assert cur.fetchone() == (50000, )
+
+            cur.execute("INSERT INTO foo SELECT g, 'again' || g FROM generate_series(1, 1000) g")
+            cur.execute('VACUUM foo')
+            cur.execute("SELECT count(*) FROM foo")
+            assert cur.fetchone() == (51000, )
+
+            cur.execute("SELECT pg_relation_size('foo', 'vm'), pg_relation_size('foo', 'fsm')")
+            vm_size, fsm_size = cur.fetchone()
+            log.info(f'vm size {vm_size}, fsm size {fsm_size}')
+            assert vm_size > 0 and fsm_size > 0
@@ -0,0 +1,60 @@
+import os
+from uuid import uuid4
+
+import pytest
+from fixtures.log_helper import log
+from fixtures.zenith_fixtures import PgBin, ZenithEnv, ZenithPageserverApiException
+
+
+#
+# Create root timelines from the dumps of a database made with pg_dump,
+# in the plain and the custom format.
+#
+def test_import_dump(zenith_simple_env: ZenithEnv, pg_bin: PgBin, test_output_dir):
+    env = zenith_simple_env
+    env.zenith_cli.create_branch('test_import_dump', 'empty')
+    pg = env.postgres.create_start('test_import_dump')
+    pg.safe_psql('CREATE TABLE t(key int primary key, value text)')
+    pg.safe_psql("INSERT INTO t SELECT generate_series(1, 100000), 'payload'")
+
+    client = env.pageserver.http_client()
+    empty_timeline_id = uuid4()
+    client.timeline_create(env.initial_tenant, new_timeline_id=empty_timeline_id)
+    empty_size = client.timeline_detail(
+        env.initial_tenant, empty_timeline_id)['local']['current_logical_size_non_incremental']
+
+    for dump_format in ['plain', 'custom']:
+        dump_path = os.path.join(test_output_dir, f'dump.{dump_format}')
+        pg_bin.run(['pg_dump', f'--format={dump_format}', '-f', dump_path, pg.connstr()])
+
+        timeline_id = uuid4()
+        client.timeline_create(env.initial_tenant,
+                               new_timeline_id=timeline_id,
+                               import_dump_path=dump_path)
+        size = client.timeline_detail(
+            env.initial_tenant, timeline_id)['local']['current_logical_size_non_incremental']
+        log.info(f'{dump_format} dump imported, logical size {size}, empty size {empty_size}')
+        # The table with its index makes a few megabytes
+        assert size > empty_size + 2 * 1024 * 1024
+
+
+#
+# A dump that fails to restore doesn't leave a timeline behind.
+#
+def test_import_dump_failure(zenith_simple_env: ZenithEnv, test_output_dir):
+    env = zenith_simple_env
+    client = env.pageserver.http_client()
+
+    dump_path = os.path.join(test_output_dir, 'broken.sql')
+    with open(dump_path, 'w') as f:
+        f.write('CREATE TABLE t(key int);\nINSERT INTO no_such_table VALUES (1);\n')
+
+    timeline_id = uuid4()
+    with pytest.raises(ZenithPageserverApiException, match='psql failed'):
+        client.timeline_create(env.initial_tenant,
+                               new_timeline_id=timeline_id,
+                               import_dump_path=dump_path)
+    assert timeline_id.hex not in {
+        t['timeline_id']
+        for t in client.timeline_list(env.initial_tenant)
+    }
@@ -0,0 +1,61 @@
+import time
+import uuid
+from contextlib import closing
+
+from fixtures.log_helper import log
+from fixtures.utils import lsn_from_hex
+from fixtures.zenith_fixtures import ZenithEnvBuilder, wait_for_last_record_lsn
+
+
+#
+# Pause the WAL ingest of a timeline, check that the pageserver stays at the
+# LSN it was paused at while the compute keeps writing, and catches up after
+# the ingest is resumed.
+#
+def test_ingest_pause(zenith_env_builder: ZenithEnvBuilder):
+    zenith_env_builder.num_safekeepers = 1
+    env = zenith_env_builder.init_start()
+    client = env.pageserver.http_client()
+
+    env.zenith_cli.create_branch('test_ingest_pause')
+    # Large shared_buffers, so that the compute doesn't read the pages
+    # written while the ingest is paused from the pageserver
+    pg = env.postgres.create_start('test_ingest_pause', config_lines=['shared_buffers=512MB'])
+
+    with closing(pg.connect()) as conn, conn.cursor() as cur:
+        cur.execute("SHOW neon.tenant_id")
+        tenant = uuid.UUID(cur.fetchone()[0])
+        cur.execute("SHOW neon.timeline_id")
+        timeline = uuid.UUID(cur.fetchone()[0])
+
+        cur.execute('CREATE TABLE foo (t text)')
+        cur.execute("INSERT INTO foo SELECT 'before pause' || g FROM generate_series(1, 1000) g")
+        cur.execute('SELECT pg_current_wal_flush_lsn()')
+        wait_for_last_record_lsn(client, tenant, timeline, lsn_from_hex(cur.fetchone()[0]))
+
+        status = client.timeline_pause_ingest(tenant, timeline)
+        log.info(f'paused: {status}')
+        assert status['paused']
+        paused_lsn = lsn_from_hex(status['last_record_lsn'])
+
+        # Pausing again is a no-op
+        assert client.timeline_pause_ingest(tenant, timeline)['paused_since'] == status['paused_since']
+
+        cur.execute("INSERT INTO foo SELECT 'during pause' || g FROM generate_series(1, 1000) g")
+        cur.execute('SELECT pg_current_wal_flush_lsn()')
+        flush_lsn = lsn_from_hex(cur.fetchone()[0])
+        assert flush_lsn > paused_lsn
+
+        # The safekeeper keeps calling back, but no WAL receiver is started
+        time.sleep(3)
+        detail = client.timeline_detail(tenant, timeline)
+        assert lsn_from_hex(detail['local']['last_record_lsn']) == paused_lsn
+        assert len(client.thread_list(tenant, kind='WalReceiver')) == 0
+
+        status = client.timeline_resume_ingest(tenant, timeline)
+        assert not status['paused']
+        assert status['paused_since'] is None
+        wait_for_last_record_lsn(client, tenant, timeline, flush_lsn)
+
+        cur.execute('SELECT count(*) FROM foo')
+        assert cur.fetchone() == (2000, )
@@ -85,9 +85,17 @@ def test_pageserver_http_get_wal_receiver_success(zenith_simple_env: ZenithEnv):
             "wal_producer_connstr",
             "last_received_msg_lsn",
             "last_received_msg_ts",
+            "needs_wal_from",
+            "safekeeper_flush_lsn",
+            "connection_state",
+            "reconnects",
+            "last_connection_error",
         ]
+        assert res["connection_state"] == "streaming"
 
         assert res["last_received_msg_lsn"] is not None, "the last received message's LSN is empty"
+        assert res["needs_wal_from"] is not None, "the streaming start LSN is empty"
+        assert res["safekeeper_flush_lsn"] is not None, "the safekeeper's flush LSN is empty"
 
         last_msg_lsn = lsn_from_hex(res["last_received_msg_lsn"])
         assert prev_msg_lsn is None or prev_msg_lsn < last_msg_lsn, \
@@ -107,6 +115,72 @@ def test_pageserver_http_get_wal_receiver_success(zenith_simple_env: ZenithEnv):
     wait_until(number_of_iterations=5, interval=1, func=lambda: expect_updated_msg_lsn(lsn))
 
 
+def test_pageserver_http_threads(zenith_simple_env: ZenithEnv):
+    env = zenith_simple_env
+    client = env.pageserver.http_client()
+
+    tenant_id, timeline_id = env.zenith_cli.create_tenant()
+    pg = env.postgres.create_start(DEFAULT_BRANCH_NAME, tenant_id=tenant_id)
+    pg.safe_psql("CREATE TABLE t(key int primary key, value text)")
+
+    threads = client.thread_list(tenant_id)
+    kinds = {thread['kind'] for thread in threads}
+    assert {'WalReceiver', 'PageRequestHandler', 'Compactor', 'GarbageCollector'} <= kinds
+    for thread in threads:
+        assert thread['tenant_id'] == tenant_id.hex
+        assert thread['uptime_secs'] >= 0
+
+    wal_receivers = client.thread_list(tenant_id, kind='WalReceiver')
+    assert len(wal_receivers) == 1
+    assert wal_receivers[0]['timeline_id'] == timeline_id.hex
+    assert wal_receivers[0]['idle_secs'] is not None
+
+    # Threads that are not restarted on demand can't be stopped
+    with pytest.raises(ZenithPageserverApiException, match='cannot be shut down'):
+        client.thread_shutdown(tenant_id, 'Compactor')
+
+    stopped = client.thread_shutdown(tenant_id, 'WalReceiver', timeline_id)
+    assert [thread['id'] for thread in stopped] == [wal_receivers[0]['id']]
+
+    # The safekeepers start a new WAL receiver, and the compute keeps working
+    def wal_receiver_restarted():
+        restarted = client.thread_list(tenant_id, kind='WalReceiver')
+        assert len(restarted) == 1
+        assert restarted[0]['id'] != wal_receivers[0]['id']
+
+    wait_until(number_of_iterations=10, interval=1, func=wal_receiver_restarted)
+    pg.safe_psql("INSERT INTO t VALUES (1, 'payload')")
+    assert pg.safe_psql("SELECT count(*) FROM t") == [(1, )]
+
+
+def test_pageserver_page_service_drain(zenith_simple_env: ZenithEnv):
+    env = zenith_simple_env
+    client = env.pageserver.http_client()
+
+    env.zenith_cli.create_branch('test_page_service_drain')
+    pg = env.postgres.create_start('test_page_service_drain')
+    pg.safe_psql("CREATE TABLE t(key int primary key, value text)")
+    assert client.page_service_drain_status()['active_sessions'] >= 1
+
+    client.page_service_drain()
+
+    # The compute's connection is closed after the request it's serving
+    def drained():
+        assert client.page_service_drain_status()['drained']
+
+    wait_until(number_of_iterations=10, interval=1, func=drained)
+
+    # New compute sessions are refused until resumed
+    pg.stop()
+    with pytest.raises(Exception):
+        pg.start()
+
+    status = client.page_service_resume()
+    assert not status['draining']
+    pg.start()
+    assert pg.safe_psql("SELECT count(*) FROM t") == [(0, )]
+
+
 def test_pageserver_http_api_client(zenith_simple_env: ZenithEnv):
     env = zenith_simple_env
     client = env.pageserver.http_client()
@@ -0,0 +1,41 @@
+import pytest
+from fixtures.zenith_fixtures import ZenithEnvBuilder, ZenithPageserverApiException
+from fixtures.utils import lsn_from_hex
+
+
+# Test that the fence LSN covers all the WAL written so far, and that the reads
+# below the read floor are rejected until it's cleared.
+def test_read_floor(zenith_env_builder: ZenithEnvBuilder):
+    env = zenith_env_builder.init_start()
+
+    timeline_id = env.zenith_cli.create_branch('test_read_floor')
+    pg = env.postgres.create_start('test_read_floor')
+    pg.safe_psql('CREATE TABLE foo (t text)')
+    pg.safe_psql("INSERT INTO foo SELECT 'payload' || g FROM generate_series(1, 100) g")
+    old_lsn = pg.safe_psql('SELECT pg_current_wal_insert_lsn()')[0][0]
+    pg.safe_psql("INSERT INTO foo SELECT 'payload' || g FROM generate_series(1, 100) g")
+    current_lsn = pg.safe_psql('SELECT pg_current_wal_flush_lsn()')[0][0]
+    pg.stop()
+
+    tenant_id = env.initial_tenant
+    ps_http = env.pageserver.http_client()
+
+    fence = ps_http.timeline_fence(tenant_id, timeline_id, set_read_floor=True)
+    assert lsn_from_hex(fence['fence_lsn']) >= lsn_from_hex(current_lsn)
+    assert lsn_from_hex(fence['disk_consistent_lsn']) >= lsn_from_hex(fence['fence_lsn'])
+    assert fence['read_floor_lsn'] == fence['fence_lsn']
+
+    # A read-only node at an older LSN can't start
+    with pytest.raises(Exception):
+        env.postgres.create_start('test_read_floor', node_name='test_read_floor_old', lsn=old_lsn)
+
+    # The read floor can't be ahead of the WAL
+    with pytest.raises(ZenithPageserverApiException, match='ahead of the last record LSN'):
+        ps_http.timeline_set_read_floor(tenant_id, timeline_id, 'FF/0')
+
+    cleared = ps_http.timeline_set_read_floor(tenant_id, timeline_id, None)
+    assert cleared['read_floor_lsn'] is None
+    pg_old = env.postgres.create_start('test_read_floor',
+                                       node_name='test_read_floor_old2',
+                                       lsn=old_lsn)
+    assert pg_old.safe_psql('SELECT count(*) FROM foo') == [(100, )]
@@ -0,0 +1,69 @@
+import os
+import shutil
+import uuid
+from contextlib import closing
+
+import pytest
+from fixtures.log_helper import log
+from fixtures.utils import lsn_from_hex
+from fixtures.zenith_fixtures import (ZenithEnvBuilder,
+                                      ZenithPageserverApiException,
+                                      wait_for_last_record_lsn)
+
+
+#
+# Leave a layer file beyond the disk_consistent_lsn of a timeline, as if the
+# pageserver crashed in the middle of a flush, and check that it's set aside
+# when the timeline is loaded, and can be purged.
+#
+def test_set_aside_layers(zenith_env_builder: ZenithEnvBuilder):
+    env = zenith_env_builder.init_start()
+    client = env.pageserver.http_client()
+
+    env.zenith_cli.create_branch('test_set_aside_layers')
+    pg = env.postgres.create_start('test_set_aside_layers')
+
+    with closing(pg.connect()) as conn, conn.cursor() as cur:
+        cur.execute("SHOW neon.tenant_id")
+        tenant = uuid.UUID(cur.fetchone()[0])
+        cur.execute("SHOW neon.timeline_id")
+        timeline = uuid.UUID(cur.fetchone()[0])
+
+        cur.execute('CREATE TABLE foo (t text)')
+        cur.execute("INSERT INTO foo SELECT 'long string to consume some space' || g FROM generate_series(1, 10000) g")
+        cur.execute('SELECT pg_current_wal_flush_lsn()')
+        wait_for_last_record_lsn(client, tenant, timeline, lsn_from_hex(cur.fetchone()[0]))
+    pg.stop()
+    env.pageserver.safe_psql(f"checkpoint {tenant.hex} {timeline.hex}")
+    assert client.timeline_set_aside_layers(tenant, timeline) == []
+
+    env.pageserver.stop()
+
+    # Copy a delta layer under a name with an LSN range far in the future
+    timeline_path = f'{env.repo_dir}/tenants/{tenant.hex}/timelines/{timeline.hex}'
+    delta_name = next(name for name in sorted(os.listdir(timeline_path))
+                      if name.startswith('00000') and name.count('-') == 2)
+    key_range, _ = delta_name.split('__', 1)
+    future_name = f'{key_range}__FFFFFFFF00000000-FFFFFFFF00000010'
+    shutil.copy(os.path.join(timeline_path, delta_name), os.path.join(timeline_path, future_name))
+
+    env.pageserver.start()
+    # Load the timeline
+    client.timeline_doctor(tenant, timeline, sample_pages=0)
+
+    assert not os.path.exists(os.path.join(timeline_path, future_name))
+    set_aside = client.timeline_set_aside_layers(tenant, timeline)
+    log.info(f'set-aside layer files: {set_aside}')
+    assert len(set_aside) == 1
+    assert set_aside[0]['file_name'] == future_name
+    assert set_aside[0]['original_name'] == future_name
+    assert set_aside[0]['layer_start_lsn'] == 'FFFFFFFF/0'
+    assert os.path.exists(os.path.join(timeline_path, 'set_aside_layers', future_name))
+
+    with pytest.raises(ZenithPageserverApiException, match='No set-aside layer file'):
+        client.timeline_set_aside_layers_purge(tenant, timeline, 'no_such_file')
+
+    purged = client.timeline_set_aside_layers_purge(tenant, timeline)
+    assert [file['file_name'] for file in purged] == [future_name]
+    assert client.timeline_set_aside_layers(tenant, timeline) == []
+    assert not os.path.exists(os.path.join(timeline_path, 'set_aside_layers', future_name))
@@ -0,0 +1,95 @@
+import socket
+import struct
+from uuid import UUID
+
+import pytest
+from fixtures.utils import lsn_from_hex
+from fixtures.zenith_fixtures import ZenithEnv, last_record_lsn, wait_for_last_record_lsn
+
+
+class PagestreamClient:
+    """
+    Minimal client of the pagestream protocol of the page service, that only
+    sends 'exists' requests.
+    """
+    def __init__(self, port: int, tenant_id: UUID, timeline_id: UUID, options: str):
+        self.sock = socket.create_connection(('localhost', port), timeout=10)
+        startup = struct.pack('!i', 196608) + b'user\0zenith_admin\0\0'
+        self.sock.sendall(struct.pack('!i', len(startup) + 4) + startup)
+        self._read_until(b'Z')
+
+        query = f"pagestream {tenant_id.hex} {timeline_id.hex} {options}".encode() + b'\0'
+        self._send(b'Q', query)
+        self._read_until(b'W')
+
+    def close(self):
+        self.sock.close()
+
+    def _send(self, tag: bytes, body: bytes):
+        self.sock.sendall(tag + struct.pack('!i', len(body) + 4) + body)
+
+    def _recv_exact(self, n: int) -> bytes:
+        buf = b''
+        while len(buf) < n:
+            chunk = self.sock.recv(n - len(buf))
+            assert chunk, 'connection closed'
+            buf += chunk
+        return buf
+
+    def _read_message(self):
+        header = self._recv_exact(5)
+        (length, ) = struct.unpack('!i', header[1:])
+        return header[:1], self._recv_exact(length - 4)
+
+    def _read_until(self, expected_tag: bytes) -> bytes:
+        while True:
+            tag, body = self._read_message()
+            if tag == b'E':
+                raise Exception(body.decode(errors='replace'))
+            if tag == expected_tag:
+                return body
+
+    def exists(self, lsn: int, spcnode: int, dbnode: int, relnode: int) -> bool:
+        # Tag 0 is 'exists', not for the latest version of the page
+        self._send(b'd', struct.pack('!BBQIIIB', 0, 0, lsn, spcnode, dbnode, relnode, 0))
+        response = self._read_until(b'd')
+        if response[0] == 100:
+            return response[1] != 0
+        assert response[0] == 103, f'unexpected response {response!r}'
+        raise Exception(response[1:-1].decode(errors='replace'))
+
+
+# Test that with a bounded lag, requests ahead of the WAL received are served
+# right away, up to the lag, and fail further ahead.
+def test_stale_reads(zenith_simple_env: ZenithEnv):
+    env = zenith_simple_env
+    env.zenith_cli.create_branch('test_stale_reads', 'empty')
+    pg = env.postgres.create_start('test_stale_reads')
+    tenant_id = UUID(pg.safe_psql("show neon.tenant_id")[0][0])
+    timeline_id = UUID(pg.safe_psql("show neon.timeline_id")[0][0])
+
+    pg.safe_psql("CREATE TABLE t AS SELECT g FROM generate_series(1, 1000) g")
+    dbnode = pg.safe_psql("SELECT oid FROM pg_database WHERE datname = current_database()")[0][0]
+    relnode = pg.safe_psql("SELECT pg_relation_filenode('t')")[0][0]
+    current_lsn = lsn_from_hex(pg.safe_psql("SELECT pg_current_wal_flush_lsn()")[0][0])
+    client = env.pageserver.http_client()
+    wait_for_last_record_lsn(client, tenant_id, timeline_id, current_lsn)
+    # Nothing but the shutdown checkpoint is written on the timeline anymore
+    pg.stop()
+    last_lsn = last_record_lsn(client, tenant_id, timeline_id)
+
+    stream = PagestreamClient(env.pageserver.service_port.pg,
+                              tenant_id,
+                              timeline_id,
+                              'stale_reads=1000000')
+    try:
+        assert stream.exists(last_lsn, 1663, dbnode, relnode)
+        # Served at the last record LSN, without waiting for the WAL that never comes
+        assert stream.exists(last_lsn + 500000, 1663, dbnode, relnode)
+        with pytest.raises(Exception, match='bytes ahead of last record LSN'):
+            stream.exists(last_lsn + 2000000, 1663, dbnode, relnode)
+    finally:
+        stream.close()
+
+    with pytest.raises(Exception, match='invalid stale_reads'):
+        PagestreamClient(env.pageserver.service_port.pg, tenant_id, timeline_id, 'stale_reads=x')
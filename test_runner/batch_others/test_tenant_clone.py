@@ -0,0 +1,53 @@
+import os
+from contextlib import closing
+from uuid import UUID, uuid4
+
+import pytest
+from fixtures.log_helper import log
+from fixtures.utils import lsn_from_hex
+from fixtures.zenith_fixtures import LocalFsStorage, ZenithEnvBuilder, ZenithPageserverApiException, assert_local, wait_for_last_record_lsn, wait_for_upload, wait_until
+
+
+# Test cloning a tenant: the clone gets the source's timelines from the remote
+# storage, with only the index part written for it, not the layer files.
+def test_tenant_clone(zenith_env_builder: ZenithEnvBuilder):
+    zenith_env_builder.enable_local_fs_remote_storage()
+    env = zenith_env_builder.init_start()
+    client = env.pageserver.http_client()
+    pg = env.postgres.create_start('main')
+    tenant_id = UUID(pg.safe_psql("show neon.tenant_id")[0][0])
+    timeline_id = UUID(pg.safe_psql("show neon.timeline_id")[0][0])
+
+    with closing(pg.connect()) as conn:
+        with conn.cursor() as cur:
+            cur.execute("CREATE TABLE t AS SELECT g FROM generate_series(1, 10000) g")
+            cur.execute("SELECT pg_current_wal_flush_lsn()")
+            current_lsn = lsn_from_hex(cur.fetchone()[0])
+    pg.stop()
+
+    wait_for_last_record_lsn(client, tenant_id, timeline_id, current_lsn)
+    env.pageserver.safe_psql(f"checkpoint {tenant_id.hex} {timeline_id.hex}")
+    wait_for_upload(client, tenant_id, timeline_id, current_lsn)
+    source_detail = assert_local(client, tenant_id, timeline_id)
+
+    new_tenant_id = uuid4()
+    assert client.tenant_clone(tenant_id, new_tenant_id) == new_tenant_id
+    with pytest.raises(ZenithPageserverApiException, match='already exists'):
+        client.tenant_clone(tenant_id, new_tenant_id)
+
+    wait_until(number_of_iterations=10,
+               interval=1,
+               func=lambda: assert_local(client, new_tenant_id, timeline_id))
+    clone_detail = client.timeline_detail(new_tenant_id, timeline_id)
+    log.info(f'source timeline: {source_detail}, clone: {clone_detail}')
+    assert clone_detail['local']['disk_consistent_lsn'] == source_detail['local'][
+        'disk_consistent_lsn']
+    assert clone_detail['local']['current_logical_size'] == source_detail['local'][
+        'current_logical_size']
+
+    # The clone refers to the layers of the source tenant
+    assert isinstance(env.remote_storage, LocalFsStorage)
+    remote_tenants_path = env.remote_storage.local_path / 'tenants'
+    clone_files = os.listdir(remote_tenants_path / new_tenant_id.hex / 'timelines' /
+                             timeline_id.hex)
+    assert clone_files and all(f.startswith('index_part') for f in clone_files)
@@ -3,7 +3,7 @@ from contextlib import closing
 import pytest
 import psycopg2.extras
 
-from fixtures.zenith_fixtures import ZenithEnvBuilder
+from fixtures.zenith_fixtures import ZenithEnvBuilder, ZenithPageserverApiException
 from fixtures.log_helper import log
 
 
@@ -109,3 +109,31 @@ tenant_config={checkpoint_distance = 10000, compaction_target_size = 1048576}'''
                     "image_creation_threshold": 3,
                     "pitr_interval": 2592000
                 }.items())
+
+
+def test_tenant_effective_config(zenith_env_builder: ZenithEnvBuilder):
+    zenith_env_builder.pageserver_config_override = "tenant_config={checkpoint_distance = 10000}"
+    env = zenith_env_builder.init_start()
+    client = env.pageserver.http_client()
+
+    tenant, _ = env.zenith_cli.create_tenant(conf={'gc_period': '30sec'})
+
+    conf = client.tenant_config(tenant)
+    log.info(f"effective config: {conf}")
+    assert conf['gc_period'] == {'value': '30s', 'source': 'tenant'}
+    assert conf['checkpoint_distance'] == {'value': 10000, 'source': 'pageserver'}
+    assert conf['logical_size_soft_limit'] == {'value': None, 'source': 'pageserver'}
+
+    # Settings out of range, or not working together, are rejected
+    # and leave the config as it was
+    with pytest.raises(ZenithPageserverApiException, match='compaction_threshold'):
+        client.tenant_config_update(tenant, {'compaction_threshold': 0})
+    with pytest.raises(ZenithPageserverApiException, match='gc_period'):
+        client.tenant_config_update(tenant, {'gc_period': '0 s'})
+    assert client.tenant_config(tenant) == conf
+
+    # A zero GC period is fine once GC is disabled
+    client.tenant_config_update(tenant, {'gc_horizon': 0, 'gc_period': '0 s'})
+    conf = client.tenant_config(tenant)
+    assert conf['gc_horizon'] == {'value': 0, 'source': 'tenant'}
+    assert conf['gc_period'] == {'value': '0s', 'source': 'tenant'}
@@ -0,0 +1,65 @@
+import os
+import shutil
+from contextlib import closing
+from pathlib import Path
+from uuid import UUID
+
+import pytest
+from fixtures.log_helper import log
+from fixtures.utils import lsn_from_hex
+from fixtures.zenith_fixtures import LocalFsStorage, ZenithEnvBuilder, ZenithPageserverApiException, assert_local, wait_for_last_record_lsn, wait_for_upload, wait_until
+
+
+# Test that the layers are uploaded under storage paths with the tenant
+# generation, and that another pageserver downloads them from there.
+def test_tenant_generations(zenith_env_builder: ZenithEnvBuilder):
+    zenith_env_builder.enable_local_fs_remote_storage()
+    env = zenith_env_builder.init_start()
+    client = env.pageserver.http_client()
+    pg = env.postgres.create_start('main')
+    tenant_id = UUID(pg.safe_psql("show neon.tenant_id")[0][0])
+    timeline_id = UUID(pg.safe_psql("show neon.timeline_id")[0][0])
+
+    client.tenant_set_generation(tenant_id, 3)
+    with pytest.raises(ZenithPageserverApiException, match='cannot go backwards'):
+        client.tenant_set_generation(tenant_id, 2)
+
+    with closing(pg.connect()) as conn:
+        with conn.cursor() as cur:
+            cur.execute("CREATE TABLE t AS SELECT g FROM generate_series(1, 10000) g")
+            cur.execute("SELECT pg_current_wal_flush_lsn()")
+            current_lsn = lsn_from_hex(cur.fetchone()[0])
+
+    wait_for_last_record_lsn(client, tenant_id, timeline_id, current_lsn)
+    env.pageserver.safe_psql(f"checkpoint {tenant_id.hex} {timeline_id.hex}")
+    wait_for_upload(client, tenant_id, timeline_id, current_lsn)
+
+    assert isinstance(env.remote_storage, LocalFsStorage)
+    remote_timeline_path = env.remote_storage.local_path / 'tenants' / tenant_id.hex / 'timelines' / timeline_id.hex
+    remote_layers = [
+        f for f in os.listdir(remote_timeline_path) if not f.startswith('index_part')
+    ]
+    log.info(f'remote layers: {remote_layers}')
+    # The layers of the initial checkpoint were uploaded before the tenant got a generation
+    assert any(f.endswith('-g00000003') for f in remote_layers)
+    assert all('-g' not in f or f.endswith('-g00000003') for f in remote_layers)
+
+    # Another pageserver, with no local files, downloads the layers of generation 3
+    env.postgres.stop_all()
+    env.pageserver.stop()
+    dir_to_clear = Path(env.repo_dir) / 'tenants'
+    shutil.rmtree(dir_to_clear)
+    os.mkdir(dir_to_clear)
+    env.pageserver.start()
+
+    client.timeline_attach(tenant_id, timeline_id)
+    wait_until(number_of_iterations=10,
+               interval=1,
+               func=lambda: assert_local(client, tenant_id, timeline_id))
+
+    # The layers get their local names back
+    local_timeline_path = Path(env.repo_dir) / 'tenants' / tenant_id.hex / 'timelines' / timeline_id.hex
+    assert not [f for f in os.listdir(local_timeline_path) if '-g' in f]
+
+    pg = env.postgres.create_start('main')
+    assert pg.safe_psql("SELECT count(*) FROM t") == [(10000, )]
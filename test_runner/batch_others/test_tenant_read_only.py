@@ -0,0 +1,48 @@
+import pytest
+from fixtures.zenith_fixtures import ZenithEnvBuilder, ZenithPageserverApiException, wait_until
+
+
+# Test that persistent layer write errors switch the tenant into read-only
+# mode, that no WAL receiver can be started for it then, and that the operator
+# can clear the state.
+def test_tenant_read_only(zenith_env_builder: ZenithEnvBuilder):
+    zenith_env_builder.num_safekeepers = 1
+    env = zenith_env_builder.init_start()
+    env.zenith_cli.create_branch('test_tenant_read_only')
+    pg = env.postgres.create_start('test_tenant_read_only')
+    tenant_id = env.initial_tenant
+    timeline_id = pg.safe_psql("show neon.timeline_id")[0][0]
+    ps_http = env.pageserver.http_client()
+
+    def tenant_state():
+        return next(t['state'] for t in ps_http.tenant_list() if t['id'] == tenant_id.hex)
+
+    with pytest.raises(ZenithPageserverApiException, match='not in read-only mode'):
+        ps_http.tenant_clear_read_only(tenant_id)
+
+    pg.safe_psql("CREATE TABLE t AS SELECT g FROM generate_series(1, 10000) g")
+
+    # Every flush fails with ENOSPC now, the frozen layer stays and is retried
+    env.pageserver.safe_psql("failpoints layer-flush-write-error=return")
+    for _ in range(3):
+        with pytest.raises(Exception, match='layer-flush-write-error'):
+            env.pageserver.safe_psql(f"checkpoint {tenant_id.hex} {timeline_id}")
+
+    def is_read_only():
+        assert tenant_state() == 'ReadOnly'
+
+    wait_until(10, 1, is_read_only)
+
+    # WAL receivers are refused
+    safekeeper_connstr = f"host=localhost port={env.safekeepers[0].port.pg} options='-c ztimelineid={timeline_id} ztenantid={tenant_id.hex}'"
+    with pytest.raises(Exception, match='read-only mode'):
+        env.pageserver.safe_psql(f"callmemaybe {tenant_id.hex} {timeline_id} {safekeeper_connstr}")
+
+    # Once the storage is fixed, the operator clears the state, and the layer gets flushed
+    env.pageserver.safe_psql("failpoints layer-flush-write-error=off")
+    ps_http.tenant_clear_read_only(tenant_id)
+    assert tenant_state() != 'ReadOnly'
+    env.pageserver.safe_psql(f"checkpoint {tenant_id.hex} {timeline_id}")
+
+    with pytest.raises(ZenithPageserverApiException, match='not in read-only mode'):
+        ps_http.tenant_clear_read_only(tenant_id)
@@ -110,6 +110,8 @@ def test_tenant_relocation(zenith_env_builder: ZenithEnvBuilder,
                            port_distributor: PortDistributor,
                            with_load: str):
     zenith_env_builder.enable_local_fs_remote_storage()
+    # the local files are checked to be gone right after the detach
+    zenith_env_builder.pageserver_config_override = "detach_grace_period='0 s'"
 
     env = zenith_env_builder.init_start()
 
@@ -0,0 +1,69 @@
+from contextlib import closing
+
+import psycopg2.extras
+from fixtures.log_helper import log
+from fixtures.utils import lsn_from_hex, print_gc_result
+from fixtures.zenith_fixtures import ZenithEnvBuilder, assert_local, wait_for_last_record_lsn, wait_for_upload, wait_until
+
+
+#
+# Test that an archived branch has no local state but is still listed, that GC
+# keeps the data of its ancestor at the branch point, and that the branch can
+# be read again after it's unarchived.
+#
+def test_timeline_archive(zenith_env_builder: ZenithEnvBuilder):
+    zenith_env_builder.enable_local_fs_remote_storage()
+    zenith_env_builder.pageserver_config_override = "tenant_config={pitr_interval = '0 sec'}"
+    env = zenith_env_builder.init_start()
+    tenant_id = env.initial_tenant
+    ps_http = env.pageserver.http_client()
+
+    pg_main = env.postgres.create_start('main')
+    main_timeline_id = pg_main.safe_psql("show neon.timeline_id")[0][0]
+    pg_main.safe_psql(
+        "CREATE TABLE foo AS SELECT g, 'before branch' || g AS t FROM generate_series(1, 10000) g")
+
+    timeline_id = env.zenith_cli.create_branch('test_timeline_archive', 'main')
+    pg = env.postgres.create_start('test_timeline_archive')
+    pg.safe_psql("CREATE TABLE bar AS SELECT 'on branch' || g AS t FROM generate_series(1, 1000) g")
+    flush_lsn = lsn_from_hex(pg.safe_psql("SELECT pg_current_wal_flush_lsn()")[0][0])
+    pg.stop()
+
+    wait_for_last_record_lsn(ps_http, tenant_id, timeline_id, flush_lsn)
+    env.pageserver.safe_psql(f"checkpoint {tenant_id.hex} {timeline_id.hex}")
+    wait_for_upload(ps_http, tenant_id, timeline_id, flush_lsn)
+    branch_lsn = ps_http.timeline_detail(tenant_id, timeline_id)['local']['ancestor_lsn']
+
+    ps_http.timeline_archive(tenant_id, timeline_id)
+
+    timeline_path = env.repo_dir / 'tenants' / tenant_id.hex / 'timelines' / timeline_id.hex
+    assert [p.name for p in timeline_path.iterdir()] == ['archived']
+    archived = [t for t in ps_http.timeline_list(tenant_id) if t['timeline_id'] == timeline_id.hex]
+    assert len(archived) == 1
+    assert archived[0]['local'] is None
+    assert archived[0]['remote']['archived']
+
+    # Overwrite the data on main, so that GC could remove the versions at the
+    # branch point, if it didn't keep them for the archived branch
+    pg_main.safe_psql("UPDATE foo SET t = 'after branch' || g")
+    pg_main.safe_psql("VACUUM foo")
+    with closing(env.pageserver.connect()) as psconn:
+        with psconn.cursor(cursor_factory=psycopg2.extras.DictCursor) as pscur:
+            pscur.execute(f"checkpoint {tenant_id.hex} {main_timeline_id}")
+            pscur.execute(f"do_gc {tenant_id.hex} {main_timeline_id} 0")
+            row = pscur.fetchone()
+            print_gc_result(row)
+    report = ps_http.gc_space_report(tenant_id, main_timeline_id)
+    log.info(f'GC space report: {report}')
+    assert branch_lsn in report['retain_lsns']
+    assert row['bytes_needed_by_branches'] > 0
+
+    ps_http.timeline_unarchive(tenant_id, timeline_id)
+    wait_until(number_of_iterations=10,
+               interval=1,
+               func=lambda: assert_local(ps_http, tenant_id, timeline_id))
+    assert not ps_http.timeline_detail(tenant_id, timeline_id)['remote']['archived']
+
+    pg.start()
+    assert pg.safe_psql("SELECT count(*) FROM foo WHERE t LIKE 'before branch%'") == [(10000, )]
+    assert pg.safe_psql("SELECT count(*) FROM bar") == [(1000, )]
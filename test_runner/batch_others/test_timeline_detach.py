@@ -0,0 +1,58 @@
+import pytest
+from fixtures.zenith_fixtures import ZenithEnvBuilder, ZenithPageserverApiException
+
+
+# Test that a detached timeline keeps its files for the grace period, and can be
+# reattached from them, also after a pageserver restart.
+def test_timeline_detach_undo(zenith_env_builder: ZenithEnvBuilder):
+    env = zenith_env_builder.init_start()
+
+    timeline_id = env.zenith_cli.create_branch('test_timeline_detach_undo')
+    pg = env.postgres.create_start('test_timeline_detach_undo')
+    pg.safe_psql("CREATE TABLE foo AS SELECT 'payload' || g AS t FROM generate_series(1, 10000) g")
+    pg.stop()
+
+    tenant_id = env.initial_tenant
+    timeline_path = env.repo_dir / 'tenants' / tenant_id.hex / 'timelines' / timeline_id.hex
+    ps_http = env.pageserver.http_client()
+
+    def detach():
+        ps_http.timeline_detach(tenant_id, timeline_id)
+        with pytest.raises(ZenithPageserverApiException):
+            ps_http.timeline_detail(tenant_id, timeline_id)
+        assert (timeline_path / 'detached').exists()
+        assert (timeline_path / 'metadata').exists()
+
+    def check_data():
+        pg.start()
+        assert pg.safe_psql('SELECT count(*) FROM foo') == [(10000, )]
+        pg.stop()
+
+    detach()
+    ps_http.timeline_attach(tenant_id, timeline_id)
+    assert not (timeline_path / 'detached').exists()
+    check_data()
+
+    # The detach is remembered over a restart
+    detach()
+    env.pageserver.stop()
+    env.pageserver.start()
+    with pytest.raises(ZenithPageserverApiException):
+        ps_http.timeline_detail(tenant_id, timeline_id)
+    assert (timeline_path / 'detached').exists()
+
+    ps_http.timeline_attach(tenant_id, timeline_id)
+    check_data()
+
+
+# Test that without a grace period, detach removes the files at once
+def test_timeline_detach_no_grace_period(zenith_env_builder: ZenithEnvBuilder):
+    zenith_env_builder.pageserver_config_override = "detach_grace_period='0 s'"
+    env = zenith_env_builder.init_start()
+
+    timeline_id = env.zenith_cli.create_branch('test_timeline_detach_no_grace_period')
+    timeline_path = env.repo_dir / 'tenants' / env.initial_tenant.hex / 'timelines' / timeline_id.hex
+    assert timeline_path.exists()
+
+    env.pageserver.http_client().timeline_detach(env.initial_tenant, timeline_id)
+    assert not timeline_path.exists()
@@ -0,0 +1,62 @@
+import os
+import uuid
+from contextlib import closing
+
+from fixtures.log_helper import log
+from fixtures.utils import lsn_from_hex
+from fixtures.zenith_fixtures import ZenithEnvBuilder, wait_for_last_record_lsn
+
+
+def check_status(report, name):
+    return next(check['status'] for check in report['checks'] if check['name'] == name)
+
+
+#
+# Run the timeline doctor on a healthy timeline, then remove its layer files
+# and check that the layer map check fails.
+#
+def test_timeline_doctor(zenith_env_builder: ZenithEnvBuilder):
+    zenith_env_builder.num_safekeepers = 1
+    env = zenith_env_builder.init_start()
+    client = env.pageserver.http_client()
+
+    env.zenith_cli.create_branch('test_timeline_doctor')
+    pg = env.postgres.create_start('test_timeline_doctor')
+
+    with closing(pg.connect()) as conn, conn.cursor() as cur:
+        cur.execute("SHOW neon.tenant_id")
+        tenant = uuid.UUID(cur.fetchone()[0])
+        cur.execute("SHOW neon.timeline_id")
+        timeline = uuid.UUID(cur.fetchone()[0])
+
+        cur.execute('CREATE TABLE foo (t text)')
+        cur.execute("INSERT INTO foo SELECT 'long string to consume some space' || g FROM generate_series(1, 10000) g")
+        cur.execute('SELECT pg_current_wal_flush_lsn()')
+        wait_for_last_record_lsn(client, tenant, timeline, lsn_from_hex(cur.fetchone()[0]))
+    pg.stop()
+    env.pageserver.safe_psql(f"checkpoint {tenant.hex} {timeline.hex}")
+
+    report = client.timeline_doctor(tenant, timeline)
+    log.info(f'report: {report}')
+    assert report['status'] == 'ok'
+    assert [check['name'] for check in report['checks']] == [
+        'metadata', 'layer_map', 'ancestors', 'remote_index', 'page_reconstruction'
+    ]
+
+    # The parent of the branch is examined too
+    assert check_status(report, 'ancestors') == 'ok'
+
+    # Lose the layer files of the branch
+    timeline_path = f'{env.repo_dir}/tenants/{tenant.hex}/timelines/{timeline.hex}'
+    for filename in os.listdir(timeline_path):
+        if filename.startswith('00000'):
+            os.remove(os.path.join(timeline_path, filename))
+
+    report = client.timeline_doctor(tenant, timeline, sample_pages=0)
+    log.info(f'report after removing the layers: {report}')
+    assert report['status'] == 'failed'
+    assert check_status(report, 'metadata') == 'ok'
+    layer_map = next(check for check in report['checks'] if check['name'] == 'layer_map')
+    assert layer_map['status'] == 'failed'
+    assert any('is missing' in finding for finding in layer_map['findings'])
+    assert len(layer_map['remediations']) > 0
@@ -0,0 +1,57 @@
+import os
+from pathlib import Path
+from uuid import UUID
+
+import pytest
+from fixtures.utils import lsn_from_hex, lsn_to_hex
+from fixtures.zenith_fixtures import ZenithEnv, ZenithPageserverApiException, wait_for_last_record_lsn
+
+
+# Test creating image layers of a timeline on request, for a relation and for
+# the whole timeline.
+def test_timeline_materialize(zenith_simple_env: ZenithEnv):
+    env = zenith_simple_env
+    env.zenith_cli.create_branch('test_timeline_materialize', 'empty')
+    pg = env.postgres.create_start('test_timeline_materialize')
+    tenant_id = UUID(pg.safe_psql("show neon.tenant_id")[0][0])
+    timeline_id = UUID(pg.safe_psql("show neon.timeline_id")[0][0])
+    client = env.pageserver.http_client()
+
+    pg.safe_psql("CREATE TABLE t AS SELECT g FROM generate_series(1, 10000) g")
+    dbnode = pg.safe_psql("SELECT oid FROM pg_database WHERE datname = current_database()")[0][0]
+    relnode = pg.safe_psql("SELECT pg_relation_filenode('t')")[0][0]
+    current_lsn = lsn_from_hex(pg.safe_psql("SELECT pg_current_wal_flush_lsn()")[0][0])
+    wait_for_last_record_lsn(client, tenant_id, timeline_id, current_lsn)
+
+    timeline_path = Path(env.repo_dir) / 'tenants' / tenant_id.hex / 'timelines' / timeline_id.hex
+
+    def layer_files():
+        return set(f for f in os.listdir(timeline_path) if '__' in f)
+
+    layers_before = layer_files()
+    info = client.timeline_materialize(tenant_id,
+                                       timeline_id,
+                                       lsn=lsn_to_hex(current_lsn),
+                                       relations=[{
+                                           'spcnode': 1663,
+                                           'dbnode': dbnode,
+                                           'relnode': relnode,
+                                           'forknum': 0,
+                                       }])
+    assert lsn_from_hex(info['lsn']) == current_lsn
+    assert info['image_layers_created'] > 0
+    assert info['image_layer_bytes'] > 0
+    assert len(layer_files() - layers_before) == info['image_layers_created']
+
+    # The whole timeline, at the last record LSN
+    info = client.timeline_materialize(tenant_id, timeline_id)
+    assert lsn_from_hex(info['lsn']) >= current_lsn
+    assert info['image_layers_created'] > 0
+
+    with pytest.raises(ZenithPageserverApiException, match='beyond the last record LSN'):
+        client.timeline_materialize(tenant_id, timeline_id, lsn=lsn_to_hex(current_lsn + 0x1000000))
+
+    # The pages are read from the new image layers
+    pg.stop()
+    pg.start()
+    assert pg.safe_psql("SELECT count(*) FROM t") == [(10000, )]
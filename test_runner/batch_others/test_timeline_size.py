@@ -1,7 +1,8 @@
 from contextlib import closing
 import psycopg2.extras
 import psycopg2.errors
-from fixtures.zenith_fixtures import ZenithEnv, ZenithEnvBuilder, Postgres, assert_local
+from fixtures.zenith_fixtures import ZenithEnv, ZenithEnvBuilder, Postgres, assert_local, wait_for_last_record_lsn
+from fixtures.utils import lsn_from_hex
 from fixtures.log_helper import log
 import time
 
@@ -69,9 +70,9 @@ def wait_for_pageserver_catchup(pgmain: Postgres, polling_interval=1, timeout=60
         time.sleep(polling_interval)
 
 
-def test_timeline_size_quota(zenith_env_builder: ZenithEnvBuilder):
+def test_timeline_size_soft_limit(zenith_env_builder: ZenithEnvBuilder):
     env = zenith_env_builder.init_start()
-    new_timeline_id = env.zenith_cli.create_branch('test_timeline_size_quota')
+    new_timeline_id = env.zenith_cli.create_branch('test_timeline_size_soft_limit')
 
     client = env.pageserver.http_client()
     res = assert_local(client, env.initial_tenant, new_timeline_id)
@@ -79,10 +80,10 @@ def test_timeline_size_quota(zenith_env_builder: ZenithEnvBuilder):
         "current_logical_size_non_incremental"]
 
     pgmain = env.postgres.create_start(
-        "test_timeline_size_quota",
+        "test_timeline_size_soft_limit",
         # Set small limit for the test
         config_lines=['neon.max_cluster_size=30MB'])
-    log.info("postgres is running on 'test_timeline_size_quota' branch")
+    log.info("postgres is running on 'test_timeline_size_soft_limit' branch")
 
     with closing(pgmain.connect()) as conn:
         with conn.cursor() as cur:
@@ -133,3 +134,42 @@ def test_timeline_size_quota(zenith_env_builder: ZenithEnvBuilder):
             cur.execute("SELECT * from pg_size_pretty(pg_cluster_size())")
             pg_cluster_size = cur.fetchone()
             log.info(f"pg_cluster_size = {pg_cluster_size}")
+
+
+# Test that the pageserver ingests all the WAL of a timeline over its logical
+# size soft limit, and only reports the timeline over it.
+def test_logical_size_soft_limit_ingests_wal(zenith_env_builder: ZenithEnvBuilder):
+    env = zenith_env_builder.init_start()
+    tenant, _ = env.zenith_cli.create_tenant()
+    timeline = env.zenith_cli.create_timeline('test_logical_size_soft_limit', tenant_id=tenant)
+    client = env.pageserver.http_client()
+
+    initial_size = assert_local(client, tenant, timeline)['local']['current_logical_size']
+    limit = initial_size + 5 * 1024 * 1024
+    env.zenith_cli.config_tenant(tenant, {'logical_size_soft_limit': str(limit)})
+
+    pg = env.postgres.create_start('test_logical_size_soft_limit', tenant_id=tenant)
+
+    def wait_for_catchup():
+        current_lsn = lsn_from_hex(pg.safe_psql("SELECT pg_current_wal_flush_lsn()")[0][0])
+        wait_for_last_record_lsn(client, tenant, timeline, current_lsn)
+        return assert_local(client, tenant, timeline)['local']
+
+    pg.safe_psql("CREATE TABLE foo (t text)")
+    pg.safe_psql('''
+        INSERT INTO foo
+            SELECT 'long string to consume some space' || g
+            FROM generate_series(1, 300000) g
+    ''')
+
+    # All the WAL gets ingested, the timeline is only reported over its soft limit
+    timeline_info = wait_for_catchup()
+    assert timeline_info['current_logical_size'] > limit
+    assert timeline_info['size_soft_limit_exceeded']
+    assert pg.safe_psql("SELECT count(*) FROM foo")[0][0] == 300000
+
+    # Dropping the table gets the timeline back under the soft limit
+    pg.safe_psql("DROP TABLE foo")
+    timeline_info = wait_for_catchup()
+    assert timeline_info['current_logical_size'] < limit
+    assert not timeline_info['size_soft_limit_exceeded']
@@ -0,0 +1,65 @@
+from contextlib import closing
+from uuid import UUID
+
+import pytest
+from fixtures.utils import lsn_from_hex, lsn_to_hex
+from fixtures.zenith_fixtures import ZenithEnv, ZenithEnvBuilder, ZenithPageserverApiException, remote_consistent_lsn
+
+
+def insert_rows(pg) -> int:
+    with closing(pg.connect()) as conn:
+        with conn.cursor() as cur:
+            cur.execute("CREATE TABLE t AS SELECT g FROM generate_series(1, 10000) g")
+            cur.execute("SELECT pg_current_wal_flush_lsn()")
+            return lsn_from_hex(cur.fetchone()[0])
+
+
+# Test waiting for an LSN to be flushed to disk, and uploaded.
+def test_timeline_wait_durable(zenith_env_builder: ZenithEnvBuilder):
+    zenith_env_builder.enable_local_fs_remote_storage()
+    env = zenith_env_builder.init_start()
+    client = env.pageserver.http_client()
+    pg = env.postgres.create_start('main')
+    tenant_id = UUID(pg.safe_psql("show neon.tenant_id")[0][0])
+    timeline_id = UUID(pg.safe_psql("show neon.timeline_id")[0][0])
+
+    current_lsn = insert_rows(pg)
+    info = client.timeline_wait_durable(tenant_id, timeline_id, lsn_to_hex(current_lsn))
+    assert lsn_from_hex(info['lsn']) == current_lsn
+    assert lsn_from_hex(info['disk_consistent_lsn']) >= current_lsn
+    assert info['remote_consistent_lsn'] is None
+
+    pg.safe_psql("INSERT INTO t SELECT g FROM generate_series(1, 10000) g")
+    current_lsn = lsn_from_hex(pg.safe_psql("SELECT pg_current_wal_flush_lsn()")[0][0])
+    info = client.timeline_wait_durable(tenant_id,
+                                        timeline_id,
+                                        lsn_to_hex(current_lsn),
+                                        scope='remote',
+                                        timeout='30s')
+    assert lsn_from_hex(info['disk_consistent_lsn']) >= current_lsn
+    assert lsn_from_hex(info['remote_consistent_lsn']) >= current_lsn
+    assert remote_consistent_lsn(client, tenant_id, timeline_id) >= current_lsn
+
+    with pytest.raises(ZenithPageserverApiException, match='unknown durability scope'):
+        client.timeline_wait_durable(tenant_id,
+                                     timeline_id,
+                                     lsn_to_hex(current_lsn),
+                                     scope='everywhere')
+
+
+def test_timeline_wait_durable_without_remote_storage(zenith_simple_env: ZenithEnv):
+    env = zenith_simple_env
+    env.zenith_cli.create_branch('test_timeline_wait_durable', 'empty')
+    pg = env.postgres.create_start('test_timeline_wait_durable')
+    tenant_id = UUID(pg.safe_psql("show neon.tenant_id")[0][0])
+    timeline_id = UUID(pg.safe_psql("show neon.timeline_id")[0][0])
+    client = env.pageserver.http_client()
+
+    current_lsn = insert_rows(pg)
+    with pytest.raises(ZenithPageserverApiException, match='without remote storage configured'):
+        client.timeline_wait_durable(tenant_id,
+                                     timeline_id,
+                                     lsn_to_hex(current_lsn),
+                                     scope='remote')
+    info = client.timeline_wait_durable(tenant_id, timeline_id, lsn_to_hex(current_lsn))
+    assert lsn_from_hex(info['disk_consistent_lsn']) >= current_lsn
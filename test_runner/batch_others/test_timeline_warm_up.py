@@ -0,0 +1,63 @@
+import os
+import shutil
+from contextlib import closing
+from pathlib import Path
+from uuid import UUID
+
+import pytest
+from fixtures.utils import lsn_from_hex
+from fixtures.zenith_fixtures import ZenithEnvBuilder, ZenithPageserverApiException, assert_local, wait_for_last_record_lsn, wait_for_upload, wait_until
+
+
+# Test warming up a local timeline, and one that has to be downloaded first.
+def test_timeline_warm_up(zenith_env_builder: ZenithEnvBuilder):
+    zenith_env_builder.enable_local_fs_remote_storage()
+    env = zenith_env_builder.init_start()
+    client = env.pageserver.http_client()
+    pg = env.postgres.create_start('main')
+    tenant_id = UUID(pg.safe_psql("show neon.tenant_id")[0][0])
+    timeline_id = UUID(pg.safe_psql("show neon.timeline_id")[0][0])
+
+    with closing(pg.connect()) as conn:
+        with conn.cursor() as cur:
+            cur.execute("CREATE TABLE t AS SELECT g FROM generate_series(1, 10000) g")
+            cur.execute("SELECT pg_current_wal_flush_lsn()")
+            current_lsn = lsn_from_hex(cur.fetchone()[0])
+
+    wait_for_last_record_lsn(client, tenant_id, timeline_id, current_lsn)
+    env.pageserver.safe_psql(f"checkpoint {tenant_id.hex} {timeline_id.hex}")
+    wait_for_upload(client, tenant_id, timeline_id, current_lsn)
+
+    warm_up_info = client.timeline_warm_up(tenant_id, timeline_id)
+    assert warm_up_info is not None
+    assert lsn_from_hex(warm_up_info['lsn']) >= current_lsn
+    assert warm_up_info['layers_read'] > 0
+    assert warm_up_info['layer_bytes_read'] > 0
+    assert warm_up_info['pages_materialized'] > 0
+
+    warm_up_info = client.timeline_warm_up(tenant_id, timeline_id, materialize_pages=0)
+    assert warm_up_info is not None
+    assert warm_up_info['pages_materialized'] == 0
+
+    with pytest.raises(ZenithPageserverApiException, match='Invalid lsn'):
+        client.timeline_warm_up(tenant_id, timeline_id, lsn='not an lsn')
+
+    # Without the local files, the warm-up schedules the download of the timeline
+    env.postgres.stop_all()
+    env.pageserver.stop()
+    dir_to_clear = Path(env.repo_dir) / 'tenants'
+    shutil.rmtree(dir_to_clear)
+    os.mkdir(dir_to_clear)
+    env.pageserver.start()
+
+    assert client.timeline_warm_up(tenant_id, timeline_id) is None
+    wait_until(number_of_iterations=10,
+               interval=1,
+               func=lambda: assert_local(client, tenant_id, timeline_id))
+
+    warm_up_info = client.timeline_warm_up(tenant_id, timeline_id)
+    assert warm_up_info is not None
+    assert warm_up_info['layers_read'] > 0
+
+    pg = env.postgres.create_start('main')
+    assert pg.safe_psql("SELECT count(*) FROM t") == [(10000, )]
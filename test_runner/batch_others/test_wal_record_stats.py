@@ -0,0 +1,37 @@
+from fixtures.zenith_fixtures import ZenithEnvBuilder, wait_until
+
+
+# Test the per-record-type WAL stats of a timeline, and that its metrics go
+# away when the timeline is detached.
+def test_wal_record_stats(zenith_env_builder: ZenithEnvBuilder):
+    env = zenith_env_builder.init_start()
+    tenant_id = env.initial_tenant
+    timeline_id = env.zenith_cli.create_branch('test_wal_record_stats')
+    pg = env.postgres.create_start('test_wal_record_stats')
+    ps_http = env.pageserver.http_client()
+
+    pg.safe_psql("CREATE TABLE t AS SELECT g FROM generate_series(1, 10000) g")
+    pg.safe_psql("INSERT INTO t SELECT g FROM generate_series(1, 10000) g")
+
+    def heap_records():
+        stats = ps_http.wal_record_stats(tenant_id, timeline_id)
+        # Largest first
+        assert [s['bytes'] for s in stats] == sorted([s['bytes'] for s in stats], reverse=True)
+        heap = [s for s in stats if s['rmgr'] == 'Heap']
+        assert sum(s['records'] for s in heap) >= 10000
+
+    wait_until(20, 0.5, heap_records)
+
+    metric = f'pageserver_wal_records_ingested_total{{rmgr="Heap",tenant_id="{tenant_id.hex}",timeline_id="{timeline_id.hex}"}}'
+    assert metric in ps_http.get_metrics()
+
+    pg.stop()
+    ps_http.timeline_detach(tenant_id, timeline_id)
+
+    def metric_removed():
+        assert not [
+            line for line in ps_http.get_metrics().splitlines()
+            if line.startswith('pageserver_wal_record') and timeline_id.hex in line
+        ]
+
+    wait_until(20, 0.5, metric_removed)
@@ -0,0 +1,57 @@
+import pytest
+from fixtures.zenith_fixtures import ZenithEnv, ZenithEnvBuilder
+from fixtures.metrics import parse_metrics
+from fixtures.log_helper import log
+
+
+def wal_redo_counters(env: ZenithEnv):
+    metrics = parse_metrics(env.pageserver.http_client().get_metrics(), "pageserver")
+    labels = {'tenant_id': env.initial_tenant.hex}
+    timeouts = metrics.query_one('pageserver_wal_redo_timeouts_total', labels).value
+    launches = metrics.query_one('pageserver_wal_redo_process_launches_total', labels).value
+    return int(timeouts), int(launches)
+
+
+#
+# A timed out WAL redo request is retried once with a new WAL redo process,
+# a second timeout is returned as an error.
+#
+def test_wal_redo_timeout(zenith_env_builder: ZenithEnvBuilder):
+    zenith_env_builder.pageserver_config_override = "wal_redo_timeout='2s'"
+    env = zenith_env_builder.init_start()
+
+    env.zenith_cli.create_branch('test_wal_redo_timeout', 'main')
+    pg = env.postgres.create_start('test_wal_redo_timeout')
+
+    def create_table(name: str):
+        pg.safe_psql(f'CREATE TABLE {name} (x int)')
+        pg.safe_psql(f'INSERT INTO {name} VALUES (generate_series(1, 1000))')
+        # Restart the compute, so that the pages of the table are read back from
+        # the page server, which reconstructs them with the WAL redo process.
+        pg.stop()
+        pg.start()
+
+    # Reading the catalog at startup has launched the WAL redo process already.
+    create_table('foo')
+    assert pg.safe_psql('SELECT count(*) FROM foo')[0][0] == 1000
+    timeouts, launches = wal_redo_counters(env)
+    assert launches >= 1
+
+    # The first attempt times out, the retry with a new process succeeds.
+    create_table('bar')
+    env.pageserver.safe_psql('failpoints wal-redo-before-poll=1*sleep(4000)')
+    assert pg.safe_psql('SELECT count(*) FROM bar')[0][0] == 1000
+    assert wal_redo_counters(env) == (timeouts + 1, launches + 1)
+    timeouts, launches = timeouts + 1, launches + 1
+
+    # Both attempts time out, the request fails.
+    create_table('baz')
+    env.pageserver.safe_psql('failpoints wal-redo-before-poll=2*sleep(4000)')
+    with pytest.raises(Exception):
+        pg.safe_psql('SELECT count(*) FROM baz')
+    assert wal_redo_counters(env) == (timeouts + 2, launches + 1)
+    log.info('second WAL redo timeout was returned as an error')
+
+    # Without the failpoint, a new process serves the table again.
+    env.pageserver.safe_psql('failpoints wal-redo-before-poll=off')
+    assert pg.safe_psql('SELECT count(*) FROM baz')[0][0] == 1000
@@ -77,7 +77,11 @@ def print_gc_result(row):
     log.info("GC duration {elapsed} ms".format_map(row))
     log.info(
         "  total: {layers_total}, needed_by_cutoff {layers_needed_by_cutoff}, needed_by_pitr {layers_needed_by_pitr}"
-        " needed_by_branches: {layers_needed_by_branches}, not_updated: {layers_not_updated}, removed: {layers_removed}"
+        " needed_by_branches: {layers_needed_by_branches}, not_updated: {layers_not_updated}, too_recent: {layers_too_recent}, needed_by_policy: {layers_needed_by_policy}, removed: {layers_removed}"
+        .format_map(row))
+    log.info(
+        "  bytes needed_by_cutoff {bytes_needed_by_cutoff}, needed_by_pitr {bytes_needed_by_pitr}"
+        " needed_by_branches: {bytes_needed_by_branches}, not_updated: {bytes_not_updated}, too_recent: {bytes_too_recent}, needed_by_policy: {bytes_needed_by_policy}, removed: {bytes_removed}"
         .format_map(row))
 
 
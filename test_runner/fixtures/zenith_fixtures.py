@@ -789,12 +789,32 @@ class ZenithPageserverHttpClient(requests.Session):
         )
         self.verbose_error(res)
 
+    def timeline_archive(self, tenant_id: uuid.UUID, timeline_id: uuid.UUID):
+        res = self.post(
+            f"http://localhost:{self.port}/v1/tenant/{tenant_id.hex}/timeline/{timeline_id.hex}/archive",
+        )
+        self.verbose_error(res)
+
+    def timeline_unarchive(self, tenant_id: uuid.UUID, timeline_id: uuid.UUID):
+        res = self.post(
+            f"http://localhost:{self.port}/v1/tenant/{tenant_id.hex}/timeline/{timeline_id.hex}/unarchive",
+        )
+        self.verbose_error(res)
+
+    def tenant_set_generation(self, tenant_id: uuid.UUID, generation: int):
+        res = self.put(
+            f"http://localhost:{self.port}/v1/tenant/{tenant_id.hex}/generation",
+            json={'generation': generation},
+        )
+        self.verbose_error(res)
+
     def timeline_create(
         self,
         tenant_id: uuid.UUID,
         new_timeline_id: Optional[uuid.UUID] = None,
         ancestor_timeline_id: Optional[uuid.UUID] = None,
         ancestor_start_lsn: Optional[str] = None,
+        import_dump_path: Optional[str] = None,
     ) -> Dict[Any, Any]:
         res = self.post(f"http://localhost:{self.port}/v1/tenant/{tenant_id.hex}/timeline",
                         json={
@@ -804,6 +824,8 @@ class ZenithPageserverHttpClient(requests.Session):
                             ancestor_start_lsn,
                             'ancestor_timeline_id':
                             ancestor_timeline_id.hex if ancestor_timeline_id else None,
+                            'import_dump_path':
+                            import_dump_path,
                         })
         self.verbose_error(res)
         if res.status_code == 409:
@@ -813,6 +835,66 @@ class ZenithPageserverHttpClient(requests.Session):
         assert isinstance(res_json, dict)
         return res_json
 
+    def timeline_create_batch(
+        self,
+        tenant_id: uuid.UUID,
+        ancestor_timeline_id: uuid.UUID,
+        new_timeline_ids: List[uuid.UUID],
+        ancestor_start_lsn: Optional[str] = None,
+    ) -> Dict[Any, Any]:
+        res = self.post(f"http://localhost:{self.port}/v1/tenant/{tenant_id.hex}/timeline/batch",
+                        json={
+                            'ancestor_timeline_id': ancestor_timeline_id.hex,
+                            'ancestor_start_lsn': ancestor_start_lsn,
+                            'new_timeline_ids': [t.hex for t in new_timeline_ids],
+                        })
+        self.verbose_error(res)
+        res_json = res.json()
+        assert isinstance(res_json, dict)
+        return res_json
+
+    def gc_status(self) -> Dict[Any, Any]:
+        res = self.get(f"http://localhost:{self.port}/v1/gc")
+        self.verbose_error(res)
+        res_json = res.json()
+        assert isinstance(res_json, dict)
+        return res_json
+
+    def gc_pause(self) -> Dict[Any, Any]:
+        res = self.post(f"http://localhost:{self.port}/v1/gc/pause")
+        self.verbose_error(res)
+        res_json = res.json()
+        assert isinstance(res_json, dict)
+        return res_json
+
+    def gc_resume(self) -> Dict[Any, Any]:
+        res = self.post(f"http://localhost:{self.port}/v1/gc/resume")
+        self.verbose_error(res)
+        res_json = res.json()
+        assert isinstance(res_json, dict)
+        return res_json
+
+    def page_service_drain_status(self) -> Dict[Any, Any]:
+        res = self.get(f"http://localhost:{self.port}/v1/page_service/drain")
+        self.verbose_error(res)
+        res_json = res.json()
+        assert isinstance(res_json, dict)
+        return res_json
+
+    def page_service_drain(self) -> Dict[Any, Any]:
+        res = self.post(f"http://localhost:{self.port}/v1/page_service/drain")
+        self.verbose_error(res)
+        res_json = res.json()
+        assert isinstance(res_json, dict)
+        return res_json
+
+    def page_service_resume(self) -> Dict[Any, Any]:
+        res = self.post(f"http://localhost:{self.port}/v1/page_service/resume")
+        self.verbose_error(res)
+        res_json = res.json()
+        assert isinstance(res_json, dict)
+        return res_json
+
     def tenant_list(self) -> List[Dict[Any, Any]]:
         res = self.get(f"http://localhost:{self.port}/v1/tenant")
         self.verbose_error(res)
@@ -820,6 +902,10 @@ class ZenithPageserverHttpClient(requests.Session):
         assert isinstance(res_json, list)
         return res_json
 
+    def tenant_clear_read_only(self, tenant_id: uuid.UUID):
+        res = self.post(f"http://localhost:{self.port}/v1/tenant/{tenant_id.hex}/clear_read_only")
+        self.verbose_error(res)
+
     def tenant_create(self, new_tenant_id: Optional[uuid.UUID] = None) -> uuid.UUID:
         res = self.post(
             f"http://localhost:{self.port}/v1/tenant",
@@ -834,6 +920,36 @@ class ZenithPageserverHttpClient(requests.Session):
         assert isinstance(new_tenant_id, str)
         return uuid.UUID(new_tenant_id)
 
+    def tenant_clone(self,
+                     tenant_id: uuid.UUID,
+                     new_tenant_id: Optional[uuid.UUID] = None) -> uuid.UUID:
+        res = self.post(
+            f"http://localhost:{self.port}/v1/tenant/{tenant_id.hex}/clone",
+            json={
+                'new_tenant_id': new_tenant_id.hex if new_tenant_id else None,
+            },
+        )
+        self.verbose_error(res)
+        new_tenant_id = res.json()
+        assert isinstance(new_tenant_id, str)
+        return uuid.UUID(new_tenant_id)
+
+    def tenant_config(self, tenant_id: uuid.UUID) -> Dict[str, Dict[str, Any]]:
+        res = self.get(f"http://localhost:{self.port}/v1/tenant/{tenant_id.hex}/config")
+        self.verbose_error(res)
+        res_json = res.json()
+        assert isinstance(res_json, dict)
+        return res_json
+
+    def tenant_config_update(self, tenant_id: uuid.UUID, conf: Dict[str, Any]):
+        res = self.put(
+            f"http://localhost:{self.port}/v1/tenant/config",
+            json={
+                'tenant_id': tenant_id.hex, **conf
+            },
+        )
+        self.verbose_error(res)
+
     def timeline_list(self, tenant_id: uuid.UUID) -> List[Dict[Any, Any]]:
         res = self.get(f"http://localhost:{self.port}/v1/tenant/{tenant_id.hex}/timeline")
         self.verbose_error(res)
@@ -859,8 +975,234 @@ class ZenithPageserverHttpClient(requests.Session):
         assert isinstance(res_json, dict)
         return res_json
 
-    def get_metrics(self) -> str:
-        res = self.get(f"http://localhost:{self.port}/metrics")
+    def timeline_pause_ingest(self, tenant_id: uuid.UUID,
+                              timeline_id: uuid.UUID) -> Dict[Any, Any]:
+        res = self.post(
+            f"http://localhost:{self.port}/v1/tenant/{tenant_id.hex}/timeline/{timeline_id.hex}/ingest/pause"
+        )
+        self.verbose_error(res)
+        res_json = res.json()
+        assert isinstance(res_json, dict)
+        return res_json
+
+    def timeline_resume_ingest(self, tenant_id: uuid.UUID,
+                               timeline_id: uuid.UUID) -> Dict[Any, Any]:
+        res = self.post(
+            f"http://localhost:{self.port}/v1/tenant/{tenant_id.hex}/timeline/{timeline_id.hex}/ingest/resume"
+        )
+        self.verbose_error(res)
+        res_json = res.json()
+        assert isinstance(res_json, dict)
+        return res_json
+
+    def thread_list(self,
+                    tenant_id: uuid.UUID,
+                    kind: Optional[str] = None) -> List[Dict[Any, Any]]:
+        params = {} if kind is None else {'kind': kind}
+        res = self.get(f"http://localhost:{self.port}/v1/tenant/{tenant_id.hex}/threads",
+                       params=params)
+        self.verbose_error(res)
+        res_json = res.json()
+        assert isinstance(res_json, list)
+        return res_json
+
+    def thread_shutdown(self,
+                        tenant_id: uuid.UUID,
+                        kind: str,
+                        timeline_id: Optional[uuid.UUID] = None) -> List[Dict[Any, Any]]:
+        params = {'kind': kind}
+        if timeline_id is not None:
+            params['timeline_id'] = timeline_id.hex
+        res = self.post(f"http://localhost:{self.port}/v1/tenant/{tenant_id.hex}/threads/shutdown",
+                        params=params)
+        self.verbose_error(res)
+        res_json = res.json()
+        assert isinstance(res_json, list)
+        return res_json
+
+    def wal_record_stats(self,
+                         tenant_id: uuid.UUID,
+                         timeline_id: uuid.UUID) -> List[Dict[Any, Any]]:
+        res = self.get(
+            f"http://localhost:{self.port}/v1/tenant/{tenant_id.hex}/timeline/{timeline_id.hex}/wal_record_stats"
+        )
+        self.verbose_error(res)
+        res_json = res.json()
+        assert isinstance(res_json, list)
+        return res_json
+
+    def gc_space_report(self,
+                        tenant_id: uuid.UUID,
+                        timeline_id: uuid.UUID,
+                        limit: Optional[int] = None) -> Dict[Any, Any]:
+        params = {} if limit is None else {'limit': limit}
+        res = self.get(
+            f"http://localhost:{self.port}/v1/tenant/{tenant_id.hex}/timeline/{timeline_id.hex}/gc_space_report",
+            params=params,
+        )
+        self.verbose_error(res)
+        res_json = res.json()
+        assert isinstance(res_json, dict)
+        return res_json
+
+    def gc_estimate(self, tenant_id: uuid.UUID, timeline_id: uuid.UUID) -> Dict[Any, Any]:
+        res = self.get(
+            f"http://localhost:{self.port}/v1/tenant/{tenant_id.hex}/timeline/{timeline_id.hex}/gc_estimate"
+        )
+        self.verbose_error(res)
+        res_json = res.json()
+        assert isinstance(res_json, dict)
+        return res_json
+
+    def timeline_doctor(self,
+                        tenant_id: uuid.UUID,
+                        timeline_id: uuid.UUID,
+                        sample_pages: Optional[int] = None) -> Dict[Any, Any]:
+        params = {} if sample_pages is None else {'sample_pages': sample_pages}
+        res = self.get(
+            f"http://localhost:{self.port}/v1/tenant/{tenant_id.hex}/timeline/{timeline_id.hex}/doctor",
+            params=params,
+        )
+        self.verbose_error(res)
+        res_json = res.json()
+        assert isinstance(res_json, dict)
+        return res_json
+
+    def timeline_set_aside_layers(self, tenant_id: uuid.UUID,
+                                  timeline_id: uuid.UUID) -> List[Dict[Any, Any]]:
+        res = self.get(
+            f"http://localhost:{self.port}/v1/tenant/{tenant_id.hex}/timeline/{timeline_id.hex}/set_aside_layers"
+        )
+        self.verbose_error(res)
+        res_json = res.json()
+        assert isinstance(res_json, list)
+        return res_json
+
+    def timeline_set_aside_layers_purge(self,
+                                        tenant_id: uuid.UUID,
+                                        timeline_id: uuid.UUID,
+                                        file_name: Optional[str] = None) -> List[Dict[Any, Any]]:
+        params = {} if file_name is None else {'file_name': file_name}
+        res = self.post(
+            f"http://localhost:{self.port}/v1/tenant/{tenant_id.hex}/timeline/{timeline_id.hex}/set_aside_layers/purge",
+            params=params,
+        )
+        self.verbose_error(res)
+        res_json = res.json()
+        assert isinstance(res_json, list)
+        return res_json
+
+    def timeline_warm_up(self,
+                         tenant_id: uuid.UUID,
+                         timeline_id: uuid.UUID,
+                         lsn: Optional[str] = None,
+                         materialize_pages: Optional[int] = None) -> Optional[Dict[Any, Any]]:
+        params: Dict[str, Any] = {}
+        if lsn is not None:
+            params['lsn'] = lsn
+        if materialize_pages is not None:
+            params['materialize_pages'] = materialize_pages
+        res = self.post(
+            f"http://localhost:{self.port}/v1/tenant/{tenant_id.hex}/timeline/{timeline_id.hex}/warm_up",
+            params=params,
+        )
+        self.verbose_error(res)
+        # The timeline is not local yet, its download got scheduled
+        if res.status_code == 202:
+            return None
+        res_json = res.json()
+        assert isinstance(res_json, dict)
+        return res_json
+
+    def timeline_materialize(self,
+                             tenant_id: uuid.UUID,
+                             timeline_id: uuid.UUID,
+                             lsn: Optional[str] = None,
+                             relations: Optional[List[Dict[str, int]]] = None) -> Dict[Any, Any]:
+        body: Dict[str, Any] = {}
+        if lsn is not None:
+            body['lsn'] = lsn
+        if relations is not None:
+            body['relations'] = relations
+        res = self.post(
+            f"http://localhost:{self.port}/v1/tenant/{tenant_id.hex}/timeline/{timeline_id.hex}/materialize",
+            json=body,
+        )
+        self.verbose_error(res)
+        res_json = res.json()
+        assert isinstance(res_json, dict)
+        return res_json
+
+    def timeline_wait_durable(self,
+                              tenant_id: uuid.UUID,
+                              timeline_id: uuid.UUID,
+                              lsn: str,
+                              scope: str = 'local',
+                              timeout: Optional[str] = None) -> Dict[Any, Any]:
+        params = {'lsn': lsn, 'scope': scope}
+        if timeout is not None:
+            params['timeout'] = timeout
+        res = self.post(
+            f"http://localhost:{self.port}/v1/tenant/{tenant_id.hex}/timeline/{timeline_id.hex}/wait_durable",
+            params=params,
+        )
+        self.verbose_error(res)
+        res_json = res.json()
+        assert isinstance(res_json, dict)
+        return res_json
+
+    def timeline_fence(self,
+                       tenant_id: uuid.UUID,
+                       timeline_id: uuid.UUID,
+                       set_read_floor: bool = False) -> Dict[Any, Any]:
+        res = self.post(
+            f"http://localhost:{self.port}/v1/tenant/{tenant_id.hex}/timeline/{timeline_id.hex}/fence",
+            params={'set_read_floor': 'true' if set_read_floor else 'false'},
+        )
+        self.verbose_error(res)
+        res_json = res.json()
+        assert isinstance(res_json, dict)
+        return res_json
+
+    def timeline_set_read_floor(self,
+                                tenant_id: uuid.UUID,
+                                timeline_id: uuid.UUID,
+                                lsn: Optional[str]) -> Dict[Any, Any]:
+        res = self.put(
+            f"http://localhost:{self.port}/v1/tenant/{tenant_id.hex}/timeline/{timeline_id.hex}/read_floor",
+            json={'lsn': lsn},
+        )
+        self.verbose_error(res)
+        res_json = res.json()
+        assert isinstance(res_json, dict)
+        return res_json
+
+    def tenant_append_only_relations(self, tenant_id: uuid.UUID) -> List[str]:
+        res = self.get(
+            f"http://localhost:{self.port}/v1/tenant/{tenant_id.hex}/append_only_relations")
+        self.verbose_error(res)
+        res_json = res.json()
+        assert isinstance(res_json, list)
+        return res_json
+
+    def tenant_set_append_only(self,
+                               tenant_id: uuid.UUID,
+                               relations: List[str],
+                               append_only: bool = True) -> List[str]:
+        res = self.put(
+            f"http://localhost:{self.port}/v1/tenant/{tenant_id.hex}/append_only_relations",
+            json={
+                'relations': relations,
+                'append_only': append_only,
+            },
+        )
+        self.verbose_error(res)
+        res_json = res.json()
+        assert isinstance(res_json, list)
+        return res_json
+
+    def get_metrics(self, basic_auth: Optional[Tuple[str, str]] = None) -> str:
+        res = self.get(f"http://localhost:{self.port}/metrics", auth=basic_auth)
         self.verbose_error(res)
         return res.text
 